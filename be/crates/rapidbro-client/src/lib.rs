@@ -0,0 +1,287 @@
+// Typed async client for the rapidbro HTTP API. Requests go to the current versioned prefix
+// and API errors are decoded from the server's ErrorResponse body.
+
+use rapidbro_types::{
+    BusEta, BusResponse, DailyRouteReport, Envelope, ErrorResponse, GetAllResponse,
+    GtfsValidationReport, IncidentsResponse, IngestorStatus, NearestStopResponse,
+    RouteGroupEtaResponse, RouteGroupLiveResponse, RouteMultiStopEtaResponse,
+    RouteRuntimesResponse, RouteShapeResponse, RouteStopsResponse, ServiceDeliveryResponse,
+    ServiceTodayResponse, StopIncomingResponse, StopRoutesResponse,
+};
+use serde::de::DeserializeOwned;
+use std::fmt;
+
+pub use rapidbro_types as types;
+
+const API_PREFIX: &str = "/v1";
+const API_VERSION_HEADER: &str = "x-api-version";
+const ENVELOPE_API_VERSION: &str = "2";
+
+#[derive(Debug)]
+pub enum ClientError {
+    Http(reqwest::Error),
+    Api { status: u16, message: String },
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ClientError::Http(err) => write!(f, "request failed: {}", err),
+            ClientError::Api { status, message } => {
+                write!(f, "API returned {}: {}", status, message)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ClientError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ClientError::Http(err) => Some(err),
+            ClientError::Api { .. } => None,
+        }
+    }
+}
+
+impl From<reqwest::Error> for ClientError {
+    fn from(err: reqwest::Error) -> Self {
+        ClientError::Http(err)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RapidbroClient {
+    http: reqwest::Client,
+    base_url: String,
+    // Set to scope every request to one city of a multi-city deployment.
+    city: Option<String>,
+}
+
+impl RapidbroClient {
+    pub fn new(base_url: impl Into<String>) -> Self {
+        Self::with_http_client(reqwest::Client::new(), base_url)
+    }
+
+    pub fn with_http_client(http: reqwest::Client, base_url: impl Into<String>) -> Self {
+        let base_url = base_url.into().trim_end_matches('/').to_string();
+        Self {
+            http,
+            base_url,
+            city: None,
+        }
+    }
+
+    // Requests go to /v1/{city_id}/... instead of the server's default city.
+    pub fn for_city(mut self, city_id: impl Into<String>) -> Self {
+        self.city = Some(city_id.into());
+        self
+    }
+
+    pub async fn get_all(&self) -> Result<GetAllResponse, ClientError> {
+        self.get_json("/get-all", &[]).await
+    }
+
+    // Only buses changed since the given meta.snapshot_seq; check meta.is_delta, since the
+    // server replies with the full fleet when it can no longer diff that far back.
+    pub async fn get_all_since(&self, snapshot_seq: u64) -> Result<GetAllResponse, ClientError> {
+        self.get_json(
+            "/get-all",
+            &[("since_snapshot_seq", snapshot_seq.to_string())],
+        )
+        .await
+    }
+
+    pub async fn ingestor_status(&self) -> Result<IngestorStatus, ClientError> {
+        self.get_json("/ingestor/status", &[]).await
+    }
+
+    // Rows the server's current GTFS feed was loaded without.
+    pub async fn gtfs_validation(&self) -> Result<GtfsValidationReport, ClientError> {
+        self.get_json("/gtfs/validation", &[]).await
+    }
+
+    pub async fn incidents(&self) -> Result<IncidentsResponse, ClientError> {
+        self.get_json("/incidents", &[]).await
+    }
+
+    pub async fn service_today(&self) -> Result<ServiceTodayResponse, ClientError> {
+        self.get_json("/service-today", &[]).await
+    }
+
+    // Live buses come back active; ones that recently left the feed come back inactive.
+    pub async fn bus(&self, bus_no: &str) -> Result<BusResponse, ClientError> {
+        self.get_json(&format!("/buses/{}", bus_no), &[]).await
+    }
+
+    pub async fn stop_eta(&self, stop_id: &str) -> Result<StopIncomingResponse, ClientError> {
+        self.get_json(&format!("/stops/{}/eta", stop_id), &[]).await
+    }
+
+    pub async fn stop_routes(&self, stop_id: &str) -> Result<StopRoutesResponse, ClientError> {
+        self.get_json(&format!("/stops/{}/routes", stop_id), &[])
+            .await
+    }
+
+    pub async fn route_eta(
+        &self,
+        route_id: &str,
+        stop_id: &str,
+    ) -> Result<Vec<BusEta>, ClientError> {
+        self.get_json(&format!("/route/{}/eta/{}", route_id, stop_id), &[])
+            .await
+    }
+
+    pub async fn route_eta_for_stops(
+        &self,
+        route_id: &str,
+        stop_ids: &[&str],
+    ) -> Result<RouteMultiStopEtaResponse, ClientError> {
+        self.get_json(
+            &format!("/route/{}/eta", route_id),
+            &[("stops", stop_ids.join(","))],
+        )
+        .await
+    }
+
+    pub async fn group_live(&self, group: &str) -> Result<RouteGroupLiveResponse, ClientError> {
+        self.get_json(&format!("/groups/{}/live", group), &[]).await
+    }
+
+    pub async fn group_eta(
+        &self,
+        group: &str,
+        stop_id: &str,
+    ) -> Result<RouteGroupEtaResponse, ClientError> {
+        self.get_json(&format!("/groups/{}/eta/{}", group, stop_id), &[])
+            .await
+    }
+
+    pub async fn route_stops(&self, route_id: &str) -> Result<RouteStopsResponse, ClientError> {
+        self.get_json(&format!("/route/{}/stops", route_id), &[])
+            .await
+    }
+
+    pub async fn route_runtimes(
+        &self,
+        route_id: &str,
+    ) -> Result<RouteRuntimesResponse, ClientError> {
+        self.get_json(&format!("/routes/{}/runtimes", route_id), &[])
+            .await
+    }
+
+    // date as YYYY-MM-DD; today in Kuala Lumpur when None.
+    pub async fn route_service_delivery(
+        &self,
+        route_id: &str,
+        date: Option<&str>,
+    ) -> Result<ServiceDeliveryResponse, ClientError> {
+        let query: Vec<(&str, String)> = date
+            .map(|date| vec![("date", date.to_string())])
+            .unwrap_or_default();
+        self.get_json(&format!("/routes/{}/service-delivery", route_id), &query)
+            .await
+    }
+
+    pub async fn route_shape(&self, route_id: &str) -> Result<RouteShapeResponse, ClientError> {
+        self.get_json(&format!("/route/{}/shape", route_id), &[])
+            .await
+    }
+
+    pub async fn nearest_stop(
+        &self,
+        lat: f64,
+        lon: f64,
+    ) -> Result<NearestStopResponse, ClientError> {
+        self.get_json(
+            "/stops/nearest",
+            &[("lat", lat.to_string()), ("lon", lon.to_string())],
+        )
+        .await
+    }
+
+    // date as YYYY-MM-DD, a Kuala Lumpur service day that has already ended.
+    pub async fn daily_route_report(&self, date: &str) -> Result<DailyRouteReport, ClientError> {
+        self.get_json(&format!("/reports/{}/routes.json", date), &[])
+            .await
+    }
+
+    // Static lookups wrapped with freshness meta (API version 2), for callers that cache them.
+    pub async fn route_stops_with_meta(
+        &self,
+        route_id: &str,
+    ) -> Result<Envelope<RouteStopsResponse>, ClientError> {
+        self.get_enveloped(&format!("/route/{}/stops", route_id), &[])
+            .await
+    }
+
+    pub async fn stop_routes_with_meta(
+        &self,
+        stop_id: &str,
+    ) -> Result<Envelope<StopRoutesResponse>, ClientError> {
+        self.get_enveloped(&format!("/stops/{}/routes", stop_id), &[])
+            .await
+    }
+
+    pub async fn nearest_stop_with_meta(
+        &self,
+        lat: f64,
+        lon: f64,
+    ) -> Result<Envelope<NearestStopResponse>, ClientError> {
+        self.get_enveloped(
+            "/stops/nearest",
+            &[("lat", lat.to_string()), ("lon", lon.to_string())],
+        )
+        .await
+    }
+
+    async fn get_json<T: DeserializeOwned>(
+        &self,
+        path: &str,
+        query: &[(&str, String)],
+    ) -> Result<T, ClientError> {
+        self.send_json(self.http.get(self.url(path)).query(query))
+            .await
+    }
+
+    async fn get_enveloped<T: DeserializeOwned>(
+        &self,
+        path: &str,
+        query: &[(&str, String)],
+    ) -> Result<Envelope<T>, ClientError> {
+        let request = self
+            .http
+            .get(self.url(path))
+            .query(query)
+            .header(API_VERSION_HEADER, ENVELOPE_API_VERSION);
+        self.send_json(request).await
+    }
+
+    fn url(&self, path: &str) -> String {
+        match &self.city {
+            Some(city) => format!("{}{}/{}{}", self.base_url, API_PREFIX, city, path),
+            None => format!("{}{}{}", self.base_url, API_PREFIX, path),
+        }
+    }
+
+    async fn send_json<T: DeserializeOwned>(
+        &self,
+        request: reqwest::RequestBuilder,
+    ) -> Result<T, ClientError> {
+        let response = request.send().await?;
+        let status = response.status();
+        if !status.is_success() {
+            let message = match response.json::<ErrorResponse>().await {
+                Ok(body) => body.error,
+                Err(_) => status
+                    .canonical_reason()
+                    .unwrap_or("unknown error")
+                    .to_string(),
+            };
+            return Err(ClientError::Api {
+                status: status.as_u16(),
+                message,
+            });
+        }
+        Ok(response.json::<T>().await?)
+    }
+}
@@ -0,0 +1,68 @@
+// wasm-bindgen bindings so the frontend can re-extrapolate ETAs between server updates with the
+// same code the server runs. Arguments and results are the API's JSON shapes passed as plain JS
+// objects; thresholds should come from GET /v1/eta/model so both sides use identical parameters.
+
+use std::collections::HashMap;
+
+use rapidbro_eta::{BusMotionState, Thresholds};
+use rapidbro_types::{BusPosition, RouteStopsResponse};
+use serde::de::DeserializeOwned;
+use wasm_bindgen::prelude::*;
+
+fn from_js<T: DeserializeOwned>(value: JsValue, name: &str) -> Result<T, JsError> {
+    serde_wasm_bindgen::from_value(value)
+        .map_err(|error| JsError::new(&format!("Invalid {}: {}", name, error)))
+}
+
+// Compare with `model_version` from GET /v1/eta/model; a mismatch means the bundle is older or
+// newer than the server and its estimates may drift.
+#[wasm_bindgen(js_name = modelVersion)]
+pub fn model_version() -> u32 {
+    rapidbro_eta::MODEL_VERSION
+}
+
+// `motion_states` is optional: without smoothed speeds and dwell times the raw reported speed is
+// used, exactly as the server does for a bus it has seen only once.
+#[wasm_bindgen(js_name = calculateRouteEta)]
+pub fn calculate_route_eta(
+    buses: JsValue,
+    motion_states: JsValue,
+    route_id: &str,
+    target_stop_id: &str,
+    route_stops: JsValue,
+    thresholds: JsValue,
+    now_ms: f64,
+) -> Result<JsValue, JsError> {
+    let buses: Vec<BusPosition> = from_js(buses, "buses")?;
+    let motion_states: Option<HashMap<String, BusMotionState>> =
+        from_js(motion_states, "motion_states")?;
+    let route_stops: RouteStopsResponse = from_js(route_stops, "route_stops")?;
+    let thresholds: Thresholds = from_js(thresholds, "thresholds")?;
+
+    let eta_results = rapidbro_eta::calculate_route_eta_from_stops(
+        &buses,
+        &motion_states.unwrap_or_default(),
+        route_id,
+        target_stop_id,
+        &route_stops,
+        None,
+        &thresholds,
+        now_ms as i64,
+    )
+    .map_err(|error| JsError::new(&error))?;
+    serde_wasm_bindgen::to_value(&eta_results).map_err(|error| JsError::new(&error.to_string()))
+}
+
+#[wasm_bindgen(js_name = etaSpeedKmh)]
+pub fn eta_speed_kmh(
+    raw_speed_kmh: f64,
+    smoothed_speed_kmh: Option<f64>,
+    thresholds: JsValue,
+) -> Result<f64, JsError> {
+    let thresholds: Thresholds = from_js(thresholds, "thresholds")?;
+    Ok(rapidbro_eta::eta_speed_kmh(
+        raw_speed_kmh,
+        smoothed_speed_kmh,
+        &thresholds,
+    ))
+}
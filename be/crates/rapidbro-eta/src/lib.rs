@@ -0,0 +1,1518 @@
+// ETA engine shared by the rapidbro backend and any other consumer, such as a WASM frontend:
+// stop resolution, distance along the route, the speed model and crowding hints. Everything here
+// takes plain inputs (positions, route stops and geometry, thresholds); there is no HTTP, Redis
+// or filesystem access.
+
+use std::collections::{HashMap, HashSet};
+
+use chrono::{Datelike, FixedOffset, Timelike};
+use rapidbro_types::{
+    BusEta, BusPosition, IncomingStatus, PredictedCrowding, RouteDetour, RouteStopsResponse,
+    StopCandidate, StopResolutionDecision, StopResolutionSource, StopWithDetails,
+};
+use serde::{Deserialize, Serialize};
+
+// Bumped whenever a change to the math would make two builds disagree on the same inputs, so
+// clients running their own copy (rapidbro-eta-wasm) can tell they are out of step.
+pub const MODEL_VERSION: u32 = 7;
+pub const KL_UTC_OFFSET_SECONDS: i32 = 8 * 3_600;
+pub const DEFAULT_STATIONARY_SPEED_THRESHOLD_KMH: f64 = 1.0;
+pub const DEFAULT_STATIONARY_DISTANCE_THRESHOLD_KM: f64 = 0.03;
+pub const DEFAULT_STATIONARY_WINDOW_SECONDS: i64 = 60;
+pub const DEFAULT_SPEED_KMH: f64 = 20.0;
+pub const DEFAULT_SPEED_EMA_ALPHA: f64 = 0.3;
+pub const DEFAULT_MIN_ETA_SPEED_KMH: f64 = 5.0;
+pub const DEFAULT_MAX_ETA_SPEED_KMH: f64 = 60.0;
+// Larger jumps between two ingests mean a new trip or a bad fix rather than real progress.
+pub const MAX_CHAINAGE_STEP_M: f64 = 3_000.0;
+// A bus held at a stop this long is most likely boarding a queue of riders.
+pub const CROWDED_DWELL_MS: i64 = 30_000;
+pub const MIN_DWELL_MS: i64 = 5_000;
+// Longer stops are layovers or breakdowns rather than boarding.
+pub const MAX_DWELL_MS: i64 = 10 * 60_000;
+pub const DWELL_STOP_RADIUS_M: f64 = 40.0;
+// A stop whose direction of travel differs from the bus heading by more than this is on the
+// other side of the road (or the other leg of a loop) and loses to any stop that agrees.
+pub const MAX_HEADING_DEVIATION_DEG: f64 = 90.0;
+// Runtimes beyond these are layovers, breakdowns or feed gaps rather than running time.
+pub const MAX_SEGMENT_RUNTIME_MS: i64 = 30 * 60_000;
+pub const MAX_TRIP_RUNTIME_MS: i64 = 4 * 3_600_000;
+// GPS fixes younger than this count at full weight; older ones halve in weight every
+// STALE_FIX_HALF_LIFE_MS.
+pub const FRESH_FIX_MS: i64 = 30_000;
+pub const STALE_FIX_HALF_LIFE_MS: i64 = 30_000;
+// Below this weight (a fix roughly 90 s old) a bus still gets an ETA but no longer counts as
+// incoming on its own.
+pub const MIN_INCOMING_DATA_WEIGHT: f64 = 0.25;
+// A bus further than this from its route shape on this many consecutive updates is off-route:
+// diverted, or tagged with the wrong route code.
+pub const DEFAULT_ROUTE_DEVIATION_CORRIDOR_M: f64 = 250.0;
+pub const DEFAULT_ROUTE_DEVIATION_MIN_UPDATES: u32 = 3;
+// Horizons for a stop's incoming_status. Past DEFAULT_INCOMING_MAX_MINUTES an ETA is mostly
+// extrapolation (a bus stopped far up the route, a fallback speed) and promises nothing.
+pub const DEFAULT_ARRIVING_SOON_MAX_MINUTES: f64 = 5.0;
+pub const DEFAULT_INCOMING_MAX_MINUTES: f64 = 30.0;
+
+#[derive(Debug, Clone)]
+pub struct ResolvedCurrentStop {
+    pub stop_id: String,
+    pub stop_name: String,
+    pub sequence: u32,
+    pub source: StopResolutionSource,
+}
+
+// Tunables for stop resolution, stationary filtering and ETA math. Defaults come from the
+// environment and are overridden by any values persisted via PATCH /admin/thresholds.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct Thresholds {
+    pub max_derived_stop_distance_km: f64,
+    pub stationary_speed_threshold_kmh: f64,
+    pub stationary_distance_threshold_km: f64,
+    pub stationary_window_ms: i64,
+    pub default_speed_kmh: f64,
+    pub speed_ema_alpha: f64,
+    pub min_eta_speed_kmh: f64,
+    pub max_eta_speed_kmh: f64,
+    // Defaulted so thresholds persisted before deviation detection still load.
+    #[serde(default = "default_route_deviation_corridor_m")]
+    pub route_deviation_corridor_m: f64,
+    #[serde(default = "default_route_deviation_min_updates")]
+    pub route_deviation_min_updates: u32,
+    #[serde(default = "default_arriving_soon_max_minutes")]
+    pub arriving_soon_max_minutes: f64,
+    #[serde(default = "default_incoming_max_minutes")]
+    pub incoming_max_minutes: f64,
+}
+
+fn default_route_deviation_corridor_m() -> f64 {
+    DEFAULT_ROUTE_DEVIATION_CORRIDOR_M
+}
+
+fn default_route_deviation_min_updates() -> u32 {
+    DEFAULT_ROUTE_DEVIATION_MIN_UPDATES
+}
+
+fn default_arriving_soon_max_minutes() -> f64 {
+    DEFAULT_ARRIVING_SOON_MAX_MINUTES
+}
+
+fn default_incoming_max_minutes() -> f64 {
+    DEFAULT_INCOMING_MAX_MINUTES
+}
+
+// The parameters the server is currently estimating with, as served by GET /v1/eta/model.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EtaModel {
+    pub model_version: u32,
+    pub thresholds: Thresholds,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BusMotionState {
+    pub reference_lat: f64,
+    pub reference_lon: f64,
+    pub stationary_since_unix_ms: Option<i64>,
+    // Exponential moving average of reported speed; absent for states written before smoothing.
+    #[serde(default)]
+    pub smoothed_speed_kmh: Option<f64>,
+    #[serde(default)]
+    pub chainage: Option<BusChainage>,
+    // Carried forward between ingests so consecutive stop passages can be timed.
+    #[serde(default)]
+    pub last_stop_passage: Option<StopPassage>,
+    // Consecutive updates outside the route corridor; see track_route_deviation.
+    #[serde(default)]
+    pub off_route_updates: u32,
+    #[serde(default)]
+    pub off_route: bool,
+}
+
+// The most recent route stop a bus crossed, by index into RouteGeometry::stop_chainages.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StopPassage {
+    pub shape_id: String,
+    pub stop_index: usize,
+    pub stop_id: String,
+    pub passed_at_unix_ms: i64,
+    // When the bus crossed the first stop of the shape, if it has been tracked since.
+    pub trip_started_at_unix_ms: Option<i64>,
+}
+
+// A timed run between two consecutive stops, or from the first stop of a shape to the last.
+#[derive(Debug, Clone, PartialEq)]
+pub enum RuntimeSample {
+    Segment {
+        from_stop_id: String,
+        to_stop_id: String,
+        started_at_unix_ms: i64,
+        runtime_ms: i64,
+    },
+    Trip {
+        started_at_unix_ms: i64,
+        runtime_ms: i64,
+    },
+}
+
+// Position of a bus projected onto its route shape, in meters from the start of the shape.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BusChainage {
+    pub shape_id: String,
+    pub chainage_m: f64,
+    pub shape_length_m: f64,
+    pub offset_m: f64,
+    pub updated_at_unix_ms: i64,
+}
+
+#[derive(Debug, Clone)]
+pub struct RouteGeometry {
+    pub route_id: String,
+    pub shape_id: String,
+    pub points: Vec<(f64, f64)>,
+    pub cumulative_m: Vec<f64>,
+    // Route stops projected onto the shape, ordered by chainage.
+    pub stop_chainages: Vec<(String, f64)>,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ShapeProjection {
+    pub chainage_m: f64,
+    pub offset_m: f64,
+}
+
+pub fn update_bus_motion_state(
+    previous_state: Option<&BusMotionState>,
+    bus: &BusPosition,
+    now_ms: i64,
+    thresholds: &Thresholds,
+) -> BusMotionState {
+    let reference_lat = previous_state
+        .map(|state| state.reference_lat)
+        .unwrap_or(bus.latitude);
+    let reference_lon = previous_state
+        .map(|state| state.reference_lon)
+        .unwrap_or(bus.longitude);
+    let distance_from_reference =
+        haversine_distance(bus.latitude, bus.longitude, reference_lat, reference_lon);
+    let is_slow = bus.speed <= thresholds.stationary_speed_threshold_kmh;
+    let smoothed_speed_kmh = Some(
+        match previous_state.and_then(|state| state.smoothed_speed_kmh) {
+            Some(previous_speed) => {
+                thresholds.speed_ema_alpha * bus.speed
+                    + (1.0 - thresholds.speed_ema_alpha) * previous_speed
+            }
+            None => bus.speed,
+        },
+    );
+
+    if distance_from_reference >= thresholds.stationary_distance_threshold_km {
+        return BusMotionState {
+            reference_lat: bus.latitude,
+            reference_lon: bus.longitude,
+            stationary_since_unix_ms: is_slow.then_some(now_ms),
+            smoothed_speed_kmh,
+            chainage: None,
+            last_stop_passage: None,
+            off_route_updates: 0,
+            off_route: false,
+        };
+    }
+
+    if is_slow {
+        return BusMotionState {
+            reference_lat,
+            reference_lon,
+            stationary_since_unix_ms: previous_state
+                .and_then(|state| state.stationary_since_unix_ms)
+                .or(Some(now_ms)),
+            smoothed_speed_kmh,
+            chainage: None,
+            last_stop_passage: None,
+            off_route_updates: 0,
+            off_route: false,
+        };
+    }
+
+    BusMotionState {
+        reference_lat: bus.latitude,
+        reference_lon: bus.longitude,
+        stationary_since_unix_ms: None,
+        smoothed_speed_kmh,
+        chainage: None,
+        last_stop_passage: None,
+        off_route_updates: 0,
+        off_route: false,
+    }
+}
+
+pub fn is_bus_stationary(
+    motion_states: &HashMap<String, BusMotionState>,
+    bus_no: &str,
+    now_ms: i64,
+    thresholds: &Thresholds,
+) -> bool {
+    motion_states
+        .get(bus_no)
+        .and_then(|state| state.stationary_since_unix_ms)
+        .map(|since_ms| now_ms - since_ms >= thresholds.stationary_window_ms)
+        .unwrap_or(false)
+}
+
+pub fn filter_non_stationary_buses(
+    buses: &[BusPosition],
+    motion_states: &HashMap<String, BusMotionState>,
+    now_ms: i64,
+    thresholds: &Thresholds,
+) -> Vec<BusPosition> {
+    // Buses parked inside a depot geofence are out of service even when their GPS jitters.
+    buses
+        .iter()
+        .filter(|bus| !bus.in_depot)
+        // Off-route buses are not serving the stops of the route they are tagged with.
+        .filter(|bus| !bus.off_route)
+        .filter(|bus| !is_bus_stationary(motion_states, &bus.bus_no, now_ms, thresholds))
+        .cloned()
+        .collect()
+}
+
+pub fn resolve_current_stop(
+    bus: &BusPosition,
+    route_stops: &RouteStopsResponse,
+    thresholds: &Thresholds,
+) -> Option<ResolvedCurrentStop> {
+    if let Some(bus_stop_id) = bus.busstop_id.as_ref().filter(|id| !id.is_empty()) {
+        if let Some(stop) = route_stops
+            .stops
+            .iter()
+            .find(|stop| stop.stop_id == *bus_stop_id)
+        {
+            return Some(ResolvedCurrentStop {
+                stop_id: stop.stop_id.clone(),
+                stop_name: stop.stop_name.clone(),
+                sequence: stop.sequence,
+                source: StopResolutionSource::Live,
+            });
+        }
+    }
+
+    let mut within_range: Vec<(&StopWithDetails, f64, Option<f64>)> = route_stops
+        .stops
+        .iter()
+        .zip(stop_heading_deltas(bus, route_stops, thresholds))
+        .map(|(stop, heading_delta)| {
+            (
+                stop,
+                haversine_distance(bus.latitude, bus.longitude, stop.stop_lat, stop.stop_lon),
+                heading_delta,
+            )
+        })
+        .filter(|(_, distance_km, _)| *distance_km <= thresholds.max_derived_stop_distance_km)
+        .collect();
+    within_range.sort_by(|a, b| a.1.partial_cmp(&b.1).unwrap_or(std::cmp::Ordering::Equal));
+
+    // Prefer the nearest stop served in the direction the bus is heading; with no usable
+    // heading, or no such stop in range, fall back to the plain nearest stop.
+    let (nearest_stop, _, _) = within_range
+        .iter()
+        .find(|(_, _, heading_delta)| {
+            heading_delta.is_some_and(|delta| delta <= MAX_HEADING_DEVIATION_DEG)
+        })
+        .or_else(|| within_range.first())?;
+
+    Some(ResolvedCurrentStop {
+        stop_id: nearest_stop.stop_id.clone(),
+        stop_name: nearest_stop.stop_name.clone(),
+        sequence: nearest_stop.sequence,
+        source: StopResolutionSource::Derived,
+    })
+}
+
+// Why resolve_current_stop placed a bus where it did: its outcome plus the nearest route stops,
+// closest first. Separate from resolve_current_stop so the ETA path never ranks candidates.
+pub fn trace_current_stop(
+    bus: &BusPosition,
+    route_stops: &RouteStopsResponse,
+    thresholds: &Thresholds,
+    max_candidates: usize,
+) -> (
+    StopResolutionDecision,
+    Option<ResolvedCurrentStop>,
+    Vec<StopCandidate>,
+) {
+    let resolved = resolve_current_stop(bus, route_stops, thresholds);
+    let decision = match resolved.as_ref().map(|stop| &stop.source) {
+        Some(StopResolutionSource::Live) => StopResolutionDecision::Live,
+        Some(StopResolutionSource::Derived) => StopResolutionDecision::Derived,
+        None if route_stops.stops.is_empty() => StopResolutionDecision::NoStops,
+        None => StopResolutionDecision::TooFar,
+    };
+
+    let mut candidates: Vec<StopCandidate> = route_stops
+        .stops
+        .iter()
+        .zip(stop_heading_deltas(bus, route_stops, thresholds))
+        .map(|(stop, heading_delta)| StopCandidate {
+            stop_id: stop.stop_id.clone(),
+            stop_name: stop.stop_name.clone(),
+            sequence: stop.sequence,
+            distance_m: (haversine_distance(
+                bus.latitude,
+                bus.longitude,
+                stop.stop_lat,
+                stop.stop_lon,
+            ) * 1000.0)
+                .round(),
+            heading_delta_deg: heading_delta.map(f64::round),
+        })
+        .collect();
+    candidates.sort_by(|a, b| {
+        a.distance_m
+            .partial_cmp(&b.distance_m)
+            .unwrap_or(std::cmp::Ordering::Equal)
+    });
+    candidates.truncate(max_candidates);
+
+    (decision, resolved, candidates)
+}
+
+// Angle between the bus heading and the route's direction of travel at each stop (towards the
+// next stop; from the previous one at the last stop), in stop order. All None when the bus is
+// too slow for its reported heading to mean anything.
+pub fn stop_heading_deltas(
+    bus: &BusPosition,
+    route_stops: &RouteStopsResponse,
+    thresholds: &Thresholds,
+) -> Vec<Option<f64>> {
+    let stops = &route_stops.stops;
+    let heading_usable = bus.speed > thresholds.stationary_speed_threshold_kmh
+        && bus.angle.is_finite()
+        && stops.len() >= 2;
+    (0..stops.len())
+        .map(|index| {
+            if !heading_usable {
+                return None;
+            }
+            let (from, to) = if index + 1 < stops.len() {
+                (&stops[index], &stops[index + 1])
+            } else {
+                (&stops[index - 1], &stops[index])
+            };
+            let route_bearing =
+                initial_bearing_deg(from.stop_lat, from.stop_lon, to.stop_lat, to.stop_lon);
+            let delta = (bus.angle - route_bearing).rem_euclid(360.0);
+            Some(delta.min(360.0 - delta))
+        })
+        .collect()
+}
+
+// Compass bearing from the first coordinate to the second, 0-360 degrees clockwise from north.
+pub fn initial_bearing_deg(lat1: f64, lon1: f64, lat2: f64, lon2: f64) -> f64 {
+    let (lat1, lat2) = (lat1.to_radians(), lat2.to_radians());
+    let dlon = (lon2 - lon1).to_radians();
+    let y = dlon.sin() * lat2.cos();
+    let x = lat1.cos() * lat2.sin() - lat1.sin() * lat2.cos() * dlon.cos();
+    y.atan2(x).to_degrees().rem_euclid(360.0)
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn calculate_route_eta_from_stops(
+    buses: &[BusPosition],
+    motion_states: &HashMap<String, BusMotionState>,
+    route_id: &str,
+    target_stop_id: &str,
+    route_stops: &RouteStopsResponse,
+    geometry: Option<&RouteGeometry>,
+    thresholds: &Thresholds,
+    now_ms: i64,
+) -> Result<Vec<BusEta>, String> {
+    let mut results = calculate_route_eta_for_stops(
+        buses,
+        motion_states,
+        route_id,
+        &[target_stop_id],
+        route_stops,
+        geometry,
+        thresholds,
+        now_ms,
+    )?;
+    Ok(results.pop().unwrap_or_default())
+}
+
+// ETAs towards several stops of one route, in the order given. Each bus is resolved onto the
+// route once and reused for every target. With a route shape, distance is measured along it;
+// see shape_distance_km.
+#[allow(clippy::too_many_arguments)]
+pub fn calculate_route_eta_for_stops(
+    buses: &[BusPosition],
+    motion_states: &HashMap<String, BusMotionState>,
+    route_id: &str,
+    target_stop_ids: &[&str],
+    route_stops: &RouteStopsResponse,
+    geometry: Option<&RouteGeometry>,
+    thresholds: &Thresholds,
+    now_ms: i64,
+) -> Result<Vec<Vec<BusEta>>, String> {
+    let mut targets: Vec<(u32, f64, Option<f64>)> = Vec::with_capacity(target_stop_ids.len());
+    for target_stop_id in target_stop_ids {
+        let index = route_stops
+            .stops
+            .iter()
+            .position(|s| s.stop_id == *target_stop_id)
+            .ok_or_else(|| {
+                format!(
+                    "Target stop '{}' not found in route '{}'",
+                    target_stop_id, route_id
+                )
+            })?;
+        let target_chainage_m = geometry.and_then(|geometry| {
+            geometry
+                .stop_chainages
+                .iter()
+                .find(|(stop_id, _)| stop_id == target_stop_id)
+                .map(|(_, chainage_m)| *chainage_m)
+        });
+        targets.push((
+            route_stops.stops[index].sequence,
+            index as f64 / route_stops.stops.len().max(1) as f64,
+            target_chainage_m,
+        ));
+    }
+
+    let resolved_buses: Vec<(&BusPosition, ResolvedCurrentStop, Option<f64>)> = buses
+        .iter()
+        .filter(|bus| is_bus_on_route(&bus.route, route_id))
+        .filter_map(|bus| {
+            resolve_current_stop(bus, route_stops, thresholds).map(|stop| {
+                let bus_chainage_m =
+                    geometry.and_then(|geometry| shape_chainage_m(geometry, bus, thresholds));
+                (bus, stop, bus_chainage_m)
+            })
+        })
+        .collect();
+
+    let mut all_results: Vec<Vec<BusEta>> = Vec::with_capacity(targets.len());
+    for (target_sequence, target_position, target_chainage_m) in targets {
+        let mut eta_results: Vec<BusEta> = Vec::new();
+
+        for (bus, resolved_stop, bus_chainage_m) in &resolved_buses {
+            let current_sequence = resolved_stop.sequence;
+            if current_sequence >= target_sequence {
+                continue;
+            }
+
+            let stops_away = target_sequence - current_sequence;
+
+            let total_distance_km = match (*bus_chainage_m, target_chainage_m) {
+                (Some(from_m), Some(to_m)) if to_m > from_m => (to_m - from_m) / 1000.0,
+                _ => straight_line_distance_km(bus, route_stops, current_sequence, target_sequence),
+            };
+
+            let motion_state = motion_states.get(&bus.bus_no);
+            let smoothed_speed_kmh = motion_state.and_then(|state| state.smoothed_speed_kmh);
+            let dwell_ms = motion_state
+                .and_then(|state| state.stationary_since_unix_ms)
+                .map(|since_ms| now_ms - since_ms);
+            let speed = eta_speed_kmh(bus.speed, smoothed_speed_kmh, thresholds);
+            let eta_minutes = (total_distance_km / speed) * 60.0;
+
+            eta_results.push(BusEta {
+                route_id: route_id.to_string(),
+                bus_no: bus.bus_no.clone(),
+                current_lat: bus.latitude,
+                current_lon: bus.longitude,
+                current_stop_id: resolved_stop.stop_id.clone(),
+                current_stop_name: resolved_stop.stop_name.clone(),
+                current_sequence,
+                stop_resolution_source: resolved_stop.source.clone(),
+                stops_away,
+                distance_km: (total_distance_km * 100.0).round() / 100.0,
+                speed_kmh: bus.speed,
+                smoothed_speed_kmh: smoothed_speed_kmh.map(|speed| (speed * 10.0).round() / 10.0),
+                eta_minutes: (eta_minutes * 10.0).round() / 10.0,
+                predicted_crowding: predict_crowding(now_ms, target_position, dwell_ms),
+                predicted_crowding_source: "heuristic".to_string(),
+                place: None,
+                eta_source: None,
+                departs_terminus_in_minutes: None,
+                predicted_arrival_unix_ms: Some(predicted_arrival_unix_ms(now_ms, eta_minutes)),
+                data_age_seconds: None,
+                data_weight: None,
+                expires_at_unix_ms: None,
+                route_display: None,
+            });
+        }
+
+        eta_results.sort_by(|a, b| {
+            a.eta_minutes
+                .partial_cmp(&b.eta_minutes)
+                .unwrap_or(std::cmp::Ordering::Equal)
+        });
+        all_results.push(eta_results);
+    }
+
+    Ok(all_results)
+}
+
+// Where the bus sits along the route shape, or None when it is outside the corridor a chainage
+// is trusted in (the same one project_bus_chainage uses).
+fn shape_chainage_m(
+    geometry: &RouteGeometry,
+    bus: &BusPosition,
+    thresholds: &Thresholds,
+) -> Option<f64> {
+    project_onto_shape(geometry, bus.latitude, bus.longitude)
+        .filter(|projection| {
+            projection.offset_m <= thresholds.max_derived_stop_distance_km * 1000.0
+        })
+        .map(|projection| projection.chainage_m)
+}
+
+// Fallback for routes without a shape, or a bus or stop that can't be placed on it: straight
+// hops from the bus through every stop up to the target.
+fn straight_line_distance_km(
+    bus: &BusPosition,
+    route_stops: &RouteStopsResponse,
+    current_sequence: u32,
+    target_sequence: u32,
+) -> f64 {
+    let mut total_distance_km = 0.0;
+    let mut prev_lat = bus.latitude;
+    let mut prev_lon = bus.longitude;
+    for stop in route_stops
+        .stops
+        .iter()
+        .filter(|s| s.sequence > current_sequence && s.sequence <= target_sequence)
+    {
+        total_distance_km += haversine_distance(prev_lat, prev_lon, stop.stop_lat, stop.stop_lon);
+        prev_lat = stop.stop_lat;
+        prev_lon = stop.stop_lon;
+    }
+    total_distance_km
+}
+
+pub fn data_age_weight(age_ms: i64) -> f64 {
+    if age_ms <= FRESH_FIX_MS {
+        return 1.0;
+    }
+    0.5_f64.powf((age_ms - FRESH_FIX_MS) as f64 / STALE_FIX_HALF_LIFE_MS as f64)
+}
+
+// Stamps each ETA with the age of its bus's last fix, when it stops being valid and the
+// wall-clock arrival it implies (the fix time plus eta_minutes), then re-sorts so a stale bus
+// ranks behind a live one with a slightly longer ETA. Buses missing from last_seen_unix_ms
+// (history frames recorded before ages were kept) are treated as fresh as of now_ms.
+pub fn apply_data_age(
+    eta_results: &mut [BusEta],
+    last_seen_unix_ms: &HashMap<String, i64>,
+    now_ms: i64,
+    void_after_ms: i64,
+) {
+    for eta in eta_results.iter_mut() {
+        let Some(&last_seen_ms) = last_seen_unix_ms.get(&eta.bus_no) else {
+            eta.predicted_arrival_unix_ms =
+                Some(predicted_arrival_unix_ms(now_ms, eta.eta_minutes));
+            continue;
+        };
+        eta.predicted_arrival_unix_ms =
+            Some(predicted_arrival_unix_ms(last_seen_ms, eta.eta_minutes));
+        let age_ms = (now_ms - last_seen_ms).max(0);
+        eta.data_age_seconds = Some((age_ms as f64 / 100.0).round() / 10.0);
+        eta.data_weight = Some((data_age_weight(age_ms) * 100.0).round() / 100.0);
+        eta.expires_at_unix_ms = Some(last_seen_ms + void_after_ms);
+    }
+    sort_by_weighted_arrival(eta_results, now_ms);
+}
+
+pub fn predicted_arrival_unix_ms(generated_at_unix_ms: i64, eta_minutes: f64) -> i64 {
+    generated_at_unix_ms + (eta_minutes * 60_000.0).round() as i64
+}
+
+// Orders by time left until the predicted arrival, so ETAs computed from fixes taken at
+// different moments compare fairly, stretched by data_weight so stale buses sink. An arrival
+// already due counts as no time left, so a low weight can't pull it ahead; among those the
+// fresher fix goes first.
+pub fn sort_by_weighted_arrival(eta_results: &mut [BusEta], now_ms: i64) {
+    let weight = |eta: &BusEta| eta.data_weight.unwrap_or(1.0).max(0.01);
+    let weighted = |eta: &BusEta| {
+        let remaining_ms = eta
+            .predicted_arrival_unix_ms
+            .map(|arrival_ms| (arrival_ms - now_ms) as f64)
+            .unwrap_or(eta.eta_minutes * 60_000.0)
+            .max(0.0);
+        remaining_ms / weight(eta)
+    };
+    eta_results.sort_by(|a, b| {
+        weighted(a)
+            .partial_cmp(&weighted(b))
+            .unwrap_or(std::cmp::Ordering::Equal)
+            .then_with(|| {
+                weight(b)
+                    .partial_cmp(&weight(a))
+                    .unwrap_or(std::cmp::Ordering::Equal)
+            })
+    });
+}
+
+// What a stop can tell a rider: only ETAs on fresh enough data and within incoming_max_minutes
+// count, and the soonest of those decides between arriving_soon and incoming.
+pub fn incoming_status(eta_results: &[BusEta], thresholds: &Thresholds) -> IncomingStatus {
+    let soonest = eta_results
+        .iter()
+        .filter(|eta| eta.data_weight.unwrap_or(1.0) >= MIN_INCOMING_DATA_WEIGHT)
+        .filter(|eta| eta.eta_minutes <= thresholds.incoming_max_minutes)
+        .map(|eta| eta.eta_minutes)
+        .min_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+    match soonest {
+        Some(minutes) if minutes <= thresholds.arriving_soon_max_minutes => {
+            IncomingStatus::ArrivingSoon
+        }
+        Some(_) => IncomingStatus::Incoming,
+        None => IncomingStatus::None,
+    }
+}
+
+// Score peak-hour demand, mid-route load (riders board early and alight late) and an ongoing
+// long dwell, then bucket the total. Deliberately coarse: it is a hint, not a measurement.
+pub fn predict_crowding(
+    now_ms: i64,
+    stop_position: f64,
+    dwell_ms: Option<i64>,
+) -> PredictedCrowding {
+    let mut score = 0;
+
+    if let (Some(utc_time), Some(kl_offset)) = (
+        chrono::DateTime::from_timestamp_millis(now_ms),
+        FixedOffset::east_opt(KL_UTC_OFFSET_SECONDS),
+    ) {
+        let local_time = utc_time.with_timezone(&kl_offset);
+        let minute_of_day = local_time.hour() * 60 + local_time.minute();
+        let is_weekday = local_time.weekday().number_from_monday() <= 5;
+        let is_peak = (7 * 60..9 * 60 + 30).contains(&minute_of_day)
+            || (17 * 60..19 * 60 + 30).contains(&minute_of_day);
+        let is_shoulder = (6 * 60..10 * 60 + 30).contains(&minute_of_day)
+            || (16 * 60..20 * 60 + 30).contains(&minute_of_day);
+
+        score += match (is_peak, is_shoulder, is_weekday) {
+            (true, _, true) => 2,
+            (true, _, false) | (false, true, true) => 1,
+            _ => 0,
+        };
+    }
+
+    if (0.3..=0.8).contains(&stop_position) {
+        score += 1;
+    }
+
+    if dwell_ms.is_some_and(|dwell_ms| dwell_ms >= CROWDED_DWELL_MS) {
+        score += 1;
+    }
+
+    match score {
+        0 | 1 => PredictedCrowding::Low,
+        2 => PredictedCrowding::Medium,
+        _ => PredictedCrowding::High,
+    }
+}
+
+// Prefer the smoothed speed, fall back to the raw reading and then the default, and keep the
+// result within the configured bounds so a crawl or a GPS spike can't produce absurd ETAs.
+pub fn eta_speed_kmh(
+    raw_speed_kmh: f64,
+    smoothed_speed_kmh: Option<f64>,
+    thresholds: &Thresholds,
+) -> f64 {
+    let speed = smoothed_speed_kmh
+        .filter(|speed| *speed > 0.0)
+        .or((raw_speed_kmh > 0.0).then_some(raw_speed_kmh))
+        .unwrap_or(thresholds.default_speed_kmh);
+    speed.clamp(thresholds.min_eta_speed_kmh, thresholds.max_eta_speed_kmh)
+}
+
+// True when eta_speed_kmh has nothing usable to go on: no positive speed at all, or one below
+// the floor it would be clamped up to.
+pub fn is_live_speed_unreliable(
+    raw_speed_kmh: f64,
+    smoothed_speed_kmh: Option<f64>,
+    thresholds: &Thresholds,
+) -> bool {
+    smoothed_speed_kmh
+        .filter(|speed| *speed > 0.0)
+        .or((raw_speed_kmh > 0.0).then_some(raw_speed_kmh))
+        .is_none_or(|speed| speed < thresholds.min_eta_speed_kmh)
+}
+
+// Replaces the speed-based ETA with the sum of typical segment runtimes from the bus's current
+// stop to the target, for buses whose live speed is unreliable. segment_runtime_s holds mean
+// seconds per (from_stop_id, to_stop_id) for the current hour of day; an ETA is left alone
+// unless every segment it needs has a mean.
+pub fn apply_runtime_profile(
+    eta_results: &mut [BusEta],
+    route_stops: &RouteStopsResponse,
+    target_stop_id: &str,
+    segment_runtime_s: &HashMap<(String, String), f64>,
+    thresholds: &Thresholds,
+) {
+    if segment_runtime_s.is_empty() {
+        return;
+    }
+    let Some(target_index) = route_stops
+        .stops
+        .iter()
+        .position(|stop| stop.stop_id == target_stop_id)
+    else {
+        return;
+    };
+
+    for eta in eta_results.iter_mut() {
+        if !is_live_speed_unreliable(eta.speed_kmh, eta.smoothed_speed_kmh, thresholds) {
+            continue;
+        }
+        let Some(current_index) = route_stops
+            .stops
+            .iter()
+            .position(|stop| stop.stop_id == eta.current_stop_id)
+            .filter(|index| *index < target_index)
+        else {
+            continue;
+        };
+        let runtime_s: Option<f64> = route_stops.stops[current_index..=target_index]
+            .windows(2)
+            .map(|pair| {
+                segment_runtime_s
+                    .get(&(pair[0].stop_id.clone(), pair[1].stop_id.clone()))
+                    .copied()
+            })
+            .sum();
+        if let Some(runtime_s) = runtime_s {
+            eta.eta_minutes = (runtime_s / 6.0).round() / 10.0;
+            eta.eta_source = Some("runtime_profile".to_string());
+        }
+    }
+}
+
+// Calculate haversine distance between two GPS coordinates (returns km)
+pub fn haversine_distance(lat1: f64, lon1: f64, lat2: f64, lon2: f64) -> f64 {
+    let r = 6371.0; // Earth radius in km
+    let dlat = (lat2 - lat1).to_radians();
+    let dlon = (lon2 - lon1).to_radians();
+    let a = (dlat / 2.0).sin().powi(2)
+        + lat1.to_radians().cos() * lat2.to_radians().cos() * (dlon / 2.0).sin().powi(2);
+    let c = 2.0 * a.sqrt().asin();
+    r * c
+}
+
+// Project a coordinate onto the closest segment of a route shape, using a local
+// equirectangular approximation which is accurate enough over a single segment.
+pub fn project_onto_shape(geometry: &RouteGeometry, lat: f64, lon: f64) -> Option<ShapeProjection> {
+    const EARTH_RADIUS_M: f64 = 6_371_000.0;
+
+    geometry
+        .points
+        .windows(2)
+        .enumerate()
+        .map(|(index, window)| {
+            let (start_lat, start_lon) = window[0];
+            let (end_lat, end_lon) = window[1];
+            let cos_lat = start_lat.to_radians().cos();
+            let to_xy = |point_lat: f64, point_lon: f64| {
+                (
+                    (point_lon - start_lon).to_radians() * EARTH_RADIUS_M * cos_lat,
+                    (point_lat - start_lat).to_radians() * EARTH_RADIUS_M,
+                )
+            };
+            let (segment_x, segment_y) = to_xy(end_lat, end_lon);
+            let (point_x, point_y) = to_xy(lat, lon);
+            let segment_length_sq = segment_x * segment_x + segment_y * segment_y;
+            let t = if segment_length_sq > 0.0 {
+                ((point_x * segment_x + point_y * segment_y) / segment_length_sq).clamp(0.0, 1.0)
+            } else {
+                0.0
+            };
+            let offset_m =
+                ((point_x - t * segment_x).powi(2) + (point_y - t * segment_y).powi(2)).sqrt();
+            let segment_length_m = geometry.cumulative_m[index + 1] - geometry.cumulative_m[index];
+
+            ShapeProjection {
+                chainage_m: geometry.cumulative_m[index] + t * segment_length_m,
+                offset_m,
+            }
+        })
+        .min_by(|a, b| {
+            a.offset_m
+                .partial_cmp(&b.offset_m)
+                .unwrap_or(std::cmp::Ordering::Equal)
+        })
+}
+
+pub fn project_bus_chainage(
+    bus: &BusPosition,
+    route_geometries: &HashMap<String, RouteGeometry>,
+    now_ms: i64,
+    thresholds: &Thresholds,
+) -> Option<BusChainage> {
+    let geometry = route_geometries.get(&normalize_route_code(&bus.route))?;
+    let projection = project_onto_shape(geometry, bus.latitude, bus.longitude)?;
+
+    // A bus this far from the shape is off-route or mis-tagged; its chainage would be noise.
+    if projection.offset_m > thresholds.max_derived_stop_distance_km * 1000.0 {
+        return None;
+    }
+
+    Some(BusChainage {
+        shape_id: geometry.shape_id.clone(),
+        chainage_m: projection.chainage_m,
+        shape_length_m: geometry.cumulative_m.last().copied().unwrap_or(0.0),
+        offset_m: projection.offset_m,
+        updated_at_unix_ms: now_ms,
+    })
+}
+
+// Counts consecutive updates a bus spends outside the corridor around its route shape and flags
+// it off-route once that reaches route_deviation_min_updates, so a single bad fix never does.
+// Buses on routes without a shape are never flagged.
+pub fn track_route_deviation(
+    previous: Option<&BusMotionState>,
+    current: &mut BusMotionState,
+    bus: &BusPosition,
+    route_geometries: &HashMap<String, RouteGeometry>,
+    thresholds: &Thresholds,
+) {
+    let offset_m = route_geometries
+        .get(&normalize_route_code(&bus.route))
+        .and_then(|geometry| project_onto_shape(geometry, bus.latitude, bus.longitude))
+        .map(|projection| projection.offset_m);
+    current.off_route_updates = match offset_m {
+        Some(offset_m) if offset_m > thresholds.route_deviation_corridor_m => previous
+            .map(|state| state.off_route_updates)
+            .unwrap_or(0)
+            .saturating_add(1),
+        _ => 0,
+    };
+    current.off_route = current.off_route_updates >= thresholds.route_deviation_min_updates;
+}
+
+// A bus following a declared detour path leaves its route corridor on purpose; it stays within
+// the same corridor around the detour shape instead.
+pub fn is_on_detour(bus: &BusPosition, detours: &[RouteDetour], thresholds: &Thresholds) -> bool {
+    detours.iter().any(|detour| {
+        let points: Vec<(f64, f64)> = detour.shape.iter().map(|p| (p.lat, p.lon)).collect();
+        let mut cumulative_m = vec![0.0];
+        for pair in points.windows(2) {
+            let length_m = haversine_distance(pair[0].0, pair[0].1, pair[1].0, pair[1].1) * 1000.0;
+            cumulative_m.push(cumulative_m.last().copied().unwrap_or(0.0) + length_m);
+        }
+        let geometry = RouteGeometry {
+            route_id: detour.route_id.clone(),
+            shape_id: detour.id.clone(),
+            points,
+            cumulative_m,
+            stop_chainages: Vec::new(),
+        };
+        project_onto_shape(&geometry, bus.latitude, bus.longitude)
+            .is_some_and(|projection| projection.offset_m <= thresholds.route_deviation_corridor_m)
+    })
+}
+
+// Route stops without the ones an active detour skips, so no bus resolves onto them and no ETA
+// is produced for them. Sequences are kept as they are in the feed.
+pub fn skip_detoured_stops(
+    route_stops: &RouteStopsResponse,
+    detours: &[RouteDetour],
+) -> RouteStopsResponse {
+    let skipped: HashSet<&str> = detours
+        .iter()
+        .flat_map(|detour| detour.skipped_stop_ids.iter().map(String::as_str))
+        .collect();
+    RouteStopsResponse {
+        route_id: route_stops.route_id.clone(),
+        route_short_name: route_stops.route_short_name.clone(),
+        route_long_name: route_stops.route_long_name.clone(),
+        stops: route_stops
+            .stops
+            .iter()
+            .filter(|stop| !skipped.contains(stop.stop_id.as_str()))
+            .cloned()
+            .collect(),
+        next_cursor: route_stops.next_cursor.clone(),
+    }
+}
+
+// (sequence before, sequence after, straight km, detour km) for a detour with a shape: the
+// stops it bridges, the distance skip_detoured_stops leaves between them and the distance
+// along the detour path. None when the skipped stops don't sit inside the route.
+fn detour_span(
+    route_stops: &RouteStopsResponse,
+    detour: &RouteDetour,
+) -> Option<(u32, u32, f64, f64)> {
+    let (first, last) = (detour.shape.first()?, detour.shape.last()?);
+    let skipped_indices: Vec<usize> = route_stops
+        .stops
+        .iter()
+        .enumerate()
+        .filter(|(_, stop)| detour.skipped_stop_ids.contains(&stop.stop_id))
+        .map(|(index, _)| index)
+        .collect();
+    let before = route_stops
+        .stops
+        .get(skipped_indices.first()?.checked_sub(1)?)?;
+    let after = route_stops.stops.get(skipped_indices.last()? + 1)?;
+
+    let kept: Vec<&StopWithDetails> = route_stops
+        .stops
+        .iter()
+        .filter(|stop| stop.sequence >= before.sequence && stop.sequence <= after.sequence)
+        .filter(|stop| !detour.skipped_stop_ids.contains(&stop.stop_id))
+        .collect();
+    let straight_km: f64 = kept
+        .windows(2)
+        .map(|pair| {
+            haversine_distance(
+                pair[0].stop_lat,
+                pair[0].stop_lon,
+                pair[1].stop_lat,
+                pair[1].stop_lon,
+            )
+        })
+        .sum();
+    let shape_km: f64 = detour
+        .shape
+        .windows(2)
+        .map(|pair| haversine_distance(pair[0].lat, pair[0].lon, pair[1].lat, pair[1].lon))
+        .sum();
+    let detour_km = haversine_distance(before.stop_lat, before.stop_lon, first.lat, first.lon)
+        + shape_km
+        + haversine_distance(last.lat, last.lon, after.stop_lat, after.stop_lon);
+    Some((before.sequence, after.sequence, straight_km, detour_km))
+}
+
+// ETAs computed on skip_detoured_stops cut straight across each detour; buses whose remaining
+// trip crosses one get the detour path length instead, at the speed their ETA already implied.
+// route_stops is the full sequence, skipped stops included.
+pub fn apply_detour_geometry(
+    eta_results: &mut [BusEta],
+    route_stops: &RouteStopsResponse,
+    target_stop_id: &str,
+    detours: &[RouteDetour],
+) {
+    let Some(target_sequence) = route_stops
+        .stops
+        .iter()
+        .find(|stop| stop.stop_id == target_stop_id)
+        .map(|stop| stop.sequence)
+    else {
+        return;
+    };
+    let spans: Vec<(u32, u32, f64, f64)> = detours
+        .iter()
+        .filter_map(|detour| detour_span(route_stops, detour))
+        .collect();
+
+    for eta in eta_results.iter_mut() {
+        let extra_km: f64 = spans
+            .iter()
+            .filter(|(before, after, _, _)| {
+                eta.current_sequence <= *before && target_sequence >= *after
+            })
+            .map(|(_, _, straight_km, detour_km)| detour_km - straight_km)
+            .sum();
+        if extra_km == 0.0 || eta.distance_km <= 0.0 {
+            continue;
+        }
+        let distance_km = (eta.distance_km + extra_km).max(0.0);
+        let eta_minutes = eta.eta_minutes * distance_km / eta.distance_km;
+        if let Some(arrival_ms) = eta.predicted_arrival_unix_ms.as_mut() {
+            *arrival_ms += ((eta_minutes - eta.eta_minutes) * 60_000.0) as i64;
+        }
+        eta.distance_km = (distance_km * 100.0).round() / 100.0;
+        eta.eta_minutes = (eta_minutes * 10.0).round() / 10.0;
+    }
+}
+
+// A dwell completes when a bus that was stationary starts moving again. It is attributed to
+// the stop the feed reports, or failing that the route stop nearest the bus along the shape.
+pub fn completed_dwell(
+    previous: &BusMotionState,
+    current: &BusMotionState,
+    bus: &BusPosition,
+    geometry: Option<&RouteGeometry>,
+    now_ms: i64,
+) -> Option<(String, i64)> {
+    let stationary_since = previous.stationary_since_unix_ms?;
+    if current.stationary_since_unix_ms == Some(stationary_since) {
+        return None;
+    }
+    let dwell_ms = now_ms - stationary_since;
+    if !(MIN_DWELL_MS..=MAX_DWELL_MS).contains(&dwell_ms) {
+        return None;
+    }
+
+    if let Some(stop_id) = bus.busstop_id.as_ref().filter(|id| !id.is_empty()) {
+        return Some((stop_id.clone(), dwell_ms));
+    }
+    let chainage = previous.chainage.as_ref()?;
+    let geometry = geometry.filter(|geometry| geometry.shape_id == chainage.shape_id)?;
+    geometry
+        .stop_chainages
+        .iter()
+        .map(|(stop_id, stop_chainage_m)| (stop_id, (stop_chainage_m - chainage.chainage_m).abs()))
+        .filter(|(_, distance_m)| *distance_m <= DWELL_STOP_RADIUS_M)
+        .min_by(|(_, left), (_, right)| {
+            left.partial_cmp(right).unwrap_or(std::cmp::Ordering::Equal)
+        })
+        .map(|(stop_id, _)| (stop_id.clone(), dwell_ms))
+}
+
+// A trip starts when a bus that stood at the first stop of its shape for at least the stationary
+// window moves off along the shape. Buses that drive away backwards (a depot run, a U-turn) do not
+// count.
+pub fn departed_terminus(
+    previous: &BusMotionState,
+    current: &BusMotionState,
+    geometry: Option<&RouteGeometry>,
+    now_ms: i64,
+    thresholds: &Thresholds,
+) -> bool {
+    let Some(stationary_since) = previous.stationary_since_unix_ms else {
+        return false;
+    };
+    if current.stationary_since_unix_ms == Some(stationary_since)
+        || now_ms - stationary_since < thresholds.stationary_window_ms
+    {
+        return false;
+    }
+    let (Some(geometry), Some(from), Some(to)) = (
+        geometry,
+        previous.chainage.as_ref(),
+        current.chainage.as_ref(),
+    ) else {
+        return false;
+    };
+    let Some((_, first_stop_chainage_m)) = geometry.stop_chainages.first() else {
+        return false;
+    };
+    from.shape_id == geometry.shape_id
+        && to.shape_id == geometry.shape_id
+        && (from.chainage_m - first_stop_chainage_m).abs() <= DWELL_STOP_RADIUS_M
+        && to.chainage_m > from.chainage_m
+}
+
+pub fn stops_passed_between<'a>(
+    geometry: &'a RouteGeometry,
+    previous: &BusChainage,
+    current: &BusChainage,
+) -> Vec<&'a str> {
+    let step_m = current.chainage_m - previous.chainage_m;
+    if previous.shape_id != current.shape_id || step_m <= 0.0 || step_m > MAX_CHAINAGE_STEP_M {
+        return Vec::new();
+    }
+
+    geometry
+        .stop_chainages
+        .iter()
+        .filter(|(_, chainage_m)| {
+            *chainage_m > previous.chainage_m && *chainage_m <= current.chainage_m
+        })
+        .map(|(stop_id, _)| stop_id.as_str())
+        .collect()
+}
+
+// Times every stop crossed between the previous and current chainage, interpolating the crossing
+// instant along the step, and advances current.last_stop_passage. Only passages of consecutive
+// stops yield a segment sample, so a skipped stop (feed gap, detour) never produces a bogus one.
+pub fn record_stop_passages(
+    geometry: &RouteGeometry,
+    previous: &BusMotionState,
+    current: &mut BusMotionState,
+) -> Vec<RuntimeSample> {
+    let mut last_passage = previous
+        .last_stop_passage
+        .clone()
+        .filter(|passage| passage.shape_id == geometry.shape_id);
+    let mut samples = Vec::new();
+
+    if let (Some(from), Some(to)) = (previous.chainage.as_ref(), current.chainage.as_ref()) {
+        let step_m = to.chainage_m - from.chainage_m;
+        let step_ms = to.updated_at_unix_ms - from.updated_at_unix_ms;
+        if from.shape_id == geometry.shape_id
+            && to.shape_id == geometry.shape_id
+            && step_m > 0.0
+            && step_m <= MAX_CHAINAGE_STEP_M
+        {
+            let last_index = geometry.stop_chainages.len().saturating_sub(1);
+            for (stop_index, (stop_id, stop_chainage_m)) in
+                geometry.stop_chainages.iter().enumerate()
+            {
+                if *stop_chainage_m <= from.chainage_m || *stop_chainage_m > to.chainage_m {
+                    continue;
+                }
+                let fraction = (stop_chainage_m - from.chainage_m) / step_m;
+                let passed_at_unix_ms =
+                    from.updated_at_unix_ms + (fraction * step_ms as f64).round() as i64;
+
+                let consecutive = last_passage
+                    .as_ref()
+                    .filter(|passage| passage.stop_index + 1 == stop_index);
+                if let Some(passage) = consecutive {
+                    let runtime_ms = passed_at_unix_ms - passage.passed_at_unix_ms;
+                    if (1..=MAX_SEGMENT_RUNTIME_MS).contains(&runtime_ms) {
+                        samples.push(RuntimeSample::Segment {
+                            from_stop_id: passage.stop_id.clone(),
+                            to_stop_id: stop_id.clone(),
+                            started_at_unix_ms: passage.passed_at_unix_ms,
+                            runtime_ms,
+                        });
+                    }
+                }
+                let trip_started_at_unix_ms = if stop_index == 0 {
+                    Some(passed_at_unix_ms)
+                } else {
+                    consecutive.and_then(|passage| passage.trip_started_at_unix_ms)
+                };
+                if stop_index == last_index {
+                    if let Some(started_at_unix_ms) = trip_started_at_unix_ms {
+                        let runtime_ms = passed_at_unix_ms - started_at_unix_ms;
+                        if (1..=MAX_TRIP_RUNTIME_MS).contains(&runtime_ms) {
+                            samples.push(RuntimeSample::Trip {
+                                started_at_unix_ms,
+                                runtime_ms,
+                            });
+                        }
+                    }
+                }
+
+                last_passage = Some(StopPassage {
+                    shape_id: geometry.shape_id.clone(),
+                    stop_index,
+                    stop_id: stop_id.clone(),
+                    passed_at_unix_ms,
+                    trip_started_at_unix_ms,
+                });
+            }
+        }
+    }
+
+    current.last_stop_passage = last_passage;
+    samples
+}
+
+pub fn is_bus_on_route(bus_route: &str, route_id: &str) -> bool {
+    let bus_base = normalize_route_code(bus_route);
+    let route_base = normalize_route_code(route_id);
+    !bus_base.is_empty() && bus_base == route_base
+}
+
+pub fn normalize_route_code(route: &str) -> String {
+    route
+        .trim()
+        .to_uppercase()
+        .trim_end_matches('0')
+        .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOW_MS: i64 = 1_700_000_000_000;
+
+    fn thresholds() -> Thresholds {
+        Thresholds {
+            max_derived_stop_distance_km: 0.75,
+            stationary_speed_threshold_kmh: DEFAULT_STATIONARY_SPEED_THRESHOLD_KMH,
+            stationary_distance_threshold_km: DEFAULT_STATIONARY_DISTANCE_THRESHOLD_KM,
+            stationary_window_ms: DEFAULT_STATIONARY_WINDOW_SECONDS * 1_000,
+            default_speed_kmh: DEFAULT_SPEED_KMH,
+            speed_ema_alpha: DEFAULT_SPEED_EMA_ALPHA,
+            min_eta_speed_kmh: DEFAULT_MIN_ETA_SPEED_KMH,
+            max_eta_speed_kmh: DEFAULT_MAX_ETA_SPEED_KMH,
+            route_deviation_corridor_m: DEFAULT_ROUTE_DEVIATION_CORRIDOR_M,
+            route_deviation_min_updates: DEFAULT_ROUTE_DEVIATION_MIN_UPDATES,
+            arriving_soon_max_minutes: DEFAULT_ARRIVING_SOON_MAX_MINUTES,
+            incoming_max_minutes: DEFAULT_INCOMING_MAX_MINUTES,
+        }
+    }
+
+    fn bus(lat: f64, lon: f64, speed: f64, angle: f64) -> BusPosition {
+        BusPosition {
+            dt_received: None,
+            dt_gps: None,
+            latitude: lat,
+            longitude: lon,
+            dir: None,
+            speed,
+            angle,
+            route: "T789".to_string(),
+            bus_no: "WXY1234".to_string(),
+            trip_no: None,
+            captain_id: None,
+            trip_rev_kind: None,
+            engine_status: 1,
+            accessibility: 0,
+            busstop_id: None,
+            provider: "RKL".to_string(),
+            trip: None,
+            in_depot: false,
+            depot_name: None,
+            outside_service_area: false,
+            off_route: false,
+            vehicle: None,
+            last_seen_unix_ms: None,
+            expires_in_seconds: None,
+        }
+    }
+
+    fn stop(stop_id: &str, sequence: u32, lat: f64, lon: f64) -> StopWithDetails {
+        StopWithDetails {
+            stop_id: stop_id.to_string(),
+            stop_code: None,
+            stop_name: format!("Stop {}", stop_id),
+            stop_desc: String::new(),
+            stop_lat: lat,
+            stop_lon: lon,
+            sequence,
+        }
+    }
+
+    // East along one street and back west along the next, about 55 m apart, as an out-and-back
+    // route lists both sides of the road.
+    fn out_and_back_route() -> RouteStopsResponse {
+        RouteStopsResponse {
+            route_id: "T789".to_string(),
+            route_short_name: "T789".to_string(),
+            route_long_name: "Out and back".to_string(),
+            stops: vec![
+                stop("A1", 1, 3.0, 101.700),
+                stop("A2", 2, 3.0, 101.705),
+                stop("A3", 3, 3.0, 101.710),
+                stop("B3", 4, 3.0005, 101.710),
+                stop("B2", 5, 3.0005, 101.705),
+                stop("B1", 6, 3.0005, 101.700),
+            ],
+            next_cursor: None,
+        }
+    }
+
+    fn straight_geometry() -> RouteGeometry {
+        let points = vec![(3.0, 101.700), (3.0, 101.705), (3.0, 101.710)];
+        let mut cumulative_m = vec![0.0];
+        for pair in points.windows(2) {
+            let length_m = haversine_distance(pair[0].0, pair[0].1, pair[1].0, pair[1].1) * 1000.0;
+            cumulative_m.push(cumulative_m.last().copied().unwrap_or(0.0) + length_m);
+        }
+        RouteGeometry {
+            route_id: "T789".to_string(),
+            shape_id: "T789_0".to_string(),
+            points,
+            cumulative_m,
+            stop_chainages: Vec::new(),
+        }
+    }
+
+    fn eta(bus_no: &str, arrival_in_ms: i64, data_weight: f64) -> BusEta {
+        BusEta {
+            route_id: "T789".to_string(),
+            bus_no: bus_no.to_string(),
+            current_lat: 3.1,
+            current_lon: 101.6,
+            current_stop_id: "1000".to_string(),
+            current_stop_name: "Stop".to_string(),
+            current_sequence: 1,
+            stop_resolution_source: StopResolutionSource::Derived,
+            stops_away: 1,
+            distance_km: 1.0,
+            speed_kmh: 20.0,
+            smoothed_speed_kmh: None,
+            eta_minutes: arrival_in_ms as f64 / 60_000.0,
+            predicted_crowding: PredictedCrowding::Low,
+            predicted_crowding_source: "heuristic".to_string(),
+            place: None,
+            eta_source: None,
+            departs_terminus_in_minutes: None,
+            predicted_arrival_unix_ms: Some(NOW_MS + arrival_in_ms),
+            data_age_seconds: None,
+            data_weight: Some(data_weight),
+            expires_at_unix_ms: None,
+            route_display: None,
+        }
+    }
+
+    fn bus_order(eta_results: &[BusEta]) -> Vec<&str> {
+        eta_results.iter().map(|eta| eta.bus_no.as_str()).collect()
+    }
+
+    #[test]
+    fn weighted_arrival_sinks_stale_buses() {
+        let mut eta_results = vec![eta("stale", 120_000, 0.2), eta("fresh", 300_000, 1.0)];
+        sort_by_weighted_arrival(&mut eta_results, NOW_MS);
+        assert_eq!(bus_order(&eta_results), ["fresh", "stale"]);
+    }
+
+    #[test]
+    fn weighted_arrival_keeps_stale_past_arrival_behind_fresh_one() {
+        let mut eta_results = vec![eta("stale", -60_000, 0.05), eta("fresh", -10_000, 1.0)];
+        sort_by_weighted_arrival(&mut eta_results, NOW_MS);
+        assert_eq!(bus_order(&eta_results), ["fresh", "stale"]);
+    }
+
+    #[test]
+    fn resolve_current_stop_trusts_reported_busstop_id() {
+        let mut reported = bus(3.0, 101.700, 20.0, 90.0);
+        reported.busstop_id = Some("B2".to_string());
+        let resolved = resolve_current_stop(&reported, &out_and_back_route(), &thresholds())
+            .expect("resolved");
+        assert_eq!(resolved.stop_id, "B2");
+        assert_eq!(resolved.sequence, 5);
+        assert_eq!(resolved.source, StopResolutionSource::Live);
+    }
+
+    #[test]
+    fn resolve_current_stop_falls_back_to_nearest_stop_for_unknown_busstop_id() {
+        let mut reported = bus(3.0, 101.7101, 0.0, 0.0);
+        reported.busstop_id = Some("not-on-route".to_string());
+        let resolved = resolve_current_stop(&reported, &out_and_back_route(), &thresholds())
+            .expect("resolved");
+        assert_eq!(resolved.stop_id, "A3");
+        assert_eq!(resolved.source, StopResolutionSource::Derived);
+    }
+
+    #[test]
+    fn resolve_current_stop_follows_heading_onto_the_far_side_of_the_road() {
+        let route = out_and_back_route();
+        // Nearer A2, but heading west, which only the return leg serves.
+        let westbound = bus(3.0001, 101.7051, 20.0, 270.0);
+        let resolved = resolve_current_stop(&westbound, &route, &thresholds()).expect("resolved");
+        assert_eq!(resolved.stop_id, "B2");
+
+        let eastbound = bus(3.0001, 101.7051, 20.0, 90.0);
+        let resolved = resolve_current_stop(&eastbound, &route, &thresholds()).expect("resolved");
+        assert_eq!(resolved.stop_id, "A2");
+
+        // Too slow for the heading to count: plain nearest stop.
+        let stopped = bus(3.0001, 101.7051, 0.0, 270.0);
+        let resolved = resolve_current_stop(&stopped, &route, &thresholds()).expect("resolved");
+        assert_eq!(resolved.stop_id, "A2");
+    }
+
+    #[test]
+    fn resolve_current_stop_gives_up_beyond_max_derived_distance() {
+        let far_away = bus(3.1, 101.8, 20.0, 90.0);
+        assert!(resolve_current_stop(&far_away, &out_and_back_route(), &thresholds()).is_none());
+    }
+
+    #[test]
+    fn project_onto_shape_measures_chainage_and_offset() {
+        let geometry = straight_geometry();
+        let segment_m = geometry.cumulative_m[1];
+        // Halfway along the second segment, about 11 m north of it.
+        let projection =
+            project_onto_shape(&geometry, 3.0001, 101.7075).expect("projected onto shape");
+        assert!((projection.chainage_m - segment_m * 1.5).abs() < 1.0);
+        assert!((projection.offset_m - 11.1).abs() < 0.5);
+    }
+
+    #[test]
+    fn project_onto_shape_clamps_to_the_shape_ends() {
+        let geometry = straight_geometry();
+        let before_start = project_onto_shape(&geometry, 3.0, 101.699).expect("projected");
+        assert!(before_start.chainage_m.abs() < 1e-6);
+        let past_end = project_onto_shape(&geometry, 3.0, 101.711).expect("projected");
+        let shape_length_m = *geometry.cumulative_m.last().unwrap();
+        assert!((past_end.chainage_m - shape_length_m).abs() < 1e-6);
+    }
+
+    #[test]
+    fn project_onto_shape_needs_a_segment() {
+        let mut geometry = straight_geometry();
+        geometry.points.truncate(1);
+        assert!(project_onto_shape(&geometry, 3.0, 101.7).is_none());
+    }
+
+    #[test]
+    fn data_age_weight_halves_every_half_life_after_a_fresh_fix() {
+        assert_eq!(data_age_weight(0), 1.0);
+        assert_eq!(data_age_weight(FRESH_FIX_MS), 1.0);
+        assert!((data_age_weight(FRESH_FIX_MS + STALE_FIX_HALF_LIFE_MS) - 0.5).abs() < 1e-9);
+        assert!((data_age_weight(FRESH_FIX_MS + 2 * STALE_FIX_HALF_LIFE_MS) - 0.25).abs() < 1e-9);
+    }
+
+    #[test]
+    fn apply_data_age_stamps_ages_and_counts_from_the_fix() {
+        let mut eta_results = vec![eta("stale", 60_000, 1.0), eta("untracked", 120_000, 1.0)];
+        for eta in &mut eta_results {
+            eta.data_weight = None;
+        }
+        let last_seen_ms = NOW_MS - 90_000;
+        let last_seen = HashMap::from([("stale".to_string(), last_seen_ms)]);
+        apply_data_age(&mut eta_results, &last_seen, NOW_MS, 300_000);
+
+        let stale = eta_results
+            .iter()
+            .find(|eta| eta.bus_no == "stale")
+            .unwrap();
+        assert_eq!(stale.data_age_seconds, Some(90.0));
+        assert_eq!(stale.data_weight, Some(0.25));
+        assert_eq!(stale.predicted_arrival_unix_ms, Some(last_seen_ms + 60_000));
+        assert_eq!(stale.expires_at_unix_ms, Some(last_seen_ms + 300_000));
+
+        let untracked = eta_results
+            .iter()
+            .find(|eta| eta.bus_no == "untracked")
+            .unwrap();
+        assert_eq!(untracked.data_weight, None);
+        assert_eq!(untracked.predicted_arrival_unix_ms, Some(NOW_MS + 120_000));
+    }
+
+    #[test]
+    fn apply_data_age_ranks_a_stale_bus_behind_a_live_one() {
+        // By its last fix the stale bus arrives a minute before the live one, but that fix is
+        // 90 s old.
+        let mut eta_results = vec![eta("stale", 150_000, 1.0), eta("live", 120_000, 1.0)];
+        let last_seen = HashMap::from([
+            ("stale".to_string(), NOW_MS - 90_000),
+            ("live".to_string(), NOW_MS),
+        ]);
+        apply_data_age(&mut eta_results, &last_seen, NOW_MS, 300_000);
+        assert_eq!(bus_order(&eta_results), ["live", "stale"]);
+    }
+
+    #[test]
+    fn weighted_arrival_orders_by_time_left() {
+        let mut eta_results = vec![eta("later", 300_000, 1.0), eta("sooner", 60_000, 1.0)];
+        sort_by_weighted_arrival(&mut eta_results, NOW_MS);
+        assert_eq!(bus_order(&eta_results), ["sooner", "later"]);
+    }
+
+    #[test]
+    fn skip_detoured_stops_drops_only_skipped_stops() {
+        let route = RouteStopsResponse {
+            next_cursor: Some("next".to_string()),
+            ..out_and_back_route()
+        };
+        let detour = RouteDetour {
+            id: "d1".to_string(),
+            route_id: "T789".to_string(),
+            skipped_stop_ids: vec!["A2".to_string(), "B2".to_string()],
+            shape: Vec::new(),
+            valid_from_unix_ms: NOW_MS,
+            valid_until_unix_ms: NOW_MS + 3_600_000,
+            message: "Road works".to_string(),
+            created_at_unix_ms: NOW_MS,
+        };
+        let served = skip_detoured_stops(&route, &[detour]);
+        let kept: Vec<(&str, u32)> = served
+            .stops
+            .iter()
+            .map(|stop| (stop.stop_id.as_str(), stop.sequence))
+            .collect();
+        assert_eq!(kept, [("A1", 1), ("A3", 3), ("B3", 4), ("B1", 6)]);
+        assert_eq!(served.next_cursor.as_deref(), Some("next"));
+    }
+
+    #[test]
+    fn skip_detoured_stops_without_detours_keeps_the_route() {
+        let route = out_and_back_route();
+        assert_eq!(
+            skip_detoured_stops(&route, &[]).stops.len(),
+            route.stops.len()
+        );
+    }
+}
@@ -0,0 +1,1278 @@
+// Request and response models shared by the rapidbro backend and its Rust consumers.
+// Field names are the wire format; renaming anything here is an API change.
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct BusPosition {
+    pub dt_received: Option<String>,
+    pub dt_gps: Option<String>,
+    pub latitude: f64,
+    pub longitude: f64,
+    pub dir: Option<String>,
+    pub speed: f64,
+    pub angle: f64,
+    pub route: String,
+    pub bus_no: String,
+    pub trip_no: Option<String>,
+    pub captain_id: Option<String>,
+    pub trip_rev_kind: Option<String>,
+    pub engine_status: i32,
+    pub accessibility: i32,
+    pub busstop_id: Option<String>,
+    pub provider: String,
+    // Normalized view of trip_no/trip_rev_kind, filled in at ingest time.
+    #[serde(default)]
+    pub trip: Option<TripMetadata>,
+    // Set at ingest when the position falls inside a configured depot geofence.
+    #[serde(default)]
+    pub in_depot: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub depot_name: Option<String>,
+    // Set at ingest when a service area is configured and the position falls outside it.
+    #[serde(default)]
+    pub outside_service_area: bool,
+    // Filled in from the motion state at read time: the bus has stayed outside the corridor
+    // around its route shape for several updates. Off-route buses get no ETAs.
+    #[serde(default)]
+    pub off_route: bool,
+    // Roster details merged in at response time; never stored with the position.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub vehicle: Option<VehicleInfo>,
+    // Freshness from the last_seen index, filled in at response time like vehicle. Once
+    // expires_in_seconds reaches 0 the bus is dropped on the next read.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_seen_unix_ms: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_in_seconds: Option<i64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct TripMetadata {
+    pub trip_no: Option<String>,
+    pub direction: TripDirection,
+    // Raw provider code, kept so unrecognised values can still be inspected.
+    pub trip_rev_kind: Option<String>,
+}
+
+// trip_rev_kind is undocumented upstream; the codes below are the forward/reverse spellings
+// observed in the feed, anything else is reported as unknown rather than guessed.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+#[serde(rename_all = "lowercase")]
+pub enum TripDirection {
+    Outbound,
+    Inbound,
+    Unknown,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct StopWithDetails {
+    pub stop_id: String,
+    // Public code printed on the stop sign; may differ from the internal stop_id.
+    #[serde(default)]
+    pub stop_code: Option<String>,
+    pub stop_name: String,
+    pub stop_desc: String,
+    pub stop_lat: f64,
+    pub stop_lon: f64,
+    pub sequence: u32,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct RouteStopsResponse {
+    pub route_id: String,
+    pub route_short_name: String,
+    pub route_long_name: String,
+    pub stops: Vec<StopWithDetails>,
+    // Pass back as ?cursor= for the next page; absent on the last page or without ?limit=.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub next_cursor: Option<String>,
+}
+
+// ?limit= and ?cursor= on list endpoints. Without either, the whole list comes back at once.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct PageQuery {
+    pub limit: Option<usize>,
+    pub cursor: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct RouteShapePoint {
+    pub lat: f64,
+    pub lon: f64,
+    pub sequence: u32,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct RouteShapeResponse {
+    pub route_id: String,
+    pub shape_id: String,
+    pub points: Vec<RouteShapePoint>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct NearestStopQuery {
+    pub lat: f64,
+    pub lon: f64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct NearestStopResponse {
+    pub stop_id: String,
+    // Public code printed on the stop sign; may differ from the internal stop_id.
+    #[serde(default)]
+    pub stop_code: Option<String>,
+    pub stop_name: String,
+    pub stop_desc: String,
+    pub stop_lat: f64,
+    pub stop_lon: f64,
+    pub distance_km: f64,
+    pub distance_meters: f64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub place: Option<PlaceContext>,
+}
+
+// Street context from the optional reverse geocoder, e.g. "Jalan Pantai Baharu" in "Bangsar".
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct PlaceContext {
+    pub street: Option<String>,
+    pub locality: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct StopRouteSummary {
+    pub route_id: String,
+    pub route_short_name: String,
+    pub route_long_name: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct StopRoutesResponse {
+    pub stop_id: String,
+    // Public code printed on the stop sign; may differ from the internal stop_id.
+    #[serde(default)]
+    pub stop_code: Option<String>,
+    pub routes: Vec<StopRouteSummary>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct ErrorResponse {
+    pub error: String,
+    // Per-parameter problems for 400s from request validation; empty otherwise.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub fields: Vec<FieldError>,
+}
+
+// Shape of every JSON response under API version 2. Bodies that already carried their own meta
+// keep it, with these fields added alongside.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct Envelope<T> {
+    pub data: T,
+    pub meta: ResponseMeta,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct ResponseMeta {
+    pub source: String,
+    pub generated_at_unix_ms: i64,
+    pub last_ingest_at_unix_ms: Option<i64>,
+    pub is_stale: bool,
+    #[serde(default)]
+    pub warming_up: bool,
+    // Changes whenever the static timetable is reloaded; a cache key for stop and route data.
+    pub gtfs_feed_version: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct FieldError {
+    pub field: String,
+    pub message: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+#[serde(rename_all = "lowercase")]
+pub enum StopResolutionSource {
+    Live,
+    Derived,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct BusEta {
+    pub route_id: String,
+    pub bus_no: String,
+    pub current_lat: f64,
+    pub current_lon: f64,
+    pub current_stop_id: String,
+    pub current_stop_name: String,
+    pub current_sequence: u32,
+    pub stop_resolution_source: StopResolutionSource,
+    pub stops_away: u32,
+    pub distance_km: f64,
+    pub speed_kmh: f64,
+    pub smoothed_speed_kmh: Option<f64>,
+    pub eta_minutes: f64,
+    pub predicted_crowding: PredictedCrowding,
+    pub predicted_crowding_source: String,
+    // Where the bus currently is, when a reverse geocoder is configured.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub place: Option<PlaceContext>,
+    // "runtime_profile" when eta_minutes comes from typical segment runtimes because the live
+    // speed was unusable; absent for the usual speed-based estimate.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub eta_source: Option<String>,
+    // Minutes until a bus laying over at the route's first stop is expected to leave it, already
+    // included in eta_minutes; eta_source then says whether the timetable or past layovers gave it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub departs_terminus_in_minutes: Option<f64>,
+    // eta_minutes counted from the fix it was computed from, as an absolute time. Unlike
+    // eta_minutes this is comparable across buses reported at different moments.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub predicted_arrival_unix_ms: Option<i64>,
+    // Seconds since the bus last reported a GPS fix, when known.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub data_age_seconds: Option<f64>,
+    // 1.0 for a fresh fix, decaying towards 0 as the fix ages; ordering divides eta_minutes by it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub data_weight: Option<f64>,
+    // After this instant the bus drops out of the live set and the prediction should be ignored.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_at_unix_ms: Option<i64>,
+    // Route name, colors and headsign so a marker can be drawn without a route lookup.
+    #[serde(default, flatten, skip_serializing_if = "Option::is_none")]
+    pub route_display: Option<RouteDisplay>,
+}
+
+// GTFS route metadata joined onto live bus and ETA rows at response time. Colors are "#RRGGBB",
+// falling back to the GTFS defaults (white route, black text) when the feed leaves them blank.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct RouteDisplay {
+    pub route_short_name: String,
+    pub route_long_name: String,
+    pub route_color: String,
+    pub route_text_color: String,
+    pub headsign: String,
+}
+
+// Coarse occupancy guess, always reported alongside predicted_crowding_source = "heuristic"
+// until the feed carries real passenger counts.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+#[serde(rename_all = "lowercase")]
+pub enum PredictedCrowding {
+    Low,
+    Medium,
+    High,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct IngestorStatus {
+    pub connected: bool,
+    pub reconnect_count: u64,
+    pub messages_processed: u64,
+    pub buses_written: u64,
+    pub decode_failures: u64,
+    pub redis_write_failures: u64,
+    // Reports collapsed into another vehicle by bus_no reconciliation.
+    #[serde(default)]
+    pub duplicate_buses_merged: u64,
+    #[serde(default)]
+    pub outside_service_area_positions: u64,
+    // Buses seeded from GTFS-RT vehicle positions while the snapshot was empty.
+    #[serde(default)]
+    pub seeded_buses: u64,
+    #[serde(default)]
+    pub last_seeded_unix_ms: Option<i64>,
+    // Redis write chunks retried after a transient failure, and ones lost after every retry.
+    #[serde(default)]
+    pub write_retries: u64,
+    #[serde(default)]
+    pub write_chunks_failed: u64,
+    #[serde(default)]
+    pub buses_write_failed: u64,
+    // Buses skipped because their position or motion state would not serialize.
+    #[serde(default)]
+    pub serialize_failures: u64,
+    pub last_message_unix_ms: Option<i64>,
+    pub last_error: Option<String>,
+    // Current wait between onFts-reload requests; adapts to how the feed behaves. None while
+    // disconnected.
+    #[serde(default)]
+    pub reload_interval_seconds: Option<u64>,
+    // Meters of each ingest pipeline stage, in pipeline order.
+    #[serde(default)]
+    pub stages: Vec<IngestStageStats>,
+}
+
+// Published on the "{prefix}:ingest:events" Redis channel after every stored batch, so other
+// services can react to new positions instead of polling.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct IngestEvent {
+    pub snapshot_seq: u64,
+    pub written_at_unix_ms: i64,
+    pub bus_nos: Vec<String>,
+    // Buses in the batch per route code.
+    pub route_counts: std::collections::BTreeMap<String, usize>,
+}
+
+// Emitted once when a bus laying over at its route's first stop pulls away: appended to the bus
+// event stream (and so relayed to the bus event webhook) and published on the
+// "{prefix}:events:trips" Redis channel.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct TripStartedEvent {
+    pub bus_no: String,
+    pub route_id: String,
+    pub direction: TripDirection,
+    pub trip_no: Option<String>,
+    pub shape_id: String,
+    pub terminus_stop_id: String,
+    pub started_at_unix_ms: i64,
+    // Predicted arrivals at the stops ahead, in route order.
+    pub timeline: Vec<TripTimelineStop>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct TripTimelineStop {
+    pub stop_id: String,
+    pub stop_name: String,
+    pub sequence: u32,
+    pub eta_minutes: f64,
+    pub predicted_arrival_unix_ms: i64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct IngestStageStats {
+    pub name: String,
+    pub runs: u64,
+    pub failures: u64,
+    // Reports the stage removed from batches, e.g. merged duplicates or invalid reports.
+    pub buses_dropped: u64,
+    pub total_us: u64,
+    pub last_us: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct GetAllMeta {
+    pub source: String,
+    pub last_ingest_at_unix_ms: Option<i64>,
+    pub is_stale: bool,
+    pub active_bus_count: usize,
+    // Set shortly after a server start, while motion state and history are still filling in.
+    #[serde(default)]
+    pub warming_up: bool,
+    // Pass back as ?since_snapshot_seq= to receive only what changed. None in fixture mode.
+    // Every page of a paginated read reports the sequence of its first page, so syncing from it
+    // once the last page is in picks up whatever changed in between.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub snapshot_seq: Option<u64>,
+    // True when data holds only buses changed since the requested sequence.
+    #[serde(default)]
+    pub is_delta: bool,
+    // How far the Redis replica this response was read from trails the primary.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub replica_lag_ms: Option<i64>,
+    // Pass back as ?cursor= for the next page; absent on the last page or without ?limit=.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub next_cursor: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct GetAllResponse {
+    pub data: Vec<BusPosition>,
+    // Buses gone from the fleet since the requested sequence; only set on delta replies.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub removed_bus_nos: Vec<String>,
+    pub meta: GetAllMeta,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct GetAllQuery {
+    pub since_snapshot_seq: Option<u64>,
+    // Pages through full replies only, in bus_no order.
+    pub limit: Option<usize>,
+    pub cursor: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct RouteBusPositionResponse {
+    #[serde(flatten)]
+    pub bus: BusPosition,
+    pub resolved_stop_id: Option<String>,
+    pub resolved_stop_name: Option<String>,
+    pub resolved_stop_sequence: Option<u32>,
+    pub stop_resolution_source: Option<StopResolutionSource>,
+    pub smoothed_speed_kmh: Option<f64>,
+    pub chainage_m: Option<f64>,
+    pub progress_percent: Option<f64>,
+    #[serde(default, flatten, skip_serializing_if = "Option::is_none")]
+    pub route_display: Option<RouteDisplay>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct RouteGroupLiveResponse {
+    pub group: String,
+    pub route_ids: Vec<String>,
+    pub active_bus_count: usize,
+    pub data: Vec<RouteBusPositionResponse>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct RouteGroupEtaResponse {
+    pub group: String,
+    pub route_ids: Vec<String>,
+    pub stop_id: String,
+    pub data: Vec<BusEta>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub messages: Vec<RiderMessage>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct RouteStopEta {
+    pub stop_id: String,
+    pub data: Vec<BusEta>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct RouteMultiStopEtaResponse {
+    pub route_id: String,
+    // One entry per requested stop, in request order.
+    pub stops: Vec<RouteStopEta>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub messages: Vec<RiderMessage>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct RouteServiceToday {
+    pub route_id: String,
+    pub route_short_name: String,
+    pub running_today: bool,
+    // What the regular weekly calendar says for today, before any holiday exceptions.
+    pub runs_normally: bool,
+    pub service_ids: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct ServiceTodayResponse {
+    // Service day in Kuala Lumpur time, YYYY-MM-DD.
+    pub date: String,
+    pub weekday: String,
+    // Set when calendar_dates.txt adds or removes any service today, e.g. a public holiday.
+    pub is_holiday_schedule: bool,
+    pub added_service_ids: Vec<String>,
+    pub removed_service_ids: Vec<String>,
+    pub running_route_count: usize,
+    pub changed_route_count: usize,
+    pub routes: Vec<RouteServiceToday>,
+}
+
+// How confidently a stop board can promise a bus. ETAs on stale data or beyond the incoming
+// horizon don't count, so a stop can list buses and still be none.
+// Stable codes for caveats a frontend should show next to ETAs; message is a default English
+// wording for clients that don't localize the code.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum RiderMessageCode {
+    DataDelayed,
+    LowConfidence,
+    LastBusDeparted,
+    ScheduledHeadwayOnly,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct RiderMessage {
+    pub code: RiderMessageCode,
+    pub message: String,
+    // Routes the message is about; empty when it covers the whole response.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub route_ids: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+#[serde(rename_all = "snake_case")]
+pub enum IncomingStatus {
+    ArrivingSoon,
+    Incoming,
+    #[default]
+    None,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct StopIncomingMeta {
+    pub source: String,
+    pub generated_at_unix_ms: i64,
+    // Set when the response was rebuilt from history for a past instant.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub as_of_unix_ms: Option<i64>,
+    pub last_ingest_at_unix_ms: Option<i64>,
+    pub is_stale: bool,
+    pub active_bus_count: usize,
+    pub incoming_bus_count: usize,
+    // incoming_status is anything but none; kept for older clients.
+    pub has_incoming_buses: bool,
+    #[serde(default)]
+    pub incoming_status: IncomingStatus,
+    // Set shortly after a server start; early ETAs run on thin motion state.
+    #[serde(default)]
+    pub warming_up: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub replica_lag_ms: Option<i64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct StopIncomingResponse {
+    pub stop_id: String,
+    // Public code printed on the stop sign; may differ from the internal stop_id.
+    #[serde(default)]
+    pub stop_code: Option<String>,
+    pub stop_name: String,
+    pub stop_desc: String,
+    pub data: Vec<BusEta>,
+    pub recent_departures: Vec<RecentDeparture>,
+    // Active detours on routes calling here; buses on those routes may not serve this stop.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub detours: Vec<DetourNotice>,
+    // Set while the stop is closed; data is then empty.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub closure: Option<StopClosureNotice>,
+    // Caveats to show with the ETAs, e.g. DATA_DELAYED while positions are stale.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub messages: Vec<RiderMessage>,
+    // Frequency-based routes at the stop with no live bus due, with the headway scheduled now.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub scheduled_headways: Vec<ScheduledHeadway>,
+    pub meta: StopIncomingMeta,
+}
+
+// One frequencies.txt window: a bus every headway_minutes between start_time and end_time.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct ScheduledHeadway {
+    pub route_id: String,
+    #[serde(default)]
+    pub direction_id: Option<u32>,
+    // GTFS times of the service day, HH:MM:SS; may pass 24:00:00.
+    pub start_time: String,
+    pub end_time: String,
+    pub headway_minutes: f64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct RouteHeadwaysResponse {
+    pub route_id: String,
+    // YYYY-MM-DD, Kuala Lumpur time.
+    pub service_date: String,
+    // Ordered by start_time; empty for a route run to a timetable rather than a frequency.
+    pub headways: Vec<ScheduledHeadway>,
+}
+
+// Why the loader skipped or dropped a row of the static feed.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum GtfsIssueKind {
+    MalformedRow,
+    DuplicateId,
+    InvalidCoordinates,
+    // The row refers to an id its table doesn't have, e.g. a stop_times.txt stop_id missing
+    // from stops.txt.
+    MissingReference,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct GtfsValidationIssue {
+    pub kind: GtfsIssueKind,
+    // CSV line number, header included; absent when the issue came from joining tables.
+    #[serde(default)]
+    pub line: Option<u64>,
+    #[serde(default)]
+    pub id: Option<String>,
+    pub message: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct GtfsFileValidation {
+    // File name; an extra provider's feed files read "{provider}/stop_times.txt".
+    pub file: String,
+    pub issue_count: u64,
+    // The first issues found, in file order; issue_count covers them all.
+    pub issues: Vec<GtfsValidationIssue>,
+}
+
+// What was wrong with the feed currently served. Rows listed here were left out and the rest of
+// the feed loaded without them.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct GtfsValidationReport {
+    pub feed_version: String,
+    pub issue_count: u64,
+    // Only files with issues, by name.
+    pub files: Vec<GtfsFileValidation>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct RecentDeparture {
+    pub route_id: String,
+    pub bus_no: String,
+    pub departed_at_unix_ms: i64,
+    pub minutes_ago: f64,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+#[serde(rename_all = "snake_case")]
+pub enum IncidentKind {
+    ActiveBusDrop,
+    DecodeFailureSpike,
+    IdenticalCoordinates,
+    RouteDeviation,
+}
+
+impl IncidentKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            IncidentKind::ActiveBusDrop => "active_bus_drop",
+            IncidentKind::DecodeFailureSpike => "decode_failure_spike",
+            IncidentKind::IdenticalCoordinates => "identical_coordinates",
+            IncidentKind::RouteDeviation => "route_deviation",
+        }
+    }
+}
+
+// A feed anomaly; open while resolved_at_unix_ms is None.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct Incident {
+    pub id: String,
+    pub kind: IncidentKind,
+    pub message: String,
+    pub opened_at_unix_ms: i64,
+    pub last_detected_at_unix_ms: i64,
+    pub resolved_at_unix_ms: Option<i64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct IncidentsResponse {
+    pub data: Vec<Incident>,
+}
+
+// Static fleet details from the optional vehicle roster CSV, keyed by canonical bus_no.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct VehicleInfo {
+    pub model: Option<String>,
+    pub depot: Option<String>,
+    pub capacity: Option<u32>,
+    pub wheelchair_lift: Option<bool>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct FleetVehicle {
+    pub bus_no: String,
+    pub vehicle: VehicleInfo,
+    pub active: bool,
+    pub route: Option<String>,
+    pub latitude: Option<f64>,
+    pub longitude: Option<f64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct FleetResponse {
+    pub data: Vec<FleetVehicle>,
+    pub roster_size: usize,
+    // Across every page, not just this one.
+    pub active_count: usize,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub next_cursor: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct FleetQuery {
+    pub depot: Option<String>,
+    pub wheelchair_lift: Option<bool>,
+    pub active: Option<bool>,
+    // Pages in bus_no order.
+    pub limit: Option<usize>,
+    pub cursor: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct InDepotResponse {
+    pub data: Vec<BusPosition>,
+    pub count_by_depot: std::collections::BTreeMap<String, usize>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct DwellBucket {
+    pub label: String,
+    pub count: u64,
+}
+
+// Dwell events at a stop within one local (Kuala Lumpur) hour of day, across all days recorded.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct DwellHourStats {
+    pub hour: u32,
+    pub event_count: u64,
+    pub mean_dwell_seconds: f64,
+    pub buckets: Vec<DwellBucket>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct DwellStatsResponse {
+    pub stop_id: String,
+    // Public code printed on the stop sign; may differ from the internal stop_id.
+    #[serde(default)]
+    pub stop_code: Option<String>,
+    pub total_events: u64,
+    pub hours: Vec<DwellHourStats>,
+    // Always "dwell_proxy": dwell time stands in for boarding activity, no passenger counts.
+    pub source: String,
+}
+
+// A route stop considered when placing a bus, by straight-line distance from its position.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct StopCandidate {
+    pub stop_id: String,
+    pub stop_name: String,
+    pub sequence: u32,
+    pub distance_m: f64,
+    // Bus heading versus the route's direction of travel at this stop; absent when the bus was
+    // too slow for its heading to count.
+    #[serde(default)]
+    pub heading_delta_deg: Option<f64>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+#[serde(rename_all = "snake_case")]
+pub enum StopResolutionDecision {
+    // The feed's busstop_id is a stop of the route.
+    Live,
+    // Nearest route stop, within max_distance_m.
+    Derived,
+    // Even the nearest route stop is beyond max_distance_m; the bus gets no ETA on this route.
+    TooFar,
+    NoStops,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct StopResolutionRecord {
+    pub at_unix_ms: i64,
+    pub route_id: String,
+    pub latitude: f64,
+    pub longitude: f64,
+    // busstop_id as reported by the feed, when it had one.
+    #[serde(default)]
+    pub reported_stop_id: Option<String>,
+    pub decision: StopResolutionDecision,
+    #[serde(default)]
+    pub resolved_stop_id: Option<String>,
+    pub max_distance_m: f64,
+    // Nearest first.
+    pub candidates: Vec<StopCandidate>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct StopResolutionLogResponse {
+    pub bus_no: String,
+    // Oldest first.
+    pub records: Vec<StopResolutionRecord>,
+}
+
+// Timed runs within one local (Kuala Lumpur) hour of day, by when the run started.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct RuntimeHourStats {
+    pub hour: u32,
+    pub sample_count: u64,
+    pub mean_runtime_seconds: f64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct SegmentRuntimeProfile {
+    pub from_stop_id: String,
+    pub to_stop_id: String,
+    pub hours: Vec<RuntimeHourStats>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct RouteRuntimesResponse {
+    pub route_id: String,
+    // First stop to last stop of the route shape.
+    pub trip: Vec<RuntimeHourStats>,
+    // Consecutive stop pairs, in route order when served by the API.
+    pub segments: Vec<SegmentRuntimeProfile>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum SearchResult {
+    Route {
+        route_id: String,
+        route_short_name: String,
+        route_long_name: String,
+        score: u32,
+    },
+    Stop {
+        stop_id: String,
+        stop_code: Option<String>,
+        stop_name: String,
+        stop_desc: String,
+        stop_lat: f64,
+        stop_lon: f64,
+        score: u32,
+    },
+}
+
+impl SearchResult {
+    pub fn score(&self) -> u32 {
+        match self {
+            SearchResult::Route { score, .. } | SearchResult::Stop { score, .. } => *score,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct SearchQuery {
+    pub q: String,
+    pub limit: Option<usize>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct SearchResponse {
+    pub query: String,
+    pub results: Vec<SearchResult>,
+}
+
+// One Kuala Lumpur service day of observed operations per route, built from snapshot history.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct DailyRouteReport {
+    // YYYY-MM-DD
+    pub date: String,
+    pub generated_at_unix_ms: i64,
+    // History frames the report was built from; a low count means gaps in coverage.
+    pub frames_sampled: usize,
+    pub routes: Vec<RouteDayStats>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct RouteDayStats {
+    pub route: String,
+    // Hours of the day in which at least one bus reported on the route.
+    pub service_hours_observed: u32,
+    pub buses_deployed: usize,
+    pub km_operated: f64,
+    // Hours in which trips started from the first stop, ascending.
+    pub hourly: Vec<RouteHourStats>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct RouteHourStats {
+    pub hour: u32,
+    pub trips_started: usize,
+    // Mean gap before each trip started in the hour; None when no earlier trip that day.
+    pub average_headway_minutes: Option<f64>,
+}
+
+// Whether a scheduled trip was run, judged by trip starts observed in snapshot history.
+// Upcoming trips are not yet past their matching window and count as neither.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+#[serde(rename_all = "snake_case")]
+pub enum TripDeliveryStatus {
+    Operated,
+    Missed,
+    Upcoming,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct ScheduledTripDelivery {
+    pub trip_id: String,
+    pub direction_id: Option<u32>,
+    pub headsign: Option<String>,
+    pub scheduled_start_unix_ms: i64,
+    pub status: TripDeliveryStatus,
+    // Set when operated: the matched start from the first stop and the bus that ran it.
+    pub observed_start_unix_ms: Option<i64>,
+    // Positive when the trip started late.
+    pub start_deviation_minutes: Option<f64>,
+    pub bus_no: Option<String>,
+}
+
+// Scheduled trips of one route on one Kuala Lumpur service day against what buses ran.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct ServiceDeliveryResponse {
+    pub route_id: String,
+    // YYYY-MM-DD
+    pub date: String,
+    pub generated_at_unix_ms: i64,
+    pub scheduled_trip_count: usize,
+    pub operated_count: usize,
+    pub missed_count: usize,
+    // Operated share of trips already due; None until the first trip is due.
+    pub delivery_ratio: Option<f64>,
+    // In scheduled start order.
+    pub trips: Vec<ScheduledTripDelivery>,
+}
+
+// Request counts summed over the last `days` KL days, busiest first. Counts are aggregated at
+// the server; nothing about the caller is recorded.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct UsageResponse {
+    pub days: u32,
+    // Keyed by route pattern, e.g. /v1/stops/{stop_id}/eta.
+    pub endpoints: Vec<UsageCount>,
+    pub stops: Vec<UsageCount>,
+    pub routes: Vec<UsageCount>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct UsageCount {
+    pub key: String,
+    pub count: u64,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+#[serde(rename_all = "snake_case")]
+pub enum BusStatus {
+    Active,
+    // Dropped out of the feed; data is the last position seen.
+    Inactive,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct BusResponse {
+    pub bus_no: String,
+    pub status: BusStatus,
+    pub last_seen_unix_ms: Option<i64>,
+    // Set once the bus expired from the live fleet.
+    pub removed_at_unix_ms: Option<i64>,
+    pub data: BusPosition,
+}
+
+// Startup runs these in order; the listener binds once the ingestor has started, so a running
+// server only ever reports warming_up or ready.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+#[serde(rename_all = "snake_case")]
+pub enum StartupPhase {
+    LoadingConfig,
+    LoadingGtfs,
+    VerifyingRedis,
+    StartingIngestor,
+    WarmingUp,
+    Ready,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct StartupPhaseTiming {
+    pub phase: StartupPhase,
+    pub completed_at_unix_ms: i64,
+    pub duration_ms: i64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct ReadinessResponse {
+    pub phase: StartupPhase,
+    pub ready: bool,
+    // Live endpoints answer 503 until warmup ends; GTFS-only endpoints are already served.
+    pub gtfs_only: bool,
+    pub phases: Vec<StartupPhaseTiming>,
+    // Every city served, the default one first; the response is ready only once they all are.
+    #[serde(default)]
+    pub cities: Vec<CityReadiness>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct CityReadiness {
+    pub city_id: String,
+    pub phase: StartupPhase,
+    pub ready: bool,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct DetourPoint {
+    pub lat: f64,
+    pub lon: f64,
+}
+
+// A temporary diversion declared by an operator: between valid_from and valid_until the route
+// does not call at skipped_stop_ids, and when a shape is given buses follow it around them.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct RouteDetour {
+    pub id: String,
+    pub route_id: String,
+    pub skipped_stop_ids: Vec<String>,
+    // From the last stop served before the skipped ones to the first stop served after them.
+    #[serde(default)]
+    pub shape: Vec<DetourPoint>,
+    pub valid_from_unix_ms: i64,
+    pub valid_until_unix_ms: i64,
+    pub message: String,
+    pub created_at_unix_ms: i64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct DetourRequest {
+    pub skipped_stop_ids: Vec<String>,
+    #[serde(default)]
+    pub shape: Vec<DetourPoint>,
+    // Defaults to now.
+    #[serde(default)]
+    pub valid_from_unix_ms: Option<i64>,
+    pub valid_until_unix_ms: i64,
+    pub message: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct RouteDetoursResponse {
+    pub route_id: String,
+    pub detours: Vec<RouteDetour>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct DetourNotice {
+    pub detour_id: String,
+    pub route_id: String,
+    pub message: String,
+    // True when this stop is one the detour skips.
+    pub stop_skipped: bool,
+    pub valid_until_unix_ms: i64,
+}
+
+// A planned closure: between valid_from and valid_until no bus calls at the stop.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct StopClosure {
+    pub id: String,
+    pub stop_id: String,
+    pub stop_name: String,
+    pub valid_from_unix_ms: i64,
+    pub valid_until_unix_ms: i64,
+    pub message: String,
+    pub created_at_unix_ms: i64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct StopClosureRequest {
+    // Defaults to now.
+    #[serde(default)]
+    pub valid_from_unix_ms: Option<i64>,
+    pub valid_until_unix_ms: i64,
+    pub message: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct StopClosuresResponse {
+    pub stop_id: String,
+    pub closures: Vec<StopClosure>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct NearbyStop {
+    pub stop_id: String,
+    #[serde(default)]
+    pub stop_code: Option<String>,
+    pub stop_name: String,
+    pub distance_meters: f64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+pub struct StopClosureNotice {
+    pub closure_id: String,
+    pub message: String,
+    pub valid_until_unix_ms: i64,
+    // Open stops nearby, closest first.
+    pub alternatives: Vec<NearbyStop>,
+}
+
+// JSON Schema for every model above by type name, for generating typed clients in other
+// languages. Envelope is generic and left out; its data is one of the listed types.
+#[cfg(feature = "schemars")]
+macro_rules! schema_registry {
+    ($($name:ident),* $(,)?) => {
+        pub const SCHEMA_TYPE_NAMES: &[&str] = &[$(stringify!($name)),*];
+
+        pub fn json_schema(type_name: &str) -> Option<schemars::schema::RootSchema> {
+            match type_name {
+                $(stringify!($name) => Some(schemars::schema_for!($name)),)*
+                _ => None,
+            }
+        }
+    };
+}
+
+#[cfg(feature = "schemars")]
+schema_registry!(
+    BusPosition,
+    TripMetadata,
+    TripDirection,
+    StopWithDetails,
+    RouteStopsResponse,
+    PageQuery,
+    RouteShapePoint,
+    RouteShapeResponse,
+    NearestStopQuery,
+    NearestStopResponse,
+    PlaceContext,
+    StopRouteSummary,
+    StopRoutesResponse,
+    ErrorResponse,
+    ResponseMeta,
+    FieldError,
+    StopResolutionSource,
+    BusEta,
+    RouteDisplay,
+    PredictedCrowding,
+    IngestorStatus,
+    IngestStageStats,
+    IngestEvent,
+    TripStartedEvent,
+    TripTimelineStop,
+    GetAllMeta,
+    GetAllResponse,
+    GetAllQuery,
+    RouteBusPositionResponse,
+    RouteGroupLiveResponse,
+    RouteGroupEtaResponse,
+    RouteStopEta,
+    RouteMultiStopEtaResponse,
+    RouteServiceToday,
+    ServiceTodayResponse,
+    IncomingStatus,
+    RiderMessageCode,
+    RiderMessage,
+    StopIncomingMeta,
+    StopIncomingResponse,
+    ScheduledHeadway,
+    RouteHeadwaysResponse,
+    GtfsIssueKind,
+    GtfsValidationIssue,
+    GtfsFileValidation,
+    GtfsValidationReport,
+    RecentDeparture,
+    IncidentKind,
+    Incident,
+    IncidentsResponse,
+    VehicleInfo,
+    FleetVehicle,
+    FleetResponse,
+    FleetQuery,
+    InDepotResponse,
+    DwellBucket,
+    DwellHourStats,
+    DwellStatsResponse,
+    StopCandidate,
+    StopResolutionDecision,
+    StopResolutionRecord,
+    StopResolutionLogResponse,
+    RuntimeHourStats,
+    SegmentRuntimeProfile,
+    RouteRuntimesResponse,
+    SearchResult,
+    SearchQuery,
+    SearchResponse,
+    DailyRouteReport,
+    RouteDayStats,
+    RouteHourStats,
+    TripDeliveryStatus,
+    ScheduledTripDelivery,
+    ServiceDeliveryResponse,
+    UsageResponse,
+    UsageCount,
+    BusStatus,
+    BusResponse,
+    StartupPhase,
+    StartupPhaseTiming,
+    ReadinessResponse,
+    CityReadiness,
+    DetourPoint,
+    RouteDetour,
+    DetourRequest,
+    RouteDetoursResponse,
+    DetourNotice,
+    StopClosure,
+    StopClosureRequest,
+    StopClosuresResponse,
+    NearbyStop,
+    StopClosureNotice,
+);
@@ -0,0 +1,17 @@
+#!/usr/bin/env bash
+# Builds the ETA engine for the browser. Output is an ES module plus .wasm under
+# crates/rapidbro-eta-wasm/pkg (or the directory given as the first argument).
+#
+#   scripts/build-eta-wasm.sh
+#   scripts/build-eta-wasm.sh ../fe/src/generated/rapidbro-eta
+set -euo pipefail
+
+cd "$(dirname "$0")/.."
+OUT_DIR=$(realpath -m "${1:-crates/rapidbro-eta-wasm/pkg}")
+
+if ! command -v wasm-pack >/dev/null; then
+  echo "wasm-pack is required: cargo install wasm-pack" >&2
+  exit 1
+fi
+
+wasm-pack build crates/rapidbro-eta-wasm --release --target web --out-dir "$OUT_DIR"
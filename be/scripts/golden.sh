@@ -0,0 +1,62 @@
+#!/usr/bin/env bash
+# Golden-file check for the public JSON API. Starts the backend in fixture mode (fixed snapshot,
+# fixed clock, no Redis) and compares key endpoints against fixtures/golden/*.json.
+#
+#   scripts/golden.sh            compare, exit non-zero on any difference
+#   scripts/golden.sh --update   rewrite the golden files from the current build
+set -euo pipefail
+
+cd "$(dirname "$0")/.."
+GOLDEN_DIR=fixtures/golden
+BIND_ADDR=127.0.0.1:3939
+BASE_URL="http://${BIND_ADDR}/v1"
+UPDATE=0
+[[ "${1:-}" == "--update" ]] && UPDATE=1
+
+# name|path
+ENDPOINTS=(
+  "get-all|/get-all"
+  "stop-eta-1000838|/stops/1000838/eta"
+  "stop-eta-1008485|/stops/1008485/eta"
+  "route-eta-t7890-1000838|/route/T7890/eta/1000838"
+  "route-stops-t7890|/route/T7890/stops"
+  "stop-routes-1000838|/stops/1000838/routes"
+  "nearest-stop|/stops/nearest?lat=3.1219&lon=101.6572"
+)
+
+cargo build --quiet
+# Optional enrichments read their own env vars; unset them so only the fixture shapes output.
+env -u REVERSE_GEOCODE_PATH -u REVERSE_GEOCODE_URL -u VEHICLE_ROSTER_PATH \
+  -u DEPOT_GEOFENCE_PATH -u SERVICE_AREA_PATH -u CAPTAIN_ID_PRIVACY \
+  FIXTURE_SNAPSHOT_PATH=fixtures/snapshot.json \
+  FIXTURE_NOW_MS=1772409600000 \
+  BIND_ADDR="$BIND_ADDR" \
+  ./target/debug/be >/tmp/rapidbro-golden.log 2>&1 &
+SERVER_PID=$!
+trap 'kill "$SERVER_PID" 2>/dev/null || true' EXIT
+
+for _ in $(seq 1 50); do
+  curl -sf "${BASE_URL}/get-all" >/dev/null && break
+  sleep 0.2
+done
+
+mkdir -p "$GOLDEN_DIR"
+failed=0
+for entry in "${ENDPOINTS[@]}"; do
+  name="${entry%%|*}"
+  path="${entry#*|}"
+  actual=$(curl -s "${BASE_URL}${path}" | jq -S .)
+  golden="${GOLDEN_DIR}/${name}.json"
+  if [[ $UPDATE -eq 1 ]]; then
+    printf '%s\n' "$actual" >"$golden"
+    echo "updated ${golden}"
+  elif [[ ! -f "$golden" ]]; then
+    echo "missing ${golden}; run with --update to create it"
+    failed=1
+  elif ! diff -u "$golden" <(printf '%s\n' "$actual"); then
+    echo "mismatch: ${path}"
+    failed=1
+  fi
+done
+
+exit "$failed"
@@ -0,0 +1,6049 @@
+// HTTP handlers, extractors and middleware.
+
+use axum::{
+    extract::{
+        DefaultBodyLimit, FromRequestParts, MatchedPath, Path, Query, RawPathParams, Request, State,
+    },
+    http::{
+        header::{
+            ACCEPT, AUTHORIZATION, CACHE_CONTROL, CONTENT_DISPOSITION, CONTENT_LENGTH,
+            CONTENT_TYPE, ETAG, IF_NONE_MATCH, LINK, RETRY_AFTER, VARY, WWW_AUTHENTICATE,
+        },
+        request::Parts,
+        HeaderMap, HeaderName, HeaderValue, StatusCode,
+    },
+    middleware::{self, Next},
+    response::{Html, IntoResponse, Response},
+    routing::{delete, get, post},
+    Extension, Json, Router,
+};
+use base64::Engine;
+use chrono::{FixedOffset, NaiveDate, TimeZone};
+use hmac::{Hmac, Mac};
+use rapidbro_eta::{
+    apply_data_age, apply_runtime_profile, calculate_route_eta_for_stops,
+    calculate_route_eta_from_stops, filter_non_stationary_buses, haversine_distance,
+    incoming_status, is_bus_on_route, normalize_route_code, resolve_current_stop,
+    sort_by_weighted_arrival, EtaModel, RouteGeometry, Thresholds, KL_UTC_OFFSET_SECONDS,
+    MODEL_VERSION,
+};
+use rapidbro_types::{
+    json_schema, BusEta, BusPosition, BusResponse, BusStatus, CityReadiness, DailyRouteReport,
+    DetourRequest, DwellBucket, DwellHourStats, DwellStatsResponse, ErrorResponse, FieldError,
+    FleetQuery, FleetResponse, FleetVehicle, GetAllMeta, GetAllQuery, GetAllResponse,
+    GtfsValidationReport, InDepotResponse, IncidentsResponse, IncomingStatus, IngestorStatus,
+    NearestStopQuery, NearestStopResponse, PageQuery, PlaceContext, ReadinessResponse,
+    ResponseMeta, RouteBusPositionResponse, RouteDetour, RouteDetoursResponse,
+    RouteGroupEtaResponse, RouteGroupLiveResponse, RouteHeadwaysResponse,
+    RouteMultiStopEtaResponse, RouteRuntimesResponse, RouteServiceToday, RouteStopEta,
+    RouteStopsResponse, ScheduledTripDelivery, SearchQuery, SearchResponse, SearchResult,
+    ServiceDeliveryResponse, ServiceTodayResponse, StartupPhase, StartupPhaseTiming, StopClosure,
+    StopClosureNotice, StopClosureRequest, StopClosuresResponse, StopIncomingMeta,
+    StopIncomingResponse, StopResolutionLogResponse, StopResolutionRecord, StopRoutesResponse,
+    TripDeliveryStatus, UsageResponse, SCHEMA_TYPE_NAMES,
+};
+use sentry::SentryFutureExt;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::json;
+use sha2::Sha256;
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::fs::File;
+use std::sync::{
+    atomic::{AtomicU64, AtomicUsize, Ordering as AtomicOrdering},
+    Arc,
+};
+use std::time::{Duration, Instant};
+use tokio::{
+    sync::{RwLock, Semaphore},
+    time::MissedTickBehavior,
+};
+
+use crate::eta::{
+    apply_route_detours, apply_thresholds_patch, calculate_stop_eta_from_snapshot, detour_notices,
+    eta_stops_by_route, record_shadow_predictions, record_stop_resolutions,
+    runtime_profile_eta_minutes, score_shadow_predictions, segment_runtimes_for_hour,
+    terminus_departure_etas, DWELL_BUCKETS,
+};
+use crate::gtfs::{
+    cached_stops_by_route, city_gtfs_feed_version, current_gtfs_feed, find_nearest_stop,
+    find_route, get_routes_for_stop, get_shape_by_route, is_regular_service_active,
+    is_service_active, is_t789_route, kl_hour_of_day, load_gtfs_context, nearby_open_stops,
+    resolve_stop_key, route_display, route_headways, scheduled_departures_for_stop,
+    scheduled_trip_starts_for_route, LoadError, ScheduledDeparture, ScheduledTripStart,
+};
+use crate::ingest::{
+    apply_captain_id_privacy, attach_bus_freshness, attach_vehicle_info, canonical_bus_no,
+    gtfs_rt_refresh_seconds, is_warming_up,
+};
+use crate::messages::{
+    departed_route_ids, rider_messages, rider_signals, scheduled_headways_without_buses,
+};
+use crate::models::{
+    DeadLetterSample, FeatureFlags, GeocodedPlace, GtfsContext, GtfsFeed, HotStopBoard,
+    LatencyHistogram, PositionPrecision, RedactionProfile, RedisBusSnapshot, RedisStateSnapshot,
+    ResponseShape, ServiceAlert, ShadowStats, SnapshotImportResponse, Stop, ThresholdsPatch,
+};
+use crate::store::{
+    active_stop_closures, daily_route_report_csv, export_redis_state, import_redis_state, kl_date,
+    kl_day_bounds_ms, load_active_bus_snapshot, load_activity, load_bus_changes_since,
+    load_bus_snapshot_as_of, load_bus_tombstone, load_daily_route_report, load_incidents,
+    load_last_ingest_at, load_observed_trip_starts, load_recent_departures, load_route_runtimes,
+    load_snapshot_seq, load_usage, read_redis_client, replica_is_behind, ObservedTripStart,
+    ACTIVITY_RETENTION_HOURS, DELTA_SYNC_MAX_LAG_SEQS, REDIS_DETOURS_KEY, REDIS_FEATURE_FLAGS_KEY,
+    REDIS_ROUTES_LAST_SEEN_KEY, REDIS_SERVICE_ALERTS_KEY, REDIS_STOP_CLOSURES_KEY,
+    REDIS_STOP_DWELL_KEY_PREFIX, REDIS_THRESHOLDS_KEY, USAGE_FLUSH_SECONDS, USAGE_RETENTION_DAYS,
+};
+use crate::{env_or, load_persisted_thresholds, now_unix_ms, AppState};
+
+// Bounds concurrent work on the Redis- and GTFS-heavy endpoints: up to `permits` requests run
+// at once, up to `max_queued` more wait briefly for a slot, and anything beyond that is turned
+// away with a 503 rather than piling onto the Redis connection.
+#[derive(Debug, Clone)]
+pub(crate) struct LoadShedder {
+    permits: Arc<Semaphore>,
+    queued: Arc<AtomicUsize>,
+    max_queued: usize,
+    queue_timeout: Duration,
+    retry_after_seconds: u64,
+    shed_total: Arc<AtomicU64>,
+}
+
+// Holds a place in the load-shedding queue; released on drop so a client that disconnects
+// while waiting doesn't leak queue capacity.
+struct QueueSlot<'a>(pub(crate) &'a AtomicUsize);
+
+impl Drop for QueueSlot<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, AtomicOrdering::Relaxed);
+    }
+}
+
+// Shares one in-flight computation among concurrent identical requests. Callers put the ingest
+// batch count in the key, so a request arriving after new data never joins an older computation.
+// Failures are not shared: if the computation errors (or its caller goes away), the next waiter
+// runs it again.
+#[derive(Debug)]
+pub(crate) struct Singleflight<T> {
+    flights: std::sync::Mutex<HashMap<String, Arc<tokio::sync::OnceCell<T>>>>,
+    coalesced_total: AtomicU64,
+}
+
+// Drops the flight from the table as soon as any participant is done with it, so the next
+// request starts a fresh one. Callers that already joined keep the cell through their own Arc.
+struct FlightGuard<'a, T> {
+    flights: &'a std::sync::Mutex<HashMap<String, Arc<tokio::sync::OnceCell<T>>>>,
+    key: String,
+    cell: Arc<tokio::sync::OnceCell<T>>,
+}
+
+impl<T> Drop for FlightGuard<'_, T> {
+    fn drop(&mut self) {
+        let Ok(mut flights) = self.flights.lock() else {
+            return;
+        };
+        if flights
+            .get(&self.key)
+            .is_some_and(|current| Arc::ptr_eq(current, &self.cell))
+        {
+            flights.remove(&self.key);
+        }
+    }
+}
+
+impl<T: Clone> Singleflight<T> {
+    pub(crate) fn new() -> Self {
+        Singleflight {
+            flights: std::sync::Mutex::new(HashMap::new()),
+            coalesced_total: AtomicU64::new(0),
+        }
+    }
+
+    async fn run<F, Fut>(&self, key: String, compute: F) -> Result<T, AppError>
+    where
+        F: FnOnce() -> Fut,
+        Fut: std::future::Future<Output = Result<T, AppError>>,
+    {
+        // The lock is released before any await so handler futures stay Send.
+        let joined = match self.flights.lock() {
+            Ok(mut flights) => Some(match flights.get(&key) {
+                Some(cell) => {
+                    self.coalesced_total.fetch_add(1, AtomicOrdering::Relaxed);
+                    cell.clone()
+                }
+                None => {
+                    let cell = Arc::new(tokio::sync::OnceCell::new());
+                    flights.insert(key.clone(), cell.clone());
+                    cell
+                }
+            }),
+            Err(_) => None,
+        };
+        let Some(cell) = joined else {
+            return compute().await;
+        };
+        let guard = FlightGuard {
+            flights: &self.flights,
+            key,
+            cell,
+        };
+        guard.cell.get_or_try_init(compute).await.cloned()
+    }
+}
+
+// Slow-request thresholds: one default plus optional per-route overrides keyed by the route
+// pattern, e.g. SLOW_REQUEST_BUDGETS_MS="/v1/stops/{stop_id}/eta=200,/v1/search=100".
+#[derive(Debug)]
+pub(crate) struct LatencyBudgets {
+    default_ms: u64,
+    by_route: HashMap<String, u64>,
+}
+
+// Time spent in each stage of the current request, accumulated by StageTimer guards and read
+// back by track_latency once the handler returns.
+#[derive(Debug, Default)]
+struct StageTimings {
+    redis_us: AtomicU64,
+    gtfs_us: AtomicU64,
+    eta_compute_us: AtomicU64,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Stage {
+    Redis,
+    Gtfs,
+    EtaCompute,
+}
+
+tokio::task_local! {
+    static REQUEST_STAGES: Arc<StageTimings>;
+}
+
+// Charges the time until drop to a stage of the current request. Outside a request (background
+// tasks, replay) there is nothing to charge and the timing is dropped.
+pub(crate) struct StageTimer {
+    stage: Stage,
+    started_at: Instant,
+}
+
+impl StageTimer {
+    pub(crate) fn start(stage: Stage) -> Self {
+        StageTimer {
+            stage,
+            started_at: Instant::now(),
+        }
+    }
+}
+
+impl Drop for StageTimer {
+    fn drop(&mut self) {
+        let elapsed_us = self.started_at.elapsed().as_micros() as u64;
+        let _ = REQUEST_STAGES.try_with(|timings| {
+            let counter = match self.stage {
+                Stage::Redis => &timings.redis_us,
+                Stage::Gtfs => &timings.gtfs_us,
+                Stage::EtaCompute => &timings.eta_compute_us,
+            };
+            counter.fetch_add(elapsed_us, AtomicOrdering::Relaxed);
+        });
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct GtfsProxyQuery {
+    // "json" (default) or "protobuf"/"pb"; an Accept of application/x-protobuf also selects protobuf.
+    format: Option<String>,
+}
+
+// Street/locality lookup for coordinates. The offline table is precomputed from an OSM
+// extract and bucketed into a coarse grid; the HTTP variant calls a Nominatim-compatible
+// endpoint and caches answers per ~10 m cell.
+#[derive(Debug)]
+pub(crate) enum ReverseGeocoder {
+    Offline {
+        places: Vec<GeocodedPlace>,
+        grid: HashMap<(i64, i64), Vec<usize>>,
+    },
+    Http {
+        client: reqwest::Client,
+        url_template: String,
+        cache: RwLock<HashMap<(i64, i64), Option<PlaceContext>>>,
+    },
+}
+
+#[derive(Debug, Deserialize)]
+struct AsOfQuery {
+    as_of: Option<i64>,
+}
+
+#[derive(Debug, Deserialize)]
+struct UsageQuery {
+    days: Option<u32>,
+    limit: Option<usize>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ServiceDeliveryQuery {
+    // YYYY-MM-DD; today in Kuala Lumpur when absent.
+    date: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ShapeQuery {
+    // "json" (the default) or "geojson" for a LineString Feature.
+    format: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ActivityQuery {
+    hours: Option<u32>,
+}
+
+#[derive(Debug, Deserialize)]
+struct MultiStopEtaQuery {
+    // Comma-separated stop ids or sign codes.
+    stops: String,
+    as_of: Option<i64>,
+}
+
+// How far an observed trip start may be from its scheduled start and still count as that trip.
+const SERVICE_DELIVERY_MATCH_WINDOW_MINUTES: i64 = 15;
+
+const CURRENT_API_VERSION: u32 = 1;
+pub(crate) const CURRENT_API_PREFIX: &str = "/v1";
+const SUPPORTED_API_VERSIONS: [u32; 2] = [1, 2];
+// Pinning this version wraps every JSON body in a {data, meta} Envelope.
+const ENVELOPE_API_VERSION: u32 = 2;
+const API_VERSION_HEADER: &str = "x-api-version";
+const API_KEY_HEADER: &str = "x-api-key";
+const RESPONSE_PROFILE_HEADER: &str = "x-response-profile";
+const POSITION_PRECISION_HEADER: &str = "x-position-precision";
+// About 11 m; enough to place a bus on its street without tracing it.
+const COARSE_COORDINATE_DECIMALS: i32 = 4;
+pub(crate) const COARSE_SPEED_STEP_KMH: f64 = 5.0;
+const DEFAULT_COARSE_POSITION_TIERS: &str = "public";
+pub(crate) const REDACTION_PROFILES: [RedactionProfile; 3] = [
+    RedactionProfile::Public,
+    RedactionProfile::Partner,
+    RedactionProfile::Internal,
+];
+pub(crate) const POSITION_PRECISIONS: [PositionPrecision; 2] =
+    [PositionPrecision::Exact, PositionPrecision::Coarse];
+// Live bus position fields, on positions and on the ETA rows built from them. Stop coordinates
+// are published in the static feed and left alone.
+const BUS_COORDINATE_FIELDS: [&str; 4] = ["latitude", "longitude", "current_lat", "current_lon"];
+const BUS_SPEED_FIELDS: [&str; 3] = ["speed", "speed_kmh", "smoothed_speed_kmh"];
+// Upper bounds for the end-to-end freshness histograms. The feed itself reports every few
+// seconds, so anything past a minute or two is a stalled device or pipeline.
+pub(crate) const FRESHNESS_BUCKETS_SECONDS: [f64; 10] =
+    [0.5, 1.0, 2.0, 5.0, 10.0, 15.0, 30.0, 60.0, 120.0, 300.0];
+const FLAG_RUNTIME_PROFILE_ETA: &str = "runtime_profile_eta";
+const FLAG_DIRECTION_INFERENCE: &str = "direction_inference";
+// Every flag this build reads, with its rollout when neither FEATURE_FLAGS nor Redis sets one.
+// Both gate behaviors that shipped before the flags existed, so they start fully on.
+const KNOWN_FEATURE_FLAGS: [(&str, u8); 2] = [
+    (FLAG_RUNTIME_PROFILE_ETA, 100),
+    (FLAG_DIRECTION_INFERENCE, 100),
+];
+const DEFAULT_LOAD_SHED_MAX_IN_FLIGHT: usize = 64;
+const DEFAULT_LOAD_SHED_MAX_QUEUED: usize = 128;
+const DEFAULT_LOAD_SHED_QUEUE_TIMEOUT_MS: u64 = 500;
+const DEFAULT_LOAD_SHED_RETRY_AFTER_SECONDS: u64 = 2;
+const DEFAULT_SLOW_REQUEST_THRESHOLD_MS: u64 = 500;
+const PANTAI_HILLPARK_PHASE_5_STOP_ID: &str = "1008485";
+const DEFAULT_MAP_TILE_URL: &str = "https://tile.openstreetmap.org/{z}/{x}/{y}.png";
+const MAP_TILE_SIZE: f64 = 256.0;
+const DEFAULT_MAP_WIDTH: u32 = 600;
+const DEFAULT_MAP_HEIGHT: u32 = 400;
+const MAX_MAP_DIMENSION: u32 = 1_280;
+const MIN_MAP_DIMENSION: u32 = 100;
+const MAP_PADDING_PX: f64 = 24.0;
+const MAP_MAX_ZOOM: u32 = 17;
+const REVERSE_GEOCODE_GRID_DEGREES: f64 = 0.01;
+const REVERSE_GEOCODE_MAX_DISTANCE_M: f64 = 150.0;
+const REVERSE_GEOCODE_CACHE_CAPACITY: usize = 20_000;
+const REVERSE_GEOCODE_TIMEOUT_SECONDS: u64 = 2;
+pub(crate) const MAX_QUERY_LIMIT: usize = 500;
+// Page size when a cursor comes without ?limit=.
+const DEFAULT_PAGE_LIMIT: usize = 100;
+const MAX_CURSOR_LENGTH: usize = 512;
+const DEFAULT_USAGE_LIMIT: usize = 50;
+pub(crate) const DEFAULT_HOT_STOP_COUNT: usize = 50;
+// Schemas only change with a deploy.
+const SCHEMA_CACHE_CONTROL: &str = "public, max-age=3600";
+// Snapshot imports carry the whole keyspace, far past the default request body limit.
+const SNAPSHOT_IMPORT_MAX_BYTES: usize = 256 * 1024 * 1024;
+const HOT_STOP_POLL_MS: u64 = 500;
+const MAX_ID_LENGTH: usize = 64;
+// Detour and stop closure notices are shown on stop boards as is.
+const MAX_SERVICE_CHANGE_MESSAGE_CHARS: usize = 280;
+const CLOSURE_ALTERNATIVE_COUNT: usize = 3;
+const CLOSURE_ALTERNATIVE_RADIUS_M: f64 = 800.0;
+const DEFAULT_ACTIVITY_HOURS: u32 = 24;
+const MAX_SEARCH_QUERY_LENGTH: usize = 100;
+const MAX_ETA_STOPS: usize = 20;
+const DEFAULT_SEARCH_LIMIT: usize = 20;
+const MAX_SEARCH_LIMIT: usize = 50;
+// Public API surface. Served under /v1 and, for clients that predate versioning, at the root
+// as deprecated aliases. Both copies share one load shedder so the limit covers them together;
+// /gtfs (served from its cache) and /ingestor/status (in memory) are never shed so monitoring
+// keeps working during a spike.
+pub(crate) fn api_routes(state: &AppState) -> Router<AppState> {
+    Router::new()
+        // Live routes read the bus snapshot and are held back by WARMUP_GTFS_ONLY.
+        .route("/get-all", get(fetch_all_buses))
+        .route("/get-route-t789", get(get_route_t789))
+        .route("/get-t789-eta", get(get_t789_eta))
+        .route(
+            "/get-pantai-hillpark-phase-5-eta",
+            get(get_pantai_hillpark_phase_5_eta),
+        )
+        .route("/route/{route_id}/eta", get(get_route_eta_for_stops))
+        .route("/route/{route_id}/eta/{stop_id}", get(get_route_eta))
+        .route("/stops/{stop_id}/eta", get(get_stop_eta))
+        .route("/fleet", get(get_fleet))
+        .route("/fleet/in-depot", get(get_fleet_in_depot))
+        .route("/buses/{bus_no}", get(get_bus))
+        .route("/groups/{name}/live", get(get_group_live))
+        .route("/groups/{name}/eta/{stop_id}", get(get_group_eta))
+        .route_layer(middleware::from_fn_with_state(
+            state.clone(),
+            hold_live_during_warmup,
+        ))
+        .route("/stops/{stop_id}/routes", get(get_stop_routes))
+        .route("/stops/{stop_id}/dwell-stats", get(get_stop_dwell_stats))
+        .route("/routes/{route_id}/runtimes", get(get_route_runtimes))
+        .route("/routes/{route_id}/headways", get(get_route_headways))
+        .route(
+            "/routes/{route_id}/service-delivery",
+            get(get_route_service_delivery),
+        )
+        .route(
+            "/stops/{stop_id}/departures.ics",
+            get(get_stop_departures_ics),
+        )
+        .route("/route/{route_id}/stops", get(get_route_stops))
+        .route("/route/{route_id}/shape", get(get_route_shape))
+        .route("/route/{route_id}/map.png", get(get_route_map_png))
+        .route("/stops/nearest", get(get_nearest_stop))
+        .route("/search", get(search_routes_and_stops))
+        .route("/alerts.atom", get(get_alerts_atom))
+        .route("/incidents", get(get_incidents))
+        .route("/service-today", get(get_service_today))
+        .route("/viz/activity", get(get_viz_activity))
+        .route("/reports/{date}/routes.json", get(get_daily_route_report))
+        .route(
+            "/reports/{date}/routes.csv",
+            get(get_daily_route_report_csv),
+        )
+        .route_layer(middleware::from_fn_with_state(
+            state.load_shedder.clone(),
+            shed_load,
+        ))
+        .route("/gtfs", get(prasarana_gtfs_data))
+        .route("/gtfs/validation", get(get_gtfs_validation))
+        .route("/ingestor/status", get(get_ingestor_status))
+        .route("/eta/model", get(get_eta_model))
+        .route_layer(middleware::from_fn_with_state(
+            state.clone(),
+            wrap_in_envelope,
+        ))
+        .route_layer(middleware::from_fn_with_state(
+            state.clone(),
+            apply_redaction_profile,
+        ))
+        .route_layer(middleware::from_fn_with_state(state.clone(), track_usage))
+}
+
+// Operator API for one city, all of it behind ADMIN_API_KEY. Served under /admin for the default
+// city and under /admin/{city_id} for every city.
+pub(crate) fn admin_routes() -> Router<AppState> {
+    Router::new()
+        .route("/thresholds", get(get_thresholds).patch(patch_thresholds))
+        .route("/usage", get(get_usage))
+        .route(
+            "/routes/{route_id}/detours",
+            get(get_route_detours).post(post_route_detour),
+        )
+        .route(
+            "/routes/{route_id}/detours/{detour_id}",
+            delete(delete_route_detour),
+        )
+        .route(
+            "/stops/{stop_id}/closures",
+            get(get_stop_closures).post(post_stop_closure),
+        )
+        .route(
+            "/stops/{stop_id}/closures/{closure_id}",
+            delete(delete_stop_closure),
+        )
+        .route("/snapshot/export", get(get_snapshot_export))
+        .route(
+            "/snapshot/import",
+            post(post_snapshot_import).layer(DefaultBodyLimit::max(SNAPSHOT_IMPORT_MAX_BYTES)),
+        )
+        .route(
+            "/feature-flags",
+            get(get_feature_flags).patch(patch_feature_flags),
+        )
+}
+
+async fn hold_live_during_warmup(
+    State(state): State<AppState>,
+    request: Request,
+    next: Next,
+) -> Response {
+    if state.warmup.gtfs_only && is_warming_up(&state).await {
+        return AppError::WarmingUp.into_response();
+    }
+    next.run(request).await
+}
+
+// Where one city is in startup. The startup phases are the process's, but each city warms up
+// on its own ingestor.
+async fn city_readiness(state: &AppState, phases: &[StartupPhaseTiming]) -> CityReadiness {
+    let warming_up = is_warming_up(state).await;
+    let ingestor_started = state.fixture.is_some()
+        || phases
+            .iter()
+            .any(|timing| timing.phase == StartupPhase::StartingIngestor);
+    let phase = match phases.last() {
+        _ if ingestor_started && !warming_up => StartupPhase::Ready,
+        _ if ingestor_started => StartupPhase::WarmingUp,
+        Some(timing) => timing.phase,
+        None => StartupPhase::LoadingConfig,
+    };
+    CityReadiness {
+        city_id: state.city.id.clone(),
+        phase,
+        ready: phase == StartupPhase::Ready,
+    }
+}
+
+// 200 once every city is through warmup, 503 before, so orchestrators can hold traffic until
+// live data is trustworthy everywhere. The top-level phase is that of the first city still
+// starting up; the body also lists each city's and how long each startup phase took.
+pub(crate) async fn get_readiness(State(city_states): State<Arc<Vec<AppState>>>) -> Response {
+    let phases = city_states
+        .first()
+        .and_then(|state| {
+            state
+                .startup_phases
+                .lock()
+                .map(|phases| phases.clone())
+                .ok()
+        })
+        .unwrap_or_default();
+    let mut cities = Vec::with_capacity(city_states.len());
+    for state in city_states.iter() {
+        cities.push(city_readiness(state, &phases).await);
+    }
+    let phase = cities
+        .iter()
+        .find(|city| !city.ready)
+        .map_or(StartupPhase::Ready, |city| city.phase);
+    let ready = phase == StartupPhase::Ready;
+    let status = if ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (
+        status,
+        Json(ReadinessResponse {
+            phase,
+            ready,
+            gtfs_only: !ready && city_states.iter().any(|state| state.warmup.gtfs_only),
+            phases,
+            cities,
+        }),
+    )
+        .into_response()
+}
+
+// Counts what was asked for, never who asked: the route pattern plus any stop_id or route_id
+// path parameter, and only once the request succeeded.
+async fn track_usage(
+    State(state): State<AppState>,
+    params: RawPathParams,
+    request: Request,
+    next: Next,
+) -> Response {
+    let endpoint = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|path| path.as_str().to_string());
+    let path_ids: Vec<(String, String)> = params
+        .iter()
+        .filter(|(name, _)| matches!(*name, "stop_id" | "route_id"))
+        .map(|(name, value)| (name.to_string(), value.trim().to_string()))
+        .collect();
+    let response = next.run(request).await;
+    if !response.status().is_success() {
+        return response;
+    }
+
+    let count = |counters: &std::sync::Mutex<HashMap<String, u64>>, key: String| {
+        if let Ok(mut counts) = counters.lock() {
+            *counts.entry(key).or_default() += 1;
+        }
+    };
+    if let Some(endpoint) = endpoint {
+        count(&state.usage.endpoints, endpoint);
+    }
+    for (name, value) in path_ids {
+        match name.as_str() {
+            "stop_id" => count(&state.usage.stops, value),
+            _ => count(&state.usage.routes, value),
+        }
+    }
+    response
+}
+
+// Waiting is capped twice over: by queue depth, so a burst can't build an unbounded backlog,
+// and by time, so queued clients hear back quickly instead of timing out on their own.
+async fn shed_load(State(shedder): State<LoadShedder>, request: Request, next: Next) -> Response {
+    let _permit = match shedder.permits.clone().try_acquire_owned() {
+        Ok(permit) => permit,
+        Err(_) => {
+            if shedder.queued.fetch_add(1, AtomicOrdering::Relaxed) >= shedder.max_queued {
+                shedder.queued.fetch_sub(1, AtomicOrdering::Relaxed);
+                return reject_overloaded(&shedder);
+            }
+            let _slot = QueueSlot(&shedder.queued);
+            match tokio::time::timeout(
+                shedder.queue_timeout,
+                shedder.permits.clone().acquire_owned(),
+            )
+            .await
+            {
+                Ok(Ok(permit)) => permit,
+                _ => return reject_overloaded(&shedder),
+            }
+        }
+    };
+    next.run(request).await
+}
+
+fn reject_overloaded(shedder: &LoadShedder) -> Response {
+    shedder.shed_total.fetch_add(1, AtomicOrdering::Relaxed);
+    AppError::Overloaded(shedder.retry_after_seconds).into_response()
+}
+
+// Every request is timed; only those over their route's budget are logged, as one JSON line
+// with the elapsed time split into Redis, GTFS and ETA stages plus whatever is left over.
+pub(crate) async fn track_latency(
+    State(budgets): State<Arc<LatencyBudgets>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let method = request.method().clone();
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|path| path.as_str().to_string())
+        .unwrap_or_else(|| request.uri().path().to_string());
+    let timings = Arc::new(StageTimings::default());
+    let started_at = Instant::now();
+    let response = REQUEST_STAGES
+        .scope(timings.clone(), next.run(request))
+        .await;
+    let elapsed_ms = started_at.elapsed().as_secs_f64() * 1_000.0;
+
+    let budget_ms = budgets
+        .by_route
+        .get(&route)
+        .copied()
+        .unwrap_or(budgets.default_ms);
+    if elapsed_ms > budget_ms as f64 {
+        let redis_ms = timings.redis_us.load(AtomicOrdering::Relaxed) as f64 / 1_000.0;
+        let gtfs_ms = timings.gtfs_us.load(AtomicOrdering::Relaxed) as f64 / 1_000.0;
+        let eta_compute_ms = timings.eta_compute_us.load(AtomicOrdering::Relaxed) as f64 / 1_000.0;
+        println!(
+            "{}",
+            serde_json::json!({
+                "event": "slow_request",
+                "method": method.as_str(),
+                "route": route,
+                "status": response.status().as_u16(),
+                "elapsed_ms": elapsed_ms,
+                "budget_ms": budget_ms,
+                "redis_ms": redis_ms,
+                "gtfs_ms": gtfs_ms,
+                "eta_compute_ms": eta_compute_ms,
+                "other_ms": (elapsed_ms - redis_ms - gtfs_ms - eta_compute_ms).max(0.0),
+            })
+        );
+    }
+    response
+}
+
+pub(crate) fn latency_budgets_from_env() -> LatencyBudgets {
+    let by_route = env::var("SLOW_REQUEST_BUDGETS_MS")
+        .unwrap_or_default()
+        .split(',')
+        .filter_map(|entry| {
+            let (route, budget) = entry.split_once('=')?;
+            Some((route.trim().to_string(), budget.trim().parse::<u64>().ok()?))
+        })
+        .collect();
+    LatencyBudgets {
+        default_ms: env_or(
+            "SLOW_REQUEST_THRESHOLD_MS",
+            DEFAULT_SLOW_REQUEST_THRESHOLD_MS,
+        ),
+        by_route,
+    }
+}
+
+// API_KEY_TIERS="key1=partner,key2=internal". Entries with an unknown tier are skipped.
+pub(crate) fn api_key_tiers_from_env() -> HashMap<String, RedactionProfile> {
+    env::var("API_KEY_TIERS")
+        .unwrap_or_default()
+        .split(',')
+        .filter_map(|entry| {
+            let (key, tier) = entry.split_once('=')?;
+            let Some(profile) = RedactionProfile::from_name(tier) else {
+                println!("Ignoring API key with unknown tier '{}'", tier.trim());
+                return None;
+            };
+            Some((key.trim().to_string(), profile)).filter(|(key, _)| !key.is_empty())
+        })
+        .collect()
+}
+
+// COARSE_POSITION_TIERS="public,partner"; defaults to public only. Empty serves every tier exact
+// positions unless it asks otherwise.
+pub(crate) fn coarse_position_tiers_from_env() -> HashSet<RedactionProfile> {
+    env::var("COARSE_POSITION_TIERS")
+        .unwrap_or_else(|_| DEFAULT_COARSE_POSITION_TIERS.to_string())
+        .split(',')
+        .filter(|tier| !tier.trim().is_empty())
+        .filter_map(|tier| {
+            let profile = RedactionProfile::from_name(tier);
+            if profile.is_none() {
+                println!(
+                    "Ignoring unknown tier '{}' in COARSE_POSITION_TIERS",
+                    tier.trim()
+                );
+            }
+            profile
+        })
+        .collect()
+}
+
+pub(crate) fn load_shedder_from_env() -> LoadShedder {
+    let max_in_flight = env_or("LOAD_SHED_MAX_IN_FLIGHT", DEFAULT_LOAD_SHED_MAX_IN_FLIGHT).max(1);
+    LoadShedder {
+        permits: Arc::new(Semaphore::new(max_in_flight)),
+        queued: Arc::new(AtomicUsize::new(0)),
+        max_queued: env_or("LOAD_SHED_MAX_QUEUED", DEFAULT_LOAD_SHED_MAX_QUEUED),
+        queue_timeout: Duration::from_millis(env_or(
+            "LOAD_SHED_QUEUE_TIMEOUT_MS",
+            DEFAULT_LOAD_SHED_QUEUE_TIMEOUT_MS,
+        )),
+        retry_after_seconds: env_or(
+            "LOAD_SHED_RETRY_AFTER_SECONDS",
+            DEFAULT_LOAD_SHED_RETRY_AFTER_SECONDS,
+        ),
+        shed_total: Arc::new(AtomicU64::new(0)),
+    }
+}
+
+pub(crate) async fn mark_deprecated_alias(request: Request, next: Next) -> Response {
+    let successor_path = format!("{}{}", CURRENT_API_PREFIX, request.uri().path());
+    let mut response = next.run(request).await;
+    let headers = response.headers_mut();
+    headers.insert(
+        HeaderName::from_static("deprecation"),
+        HeaderValue::from_static("true"),
+    );
+    if let Ok(link) =
+        HeaderValue::from_str(&format!("<{}>; rel=\"successor-version\"", successor_path))
+    {
+        headers.insert(LINK, link);
+    }
+    response
+}
+
+// Clients may pin a version with `X-API-Version: N` or
+// `Accept: application/vnd.rapidbro.vN+json`; unknown versions are refused up front instead of
+// silently receiving a shape they don't understand.
+pub(crate) async fn negotiate_api_version(request: Request, next: Next) -> Response {
+    if let Some(requested_version) = requested_api_version(request.headers()) {
+        if !SUPPORTED_API_VERSIONS.contains(&requested_version) {
+            return (
+                StatusCode::NOT_ACCEPTABLE,
+                Json(ErrorResponse {
+                    error: format!(
+                        "API version {} is not supported; supported versions: {:?}",
+                        requested_version, SUPPORTED_API_VERSIONS
+                    ),
+                    fields: Vec::new(),
+                }),
+            )
+                .into_response();
+        }
+    }
+
+    let served_version = requested_api_version(request.headers()).unwrap_or(CURRENT_API_VERSION);
+    let mut response = next.run(request).await;
+    response.headers_mut().insert(
+        HeaderName::from_static(API_VERSION_HEADER),
+        HeaderValue::from(served_version),
+    );
+    response
+}
+
+// Under version 2 a bare body becomes {data, meta}; a body with its own meta object keeps its
+// shape and gains any standard meta fields it lacked. Errors and non-JSON bodies pass through.
+async fn wrap_in_envelope(State(state): State<AppState>, request: Request, next: Next) -> Response {
+    if requested_api_version(request.headers()) != Some(ENVELOPE_API_VERSION) {
+        return next.run(request).await;
+    }
+    let response = next.run(request).await;
+    let is_json = response
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with("application/json"));
+    if !response.status().is_success() || !is_json {
+        return response;
+    }
+
+    let (mut parts, body) = response.into_parts();
+    let bytes = match axum::body::to_bytes(body, usize::MAX).await {
+        Ok(bytes) => bytes,
+        Err(err) => return AppError::Internal(err.to_string()).into_response(),
+    };
+    let Ok(value) = serde_json::from_slice::<serde_json::Value>(&bytes) else {
+        return Response::from_parts(parts, bytes.into());
+    };
+    let meta = match response_meta(&state).await {
+        Ok(meta) => meta,
+        Err(err) => return err.into_response(),
+    };
+    let enveloped = match (value, serde_json::to_value(meta)) {
+        (serde_json::Value::Object(mut body), Ok(serde_json::Value::Object(standard)))
+            if body.get("meta").is_some_and(serde_json::Value::is_object) =>
+        {
+            if let Some(serde_json::Value::Object(own)) = body.get_mut("meta") {
+                for (field, value) in standard {
+                    own.entry(field).or_insert(value);
+                }
+            }
+            serde_json::Value::Object(body)
+        }
+        (data, Ok(meta)) => json!({ "data": data, "meta": meta }),
+        (_, Err(err)) => return AppError::Internal(err.to_string()).into_response(),
+    };
+    let Ok(body) = serde_json::to_vec(&enveloped) else {
+        return AppError::Internal("Failed to serialize response envelope".to_string())
+            .into_response();
+    };
+    parts.headers.remove(CONTENT_LENGTH);
+    Response::from_parts(parts, body.into())
+}
+
+// Every JSON body leaves through here, so no handler has to remember which fields a tier may
+// see. A key that is sent but not recognized is refused rather than served as public, so a
+// misconfigured partner notices. `X-Position-Precision: coarse` asks for coarse positions;
+// asking for exact on a tier that defaults to coarse is ignored. Handlers serving something
+// other than JSON read the ResponseShape from the request and apply it themselves.
+pub(crate) async fn apply_redaction_profile(
+    State(state): State<AppState>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let provided_key = request
+        .headers()
+        .get(API_KEY_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .map(str::trim)
+        .filter(|key| !key.is_empty());
+    let profile = match provided_key {
+        None => RedactionProfile::Public,
+        Some(key) if state.admin_api_key.as_deref() == Some(key) => RedactionProfile::Internal,
+        Some(key) => match state.api_key_tiers.get(key) {
+            Some(profile) => *profile,
+            None => return AppError::Unauthorized("Unknown API key".to_string()).into_response(),
+        },
+    };
+    let requested_precision = match request
+        .headers()
+        .get(POSITION_PRECISION_HEADER)
+        .map(|value| value.to_str().unwrap_or_default().trim().to_lowercase())
+        .as_deref()
+    {
+        None | Some("exact") => PositionPrecision::Exact,
+        Some("coarse") => PositionPrecision::Coarse,
+        Some(other) => {
+            return AppError::Validation(format!(
+                "Unknown position precision '{}'; expected exact or coarse",
+                other
+            ))
+            .into_response()
+        }
+    };
+    let precision = if state.coarse_position_tiers.contains(&profile) {
+        PositionPrecision::Coarse
+    } else {
+        requested_precision
+    };
+
+    request
+        .extensions_mut()
+        .insert(ResponseShape { profile, precision });
+    let response = next.run(request).await;
+    let (mut parts, body) = response.into_parts();
+    parts.headers.insert(
+        HeaderName::from_static(RESPONSE_PROFILE_HEADER),
+        HeaderValue::from_static(profile.name()),
+    );
+    parts.headers.insert(
+        HeaderName::from_static(POSITION_PRECISION_HEADER),
+        HeaderValue::from_static(precision.name()),
+    );
+    let is_json = parts
+        .headers
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with("application/json"));
+    let shaped_by_handler = parts.extensions.get::<ResponseShape>().is_some();
+    let unchanged = profile.hidden_fields().is_empty() && precision == PositionPrecision::Exact;
+    if unchanged || shaped_by_handler || !parts.status.is_success() || !is_json {
+        return Response::from_parts(parts, body);
+    }
+
+    let bytes = match axum::body::to_bytes(body, usize::MAX).await {
+        Ok(bytes) => bytes,
+        Err(err) => return AppError::Internal(err.to_string()).into_response(),
+    };
+    let Ok(mut value) = serde_json::from_slice::<serde_json::Value>(&bytes) else {
+        return Response::from_parts(parts, bytes.into());
+    };
+    redact_json(&mut value, profile, precision);
+    let Ok(body) = serde_json::to_vec(&value) else {
+        return AppError::Internal("Failed to serialize redacted response".to_string())
+            .into_response();
+    };
+    parts.headers.remove(CONTENT_LENGTH);
+    Response::from_parts(parts, body.into())
+}
+
+impl LatencyHistogram {
+    pub(crate) fn new(bounds_seconds: &'static [f64]) -> Self {
+        LatencyHistogram {
+            bounds_seconds,
+            counts: (0..=bounds_seconds.len())
+                .map(|_| AtomicU64::new(0))
+                .collect(),
+            sum_ms: AtomicU64::new(0),
+        }
+    }
+
+    // Negative spans (a device clock running ahead) count as zero.
+    pub(crate) fn observe_ms(&self, elapsed_ms: i64) {
+        let elapsed_ms = elapsed_ms.max(0);
+        let seconds = elapsed_ms as f64 / 1_000.0;
+        let index = self
+            .bounds_seconds
+            .iter()
+            .position(|bound| seconds <= *bound)
+            .unwrap_or(self.bounds_seconds.len());
+        self.counts[index].fetch_add(1, AtomicOrdering::Relaxed);
+        self.sum_ms
+            .fetch_add(elapsed_ms as u64, AtomicOrdering::Relaxed);
+    }
+
+    fn render(&self, name: &str, help: &str) -> String {
+        let mut body = format!("# HELP {} {}\n# TYPE {} histogram\n", name, help, name);
+        let mut cumulative = 0;
+        for (index, count) in self.counts.iter().enumerate() {
+            cumulative += count.load(AtomicOrdering::Relaxed);
+            let bound = self
+                .bounds_seconds
+                .get(index)
+                .map(|bound| bound.to_string())
+                .unwrap_or_else(|| "+Inf".to_string());
+            body.push_str(&format!(
+                "{}_bucket{{le=\"{}\"}} {}\n",
+                name, bound, cumulative
+            ));
+        }
+        body.push_str(&format!(
+            "{}_sum {:.3}\n{}_count {}\n",
+            name,
+            self.sum_ms.load(AtomicOrdering::Relaxed) as f64 / 1_000.0,
+            name,
+            cumulative
+        ));
+        body
+    }
+}
+
+impl RedactionProfile {
+    fn from_name(name: &str) -> Option<Self> {
+        match name.trim().to_lowercase().as_str() {
+            "public" => Some(RedactionProfile::Public),
+            "partner" => Some(RedactionProfile::Partner),
+            "internal" => Some(RedactionProfile::Internal),
+            _ => None,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            RedactionProfile::Public => "public",
+            RedactionProfile::Partner => "partner",
+            RedactionProfile::Internal => "internal",
+        }
+    }
+
+    // Object keys removed at any depth.
+    fn hidden_fields(self) -> &'static [&'static str] {
+        match self {
+            RedactionProfile::Public => &["captain_id", "trip_no"],
+            RedactionProfile::Partner => &["captain_id"],
+            RedactionProfile::Internal => &[],
+        }
+    }
+}
+
+impl PositionPrecision {
+    fn name(self) -> &'static str {
+        match self {
+            PositionPrecision::Exact => "exact",
+            PositionPrecision::Coarse => "coarse",
+        }
+    }
+}
+
+fn redact_json(
+    value: &mut serde_json::Value,
+    profile: RedactionProfile,
+    precision: PositionPrecision,
+) {
+    match value {
+        serde_json::Value::Object(fields) => {
+            for hidden in profile.hidden_fields() {
+                fields.remove(*hidden);
+            }
+            for (name, field) in fields.iter_mut() {
+                match (precision, field.as_f64()) {
+                    (PositionPrecision::Coarse, Some(coordinate))
+                        if BUS_COORDINATE_FIELDS.contains(&name.as_str()) =>
+                    {
+                        let scale = 10f64.powi(COARSE_COORDINATE_DECIMALS);
+                        *field = json!((coordinate * scale).round() / scale);
+                    }
+                    (PositionPrecision::Coarse, Some(speed))
+                        if BUS_SPEED_FIELDS.contains(&name.as_str()) =>
+                    {
+                        *field =
+                            json!((speed / COARSE_SPEED_STEP_KMH).round() * COARSE_SPEED_STEP_KMH);
+                    }
+                    _ => redact_json(field, profile, precision),
+                }
+            }
+        }
+        serde_json::Value::Array(items) => {
+            for item in items {
+                redact_json(item, profile, precision);
+            }
+        }
+        _ => {}
+    }
+}
+
+// redact_json for the GTFS-RT vehicle feed, whose protobuf never passes through it. The feed's
+// trip_id is what the socket calls trip_no, and its speeds are in m/s.
+pub(crate) fn shape_gtfs_rt_feed(feed: &mut gtfs_realtime::FeedMessage, shape: ResponseShape) {
+    let hides_trip = shape.profile.hidden_fields().contains(&"trip_no");
+    let scale = 10f64.powi(COARSE_COORDINATE_DECIMALS);
+    for vehicle in feed
+        .entity
+        .iter_mut()
+        .filter_map(|entity| entity.vehicle.as_mut())
+    {
+        if hides_trip {
+            if let Some(trip) = vehicle.trip.as_mut() {
+                trip.trip_id = None;
+            }
+        }
+        if shape.precision == PositionPrecision::Exact {
+            continue;
+        }
+        if let Some(position) = vehicle.position.as_mut() {
+            position.latitude = ((f64::from(position.latitude) * scale).round() / scale) as f32;
+            position.longitude = ((f64::from(position.longitude) * scale).round() / scale) as f32;
+            position.speed = position.speed.map(|meters_per_second| {
+                let speed_kmh = f64::from(meters_per_second) * 3.6;
+                ((speed_kmh / COARSE_SPEED_STEP_KMH).round() * COARSE_SPEED_STEP_KMH / 3.6) as f32
+            });
+        }
+    }
+}
+
+async fn response_meta(state: &AppState) -> Result<ResponseMeta, AppError> {
+    let now_ms = state.clock.now_ms();
+    let last_ingest_at_unix_ms = load_last_ingest_at(state).await?;
+    let replica_lag = *state.replica_lag.read().await;
+    let is_stale = match last_ingest_at_unix_ms {
+        Some(last_ingest_ms) => now_ms - last_ingest_ms > state.stale_after_ms,
+        None => true,
+    } || replica_lag
+        .is_some_and(|lag| replica_is_behind(&lag, state.stale_after_ms));
+    Ok(ResponseMeta {
+        source: if state.fixture.is_some() {
+            "fixture"
+        } else {
+            "redis"
+        }
+        .to_string(),
+        generated_at_unix_ms: now_ms,
+        last_ingest_at_unix_ms,
+        is_stale,
+        warming_up: is_warming_up(state).await,
+        gtfs_feed_version: city_gtfs_feed_version(&state.city),
+    })
+}
+
+fn requested_api_version(headers: &HeaderMap) -> Option<u32> {
+    if let Some(version) = headers
+        .get(API_VERSION_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().parse::<u32>().ok())
+    {
+        return Some(version);
+    }
+
+    headers
+        .get(ACCEPT)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|accept| {
+            accept.split(',').find_map(|media_type| {
+                media_type
+                    .trim()
+                    .strip_prefix("application/vnd.rapidbro.v")
+                    .and_then(|rest| rest.split('+').next())
+                    .and_then(|version| version.parse::<u32>().ok())
+            })
+        })
+}
+
+// With ?since_snapshot_seq= only buses written since that sequence are returned, plus the ids
+// of buses that have since left the fleet. A sequence the server can no longer diff against
+// (too old, or from before a Redis reset) gets the full fleet with is_delta = false.
+async fn fetch_all_buses(
+    ValidQuery(query): ValidQuery<GetAllQuery>,
+    State(state): State<AppState>,
+) -> Result<Json<GetAllResponse>, AppError> {
+    let cursor = query
+        .cursor
+        .as_deref()
+        .map(|value| decode_page_cursor(&state.cursor_secret, value, &PageList::Buses))
+        .transpose()?;
+    // Read before the snapshot, so changes racing the read are sent again next time rather
+    // than missed.
+    let snapshot_seq = load_snapshot_seq(&state).await?;
+    let snapshot = load_active_bus_snapshot(&state).await?;
+    let now_ms = state.clock.now_ms();
+    let replica_lag = *state.replica_lag.read().await;
+    let is_stale = match snapshot.last_ingest_at_unix_ms {
+        Some(last_ingest_ms) => now_ms - last_ingest_ms > state.stale_after_ms,
+        None => true,
+    } || replica_lag
+        .is_some_and(|lag| replica_is_behind(&lag, state.stale_after_ms));
+
+    let delta = match (query.since_snapshot_seq, snapshot_seq) {
+        (Some(since), Some(current))
+            if since <= current && current - since <= DELTA_SYNC_MAX_LAG_SEQS =>
+        {
+            Some(load_bus_changes_since(&state, since).await?)
+        }
+        _ => None,
+    };
+    let is_delta = delta.is_some();
+    let (buses, removed_bus_nos) = match delta {
+        Some((changed, removed)) => {
+            let present: HashSet<&str> = snapshot
+                .buses
+                .iter()
+                .map(|bus| bus.bus_no.as_str())
+                .collect();
+            // A bus written since but absent now was merged away or left the service area.
+            let mut removed_bus_nos: Vec<String> = changed
+                .iter()
+                .chain(removed.iter())
+                .filter(|bus_no| !present.contains(bus_no.as_str()))
+                .cloned()
+                .collect::<HashSet<String>>()
+                .into_iter()
+                .collect();
+            removed_bus_nos.sort();
+            let buses: Vec<BusPosition> = snapshot
+                .buses
+                .into_iter()
+                .filter(|bus| changed.contains(&bus.bus_no))
+                .collect();
+            (buses, removed_bus_nos)
+        }
+        None => {
+            let mut buses = snapshot.buses;
+            buses.sort_by(|left, right| left.bus_no.cmp(&right.bus_no));
+            (buses, Vec::new())
+        }
+    };
+    let (buses, next_after) = take_page(
+        buses,
+        |bus| bus.bus_no.clone(),
+        query.limit,
+        cursor.as_ref(),
+    );
+    // Later pages keep reporting the first page's sequence; see GetAllMeta::snapshot_seq.
+    let snapshot_seq = match &cursor {
+        Some(cursor) => cursor.snapshot_seq,
+        None => snapshot_seq,
+    };
+    let next_cursor = next_after.map(|after| {
+        encode_page_cursor(
+            &state.cursor_secret,
+            &PageCursor {
+                list: PageList::Buses,
+                after,
+                snapshot_seq,
+            },
+        )
+    });
+
+    println!(
+        "Calling fetch_all_buses via Redis: {} buses, {} removed, since_snapshot_seq={:?}",
+        buses.len(),
+        removed_bus_nos.len(),
+        query.since_snapshot_seq
+    );
+    for bus in &buses {
+        if let Some(last_seen_ms) = snapshot.last_seen_unix_ms.get(&bus.bus_no) {
+            state
+                .redis_to_response_seconds
+                .observe_ms(now_ms - last_seen_ms);
+        }
+    }
+    Ok(Json(GetAllResponse {
+        data: buses
+            .into_iter()
+            .map(|bus| apply_captain_id_privacy(bus, &state.privacy))
+            .map(|bus| attach_vehicle_info(bus, &state.vehicle_roster))
+            .map(|bus| {
+                attach_bus_freshness(bus, &snapshot.last_seen_unix_ms, now_ms, state.bus_ttl_ms)
+            })
+            .collect(),
+        removed_bus_nos,
+        meta: GetAllMeta {
+            source: "redis".to_string(),
+            last_ingest_at_unix_ms: snapshot.last_ingest_at_unix_ms,
+            is_stale,
+            active_bus_count: snapshot.active_bus_count,
+            warming_up: is_warming_up(&state).await,
+            snapshot_seq,
+            is_delta,
+            replica_lag_ms: replica_lag.map(|lag| lag.lag_ms),
+            next_cursor,
+        },
+    }))
+}
+
+// One bus by number: live while it is in the fleet, then an inactive tombstone with its last
+// position until BUS_TOMBSTONE_RETENTION_MS after it expired.
+async fn get_bus(
+    ValidPath(BusPath { bus_no }): ValidPath<BusPath>,
+    State(state): State<AppState>,
+) -> Result<Json<BusResponse>, AppError> {
+    let bus_no = canonical_bus_no(&bus_no, &state.bus_no_rules);
+    let snapshot = load_active_bus_snapshot(&state).await?;
+    let last_seen_unix_ms = snapshot.last_seen_unix_ms.get(&bus_no).copied();
+    let (status, bus, last_seen_unix_ms, removed_at_unix_ms) =
+        match snapshot.buses.into_iter().find(|bus| bus.bus_no == bus_no) {
+            Some(bus) => (
+                BusStatus::Active,
+                attach_bus_freshness(
+                    bus,
+                    &snapshot.last_seen_unix_ms,
+                    state.clock.now_ms(),
+                    state.bus_ttl_ms,
+                ),
+                last_seen_unix_ms,
+                None,
+            ),
+            None => {
+                let tombstone = load_bus_tombstone(&state, &bus_no)
+                    .await?
+                    .filter(|tombstone| !tombstone.bus.outside_service_area)
+                    .ok_or_else(|| AppError::NotFound(format!("Bus '{}' not found", bus_no)))?;
+                (
+                    BusStatus::Inactive,
+                    tombstone.bus,
+                    Some(tombstone.last_seen_unix_ms),
+                    Some(tombstone.removed_at_unix_ms),
+                )
+            }
+        };
+
+    if let (BusStatus::Active, Some(last_seen_ms)) = (&status, last_seen_unix_ms) {
+        state
+            .redis_to_response_seconds
+            .observe_ms(state.clock.now_ms() - last_seen_ms);
+    }
+    println!("Calling get_bus for bus_no={}: {:?}", bus_no, status);
+    Ok(Json(BusResponse {
+        bus_no,
+        status,
+        last_seen_unix_ms,
+        removed_at_unix_ms,
+        data: attach_vehicle_info(
+            apply_captain_id_privacy(bus, &state.privacy),
+            &state.vehicle_roster,
+        ),
+    }))
+}
+
+// Rows the served feed was loaded without, by file; the same report after every reload.
+async fn get_gtfs_validation(State(state): State<AppState>) -> Json<GtfsValidationReport> {
+    Json(current_gtfs_feed(&state).validation.clone())
+}
+
+async fn get_ingestor_status(State(state): State<AppState>) -> Json<IngestorStatus> {
+    Json(state.ingestor_status.read().await.clone())
+}
+
+async fn get_fleet_in_depot(
+    State(state): State<AppState>,
+) -> Result<Json<InDepotResponse>, AppError> {
+    let snapshot = load_active_bus_snapshot(&state).await?;
+    let mut count_by_depot = std::collections::BTreeMap::new();
+    let data: Vec<BusPosition> = snapshot
+        .buses
+        .into_iter()
+        .filter(|bus| bus.in_depot)
+        .inspect(|bus| {
+            if let Some(depot_name) = &bus.depot_name {
+                *count_by_depot.entry(depot_name.clone()).or_insert(0) += 1;
+            }
+        })
+        .map(|bus| apply_captain_id_privacy(bus, &state.privacy))
+        .map(|bus| attach_vehicle_info(bus, &state.vehicle_roster))
+        .collect();
+
+    println!("Calling get_fleet_in_depot: {} buses", data.len());
+    Ok(Json(InDepotResponse {
+        data,
+        count_by_depot,
+    }))
+}
+
+// REVERSE_GEOCODE_PATH (a lat,lon,street,locality CSV precomputed from OSM) wins over
+// REVERSE_GEOCODE_URL (a template with {lat} and {lon}); with neither, responses carry no place.
+pub(crate) fn reverse_geocoder_from_env() -> Option<ReverseGeocoder> {
+    if let Some(path) = env::var("REVERSE_GEOCODE_PATH")
+        .ok()
+        .filter(|value| !value.trim().is_empty())
+    {
+        let geocoder = load_offline_geocoder(&path).unwrap_or_else(|error| {
+            panic!("Failed to load reverse geocode table '{}': {}", path, error)
+        });
+        return Some(geocoder);
+    }
+
+    let url_template = env::var("REVERSE_GEOCODE_URL")
+        .ok()
+        .filter(|value| !value.trim().is_empty())?;
+    let client = reqwest::Client::builder()
+        .user_agent(concat!("rapidbro/", env!("CARGO_PKG_VERSION")))
+        .timeout(Duration::from_secs(REVERSE_GEOCODE_TIMEOUT_SECONDS))
+        .build()
+        .unwrap_or_else(|error| panic!("Failed to build reverse geocode client: {}", error));
+    println!("Reverse geocoding via {}", url_template);
+    Some(ReverseGeocoder::Http {
+        client,
+        url_template,
+        cache: RwLock::new(HashMap::new()),
+    })
+}
+
+fn load_offline_geocoder(path: &str) -> Result<ReverseGeocoder, LoadError> {
+    let file = File::open(path)?;
+    let mut rdr = csv::ReaderBuilder::new()
+        .has_headers(true)
+        .trim(csv::Trim::All)
+        .from_reader(file);
+    let mut places = Vec::new();
+    let mut grid: HashMap<(i64, i64), Vec<usize>> = HashMap::new();
+    for result in rdr.deserialize() {
+        let mut place: GeocodedPlace = result?;
+        place.street = place.street.filter(|value| !value.is_empty());
+        place.locality = place.locality.filter(|value| !value.is_empty());
+        if place.street.is_none() && place.locality.is_none() {
+            continue;
+        }
+        grid.entry(geocode_grid_cell(place.lat, place.lon))
+            .or_default()
+            .push(places.len());
+        places.push(place);
+    }
+    println!("Loaded reverse geocode table with {} places", places.len());
+    Ok(ReverseGeocoder::Offline { places, grid })
+}
+
+fn geocode_grid_cell(lat: f64, lon: f64) -> (i64, i64) {
+    (
+        (lat / REVERSE_GEOCODE_GRID_DEGREES).floor() as i64,
+        (lon / REVERSE_GEOCODE_GRID_DEGREES).floor() as i64,
+    )
+}
+
+// Lookup failures are logged and reported as no place; street names are decoration only.
+async fn reverse_geocode(geocoder: &ReverseGeocoder, lat: f64, lon: f64) -> Option<PlaceContext> {
+    match geocoder {
+        ReverseGeocoder::Offline { places, grid } => {
+            let (cell_lat, cell_lon) = geocode_grid_cell(lat, lon);
+            (cell_lat - 1..=cell_lat + 1)
+                .flat_map(|row| (cell_lon - 1..=cell_lon + 1).map(move |col| (row, col)))
+                .filter_map(|cell| grid.get(&cell))
+                .flatten()
+                .map(|&index| {
+                    let place = &places[index];
+                    (
+                        place,
+                        haversine_distance(lat, lon, place.lat, place.lon) * 1000.0,
+                    )
+                })
+                .filter(|(_, distance_m)| *distance_m <= REVERSE_GEOCODE_MAX_DISTANCE_M)
+                .min_by(|(_, left), (_, right)| {
+                    left.partial_cmp(right).unwrap_or(std::cmp::Ordering::Equal)
+                })
+                .map(|(place, _)| PlaceContext {
+                    street: place.street.clone(),
+                    locality: place.locality.clone(),
+                })
+        }
+        ReverseGeocoder::Http {
+            client,
+            url_template,
+            cache,
+        } => {
+            let key = (
+                (lat * 10_000.0).round() as i64,
+                (lon * 10_000.0).round() as i64,
+            );
+            if let Some(cached) = cache.read().await.get(&key) {
+                return cached.clone();
+            }
+
+            let url = url_template
+                .replace("{lat}", &format!("{:.5}", lat))
+                .replace("{lon}", &format!("{:.5}", lon));
+            let body = match client
+                .get(&url)
+                .send()
+                .await
+                .and_then(|response| response.error_for_status())
+            {
+                Ok(response) => response.text().await.ok()?,
+                Err(error) => {
+                    println!("Reverse geocode request failed for {}: {}", url, error);
+                    return None;
+                }
+            };
+            let place = serde_json::from_str::<serde_json::Value>(&body)
+                .ok()
+                .and_then(|value| place_from_geocoder_json(&value));
+
+            let mut cache = cache.write().await;
+            if cache.len() >= REVERSE_GEOCODE_CACHE_CAPACITY {
+                cache.clear();
+            }
+            cache.insert(key, place.clone());
+            place
+        }
+    }
+}
+
+// Nominatim-style body: street from address.road, locality from the most local named area.
+fn place_from_geocoder_json(value: &serde_json::Value) -> Option<PlaceContext> {
+    let address = value.get("address")?;
+    let field = |names: &[&str]| {
+        names
+            .iter()
+            .filter_map(|name| address.get(*name).and_then(|value| value.as_str()))
+            .map(str::trim)
+            .find(|value| !value.is_empty())
+            .map(str::to_string)
+    };
+    let street = field(&["road", "pedestrian", "street"]);
+    let locality = field(&["suburb", "neighbourhood", "city_district", "town", "city"]);
+    if street.is_none() && locality.is_none() {
+        return None;
+    }
+    Some(PlaceContext { street, locality })
+}
+
+async fn annotate_bus_places(state: &AppState, etas: &mut [BusEta]) {
+    let Some(geocoder) = &state.reverse_geocoder else {
+        return;
+    };
+    let places = futures_util::future::join_all(
+        etas.iter()
+            .map(|eta| reverse_geocode(geocoder, eta.current_lat, eta.current_lon)),
+    )
+    .await;
+    for (eta, place) in etas.iter_mut().zip(places) {
+        eta.place = place;
+    }
+}
+
+fn attach_route_display(gtfs: &GtfsContext, etas: &mut [BusEta]) {
+    for eta in etas.iter_mut() {
+        eta.route_display = route_display(gtfs, &eta.route_id, None);
+    }
+}
+
+pub(crate) fn parse_flag(value: &str) -> Option<bool> {
+    match value.trim().to_lowercase().as_str() {
+        "1" | "true" | "yes" | "y" => Some(true),
+        "0" | "false" | "no" | "n" => Some(false),
+        _ => None,
+    }
+}
+
+async fn get_fleet(
+    ValidQuery(query): ValidQuery<FleetQuery>,
+    State(state): State<AppState>,
+) -> Result<Json<FleetResponse>, AppError> {
+    let cursor = query
+        .cursor
+        .as_deref()
+        .map(|value| decode_page_cursor(&state.cursor_secret, value, &PageList::Fleet))
+        .transpose()?;
+    // Only paginated reads report a sequence, so plain roster reads cost no extra round trip.
+    let snapshot_seq = match &cursor {
+        Some(cursor) => cursor.snapshot_seq,
+        None if query.limit.is_some() => load_snapshot_seq(&state).await?,
+        None => None,
+    };
+    let snapshot = load_active_bus_snapshot(&state).await?;
+    let live_buses: HashMap<&str, &BusPosition> = snapshot
+        .buses
+        .iter()
+        .map(|bus| (bus.bus_no.as_str(), bus))
+        .collect();
+
+    let mut data: Vec<FleetVehicle> = state
+        .vehicle_roster
+        .iter()
+        .filter(|(_, vehicle)| match &query.depot {
+            Some(depot) => vehicle
+                .depot
+                .as_deref()
+                .is_some_and(|value| value.eq_ignore_ascii_case(depot.trim())),
+            None => true,
+        })
+        .filter(|(_, vehicle)| match query.wheelchair_lift {
+            Some(wanted) => vehicle.wheelchair_lift == Some(wanted),
+            None => true,
+        })
+        .map(|(bus_no, vehicle)| {
+            let live = live_buses.get(bus_no.as_str());
+            FleetVehicle {
+                bus_no: bus_no.clone(),
+                vehicle: vehicle.clone(),
+                active: live.is_some(),
+                route: live.map(|bus| bus.route.clone()),
+                latitude: live.map(|bus| bus.latitude),
+                longitude: live.map(|bus| bus.longitude),
+            }
+        })
+        .filter(|vehicle| query.active.is_none_or(|wanted| vehicle.active == wanted))
+        .collect();
+    data.sort_by(|left, right| left.bus_no.cmp(&right.bus_no));
+
+    let active_count = data.iter().filter(|vehicle| vehicle.active).count();
+    let (data, next_after) = take_page(
+        data,
+        |vehicle| vehicle.bus_no.clone(),
+        query.limit,
+        cursor.as_ref(),
+    );
+    println!(
+        "Calling get_fleet: {} vehicles, {} active",
+        data.len(),
+        active_count
+    );
+    Ok(Json(FleetResponse {
+        data,
+        roster_size: state.vehicle_roster.len(),
+        active_count,
+        next_cursor: next_after.map(|after| {
+            encode_page_cursor(
+                &state.cursor_secret,
+                &PageCursor {
+                    list: PageList::Fleet,
+                    after,
+                    snapshot_seq,
+                },
+            )
+        }),
+    }))
+}
+
+// Give each request its own Sentry hub tagged with the method and path, so breadcrumbs and
+// events raised while handling it carry that context, and report every 5xx response.
+pub(crate) async fn sentry_request_context(request: Request, next: Next) -> Response {
+    let hub = Arc::new(sentry::Hub::new_from_top(sentry::Hub::current()));
+    let method = request.method().to_string();
+    let path = request.uri().path().to_string();
+    hub.configure_scope(|scope| {
+        scope.set_tag("http.method", &method);
+        scope.set_tag("http.path", &path);
+    });
+
+    let response = next.run(request).bind_hub(hub.clone()).await;
+    if response.status().is_server_error() {
+        hub.capture_message(
+            &format!("{} {} responded with {}", method, path, response.status()),
+            sentry::Level::Error,
+        );
+    }
+    response
+}
+
+// Every handler error. The variant picks the HTTP status and the category that server-side
+// failures are reported under; the message is what clients see in ErrorResponse.error.
+#[derive(Debug, thiserror::Error)]
+pub(crate) enum AppError {
+    #[error("Redis error: {0}")]
+    Redis(#[from] redis::RedisError),
+    #[error("{0}")]
+    Upstream(String),
+    #[error("{0}")]
+    NotFound(String),
+    #[error("{0}")]
+    Validation(String),
+    #[error("Invalid request: {}", describe_field_errors(.0))]
+    InvalidFields(Vec<FieldError>),
+    #[error("{0}")]
+    Conflict(String),
+    #[error("{0}")]
+    Unauthorized(String),
+    // A feature that is switched off by configuration.
+    #[error("{0}")]
+    Disabled(String),
+    #[error("Internal server error: {0}")]
+    Internal(String),
+    // Turned away by the load shedder; carries the Retry-After hint in seconds.
+    #[error("Server is busy, retry in {0}s")]
+    Overloaded(u64),
+    // A live endpoint asked for before warmup ended, with WARMUP_GTFS_ONLY set.
+    #[error("Live data is still warming up; only GTFS endpoints are served for now")]
+    WarmingUp,
+}
+
+impl AppError {
+    fn status(&self) -> StatusCode {
+        match self {
+            AppError::Redis(_) | AppError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            AppError::Upstream(_)
+            | AppError::Disabled(_)
+            | AppError::Overloaded(_)
+            | AppError::WarmingUp => StatusCode::SERVICE_UNAVAILABLE,
+            AppError::NotFound(_) => StatusCode::NOT_FOUND,
+            AppError::Validation(_) | AppError::InvalidFields(_) => StatusCode::BAD_REQUEST,
+            AppError::Conflict(_) => StatusCode::CONFLICT,
+            AppError::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+        }
+    }
+
+    fn category(&self) -> &'static str {
+        match self {
+            AppError::Redis(_) => "redis",
+            AppError::Upstream(_) => "upstream",
+            AppError::NotFound(_) => "not_found",
+            AppError::Validation(_) | AppError::InvalidFields(_) => "validation",
+            AppError::Conflict(_) => "conflict",
+            AppError::Unauthorized(_) => "unauthorized",
+            AppError::Disabled(_) => "disabled",
+            AppError::Internal(_) => "internal",
+            AppError::Overloaded(_) => "overloaded",
+            AppError::WarmingUp => "warming_up",
+        }
+    }
+}
+
+impl IntoResponse for AppError {
+    fn into_response(self) -> Response {
+        let status = self.status();
+        let message = self.to_string();
+        // Only our own failures are worth a breadcrumb; client mistakes and deliberate
+        // shedding are not.
+        if status.is_server_error()
+            && !matches!(self, AppError::Overloaded(_) | AppError::WarmingUp)
+        {
+            sentry::add_breadcrumb(sentry::Breadcrumb {
+                category: Some(format!("handler.{}", self.category())),
+                message: Some(message.clone()),
+                level: sentry::Level::Error,
+                ..Default::default()
+            });
+        }
+        let retry_after = match &self {
+            AppError::Overloaded(seconds) => Some(*seconds),
+            _ => None,
+        };
+        let fields = match self {
+            AppError::InvalidFields(fields) => fields,
+            _ => Vec::new(),
+        };
+        let mut response = (
+            status,
+            Json(ErrorResponse {
+                error: message,
+                fields,
+            }),
+        )
+            .into_response();
+        if let Some(seconds) = retry_after {
+            response
+                .headers_mut()
+                .insert(RETRY_AFTER, HeaderValue::from(seconds));
+        }
+        response
+    }
+}
+
+fn describe_field_errors(fields: &[FieldError]) -> String {
+    fields
+        .iter()
+        .map(|field| format!("{} {}", field.field, field.message))
+        .collect::<Vec<_>>()
+        .join("; ")
+}
+
+// Bounds checks for query and path parameters, run by ValidQuery/ValidPath before a handler
+// sees the value. Deserialization already rejects wrong types; this catches wrong values.
+trait Validate {
+    fn validate(&self) -> Vec<FieldError>;
+}
+
+struct ValidQuery<T>(pub(crate) T);
+
+impl<T, S> FromRequestParts<S> for ValidQuery<T>
+where
+    T: DeserializeOwned + Validate + Send,
+    S: Send + Sync,
+{
+    type Rejection = AppError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let Query(value) = Query::<T>::from_request_parts(parts, state)
+            .await
+            .map_err(|rejection| AppError::Validation(rejection.body_text()))?;
+        let errors = value.validate();
+        if !errors.is_empty() {
+            return Err(AppError::InvalidFields(errors));
+        }
+        Ok(ValidQuery(value))
+    }
+}
+
+pub(crate) struct ValidPath<T>(pub(crate) T);
+
+impl<T, S> FromRequestParts<S> for ValidPath<T>
+where
+    T: DeserializeOwned + Validate + Send,
+    S: Send + Sync,
+{
+    type Rejection = AppError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let Path(value) = Path::<T>::from_request_parts(parts, state)
+            .await
+            .map_err(|rejection| AppError::Validation(rejection.body_text()))?;
+        let errors = value.validate();
+        if !errors.is_empty() {
+            return Err(AppError::InvalidFields(errors));
+        }
+        Ok(ValidPath(value))
+    }
+}
+
+fn field_error(field: &str, message: impl Into<String>) -> FieldError {
+    FieldError {
+        field: field.to_string(),
+        message: message.into(),
+    }
+}
+
+// GTFS ids and stop codes are short alphanumeric tokens, and ids merged in from an extra feed
+// carry a "provider:" prefix; surrounding whitespace is tolerated.
+fn check_id(errors: &mut Vec<FieldError>, field: &str, value: &str) {
+    let value = value.trim();
+    if value.is_empty() {
+        errors.push(field_error(field, "must not be empty"));
+    } else if value.chars().count() > MAX_ID_LENGTH {
+        errors.push(field_error(
+            field,
+            format!("must be at most {} characters", MAX_ID_LENGTH),
+        ));
+    } else if !value
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.' | ':'))
+    {
+        errors.push(field_error(
+            field,
+            "may only contain letters, digits, '-', '_', '.' and ':'",
+        ));
+    }
+}
+
+fn check_range(errors: &mut Vec<FieldError>, field: &str, value: f64, min: f64, max: f64) {
+    if !value.is_finite() || value < min || value > max {
+        errors.push(field_error(
+            field,
+            format!("must be between {} and {}", min, max),
+        ));
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct StopPath {
+    stop_id: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct RoutePath {
+    route_id: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct RouteStopPath {
+    route_id: String,
+    stop_id: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct ReportPath {
+    date: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct SchemaPath {
+    type_name: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct GroupPath {
+    name: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct GroupStopPath {
+    name: String,
+    stop_id: String,
+}
+
+// A stop named in the path by stop_id or sign code, in any case and with stray whitespace,
+// resolved once against GTFS stops so handlers only ever see the canonical stop_id.
+#[derive(Debug, Clone)]
+struct StopRef {
+    stop_id: String,
+    stop_code: Option<String>,
+}
+
+// A route named in the path by route_id or short name: "t789", "T789" and "T7890" all resolve
+// to the same GTFS route.
+#[derive(Debug, Clone)]
+struct RouteRef {
+    route_id: String,
+}
+
+#[derive(Debug, Clone)]
+struct RouteStopRefs {
+    route: RouteRef,
+    stop: StopRef,
+}
+
+// Stop and route keys resolve against the GTFS feed of the city the request is routed to.
+impl FromRequestParts<AppState> for StopRef {
+    type Rejection = AppError;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        let ValidPath(StopPath { stop_id }) =
+            ValidPath::<StopPath>::from_request_parts(parts, state).await?;
+        resolve_stop_ref(&current_gtfs_feed(state), &stop_id)
+    }
+}
+
+impl FromRequestParts<AppState> for RouteRef {
+    type Rejection = AppError;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        let ValidPath(RoutePath { route_id }) =
+            ValidPath::<RoutePath>::from_request_parts(parts, state).await?;
+        resolve_route_ref(&current_gtfs_feed(state), &route_id)
+    }
+}
+
+impl FromRequestParts<AppState> for RouteStopRefs {
+    type Rejection = AppError;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        let ValidPath(RouteStopPath { route_id, stop_id }) =
+            ValidPath::<RouteStopPath>::from_request_parts(parts, state).await?;
+        let gtfs = current_gtfs_feed(state);
+        Ok(RouteStopRefs {
+            route: resolve_route_ref(&gtfs, &route_id)?,
+            stop: resolve_stop_ref(&gtfs, &stop_id)?,
+        })
+    }
+}
+
+fn resolve_stop_ref(gtfs: &GtfsFeed, key: &str) -> Result<StopRef, AppError> {
+    let stop_id = resolve_stop_key(key, &gtfs.stops_map, &gtfs.stop_ids_by_code)?;
+    let stop = gtfs
+        .stops_map
+        .get(&stop_id)
+        .ok_or_else(|| AppError::NotFound(format!("Stop '{}' not found", key.trim())))?;
+    Ok(StopRef {
+        stop_id: stop.stop_id.clone(),
+        stop_code: stop.stop_code.clone(),
+    })
+}
+
+fn resolve_route_ref(gtfs: &GtfsFeed, key: &str) -> Result<RouteRef, AppError> {
+    let route = find_route(&gtfs.routes, key)
+        .ok_or_else(|| AppError::NotFound(format!("Route '{}' not found", key.trim())))?;
+    Ok(RouteRef {
+        route_id: route.route_id.clone(),
+    })
+}
+
+impl Validate for StopPath {
+    fn validate(&self) -> Vec<FieldError> {
+        let mut errors = Vec::new();
+        check_id(&mut errors, "stop_id", &self.stop_id);
+        errors
+    }
+}
+
+impl Validate for RoutePath {
+    fn validate(&self) -> Vec<FieldError> {
+        let mut errors = Vec::new();
+        check_id(&mut errors, "route_id", &self.route_id);
+        errors
+    }
+}
+
+impl Validate for RouteStopPath {
+    fn validate(&self) -> Vec<FieldError> {
+        let mut errors = Vec::new();
+        check_id(&mut errors, "route_id", &self.route_id);
+        check_id(&mut errors, "stop_id", &self.stop_id);
+        errors
+    }
+}
+
+impl Validate for ReportPath {
+    fn validate(&self) -> Vec<FieldError> {
+        let mut errors = Vec::new();
+        if NaiveDate::parse_from_str(self.date.trim(), "%Y-%m-%d").is_err() {
+            errors.push(field_error("date", "must be a date as YYYY-MM-DD"));
+        }
+        errors
+    }
+}
+
+impl Validate for GroupPath {
+    fn validate(&self) -> Vec<FieldError> {
+        let mut errors = Vec::new();
+        check_id(&mut errors, "name", &self.name);
+        errors
+    }
+}
+
+impl Validate for GroupStopPath {
+    fn validate(&self) -> Vec<FieldError> {
+        let mut errors = Vec::new();
+        check_id(&mut errors, "name", &self.name);
+        check_id(&mut errors, "stop_id", &self.stop_id);
+        errors
+    }
+}
+
+// The list a cursor pages through. A cursor is only accepted by the list that issued it.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "list", rename_all = "snake_case")]
+pub(crate) enum PageList {
+    Buses,
+    Fleet,
+    RouteStops { route_id: String },
+}
+
+// Opaque to clients: JSON, then an HMAC over it so a cursor can't be forged or edited.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct PageCursor {
+    #[serde(flatten)]
+    list: PageList,
+    // Sort key of the last item already served; the next page starts after it, so items that
+    // come or go between pages never shift the rest.
+    after: String,
+    // Live data sequence the first page was read at.
+    snapshot_seq: Option<u64>,
+}
+
+fn encode_page_cursor(secret: &str, cursor: &PageCursor) -> String {
+    let engine = &base64::engine::general_purpose::URL_SAFE_NO_PAD;
+    let payload = serde_json::to_vec(cursor).unwrap_or_default();
+    format!(
+        "{}.{}",
+        engine.encode(&payload),
+        engine.encode(page_cursor_mac(secret, &payload).finalize().into_bytes())
+    )
+}
+
+fn decode_page_cursor(secret: &str, value: &str, list: &PageList) -> Result<PageCursor, AppError> {
+    let invalid = || AppError::Validation("cursor is invalid for this list".to_string());
+    let engine = &base64::engine::general_purpose::URL_SAFE_NO_PAD;
+    let (payload, signature) = value.trim().split_once('.').ok_or_else(invalid)?;
+    let payload = engine.decode(payload).map_err(|_| invalid())?;
+    let signature = engine.decode(signature).map_err(|_| invalid())?;
+    page_cursor_mac(secret, &payload)
+        .verify_slice(&signature)
+        .map_err(|_| invalid())?;
+    let cursor: PageCursor = serde_json::from_slice(&payload).map_err(|_| invalid())?;
+    if &cursor.list != list {
+        return Err(invalid());
+    }
+    Ok(cursor)
+}
+
+fn page_cursor_mac(secret: &str, payload: &[u8]) -> Hmac<Sha256> {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any length");
+    mac.update(payload);
+    mac
+}
+
+// One page of items already sorted by sort_key, and the key to resume after when more remain.
+// Without a limit or cursor the whole list is one page, as before pagination existed.
+fn take_page<T>(
+    items: Vec<T>,
+    sort_key: impl Fn(&T) -> String,
+    limit: Option<usize>,
+    cursor: Option<&PageCursor>,
+) -> (Vec<T>, Option<String>) {
+    let Some(limit) = limit.or(cursor.map(|_| DEFAULT_PAGE_LIMIT)) else {
+        return (items, None);
+    };
+    let mut remaining = items
+        .into_iter()
+        .filter(|item| cursor.is_none_or(|cursor| sort_key(item) > cursor.after))
+        .peekable();
+    let page: Vec<T> = remaining.by_ref().take(limit).collect();
+    let next_after = match (remaining.peek(), page.last()) {
+        (Some(_), Some(last)) => Some(sort_key(last)),
+        _ => None,
+    };
+    (page, next_after)
+}
+
+fn check_page_query(errors: &mut Vec<FieldError>, limit: Option<usize>, cursor: Option<&str>) {
+    if limit.is_some_and(|limit| !(1..=MAX_QUERY_LIMIT).contains(&limit)) {
+        errors.push(field_error(
+            "limit",
+            format!("must be between 1 and {}", MAX_QUERY_LIMIT),
+        ));
+    }
+    if cursor.is_some_and(|cursor| cursor.trim().is_empty() || cursor.len() > MAX_CURSOR_LENGTH) {
+        errors.push(field_error(
+            "cursor",
+            format!("must be 1 to {} characters", MAX_CURSOR_LENGTH),
+        ));
+    }
+}
+
+impl Validate for NearestStopQuery {
+    fn validate(&self) -> Vec<FieldError> {
+        let mut errors = Vec::new();
+        check_range(&mut errors, "lat", self.lat, -90.0, 90.0);
+        check_range(&mut errors, "lon", self.lon, -180.0, 180.0);
+        errors
+    }
+}
+
+impl Validate for SearchQuery {
+    fn validate(&self) -> Vec<FieldError> {
+        let mut errors = Vec::new();
+        let q = self.q.trim();
+        if q.is_empty() {
+            errors.push(field_error("q", "must not be empty"));
+        } else if q.chars().count() > MAX_SEARCH_QUERY_LENGTH {
+            errors.push(field_error(
+                "q",
+                format!("must be at most {} characters", MAX_SEARCH_QUERY_LENGTH),
+            ));
+        }
+        if let Some(limit) = self.limit {
+            if !(1..=MAX_QUERY_LIMIT).contains(&limit) {
+                errors.push(field_error(
+                    "limit",
+                    format!("must be between 1 and {}", MAX_QUERY_LIMIT),
+                ));
+            }
+        }
+        errors
+    }
+}
+
+impl Validate for GetAllQuery {
+    fn validate(&self) -> Vec<FieldError> {
+        // Any u64 is acceptable; one the server can't diff against falls back to a full reply.
+        let mut errors = Vec::new();
+        check_page_query(&mut errors, self.limit, self.cursor.as_deref());
+        if self.since_snapshot_seq.is_some() && (self.limit.is_some() || self.cursor.is_some()) {
+            errors.push(field_error(
+                "since_snapshot_seq",
+                "cannot be combined with limit or cursor",
+            ));
+        }
+        errors
+    }
+}
+
+impl Validate for PageQuery {
+    fn validate(&self) -> Vec<FieldError> {
+        let mut errors = Vec::new();
+        check_page_query(&mut errors, self.limit, self.cursor.as_deref());
+        errors
+    }
+}
+
+impl Validate for FleetQuery {
+    fn validate(&self) -> Vec<FieldError> {
+        let mut errors = Vec::new();
+        check_page_query(&mut errors, self.limit, self.cursor.as_deref());
+        if let Some(depot) = &self.depot {
+            if depot.trim().is_empty() || depot.chars().count() > MAX_ID_LENGTH {
+                errors.push(field_error(
+                    "depot",
+                    format!("must be 1 to {} characters", MAX_ID_LENGTH),
+                ));
+            }
+        }
+        errors
+    }
+}
+
+impl Validate for AsOfQuery {
+    fn validate(&self) -> Vec<FieldError> {
+        let mut errors = Vec::new();
+        if self.as_of.is_some_and(|as_of| as_of <= 0) {
+            errors.push(field_error(
+                "as_of",
+                "must be a positive unix timestamp in ms",
+            ));
+        }
+        errors
+    }
+}
+
+impl Validate for ServiceDeliveryQuery {
+    fn validate(&self) -> Vec<FieldError> {
+        let mut errors = Vec::new();
+        if self
+            .date
+            .as_deref()
+            .is_some_and(|date| NaiveDate::parse_from_str(date.trim(), "%Y-%m-%d").is_err())
+        {
+            errors.push(field_error("date", "must be a date as YYYY-MM-DD"));
+        }
+        errors
+    }
+}
+
+impl Validate for ShapeQuery {
+    fn validate(&self) -> Vec<FieldError> {
+        let mut errors = Vec::new();
+        if self
+            .format
+            .as_deref()
+            .is_some_and(|format| !matches!(format.trim(), "json" | "geojson"))
+        {
+            errors.push(field_error("format", "must be json or geojson"));
+        }
+        errors
+    }
+}
+
+impl Validate for ActivityQuery {
+    fn validate(&self) -> Vec<FieldError> {
+        let mut errors = Vec::new();
+        if self
+            .hours
+            .is_some_and(|hours| !(1..=ACTIVITY_RETENTION_HOURS).contains(&hours))
+        {
+            errors.push(field_error(
+                "hours",
+                format!("must be between 1 and {}", ACTIVITY_RETENTION_HOURS),
+            ));
+        }
+        errors
+    }
+}
+
+impl Validate for UsageQuery {
+    fn validate(&self) -> Vec<FieldError> {
+        let mut errors = Vec::new();
+        if self
+            .days
+            .is_some_and(|days| !(1..=USAGE_RETENTION_DAYS).contains(&days))
+        {
+            errors.push(field_error(
+                "days",
+                format!("must be between 1 and {}", USAGE_RETENTION_DAYS),
+            ));
+        }
+        if self
+            .limit
+            .is_some_and(|limit| !(1..=MAX_QUERY_LIMIT).contains(&limit))
+        {
+            errors.push(field_error(
+                "limit",
+                format!("must be between 1 and {}", MAX_QUERY_LIMIT),
+            ));
+        }
+        errors
+    }
+}
+
+impl Validate for MultiStopEtaQuery {
+    fn validate(&self) -> Vec<FieldError> {
+        let mut errors = Vec::new();
+        let stops: Vec<&str> = self.stops.split(',').collect();
+        if stops.len() > MAX_ETA_STOPS {
+            errors.push(field_error(
+                "stops",
+                format!("must list at most {} stops", MAX_ETA_STOPS),
+            ));
+        } else {
+            for stop in stops {
+                check_id(&mut errors, "stops", stop);
+            }
+        }
+        if self.as_of.is_some_and(|as_of| as_of <= 0) {
+            errors.push(field_error(
+                "as_of",
+                "must be a positive unix timestamp in ms",
+            ));
+        }
+        errors
+    }
+}
+
+// Deserializing a bool already rejects anything but true/false.
+impl Validate for SnapshotExportQuery {
+    fn validate(&self) -> Vec<FieldError> {
+        Vec::new()
+    }
+}
+
+impl Validate for SchemaPath {
+    fn validate(&self) -> Vec<FieldError> {
+        let mut errors = Vec::new();
+        check_id(&mut errors, "type_name", &self.type_name);
+        errors
+    }
+}
+
+impl Validate for DeparturesIcsQuery {
+    fn validate(&self) -> Vec<FieldError> {
+        let mut errors = Vec::new();
+        if let Some(route) = &self.route {
+            check_id(&mut errors, "route", route);
+        }
+        errors
+    }
+}
+
+impl Validate for RouteMapQuery {
+    fn validate(&self) -> Vec<FieldError> {
+        let mut errors = Vec::new();
+        for (field, value) in [("width", self.width), ("height", self.height)] {
+            if let Some(value) = value {
+                if !(MIN_MAP_DIMENSION..=MAX_MAP_DIMENSION).contains(&value) {
+                    errors.push(field_error(
+                        field,
+                        format!(
+                            "must be between {} and {}",
+                            MIN_MAP_DIMENSION, MAX_MAP_DIMENSION
+                        ),
+                    ));
+                }
+            }
+        }
+        errors
+    }
+}
+
+impl Validate for GtfsProxyQuery {
+    fn validate(&self) -> Vec<FieldError> {
+        let mut errors = Vec::new();
+        if let Some(format) = &self.format {
+            if !matches!(
+                format.trim().to_lowercase().as_str(),
+                "json" | "protobuf" | "pb"
+            ) {
+                errors.push(field_error("format", "must be json or protobuf"));
+            }
+        }
+        errors
+    }
+}
+
+// FEATURE_FLAGS="runtime_profile_eta=100,direction_inference=0" sets the starting rollout.
+// Unknown names and percentages over 100 are configuration mistakes, so they stop startup.
+pub(crate) fn feature_flags_from_env() -> FeatureFlags {
+    let mut defaults: HashMap<String, u8> = KNOWN_FEATURE_FLAGS
+        .iter()
+        .map(|(flag, percent)| (flag.to_string(), *percent))
+        .collect();
+    let raw = env::var("FEATURE_FLAGS").unwrap_or_default();
+    for entry in raw
+        .split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+    {
+        let parsed = entry
+            .split_once('=')
+            .and_then(|(flag, percent)| Some((flag.trim(), percent.trim().parse::<u8>().ok()?)))
+            .filter(|(flag, percent)| is_known_feature_flag(flag) && *percent <= 100);
+        match parsed {
+            Some((flag, percent)) => {
+                defaults.insert(flag.to_string(), percent);
+            }
+            None => panic!(
+                "Invalid FEATURE_FLAGS entry '{}'; expected one of {:?} as name=0..100",
+                entry,
+                KNOWN_FEATURE_FLAGS.map(|(flag, _)| flag)
+            ),
+        }
+    }
+    // SHADOW_FEATURE_FLAGS="runtime_profile_eta" serves the old behavior and measures the new one.
+    let shadow: HashSet<String> = env::var("SHADOW_FEATURE_FLAGS")
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|flag| !flag.is_empty())
+        .map(|flag| {
+            if !is_known_feature_flag(flag) {
+                panic!(
+                    "Invalid SHADOW_FEATURE_FLAGS entry '{}'; expected one of {:?}",
+                    flag,
+                    KNOWN_FEATURE_FLAGS.map(|(flag, _)| flag)
+                );
+            }
+            flag.to_string()
+        })
+        .collect();
+    new_feature_flags(defaults, shadow)
+}
+
+pub(crate) fn new_feature_flags(
+    defaults: HashMap<String, u8>,
+    shadow: HashSet<String>,
+) -> FeatureFlags {
+    FeatureFlags {
+        rollout_percent: std::sync::Mutex::new(defaults.clone()),
+        defaults,
+        shadow,
+        evaluations: std::sync::Mutex::new(HashMap::new()),
+    }
+}
+
+pub(crate) fn is_known_feature_flag(flag: &str) -> bool {
+    KNOWN_FEATURE_FLAGS.iter().any(|(known, _)| *known == flag)
+}
+
+// The subject is what gets bucketed: the stop for stop-wide ETAs, the route otherwise. Hashing
+// it with the flag name keeps a subject on one variant across requests and instances without
+// tying the variants of different flags together.
+fn feature_enabled(flags: &FeatureFlags, flag: &str, subject: &str) -> bool {
+    let percent = match flags.rollout_percent.lock() {
+        Ok(rollout_percent) => rollout_percent.get(flag).copied(),
+        Err(_) => flags.defaults.get(flag).copied(),
+    }
+    .unwrap_or(0);
+    let enabled = match percent {
+        0 => false,
+        percent if percent >= 100 => true,
+        percent => feature_bucket(flag, subject) < percent,
+    };
+    if let Ok(mut evaluations) = flags.evaluations.lock() {
+        *evaluations.entry((flag.to_string(), enabled)).or_default() += 1;
+    }
+    enabled
+}
+
+// FNV-1a, so the bucket is the same on every instance and every build.
+fn feature_bucket(flag: &str, subject: &str) -> u8 {
+    let hash = flag
+        .bytes()
+        .chain(std::iter::once(b':'))
+        .chain(subject.bytes())
+        .fold(0xcbf29ce484222325_u64, |hash, byte| {
+            (hash ^ u64::from(byte)).wrapping_mul(0x100000001b3)
+        });
+    (hash % 100) as u8
+}
+
+async fn get_feature_flags(
+    headers: HeaderMap,
+    State(state): State<AppState>,
+) -> Result<Json<HashMap<String, u8>>, AppError> {
+    require_admin(&state, &headers)?;
+    let rollout_percent = state
+        .feature_flags
+        .rollout_percent
+        .lock()
+        .map_err(internal_error)?
+        .clone();
+    Ok(Json(rollout_percent))
+}
+
+// Persisted per city in Redis so every instance converges on the new rollout within one
+// refresh; this instance applies it immediately.
+async fn patch_feature_flags(
+    headers: HeaderMap,
+    State(state): State<AppState>,
+    Json(patch): Json<HashMap<String, u8>>,
+) -> Result<Json<HashMap<String, u8>>, AppError> {
+    require_admin(&state, &headers)?;
+    let field_errors: Vec<FieldError> = patch
+        .iter()
+        .filter_map(|(flag, percent)| {
+            let message = if !is_known_feature_flag(flag) {
+                "unknown feature flag"
+            } else if *percent > 100 {
+                "must be a percentage between 0 and 100"
+            } else {
+                return None;
+            };
+            Some(FieldError {
+                field: flag.clone(),
+                message: message.to_string(),
+            })
+        })
+        .collect();
+    if !field_errors.is_empty() {
+        return Err(AppError::InvalidFields(field_errors));
+    }
+    if patch.is_empty() {
+        return get_feature_flags(headers, State(state)).await;
+    }
+
+    let mut redis_conn = state
+        .redis_client
+        .get_multiplexed_async_connection()
+        .await?;
+    let mut hset = redis::cmd("HSET");
+    hset.arg(state.redis_keys.key(REDIS_FEATURE_FLAGS_KEY));
+    for (flag, percent) in &patch {
+        hset.arg(flag).arg(percent);
+    }
+    hset.query_async::<()>(&mut redis_conn).await?;
+
+    let mut rollout_percent = state
+        .feature_flags
+        .rollout_percent
+        .lock()
+        .map_err(internal_error)?;
+    rollout_percent.extend(patch);
+    println!("Updated feature flags: {:?}", rollout_percent);
+    Ok(Json(rollout_percent.clone()))
+}
+
+#[derive(Debug, Deserialize)]
+struct RouteDetourPath {
+    route_id: String,
+    detour_id: String,
+}
+
+impl Validate for RouteDetourPath {
+    fn validate(&self) -> Vec<FieldError> {
+        let mut errors = Vec::new();
+        check_id(&mut errors, "route_id", &self.route_id);
+        check_id(&mut errors, "detour_id", &self.detour_id);
+        errors
+    }
+}
+
+// Declared detours on the route that have not expired yet, upcoming ones included.
+async fn get_route_detours(
+    headers: HeaderMap,
+    RouteRef { route_id }: RouteRef,
+    State(state): State<AppState>,
+) -> Result<Json<RouteDetoursResponse>, AppError> {
+    require_admin(&state, &headers)?;
+    let detours = state
+        .detours
+        .lock()
+        .map_err(internal_error)?
+        .get(&route_id)
+        .cloned()
+        .unwrap_or_default();
+    Ok(Json(RouteDetoursResponse { route_id, detours }))
+}
+
+// Skipped stops must be on the route. A detour with a shape replaces one stretch of it, so its
+// stops have to be consecutive with a served stop on either side for the path to join.
+fn validate_detour(
+    detour: &RouteDetour,
+    route_stops: &RouteStopsResponse,
+    now_ms: i64,
+) -> Vec<FieldError> {
+    let mut errors = Vec::new();
+    if detour.skipped_stop_ids.is_empty() {
+        errors.push(field_error("skipped_stop_ids", "must not be empty"));
+    }
+    let mut skipped_indices: Vec<usize> = Vec::new();
+    for stop_id in &detour.skipped_stop_ids {
+        match route_stops
+            .stops
+            .iter()
+            .position(|stop| stop.stop_id == *stop_id)
+        {
+            Some(index) => skipped_indices.push(index),
+            None => errors.push(field_error(
+                "skipped_stop_ids",
+                format!("stop '{}' is not on route '{}'", stop_id, detour.route_id),
+            )),
+        }
+    }
+    if !detour.shape.is_empty() && !skipped_indices.is_empty() {
+        skipped_indices.sort_unstable();
+        skipped_indices.dedup();
+        let first = skipped_indices[0];
+        let last = skipped_indices[skipped_indices.len() - 1];
+        if last - first + 1 != skipped_indices.len() {
+            errors.push(field_error(
+                "skipped_stop_ids",
+                "must be consecutive stops when a shape is given",
+            ));
+        } else if first == 0 || last + 1 == route_stops.stops.len() {
+            errors.push(field_error(
+                "skipped_stop_ids",
+                "must not include the first or last stop when a shape is given",
+            ));
+        }
+    }
+    for (index, point) in detour.shape.iter().enumerate() {
+        check_range(
+            &mut errors,
+            &format!("shape[{}].lat", index),
+            point.lat,
+            -90.0,
+            90.0,
+        );
+        check_range(
+            &mut errors,
+            &format!("shape[{}].lon", index),
+            point.lon,
+            -180.0,
+            180.0,
+        );
+    }
+    check_service_change_window(
+        &mut errors,
+        detour.valid_from_unix_ms,
+        detour.valid_until_unix_ms,
+        now_ms,
+    );
+    check_service_change_message(&mut errors, &detour.message);
+    errors
+}
+
+fn check_service_change_window(
+    errors: &mut Vec<FieldError>,
+    valid_from_unix_ms: i64,
+    valid_until_unix_ms: i64,
+    now_ms: i64,
+) {
+    if valid_until_unix_ms <= valid_from_unix_ms {
+        errors.push(field_error(
+            "valid_until_unix_ms",
+            "must be after valid_from_unix_ms",
+        ));
+    } else if valid_until_unix_ms <= now_ms {
+        errors.push(field_error("valid_until_unix_ms", "must be in the future"));
+    }
+}
+
+fn check_service_change_message(errors: &mut Vec<FieldError>, message: &str) {
+    if message.is_empty() {
+        errors.push(field_error("message", "must not be empty"));
+    } else if message.chars().count() > MAX_SERVICE_CHANGE_MESSAGE_CHARS {
+        errors.push(field_error(
+            "message",
+            format!(
+                "must be at most {} characters",
+                MAX_SERVICE_CHANGE_MESSAGE_CHARS
+            ),
+        ));
+    }
+}
+
+// Persisted per city like feature flags, so every instance applies the detour within one
+// refresh; this instance applies it immediately.
+async fn post_route_detour(
+    headers: HeaderMap,
+    RouteRef { route_id }: RouteRef,
+    State(state): State<AppState>,
+    Json(request): Json<DetourRequest>,
+) -> Result<Json<RouteDetour>, AppError> {
+    require_admin(&state, &headers)?;
+    let gtfs = load_gtfs_context(&state);
+    let route_stops = cached_stops_by_route(&gtfs, &route_id, None)?;
+    let now_ms = state.clock.now_ms();
+    let detour = RouteDetour {
+        id: format!("{}-{}", route_id, now_ms),
+        route_id,
+        skipped_stop_ids: request
+            .skipped_stop_ids
+            .iter()
+            .map(|stop_id| stop_id.trim().to_string())
+            .collect(),
+        shape: request.shape,
+        valid_from_unix_ms: request.valid_from_unix_ms.unwrap_or(now_ms),
+        valid_until_unix_ms: request.valid_until_unix_ms,
+        message: request.message.trim().to_string(),
+        created_at_unix_ms: now_ms,
+    };
+    let errors = validate_detour(&detour, &route_stops, now_ms);
+    if !errors.is_empty() {
+        return Err(AppError::InvalidFields(errors));
+    }
+
+    let mut redis_conn = state
+        .redis_client
+        .get_multiplexed_async_connection()
+        .await?;
+    redis::cmd("HSET")
+        .arg(state.redis_keys.key(REDIS_DETOURS_KEY))
+        .arg(&detour.id)
+        .arg(serde_json::to_string(&detour).map_err(internal_error)?)
+        .query_async::<()>(&mut redis_conn)
+        .await?;
+    state
+        .detours
+        .lock()
+        .map_err(internal_error)?
+        .entry(detour.route_id.clone())
+        .or_default()
+        .push(detour.clone());
+    println!(
+        "Declared detour {} on route {}: {} stops skipped until {}",
+        detour.id,
+        detour.route_id,
+        detour.skipped_stop_ids.len(),
+        detour.valid_until_unix_ms
+    );
+    Ok(Json(detour))
+}
+
+// Ends a detour early; ETAs and boards go back to the scheduled route on the next request.
+async fn delete_route_detour(
+    headers: HeaderMap,
+    ValidPath(RouteDetourPath {
+        route_id,
+        detour_id,
+    }): ValidPath<RouteDetourPath>,
+    State(state): State<AppState>,
+) -> Result<Json<RouteDetoursResponse>, AppError> {
+    require_admin(&state, &headers)?;
+    let RouteRef { route_id } = resolve_route_ref(&current_gtfs_feed(&state), &route_id)?;
+    let detour_id = detour_id.trim();
+    let is_declared = state
+        .detours
+        .lock()
+        .map_err(internal_error)?
+        .get(&route_id)
+        .is_some_and(|detours| detours.iter().any(|detour| detour.id == detour_id));
+    if !is_declared {
+        return Err(AppError::NotFound(format!(
+            "Detour '{}' not found on route '{}'",
+            detour_id, route_id
+        )));
+    }
+
+    let mut redis_conn = state
+        .redis_client
+        .get_multiplexed_async_connection()
+        .await?;
+    redis::cmd("HDEL")
+        .arg(state.redis_keys.key(REDIS_DETOURS_KEY))
+        .arg(detour_id)
+        .query_async::<()>(&mut redis_conn)
+        .await?;
+    let mut detours = state.detours.lock().map_err(internal_error)?;
+    let route_detours = detours.entry(route_id.clone()).or_default();
+    route_detours.retain(|detour| detour.id != detour_id);
+    println!("Ended detour {} on route {}", detour_id, route_id);
+    Ok(Json(RouteDetoursResponse {
+        route_id,
+        detours: route_detours.clone(),
+    }))
+}
+
+#[derive(Debug, Deserialize)]
+struct StopClosurePath {
+    stop_id: String,
+    closure_id: String,
+}
+
+impl Validate for StopClosurePath {
+    fn validate(&self) -> Vec<FieldError> {
+        let mut errors = Vec::new();
+        check_id(&mut errors, "stop_id", &self.stop_id);
+        check_id(&mut errors, "closure_id", &self.closure_id);
+        errors
+    }
+}
+
+// Declared closures of the stop that have not expired yet, upcoming ones included.
+async fn get_stop_closures(
+    headers: HeaderMap,
+    StopRef { stop_id, .. }: StopRef,
+    State(state): State<AppState>,
+) -> Result<Json<StopClosuresResponse>, AppError> {
+    require_admin(&state, &headers)?;
+    let closures = state
+        .stop_closures
+        .lock()
+        .map_err(internal_error)?
+        .get(&stop_id)
+        .cloned()
+        .unwrap_or_default();
+    Ok(Json(StopClosuresResponse { stop_id, closures }))
+}
+
+// Persisted per city next to detours; this instance applies the closure immediately.
+async fn post_stop_closure(
+    headers: HeaderMap,
+    StopRef { stop_id, .. }: StopRef,
+    State(state): State<AppState>,
+    Json(request): Json<StopClosureRequest>,
+) -> Result<Json<StopClosure>, AppError> {
+    require_admin(&state, &headers)?;
+    let stop_name = current_gtfs_feed(&state)
+        .stops_map
+        .get(&stop_id)
+        .map(|stop| stop.stop_name.clone())
+        .unwrap_or_default();
+    let now_ms = state.clock.now_ms();
+    let closure = StopClosure {
+        id: format!("{}-{}", stop_id, now_ms),
+        stop_id,
+        stop_name,
+        valid_from_unix_ms: request.valid_from_unix_ms.unwrap_or(now_ms),
+        valid_until_unix_ms: request.valid_until_unix_ms,
+        message: request.message.trim().to_string(),
+        created_at_unix_ms: now_ms,
+    };
+    let mut errors = Vec::new();
+    check_service_change_window(
+        &mut errors,
+        closure.valid_from_unix_ms,
+        closure.valid_until_unix_ms,
+        now_ms,
+    );
+    check_service_change_message(&mut errors, &closure.message);
+    if !errors.is_empty() {
+        return Err(AppError::InvalidFields(errors));
+    }
+
+    let mut redis_conn = state
+        .redis_client
+        .get_multiplexed_async_connection()
+        .await?;
+    redis::cmd("HSET")
+        .arg(state.redis_keys.key(REDIS_STOP_CLOSURES_KEY))
+        .arg(&closure.id)
+        .arg(serde_json::to_string(&closure).map_err(internal_error)?)
+        .query_async::<()>(&mut redis_conn)
+        .await?;
+    state
+        .stop_closures
+        .lock()
+        .map_err(internal_error)?
+        .entry(closure.stop_id.clone())
+        .or_default()
+        .push(closure.clone());
+    println!(
+        "Declared closure {} of stop {} until {}",
+        closure.id, closure.stop_id, closure.valid_until_unix_ms
+    );
+    Ok(Json(closure))
+}
+
+// Reopens a stop early.
+async fn delete_stop_closure(
+    headers: HeaderMap,
+    ValidPath(StopClosurePath {
+        stop_id,
+        closure_id,
+    }): ValidPath<StopClosurePath>,
+    State(state): State<AppState>,
+) -> Result<Json<StopClosuresResponse>, AppError> {
+    require_admin(&state, &headers)?;
+    let StopRef { stop_id, .. } = resolve_stop_ref(&current_gtfs_feed(&state), &stop_id)?;
+    let closure_id = closure_id.trim();
+    let is_declared = state
+        .stop_closures
+        .lock()
+        .map_err(internal_error)?
+        .get(&stop_id)
+        .is_some_and(|closures| closures.iter().any(|closure| closure.id == closure_id));
+    if !is_declared {
+        return Err(AppError::NotFound(format!(
+            "Closure '{}' not found for stop '{}'",
+            closure_id, stop_id
+        )));
+    }
+
+    let mut redis_conn = state
+        .redis_client
+        .get_multiplexed_async_connection()
+        .await?;
+    redis::cmd("HDEL")
+        .arg(state.redis_keys.key(REDIS_STOP_CLOSURES_KEY))
+        .arg(closure_id)
+        .query_async::<()>(&mut redis_conn)
+        .await?;
+    let mut closures = state.stop_closures.lock().map_err(internal_error)?;
+    let stop_closures = closures.entry(stop_id.clone()).or_default();
+    stop_closures.retain(|closure| closure.id != closure_id);
+    println!("Ended closure {} of stop {}", closure_id, stop_id);
+    Ok(Json(StopClosuresResponse {
+        stop_id,
+        closures: stop_closures.clone(),
+    }))
+}
+
+// Closures are declared here rather than upstream, so they join the feed as alerts of their own.
+fn stop_closure_alert(closure: &StopClosure) -> ServiceAlert {
+    ServiceAlert {
+        id: format!("stop-closure-{}", closure.id),
+        header: format!("Stop closed: {}", closure.stop_name),
+        description: Some(closure.message.clone()),
+        url: None,
+        route_ids: Vec::new(),
+        stop_ids: vec![closure.stop_id.clone()],
+        active_from_unix_ms: Some(closure.valid_from_unix_ms),
+        active_until_unix_ms: Some(closure.valid_until_unix_ms),
+        first_seen_unix_ms: closure.created_at_unix_ms,
+        updated_at_unix_ms: closure.created_at_unix_ms,
+    }
+}
+
+pub(crate) fn internal_error(error: impl std::fmt::Display) -> AppError {
+    AppError::Internal(error.to_string())
+}
+
+fn require_admin(state: &AppState, headers: &HeaderMap) -> Result<(), AppError> {
+    let Some(expected_key) = state.admin_api_key.as_deref() else {
+        return Err(AppError::Disabled(
+            "Admin API is disabled; set ADMIN_API_KEY to enable it".to_string(),
+        ));
+    };
+
+    // Accept a bearer token for API clients, or basic auth (any username, the key as password)
+    // so the dashboard works from a browser prompt.
+    let authorization = headers
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default();
+    let provided_key = if let Some(token) = authorization.strip_prefix("Bearer ") {
+        Some(token.to_string())
+    } else if let Some(credentials) = authorization.strip_prefix("Basic ") {
+        base64::engine::general_purpose::STANDARD
+            .decode(credentials.trim())
+            .ok()
+            .and_then(|decoded| String::from_utf8(decoded).ok())
+            .and_then(|decoded| {
+                decoded
+                    .split_once(':')
+                    .map(|(_, password)| password.to_string())
+            })
+    } else {
+        None
+    };
+
+    if provided_key.as_deref() != Some(expected_key) {
+        return Err(AppError::Unauthorized(
+            "Missing or invalid admin API key".to_string(),
+        ));
+    }
+
+    Ok(())
+}
+
+// Bus pass-bys per stop (Point) and per stretch of shape between consecutive stops (LineString)
+// over the last `hours`, busiest first, as a GeoJSON FeatureCollection for heat maps.
+async fn get_viz_activity(
+    ValidQuery(query): ValidQuery<ActivityQuery>,
+    State(state): State<AppState>,
+) -> Result<Response, AppError> {
+    let hours = query.hours.unwrap_or(DEFAULT_ACTIVITY_HOURS);
+    let (stop_counts, segment_counts) = load_activity(&state, hours).await?;
+    let gtfs = current_gtfs_feed(&state);
+    let stops_map = &gtfs.stops_map;
+    let geometries: HashMap<&str, &RouteGeometry> = gtfs
+        .route_geometries
+        .values()
+        .map(|geometry| (geometry.route_id.as_str(), geometry))
+        .collect();
+
+    let mut features: Vec<(u64, serde_json::Value)> = Vec::new();
+    for (stop_id, count) in stop_counts {
+        let Some(stop) = stops_map.get(&stop_id) else {
+            continue;
+        };
+        features.push((
+            count,
+            json!({
+                "type": "Feature",
+                "geometry": {
+                    "type": "Point",
+                    "coordinates": [stop.stop_lon, stop.stop_lat],
+                },
+                "properties": {
+                    "kind": "stop",
+                    "stop_id": stop.stop_id,
+                    "stop_name": stop.stop_name,
+                    "pass_bys": count,
+                },
+            }),
+        ));
+    }
+    for ((route_id, from_stop_id, to_stop_id), count) in segment_counts {
+        let (Some(geometry), Some(from_stop), Some(to_stop)) = (
+            geometries.get(route_id.as_str()),
+            stops_map.get(&from_stop_id),
+            stops_map.get(&to_stop_id),
+        ) else {
+            continue;
+        };
+        let chainage = |stop_id: &str| {
+            geometry
+                .stop_chainages
+                .iter()
+                .find(|(id, _)| id == stop_id)
+                .map(|(_, chainage_m)| *chainage_m)
+        };
+        let (Some(from_m), Some(to_m)) = (
+            chainage(from_stop_id.as_str()),
+            chainage(to_stop_id.as_str()),
+        ) else {
+            continue;
+        };
+        let mut coordinates = vec![[from_stop.stop_lon, from_stop.stop_lat]];
+        coordinates.extend(
+            geometry
+                .points
+                .iter()
+                .zip(&geometry.cumulative_m)
+                .filter(|(_, cumulative_m)| **cumulative_m > from_m && **cumulative_m < to_m)
+                .map(|((lat, lon), _)| [*lon, *lat]),
+        );
+        coordinates.push([to_stop.stop_lon, to_stop.stop_lat]);
+        features.push((
+            count,
+            json!({
+                "type": "Feature",
+                "geometry": {
+                    "type": "LineString",
+                    "coordinates": coordinates,
+                },
+                "properties": {
+                    "kind": "segment",
+                    "route_id": route_id,
+                    "shape_id": geometry.shape_id,
+                    "from_stop_id": from_stop_id,
+                    "to_stop_id": to_stop_id,
+                    "pass_bys": count,
+                },
+            }),
+        ));
+    }
+    features.sort_by_key(|(count, _)| std::cmp::Reverse(*count));
+
+    println!(
+        "Calling get_viz_activity for hours={}: {} features",
+        hours,
+        features.len()
+    );
+    let body = json!({
+        "type": "FeatureCollection",
+        "window_hours": hours,
+        "generated_at_unix_ms": state.clock.now_ms(),
+        "features": features.into_iter().map(|(_, feature)| feature).collect::<Vec<_>>(),
+    });
+    Ok(([(CONTENT_TYPE, "application/geo+json")], body.to_string()).into_response())
+}
+
+async fn get_alerts_atom(State(state): State<AppState>) -> Result<Response, AppError> {
+    let mut redis_conn = read_redis_client(&state)
+        .get_multiplexed_async_connection()
+        .await?;
+    let stored: HashMap<String, String> = redis::cmd("HGETALL")
+        .arg(state.redis_keys.key(REDIS_SERVICE_ALERTS_KEY))
+        .query_async(&mut redis_conn)
+        .await?;
+
+    let mut alerts: Vec<ServiceAlert> = stored
+        .values()
+        .filter_map(|value| serde_json::from_str(value).ok())
+        .collect();
+    alerts.extend(
+        state
+            .stop_closures
+            .lock()
+            .map_err(internal_error)?
+            .values()
+            .flatten()
+            .map(stop_closure_alert),
+    );
+    alerts.sort_by_key(|alert| std::cmp::Reverse(alert.updated_at_unix_ms));
+
+    let feed_updated = alerts
+        .first()
+        .map(|alert| alert.updated_at_unix_ms)
+        .unwrap_or_else(now_unix_ms);
+    let mut body = String::from("<?xml version=\"1.0\" encoding=\"utf-8\"?>\n");
+    body.push_str("<feed xmlns=\"http://www.w3.org/2005/Atom\">\n");
+    body.push_str("  <id>urn:rapidbro:alerts</id>\n");
+    body.push_str("  <title>rapidbro service alerts</title>\n");
+    body.push_str(&format!(
+        "  <updated>{}</updated>\n",
+        format_unix_ms(Some(feed_updated))
+    ));
+    body.push_str("  <author><name>rapidbro</name></author>\n");
+
+    for alert in &alerts {
+        body.push_str("  <entry>\n");
+        body.push_str(&format!(
+            "    <id>urn:rapidbro:alert:{}</id>\n",
+            html_escape(&alert.id)
+        ));
+        body.push_str(&format!(
+            "    <title>{}</title>\n",
+            html_escape(&alert.header)
+        ));
+        body.push_str(&format!(
+            "    <published>{}</published>\n",
+            format_unix_ms(Some(alert.first_seen_unix_ms))
+        ));
+        body.push_str(&format!(
+            "    <updated>{}</updated>\n",
+            format_unix_ms(Some(alert.updated_at_unix_ms))
+        ));
+        if let Some(url) = &alert.url {
+            body.push_str(&format!("    <link href=\"{}\"/>\n", html_escape(url)));
+        }
+        for route_id in &alert.route_ids {
+            body.push_str(&format!(
+                "    <category term=\"{}\"/>\n",
+                html_escape(route_id)
+            ));
+        }
+        let mut summary = alert.description.clone().unwrap_or_default();
+        if let Some(until_ms) = alert.active_until_unix_ms {
+            summary.push_str(&format!(" (until {})", format_unix_ms(Some(until_ms))));
+        }
+        body.push_str(&format!(
+            "    <summary>{}</summary>\n",
+            html_escape(summary.trim())
+        ));
+        body.push_str("  </entry>\n");
+    }
+    body.push_str("</feed>\n");
+
+    println!("Calling get_alerts_atom: {} alerts", alerts.len());
+    Ok((
+        [(CONTENT_TYPE, "application/atom+xml; charset=utf-8")],
+        body,
+    )
+        .into_response())
+}
+
+async fn get_incidents(State(state): State<AppState>) -> Result<Json<IncidentsResponse>, AppError> {
+    let mut redis_conn = read_redis_client(&state)
+        .get_multiplexed_async_connection()
+        .await?;
+    let incidents = load_incidents(&mut redis_conn, &state.redis_keys)
+        .await
+        .map_err(internal_error)?;
+
+    println!("Calling get_incidents: {} incidents", incidents.len());
+    Ok(Json(IncidentsResponse { data: incidents }))
+}
+
+// Prometheus text exposition. Routes stay listed after their buses go quiet so a route that
+// goes dark shows up as zero active buses with a growing data age instead of vanishing.
+pub(crate) async fn get_metrics(State(state): State<AppState>) -> Result<Response, AppError> {
+    let snapshot = load_active_bus_snapshot(&state).await?;
+    let mut redis_conn = read_redis_client(&state)
+        .get_multiplexed_async_connection()
+        .await?;
+    let route_last_seen: HashMap<String, i64> = redis::cmd("HGETALL")
+        .arg(state.redis_keys.key(REDIS_ROUTES_LAST_SEEN_KEY))
+        .query_async(&mut redis_conn)
+        .await?;
+    let ingestor_status = state.ingestor_status.read().await.clone();
+    let now_ms = state.clock.now_ms();
+
+    let mut route_active_buses: HashMap<String, usize> = route_last_seen
+        .keys()
+        .map(|route| (route.clone(), 0))
+        .collect();
+    for bus in &snapshot.buses {
+        *route_active_buses
+            .entry(normalize_route_code(&bus.route))
+            .or_default() += 1;
+    }
+    let mut routes: Vec<&String> = route_active_buses.keys().collect();
+    routes.sort();
+
+    let mut body = String::new();
+    body.push_str(
+        "# HELP rapidbro_route_active_buses Buses currently reporting on the route.\n\
+         # TYPE rapidbro_route_active_buses gauge\n",
+    );
+    for route in &routes {
+        body.push_str(&format!(
+            "rapidbro_route_active_buses{{route=\"{}\"}} {}\n",
+            prometheus_label_escape(route),
+            route_active_buses[*route]
+        ));
+    }
+    body.push_str(
+        "# HELP rapidbro_route_data_age_seconds Seconds since any bus last reported on the route.\n\
+         # TYPE rapidbro_route_data_age_seconds gauge\n",
+    );
+    for route in &routes {
+        if let Some(last_seen_ms) = route_last_seen.get(*route) {
+            body.push_str(&format!(
+                "rapidbro_route_data_age_seconds{{route=\"{}\"}} {:.3}\n",
+                prometheus_label_escape(route),
+                (now_ms - last_seen_ms).max(0) as f64 / 1_000.0
+            ));
+        }
+    }
+    body.push_str(&state.gps_to_redis_seconds.render(
+        "rapidbro_gps_to_redis_seconds",
+        "Time from a bus's GPS fix to its position being written to Redis.",
+    ));
+    body.push_str(&state.redis_to_response_seconds.render(
+        "rapidbro_redis_to_response_seconds",
+        "Age of a live position since it was written to Redis, when a response serving it is built.",
+    ));
+    body.push_str(&format!(
+        "# HELP rapidbro_route_stops_cache_requests_total Route stop sequence lookups by cache outcome.\n\
+         # TYPE rapidbro_route_stops_cache_requests_total counter\n\
+         rapidbro_route_stops_cache_requests_total{{result=\"hit\"}} {}\n\
+         rapidbro_route_stops_cache_requests_total{{result=\"miss\"}} {}\n",
+        state.route_stops_cache.hits.load(AtomicOrdering::Relaxed),
+        state.route_stops_cache.misses.load(AtomicOrdering::Relaxed)
+    ));
+    let history_cache = &state.history_frame_cache;
+    body.push_str(&format!(
+        "# HELP rapidbro_history_cache_requests_total as_of history frame lookups by cache outcome.\n\
+         # TYPE rapidbro_history_cache_requests_total counter\n\
+         rapidbro_history_cache_requests_total{{result=\"hit\"}} {}\n\
+         rapidbro_history_cache_requests_total{{result=\"miss\"}} {}\n\
+         # HELP rapidbro_history_cache_evictions_total History frames dropped to stay within the memory budget.\n\
+         # TYPE rapidbro_history_cache_evictions_total counter\n\
+         rapidbro_history_cache_evictions_total {}\n\
+         # HELP rapidbro_history_cache_bytes Estimated bytes held by cached history frames.\n\
+         # TYPE rapidbro_history_cache_bytes gauge\n\
+         rapidbro_history_cache_bytes {}\n\
+         # HELP rapidbro_history_cache_budget_bytes Memory budget for cached history frames; 0 means disabled.\n\
+         # TYPE rapidbro_history_cache_budget_bytes gauge\n\
+         rapidbro_history_cache_budget_bytes {}\n",
+        history_cache.hits.load(AtomicOrdering::Relaxed),
+        history_cache.misses.load(AtomicOrdering::Relaxed),
+        history_cache.evictions.load(AtomicOrdering::Relaxed),
+        history_cache.used_bytes.load(AtomicOrdering::Relaxed),
+        history_cache.budget_bytes
+    ));
+    if let Some(lag) = *state.replica_lag.read().await {
+        body.push_str(&format!(
+            "# HELP rapidbro_redis_replica_link_up Whether the read replica is attached to the primary.\n\
+             # TYPE rapidbro_redis_replica_link_up gauge\n\
+             rapidbro_redis_replica_link_up {}\n\
+             # HELP rapidbro_redis_replica_lag_seconds How far the read replica trails the primary's last ingest.\n\
+             # TYPE rapidbro_redis_replica_lag_seconds gauge\n\
+             rapidbro_redis_replica_lag_seconds {:.3}\n",
+            u8::from(lag.link_up),
+            lag.lag_ms as f64 / 1_000.0
+        ));
+    }
+    let mut flag_evaluations: Vec<((String, bool), u64)> = state
+        .feature_flags
+        .evaluations
+        .lock()
+        .map(|evaluations| {
+            evaluations
+                .iter()
+                .map(|(key, count)| (key.clone(), *count))
+                .collect()
+        })
+        .unwrap_or_default();
+    flag_evaluations.sort();
+    body.push_str(
+        "# HELP rapidbro_feature_flag_evaluations_total Requests evaluated into each feature flag variant.\n\
+         # TYPE rapidbro_feature_flag_evaluations_total counter\n",
+    );
+    for ((flag, enabled), count) in &flag_evaluations {
+        body.push_str(&format!(
+            "rapidbro_feature_flag_evaluations_total{{flag=\"{}\",variant=\"{}\"}} {}\n",
+            prometheus_label_escape(flag),
+            if *enabled { "on" } else { "off" },
+            count
+        ));
+    }
+    let mut shadow_stats: Vec<(String, ShadowStats)> = state
+        .shadow_evaluation
+        .stats
+        .lock()
+        .map(|stats| {
+            stats
+                .iter()
+                .map(|(flag, stats)| (flag.clone(), stats.clone()))
+                .collect()
+        })
+        .unwrap_or_default();
+    shadow_stats.sort_by(|a, b| a.0.cmp(&b.0));
+    body.push_str(
+        "# HELP rapidbro_shadow_eta_comparisons_total ETAs computed both ways for a flag in shadow mode.\n\
+         # TYPE rapidbro_shadow_eta_comparisons_total counter\n\
+         # HELP rapidbro_shadow_eta_divergence_minutes_sum Absolute difference between served and shadow ETAs.\n\
+         # TYPE rapidbro_shadow_eta_divergence_minutes_sum counter\n\
+         # HELP rapidbro_shadow_eta_scored_total Shadow predictions settled by an observed departure.\n\
+         # TYPE rapidbro_shadow_eta_scored_total counter\n\
+         # HELP rapidbro_shadow_eta_error_minutes_sum Absolute arrival error of settled predictions by variant.\n\
+         # TYPE rapidbro_shadow_eta_error_minutes_sum counter\n",
+    );
+    for (flag, stats) in &shadow_stats {
+        let flag = prometheus_label_escape(flag);
+        body.push_str(&format!(
+            "rapidbro_shadow_eta_comparisons_total{{flag=\"{flag}\"}} {}\n\
+             rapidbro_shadow_eta_divergence_minutes_sum{{flag=\"{flag}\"}} {:.3}\n\
+             rapidbro_shadow_eta_scored_total{{flag=\"{flag}\"}} {}\n\
+             rapidbro_shadow_eta_error_minutes_sum{{flag=\"{flag}\",variant=\"served\"}} {:.3}\n\
+             rapidbro_shadow_eta_error_minutes_sum{{flag=\"{flag}\",variant=\"shadow\"}} {:.3}\n",
+            stats.comparisons,
+            stats.divergence_minutes_sum,
+            stats.scored,
+            stats.served_error_minutes_sum,
+            stats.shadow_error_minutes_sum,
+        ));
+    }
+    body.push_str(&format!(
+        "# HELP rapidbro_requests_shed_total Requests rejected with 503 by the load shedder.\n\
+         # TYPE rapidbro_requests_shed_total counter\n\
+         rapidbro_requests_shed_total {}\n",
+        state.load_shedder.shed_total.load(AtomicOrdering::Relaxed)
+    ));
+    body.push_str(&format!(
+        "# HELP rapidbro_stop_eta_computed_total Stop ETA responses computed since startup.\n\
+         # TYPE rapidbro_stop_eta_computed_total counter\n\
+         rapidbro_stop_eta_computed_total {}\n",
+        state.stop_eta_computed_total.load(AtomicOrdering::Relaxed)
+    ));
+    body.push_str(&format!(
+        "# HELP rapidbro_hot_stop_board_hits_total Stop ETA requests served from a precomputed hot-stop board.\n\
+         # TYPE rapidbro_hot_stop_board_hits_total counter\n\
+         rapidbro_hot_stop_board_hits_total {}\n\
+         # HELP rapidbro_hot_stop_boards Hot-stop boards currently precomputed.\n\
+         # TYPE rapidbro_hot_stop_boards gauge\n\
+         rapidbro_hot_stop_boards {}\n",
+        state.hot_stops.hits_total.load(AtomicOrdering::Relaxed),
+        state.hot_stops.boards.read().await.len()
+    ));
+    body.push_str(&format!(
+        "# HELP rapidbro_coalesced_requests_total Requests served by joining an identical in-flight computation.\n\
+         # TYPE rapidbro_coalesced_requests_total counter\n\
+         rapidbro_coalesced_requests_total{{endpoint=\"stop_eta\"}} {}\n\
+         rapidbro_coalesced_requests_total{{endpoint=\"route_eta\"}} {}\n",
+        state.stop_eta_flights.coalesced_total.load(AtomicOrdering::Relaxed),
+        state.route_eta_flights.coalesced_total.load(AtomicOrdering::Relaxed)
+    ));
+    body.push_str(&format!(
+        "# HELP rapidbro_active_buses Buses seen within the bus TTL across all routes.\n\
+         # TYPE rapidbro_active_buses gauge\n\
+         rapidbro_active_buses {}\n\
+         # HELP rapidbro_outside_service_area_buses Active buses hidden for reporting outside the service area.\n\
+         # TYPE rapidbro_outside_service_area_buses gauge\n\
+         rapidbro_outside_service_area_buses {}\n",
+        snapshot.active_bus_count, snapshot.outside_service_area_count
+    ));
+    if let Some(last_ingest_ms) = snapshot.last_ingest_at_unix_ms {
+        body.push_str(&format!(
+            "# HELP rapidbro_feed_data_age_seconds Seconds since the last successful ingest.\n\
+             # TYPE rapidbro_feed_data_age_seconds gauge\n\
+             rapidbro_feed_data_age_seconds {:.3}\n",
+            (now_ms - last_ingest_ms).max(0) as f64 / 1_000.0
+        ));
+    }
+    body.push_str(&format!(
+        "# HELP rapidbro_ingestor_connected Whether the AVL socket is connected.\n\
+         # TYPE rapidbro_ingestor_connected gauge\n\
+         rapidbro_ingestor_connected {}\n\
+         # HELP rapidbro_ingestor_messages_processed_total AVL messages processed.\n\
+         # TYPE rapidbro_ingestor_messages_processed_total counter\n\
+         rapidbro_ingestor_messages_processed_total {}\n\
+         # HELP rapidbro_ingestor_decode_failures_total AVL payloads that failed to decode.\n\
+         # TYPE rapidbro_ingestor_decode_failures_total counter\n\
+         rapidbro_ingestor_decode_failures_total {}\n\
+         # HELP rapidbro_ingestor_redis_write_failures_total Failed Redis snapshot writes.\n\
+         # TYPE rapidbro_ingestor_redis_write_failures_total counter\n\
+         rapidbro_ingestor_redis_write_failures_total {}\n\
+         # HELP rapidbro_ingestor_reconnects_total AVL socket reconnects.\n\
+         # TYPE rapidbro_ingestor_reconnects_total counter\n\
+         rapidbro_ingestor_reconnects_total {}\n",
+        u8::from(ingestor_status.connected),
+        ingestor_status.messages_processed,
+        ingestor_status.decode_failures,
+        ingestor_status.redis_write_failures,
+        ingestor_status.reconnect_count
+    ));
+    if let Some(reload_interval_seconds) = ingestor_status.reload_interval_seconds {
+        body.push_str(&format!(
+            "# HELP rapidbro_ingestor_reload_interval_seconds Current wait between feed reload requests.\n\
+             # TYPE rapidbro_ingestor_reload_interval_seconds gauge\n\
+             rapidbro_ingestor_reload_interval_seconds {}\n",
+            reload_interval_seconds
+        ));
+    }
+
+    Ok((
+        [(CONTENT_TYPE, "text/plain; version=0.0.4; charset=utf-8")],
+        body,
+    )
+        .into_response())
+}
+
+fn prometheus_label_escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+pub(crate) async fn get_admin_dashboard(
+    headers: HeaderMap,
+    State(state): State<AppState>,
+) -> Response {
+    if let Err(error) = require_admin(&state, &headers) {
+        let status = error.status();
+        let mut response = (status, error.to_string()).into_response();
+        if status == StatusCode::UNAUTHORIZED {
+            response.headers_mut().insert(
+                WWW_AUTHENTICATE,
+                HeaderValue::from_static("Basic realm=\"rapidbro admin\""),
+            );
+        }
+        return response;
+    }
+
+    let status = state.ingestor_status.read().await.clone();
+    let history: Vec<(i64, usize)> = state
+        .active_bus_count_history
+        .read()
+        .await
+        .iter()
+        .copied()
+        .collect();
+    let dead_letters: Vec<DeadLetterSample> =
+        state.dead_letters.read().await.iter().cloned().collect();
+
+    let ping_started_at = std::time::Instant::now();
+    let redis_health = match state.redis_client.get_multiplexed_async_connection().await {
+        Ok(mut redis_conn) => match redis::cmd("PING")
+            .query_async::<String>(&mut redis_conn)
+            .await
+        {
+            Ok(_) => format!("ok ({} ms)", ping_started_at.elapsed().as_millis()),
+            Err(error) => format!("error: {}", error),
+        },
+        Err(error) => format!("unreachable: {}", error),
+    };
+
+    Html(render_admin_dashboard(
+        &status,
+        &history,
+        &dead_letters,
+        &redis_health,
+        &city_gtfs_feed_version(&state.city),
+    ))
+    .into_response()
+}
+
+fn render_admin_dashboard(
+    status: &IngestorStatus,
+    history: &[(i64, usize)],
+    dead_letters: &[DeadLetterSample],
+    redis_health: &str,
+    gtfs_version: &str,
+) -> String {
+    const SPARK_LEVELS: [char; 8] = ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+
+    let max_count = history.iter().map(|(_, count)| *count).max().unwrap_or(0);
+    let sparkline: String = history
+        .iter()
+        .map(|(_, count)| {
+            let level = (count * (SPARK_LEVELS.len() - 1))
+                .checked_div(max_count)
+                .unwrap_or(0);
+            SPARK_LEVELS[level]
+        })
+        .collect();
+    let latest_count = history
+        .last()
+        .map(|(_, count)| count.to_string())
+        .unwrap_or_else(|| "n/a".to_string());
+    let decode_failure_rate = if status.messages_processed == 0 {
+        0.0
+    } else {
+        status.decode_failures as f64 / status.messages_processed as f64 * 100.0
+    };
+    let dead_letter_rows: String = dead_letters
+        .iter()
+        .rev()
+        .map(|sample| {
+            format!(
+                "<tr><td>{}</td><td>{}</td><td><code>{}</code></td></tr>",
+                format_unix_ms(Some(sample.received_at_unix_ms)),
+                html_escape(&sample.reason),
+                html_escape(&sample.payload_preview)
+            )
+        })
+        .collect();
+
+    format!(
+        r#"<!doctype html>
+<html>
+<head>
+<meta charset="utf-8">
+<meta http-equiv="refresh" content="30">
+<title>rapidbro admin</title>
+<style>
+body {{ font-family: system-ui, sans-serif; margin: 2rem; color: #222; }}
+table {{ border-collapse: collapse; margin-bottom: 1.5rem; }}
+td, th {{ border: 1px solid #ccc; padding: 0.3rem 0.6rem; text-align: left; vertical-align: top; }}
+code {{ font-size: 0.8rem; word-break: break-all; }}
+.spark {{ font-size: 1.5rem; letter-spacing: 1px; }}
+</style>
+</head>
+<body>
+<h1>rapidbro deployment health</h1>
+<h2>Ingestor</h2>
+<table>
+<tr><th>Connected</th><td>{connected}</td></tr>
+<tr><th>Reconnects</th><td>{reconnects}</td></tr>
+<tr><th>Messages processed</th><td>{messages}</td></tr>
+<tr><th>Buses written</th><td>{written}</td></tr>
+<tr><th>Decode failures</th><td>{decode_failures} ({decode_failure_rate:.2}% of messages)</td></tr>
+<tr><th>Redis write failures</th><td>{redis_failures}</td></tr>
+<tr><th>Positions outside service area</th><td>{outside_service_area}</td></tr>
+<tr><th>Last message</th><td>{last_message}</td></tr>
+<tr><th>Last error</th><td>{last_error}</td></tr>
+</table>
+<h2>Active buses</h2>
+<p>Latest: {latest_count} &middot; last {sample_count} samples</p>
+<p class="spark">{sparkline}</p>
+<h2>Dependencies</h2>
+<table>
+<tr><th>Redis</th><td>{redis_health}</td></tr>
+<tr><th>GTFS feed</th><td>{gtfs_version}</td></tr>
+</table>
+<h2>Recent dead letters</h2>
+<table>
+<tr><th>Received</th><th>Reason</th><th>Payload</th></tr>
+{dead_letter_rows}
+</table>
+</body>
+</html>"#,
+        connected = status.connected,
+        reconnects = status.reconnect_count,
+        messages = status.messages_processed,
+        written = status.buses_written,
+        decode_failures = status.decode_failures,
+        decode_failure_rate = decode_failure_rate,
+        redis_failures = status.redis_write_failures,
+        outside_service_area = status.outside_service_area_positions,
+        last_message = format_unix_ms(status.last_message_unix_ms),
+        last_error = html_escape(status.last_error.as_deref().unwrap_or("none")),
+        latest_count = latest_count,
+        sample_count = history.len(),
+        sparkline = sparkline,
+        redis_health = html_escape(redis_health),
+        gtfs_version = html_escape(gtfs_version),
+        dead_letter_rows = dead_letter_rows,
+    )
+}
+
+fn html_escape(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn format_unix_ms(unix_ms: Option<i64>) -> String {
+    unix_ms
+        .and_then(chrono::DateTime::from_timestamp_millis)
+        .map(|time| time.to_rfc3339())
+        .unwrap_or_else(|| "never".to_string())
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct BusPath {
+    bus_no: String,
+}
+
+impl Validate for BusPath {
+    fn validate(&self) -> Vec<FieldError> {
+        let mut errors = Vec::new();
+        check_id(&mut errors, "bus_no", &self.bus_no);
+        errors
+    }
+}
+
+// Recent non-live stop resolutions for one bus, to diagnose misassignments such as a bus on a
+// parallel road being placed at the wrong stop.
+pub(crate) async fn get_bus_resolution_log(
+    headers: HeaderMap,
+    ValidPath(BusPath { bus_no }): ValidPath<BusPath>,
+    State(state): State<AppState>,
+) -> Result<Json<StopResolutionLogResponse>, AppError> {
+    require_admin(&state, &headers)?;
+    let bus_no = canonical_bus_no(&bus_no, &state.bus_no_rules);
+    let records: Vec<StopResolutionRecord> = state
+        .resolution_log
+        .by_bus
+        .lock()
+        .map_err(internal_error)?
+        .get(&bus_no)
+        .map(|records| records.iter().cloned().collect())
+        .unwrap_or_default();
+    Ok(Json(StopResolutionLogResponse { bus_no, records }))
+}
+
+async fn get_usage(
+    headers: HeaderMap,
+    ValidQuery(query): ValidQuery<UsageQuery>,
+    State(state): State<AppState>,
+) -> Result<Json<UsageResponse>, AppError> {
+    require_admin(&state, &headers)?;
+    let usage = load_usage(
+        &state,
+        query.days.unwrap_or(1),
+        query.limit.unwrap_or(DEFAULT_USAGE_LIMIT),
+    )
+    .await?;
+    Ok(Json(usage))
+}
+
+#[derive(Debug, Deserialize)]
+struct SnapshotExportQuery {
+    history: Option<bool>,
+}
+
+// Every key under this city's prefix, for moving state between Redis instances, seeding
+// staging, or attaching to a bug report. ?history=true adds the as_of history frames.
+async fn get_snapshot_export(
+    headers: HeaderMap,
+    ValidQuery(query): ValidQuery<SnapshotExportQuery>,
+    State(state): State<AppState>,
+) -> Result<Response, AppError> {
+    require_admin(&state, &headers)?;
+    if state.fixture.is_some() {
+        return Err(AppError::Disabled(
+            "Snapshot export needs Redis; fixture mode has none".to_string(),
+        ));
+    }
+    let snapshot = export_redis_state(&state, query.history.unwrap_or(false)).await?;
+    let body = serde_json::to_vec(&snapshot).map_err(internal_error)?;
+
+    println!(
+        "Exported {} Redis keys for prefix {} ({} bytes)",
+        snapshot.keys.len(),
+        snapshot.key_prefix,
+        body.len()
+    );
+    Ok((
+        [
+            (CONTENT_TYPE, "application/json".to_string()),
+            (
+                CONTENT_DISPOSITION,
+                format!(
+                    "attachment; filename=\"rapidbro-{}-{}.json\"",
+                    state.city.id, snapshot.exported_at_unix_ms
+                ),
+            ),
+        ],
+        body,
+    )
+        .into_response())
+}
+
+async fn post_snapshot_import(
+    headers: HeaderMap,
+    State(state): State<AppState>,
+    Json(snapshot): Json<RedisStateSnapshot>,
+) -> Result<Json<SnapshotImportResponse>, AppError> {
+    require_admin(&state, &headers)?;
+    if state.fixture.is_some() {
+        return Err(AppError::Disabled(
+            "Snapshot import needs Redis; fixture mode has none".to_string(),
+        ));
+    }
+    let restored_keys = import_redis_state(&state, &snapshot).await?;
+
+    // Thresholds are only read from Redis at startup; pick up the imported ones now.
+    let mut redis_conn = state
+        .redis_client
+        .get_multiplexed_async_connection()
+        .await?;
+    *state.thresholds.write().await =
+        load_persisted_thresholds(Some(&mut redis_conn), &state.redis_keys).await;
+
+    println!(
+        "Imported {} Redis keys exported from prefix {} into {}",
+        restored_keys, snapshot.key_prefix, state.redis_keys.prefix
+    );
+    Ok(Json(SnapshotImportResponse {
+        key_prefix: state.redis_keys.prefix.clone(),
+        restored_keys,
+    }))
+}
+
+async fn get_thresholds(
+    headers: HeaderMap,
+    State(state): State<AppState>,
+) -> Result<Json<Thresholds>, AppError> {
+    require_admin(&state, &headers)?;
+    Ok(Json(*state.thresholds.read().await))
+}
+
+pub(crate) async fn get_schema_index() -> Response {
+    (
+        [(CACHE_CONTROL, SCHEMA_CACHE_CONTROL)],
+        Json(json!({ "types": SCHEMA_TYPE_NAMES })),
+    )
+        .into_response()
+}
+
+// JSON Schema for one API model, generated from the rapidbro-types structs so clients generated
+// from it stay in step with the server. Served with the same open CORS policy as the API.
+pub(crate) async fn get_schema(
+    ValidPath(SchemaPath { type_name }): ValidPath<SchemaPath>,
+) -> Result<Response, AppError> {
+    let schema = json_schema(&type_name).ok_or_else(|| {
+        AppError::NotFound(format!(
+            "No schema for '{}'; GET /schema lists the available types",
+            type_name
+        ))
+    })?;
+    Ok(([(CACHE_CONTROL, SCHEMA_CACHE_CONTROL)], Json(schema)).into_response())
+}
+
+// Public, read-only view of the thresholds so clients estimating locally with
+// rapidbro-eta-wasm use the same parameters as the server.
+async fn get_eta_model(State(state): State<AppState>) -> Json<EtaModel> {
+    Json(EtaModel {
+        model_version: MODEL_VERSION,
+        thresholds: *state.thresholds.read().await,
+    })
+}
+
+async fn patch_thresholds(
+    headers: HeaderMap,
+    State(state): State<AppState>,
+    Json(patch): Json<ThresholdsPatch>,
+) -> Result<Json<Thresholds>, AppError> {
+    require_admin(&state, &headers)?;
+
+    let mut thresholds = state.thresholds.write().await;
+    let updated = apply_thresholds_patch(*thresholds, patch).map_err(AppError::Validation)?;
+
+    let mut redis_conn = state
+        .redis_client
+        .get_multiplexed_async_connection()
+        .await?;
+    redis::cmd("SET")
+        .arg(state.redis_keys.key(REDIS_THRESHOLDS_KEY))
+        .arg(serde_json::to_string(&updated).map_err(internal_error)?)
+        .query_async::<()>(&mut redis_conn)
+        .await?;
+
+    *thresholds = updated;
+    println!("Updated ETA thresholds: {:?}", updated);
+    Ok(Json(updated))
+}
+
+// Get buses for route T789 specifically from Redis snapshot
+async fn get_route_t789(
+    State(state): State<AppState>,
+) -> Result<Json<serde_json::Value>, AppError> {
+    let snapshot = load_active_bus_snapshot(&state).await?;
+    let gtfs = load_gtfs_context(&state);
+    let thresholds = *state.thresholds.read().await;
+    let visible_buses = filter_non_stationary_buses(
+        &snapshot.buses,
+        &snapshot.motion_states,
+        snapshot.captured_at_unix_ms,
+        &thresholds,
+    );
+    let route_stops = cached_stops_by_route(&gtfs, "T7890", None)?;
+    let t789_buses: Vec<RouteBusPositionResponse> = visible_buses
+        .into_iter()
+        .filter(|bus| is_t789_route(&bus.route))
+        .map(|bus| route_bus_position(&state, &gtfs, &snapshot, bus, &route_stops, &thresholds))
+        .collect();
+
+    println!(
+        "Calling get_route_t789 via Redis: {} active buses",
+        t789_buses.len()
+    );
+
+    if t789_buses.len() == 1 {
+        let value = serde_json::to_value(&t789_buses[0]).unwrap_or_else(|_| json!({}));
+        Ok(Json(value))
+    } else {
+        let value = serde_json::to_value(&t789_buses).unwrap_or_else(|_| json!([]));
+        Ok(Json(value))
+    }
+}
+
+fn route_bus_position(
+    state: &AppState,
+    gtfs: &GtfsContext,
+    snapshot: &RedisBusSnapshot,
+    bus: BusPosition,
+    route_stops: &RouteStopsResponse,
+    thresholds: &Thresholds,
+) -> RouteBusPositionResponse {
+    record_stop_resolutions(
+        &state.resolution_log,
+        std::slice::from_ref(&bus),
+        &route_stops.route_id,
+        route_stops,
+        thresholds,
+        snapshot.captured_at_unix_ms,
+    );
+    let resolved_stop = resolve_current_stop(&bus, route_stops, thresholds);
+    let motion_state = snapshot.motion_states.get(&bus.bus_no);
+    let chainage = motion_state.and_then(|state| state.chainage.as_ref());
+    let direction = bus.trip.as_ref().map(|trip| &trip.direction).filter(|_| {
+        feature_enabled(
+            &state.feature_flags,
+            FLAG_DIRECTION_INFERENCE,
+            &route_stops.route_id,
+        )
+    });
+    RouteBusPositionResponse {
+        route_display: route_display(gtfs, &route_stops.route_id, direction),
+        resolved_stop_id: resolved_stop.as_ref().map(|stop| stop.stop_id.clone()),
+        resolved_stop_name: resolved_stop.as_ref().map(|stop| stop.stop_name.clone()),
+        resolved_stop_sequence: resolved_stop.as_ref().map(|stop| stop.sequence),
+        stop_resolution_source: resolved_stop.map(|stop| stop.source),
+        smoothed_speed_kmh: motion_state.and_then(|state| state.smoothed_speed_kmh),
+        chainage_m: chainage.map(|chainage| chainage.chainage_m.round()),
+        progress_percent: chainage
+            .filter(|chainage| chainage.shape_length_m > 0.0)
+            .map(|chainage| {
+                (chainage.chainage_m / chainage.shape_length_m * 1000.0).round() / 10.0
+            }),
+        bus: attach_vehicle_info(
+            apply_captain_id_privacy(bus, &state.privacy),
+            &state.vehicle_roster,
+        ),
+    }
+}
+
+fn route_group<'a>(state: &'a AppState, name: &str) -> Result<&'a Vec<String>, AppError> {
+    state
+        .route_groups
+        .get(name)
+        .ok_or_else(|| AppError::NotFound(format!("Route group '{}' not found", name)))
+}
+
+// Live buses across every route in the group. Member routes that share a base code (T789 and
+// T7890) see the same buses, so each bus is listed once under the first route that claims it.
+async fn get_group_live(
+    ValidPath(GroupPath { name }): ValidPath<GroupPath>,
+    State(state): State<AppState>,
+) -> Result<Json<RouteGroupLiveResponse>, AppError> {
+    let route_ids = route_group(&state, &name)?.clone();
+    let snapshot = load_active_bus_snapshot(&state).await?;
+    let gtfs = load_gtfs_context(&state);
+    let thresholds = *state.thresholds.read().await;
+    let visible_buses = filter_non_stationary_buses(
+        &snapshot.buses,
+        &snapshot.motion_states,
+        snapshot.captured_at_unix_ms,
+        &thresholds,
+    );
+
+    let mut seen_buses: HashSet<String> = HashSet::new();
+    let mut data: Vec<RouteBusPositionResponse> = Vec::new();
+    for route_id in &route_ids {
+        let route_stops = cached_stops_by_route(&gtfs, route_id, None)?;
+        for bus in visible_buses
+            .iter()
+            .filter(|bus| is_bus_on_route(&bus.route, route_id))
+        {
+            if seen_buses.insert(bus.bus_no.clone()) {
+                data.push(route_bus_position(
+                    &state,
+                    &gtfs,
+                    &snapshot,
+                    bus.clone(),
+                    &route_stops,
+                    &thresholds,
+                ));
+            }
+        }
+    }
+
+    println!(
+        "Calling get_group_live for group={}: {} buses",
+        name,
+        data.len()
+    );
+    Ok(Json(RouteGroupLiveResponse {
+        group: name,
+        route_ids,
+        active_bus_count: data.len(),
+        data,
+    }))
+}
+
+// ETAs to one stop from every route in the group that calls there, merged and sorted.
+async fn get_group_eta(
+    ValidPath(GroupStopPath { name, stop_id }): ValidPath<GroupStopPath>,
+    ValidQuery(query): ValidQuery<AsOfQuery>,
+    State(state): State<AppState>,
+) -> Result<Json<RouteGroupEtaResponse>, AppError> {
+    let route_ids = route_group(&state, &name)?.clone();
+    let stop = resolve_stop_ref(&current_gtfs_feed(&state), &stop_id)?;
+    let snapshot = load_bus_snapshot_as_of(&state, query.as_of).await?;
+    let gtfs = load_gtfs_context(&state);
+    let thresholds = *state.thresholds.read().await;
+    let visible_buses = filter_non_stationary_buses(
+        &snapshot.buses,
+        &snapshot.motion_states,
+        snapshot.captured_at_unix_ms,
+        &thresholds,
+    );
+    let segment_runtimes: HashMap<String, HashMap<(String, String), f64>> = {
+        let hour = kl_hour_of_day(snapshot.captured_at_unix_ms);
+        let runtime_profiles = state.runtime_profiles.read().await;
+        route_ids
+            .iter()
+            .filter(|route_id| {
+                feature_enabled(&state.feature_flags, FLAG_RUNTIME_PROFILE_ETA, route_id)
+            })
+            .filter_map(|route_id| {
+                runtime_profiles
+                    .get(route_id)
+                    .map(|profile| (route_id.clone(), segment_runtimes_for_hour(profile, hour)))
+            })
+            .collect()
+    };
+
+    let mut served = false;
+    let mut data: Vec<BusEta> = Vec::new();
+    {
+        let _timer = StageTimer::start(Stage::EtaCompute);
+        for route_id in &route_ids {
+            let route_stops = eta_stops_by_route(&gtfs, route_id)?;
+            record_stop_resolutions(
+                &state.resolution_log,
+                &visible_buses,
+                route_id,
+                &route_stops,
+                &thresholds,
+                snapshot.captured_at_unix_ms,
+            );
+            // Fails only when the route doesn't call at this stop; such members add nothing.
+            if let Ok(mut eta_results) = calculate_route_eta_from_stops(
+                &visible_buses,
+                &snapshot.motion_states,
+                route_id,
+                &stop.stop_id,
+                &route_stops,
+                gtfs.route_geometries.get(&normalize_route_code(route_id)),
+                &thresholds,
+                snapshot.captured_at_unix_ms,
+            ) {
+                if let Some(segment_runtimes) = segment_runtimes.get(route_id) {
+                    apply_runtime_profile(
+                        &mut eta_results,
+                        &route_stops,
+                        &stop.stop_id,
+                        segment_runtimes,
+                        &thresholds,
+                    );
+                }
+                apply_route_detours(&gtfs, route_id, &stop.stop_id, &mut eta_results);
+                served = true;
+                data.extend(eta_results);
+            }
+        }
+    }
+    if !served {
+        return Err(AppError::NotFound(format!(
+            "No route in group '{}' serves stop '{}'",
+            name, stop.stop_id
+        )));
+    }
+    apply_data_age(
+        &mut data,
+        &snapshot.last_seen_unix_ms,
+        snapshot.captured_at_unix_ms,
+        state.bus_ttl_ms,
+    );
+    attach_route_display(&gtfs, &mut data);
+    annotate_bus_places(&state, &mut data).await;
+    let messages = {
+        let mut signals = rider_signals(
+            &state,
+            &data,
+            snapshot.last_ingest_at_unix_ms,
+            snapshot.captured_at_unix_ms,
+            query.as_of,
+        )
+        .await;
+        if query.as_of.is_none() {
+            signals.departed_route_ids = departed_route_ids(
+                &gtfs,
+                &stop.stop_id,
+                &data,
+                &*state.terminus_schedules.read().await,
+                snapshot.captured_at_unix_ms,
+            )
+            .into_iter()
+            .filter(|route_id| route_ids.contains(route_id))
+            .collect();
+        }
+        rider_messages(&signals)
+    };
+
+    println!(
+        "Calling get_group_eta for group={}, stop_id={}: {} buses",
+        name,
+        stop.stop_id,
+        data.len()
+    );
+    Ok(Json(RouteGroupEtaResponse {
+        group: name,
+        route_ids,
+        stop_id: stop.stop_id,
+        data,
+        messages,
+    }))
+}
+
+// Calculate ETA for T789 buses from Redis snapshot to reach stop 1000838 (KL1397 FLAT PKNS KERINCHI/KL GATEWAY)
+async fn get_t789_eta(State(state): State<AppState>) -> Result<Json<Vec<BusEta>>, AppError> {
+    const TARGET_STOP_ID: &str = "1000838";
+    let eta_results = calculate_route_eta(&state, "T7890", TARGET_STOP_ID, None).await?;
+    println!(
+        "Calling get_t789_eta: found {} buses with ETA",
+        eta_results.len()
+    );
+    Ok(Json(eta_results))
+}
+
+// Calculate ETA for all incoming buses to Pantai Hillpark Phase 5 (stop 1008485).
+async fn get_pantai_hillpark_phase_5_eta(
+    State(state): State<AppState>,
+) -> Result<Json<StopIncomingResponse>, AppError> {
+    let response =
+        build_stop_incoming_response(&state, PANTAI_HILLPARK_PHASE_5_STOP_ID, None).await?;
+
+    println!(
+        "Calling get_pantai_hillpark_phase_5_eta: {} incoming buses",
+        response.data.len()
+    );
+    Ok(Json(response))
+}
+
+// Calculate ETA for buses in route/{route_id} to reach stop/{stop_id}, based on Redis snapshot.
+async fn get_route_eta(
+    RouteStopRefs { route, stop }: RouteStopRefs,
+    ValidQuery(query): ValidQuery<AsOfQuery>,
+    State(state): State<AppState>,
+) -> Result<Json<Vec<BusEta>>, AppError> {
+    let key = format!(
+        "{}|{}|{:?}|{}",
+        route.route_id,
+        stop.stop_id,
+        query.as_of,
+        ingest_batch_seq(&state).await
+    );
+    let eta_results = state
+        .route_eta_flights
+        .run(key, || {
+            calculate_route_eta(&state, &route.route_id, &stop.stop_id, query.as_of)
+        })
+        .await?;
+    println!(
+        "Calling get_route_eta for route_id={}, stop_id={}, as_of={:?}: {} buses",
+        route.route_id,
+        stop.stop_id,
+        query.as_of,
+        eta_results.len()
+    );
+    Ok(Json(eta_results))
+}
+
+// ETAs for several stops of route/{route_id} from one snapshot, e.g. ?stops=1000838,1000839.
+async fn get_route_eta_for_stops(
+    RouteRef { route_id }: RouteRef,
+    ValidQuery(query): ValidQuery<MultiStopEtaQuery>,
+    State(state): State<AppState>,
+) -> Result<Json<RouteMultiStopEtaResponse>, AppError> {
+    let gtfs = current_gtfs_feed(&state);
+    let stop_ids = query
+        .stops
+        .split(',')
+        .map(|key| resolve_stop_key(key, &gtfs.stops_map, &gtfs.stop_ids_by_code))
+        .collect::<Result<Vec<String>, AppError>>()?;
+    let target_stop_ids: Vec<&str> = stop_ids.iter().map(String::as_str).collect();
+    let eta_results =
+        calculate_route_etas(&state, &route_id, &target_stop_ids, query.as_of).await?;
+    // Caveats describe the feed as it is now, so replays go without them.
+    let messages = match query.as_of {
+        Some(_) => Vec::new(),
+        None => {
+            let last_ingest_at_unix_ms = load_last_ingest_at(&state).await?;
+            let now_ms = state.clock.now_ms();
+            let mut etas: Vec<BusEta> = eta_results.iter().flatten().cloned().collect();
+            sort_by_weighted_arrival(&mut etas, now_ms);
+            rider_messages(
+                &rider_signals(&state, &etas, last_ingest_at_unix_ms, now_ms, None).await,
+            )
+        }
+    };
+
+    println!(
+        "Calling get_route_eta_for_stops for route_id={}, stops={}, as_of={:?}: {} buses",
+        route_id,
+        stop_ids.join(","),
+        query.as_of,
+        eta_results.iter().map(Vec::len).sum::<usize>()
+    );
+    Ok(Json(RouteMultiStopEtaResponse {
+        route_id,
+        stops: stop_ids
+            .into_iter()
+            .zip(eta_results)
+            .map(|(stop_id, data)| RouteStopEta { stop_id, data })
+            .collect(),
+        messages,
+    }))
+}
+
+// Calculate ETA for all routes incoming to /stops/{stop_id}
+async fn get_stop_eta(
+    StopRef { stop_id, .. }: StopRef,
+    ValidQuery(query): ValidQuery<AsOfQuery>,
+    State(state): State<AppState>,
+) -> Result<Json<StopIncomingResponse>, AppError> {
+    let ingest_seq = ingest_batch_seq(&state).await;
+    let hot_board = match query.as_of {
+        Some(_) => None,
+        None => state
+            .hot_stops
+            .boards
+            .read()
+            .await
+            .get(&stop_id)
+            .filter(|board| board.ingest_seq == ingest_seq)
+            .map(|board| board.response.clone()),
+    };
+    let response = match hot_board {
+        Some(response) => {
+            state
+                .hot_stops
+                .hits_total
+                .fetch_add(1, AtomicOrdering::Relaxed);
+            response
+        }
+        None => {
+            let key = format!("{}|{:?}|{}", stop_id, query.as_of, ingest_seq);
+            state
+                .stop_eta_flights
+                .run(key, || {
+                    build_stop_incoming_response(&state, &stop_id, query.as_of)
+                })
+                .await?
+        }
+    };
+
+    println!(
+        "Calling get_stop_eta for stop_id={}, as_of={:?}: {} incoming buses, {} recent departures",
+        stop_id,
+        query.as_of,
+        response.data.len(),
+        response.recent_departures.len()
+    );
+    Ok(Json(response))
+}
+
+// Advances with every ingested batch; the snapshot sequence number for coalescing keys. Once
+// ingest events arrive this follows batches stored by any instance, not just this one's.
+async fn ingest_batch_seq(state: &AppState) -> u64 {
+    if let Some(event) = state.ingest_events.borrow().as_ref() {
+        return event.snapshot_seq;
+    }
+    state.ingestor_status.read().await.messages_processed
+}
+
+// Keeps boards for today's most requested stops built against the latest batch, so those reads
+// skip the snapshot load and ETA pass entirely.
+pub(crate) async fn run_hot_stop_precompute(state: AppState, hot_stop_count: usize) {
+    let mut poll_interval = tokio::time::interval(Duration::from_millis(HOT_STOP_POLL_MS));
+    poll_interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
+    let mut hot_stop_keys: Vec<String> = Vec::new();
+    let mut ranked_at_ms = i64::MIN;
+    let mut built_seq = None;
+    let mut ingest_events = state.ingest_events.subscribe();
+
+    loop {
+        tokio::select! {
+            _ = poll_interval.tick() => {}
+            Ok(()) = ingest_events.changed() => {}
+        }
+        let now_ms = state.clock.now_ms();
+        // Rankings only move when the usage flusher writes, so re-read them at the same pace.
+        if now_ms.saturating_sub(ranked_at_ms) >= USAGE_FLUSH_SECONDS as i64 * 1_000 {
+            ranked_at_ms = now_ms;
+            match load_usage(&state, 1, hot_stop_count).await {
+                Ok(usage) => {
+                    hot_stop_keys = usage.stops.into_iter().map(|count| count.key).collect();
+                    built_seq = None;
+                }
+                Err(error) => println!("Failed to rank hot stops: {}", error),
+            }
+        }
+
+        let ingest_seq = ingest_batch_seq(&state).await;
+        if built_seq == Some(ingest_seq) {
+            continue;
+        }
+        let mut boards = HashMap::new();
+        for key in &hot_stop_keys {
+            match build_stop_incoming_response(&state, key, None).await {
+                Ok(response) => {
+                    boards.insert(
+                        response.stop_id.clone(),
+                        HotStopBoard {
+                            ingest_seq,
+                            response,
+                        },
+                    );
+                }
+                Err(error) => println!("Failed to precompute stop board for {}: {}", key, error),
+            }
+        }
+        *state.hot_stops.boards.write().await = boards;
+        built_seq = Some(ingest_seq);
+    }
+}
+
+pub(crate) async fn build_stop_incoming_response(
+    state: &AppState,
+    stop_id: &str,
+    as_of: Option<i64>,
+) -> Result<StopIncomingResponse, AppError> {
+    let snapshot = load_bus_snapshot_as_of(state, as_of).await?;
+    let gtfs = load_gtfs_context(state);
+    let stop_id = resolve_stop_key(stop_id, &gtfs.stops_map, &gtfs.stop_ids_by_code)?;
+    let stop_id = stop_id.as_str();
+    let stop = gtfs
+        .stops_map
+        .get(stop_id)
+        .ok_or_else(|| AppError::NotFound(format!("Stop '{}' not found in GTFS data", stop_id)))?;
+    let thresholds = *state.thresholds.read().await;
+    // History replays have no departures to score against, so shadow runs on live reads only.
+    let shadow_runtime_profiles = as_of.is_none()
+        && state
+            .feature_flags
+            .shadow
+            .contains(FLAG_RUNTIME_PROFILE_ETA);
+    let use_runtime_profiles = !shadow_runtime_profiles
+        && feature_enabled(&state.feature_flags, FLAG_RUNTIME_PROFILE_ETA, stop_id);
+    let runtime_profiles = state.runtime_profiles.read().await;
+    let no_runtime_profiles = HashMap::new();
+    let mut eta_results = calculate_stop_eta_from_snapshot(
+        &snapshot,
+        &gtfs,
+        stop_id,
+        &thresholds,
+        state.bus_ttl_ms,
+        if use_runtime_profiles {
+            &runtime_profiles
+        } else {
+            &no_runtime_profiles
+        },
+        &state.resolution_log,
+    );
+    if shadow_runtime_profiles {
+        let shadow_eta_minutes = runtime_profile_eta_minutes(
+            &eta_results,
+            &gtfs,
+            stop_id,
+            &runtime_profiles,
+            &thresholds,
+            snapshot.captured_at_unix_ms,
+        );
+        record_shadow_predictions(
+            &state.shadow_evaluation,
+            FLAG_RUNTIME_PROFILE_ETA,
+            stop_id,
+            &eta_results,
+            &shadow_eta_minutes,
+            snapshot.captured_at_unix_ms,
+        );
+    }
+    drop(runtime_profiles);
+    // Timetables only describe the present, so replays leave waiting buses out.
+    if as_of.is_none() {
+        let terminus_schedules = state.terminus_schedules.read().await;
+        eta_results.extend(terminus_departure_etas(
+            &snapshot,
+            &gtfs,
+            stop_id,
+            &thresholds,
+            state.bus_ttl_ms,
+            &terminus_schedules,
+        ));
+        sort_by_weighted_arrival(&mut eta_results, snapshot.captured_at_unix_ms);
+    }
+    let closed_stops = active_stop_closures(state, snapshot.captured_at_unix_ms);
+    let closure = closed_stops.get(stop_id).map(|closure| StopClosureNotice {
+        closure_id: closure.id.clone(),
+        message: closure.message.clone(),
+        valid_until_unix_ms: closure.valid_until_unix_ms,
+        alternatives: nearby_open_stops(
+            &gtfs.stops_map,
+            stop,
+            &closed_stops,
+            CLOSURE_ALTERNATIVE_COUNT,
+            CLOSURE_ALTERNATIVE_RADIUS_M,
+        ),
+    });
+    // No bus calls at a closed stop.
+    if closure.is_some() {
+        eta_results.clear();
+    }
+    let incoming = incoming_status(&eta_results, &thresholds);
+    attach_route_display(&gtfs, &mut eta_results);
+    annotate_bus_places(state, &mut eta_results).await;
+    state
+        .stop_eta_computed_total
+        .fetch_add(1, AtomicOrdering::Relaxed);
+    let now_ms = state.clock.now_ms();
+    let recent_departures =
+        load_recent_departures(state, stop_id, snapshot.captured_at_unix_ms).await?;
+    score_shadow_predictions(&state.shadow_evaluation, stop_id, &recent_departures);
+    // History frames were captured from the primary; only live reads can trail it.
+    let replica_lag = match as_of {
+        Some(_) => None,
+        None => *state.replica_lag.read().await,
+    };
+    let mut signals = rider_signals(
+        state,
+        &eta_results,
+        snapshot.last_ingest_at_unix_ms,
+        snapshot.captured_at_unix_ms,
+        as_of,
+    )
+    .await;
+    let mut scheduled_headways = Vec::new();
+    if as_of.is_none() && closure.is_none() {
+        signals.departed_route_ids = departed_route_ids(
+            &gtfs,
+            stop_id,
+            &eta_results,
+            &*state.terminus_schedules.read().await,
+            snapshot.captured_at_unix_ms,
+        );
+        scheduled_headways = scheduled_headways_without_buses(
+            &gtfs,
+            stop_id,
+            &eta_results,
+            snapshot.captured_at_unix_ms,
+        );
+        signals.headway_only_route_ids = scheduled_headways
+            .iter()
+            .map(|headway| headway.route_id.clone())
+            .collect();
+    }
+    let (is_stale, warming_up) = (signals.is_stale, signals.warming_up);
+    let messages = rider_messages(&signals);
+
+    Ok(StopIncomingResponse {
+        stop_id: stop.stop_id.clone(),
+        stop_code: stop.stop_code.clone(),
+        stop_name: stop.stop_name.clone(),
+        stop_desc: stop.stop_desc.clone(),
+        meta: StopIncomingMeta {
+            source: if as_of.is_some() { "history" } else { "redis" }.to_string(),
+            generated_at_unix_ms: now_ms,
+            as_of_unix_ms: as_of,
+            last_ingest_at_unix_ms: snapshot.last_ingest_at_unix_ms,
+            is_stale,
+            active_bus_count: snapshot.active_bus_count,
+            incoming_bus_count: eta_results.len(),
+            has_incoming_buses: incoming != IncomingStatus::None,
+            incoming_status: incoming,
+            warming_up,
+            replica_lag_ms: replica_lag.map(|lag| lag.lag_ms),
+        },
+        data: eta_results,
+        recent_departures,
+        detours: detour_notices(&gtfs, stop_id),
+        closure,
+        messages,
+        scheduled_headways,
+    })
+}
+
+async fn get_stop_routes(
+    StopRef { stop_id, stop_code }: StopRef,
+    State(state): State<AppState>,
+) -> Result<Json<StopRoutesResponse>, AppError> {
+    let gtfs = load_gtfs_context(&state);
+    let routes = get_routes_for_stop(&stop_id, &gtfs)?;
+
+    println!(
+        "Calling get_stop_routes for stop_id={}: {} routes",
+        stop_id,
+        routes.len()
+    );
+
+    Ok(Json(StopRoutesResponse {
+        stop_id,
+        stop_code,
+        routes,
+    }))
+}
+
+#[derive(Debug, Deserialize)]
+struct DeparturesIcsQuery {
+    route: Option<String>,
+}
+
+// Today's and tomorrow's scheduled departures from GTFS as an iCalendar feed.
+async fn get_stop_departures_ics(
+    StopRef { stop_id, .. }: StopRef,
+    ValidQuery(query): ValidQuery<DeparturesIcsQuery>,
+    State(state): State<AppState>,
+) -> Result<Response, AppError> {
+    let gtfs = load_gtfs_context(&state);
+    let stop = gtfs
+        .stops_map
+        .get(&stop_id)
+        .ok_or_else(|| AppError::NotFound(format!("Stop '{}' not found", stop_id)))?;
+    let calendars = &gtfs.calendars;
+    let frequencies_by_trip = &gtfs.frequencies_by_trip;
+
+    let kl_offset = FixedOffset::east_opt(KL_UTC_OFFSET_SECONDS).expect("valid KL offset");
+    let today = chrono::Utc::now().with_timezone(&kl_offset).date_naive();
+    let service_dates: Vec<NaiveDate> = [Some(today), today.succ_opt()]
+        .into_iter()
+        .flatten()
+        .collect();
+
+    let departures = scheduled_departures_for_stop(
+        &stop_id,
+        query.route.as_deref(),
+        &gtfs,
+        calendars,
+        frequencies_by_trip,
+        &service_dates,
+    );
+    let body = render_departures_ics(stop, &departures, kl_offset);
+
+    println!(
+        "Calling get_stop_departures_ics for stop_id={}, route={:?}: {} departures",
+        stop_id,
+        query.route,
+        departures.len()
+    );
+    Ok(([(CONTENT_TYPE, "text/calendar; charset=utf-8")], body).into_response())
+}
+
+// Pairs scheduled trip starts with observed ones, closest first, so a late bus takes the trip it
+// most plausibly ran rather than the next one due. A trip and a start pair only when within the
+// match window and, where both are known, on the same shape.
+fn match_trip_starts(
+    scheduled: &[(ScheduledTripStart, i64)],
+    observed: &[ObservedTripStart],
+) -> HashMap<usize, usize> {
+    let window_ms = SERVICE_DELIVERY_MATCH_WINDOW_MINUTES * 60_000;
+    let mut candidates: Vec<(i64, usize, usize)> = Vec::new();
+    for (trip_index, (trip, scheduled_ms)) in scheduled.iter().enumerate() {
+        for (start_index, start) in observed.iter().enumerate() {
+            let gap_ms = (start.started_at_unix_ms - scheduled_ms).abs();
+            let same_shape = trip.shape_id.is_empty()
+                || start.shape_id.is_empty()
+                || trip.shape_id == start.shape_id;
+            if gap_ms <= window_ms && same_shape {
+                candidates.push((gap_ms, trip_index, start_index));
+            }
+        }
+    }
+    candidates.sort_unstable();
+
+    let mut matched: HashMap<usize, usize> = HashMap::new();
+    let mut used_starts: HashSet<usize> = HashSet::new();
+    for (_, trip_index, start_index) in candidates {
+        if matched.contains_key(&trip_index) || used_starts.contains(&start_index) {
+            continue;
+        }
+        matched.insert(trip_index, start_index);
+        used_starts.insert(start_index);
+    }
+    matched
+}
+
+async fn get_route_service_delivery(
+    RouteRef { route_id }: RouteRef,
+    ValidQuery(query): ValidQuery<ServiceDeliveryQuery>,
+    State(state): State<AppState>,
+) -> Result<Json<ServiceDeliveryResponse>, AppError> {
+    let gtfs = load_gtfs_context(&state);
+    let calendars = &gtfs.calendars;
+    let frequencies_by_trip = &gtfs.frequencies_by_trip;
+    let now_ms = state.clock.now_ms();
+    let date = match query.date.as_deref() {
+        Some(date) => NaiveDate::parse_from_str(date.trim(), "%Y-%m-%d").map_err(internal_error)?,
+        None => {
+            let kl_offset = FixedOffset::east_opt(KL_UTC_OFFSET_SECONDS).expect("valid KL offset");
+            chrono::DateTime::from_timestamp_millis(now_ms)
+                .ok_or_else(|| internal_error("clock is out of range"))?
+                .with_timezone(&kl_offset)
+                .date_naive()
+        }
+    };
+    let (day_start_ms, _) = kl_day_bounds_ms(date)
+        .ok_or_else(|| AppError::Validation(format!("Invalid service date {}", date)))?;
+
+    let scheduled: Vec<(ScheduledTripStart, i64)> =
+        scheduled_trip_starts_for_route(&route_id, &gtfs, calendars, frequencies_by_trip, date)
+            .into_iter()
+            .map(|trip| {
+                let scheduled_ms = day_start_ms + trip.start_secs * 1000;
+                (trip, scheduled_ms)
+            })
+            .collect();
+
+    // Trips after midnight belong to this service day, so history is read past its end.
+    let window_ms = SERVICE_DELIVERY_MATCH_WINDOW_MINUTES * 60_000;
+    let observed = match (scheduled.first(), scheduled.last()) {
+        (Some((_, first_ms)), Some((_, last_ms))) if first_ms - window_ms < now_ms => {
+            load_observed_trip_starts(
+                &state,
+                &route_id,
+                first_ms - window_ms,
+                last_ms + window_ms + 1,
+            )
+            .await?
+        }
+        _ => Vec::new(),
+    };
+    let matched = match_trip_starts(&scheduled, &observed);
+
+    let trips: Vec<ScheduledTripDelivery> = scheduled
+        .iter()
+        .enumerate()
+        .map(|(trip_index, (trip, scheduled_ms))| {
+            let start = matched
+                .get(&trip_index)
+                .map(|start_index| &observed[*start_index]);
+            let status = match start {
+                Some(_) => TripDeliveryStatus::Operated,
+                None if scheduled_ms + window_ms > now_ms => TripDeliveryStatus::Upcoming,
+                None => TripDeliveryStatus::Missed,
+            };
+            ScheduledTripDelivery {
+                trip_id: trip.trip_id.clone(),
+                direction_id: trip.direction_id,
+                headsign: trip.headsign.clone(),
+                scheduled_start_unix_ms: *scheduled_ms,
+                status,
+                observed_start_unix_ms: start.map(|start| start.started_at_unix_ms),
+                start_deviation_minutes: start.map(|start| {
+                    ((start.started_at_unix_ms - scheduled_ms) as f64 / 6_000.0).round() / 10.0
+                }),
+                bus_no: start.map(|start| start.bus_no.clone()),
+            }
+        })
+        .collect();
+
+    let operated_count = trips
+        .iter()
+        .filter(|trip| trip.status == TripDeliveryStatus::Operated)
+        .count();
+    let missed_count = trips
+        .iter()
+        .filter(|trip| trip.status == TripDeliveryStatus::Missed)
+        .count();
+    let due_count = operated_count + missed_count;
+    println!(
+        "Calling get_route_service_delivery for route_id={}, date={}: {} scheduled, {} operated, {} missed",
+        route_id,
+        date,
+        trips.len(),
+        operated_count,
+        missed_count
+    );
+    Ok(Json(ServiceDeliveryResponse {
+        route_id,
+        date: date.format("%Y-%m-%d").to_string(),
+        generated_at_unix_ms: now_ms,
+        scheduled_trip_count: trips.len(),
+        operated_count,
+        missed_count,
+        delivery_ratio: (due_count > 0)
+            .then(|| (operated_count as f64 / due_count as f64 * 1000.0).round() / 1000.0),
+        trips,
+    }))
+}
+
+// Reports are generated once the day is over; a day with no report yet is a 404.
+async fn find_daily_route_report(
+    state: &AppState,
+    date: &str,
+) -> Result<DailyRouteReport, AppError> {
+    let date = NaiveDate::parse_from_str(date.trim(), "%Y-%m-%d").map_err(internal_error)?;
+    load_daily_route_report(state, date)
+        .await?
+        .ok_or_else(|| AppError::NotFound(format!("No route report for {}", date)))
+}
+
+async fn get_daily_route_report(
+    ValidPath(ReportPath { date }): ValidPath<ReportPath>,
+    State(state): State<AppState>,
+) -> Result<Json<DailyRouteReport>, AppError> {
+    Ok(Json(find_daily_route_report(&state, &date).await?))
+}
+
+async fn get_daily_route_report_csv(
+    ValidPath(ReportPath { date }): ValidPath<ReportPath>,
+    State(state): State<AppState>,
+) -> Result<Response, AppError> {
+    let report = find_daily_route_report(&state, &date).await?;
+    let csv = daily_route_report_csv(&report).map_err(internal_error)?;
+    Ok((
+        [
+            (CONTENT_TYPE, "text/csv; charset=utf-8".to_string()),
+            (
+                CONTENT_DISPOSITION,
+                format!("attachment; filename=\"routes-{}.csv\"", report.date),
+            ),
+        ],
+        csv,
+    )
+        .into_response())
+}
+
+// Which routes run today (Kuala Lumpur service day) against what the regular weekly calendar
+// would run, so holiday and festival schedules from calendar_dates.txt stand out.
+async fn get_service_today(
+    State(state): State<AppState>,
+) -> Result<Json<ServiceTodayResponse>, AppError> {
+    let gtfs = load_gtfs_context(&state);
+    let calendars = &gtfs.calendars;
+    let kl_offset = FixedOffset::east_opt(KL_UTC_OFFSET_SECONDS).expect("valid KL offset");
+    let today = chrono::DateTime::from_timestamp_millis(state.clock.now_ms())
+        .ok_or_else(|| internal_error("clock is out of range"))?
+        .with_timezone(&kl_offset)
+        .date_naive();
+    let date_key = today.format("%Y%m%d").to_string();
+
+    let mut added_service_ids: Vec<String> = Vec::new();
+    let mut removed_service_ids: Vec<String> = Vec::new();
+    for ((service_id, date), exception_type) in &calendars.exceptions {
+        if *date != date_key {
+            continue;
+        }
+        match *exception_type {
+            1 => added_service_ids.push(service_id.clone()),
+            2 => removed_service_ids.push(service_id.clone()),
+            _ => {}
+        }
+    }
+    added_service_ids.sort();
+    removed_service_ids.sort();
+
+    let mut routes: Vec<RouteServiceToday> = gtfs
+        .routes
+        .iter()
+        .map(|route| {
+            let mut service_ids: Vec<String> = gtfs
+                .trips_by_route
+                .get(&route.route_id)
+                .map(|trips| trips.iter().map(|trip| trip.service_id.clone()).collect())
+                .unwrap_or_default();
+            service_ids.sort();
+            service_ids.dedup();
+            let runs_normally = service_ids.iter().any(|service_id| {
+                is_regular_service_active(&calendars.calendars, service_id, today)
+            });
+            service_ids.retain(|service_id| is_service_active(calendars, service_id, today));
+            RouteServiceToday {
+                route_id: route.route_id.clone(),
+                route_short_name: route.route_short_name.clone(),
+                running_today: !service_ids.is_empty(),
+                runs_normally,
+                service_ids,
+            }
+        })
+        .collect();
+    routes.sort_by(|a, b| a.route_short_name.cmp(&b.route_short_name));
+    let running_route_count = routes.iter().filter(|route| route.running_today).count();
+    let changed_route_count = routes
+        .iter()
+        .filter(|route| route.running_today != route.runs_normally)
+        .count();
+
+    println!(
+        "Calling get_service_today for {}: {} routes running, {} changed",
+        today, running_route_count, changed_route_count
+    );
+    Ok(Json(ServiceTodayResponse {
+        date: today.format("%Y-%m-%d").to_string(),
+        weekday: today.format("%A").to_string(),
+        is_holiday_schedule: !added_service_ids.is_empty() || !removed_service_ids.is_empty(),
+        added_service_ids,
+        removed_service_ids,
+        running_route_count,
+        changed_route_count,
+        routes,
+    }))
+}
+
+fn render_departures_ics(
+    stop: &Stop,
+    departures: &[ScheduledDeparture],
+    kl_offset: FixedOffset,
+) -> String {
+    let generated_at = chrono::Utc::now().format("%Y%m%dT%H%M%SZ").to_string();
+    let mut lines = vec![
+        "BEGIN:VCALENDAR".to_string(),
+        "VERSION:2.0".to_string(),
+        "PRODID:-//rapidbro//departures//EN".to_string(),
+        "CALSCALE:GREGORIAN".to_string(),
+        format!("X-WR-CALNAME:{}", ics_escape(&stop.stop_name)),
+    ];
+
+    for departure in departures {
+        let Some(midnight) = departure
+            .service_date
+            .and_hms_opt(0, 0, 0)
+            .and_then(|midnight| kl_offset.from_local_datetime(&midnight).single())
+        else {
+            continue;
+        };
+        let start = midnight + chrono::Duration::seconds(departure.departure_secs);
+        let end = start + chrono::Duration::minutes(1);
+        lines.push("BEGIN:VEVENT".to_string());
+        lines.push(format!(
+            "UID:{}-{}-{}@rapidbro",
+            departure.trip_id,
+            departure.service_date.format("%Y%m%d"),
+            departure.departure_secs
+        ));
+        lines.push(format!("DTSTAMP:{}", generated_at));
+        lines.push(format!(
+            "DTSTART:{}",
+            start.with_timezone(&chrono::Utc).format("%Y%m%dT%H%M%SZ")
+        ));
+        lines.push(format!(
+            "DTEND:{}",
+            end.with_timezone(&chrono::Utc).format("%Y%m%dT%H%M%SZ")
+        ));
+        lines.push(format!(
+            "SUMMARY:{}",
+            ics_escape(&format!(
+                "{} to {}",
+                departure.route_short_name, departure.headsign
+            ))
+        ));
+        lines.push(format!(
+            "LOCATION:{}",
+            ics_escape(&format!("{} ({})", stop.stop_name, stop.stop_id))
+        ));
+        lines.push(format!("GEO:{};{}", stop.stop_lat, stop.stop_lon));
+        lines.push("END:VEVENT".to_string());
+    }
+    lines.push("END:VCALENDAR".to_string());
+
+    let mut body = String::new();
+    for line in lines {
+        body.push_str(&fold_ics_line(&line));
+        body.push_str("\r\n");
+    }
+    body
+}
+
+fn ics_escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace(';', "\\;")
+        .replace(',', "\\,")
+        .replace('\n', "\\n")
+}
+
+// RFC 5545 caps content lines at 75 octets; continuations start with a single space.
+fn fold_ics_line(line: &str) -> String {
+    let mut folded = String::with_capacity(line.len());
+    let mut line_octets = 0;
+    let mut limit = 75;
+    for ch in line.chars() {
+        if line_octets + ch.len_utf8() > limit {
+            folded.push_str("\r\n ");
+            line_octets = 0;
+            limit = 74;
+        }
+        folded.push(ch);
+        line_octets += ch.len_utf8();
+    }
+    folded
+}
+
+async fn calculate_route_eta(
+    state: &AppState,
+    route_id: &str,
+    target_stop_id: &str,
+    as_of: Option<i64>,
+) -> Result<Vec<BusEta>, AppError> {
+    let mut results = calculate_route_etas(state, route_id, &[target_stop_id], as_of).await?;
+    Ok(results.pop().unwrap_or_default())
+}
+
+// One snapshot read and one pass resolving buses onto the route, shared by every target stop.
+async fn calculate_route_etas(
+    state: &AppState,
+    route_id: &str,
+    target_stop_ids: &[&str],
+    as_of: Option<i64>,
+) -> Result<Vec<Vec<BusEta>>, AppError> {
+    let snapshot = load_bus_snapshot_as_of(state, as_of).await?;
+    let thresholds = *state.thresholds.read().await;
+    let visible_buses = filter_non_stationary_buses(
+        &snapshot.buses,
+        &snapshot.motion_states,
+        snapshot.captured_at_unix_ms,
+        &thresholds,
+    );
+    let gtfs = load_gtfs_context(state);
+    let full_route_stops = cached_stops_by_route(&gtfs, route_id, None)?;
+    let route_stops = eta_stops_by_route(&gtfs, route_id)?;
+    // A stop a detour skips gets no buses rather than failing the request; stops that aren't on
+    // the route at all still do.
+    let served_stop_ids: Vec<&str> = target_stop_ids
+        .iter()
+        .copied()
+        .filter(|stop_id| {
+            route_stops
+                .stops
+                .iter()
+                .any(|stop| stop.stop_id == *stop_id)
+                || !full_route_stops
+                    .stops
+                    .iter()
+                    .any(|stop| stop.stop_id == *stop_id)
+        })
+        .collect();
+
+    record_stop_resolutions(
+        &state.resolution_log,
+        &visible_buses,
+        route_id,
+        &route_stops,
+        &thresholds,
+        snapshot.captured_at_unix_ms,
+    );
+    let mut served_results = {
+        let _timer = StageTimer::start(Stage::EtaCompute);
+        calculate_route_eta_for_stops(
+            &visible_buses,
+            &snapshot.motion_states,
+            route_id,
+            &served_stop_ids,
+            &route_stops,
+            gtfs.route_geometries.get(&normalize_route_code(route_id)),
+            &thresholds,
+            snapshot.captured_at_unix_ms,
+        )
+        .map_err(AppError::NotFound)?
+        .into_iter()
+    };
+    let mut all_results: Vec<Vec<BusEta>> = target_stop_ids
+        .iter()
+        .map(|stop_id| {
+            if served_stop_ids.contains(stop_id) {
+                served_results.next().unwrap_or_default()
+            } else {
+                Vec::new()
+            }
+        })
+        .collect();
+    let segment_runtimes =
+        if feature_enabled(&state.feature_flags, FLAG_RUNTIME_PROFILE_ETA, route_id) {
+            state
+                .runtime_profiles
+                .read()
+                .await
+                .get(route_id)
+                .map(|profile| {
+                    segment_runtimes_for_hour(profile, kl_hour_of_day(snapshot.captured_at_unix_ms))
+                })
+                .unwrap_or_default()
+        } else {
+            HashMap::new()
+        };
+    for (eta_results, target_stop_id) in all_results.iter_mut().zip(target_stop_ids) {
+        apply_runtime_profile(
+            eta_results,
+            &route_stops,
+            target_stop_id,
+            &segment_runtimes,
+            &thresholds,
+        );
+        apply_route_detours(&gtfs, route_id, target_stop_id, eta_results);
+        apply_data_age(
+            eta_results,
+            &snapshot.last_seen_unix_ms,
+            snapshot.captured_at_unix_ms,
+            state.bus_ttl_ms,
+        );
+        attach_route_display(&gtfs, eta_results);
+        annotate_bus_places(state, eta_results).await;
+    }
+    Ok(all_results)
+}
+
+// Data OpenDOSM Prasarana - uses protobuf (alternative data source). Served from the cache
+// kept warm by run_gtfs_rt_refresher rather than hitting upstream per request.
+async fn prasarana_gtfs_data(
+    ValidQuery(query): ValidQuery<GtfsProxyQuery>,
+    State(state): State<AppState>,
+    Extension(shape): Extension<ResponseShape>,
+    headers: HeaderMap,
+) -> Result<Response, AppError> {
+    let wants_protobuf = match query.format.as_deref().map(str::to_lowercase).as_deref() {
+        Some("protobuf") | Some("pb") => true,
+        Some("json") => false,
+        Some(other) => {
+            return Err(AppError::Validation(format!(
+                "Unknown format '{}', expected json or protobuf",
+                other
+            )))
+        }
+        None => headers
+            .get(ACCEPT)
+            .and_then(|value| value.to_str().ok())
+            .is_some_and(|accept| accept.contains("application/x-protobuf")),
+    };
+
+    let cache = state.gtfs_rt_cache.read().await;
+    let (Some(etag), Some((protobuf, json_body))) =
+        (cache.etag.clone(), cache.shaped.get(&shape).cloned())
+    else {
+        let reason = cache
+            .last_error
+            .clone()
+            .unwrap_or_else(|| "first fetch has not completed yet".to_string());
+        return Err(AppError::Upstream(format!(
+            "GTFS-RT feed unavailable: {}",
+            reason
+        )));
+    };
+    let fetched_at_unix_ms = cache.fetched_at_unix_ms;
+    drop(cache);
+
+    // The ETag is per representation and shape so a cached JSON copy never satisfies a protobuf
+    // request, nor an exact copy a coarse one.
+    let etag = format!(
+        "\"{}-{}-{}-{}\"",
+        etag,
+        shape.profile.name(),
+        shape.precision.name(),
+        if wants_protobuf { "pb" } else { "json" }
+    );
+    let cache_control = format!("public, max-age={}", gtfs_rt_refresh_seconds());
+    // Shared caches must not hand one tier's copy to another.
+    let vary = format!(
+        "{}, {}, {}",
+        ACCEPT, API_KEY_HEADER, POSITION_PRECISION_HEADER
+    );
+    let not_modified = headers
+        .get(IF_NONE_MATCH)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| {
+            value
+                .split(',')
+                .any(|tag| tag.trim() == etag || tag.trim() == "*")
+        });
+    if not_modified {
+        return Ok((
+            StatusCode::NOT_MODIFIED,
+            [(ETAG, etag), (CACHE_CONTROL, cache_control), (VARY, vary)],
+            Extension(shape),
+        )
+            .into_response());
+    }
+
+    println!(
+        "Calling prasarana_gtfs_data (format={}, fetched_at={:?})",
+        if wants_protobuf { "protobuf" } else { "json" },
+        fetched_at_unix_ms
+    );
+    let (content_type, body) = if wants_protobuf {
+        ("application/x-protobuf", protobuf)
+    } else {
+        ("application/json", json_body)
+    };
+    Ok((
+        [
+            (CONTENT_TYPE, content_type.to_string()),
+            (ETAG, etag),
+            (CACHE_CONTROL, cache_control),
+            (VARY, vary),
+        ],
+        Extension(shape),
+        body,
+    )
+        .into_response())
+}
+
+async fn get_stop_dwell_stats(
+    StopRef { stop_id, stop_code }: StopRef,
+    State(state): State<AppState>,
+) -> Result<Json<DwellStatsResponse>, AppError> {
+    let mut redis_conn = read_redis_client(&state)
+        .get_multiplexed_async_connection()
+        .await?;
+    let counters: HashMap<String, u64> = redis::cmd("HGETALL")
+        .arg(format!(
+            "{}{}",
+            state.redis_keys.key(REDIS_STOP_DWELL_KEY_PREFIX),
+            stop_id
+        ))
+        .query_async(&mut redis_conn)
+        .await?;
+
+    let hours: Vec<DwellHourStats> = (0..24)
+        .filter_map(|hour: u32| {
+            let counter = |name: &str| {
+                counters
+                    .get(&format!("{}:{}", hour, name))
+                    .copied()
+                    .unwrap_or(0)
+            };
+            let event_count = counter("count");
+            if event_count == 0 {
+                return None;
+            }
+            Some(DwellHourStats {
+                hour,
+                event_count,
+                mean_dwell_seconds: (counter("sum_ms") as f64 / event_count as f64 / 100.0).round()
+                    / 10.0,
+                buckets: DWELL_BUCKETS
+                    .iter()
+                    .map(|(label, _)| DwellBucket {
+                        label: label.to_string(),
+                        count: counter(label),
+                    })
+                    .collect(),
+            })
+        })
+        .collect();
+    let total_events = hours.iter().map(|hour| hour.event_count).sum();
+
+    println!(
+        "Calling get_stop_dwell_stats for stop_id={}: {} dwell events",
+        stop_id, total_events
+    );
+    Ok(Json(DwellStatsResponse {
+        stop_id,
+        stop_code,
+        total_events,
+        hours,
+        source: "dwell_proxy".to_string(),
+    }))
+}
+
+// Typical end-to-end and per-segment runtimes for /routes/{route_id}/runtimes, by hour of day.
+async fn get_route_runtimes(
+    RouteRef { route_id }: RouteRef,
+    State(state): State<AppState>,
+) -> Result<Json<RouteRuntimesResponse>, AppError> {
+    let mut redis_conn = read_redis_client(&state)
+        .get_multiplexed_async_connection()
+        .await?;
+    let mut response = load_route_runtimes(&mut redis_conn, &state.redis_keys, &route_id).await?;
+
+    // Serve segments in route order; pairs no longer on the route go last.
+    let gtfs = load_gtfs_context(&state);
+    if let Ok(route_stops) = cached_stops_by_route(&gtfs, &route_id, None) {
+        let position = |stop_id: &str| {
+            route_stops
+                .stops
+                .iter()
+                .position(|stop| stop.stop_id == stop_id)
+                .unwrap_or(usize::MAX)
+        };
+        response
+            .segments
+            .sort_by_key(|segment| position(&segment.from_stop_id));
+    }
+
+    println!(
+        "Calling get_route_runtimes for route_id={}: {} segments, {} trip hours",
+        route_id,
+        response.segments.len(),
+        response.trip.len()
+    );
+    Ok(Json(response))
+}
+
+// Today's frequencies.txt windows for the route (Kuala Lumpur service day).
+async fn get_route_headways(
+    RouteRef { route_id }: RouteRef,
+    State(state): State<AppState>,
+) -> Result<Json<RouteHeadwaysResponse>, AppError> {
+    let gtfs = load_gtfs_context(&state);
+    let today =
+        kl_date(state.clock.now_ms()).ok_or_else(|| internal_error("clock is out of range"))?;
+    let headways = route_headways(&route_id, &gtfs, today);
+
+    println!(
+        "Calling get_route_headways for route_id={}: {} windows",
+        route_id,
+        headways.len()
+    );
+    Ok(Json(RouteHeadwaysResponse {
+        route_id,
+        service_date: today.format("%Y-%m-%d").to_string(),
+        headways,
+    }))
+}
+
+// Axum handler for /route/:route_id/stops
+async fn get_route_stops(
+    RouteRef { route_id }: RouteRef,
+    ValidQuery(query): ValidQuery<PageQuery>,
+    State(state): State<AppState>,
+) -> Result<Json<RouteStopsResponse>, AppError> {
+    let list = PageList::RouteStops {
+        route_id: route_id.clone(),
+    };
+    let cursor = query
+        .cursor
+        .as_deref()
+        .map(|value| decode_page_cursor(&state.cursor_secret, value, &list))
+        .transpose()?;
+    let gtfs = load_gtfs_context(&state);
+    let mut response = cached_stops_by_route(&gtfs, &route_id, None)?
+        .as_ref()
+        .clone();
+    // Zero-padded so the keys sort like the stop_sequence numbers they come from.
+    let (stops, next_after) = take_page(
+        response.stops,
+        |stop| format!("{:010}", stop.sequence),
+        query.limit,
+        cursor.as_ref(),
+    );
+    response.stops = stops;
+    response.next_cursor = next_after.map(|after| {
+        encode_page_cursor(
+            &state.cursor_secret,
+            &PageCursor {
+                list,
+                after,
+                snapshot_seq: None,
+            },
+        )
+    });
+    println!("Calling get_route_stops for route_id={}", route_id);
+    Ok(Json(response))
+}
+
+// The polyline of the route's trip running today, from shapes.txt as loaded with the feed.
+async fn get_route_shape(
+    RouteRef { route_id }: RouteRef,
+    ValidQuery(query): ValidQuery<ShapeQuery>,
+    State(state): State<AppState>,
+) -> Result<Response, AppError> {
+    let gtfs = load_gtfs_context(&state);
+    let shape = get_shape_by_route(
+        &route_id,
+        gtfs.service_date
+            .map(|service_date| (&gtfs.calendars, service_date)),
+        &gtfs.trips_by_route,
+        &gtfs.shapes_by_id,
+    )?;
+    println!(
+        "Calling get_route_shape for route_id={}, format={:?}: {} points",
+        route_id,
+        query.format,
+        shape.points.len()
+    );
+
+    if query.format.as_deref().map(str::trim) != Some("geojson") {
+        return Ok(Json(shape).into_response());
+    }
+    // GeoJSON orders coordinates longitude first.
+    let coordinates: Vec<[f64; 2]> = shape
+        .points
+        .iter()
+        .map(|point| [point.lon, point.lat])
+        .collect();
+    let body = json!({
+        "type": "Feature",
+        "geometry": {
+            "type": "LineString",
+            "coordinates": coordinates,
+        },
+        "properties": {
+            "route_id": shape.route_id,
+            "shape_id": shape.shape_id,
+        },
+    });
+    Ok(([(CONTENT_TYPE, "application/geo+json")], body.to_string()).into_response())
+}
+
+#[derive(Debug, Deserialize)]
+struct RouteMapQuery {
+    width: Option<u32>,
+    height: Option<u32>,
+}
+
+// Renders the route shape, its stops and live buses over map tiles as a PNG.
+async fn get_route_map_png(
+    RouteRef { route_id }: RouteRef,
+    ValidQuery(query): ValidQuery<RouteMapQuery>,
+    State(state): State<AppState>,
+) -> Result<Response, AppError> {
+    let width = query
+        .width
+        .unwrap_or(DEFAULT_MAP_WIDTH)
+        .clamp(MIN_MAP_DIMENSION, MAX_MAP_DIMENSION);
+    let height = query
+        .height
+        .unwrap_or(DEFAULT_MAP_HEIGHT)
+        .clamp(MIN_MAP_DIMENSION, MAX_MAP_DIMENSION);
+
+    let gtfs = load_gtfs_context(&state);
+    let shape = get_shape_by_route(
+        &route_id,
+        gtfs.service_date
+            .map(|service_date| (&gtfs.calendars, service_date)),
+        &gtfs.trips_by_route,
+        &gtfs.shapes_by_id,
+    )?;
+    let route_stops = cached_stops_by_route(&gtfs, &route_id, None)?;
+    let route_color = gtfs
+        .routes
+        .iter()
+        .find(|route| route.route_id == route_id)
+        .and_then(|route| parse_hex_color(&route.route_color))
+        .unwrap_or([0, 92, 175, 255]);
+
+    let snapshot = load_active_bus_snapshot(&state).await?;
+    let thresholds = *state.thresholds.read().await;
+    let buses: Vec<(f64, f64)> = filter_non_stationary_buses(
+        &snapshot.buses,
+        &snapshot.motion_states,
+        snapshot.captured_at_unix_ms,
+        &thresholds,
+    )
+    .into_iter()
+    .filter(|bus| is_bus_on_route(&bus.route, &route_id))
+    .map(|bus| (bus.latitude, bus.longitude))
+    .collect();
+
+    let shape_points: Vec<(f64, f64)> = shape
+        .points
+        .iter()
+        .map(|point| (point.lat, point.lon))
+        .collect();
+    let stop_points: Vec<(f64, f64)> = route_stops
+        .stops
+        .iter()
+        .map(|stop| (stop.stop_lat, stop.stop_lon))
+        .collect();
+
+    let viewport = MapViewport::fit(
+        shape_points
+            .iter()
+            .chain(stop_points.iter())
+            .chain(buses.iter()),
+        width,
+        height,
+    )
+    .ok_or_else(|| AppError::NotFound(format!("Route '{}' has no geometry to draw", route_id)))?;
+
+    let mut canvas = image::RgbaImage::from_pixel(width, height, image::Rgba([235, 235, 235, 255]));
+    draw_map_tiles(&mut canvas, &viewport).await;
+
+    let line_color = image::Rgba(route_color);
+    for segment in shape_points.windows(2) {
+        let (x1, y1) = viewport.project(segment[0].0, segment[0].1);
+        let (x2, y2) = viewport.project(segment[1].0, segment[1].1);
+        // Three offset passes give a ~3px line without pulling in a rasterizer.
+        for offset in [-1.0, 0.0, 1.0] {
+            imageproc::drawing::draw_line_segment_mut(
+                &mut canvas,
+                (x1 as f32 + offset, y1 as f32),
+                (x2 as f32 + offset, y2 as f32),
+                line_color,
+            );
+            imageproc::drawing::draw_line_segment_mut(
+                &mut canvas,
+                (x1 as f32, y1 as f32 + offset),
+                (x2 as f32, y2 as f32 + offset),
+                line_color,
+            );
+        }
+    }
+    for (lat, lon) in &stop_points {
+        let (x, y) = viewport.project(*lat, *lon);
+        let center = (x.round() as i32, y.round() as i32);
+        imageproc::drawing::draw_filled_circle_mut(&mut canvas, center, 4, line_color);
+        imageproc::drawing::draw_filled_circle_mut(
+            &mut canvas,
+            center,
+            2,
+            image::Rgba([255, 255, 255, 255]),
+        );
+    }
+    for (lat, lon) in &buses {
+        let (x, y) = viewport.project(*lat, *lon);
+        let center = (x.round() as i32, y.round() as i32);
+        imageproc::drawing::draw_filled_circle_mut(
+            &mut canvas,
+            center,
+            7,
+            image::Rgba([255, 255, 255, 255]),
+        );
+        imageproc::drawing::draw_filled_circle_mut(
+            &mut canvas,
+            center,
+            5,
+            image::Rgba([220, 38, 38, 255]),
+        );
+    }
+
+    let mut png = std::io::Cursor::new(Vec::new());
+    canvas
+        .write_to(&mut png, image::ImageFormat::Png)
+        .map_err(internal_error)?;
+
+    println!(
+        "Calling get_route_map_png for route_id={}: {}x{} at zoom {}, {} buses",
+        route_id,
+        width,
+        height,
+        viewport.zoom,
+        buses.len()
+    );
+    Ok((
+        [
+            (CONTENT_TYPE, "image/png"),
+            (CACHE_CONTROL, "public, max-age=30"),
+            (
+                HeaderName::from_static("x-map-attribution"),
+                "Map data (c) OpenStreetMap contributors",
+            ),
+        ],
+        png.into_inner(),
+    )
+        .into_response())
+}
+
+// Web Mercator viewport: world pixel coordinates at `zoom`, offset so (0, 0) is the top-left
+// corner of the rendered image.
+#[derive(Debug, Clone, Copy)]
+struct MapViewport {
+    zoom: u32,
+    left_px: f64,
+    top_px: f64,
+    width: u32,
+    height: u32,
+}
+
+impl MapViewport {
+    fn fit<'a>(
+        points: impl Iterator<Item = &'a (f64, f64)> + Clone,
+        width: u32,
+        height: u32,
+    ) -> Option<Self> {
+        let mut zoom = MAP_MAX_ZOOM;
+        loop {
+            let (mut min_x, mut min_y) = (f64::MAX, f64::MAX);
+            let (mut max_x, mut max_y) = (f64::MIN, f64::MIN);
+            for (lat, lon) in points.clone() {
+                let (x, y) = mercator_world_px(*lat, *lon, zoom);
+                min_x = min_x.min(x);
+                min_y = min_y.min(y);
+                max_x = max_x.max(x);
+                max_y = max_y.max(y);
+            }
+            if min_x > max_x {
+                return None;
+            }
+
+            let fits = max_x - min_x <= f64::from(width) - 2.0 * MAP_PADDING_PX
+                && max_y - min_y <= f64::from(height) - 2.0 * MAP_PADDING_PX;
+            if fits || zoom == 1 {
+                return Some(Self {
+                    zoom,
+                    left_px: (min_x + max_x) / 2.0 - f64::from(width) / 2.0,
+                    top_px: (min_y + max_y) / 2.0 - f64::from(height) / 2.0,
+                    width,
+                    height,
+                });
+            }
+            zoom -= 1;
+        }
+    }
+
+    fn project(&self, lat: f64, lon: f64) -> (f64, f64) {
+        let (x, y) = mercator_world_px(lat, lon, self.zoom);
+        (x - self.left_px, y - self.top_px)
+    }
+}
+
+fn mercator_world_px(lat: f64, lon: f64, zoom: u32) -> (f64, f64) {
+    let scale = MAP_TILE_SIZE * f64::from(1u32 << zoom);
+    let lat_rad = lat.clamp(-85.0511, 85.0511).to_radians();
+    let x = (lon + 180.0) / 360.0 * scale;
+    let y = (1.0 - (lat_rad.tan() + 1.0 / lat_rad.cos()).ln() / std::f64::consts::PI) / 2.0 * scale;
+    (x, y)
+}
+
+// Missing tiles are left as the background colour; the overlay is still useful without them.
+async fn draw_map_tiles(canvas: &mut image::RgbaImage, viewport: &MapViewport) {
+    let tile_url = env::var("MAP_TILE_URL").unwrap_or_else(|_| DEFAULT_MAP_TILE_URL.to_string());
+    let Ok(client) = reqwest::Client::builder()
+        .user_agent(concat!("rapidbro/", env!("CARGO_PKG_VERSION")))
+        .timeout(Duration::from_secs(5))
+        .build()
+    else {
+        return;
+    };
+
+    let tiles_per_axis = 1i64 << viewport.zoom;
+    let first_x = (viewport.left_px / MAP_TILE_SIZE).floor() as i64;
+    let first_y = (viewport.top_px / MAP_TILE_SIZE).floor() as i64;
+    let last_x = ((viewport.left_px + f64::from(viewport.width)) / MAP_TILE_SIZE).floor() as i64;
+    let last_y = ((viewport.top_px + f64::from(viewport.height)) / MAP_TILE_SIZE).floor() as i64;
+
+    let mut requests = Vec::new();
+    for tile_x in first_x..=last_x {
+        for tile_y in first_y..=last_y {
+            if tile_y < 0 || tile_y >= tiles_per_axis {
+                continue;
+            }
+            let url = tile_url
+                .replace("{z}", &viewport.zoom.to_string())
+                .replace("{x}", &tile_x.rem_euclid(tiles_per_axis).to_string())
+                .replace("{y}", &tile_y.to_string());
+            let client = client.clone();
+            requests.push(async move {
+                let bytes = client
+                    .get(url)
+                    .send()
+                    .await
+                    .and_then(|response| response.error_for_status())
+                    .ok()?
+                    .bytes()
+                    .await
+                    .ok()?;
+                let tile = image::load_from_memory(&bytes).ok()?.to_rgba8();
+                Some((tile_x, tile_y, tile))
+            });
+        }
+    }
+
+    for (tile_x, tile_y, tile) in futures_util::future::join_all(requests)
+        .await
+        .into_iter()
+        .flatten()
+    {
+        let offset_x = (tile_x as f64 * MAP_TILE_SIZE - viewport.left_px).round() as i64;
+        let offset_y = (tile_y as f64 * MAP_TILE_SIZE - viewport.top_px).round() as i64;
+        image::imageops::overlay(canvas, &tile, offset_x, offset_y);
+    }
+}
+
+fn parse_hex_color(value: &str) -> Option<[u8; 4]> {
+    let hex = value.trim().trim_start_matches('#');
+    if hex.len() != 6 {
+        return None;
+    }
+    let channel = |index: usize| u8::from_str_radix(&hex[index..index + 2], 16).ok();
+    Some([channel(0)?, channel(2)?, channel(4)?, 255])
+}
+
+// One search box for routes and stops. Scores favour exact code matches, then prefixes, then
+// word prefixes, then plain substrings; ties sort routes first and then by name.
+async fn search_routes_and_stops(
+    ValidQuery(query): ValidQuery<SearchQuery>,
+    State(state): State<AppState>,
+) -> Result<Json<SearchResponse>, AppError> {
+    let needle = query.q.trim().to_uppercase();
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_SEARCH_LIMIT)
+        .clamp(1, MAX_SEARCH_LIMIT);
+    let gtfs = load_gtfs_context(&state);
+
+    let mut results: Vec<SearchResult> = Vec::new();
+    for route in &gtfs.routes {
+        let short_name = route.route_short_name.to_uppercase();
+        let score = if short_name == needle || normalize_route_code(&route.route_id) == needle {
+            100
+        } else if short_name.starts_with(&needle) {
+            80
+        } else {
+            text_match_score(&route.route_long_name, &needle)
+        };
+        if score > 0 {
+            results.push(SearchResult::Route {
+                route_id: route.route_id.clone(),
+                route_short_name: route.route_short_name.clone(),
+                route_long_name: route.route_long_name.clone(),
+                score,
+            });
+        }
+    }
+
+    for stop in gtfs.stops_map.values() {
+        let stop_code = stop.stop_code.clone();
+        let score = if stop_code.as_deref() == Some(needle.as_str()) || stop.stop_id == needle {
+            90
+        } else {
+            text_match_score(&stop.stop_name, &needle)
+                .max(text_match_score(&stop.stop_desc, &needle) / 2)
+        };
+        if score > 0 {
+            results.push(SearchResult::Stop {
+                stop_id: stop.stop_id.clone(),
+                stop_code,
+                stop_name: stop.stop_name.clone(),
+                stop_desc: stop.stop_desc.clone(),
+                stop_lat: stop.stop_lat,
+                stop_lon: stop.stop_lon,
+                score,
+            });
+        }
+    }
+
+    results.sort_by(|left, right| {
+        right
+            .score()
+            .cmp(&left.score())
+            .then_with(|| search_result_sort_key(left).cmp(&search_result_sort_key(right)))
+    });
+    results.truncate(limit);
+
+    println!(
+        "Calling search_routes_and_stops for q={}: {} results",
+        query.q,
+        results.len()
+    );
+    Ok(Json(SearchResponse {
+        query: query.q,
+        results,
+    }))
+}
+
+// `needle` is already upper-cased.
+fn text_match_score(haystack: &str, needle: &str) -> u32 {
+    let haystack = haystack.to_uppercase();
+    if haystack.starts_with(needle) {
+        70
+    } else if haystack
+        .split(|ch: char| !ch.is_alphanumeric())
+        .any(|word| word.starts_with(needle))
+    {
+        60
+    } else if haystack.contains(needle) {
+        40
+    } else {
+        0
+    }
+}
+
+fn search_result_sort_key(result: &SearchResult) -> (u8, &str) {
+    match result {
+        SearchResult::Route {
+            route_short_name, ..
+        } => (0, route_short_name.as_str()),
+        SearchResult::Stop { stop_name, .. } => (1, stop_name.as_str()),
+    }
+}
+
+// Axum handler for /stops/nearest?lat={lat}&lon={lon}
+async fn get_nearest_stop(
+    ValidQuery(query): ValidQuery<NearestStopQuery>,
+    State(state): State<AppState>,
+) -> Result<Json<NearestStopResponse>, AppError> {
+    let closed_stops = active_stop_closures(&state, state.clock.now_ms());
+    let mut response = find_nearest_stop(
+        &current_gtfs_feed(&state).stops_map,
+        query.lat,
+        query.lon,
+        &closed_stops,
+    )?;
+    if let Some(geocoder) = &state.reverse_geocoder {
+        response.place = reverse_geocode(geocoder, response.stop_lat, response.stop_lon).await;
+    }
+
+    println!(
+        "Calling get_nearest_stop for lat={}, lon={} -> stop_id={}",
+        query.lat, query.lon, response.stop_id
+    );
+    Ok(Json(response))
+}
+
+#[cfg(test)]
+mod tests {
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::gtfs::{default_city_from_env, load_city_gtfs_feed, DEFAULT_CITY_ID};
+    use crate::ingest::initial_ingestor_status;
+    use crate::models::{ExtraGtfsFeed, Warmup};
+    use crate::test_support::{
+        t789_feed_files, test_app_state, FeedDir, CALENDAR, ROUTES_HEADER, STOPS_HEADER,
+    };
+
+    const NOW_MS: i64 = 1_772_409_600_000;
+
+    async fn get_response(app: &Router, uri: &str) -> (StatusCode, String) {
+        let request = axum::http::Request::builder()
+            .uri(uri)
+            .body(axum::body::Body::empty())
+            .unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        let status = response.status();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        (status, String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    fn gtfs_rt_feed() -> gtfs_realtime::FeedMessage {
+        gtfs_realtime::FeedMessage {
+            entity: vec![gtfs_realtime::FeedEntity {
+                id: "WXY1234".to_string(),
+                vehicle: Some(gtfs_realtime::VehiclePosition {
+                    position: Some(gtfs_realtime::Position {
+                        latitude: 3.121_867,
+                        longitude: 101.657_16,
+                        // 24 km/h.
+                        speed: Some(6.667),
+                        ..Default::default()
+                    }),
+                    trip: Some(gtfs_realtime::TripDescriptor {
+                        route_id: Some("T7890".to_string()),
+                        trip_id: Some("T789002".to_string()),
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            }],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn check_id_accepts_gtfs_ids_and_names_what_is_wrong_with_the_rest() {
+        let too_long = "T".repeat(MAX_ID_LENGTH + 1);
+        let longest = "T".repeat(MAX_ID_LENGTH);
+        let only_allowed = "may only contain letters, digits, '-', '_', '.' and ':'";
+        let cases = [
+            ("1000838", None),
+            (" T7890 ", None),
+            ("weekday_T7880_T788001_0", None),
+            ("mrtfeeder:T4600", None),
+            ("route-group.v2", None),
+            (longest.as_str(), None),
+            ("", Some("must not be empty")),
+            ("   ", Some("must not be empty")),
+            (too_long.as_str(), Some("must be at most 64 characters")),
+            ("1000838/eta", Some(only_allowed)),
+            ("T789 0", Some(only_allowed)),
+            ("KL1397%20", Some(only_allowed)),
+            ("PJ469é", Some(only_allowed)),
+        ];
+        for (value, expected) in cases {
+            let mut errors = Vec::new();
+            check_id(&mut errors, "stop_id", value);
+            let messages: Vec<&str> = errors.iter().map(|error| error.message.as_str()).collect();
+            assert_eq!(
+                messages,
+                expected.into_iter().collect::<Vec<_>>(),
+                "{:?}",
+                value
+            );
+            assert!(errors.iter().all(|error| error.field == "stop_id"));
+        }
+    }
+
+    #[tokio::test]
+    async fn valid_query_and_valid_path_reject_with_field_level_400s() {
+        let app: Router = Router::new()
+            .route(
+                "/nearest",
+                get(
+                    |ValidQuery(query): ValidQuery<NearestStopQuery>| async move {
+                        format!("{},{}", query.lat, query.lon)
+                    },
+                ),
+            )
+            .route(
+                "/stops/{stop_id}",
+                get(|ValidPath(path): ValidPath<StopPath>| async move { path.stop_id }),
+            );
+        let cases = [
+            ("/nearest?lat=3.12&lon=101.65", StatusCode::OK, None),
+            (
+                "/nearest?lat=95&lon=101.65",
+                StatusCode::BAD_REQUEST,
+                Some(json!({
+                    "error": "Invalid request: lat must be between -90 and 90",
+                    "fields": [{ "field": "lat", "message": "must be between -90 and 90" }],
+                })),
+            ),
+            (
+                "/nearest?lat=-95&lon=181",
+                StatusCode::BAD_REQUEST,
+                Some(json!({
+                    "error": "Invalid request: lat must be between -90 and 90; \
+                              lon must be between -180 and 180",
+                    "fields": [
+                        { "field": "lat", "message": "must be between -90 and 90" },
+                        { "field": "lon", "message": "must be between -180 and 180" },
+                    ],
+                })),
+            ),
+            // Wrong types never reach validation; the deserializer's message is passed on.
+            (
+                "/nearest?lat=north&lon=101.65",
+                StatusCode::BAD_REQUEST,
+                None,
+            ),
+            ("/stops/mrtfeeder:1200001", StatusCode::OK, None),
+            (
+                "/stops/1000838%20eta",
+                StatusCode::BAD_REQUEST,
+                Some(json!({
+                    "error": "Invalid request: stop_id may only contain letters, digits, \
+                              '-', '_', '.' and ':'",
+                    "fields": [{
+                        "field": "stop_id",
+                        "message": "may only contain letters, digits, '-', '_', '.' and ':'",
+                    }],
+                })),
+            ),
+        ];
+        for (uri, status, expected_body) in cases {
+            let (actual_status, body) = get_response(&app, uri).await;
+            assert_eq!(actual_status, status, "{}", uri);
+            if let Some(expected_body) = expected_body {
+                let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+                assert_eq!(body, expected_body, "{}", uri);
+            } else if status == StatusCode::BAD_REQUEST {
+                let body: ErrorResponse = serde_json::from_str(&body).unwrap();
+                assert!(body.error.contains("lat"), "{}", body.error);
+                assert!(body.fields.is_empty());
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn prefixed_ids_from_an_extra_feed_resolve_through_the_path_extractors() {
+        let main_feed = FeedDir::new("api-main-feed", &t789_feed_files(Some(CALENDAR)));
+        let extra_feed = FeedDir::new(
+            "api-mrtfeeder-feed",
+            &[
+                (
+                    "routes.txt",
+                    format!(
+                        "{}T4600,mrtfeeder,T460,Stesen MRT Cochrane,3,,\n",
+                        ROUTES_HEADER
+                    ),
+                ),
+                (
+                    "trips.txt",
+                    "route_id,service_id,trip_id,shape_id,trip_headsign,direction_id\n\
+                     T4600,weekday,T4600-1,S1,Cochrane,0\n"
+                        .to_string(),
+                ),
+                (
+                    "stop_times.txt",
+                    "trip_id,arrival_time,departure_time,stop_id,stop_sequence,stop_headsign\n\
+                     T4600-1,06:00:00,06:00:00,1200001,1,\n"
+                        .to_string(),
+                ),
+                (
+                    "stops.txt",
+                    format!(
+                        "{}1200001,,KL2299 MRT COCHRANE,,3.1327,101.7226\n",
+                        STOPS_HEADER
+                    ),
+                ),
+            ],
+        );
+        let mut city = default_city_from_env();
+        city.gtfs_data_path = main_feed.0.clone();
+        city.extra_gtfs_feeds = vec![ExtraGtfsFeed {
+            provider: "mrtfeeder".to_string(),
+            gtfs_data_path: extra_feed.0.clone(),
+        }];
+        let state = test_app_state(load_city_gtfs_feed(&city).unwrap(), NOW_MS);
+        let app = Router::new()
+            .route(
+                "/stops/{stop_id}",
+                get(|stop: StopRef| async move { stop.stop_id }),
+            )
+            .route(
+                "/route/{route_id}",
+                get(|route: RouteRef| async move { route.route_id }),
+            )
+            .with_state(state);
+
+        for (uri, resolved) in [
+            ("/stops/mrtfeeder:1200001", "mrtfeeder:1200001"),
+            ("/stops/KL2299", "mrtfeeder:1200001"),
+            ("/stops/1000838", "1000838"),
+            ("/route/mrtfeeder:T4600", "mrtfeeder:T4600"),
+            ("/route/T460", "mrtfeeder:T4600"),
+            ("/route/t789", "T7890"),
+        ] {
+            assert_eq!(
+                get_response(&app, uri).await,
+                (StatusCode::OK, resolved.to_string()),
+                "{}",
+                uri
+            );
+        }
+        assert_eq!(
+            get_response(&app, "/stops/penang:1200001").await.0,
+            StatusCode::NOT_FOUND
+        );
+    }
+
+    #[tokio::test]
+    async fn readiness_waits_for_every_city_to_warm_up() {
+        let feed = FeedDir::new("api-readiness-feed", &t789_feed_files(Some(CALENDAR)));
+        let mut city = default_city_from_env();
+        city.gtfs_data_path = feed.0.clone();
+        let default_state = test_app_state(load_city_gtfs_feed(&city).unwrap(), NOW_MS);
+        default_state
+            .startup_phases
+            .lock()
+            .unwrap()
+            .push(StartupPhaseTiming {
+                phase: StartupPhase::StartingIngestor,
+                completed_at_unix_ms: NOW_MS,
+                duration_ms: 0,
+            });
+        city.id = "penang".to_string();
+        let penang_state = AppState {
+            city: Arc::new(city),
+            fixture: None,
+            warmup: Warmup {
+                min_batches: 1,
+                ..default_state.warmup
+            },
+            ingestor_status: Arc::new(RwLock::new(initial_ingestor_status())),
+            ..default_state.clone()
+        };
+        let app = Router::new().route(
+            "/ready",
+            get(get_readiness).with_state(Arc::new(vec![default_state, penang_state.clone()])),
+        );
+
+        let (status, body) = get_response(&app, "/ready").await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        let readiness: ReadinessResponse = serde_json::from_str(&body).unwrap();
+        assert_eq!(readiness.phase, StartupPhase::WarmingUp);
+        assert!(!readiness.ready);
+        assert_eq!(
+            readiness
+                .cities
+                .iter()
+                .map(|city| (city.city_id.as_str(), city.phase))
+                .collect::<Vec<_>>(),
+            vec![
+                (DEFAULT_CITY_ID, StartupPhase::Ready),
+                ("penang", StartupPhase::WarmingUp),
+            ]
+        );
+
+        penang_state
+            .ingestor_status
+            .write()
+            .await
+            .messages_processed = 1;
+        let (status, body) = get_response(&app, "/ready").await;
+        assert_eq!(status, StatusCode::OK);
+        let readiness: ReadinessResponse = serde_json::from_str(&body).unwrap();
+        assert_eq!(readiness.phase, StartupPhase::Ready);
+        assert!(readiness.cities.iter().all(|city| city.ready));
+    }
+
+    #[tokio::test]
+    async fn singleflight_shares_one_computation_among_concurrent_callers() {
+        let flights = Singleflight::<u32>::new();
+        let computed = AtomicUsize::new(0);
+        let compute = || async {
+            computed.fetch_add(1, AtomicOrdering::SeqCst);
+            // Stay in flight until every caller has joined.
+            tokio::task::yield_now().await;
+            Ok(42)
+        };
+        let (first, second, third) = tokio::join!(
+            flights.run("stop:1000838:7".to_string(), compute),
+            flights.run("stop:1000838:7".to_string(), compute),
+            flights.run("stop:1000838:7".to_string(), compute),
+        );
+        assert_eq!(
+            (first.unwrap(), second.unwrap(), third.unwrap()),
+            (42, 42, 42)
+        );
+        assert_eq!(computed.load(AtomicOrdering::SeqCst), 1);
+        assert_eq!(flights.coalesced_total.load(AtomicOrdering::Relaxed), 2);
+        assert!(flights.flights.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn singleflight_does_not_share_a_failure() {
+        let flights = Singleflight::<u32>::new();
+        let computed = AtomicUsize::new(0);
+        let failing = || async {
+            computed.fetch_add(1, AtomicOrdering::SeqCst);
+            tokio::task::yield_now().await;
+            Err(AppError::Upstream("Redis timed out".to_string()))
+        };
+        let succeeding = || async {
+            computed.fetch_add(1, AtomicOrdering::SeqCst);
+            Ok(42)
+        };
+        let (first, second) = tokio::join!(
+            flights.run("stop:1000838:7".to_string(), failing),
+            flights.run("stop:1000838:7".to_string(), succeeding),
+        );
+        assert!(matches!(first, Err(AppError::Upstream(_))));
+        assert_eq!(flights.coalesced_total.load(AtomicOrdering::Relaxed), 1);
+        // The joined caller ran its own computation once the shared one failed.
+        assert_eq!(second.unwrap(), 42);
+        assert_eq!(computed.load(AtomicOrdering::SeqCst), 2);
+    }
+
+    #[test]
+    fn redact_json_hides_fields_and_coarsens_positions_at_any_depth() {
+        let mut body = json!({
+            "data": {
+                "buses": [{
+                    "bus_no": "WXY1234",
+                    "captain_id": "anon:1a2b3c4d5e6f7a8b",
+                    "trip_no": "T789002",
+                    "latitude": 3.121_867,
+                    "longitude": 101.657_186,
+                    "speed": 23.0,
+                }],
+                "etas": [{
+                    "current_lat": 3.109_951,
+                    "current_lon": 101.640_049,
+                    "smoothed_speed_kmh": 31.2,
+                    "stop_lat": 3.110_123,
+                }],
+            },
+        });
+        redact_json(
+            &mut body,
+            RedactionProfile::Public,
+            PositionPrecision::Coarse,
+        );
+        assert_eq!(
+            body,
+            json!({
+                "data": {
+                    "buses": [{
+                        "bus_no": "WXY1234",
+                        "latitude": 3.1219,
+                        "longitude": 101.6572,
+                        "speed": 25.0,
+                    }],
+                    "etas": [{
+                        "current_lat": 3.11,
+                        "current_lon": 101.64,
+                        "smoothed_speed_kmh": 30.0,
+                        // Stops are public, so their positions stay exact.
+                        "stop_lat": 3.110_123,
+                    }],
+                },
+            })
+        );
+    }
+
+    #[test]
+    fn redact_json_leaves_internal_exact_responses_alone() {
+        let original =
+            json!([{ "captain_id": "anon:1a2b", "trip_no": "T789002", "latitude": 3.121_867 }]);
+        let mut body = original.clone();
+        redact_json(
+            &mut body,
+            RedactionProfile::Internal,
+            PositionPrecision::Exact,
+        );
+        assert_eq!(body, original);
+
+        redact_json(
+            &mut body,
+            RedactionProfile::Partner,
+            PositionPrecision::Exact,
+        );
+        assert_eq!(
+            body,
+            json!([{ "trip_no": "T789002", "latitude": 3.121_867 }])
+        );
+    }
+
+    #[test]
+    fn shape_gtfs_rt_feed_matches_the_json_redaction() {
+        let mut feed = gtfs_rt_feed();
+        shape_gtfs_rt_feed(
+            &mut feed,
+            ResponseShape {
+                profile: RedactionProfile::Public,
+                precision: PositionPrecision::Coarse,
+            },
+        );
+        let vehicle = feed.entity[0].vehicle.as_ref().unwrap();
+        let trip = vehicle.trip.as_ref().unwrap();
+        assert!(trip.trip_id.is_none());
+        assert_eq!(trip.route_id.as_deref(), Some("T7890"));
+        let position = vehicle.position.as_ref().unwrap();
+        assert_eq!(position.latitude, 3.1219_f32);
+        assert_eq!(position.longitude, 101.6572_f32);
+        assert!((f64::from(position.speed.unwrap()) * 3.6 - 25.0).abs() < 1e-3);
+
+        let mut feed = gtfs_rt_feed();
+        shape_gtfs_rt_feed(
+            &mut feed,
+            ResponseShape {
+                profile: RedactionProfile::Partner,
+                precision: PositionPrecision::Exact,
+            },
+        );
+        assert_eq!(feed, gtfs_rt_feed());
+    }
+
+    #[test]
+    fn page_cursor_only_round_trips_unaltered_for_its_own_list() {
+        let list = PageList::RouteStops {
+            route_id: "T7890".to_string(),
+        };
+        let cursor = PageCursor {
+            list: list.clone(),
+            after: "0007".to_string(),
+            snapshot_seq: Some(42),
+        };
+        let encoded = encode_page_cursor("secret", &cursor);
+
+        let decoded = decode_page_cursor("secret", &encoded, &list).unwrap();
+        assert_eq!(decoded.after, "0007");
+        assert_eq!(decoded.snapshot_seq, Some(42));
+
+        assert!(decode_page_cursor("other secret", &encoded, &list).is_err());
+        assert!(decode_page_cursor("secret", &encoded, &PageList::Buses).is_err());
+        let (payload, signature) = encoded.split_once('.').unwrap();
+        let forged = format!("{}A.{}", payload, signature);
+        assert!(decode_page_cursor("secret", &forged, &list).is_err());
+        assert!(decode_page_cursor("secret", "no-signature", &list).is_err());
+    }
+
+    #[test]
+    fn take_page_resumes_after_the_cursor() {
+        let items: Vec<String> = ["a", "b", "c", "d", "e"].map(str::to_string).into();
+        let sort_key = |item: &String| item.clone();
+
+        let (whole, next) = take_page(items.clone(), sort_key, None, None);
+        assert_eq!(whole.len(), 5);
+        assert!(next.is_none());
+
+        let (first, next) = take_page(items.clone(), sort_key, Some(2), None);
+        assert_eq!(first, vec!["a", "b"]);
+        assert_eq!(next.as_deref(), Some("b"));
+
+        let cursor = PageCursor {
+            list: PageList::Buses,
+            after: "c".to_string(),
+            snapshot_seq: None,
+        };
+        let (last, next) = take_page(items, sort_key, Some(2), Some(&cursor));
+        assert_eq!(last, vec!["d", "e"]);
+        assert!(next.is_none());
+    }
+
+    #[test]
+    fn feature_bucket_is_stable_per_flag_and_subject() {
+        let bucket = feature_bucket("runtime_profile_eta", "1000838");
+        assert!(bucket < 100);
+        assert_eq!(feature_bucket("runtime_profile_eta", "1000838"), bucket);
+        let buckets: HashSet<u8> = (0..50)
+            .map(|stop| feature_bucket("runtime_profile_eta", &stop.to_string()))
+            .collect();
+        assert!(buckets.len() > 10);
+    }
+}
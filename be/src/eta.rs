@@ -0,0 +1,787 @@
+// App-side glue for the rapidbro-eta engine: thresholds configuration, the runtime-profile
+// fallback and the stop-wide ETA over a bus snapshot and the loaded GTFS context.
+
+use rapidbro_eta::{
+    apply_data_age, apply_detour_geometry, apply_runtime_profile, calculate_route_eta_for_stops,
+    calculate_route_eta_from_stops, filter_non_stationary_buses, is_bus_on_route,
+    is_bus_stationary, normalize_route_code, predicted_arrival_unix_ms, resolve_current_stop,
+    skip_detoured_stops, trace_current_stop, Thresholds, DEFAULT_ARRIVING_SOON_MAX_MINUTES,
+    DEFAULT_INCOMING_MAX_MINUTES, DEFAULT_MAX_ETA_SPEED_KMH, DEFAULT_MIN_ETA_SPEED_KMH,
+    DEFAULT_ROUTE_DEVIATION_CORRIDOR_M, DEFAULT_ROUTE_DEVIATION_MIN_UPDATES,
+    DEFAULT_SPEED_EMA_ALPHA, DEFAULT_SPEED_KMH, DEFAULT_STATIONARY_DISTANCE_THRESHOLD_KM,
+    DEFAULT_STATIONARY_SPEED_THRESHOLD_KMH, DEFAULT_STATIONARY_WINDOW_SECONDS,
+};
+use rapidbro_types::{
+    BusEta, BusPosition, DetourNotice, RecentDeparture, RouteRuntimesResponse, RouteStopsResponse,
+    StopResolutionDecision, StopResolutionRecord, TripDirection, TripStartedEvent,
+    TripTimelineStop,
+};
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+
+use crate::api::{AppError, Stage, StageTimer};
+use crate::env_or;
+use crate::gtfs::{cached_stops_by_route, kl_hour_of_day, DEFAULT_MAX_DERIVED_STOP_DISTANCE_KM};
+use crate::models::{
+    GtfsContext, RedisBusSnapshot, ResolutionLog, ShadowEvaluation, ShadowPrediction,
+    TerminusSchedule, ThresholdsPatch,
+};
+use crate::pipeline::BusEnrichment;
+
+pub(crate) const RECENT_DEPARTURE_WINDOW_MS: i64 = 30 * 60_000;
+// Shadow predictions are scored against the departure log, which only reaches back this far.
+const SHADOW_PREDICTION_MAX_AGE_MS: i64 = 2 * 3_600_000;
+const MAX_PENDING_SHADOW_PREDICTIONS: usize = 20_000;
+// Upper bounds in seconds; the last bucket is open-ended.
+pub(crate) const DWELL_BUCKETS: [(&str, i64); 5] = [
+    ("0-15s", 15),
+    ("15-30s", 30),
+    ("30-60s", 60),
+    ("60-120s", 120),
+    ("120s+", i64::MAX),
+];
+const RESOLUTION_LOG_CAPACITY: usize = 20;
+const RESOLUTION_LOG_MAX_BUSES: usize = 2_000;
+const RESOLUTION_LOG_CANDIDATES: usize = 5;
+// Fewer samples than this in an hour are too noisy to estimate with.
+pub(crate) const MIN_RUNTIME_PROFILE_SAMPLES: u64 = 3;
+// A bus due out sooner than this has probably already missed its slot; it is shown as leaving
+// shortly rather than immediately.
+const MIN_TERMINUS_WAIT_MS: i64 = 60_000;
+// Buses that would wait longer are taken to be parked rather than laying over.
+const MAX_TERMINUS_WAIT_MS: i64 = 60 * 60_000;
+
+// Mean segment runtimes (seconds) for one local hour of day, as apply_runtime_profile expects.
+pub(crate) fn segment_runtimes_for_hour(
+    profile: &RouteRuntimesResponse,
+    hour: u32,
+) -> HashMap<(String, String), f64> {
+    profile
+        .segments
+        .iter()
+        .filter_map(|segment| {
+            segment
+                .hours
+                .iter()
+                .find(|stats| {
+                    stats.hour == hour && stats.sample_count >= MIN_RUNTIME_PROFILE_SAMPLES
+                })
+                .map(|stats| {
+                    (
+                        (segment.from_stop_id.clone(), segment.to_stop_id.clone()),
+                        stats.mean_runtime_seconds,
+                    )
+                })
+        })
+        .collect()
+}
+
+pub(crate) fn thresholds_from_env() -> Thresholds {
+    Thresholds {
+        max_derived_stop_distance_km: env_or(
+            "MAX_DERIVED_STOP_DISTANCE_KM",
+            DEFAULT_MAX_DERIVED_STOP_DISTANCE_KM,
+        ),
+        stationary_speed_threshold_kmh: env_or(
+            "STATIONARY_SPEED_THRESHOLD_KMH",
+            DEFAULT_STATIONARY_SPEED_THRESHOLD_KMH,
+        ),
+        stationary_distance_threshold_km: env_or(
+            "STATIONARY_DISTANCE_THRESHOLD_KM",
+            DEFAULT_STATIONARY_DISTANCE_THRESHOLD_KM,
+        ),
+        stationary_window_ms: env_or(
+            "STATIONARY_WINDOW_SECONDS",
+            DEFAULT_STATIONARY_WINDOW_SECONDS,
+        ) * 1_000,
+        default_speed_kmh: env_or("DEFAULT_SPEED_KMH", DEFAULT_SPEED_KMH),
+        speed_ema_alpha: env_or("SPEED_EMA_ALPHA", DEFAULT_SPEED_EMA_ALPHA),
+        min_eta_speed_kmh: env_or("MIN_ETA_SPEED_KMH", DEFAULT_MIN_ETA_SPEED_KMH),
+        max_eta_speed_kmh: env_or("MAX_ETA_SPEED_KMH", DEFAULT_MAX_ETA_SPEED_KMH),
+        route_deviation_corridor_m: env_or(
+            "ROUTE_DEVIATION_CORRIDOR_M",
+            DEFAULT_ROUTE_DEVIATION_CORRIDOR_M,
+        ),
+        route_deviation_min_updates: env_or(
+            "ROUTE_DEVIATION_MIN_UPDATES",
+            DEFAULT_ROUTE_DEVIATION_MIN_UPDATES,
+        ),
+        arriving_soon_max_minutes: env_or(
+            "ARRIVING_SOON_MAX_MINUTES",
+            DEFAULT_ARRIVING_SOON_MAX_MINUTES,
+        ),
+        incoming_max_minutes: env_or("INCOMING_MAX_MINUTES", DEFAULT_INCOMING_MAX_MINUTES),
+    }
+}
+
+pub(crate) fn apply_thresholds_patch(
+    current: Thresholds,
+    patch: ThresholdsPatch,
+) -> Result<Thresholds, String> {
+    let updated = Thresholds {
+        max_derived_stop_distance_km: patch
+            .max_derived_stop_distance_km
+            .unwrap_or(current.max_derived_stop_distance_km),
+        stationary_speed_threshold_kmh: patch
+            .stationary_speed_threshold_kmh
+            .unwrap_or(current.stationary_speed_threshold_kmh),
+        stationary_distance_threshold_km: patch
+            .stationary_distance_threshold_km
+            .unwrap_or(current.stationary_distance_threshold_km),
+        stationary_window_ms: patch
+            .stationary_window_ms
+            .unwrap_or(current.stationary_window_ms),
+        default_speed_kmh: patch.default_speed_kmh.unwrap_or(current.default_speed_kmh),
+        speed_ema_alpha: patch.speed_ema_alpha.unwrap_or(current.speed_ema_alpha),
+        min_eta_speed_kmh: patch.min_eta_speed_kmh.unwrap_or(current.min_eta_speed_kmh),
+        max_eta_speed_kmh: patch.max_eta_speed_kmh.unwrap_or(current.max_eta_speed_kmh),
+        route_deviation_corridor_m: patch
+            .route_deviation_corridor_m
+            .unwrap_or(current.route_deviation_corridor_m),
+        route_deviation_min_updates: patch
+            .route_deviation_min_updates
+            .unwrap_or(current.route_deviation_min_updates),
+        arriving_soon_max_minutes: patch
+            .arriving_soon_max_minutes
+            .unwrap_or(current.arriving_soon_max_minutes),
+        incoming_max_minutes: patch
+            .incoming_max_minutes
+            .unwrap_or(current.incoming_max_minutes),
+    };
+
+    let positive_fields = [
+        (
+            "max_derived_stop_distance_km",
+            updated.max_derived_stop_distance_km,
+        ),
+        (
+            "stationary_speed_threshold_kmh",
+            updated.stationary_speed_threshold_kmh,
+        ),
+        (
+            "stationary_distance_threshold_km",
+            updated.stationary_distance_threshold_km,
+        ),
+        ("default_speed_kmh", updated.default_speed_kmh),
+        ("speed_ema_alpha", updated.speed_ema_alpha),
+        ("min_eta_speed_kmh", updated.min_eta_speed_kmh),
+        ("max_eta_speed_kmh", updated.max_eta_speed_kmh),
+        (
+            "route_deviation_corridor_m",
+            updated.route_deviation_corridor_m,
+        ),
+        (
+            "arriving_soon_max_minutes",
+            updated.arriving_soon_max_minutes,
+        ),
+        ("incoming_max_minutes", updated.incoming_max_minutes),
+    ];
+    for (name, value) in positive_fields {
+        if !value.is_finite() || value <= 0.0 {
+            return Err(format!("'{}' must be a positive number", name));
+        }
+    }
+    if updated.stationary_window_ms <= 0 {
+        return Err("'stationary_window_ms' must be a positive number".to_string());
+    }
+    if updated.route_deviation_min_updates == 0 {
+        return Err("'route_deviation_min_updates' must be at least 1".to_string());
+    }
+    if updated.speed_ema_alpha > 1.0 {
+        return Err("'speed_ema_alpha' must be at most 1".to_string());
+    }
+    if updated.min_eta_speed_kmh > updated.max_eta_speed_kmh {
+        return Err("'min_eta_speed_kmh' must not exceed 'max_eta_speed_kmh'".to_string());
+    }
+    if updated.arriving_soon_max_minutes > updated.incoming_max_minutes {
+        return Err(
+            "'arriving_soon_max_minutes' must not exceed 'incoming_max_minutes'".to_string(),
+        );
+    }
+
+    Ok(updated)
+}
+
+// Logs how each on-route bus was placed whenever the decision came from distances rather than
+// the feed. Repeats of the same fix on the same route are skipped, since every ETA request
+// would otherwise log an identical record.
+pub(crate) fn record_stop_resolutions(
+    log: &ResolutionLog,
+    buses: &[BusPosition],
+    route_id: &str,
+    route_stops: &RouteStopsResponse,
+    thresholds: &Thresholds,
+    now_ms: i64,
+) {
+    let Ok(mut by_bus) = log.by_bus.lock() else {
+        return;
+    };
+    for bus in buses
+        .iter()
+        .filter(|bus| is_bus_on_route(&bus.route, route_id))
+    {
+        let is_repeat = by_bus
+            .get(&bus.bus_no)
+            .and_then(|records| records.back())
+            .is_some_and(|last| {
+                last.route_id == route_id
+                    && last.latitude == bus.latitude
+                    && last.longitude == bus.longitude
+                    && last.max_distance_m
+                        == (thresholds.max_derived_stop_distance_km * 1000.0).round()
+            });
+        if is_repeat {
+            continue;
+        }
+        let (decision, resolved, candidates) =
+            trace_current_stop(bus, route_stops, thresholds, RESOLUTION_LOG_CANDIDATES);
+        if decision == StopResolutionDecision::Live {
+            continue;
+        }
+
+        if by_bus.len() >= RESOLUTION_LOG_MAX_BUSES && !by_bus.contains_key(&bus.bus_no) {
+            let stalest = by_bus
+                .iter()
+                .min_by_key(|(_, records)| records.back().map(|record| record.at_unix_ms))
+                .map(|(bus_no, _)| bus_no.clone());
+            if let Some(stalest) = stalest {
+                by_bus.remove(&stalest);
+            }
+        }
+        let records = by_bus.entry(bus.bus_no.clone()).or_default();
+        if records.len() >= RESOLUTION_LOG_CAPACITY {
+            records.pop_front();
+        }
+        records.push_back(StopResolutionRecord {
+            at_unix_ms: now_ms,
+            route_id: route_id.to_string(),
+            latitude: bus.latitude,
+            longitude: bus.longitude,
+            reported_stop_id: bus.busstop_id.clone().filter(|id| !id.is_empty()),
+            decision,
+            resolved_stop_id: resolved.map(|stop| stop.stop_id),
+            max_distance_m: (thresholds.max_derived_stop_distance_km * 1000.0).round(),
+            candidates,
+        });
+    }
+}
+
+// Visible buses grouped by normalized route code, the key is_bus_on_route compares on, so a
+// route's buses are one lookup away instead of a scan of the whole fleet.
+fn shard_buses_by_route(buses: &[BusPosition]) -> HashMap<String, Vec<BusPosition>> {
+    let mut buses_by_route: HashMap<String, Vec<BusPosition>> = HashMap::new();
+    for bus in buses {
+        let route = normalize_route_code(&bus.route);
+        if !route.is_empty() {
+            buses_by_route.entry(route).or_default().push(bus.clone());
+        }
+    }
+    buses_by_route
+}
+
+// A route's stops as the ETA engine sees them: the ones active detours skip are left out.
+pub(crate) fn eta_stops_by_route(
+    gtfs: &GtfsContext,
+    route_id: &str,
+) -> Result<Arc<RouteStopsResponse>, AppError> {
+    let route_stops = cached_stops_by_route(gtfs, route_id, None)?;
+    Ok(match gtfs.detours.get(route_id) {
+        Some(detours) => Arc::new(skip_detoured_stops(&route_stops, detours)),
+        None => route_stops,
+    })
+}
+
+// Lengthens ETAs computed on eta_stops_by_route to follow the detour path where one is given.
+pub(crate) fn apply_route_detours(
+    gtfs: &GtfsContext,
+    route_id: &str,
+    target_stop_id: &str,
+    eta_results: &mut [BusEta],
+) {
+    let Some(detours) = gtfs.detours.get(route_id) else {
+        return;
+    };
+    if let Ok(route_stops) = cached_stops_by_route(gtfs, route_id, None) {
+        apply_detour_geometry(eta_results, &route_stops, target_stop_id, detours);
+    }
+}
+
+// One notice per active detour on a route that calls at the stop, skipped or not.
+pub(crate) fn detour_notices(gtfs: &GtfsContext, stop_id: &str) -> Vec<DetourNotice> {
+    gtfs.route_ids_by_stop
+        .get(stop_id)
+        .into_iter()
+        .flatten()
+        .filter_map(|route_id| gtfs.detours.get(route_id))
+        .flatten()
+        .map(|detour| DetourNotice {
+            detour_id: detour.id.clone(),
+            route_id: detour.route_id.clone(),
+            message: detour.message.clone(),
+            stop_skipped: detour.skipped_stop_ids.iter().any(|id| id == stop_id),
+            valid_until_unix_ms: detour.valid_until_unix_ms,
+        })
+        .collect()
+}
+
+pub(crate) fn calculate_stop_eta_from_snapshot(
+    snapshot: &RedisBusSnapshot,
+    gtfs: &GtfsContext,
+    stop_id: &str,
+    thresholds: &Thresholds,
+    bus_ttl_ms: i64,
+    runtime_profiles: &HashMap<String, RouteRuntimesResponse>,
+    resolution_log: &ResolutionLog,
+) -> Vec<BusEta> {
+    let _timer = StageTimer::start(Stage::EtaCompute);
+    let visible_buses = filter_non_stationary_buses(
+        &snapshot.buses,
+        &snapshot.motion_states,
+        snapshot.captured_at_unix_ms,
+        thresholds,
+    );
+    let buses_by_route = shard_buses_by_route(&visible_buses);
+    let mut all_eta_results: Vec<BusEta> = Vec::new();
+    let mut seen_bus_route: HashSet<String> = HashSet::new();
+
+    // Only routes that call at the stop, and of those only the ones with buses out.
+    let serving_route_ids = gtfs.route_ids_by_stop.get(stop_id).into_iter().flatten();
+    for route_id in serving_route_ids {
+        let Some(route_buses) = buses_by_route.get(&normalize_route_code(route_id)) else {
+            continue;
+        };
+        let route_stops = match eta_stops_by_route(gtfs, route_id) {
+            Ok(stops) => stops,
+            Err(_) => continue,
+        };
+
+        record_stop_resolutions(
+            resolution_log,
+            route_buses,
+            route_id,
+            &route_stops,
+            thresholds,
+            snapshot.captured_at_unix_ms,
+        );
+        let mut route_eta_results = match calculate_route_eta_from_stops(
+            route_buses,
+            &snapshot.motion_states,
+            route_id,
+            stop_id,
+            &route_stops,
+            gtfs.route_geometries.get(&normalize_route_code(route_id)),
+            thresholds,
+            snapshot.captured_at_unix_ms,
+        ) {
+            Ok(results) => results,
+            Err(_) => continue,
+        };
+        if let Some(profile) = runtime_profiles.get(route_id) {
+            apply_runtime_profile(
+                &mut route_eta_results,
+                &route_stops,
+                stop_id,
+                &segment_runtimes_for_hour(profile, kl_hour_of_day(snapshot.captured_at_unix_ms)),
+                thresholds,
+            );
+        }
+        apply_route_detours(gtfs, route_id, stop_id, &mut route_eta_results);
+
+        for eta in route_eta_results {
+            let key = format!("{}::{}", eta.route_id, eta.bus_no);
+            if seen_bus_route.insert(key) {
+                all_eta_results.push(eta);
+            }
+        }
+    }
+
+    apply_data_age(
+        &mut all_eta_results,
+        &snapshot.last_seen_unix_ms,
+        snapshot.captured_at_unix_ms,
+        bus_ttl_ms,
+    );
+
+    all_eta_results
+}
+
+// ETAs at stop_id for buses standing at the first stop of a route that calls there, which
+// calculate_stop_eta_from_snapshot leaves out as stationary. Buses leave in the order they
+// arrived: the first takes the next scheduled trip start, the next the one after, and so on.
+// Without a timetable a bus is expected to leave once the usual layover there has passed.
+pub(crate) fn terminus_departure_etas(
+    snapshot: &RedisBusSnapshot,
+    gtfs: &GtfsContext,
+    stop_id: &str,
+    thresholds: &Thresholds,
+    bus_ttl_ms: i64,
+    terminus_schedules: &HashMap<String, TerminusSchedule>,
+) -> Vec<BusEta> {
+    let now_ms = snapshot.captured_at_unix_ms;
+    let mut all_eta_results: Vec<BusEta> = Vec::new();
+    let mut seen_bus_route: HashSet<String> = HashSet::new();
+
+    let serving_route_ids = gtfs.route_ids_by_stop.get(stop_id).into_iter().flatten();
+    for route_id in serving_route_ids {
+        let Some(schedule) = terminus_schedules.get(route_id) else {
+            continue;
+        };
+        if schedule.first_stop_id == stop_id {
+            continue;
+        }
+        let Ok(route_stops) = eta_stops_by_route(gtfs, route_id) else {
+            continue;
+        };
+        let Some(first_stop) = route_stops
+            .stops
+            .first()
+            .filter(|stop| stop.stop_id == schedule.first_stop_id)
+        else {
+            continue;
+        };
+
+        let mut waiting: Vec<(BusPosition, i64)> = snapshot
+            .buses
+            .iter()
+            .filter(|bus| !bus.in_depot && !bus.off_route)
+            .filter(|bus| is_bus_on_route(&bus.route, route_id))
+            .filter(|bus| {
+                is_bus_stationary(&snapshot.motion_states, &bus.bus_no, now_ms, thresholds)
+            })
+            .filter(|bus| {
+                resolve_current_stop(bus, &route_stops, thresholds)
+                    .is_some_and(|stop| stop.sequence == first_stop.sequence)
+            })
+            .filter_map(|bus| {
+                let since_ms = snapshot
+                    .motion_states
+                    .get(&bus.bus_no)?
+                    .stationary_since_unix_ms?;
+                Some((bus.clone(), since_ms))
+            })
+            .collect();
+        if waiting.is_empty() {
+            continue;
+        }
+        waiting.sort_by(|a, b| (a.1, &a.0.bus_no).cmp(&(b.1, &b.0.bus_no)));
+
+        let buses: Vec<BusPosition> = waiting.iter().map(|(bus, _)| bus.clone()).collect();
+        let Ok(travel_etas) = calculate_route_eta_from_stops(
+            &buses,
+            &snapshot.motion_states,
+            route_id,
+            stop_id,
+            &route_stops,
+            gtfs.route_geometries.get(&normalize_route_code(route_id)),
+            thresholds,
+            now_ms,
+        ) else {
+            continue;
+        };
+
+        let mut departures = schedule
+            .departures_unix_ms
+            .iter()
+            .filter(|departure_ms| **departure_ms >= now_ms);
+        for (bus, stationary_since_ms) in &waiting {
+            let Some(mut eta) = travel_etas
+                .iter()
+                .find(|eta| eta.bus_no == bus.bus_no)
+                .cloned()
+            else {
+                continue;
+            };
+            let (departure_ms, source) = match departures.next() {
+                Some(departure_ms) => (*departure_ms, "terminus_schedule"),
+                None => match schedule.mean_layover_ms {
+                    Some(layover_ms) => (stationary_since_ms + layover_ms, "terminus_layover"),
+                    None => continue,
+                },
+            };
+            let wait_ms = (departure_ms - now_ms).max(MIN_TERMINUS_WAIT_MS);
+            if wait_ms > MAX_TERMINUS_WAIT_MS {
+                continue;
+            }
+            let wait_minutes = wait_ms as f64 / 60_000.0;
+            eta.eta_minutes = ((eta.eta_minutes + wait_minutes) * 10.0).round() / 10.0;
+            eta.departs_terminus_in_minutes = Some((wait_minutes * 10.0).round() / 10.0);
+            eta.eta_source = Some(source.to_string());
+            if seen_bus_route.insert(format!("{}::{}", eta.route_id, eta.bus_no)) {
+                all_eta_results.push(eta);
+            }
+        }
+    }
+
+    apply_data_age(
+        &mut all_eta_results,
+        &snapshot.last_seen_unix_ms,
+        now_ms,
+        bus_ttl_ms,
+    );
+
+    all_eta_results
+}
+
+// The trip_started event for a bus whose enrichment says it just left its terminus, with the
+// speed-based arrival at each stop ahead. None when the route's stops can't be resolved.
+pub(crate) fn trip_started_event(
+    gtfs: &GtfsContext,
+    bus: &BusPosition,
+    enrichment: &BusEnrichment,
+    thresholds: &Thresholds,
+    now_ms: i64,
+) -> Option<TripStartedEvent> {
+    let route_id = enrichment.runtime_route_id.as_ref()?;
+    let chainage = enrichment.motion_state.chainage.as_ref()?;
+    let route_stops = eta_stops_by_route(gtfs, route_id).ok()?;
+    let terminus = route_stops.stops.first()?;
+    let stops_ahead = &route_stops.stops[1..];
+    let target_stop_ids: Vec<&str> = stops_ahead
+        .iter()
+        .map(|stop| stop.stop_id.as_str())
+        .collect();
+    let motion_states = HashMap::from([(bus.bus_no.clone(), enrichment.motion_state.clone())]);
+    let etas = calculate_route_eta_for_stops(
+        std::slice::from_ref(bus),
+        &motion_states,
+        route_id,
+        &target_stop_ids,
+        &route_stops,
+        gtfs.route_geometries.get(&normalize_route_code(route_id)),
+        thresholds,
+        now_ms,
+    )
+    .ok()?;
+
+    let timeline = stops_ahead
+        .iter()
+        .zip(etas)
+        .filter_map(|(stop, stop_etas)| {
+            let eta = stop_etas.into_iter().next()?;
+            Some(TripTimelineStop {
+                stop_id: stop.stop_id.clone(),
+                stop_name: stop.stop_name.clone(),
+                sequence: stop.sequence,
+                eta_minutes: eta.eta_minutes,
+                predicted_arrival_unix_ms: eta
+                    .predicted_arrival_unix_ms
+                    .unwrap_or_else(|| predicted_arrival_unix_ms(now_ms, eta.eta_minutes)),
+            })
+        })
+        .collect();
+    Some(TripStartedEvent {
+        bus_no: bus.bus_no.clone(),
+        route_id: route_id.clone(),
+        direction: bus
+            .trip
+            .as_ref()
+            .map(|trip| trip.direction)
+            .unwrap_or(TripDirection::Unknown),
+        trip_no: bus.trip.as_ref().and_then(|trip| trip.trip_no.clone()),
+        shape_id: chainage.shape_id.clone(),
+        terminus_stop_id: terminus.stop_id.clone(),
+        started_at_unix_ms: now_ms,
+        timeline,
+    })
+}
+
+// eta_minutes each bus would have had with runtime profiles applied, keyed by (route_id, bus_no),
+// for ETAs that were computed without them.
+pub(crate) fn runtime_profile_eta_minutes(
+    etas: &[BusEta],
+    gtfs: &GtfsContext,
+    stop_id: &str,
+    runtime_profiles: &HashMap<String, RouteRuntimesResponse>,
+    thresholds: &Thresholds,
+    now_ms: i64,
+) -> HashMap<(String, String), f64> {
+    let mut etas_by_route: HashMap<&str, Vec<BusEta>> = HashMap::new();
+    for eta in etas {
+        etas_by_route
+            .entry(eta.route_id.as_str())
+            .or_default()
+            .push(eta.clone());
+    }
+
+    let hour = kl_hour_of_day(now_ms);
+    let mut minutes = HashMap::new();
+    for (route_id, mut route_etas) in etas_by_route {
+        if let (Some(profile), Ok(route_stops)) = (
+            runtime_profiles.get(route_id),
+            eta_stops_by_route(gtfs, route_id),
+        ) {
+            apply_runtime_profile(
+                &mut route_etas,
+                &route_stops,
+                stop_id,
+                &segment_runtimes_for_hour(profile, hour),
+                thresholds,
+            );
+        }
+        for eta in route_etas {
+            minutes.insert((eta.route_id, eta.bus_no), eta.eta_minutes);
+        }
+    }
+    minutes
+}
+
+// Only the first prediction per bus and stop is kept, so accuracy reflects how early the variants
+// got the arrival right rather than the last few seconds before it.
+pub(crate) fn record_shadow_predictions(
+    shadow: &ShadowEvaluation,
+    flag: &str,
+    stop_id: &str,
+    served: &[BusEta],
+    shadow_eta_minutes: &HashMap<(String, String), f64>,
+    now_ms: i64,
+) {
+    let (Ok(mut pending), Ok(mut stats)) = (shadow.pending.lock(), shadow.stats.lock()) else {
+        return;
+    };
+    let flag_stats = stats.entry(flag.to_string()).or_default();
+    if pending.len() >= MAX_PENDING_SHADOW_PREDICTIONS {
+        pending.retain(|_, prediction| {
+            now_ms - prediction.predicted_at_unix_ms <= SHADOW_PREDICTION_MAX_AGE_MS
+        });
+    }
+
+    for eta in served {
+        let Some(shadow_minutes) =
+            shadow_eta_minutes.get(&(eta.route_id.clone(), eta.bus_no.clone()))
+        else {
+            continue;
+        };
+        flag_stats.comparisons += 1;
+        flag_stats.divergence_minutes_sum += (shadow_minutes - eta.eta_minutes).abs();
+        if pending.len() >= MAX_PENDING_SHADOW_PREDICTIONS {
+            continue;
+        }
+        pending
+            .entry((
+                stop_id.to_string(),
+                format!("{}|{}", eta.route_id, eta.bus_no),
+            ))
+            .or_insert_with(|| ShadowPrediction {
+                flag: flag.to_string(),
+                predicted_at_unix_ms: now_ms,
+                served_arrival_unix_ms: predicted_arrival_unix_ms(now_ms, eta.eta_minutes),
+                shadow_arrival_unix_ms: predicted_arrival_unix_ms(now_ms, *shadow_minutes),
+            });
+    }
+}
+
+// A departure logged after the prediction was made settles it: each variant is charged its
+// absolute error against the time the bus actually left the stop.
+pub(crate) fn score_shadow_predictions(
+    shadow: &ShadowEvaluation,
+    stop_id: &str,
+    departures: &[RecentDeparture],
+) {
+    let (Ok(mut pending), Ok(mut stats)) = (shadow.pending.lock(), shadow.stats.lock()) else {
+        return;
+    };
+    for departure in departures {
+        let key = (
+            stop_id.to_string(),
+            format!("{}|{}", departure.route_id, departure.bus_no),
+        );
+        let Some(prediction) = pending.get(&key) else {
+            continue;
+        };
+        if departure.departed_at_unix_ms <= prediction.predicted_at_unix_ms {
+            continue;
+        }
+        let error_minutes =
+            |arrival_ms: i64| (arrival_ms - departure.departed_at_unix_ms).abs() as f64 / 60_000.0;
+        let flag_stats = stats.entry(prediction.flag.clone()).or_default();
+        flag_stats.scored += 1;
+        flag_stats.served_error_minutes_sum += error_minutes(prediction.served_arrival_unix_ms);
+        flag_stats.shadow_error_minutes_sum += error_minutes(prediction.shadow_arrival_unix_ms);
+        pending.remove(&key);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::test_support::test_bus;
+
+    fn patch(fields: serde_json::Value) -> ThresholdsPatch {
+        serde_json::from_value(fields).unwrap()
+    }
+
+    #[test]
+    fn thresholds_patch_overrides_only_the_fields_it_sets() {
+        let current = thresholds_from_env();
+        let updated = apply_thresholds_patch(
+            current,
+            patch(json!({ "stationary_window_ms": 90_000, "incoming_max_minutes": 45.0 })),
+        )
+        .unwrap();
+        assert_eq!(updated.stationary_window_ms, 90_000);
+        assert_eq!(updated.incoming_max_minutes, 45.0);
+        assert_eq!(updated.speed_ema_alpha, current.speed_ema_alpha);
+        assert_eq!(
+            updated.max_derived_stop_distance_km,
+            current.max_derived_stop_distance_km
+        );
+
+        let unchanged = apply_thresholds_patch(current, patch(json!({}))).unwrap();
+        assert_eq!(
+            serde_json::to_value(unchanged).unwrap(),
+            serde_json::to_value(current).unwrap()
+        );
+    }
+
+    #[test]
+    fn thresholds_patch_rejects_values_the_engine_cannot_use() {
+        let rejected = [
+            (json!({ "default_speed_kmh": 0.0 }), "'default_speed_kmh'"),
+            (
+                json!({ "route_deviation_corridor_m": -5.0 }),
+                "'route_deviation_corridor_m'",
+            ),
+            (
+                json!({ "stationary_window_ms": 0 }),
+                "'stationary_window_ms'",
+            ),
+            (
+                json!({ "route_deviation_min_updates": 0 }),
+                "'route_deviation_min_updates'",
+            ),
+            (json!({ "speed_ema_alpha": 1.5 }), "'speed_ema_alpha'"),
+            (
+                json!({ "min_eta_speed_kmh": 60.0, "max_eta_speed_kmh": 40.0 }),
+                "'min_eta_speed_kmh'",
+            ),
+            (
+                json!({ "arriving_soon_max_minutes": 20.0, "incoming_max_minutes": 10.0 }),
+                "'arriving_soon_max_minutes'",
+            ),
+        ];
+        for (fields, named) in rejected {
+            let error = apply_thresholds_patch(thresholds_from_env(), patch(fields.clone()))
+                .expect_err(&fields.to_string());
+            assert!(error.starts_with(named), "{}: {}", fields, error);
+        }
+    }
+
+    #[test]
+    fn shard_buses_by_route_groups_on_the_normalized_route() {
+        let mut unrouted = test_bus("WXY3", 3.1, 101.7, 20.0);
+        unrouted.route = " ".to_string();
+        let mut t7890 = test_bus("WXY2", 3.1, 101.7, 20.0);
+        t7890.route = "t7890".to_string();
+        let buses = vec![test_bus("WXY1", 3.1, 101.7, 20.0), t7890, unrouted];
+
+        let sharded = shard_buses_by_route(&buses);
+        assert_eq!(sharded.len(), 1);
+        let bus_nos: Vec<&str> = sharded["T789"]
+            .iter()
+            .map(|bus| bus.bus_no.as_str())
+            .collect();
+        assert_eq!(bus_nos, vec!["WXY1", "WXY2"]);
+    }
+}
@@ -0,0 +1,2479 @@
+// Static GTFS feed loading plus the route, stop and schedule lookups built on it.
+
+use chrono::{Datelike, FixedOffset, NaiveDate, Timelike, Weekday};
+use rapidbro_eta::{
+    haversine_distance, is_bus_on_route, normalize_route_code, project_onto_shape, RouteGeometry,
+    KL_UTC_OFFSET_SECONDS,
+};
+use rapidbro_types::{
+    GtfsFileValidation, GtfsIssueKind, GtfsValidationIssue, GtfsValidationReport, NearbyStop,
+    NearestStopResponse, RouteDisplay, RouteShapePoint, RouteShapeResponse, RouteStopsResponse,
+    ScheduledHeadway, StopClosure, StopRouteSummary, StopWithDetails, TripDirection,
+};
+use serde::de::DeserializeOwned;
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::fs::File;
+use std::path::{Path as StdPath, PathBuf};
+use std::sync::{atomic::Ordering as AtomicOrdering, Arc};
+use std::time::Duration;
+use tokio::time::MissedTickBehavior;
+
+use crate::api::{AppError, Stage, StageTimer};
+use crate::ingest::{DEFAULT_GTFS_RT_VEHICLE_POSITIONS_URL, SOCKET_URL};
+use crate::models::{
+    CityConfig, ExtraGtfsFeed, Frequency, GtfsContext, GtfsFeed, GtfsTables, GtfsValidation, Route,
+    RouteStopsKey, ServiceCalendar, ServiceCalendarDate, ServiceCalendars, ShapePoint, Stop,
+    StopTime, Trip,
+};
+use crate::store::{active_detours, kl_date, kl_day_bounds_ms, DEFAULT_REDIS_KEY_PREFIX};
+use crate::{env_or, AppState, DEFAULT_REDIS_URL};
+
+const GTFS_DATA_PATH: &str = "../rapid_kl_data";
+pub(crate) const DEFAULT_CITY_ID: &str = "kl";
+const DEFAULT_CITY_NAME: &str = "Kuala Lumpur";
+pub(crate) const DEFAULT_PROVIDER: &str = "RKL";
+const MAX_CITY_ID_LENGTH: usize = 32;
+const ROUTE_STOPS_CACHE_CAPACITY: usize = 512;
+const DEFAULT_GTFS_RELOAD_CHECK_SECONDS: u64 = 30;
+const DEFAULT_GTFS_STATIC_URL: &str =
+    "https://api.data.gov.my/gtfs-static/prasarana?category=rapid-bus-kl";
+const DEFAULT_GTFS_STATIC_REFRESH_HOURS: u64 = 24;
+const GTFS_STATIC_REQUEST_TIMEOUT_SECONDS: u64 = 120;
+pub(crate) const DEFAULT_MAX_DERIVED_STOP_DISTANCE_KM: f64 = 0.75;
+// Issues kept per file in the validation report; the count still covers every one.
+const MAX_GTFS_ISSUES_PER_FILE: usize = 50;
+// Failure reading a local data file: GTFS tables, geofences, rosters, fixtures.
+#[derive(Debug, thiserror::Error)]
+pub(crate) enum LoadError {
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error(transparent)]
+    Csv(#[from] csv::Error),
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+    #[error("{0}")]
+    Invalid(String),
+}
+
+impl From<String> for LoadError {
+    fn from(message: String) -> Self {
+        LoadError::Invalid(message)
+    }
+}
+
+impl From<&str> for LoadError {
+    fn from(message: &str) -> Self {
+        LoadError::Invalid(message.to_string())
+    }
+}
+
+// The static feed has no feed_info.txt, so identify it by its newest file modification time.
+pub(crate) fn gtfs_feed_version(data_dir: &StdPath) -> String {
+    let newest_modified = std::fs::read_dir(data_dir)
+        .into_iter()
+        .flatten()
+        .flatten()
+        .filter_map(|entry| entry.metadata().ok()?.modified().ok())
+        .max();
+
+    match newest_modified {
+        Some(modified) => format!(
+            "{} (modified {})",
+            data_dir.display(),
+            chrono::DateTime::<chrono::Utc>::from(modified).to_rfc3339()
+        ),
+        None => format!("{} (unavailable)", data_dir.display()),
+    }
+}
+
+pub(crate) fn is_t789_route(route: &str) -> bool {
+    normalize_route_code(route) == "T789"
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct ScheduledDeparture {
+    pub(crate) trip_id: String,
+    pub(crate) route_short_name: String,
+    pub(crate) headsign: String,
+    pub(crate) service_date: NaiveDate,
+    pub(crate) departure_secs: i64,
+}
+
+// Frequency-based trips repeat the stop_times pattern every headway within each window;
+// any other trip starts once, at its first stop's departure.
+fn trip_start_secs(
+    trip_id: &str,
+    first_secs: i64,
+    frequencies_by_trip: &HashMap<String, Vec<Frequency>>,
+) -> Vec<i64> {
+    let Some(frequencies) = frequencies_by_trip.get(trip_id) else {
+        return vec![first_secs];
+    };
+    let mut starts = Vec::new();
+    for frequency in frequencies {
+        let (Some(start), Some(end)) = (
+            parse_gtfs_time(&frequency.start_time),
+            parse_gtfs_time(&frequency.end_time),
+        ) else {
+            continue;
+        };
+        let headway = i64::from(frequency.headway_secs.max(60));
+        let mut trip_start = start;
+        while trip_start < end {
+            starts.push(trip_start);
+            trip_start += headway;
+        }
+    }
+    starts
+}
+
+// The route's frequencies.txt windows on one service date, once per direction and window,
+// ordered by start. Windows of trips whose service doesn't run that day are left out.
+pub(crate) fn route_headways(
+    route_id: &str,
+    gtfs: &GtfsContext,
+    service_date: NaiveDate,
+) -> Vec<ScheduledHeadway> {
+    let mut seen: HashSet<(Option<u32>, String, String, u32)> = HashSet::new();
+    let mut headways: Vec<(i64, ScheduledHeadway)> = Vec::new();
+    for trip in gtfs.trips_by_route.get(route_id).into_iter().flatten() {
+        let Some(frequencies) = gtfs.frequencies_by_trip.get(&trip.trip_id) else {
+            continue;
+        };
+        if !is_service_active(&gtfs.calendars, &trip.service_id, service_date) {
+            continue;
+        }
+        for frequency in frequencies {
+            let Some(start_secs) = parse_gtfs_time(&frequency.start_time) else {
+                continue;
+            };
+            if !seen.insert((
+                trip.direction_id,
+                frequency.start_time.clone(),
+                frequency.end_time.clone(),
+                frequency.headway_secs,
+            )) {
+                continue;
+            }
+            headways.push((
+                start_secs,
+                ScheduledHeadway {
+                    route_id: route_id.to_string(),
+                    direction_id: trip.direction_id,
+                    start_time: frequency.start_time.clone(),
+                    end_time: frequency.end_time.clone(),
+                    headway_minutes: (f64::from(frequency.headway_secs) / 60.0 * 10.0).round()
+                        / 10.0,
+                },
+            ));
+        }
+    }
+    headways.sort_by_key(|(start_secs, headway)| (*start_secs, headway.direction_id));
+    headways.into_iter().map(|(_, headway)| headway).collect()
+}
+
+// The window in effect at now_ms, the most frequent one where windows overlap. Windows past
+// 24:00:00 on yesterday's service day still count, as the trips in them run tonight.
+pub(crate) fn current_route_headway(
+    route_id: &str,
+    gtfs: &GtfsContext,
+    now_ms: i64,
+) -> Option<ScheduledHeadway> {
+    let today = kl_date(now_ms)?;
+    [Some(today), today.pred_opt()]
+        .into_iter()
+        .flatten()
+        .filter_map(|service_date| {
+            let (day_start_ms, _) = kl_day_bounds_ms(service_date)?;
+            let secs = (now_ms - day_start_ms) / 1_000;
+            Some((service_date, secs))
+        })
+        .flat_map(|(service_date, secs)| {
+            route_headways(route_id, gtfs, service_date)
+                .into_iter()
+                .filter(move |headway| {
+                    matches!(
+                        (parse_gtfs_time(&headway.start_time), parse_gtfs_time(&headway.end_time)),
+                        (Some(start), Some(end)) if start <= secs && secs < end
+                    )
+                })
+        })
+        .min_by(|a, b| {
+            a.headway_minutes
+                .partial_cmp(&b.headway_minutes)
+                .unwrap_or(std::cmp::Ordering::Equal)
+        })
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct ScheduledTripStart {
+    pub(crate) trip_id: String,
+    pub(crate) shape_id: String,
+    pub(crate) headsign: Option<String>,
+    pub(crate) direction_id: Option<u32>,
+    // Seconds after midnight of the service day; may pass 24h for trips after midnight.
+    pub(crate) start_secs: i64,
+}
+
+// Every trip start of the route on one service date, in start order.
+pub(crate) fn scheduled_trip_starts_for_route(
+    route_id: &str,
+    gtfs: &GtfsContext,
+    calendars: &ServiceCalendars,
+    frequencies_by_trip: &HashMap<String, Vec<Frequency>>,
+    service_date: NaiveDate,
+) -> Vec<ScheduledTripStart> {
+    let mut starts = Vec::new();
+    for trip in gtfs.trips_by_route.get(route_id).into_iter().flatten() {
+        if !is_service_active(calendars, &trip.service_id, service_date) {
+            continue;
+        }
+        let Some(first_secs) = gtfs
+            .stop_times_by_trip
+            .get(&trip.trip_id)
+            .and_then(|stop_times| stop_times.iter().min_by_key(|st| st.stop_sequence))
+            .and_then(|first_stop_time| parse_gtfs_time(&first_stop_time.departure_time))
+        else {
+            continue;
+        };
+        for start_secs in trip_start_secs(&trip.trip_id, first_secs, frequencies_by_trip) {
+            starts.push(ScheduledTripStart {
+                trip_id: trip.trip_id.clone(),
+                shape_id: trip.shape_id.clone(),
+                headsign: trip
+                    .trip_headsign
+                    .clone()
+                    .filter(|headsign| !headsign.trim().is_empty()),
+                direction_id: trip.direction_id,
+                start_secs,
+            });
+        }
+    }
+    starts.sort_by(|a, b| (a.start_secs, &a.trip_id).cmp(&(b.start_secs, &b.trip_id)));
+    starts
+}
+
+pub(crate) fn scheduled_departures_for_stop(
+    stop_id: &str,
+    route_filter: Option<&str>,
+    gtfs: &GtfsContext,
+    calendars: &ServiceCalendars,
+    frequencies_by_trip: &HashMap<String, Vec<Frequency>>,
+    service_dates: &[NaiveDate],
+) -> Vec<ScheduledDeparture> {
+    let mut departures = Vec::new();
+
+    for route in &gtfs.routes {
+        if let Some(route_filter) = route_filter {
+            if !is_bus_on_route(&route.route_id, route_filter) {
+                continue;
+            }
+        }
+        let Some(trips) = gtfs.trips_by_route.get(&route.route_id) else {
+            continue;
+        };
+
+        for trip in trips {
+            let Some(stop_times) = gtfs.stop_times_by_trip.get(&trip.trip_id) else {
+                continue;
+            };
+            let Some(stop_time) = stop_times.iter().find(|st| st.stop_id == stop_id) else {
+                continue;
+            };
+            let Some(first_stop_time) = stop_times.iter().min_by_key(|st| st.stop_sequence) else {
+                continue;
+            };
+            let (Some(stop_secs), Some(first_secs)) = (
+                parse_gtfs_time(&stop_time.departure_time),
+                parse_gtfs_time(&first_stop_time.departure_time),
+            ) else {
+                continue;
+            };
+
+            // The stop keeps its offset from the first stop of the template trip.
+            let departure_secs: Vec<i64> =
+                trip_start_secs(&trip.trip_id, first_secs, frequencies_by_trip)
+                    .into_iter()
+                    .map(|trip_start| trip_start + stop_secs - first_secs)
+                    .collect();
+
+            for service_date in service_dates {
+                if !is_service_active(calendars, &trip.service_id, *service_date) {
+                    continue;
+                }
+                for secs in &departure_secs {
+                    departures.push(ScheduledDeparture {
+                        trip_id: trip.trip_id.clone(),
+                        route_short_name: route.route_short_name.clone(),
+                        headsign: trip
+                            .trip_headsign
+                            .clone()
+                            .filter(|headsign| !headsign.trim().is_empty())
+                            .unwrap_or_else(|| route.route_long_name.clone()),
+                        service_date: *service_date,
+                        departure_secs: *secs,
+                    });
+                }
+            }
+        }
+    }
+
+    departures.sort_by_key(|departure| (departure.service_date, departure.departure_secs));
+    departures
+}
+
+// An exception for the date wins outright; otherwise the regular weekly pattern applies.
+pub(crate) fn is_service_active(
+    calendars: &ServiceCalendars,
+    service_id: &str,
+    date: NaiveDate,
+) -> bool {
+    let date_key = date.format("%Y%m%d").to_string();
+    match calendars
+        .exceptions
+        .get(&(service_id.to_string(), date_key))
+        .copied()
+    {
+        Some(1) => true,
+        Some(2) => false,
+        _ => is_regular_service_active(&calendars.calendars, service_id, date),
+    }
+}
+
+// What calendar.txt alone says, ignoring calendar_dates.txt exceptions.
+pub(crate) fn is_regular_service_active(
+    calendars: &[ServiceCalendar],
+    service_id: &str,
+    date: NaiveDate,
+) -> bool {
+    let date_key = date.format("%Y%m%d").to_string();
+    calendars.iter().any(|calendar| {
+        let runs_on_weekday = match date.weekday() {
+            Weekday::Mon => calendar.monday,
+            Weekday::Tue => calendar.tuesday,
+            Weekday::Wed => calendar.wednesday,
+            Weekday::Thu => calendar.thursday,
+            Weekday::Fri => calendar.friday,
+            Weekday::Sat => calendar.saturday,
+            Weekday::Sun => calendar.sunday,
+        } == 1;
+        // YYYYMMDD strings compare in date order.
+        calendar.service_id == service_id
+            && runs_on_weekday
+            && calendar.start_date.as_str() <= date_key.as_str()
+            && date_key.as_str() <= calendar.end_date.as_str()
+    })
+}
+
+// GTFS times are seconds past midnight of the service day and may run past 24:00:00.
+fn parse_gtfs_time(value: &str) -> Option<i64> {
+    let mut parts = value.trim().split(':');
+    let hours = parts.next()?.parse::<i64>().ok()?;
+    let minutes = parts.next()?.parse::<i64>().ok()?;
+    let seconds = parts.next()?.parse::<i64>().ok()?;
+    Some(hours * 3_600 + minutes * 60 + seconds)
+}
+
+pub(crate) fn load_gtfs_context(state: &AppState) -> GtfsContext {
+    let _timer = StageTimer::start(Stage::Gtfs);
+    GtfsContext {
+        feed: current_gtfs_feed(state),
+        route_stops_cache: state.route_stops_cache.clone(),
+        detours: active_detours(state, state.clock.now_ms()),
+        service_date: kl_date(state.clock.now_ms()),
+    }
+}
+
+// The feed as of now. Callers keep the Arc for the whole request, so a reload swapping in a new
+// feed midway never mixes tables from the two.
+pub(crate) fn current_gtfs_feed(state: &AppState) -> Arc<GtfsFeed> {
+    match state.gtfs.read() {
+        Ok(feed) => feed.clone(),
+        Err(poisoned) => poisoned.into_inner().clone(),
+    }
+}
+
+// Parses one feed directory, at startup and on every reload. Missing shapes only cost chainage
+// tracking and route geometry; the other tables are required.
+fn load_gtfs_feed(data_dir: &StdPath) -> Result<GtfsFeed, LoadError> {
+    // Taken before reading, so files replaced during the load leave a newer version behind and
+    // the reloader comes back for them.
+    let feed_version = gtfs_feed_version(data_dir);
+    Ok(build_gtfs_feed(load_gtfs_tables(data_dir)?, feed_version))
+}
+
+// The city's own feed with each of its extra_gtfs_feeds merged in. A city without extra feeds
+// loads exactly as load_gtfs_feed does.
+pub(crate) fn load_city_gtfs_feed(city: &CityConfig) -> Result<GtfsFeed, LoadError> {
+    let feed_version = city_gtfs_feed_version(city);
+    let mut tables = load_gtfs_tables(&city.gtfs_data_path)?;
+    for extra_feed in &city.extra_gtfs_feeds {
+        let extra_tables = load_gtfs_tables(&extra_feed.gtfs_data_path).map_err(|error| {
+            LoadError::Invalid(format!(
+                "feed '{}' in {}: {}",
+                extra_feed.provider,
+                extra_feed.gtfs_data_path.display(),
+                error
+            ))
+        })?;
+        merge_gtfs_tables(
+            &mut tables,
+            prefix_gtfs_ids(extra_tables, &extra_feed.provider),
+        );
+    }
+    Ok(build_gtfs_feed(tables, feed_version))
+}
+
+// Changes when any of the city's feed directories does.
+pub(crate) fn city_gtfs_feed_version(city: &CityConfig) -> String {
+    std::iter::once(gtfs_feed_version(&city.gtfs_data_path))
+        .chain(
+            city.extra_gtfs_feeds
+                .iter()
+                .map(|extra_feed| gtfs_feed_version(&extra_feed.gtfs_data_path)),
+        )
+        .collect::<Vec<_>>()
+        .join(" + ")
+}
+
+// Bad rows are left out and recorded in the tables' validation rather than failing the load.
+fn load_gtfs_tables(data_dir: &StdPath) -> Result<GtfsTables, LoadError> {
+    let mut validation = GtfsValidation::default();
+    let routes = load_routes(data_dir, &mut validation)?;
+    let trips_by_route = load_trips(data_dir, &mut validation)?;
+    let mut stop_times_by_trip = load_stop_times(data_dir, &mut validation)?;
+    let stops_map = load_stops(data_dir, &mut validation)?;
+    if routes.is_empty() || stops_map.is_empty() {
+        return Err(LoadError::Invalid(
+            "feed has no routes or stops".to_string(),
+        ));
+    }
+    // A stop sequence can't place a stop that didn't load, so its stop times go too.
+    for stop_times in stop_times_by_trip.values_mut() {
+        stop_times.retain(|stop_time| {
+            let known_stop = stops_map.contains_key(&stop_time.stop_id);
+            if !known_stop {
+                validation.record(
+                    "stop_times.txt",
+                    None,
+                    GtfsIssueKind::MissingReference,
+                    Some(&stop_time.stop_id),
+                    format!(
+                        "trip {} stops at a stop_id missing from stops.txt",
+                        stop_time.trip_id
+                    ),
+                );
+            }
+            known_stop
+        });
+    }
+    let shapes_by_id = load_shapes(data_dir, &mut validation).unwrap_or_else(|error| {
+        println!(
+            "Failed to load route shapes, chainage tracking disabled: {}",
+            error
+        );
+        HashMap::new()
+    });
+    let calendars = load_calendar(data_dir, &mut validation).unwrap_or_else(|error| {
+        println!(
+            "Failed to load service calendar, route stops follow each route's first trip: {}",
+            error
+        );
+        ServiceCalendars::default()
+    });
+    let frequencies_by_trip = load_frequencies(data_dir, &mut validation).unwrap_or_else(|error| {
+        println!(
+            "Failed to load frequencies, headway-based trips run once each: {}",
+            error
+        );
+        HashMap::new()
+    });
+    Ok(GtfsTables {
+        routes,
+        trips_by_route,
+        stop_times_by_trip,
+        stops_map,
+        shapes_by_id,
+        calendars,
+        frequencies_by_trip,
+        validation,
+    })
+}
+
+impl GtfsValidation {
+    fn record(
+        &mut self,
+        file_name: &str,
+        line: Option<u64>,
+        kind: GtfsIssueKind,
+        id: Option<&str>,
+        message: String,
+    ) {
+        let file = self
+            .files
+            .entry(file_name.to_string())
+            .or_insert_with(|| GtfsFileValidation {
+                file: file_name.to_string(),
+                issue_count: 0,
+                issues: Vec::new(),
+            });
+        file.issue_count += 1;
+        if file.issues.len() < MAX_GTFS_ISSUES_PER_FILE {
+            file.issues.push(GtfsValidationIssue {
+                kind,
+                line,
+                id: id.map(str::to_string),
+                message,
+            });
+        }
+    }
+
+    fn issue_count(&self) -> u64 {
+        self.files.values().map(|file| file.issue_count).sum()
+    }
+
+    fn into_report(self, feed_version: String) -> GtfsValidationReport {
+        GtfsValidationReport {
+            issue_count: self.issue_count(),
+            feed_version,
+            files: self.files.into_values().collect(),
+        }
+    }
+}
+
+// "{provider}:{id}" for every id another table can refer to.
+pub(crate) fn prefix_gtfs_ids(tables: GtfsTables, provider: &str) -> GtfsTables {
+    let prefixed = |id: &str| format!("{}:{}", provider, id);
+    let GtfsTables {
+        mut routes,
+        trips_by_route,
+        stop_times_by_trip,
+        stops_map,
+        shapes_by_id,
+        mut calendars,
+        frequencies_by_trip,
+        validation,
+    } = tables;
+
+    for route in &mut routes {
+        route.route_id = prefixed(&route.route_id);
+    }
+    let trips_by_route = trips_by_route
+        .into_iter()
+        .map(|(route_id, mut trips)| {
+            for trip in &mut trips {
+                trip.route_id = prefixed(&trip.route_id);
+                trip.service_id = prefixed(&trip.service_id);
+                trip.trip_id = prefixed(&trip.trip_id);
+                trip.shape_id = prefixed(&trip.shape_id);
+            }
+            (prefixed(&route_id), trips)
+        })
+        .collect();
+    let stop_times_by_trip = stop_times_by_trip
+        .into_iter()
+        .map(|(trip_id, mut stop_times)| {
+            for stop_time in &mut stop_times {
+                stop_time.trip_id = prefixed(&stop_time.trip_id);
+                stop_time.stop_id = prefixed(&stop_time.stop_id);
+            }
+            (prefixed(&trip_id), stop_times)
+        })
+        .collect();
+    let stops_map = stops_map
+        .into_iter()
+        .map(|(stop_id, mut stop)| {
+            stop.stop_id = prefixed(&stop.stop_id);
+            (prefixed(&stop_id), stop)
+        })
+        .collect();
+    let shapes_by_id = shapes_by_id
+        .into_iter()
+        .map(|(shape_id, mut points)| {
+            for point in &mut points {
+                point.shape_id = prefixed(&point.shape_id);
+            }
+            (prefixed(&shape_id), points)
+        })
+        .collect();
+    for calendar in &mut calendars.calendars {
+        calendar.service_id = prefixed(&calendar.service_id);
+    }
+    calendars.exceptions = calendars
+        .exceptions
+        .into_iter()
+        .map(|((service_id, date), exception_type)| ((prefixed(&service_id), date), exception_type))
+        .collect();
+    let frequencies_by_trip = frequencies_by_trip
+        .into_iter()
+        .map(|(trip_id, mut frequencies)| {
+            for frequency in &mut frequencies {
+                frequency.trip_id = prefixed(&frequency.trip_id);
+            }
+            (prefixed(&trip_id), frequencies)
+        })
+        .collect();
+    // Issues keep the ids as the provider's own files spell them; only the file is relabelled.
+    let validation = GtfsValidation {
+        files: validation
+            .files
+            .into_values()
+            .map(|mut file| {
+                file.file = format!("{}/{}", provider, file.file);
+                (file.file.clone(), file)
+            })
+            .collect(),
+    };
+
+    GtfsTables {
+        routes,
+        trips_by_route,
+        stop_times_by_trip,
+        stops_map,
+        shapes_by_id,
+        calendars,
+        frequencies_by_trip,
+        validation,
+    }
+}
+
+// Ids are expected to be disjoint already (see prefix_gtfs_ids); on a clash the merged-in feed
+// wins.
+fn merge_gtfs_tables(into: &mut GtfsTables, other: GtfsTables) {
+    into.routes.extend(other.routes);
+    into.trips_by_route.extend(other.trips_by_route);
+    into.stop_times_by_trip.extend(other.stop_times_by_trip);
+    into.stops_map.extend(other.stops_map);
+    into.shapes_by_id.extend(other.shapes_by_id);
+    into.calendars.calendars.extend(other.calendars.calendars);
+    into.calendars.exceptions.extend(other.calendars.exceptions);
+    into.frequencies_by_trip.extend(other.frequencies_by_trip);
+    into.validation.files.extend(other.validation.files);
+}
+
+fn build_gtfs_feed(tables: GtfsTables, feed_version: String) -> GtfsFeed {
+    let GtfsTables {
+        routes,
+        trips_by_route,
+        stop_times_by_trip,
+        stops_map,
+        shapes_by_id,
+        calendars,
+        frequencies_by_trip,
+        validation,
+    } = tables;
+    if validation.issue_count() > 0 {
+        println!(
+            "GTFS feed {} loaded without {} bad rows across {} files; see /gtfs/validation",
+            feed_version,
+            validation.issue_count(),
+            validation.files.len()
+        );
+    }
+    let route_geometries = load_route_geometries(
+        &routes,
+        &trips_by_route,
+        &stop_times_by_trip,
+        &stops_map,
+        &shapes_by_id,
+    );
+
+    GtfsFeed {
+        stop_ids_by_code: build_stop_code_index(&stops_map),
+        route_ids_by_stop: build_stop_route_index(&routes, &trips_by_route, &stop_times_by_trip),
+        validation: validation.into_report(feed_version.clone()),
+        feed_version,
+        route_geometries,
+        shapes_by_id,
+        calendars,
+        frequencies_by_trip,
+        routes,
+        trips_by_route,
+        stop_times_by_trip,
+        stops_map,
+    }
+}
+
+// Swaps in a freshly parsed feed when the files under any of the city's feed directories change. A new
+// version is only loaded once it has held for a whole check interval, so a copy still in
+// progress isn't read half-written; a feed that fails to load leaves the old one serving.
+pub(crate) async fn run_gtfs_reloader(state: AppState) {
+    let check_seconds = env_or(
+        "GTFS_RELOAD_CHECK_SECONDS",
+        DEFAULT_GTFS_RELOAD_CHECK_SECONDS,
+    );
+    if check_seconds == 0 {
+        println!(
+            "GTFS hot reload for city '{}' is off; GTFS_RELOAD_CHECK_SECONDS is 0",
+            state.city.id
+        );
+        return;
+    }
+    let mut check_interval = tokio::time::interval(Duration::from_secs(check_seconds));
+    check_interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
+    let mut pending_version: Option<String> = None;
+
+    loop {
+        check_interval.tick().await;
+        let version = city_gtfs_feed_version(&state.city);
+        if version == current_gtfs_feed(&state).feed_version {
+            pending_version = None;
+            continue;
+        }
+        if pending_version.as_ref() != Some(&version) {
+            pending_version = Some(version);
+            continue;
+        }
+        pending_version = None;
+
+        let city = state.city.clone();
+        match tokio::task::spawn_blocking(move || load_city_gtfs_feed(&city)).await {
+            Ok(Ok(feed)) => {
+                println!(
+                    "Reloaded GTFS for city '{}': {} routes, {} stops, version {}",
+                    state.city.id,
+                    feed.routes.len(),
+                    feed.stops_map.len(),
+                    feed.feed_version
+                );
+                let feed = Arc::new(feed);
+                match state.gtfs.write() {
+                    Ok(mut current) => *current = feed,
+                    Err(poisoned) => *poisoned.into_inner() = feed,
+                }
+            }
+            Ok(Err(error)) => println!(
+                "Failed to reload GTFS for city '{}', still serving the previous feed: {}",
+                state.city.id, error
+            ),
+            Err(error) => println!(
+                "GTFS reload task for city '{}' failed: {}",
+                state.city.id, error
+            ),
+        }
+    }
+}
+
+// Downloads the city's static feed zip and unpacks it over gtfs_data_path. The archive is
+// unpacked next to the live directory and only swapped in once it parses as a feed, so a bad
+// download never replaces good data; the previous zip is kept beside the directory and an
+// identical download is skipped. Returns whether the directory changed.
+pub(crate) async fn fetch_gtfs_static(
+    client: &reqwest::Client,
+    feed_url: &str,
+    data_dir: &StdPath,
+) -> Result<bool, LoadError> {
+    let response = client
+        .get(feed_url)
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+        .map_err(|error| format!("GTFS static download from {} failed: {}", feed_url, error))?;
+    let archive = response
+        .bytes()
+        .await
+        .map_err(|error| format!("GTFS static download from {} failed: {}", feed_url, error))?;
+
+    let data_dir = data_dir.to_path_buf();
+    tokio::task::spawn_blocking(move || install_gtfs_static(&archive, &data_dir))
+        .await
+        .map_err(|error| format!("GTFS static install task failed: {}", error))?
+}
+
+fn install_gtfs_static(archive: &[u8], data_dir: &StdPath) -> Result<bool, LoadError> {
+    let zip_path = gtfs_sibling_path(data_dir, ".zip");
+    if data_dir.is_dir() && std::fs::read(&zip_path).is_ok_and(|previous| previous == archive) {
+        return Ok(false);
+    }
+
+    let staging_dir = gtfs_sibling_path(data_dir, ".staging");
+    if staging_dir.exists() {
+        std::fs::remove_dir_all(&staging_dir)?;
+    }
+    std::fs::create_dir_all(&staging_dir)?;
+    let staging = StagingDir(staging_dir);
+    let mut zip = zip::ZipArchive::new(std::io::Cursor::new(archive))
+        .map_err(|error| format!("GTFS static archive is not a zip: {}", error))?;
+    for index in 0..zip.len() {
+        let mut entry = zip.by_index(index).map_err(|error| {
+            format!(
+                "GTFS static archive entry {} is unreadable: {}",
+                index, error
+            )
+        })?;
+        // Some publishers nest the tables in a folder; only the file names matter.
+        let Some(file_name) = entry
+            .enclosed_name()
+            .filter(|_| entry.is_file())
+            .and_then(|path| path.file_name().map(|name| name.to_owned()))
+        else {
+            continue;
+        };
+        let mut file = File::create(staging.0.join(file_name))?;
+        std::io::copy(&mut entry, &mut file)?;
+    }
+    load_gtfs_feed(&staging.0)?;
+
+    let previous_dir = gtfs_sibling_path(data_dir, ".previous");
+    if previous_dir.exists() {
+        std::fs::remove_dir_all(&previous_dir)?;
+    }
+    if data_dir.exists() {
+        std::fs::rename(data_dir, &previous_dir)?;
+    }
+    std::fs::rename(&staging.0, data_dir)?;
+    if previous_dir.exists() {
+        std::fs::remove_dir_all(&previous_dir)?;
+    }
+    std::fs::write(&zip_path, archive)?;
+    Ok(true)
+}
+
+// The directory a download is unpacked into; removed on drop unless it was swapped in as the
+// live directory, so a failed install never leaves a half-written copy behind.
+struct StagingDir(pub(crate) PathBuf);
+
+impl Drop for StagingDir {
+    fn drop(&mut self) {
+        if self.0.exists() {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+}
+
+// "../rapid_kl_data" with ".zip" is "../rapid_kl_data.zip", next to the directory rather than in it.
+fn gtfs_sibling_path(data_dir: &StdPath, suffix: &str) -> PathBuf {
+    let mut path = data_dir.as_os_str().to_owned();
+    path.push(suffix);
+    PathBuf::from(path)
+}
+
+// Downloads each city's static feed on a schedule; run_gtfs_reloader notices the new files and
+// swaps the parsed feed in.
+pub(crate) async fn run_gtfs_static_refresher(state: AppState, feed_url: String) {
+    let refresh_hours = env_or(
+        "GTFS_STATIC_REFRESH_HOURS",
+        DEFAULT_GTFS_STATIC_REFRESH_HOURS,
+    );
+    if refresh_hours == 0 {
+        return;
+    }
+    let client = match gtfs_static_client() {
+        Ok(client) => client,
+        Err(error) => {
+            println!(
+                "GTFS static refresh disabled, failed to build client: {}",
+                error
+            );
+            return;
+        }
+    };
+    let mut refresh_interval = tokio::time::interval(Duration::from_secs(refresh_hours * 3_600));
+    refresh_interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
+    // The first tick fires immediately, and startup has only just downloaded the feed.
+    refresh_interval.tick().await;
+
+    loop {
+        refresh_interval.tick().await;
+        match fetch_gtfs_static(&client, &feed_url, &state.city.gtfs_data_path).await {
+            Ok(true) => println!(
+                "Downloaded a new GTFS static feed for city '{}' from {}",
+                state.city.id, feed_url
+            ),
+            Ok(false) => {}
+            Err(error) => println!(
+                "GTFS static refresh for city '{}' failed: {}",
+                state.city.id, error
+            ),
+        }
+    }
+}
+
+pub(crate) fn gtfs_static_client() -> reqwest::Result<reqwest::Client> {
+    reqwest::Client::builder()
+        .user_agent(concat!("rapidbro/", env!("CARGO_PKG_VERSION")))
+        .timeout(Duration::from_secs(GTFS_STATIC_REQUEST_TIMEOUT_SECONDS))
+        .build()
+}
+
+const DEFAULT_BOOTSTRAP_CONFIG_PATH: &str = "rapidbro-cities.json";
+
+// `be bootstrap`: everything a fresh container needs before it can serve.
+#[derive(Debug)]
+pub(crate) struct BootstrapArgs {
+    data_dir: PathBuf,
+    feed_url: String,
+    redis_url: String,
+    // City registry written for CITY_REGISTRY_PATH.
+    config_path: PathBuf,
+    // Replace an existing registry file instead of keeping it.
+    force: bool,
+}
+
+pub(crate) fn parse_bootstrap_args(args: &[String]) -> Result<BootstrapArgs, String> {
+    let non_empty = |name: &str| env::var(name).ok().filter(|value| !value.trim().is_empty());
+    let mut bootstrap_args = BootstrapArgs {
+        data_dir: PathBuf::from(GTFS_DATA_PATH),
+        feed_url: non_empty("GTFS_STATIC_URL")
+            .unwrap_or_else(|| DEFAULT_GTFS_STATIC_URL.to_string()),
+        redis_url: non_empty("REDIS_URL").unwrap_or_else(|| DEFAULT_REDIS_URL.to_string()),
+        config_path: PathBuf::from(DEFAULT_BOOTSTRAP_CONFIG_PATH),
+        force: false,
+    };
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        let mut value = || {
+            iter.next()
+                .cloned()
+                .ok_or_else(|| format!("{} needs a value", arg))
+        };
+        match arg.as_str() {
+            "--data-dir" => bootstrap_args.data_dir = PathBuf::from(value()?),
+            "--url" => bootstrap_args.feed_url = value()?,
+            "--redis" => bootstrap_args.redis_url = value()?,
+            "--config" => bootstrap_args.config_path = PathBuf::from(value()?),
+            "--force" => bootstrap_args.force = true,
+            other => {
+                return Err(format!(
+                    "Unknown bootstrap argument '{}'; usage: be bootstrap [--data-dir <dir>] \
+                     [--url <gtfs zip url>] [--redis <url>] [--config <path>] [--force]",
+                    other
+                ))
+            }
+        }
+    }
+    Ok(bootstrap_args)
+}
+
+// Downloads and parses the static feed, checks Redis answers, and writes a one-city registry
+// pointing at both, so `CITY_REGISTRY_PATH=<config> be` serves straight after. Safe to rerun:
+// an unchanged feed is not rewritten and an existing registry is kept unless --force.
+pub(crate) async fn run_bootstrap(args: BootstrapArgs) -> Result<(), String> {
+    let client = gtfs_static_client().map_err(|error| error.to_string())?;
+    let changed = fetch_gtfs_static(&client, &args.feed_url, &args.data_dir)
+        .await
+        .map_err(|error| error.to_string())?;
+    println!(
+        "GTFS static feed from {} {} {}",
+        args.feed_url,
+        if changed {
+            "installed in"
+        } else {
+            "already current in"
+        },
+        args.data_dir.display()
+    );
+
+    // The same parse the server runs at startup, shapes included, so a feed that would fail
+    // there fails here instead.
+    let data_dir = args.data_dir.clone();
+    let feed = tokio::task::spawn_blocking(move || load_gtfs_feed(&data_dir))
+        .await
+        .map_err(|error| error.to_string())?
+        .map_err(|error| {
+            format!(
+                "GTFS feed in {} does not load: {}",
+                args.data_dir.display(),
+                error
+            )
+        })?;
+    println!(
+        "Parsed GTFS: {} routes, {} stops, {} route shapes, version {}",
+        feed.routes.len(),
+        feed.stops_map.len(),
+        feed.route_geometries.len(),
+        feed.feed_version
+    );
+
+    let redis_client = redis::Client::open(args.redis_url.as_str())
+        .map_err(|error| format!("Invalid Redis URL '{}': {}", args.redis_url, error))?;
+    let mut redis_conn = redis_client
+        .get_multiplexed_async_connection()
+        .await
+        .map_err(|error| format!("Failed to connect to Redis '{}': {}", args.redis_url, error))?;
+    let _: String = redis::cmd("PING")
+        .query_async(&mut redis_conn)
+        .await
+        .map_err(|error| format!("Failed to ping Redis '{}': {}", args.redis_url, error))?;
+    println!("Redis at {} is reachable", args.redis_url);
+
+    if args.config_path.exists() && !args.force {
+        println!(
+            "Keeping existing city registry {}; pass --force to replace it",
+            args.config_path.display()
+        );
+    } else {
+        let city = CityConfig {
+            gtfs_data_path: args.data_dir.clone(),
+            gtfs_static_url: Some(args.feed_url.clone()),
+            ..default_city_from_env()
+        };
+        let file = File::create(&args.config_path)
+            .map_err(|error| format!("Cannot write {}: {}", args.config_path.display(), error))?;
+        serde_json::to_writer_pretty(file, &vec![city]).map_err(|error| error.to_string())?;
+        println!("Wrote city registry {}", args.config_path.display());
+    }
+    println!(
+        "Bootstrap complete; start serving with CITY_REGISTRY_PATH={} REDIS_URL={}",
+        args.config_path.display(),
+        args.redis_url
+    );
+    Ok(())
+}
+
+// GTFS leaves route_color and route_text_color optional, defaulting to white and black.
+fn gtfs_display_color(value: &str, default: &str) -> String {
+    let hex = value.trim().trim_start_matches('#');
+    if hex.len() == 6 && hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        format!("#{}", hex.to_uppercase())
+    } else {
+        format!("#{}", default)
+    }
+}
+
+// The headsign comes from the service day's trip in the bus's direction when that's known,
+// otherwise from the service day's trip whose stop sequence the ETA follows.
+pub(crate) fn route_display(
+    gtfs: &GtfsContext,
+    route_id: &str,
+    direction: Option<&TripDirection>,
+) -> Option<RouteDisplay> {
+    let route = gtfs
+        .routes
+        .iter()
+        .find(|route| route.route_id == route_id)?;
+    let direction_id = match direction {
+        Some(TripDirection::Outbound) => Some(0),
+        Some(TripDirection::Inbound) => Some(1),
+        _ => None,
+    };
+    let service_day = gtfs
+        .service_date
+        .map(|service_date| (&gtfs.calendars, service_date));
+    let trips: Vec<&Trip> = gtfs
+        .trips_by_route
+        .get(route_id)
+        .into_iter()
+        .flatten()
+        .collect();
+    let direction_trips: Vec<&Trip> = trips
+        .iter()
+        .copied()
+        .filter(|trip| direction_id.is_some() && trip.direction_id == direction_id)
+        .collect();
+    let trip = service_day_trip(&direction_trips, service_day)
+        .or_else(|| service_day_trip(&trips, service_day));
+    Some(RouteDisplay {
+        route_short_name: route.route_short_name.clone(),
+        route_long_name: route.route_long_name.clone(),
+        route_color: gtfs_display_color(&route.route_color, "FFFFFF"),
+        route_text_color: gtfs_display_color(&route.route_text_color, "000000"),
+        headsign: trip
+            .and_then(|trip| trip.trip_headsign.clone())
+            .filter(|headsign| !headsign.trim().is_empty())
+            .unwrap_or_else(|| route.route_long_name.clone()),
+    })
+}
+
+pub(crate) fn get_routes_for_stop(
+    stop_id: &str,
+    gtfs: &GtfsContext,
+) -> Result<Vec<StopRouteSummary>, AppError> {
+    if !gtfs.stops_map.contains_key(stop_id) {
+        return Err(AppError::NotFound(format!("Stop '{}' not found", stop_id)));
+    }
+
+    let mut stop_routes: Vec<StopRouteSummary> = gtfs
+        .route_ids_by_stop
+        .get(stop_id)
+        .into_iter()
+        .flatten()
+        .filter_map(|route_id| gtfs.routes.iter().find(|route| &route.route_id == route_id))
+        .map(|route| StopRouteSummary {
+            route_id: route.route_id.clone(),
+            route_short_name: route.route_short_name.clone(),
+            route_long_name: route.route_long_name.clone(),
+        })
+        .collect();
+
+    stop_routes.sort_by(|a, b| {
+        a.route_short_name
+            .cmp(&b.route_short_name)
+            .then(a.route_id.cmp(&b.route_id))
+    });
+
+    if stop_routes.is_empty() {
+        return Err(AppError::NotFound(format!(
+            "No routes found for stop '{}'",
+            stop_id
+        )));
+    }
+
+    Ok(stop_routes)
+}
+
+// Exact route_id first, then short name, then the feed's trailing-zero variant of either.
+pub(crate) fn find_route<'a>(routes: &'a [Route], key: &str) -> Option<&'a Route> {
+    let wanted = key.trim().to_uppercase();
+    let normalized = normalize_route_code(&wanted);
+    routes
+        .iter()
+        .find(|route| route.route_id.to_uppercase() == wanted)
+        .or_else(|| {
+            routes
+                .iter()
+                .find(|route| route.route_short_name.to_uppercase() == wanted)
+        })
+        .or_else(|| {
+            routes.iter().find(|route| {
+                !normalized.is_empty()
+                    && (normalize_route_code(&route.route_id) == normalized
+                        || normalize_route_code(&route.route_short_name) == normalized)
+            })
+        })
+}
+
+// Route groups ("corridors") from a JSON object of group name to member routes, e.g.
+// {"pantai-dalam": ["T789", "T790"]}. Members may be route ids or short names and are stored
+// as canonical route_ids; a member missing from the GTFS feed fails the load.
+pub(crate) fn load_route_groups(
+    path: &str,
+    data_dir: &StdPath,
+) -> Result<HashMap<String, Vec<String>>, LoadError> {
+    let file = File::open(path)?;
+    let configured: HashMap<String, Vec<String>> = serde_json::from_reader(file)?;
+    // The feed load reports bad rows; group members are only checked against the good ones.
+    let routes = load_routes(data_dir, &mut GtfsValidation::default())?;
+    let mut groups = HashMap::new();
+    for (name, members) in configured {
+        let mut route_ids: Vec<String> = Vec::new();
+        for member in &members {
+            let route = find_route(&routes, member).ok_or_else(|| {
+                format!("Route group '{}' lists unknown route '{}'", name, member)
+            })?;
+            if !route_ids.contains(&route.route_id) {
+                route_ids.push(route.route_id.clone());
+            }
+        }
+        if route_ids.is_empty() {
+            return Err(format!("Route group '{}' has no routes", name).into());
+        }
+        groups.insert(name, route_ids);
+    }
+    println!("Loaded {} route groups", groups.len());
+    Ok(groups)
+}
+
+// Without CITY_REGISTRY_PATH the process serves the single Rapid KL deployment configured by
+// the environment, exactly as before the registry existed.
+pub(crate) fn city_registry_from_env() -> Result<Vec<CityConfig>, LoadError> {
+    match env::var("CITY_REGISTRY_PATH") {
+        Ok(path) if !path.trim().is_empty() => load_city_registry(&path),
+        _ => Ok(vec![default_city_from_env()]),
+    }
+}
+
+pub(crate) fn default_city_from_env() -> CityConfig {
+    let non_empty = |name: &str| env::var(name).ok().filter(|value| !value.trim().is_empty());
+    CityConfig {
+        id: DEFAULT_CITY_ID.to_string(),
+        name: DEFAULT_CITY_NAME.to_string(),
+        gtfs_data_path: PathBuf::from(GTFS_DATA_PATH),
+        socket_url: SOCKET_URL.to_string(),
+        provider: DEFAULT_PROVIDER.to_string(),
+        redis_key_prefix: normalize_redis_key_prefix(
+            &non_empty("REDIS_KEY_PREFIX").unwrap_or_default(),
+        )
+        .unwrap_or_else(|| DEFAULT_REDIS_KEY_PREFIX.to_string()),
+        gtfs_rt_vehicle_positions_url: Some(
+            non_empty("GTFS_RT_VEHICLE_POSITIONS_URL")
+                .unwrap_or_else(|| DEFAULT_GTFS_RT_VEHICLE_POSITIONS_URL.to_string()),
+        ),
+        route_groups_path: non_empty("ROUTE_GROUPS_PATH"),
+        gtfs_static_url: env_or("GTFS_STATIC_FETCH", false).then(|| {
+            non_empty("GTFS_STATIC_URL").unwrap_or_else(|| DEFAULT_GTFS_STATIC_URL.to_string())
+        }),
+        extra_gtfs_feeds: non_empty("EXTRA_GTFS_FEEDS")
+            .map(|value| parse_extra_gtfs_feeds(&value))
+            .unwrap_or_default(),
+    }
+}
+
+// "provider=path" pairs separated by commas, e.g. "mrtfeeder=data/gtfs_mrtfeeder". Malformed
+// entries are skipped with a note rather than failing startup.
+fn parse_extra_gtfs_feeds(value: &str) -> Vec<ExtraGtfsFeed> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .filter_map(|entry| match entry.split_once('=') {
+            Some((provider, path)) if !provider.trim().is_empty() && !path.trim().is_empty() => {
+                Some(ExtraGtfsFeed {
+                    provider: provider.trim().to_string(),
+                    gtfs_data_path: PathBuf::from(path.trim()),
+                })
+            }
+            _ => {
+                println!(
+                    "Ignoring EXTRA_GTFS_FEEDS entry '{}'; expected provider=path",
+                    entry
+                );
+                None
+            }
+        })
+        .collect()
+}
+
+// A JSON array of cities. The first entry is the default city, which also answers the
+// unscoped /v1/... and legacy root paths.
+fn load_city_registry(path: &str) -> Result<Vec<CityConfig>, LoadError> {
+    let file = File::open(path)?;
+    let mut cities: Vec<CityConfig> = serde_json::from_reader(file)?;
+    if cities.is_empty() {
+        return Err("City registry lists no cities".into());
+    }
+    let mut seen_ids = HashSet::new();
+    for city in &mut cities {
+        let valid_id = !city.id.is_empty()
+            && city.id.len() <= MAX_CITY_ID_LENGTH
+            && city
+                .id
+                .chars()
+                .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-');
+        if !valid_id {
+            return Err(format!(
+                "City id '{}' must be 1 to {} lowercase letters, digits or dashes",
+                city.id, MAX_CITY_ID_LENGTH
+            )
+            .into());
+        }
+        if !seen_ids.insert(city.id.clone()) {
+            return Err(format!("City '{}' is listed twice", city.id).into());
+        }
+        city.gtfs_static_url = city
+            .gtfs_static_url
+            .take()
+            .filter(|url| !url.trim().is_empty());
+        // A downloaded feed creates its directory on startup.
+        if city.gtfs_static_url.is_none() && !city.gtfs_data_path.is_dir() {
+            return Err(format!(
+                "City '{}' GTFS directory '{}' does not exist",
+                city.id,
+                city.gtfs_data_path.display()
+            )
+            .into());
+        }
+        let mut seen_providers = HashSet::from([city.provider.to_lowercase()]);
+        for extra_feed in &mut city.extra_gtfs_feeds {
+            extra_feed.provider = extra_feed.provider.trim().to_string();
+            if extra_feed.provider.is_empty() || extra_feed.provider.contains(':') {
+                return Err(format!(
+                    "City '{}' extra GTFS feed provider '{}' must be non-empty and contain no ':'",
+                    city.id, extra_feed.provider
+                )
+                .into());
+            }
+            if !seen_providers.insert(extra_feed.provider.to_lowercase()) {
+                return Err(format!(
+                    "City '{}' lists provider '{}' more than once",
+                    city.id, extra_feed.provider
+                )
+                .into());
+            }
+            if !extra_feed.gtfs_data_path.is_dir() {
+                return Err(format!(
+                    "City '{}' GTFS directory '{}' for provider '{}' does not exist",
+                    city.id,
+                    extra_feed.gtfs_data_path.display(),
+                    extra_feed.provider
+                )
+                .into());
+            }
+        }
+        city.redis_key_prefix = normalize_redis_key_prefix(&city.redis_key_prefix)
+            .unwrap_or_else(|| format!("{}:{}", DEFAULT_REDIS_KEY_PREFIX, city.id));
+        city.gtfs_rt_vehicle_positions_url = city
+            .gtfs_rt_vehicle_positions_url
+            .take()
+            .filter(|url| !url.trim().is_empty());
+    }
+    println!("Loaded {} cities from registry", cities.len());
+    Ok(cities)
+}
+
+fn normalize_redis_key_prefix(value: &str) -> Option<String> {
+    Some(value.trim().trim_end_matches(':').to_string()).filter(|prefix| !prefix.is_empty())
+}
+
+// GTFS data loading functions
+
+// Each row of a feed file that parses, with its CSV line. Rows that don't are recorded and
+// left out; a file that can't be read at all (missing, no header, I/O error) still fails.
+fn read_gtfs_rows<T: DeserializeOwned>(
+    file: File,
+    file_name: &str,
+    validation: &mut GtfsValidation,
+) -> Result<Vec<(Option<u64>, T)>, LoadError> {
+    let mut rdr = csv::ReaderBuilder::new()
+        .has_headers(true)
+        .from_reader(file);
+    let headers = rdr.headers()?.clone();
+    let mut record = csv::StringRecord::new();
+    let mut rows = Vec::new();
+    loop {
+        match rdr.read_record(&mut record) {
+            Ok(false) => break,
+            Ok(true) => {
+                let line = record.position().map(|position| position.line());
+                match record.deserialize::<T>(Some(&headers)) {
+                    Ok(row) => rows.push((line, row)),
+                    Err(error) => validation.record(
+                        file_name,
+                        line,
+                        GtfsIssueKind::MalformedRow,
+                        None,
+                        error.to_string(),
+                    ),
+                }
+            }
+            Err(error) if matches!(error.kind(), csv::ErrorKind::Io(_)) => return Err(error.into()),
+            Err(error) => validation.record(
+                file_name,
+                error.position().map(|position| position.line()),
+                GtfsIssueKind::MalformedRow,
+                None,
+                error.to_string(),
+            ),
+        }
+    }
+    Ok(rows)
+}
+
+// A coordinate pair a stop or shape point can be placed at. Null island is how the feed's
+// exports leave a position blank.
+fn valid_gtfs_coordinates(lat: f64, lon: f64) -> bool {
+    lat.is_finite()
+        && lon.is_finite()
+        && (-90.0..=90.0).contains(&lat)
+        && (-180.0..=180.0).contains(&lon)
+        && !(lat == 0.0 && lon == 0.0)
+}
+
+fn load_routes(
+    data_dir: &StdPath,
+    validation: &mut GtfsValidation,
+) -> Result<Vec<Route>, LoadError> {
+    let path = data_dir.join("routes.txt");
+    let file = File::open(path)?;
+    let mut seen_route_ids = HashSet::new();
+    let mut routes = Vec::new();
+    for (line, route) in read_gtfs_rows::<Route>(file, "routes.txt", validation)? {
+        if !seen_route_ids.insert(route.route_id.clone()) {
+            validation.record(
+                "routes.txt",
+                line,
+                GtfsIssueKind::DuplicateId,
+                Some(&route.route_id),
+                "route_id listed again; the first row is kept".to_string(),
+            );
+            continue;
+        }
+        routes.push(route);
+    }
+    Ok(routes)
+}
+
+fn load_trips(
+    data_dir: &StdPath,
+    validation: &mut GtfsValidation,
+) -> Result<HashMap<String, Vec<Trip>>, LoadError> {
+    let path = data_dir.join("trips.txt");
+    let file = File::open(path)?;
+    let mut seen_trip_ids = HashSet::new();
+    let mut trips_by_route: HashMap<String, Vec<Trip>> = HashMap::new();
+    for (line, trip) in read_gtfs_rows::<Trip>(file, "trips.txt", validation)? {
+        if !seen_trip_ids.insert(trip.trip_id.clone()) {
+            validation.record(
+                "trips.txt",
+                line,
+                GtfsIssueKind::DuplicateId,
+                Some(&trip.trip_id),
+                "trip_id listed again; the first row is kept".to_string(),
+            );
+            continue;
+        }
+        trips_by_route
+            .entry(trip.route_id.clone())
+            .or_default()
+            .push(trip);
+    }
+    Ok(trips_by_route)
+}
+
+fn load_stop_times(
+    data_dir: &StdPath,
+    validation: &mut GtfsValidation,
+) -> Result<HashMap<String, Vec<StopTime>>, LoadError> {
+    let path = data_dir.join("stop_times.txt");
+    let file = File::open(path)?;
+    let mut seen_stop_times = HashSet::new();
+    let mut stop_times_by_trip: HashMap<String, Vec<StopTime>> = HashMap::new();
+    for (line, stop_time) in read_gtfs_rows::<StopTime>(file, "stop_times.txt", validation)? {
+        if !seen_stop_times.insert((stop_time.trip_id.clone(), stop_time.stop_sequence)) {
+            validation.record(
+                "stop_times.txt",
+                line,
+                GtfsIssueKind::DuplicateId,
+                Some(&stop_time.trip_id),
+                format!(
+                    "stop_sequence {} listed again for the trip; the first row is kept",
+                    stop_time.stop_sequence
+                ),
+            );
+            continue;
+        }
+        stop_times_by_trip
+            .entry(stop_time.trip_id.clone())
+            .or_default()
+            .push(stop_time);
+    }
+    Ok(stop_times_by_trip)
+}
+
+fn load_stops(
+    data_dir: &StdPath,
+    validation: &mut GtfsValidation,
+) -> Result<HashMap<String, Stop>, LoadError> {
+    let path = data_dir.join("stops.txt");
+    let file = File::open(path)?;
+    let mut stops_map = HashMap::new();
+    for (line, mut stop) in read_gtfs_rows::<Stop>(file, "stops.txt", validation)? {
+        if stops_map.contains_key(&stop.stop_id) {
+            validation.record(
+                "stops.txt",
+                line,
+                GtfsIssueKind::DuplicateId,
+                Some(&stop.stop_id),
+                "stop_id listed again; the first row is kept".to_string(),
+            );
+            continue;
+        }
+        if !valid_gtfs_coordinates(stop.stop_lat, stop.stop_lon) {
+            validation.record(
+                "stops.txt",
+                line,
+                GtfsIssueKind::InvalidCoordinates,
+                Some(&stop.stop_id),
+                format!("stop_lat {} / stop_lon {}", stop.stop_lat, stop.stop_lon),
+            );
+            continue;
+        }
+        if stop
+            .stop_code
+            .as_deref()
+            .is_none_or(|code| code.trim().is_empty())
+        {
+            stop.stop_code = derive_stop_code(&stop.stop_name);
+        }
+        stops_map.insert(stop.stop_id.clone(), stop);
+    }
+    Ok(stops_map)
+}
+
+// GTFS requires only one of calendar.txt and calendar_dates.txt. A feed without calendar.txt has
+// no weekly pattern, and its services run only on the dates calendar_dates.txt adds.
+fn load_calendar(
+    data_dir: &StdPath,
+    validation: &mut GtfsValidation,
+) -> Result<ServiceCalendars, LoadError> {
+    let path = data_dir.join("calendar.txt");
+    let calendars = match File::open(path) {
+        Ok(file) => read_gtfs_rows::<ServiceCalendar>(file, "calendar.txt", validation)?
+            .into_iter()
+            .map(|(_, calendar)| calendar)
+            .collect(),
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+        Err(error) => return Err(error.into()),
+    };
+    Ok(ServiceCalendars {
+        calendars,
+        exceptions: load_calendar_dates(data_dir, validation)?,
+    })
+}
+
+// calendar_dates.txt is optional in GTFS; a feed without it has no exceptions.
+fn load_calendar_dates(
+    data_dir: &StdPath,
+    validation: &mut GtfsValidation,
+) -> Result<HashMap<(String, String), u8>, LoadError> {
+    let path = data_dir.join("calendar_dates.txt");
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => return Ok(HashMap::new()),
+        Err(error) => return Err(error.into()),
+    };
+    let mut exceptions = HashMap::new();
+    for (_, exception) in
+        read_gtfs_rows::<ServiceCalendarDate>(file, "calendar_dates.txt", validation)?
+    {
+        exceptions.insert(
+            (exception.service_id, exception.date),
+            exception.exception_type,
+        );
+    }
+    Ok(exceptions)
+}
+
+// frequencies.txt is optional in GTFS; a feed without it has no headway-based trips.
+fn load_frequencies(
+    data_dir: &StdPath,
+    validation: &mut GtfsValidation,
+) -> Result<HashMap<String, Vec<Frequency>>, LoadError> {
+    let path = data_dir.join("frequencies.txt");
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => return Ok(HashMap::new()),
+        Err(error) => return Err(error.into()),
+    };
+    let mut frequencies_by_trip: HashMap<String, Vec<Frequency>> = HashMap::new();
+    for (_, frequency) in read_gtfs_rows::<Frequency>(file, "frequencies.txt", validation)? {
+        frequencies_by_trip
+            .entry(frequency.trip_id.clone())
+            .or_default()
+            .push(frequency);
+    }
+    Ok(frequencies_by_trip)
+}
+
+// Points of each shape, in shape_pt_sequence order.
+fn load_shapes(
+    data_dir: &StdPath,
+    validation: &mut GtfsValidation,
+) -> Result<HashMap<String, Vec<ShapePoint>>, LoadError> {
+    let path = data_dir.join("shapes.txt");
+    let file = File::open(path)?;
+    let mut shapes_by_id: HashMap<String, Vec<ShapePoint>> = HashMap::new();
+    for (line, shape_point) in read_gtfs_rows::<ShapePoint>(file, "shapes.txt", validation)? {
+        if !valid_gtfs_coordinates(shape_point.shape_pt_lat, shape_point.shape_pt_lon) {
+            validation.record(
+                "shapes.txt",
+                line,
+                GtfsIssueKind::InvalidCoordinates,
+                Some(&shape_point.shape_id),
+                format!(
+                    "shape_pt_lat {} / shape_pt_lon {}",
+                    shape_point.shape_pt_lat, shape_point.shape_pt_lon
+                ),
+            );
+            continue;
+        }
+        shapes_by_id
+            .entry(shape_point.shape_id.clone())
+            .or_default()
+            .push(shape_point);
+    }
+    for points in shapes_by_id.values_mut() {
+        points.sort_by_key(|point| point.shape_pt_sequence);
+    }
+    Ok(shapes_by_id)
+}
+
+// The route's stop sequence from the cache, built from the loaded feed on a miss.
+pub(crate) fn cached_stops_by_route(
+    gtfs: &GtfsContext,
+    route_id: &str,
+    direction_id: Option<u32>,
+) -> Result<Arc<RouteStopsResponse>, AppError> {
+    let cache = &gtfs.route_stops_cache;
+    let key: RouteStopsKey = (
+        route_id.to_string(),
+        direction_id,
+        gtfs.feed_version.clone(),
+        gtfs.service_date,
+    );
+    let tick = cache.use_tick.fetch_add(1, AtomicOrdering::Relaxed);
+    if let Ok(mut entries) = cache.entries.lock() {
+        if let Some((route_stops, last_used)) = entries.get_mut(&key) {
+            *last_used = tick;
+            cache.hits.fetch_add(1, AtomicOrdering::Relaxed);
+            return Ok(route_stops.clone());
+        }
+    }
+
+    cache.misses.fetch_add(1, AtomicOrdering::Relaxed);
+    let route_stops = Arc::new(get_stops_by_route_direction(
+        route_id,
+        direction_id,
+        gtfs.service_date
+            .map(|service_date| (&gtfs.calendars, service_date)),
+        &gtfs.routes,
+        &gtfs.trips_by_route,
+        &gtfs.stop_times_by_trip,
+        &gtfs.stops_map,
+    )?);
+    // A poisoned lock only costs the caching, never the response.
+    if let Ok(mut entries) = cache.entries.lock() {
+        if entries.len() >= ROUTE_STOPS_CACHE_CAPACITY && !entries.contains_key(&key) {
+            let least_recent = entries
+                .iter()
+                .min_by_key(|(_, (_, last_used))| *last_used)
+                .map(|(key, _)| key.clone());
+            if let Some(least_recent) = least_recent {
+                entries.remove(&least_recent);
+            }
+        }
+        entries.insert(key, (route_stops.clone(), tick));
+    }
+    Ok(route_stops)
+}
+
+// Get stops by route_id
+fn get_stops_by_route(
+    route_id: &str,
+    routes: &[Route],
+    trips_by_route: &HashMap<String, Vec<Trip>>,
+    stop_times_by_trip: &HashMap<String, Vec<StopTime>>,
+    stops_map: &HashMap<String, Stop>,
+) -> Result<RouteStopsResponse, AppError> {
+    get_stops_by_route_direction(
+        route_id,
+        None,
+        None,
+        routes,
+        trips_by_route,
+        stop_times_by_trip,
+        stops_map,
+    )
+}
+
+// The sequence follows the route's first trip, or its first trip in direction_id when given.
+// With a service day, the first trip whose service runs that day is preferred, so weekday and
+// weekend variants each get their own pattern; a route with nothing running keeps its first.
+fn get_stops_by_route_direction(
+    route_id: &str,
+    direction_id: Option<u32>,
+    service_day: Option<(&ServiceCalendars, NaiveDate)>,
+    routes: &[Route],
+    trips_by_route: &HashMap<String, Vec<Trip>>,
+    stop_times_by_trip: &HashMap<String, Vec<StopTime>>,
+    stops_map: &HashMap<String, Stop>,
+) -> Result<RouteStopsResponse, AppError> {
+    // Find the route
+    let route = routes
+        .iter()
+        .find(|r| r.route_id == route_id)
+        .ok_or_else(|| AppError::NotFound(format!("Route '{}' not found", route_id)))?;
+
+    // Get trips for this route
+    let trips = trips_by_route
+        .get(route_id)
+        .ok_or_else(|| AppError::NotFound(format!("No trips found for route '{}'", route_id)))?;
+
+    // Get the first trip's stop times
+    let candidate_trips: Vec<&Trip> = trips
+        .iter()
+        .filter(|trip| direction_id.is_none() || trip.direction_id == direction_id)
+        .collect();
+    let first_trip =
+        service_day_trip(&candidate_trips, service_day).ok_or_else(|| match direction_id {
+            Some(direction_id) => AppError::NotFound(format!(
+                "No trips found for route '{}' in direction {}",
+                route_id, direction_id
+            )),
+            None => AppError::NotFound(format!("No trips found for route '{}'", route_id)),
+        })?;
+    let stop_times = stop_times_by_trip.get(&first_trip.trip_id).ok_or_else(|| {
+        AppError::NotFound(format!(
+            "No stop times found for trip '{}'",
+            first_trip.trip_id
+        ))
+    })?;
+
+    // Sort by stop_sequence
+    let mut sorted_stop_times: Vec<&StopTime> = stop_times.iter().collect();
+    sorted_stop_times.sort_by_key(|st| st.stop_sequence);
+
+    // Build response with stop details
+    let stops: Vec<StopWithDetails> = sorted_stop_times
+        .into_iter()
+        .filter_map(|st| {
+            stops_map.get(&st.stop_id).map(|stop| StopWithDetails {
+                stop_id: stop.stop_id.clone(),
+                stop_code: stop.stop_code.clone(),
+                stop_name: stop.stop_name.clone(),
+                stop_desc: stop.stop_desc.clone(),
+                stop_lat: stop.stop_lat,
+                stop_lon: stop.stop_lon,
+                sequence: st.stop_sequence,
+            })
+        })
+        .collect();
+
+    Ok(RouteStopsResponse {
+        route_id: route.route_id.clone(),
+        route_short_name: route.route_short_name.clone(),
+        route_long_name: route.route_long_name.clone(),
+        stops,
+        next_cursor: None,
+    })
+}
+
+// The trip whose pattern stands for the route: the first one running on the service day, or the
+// first one overall without a service day or when nothing runs that day.
+fn service_day_trip<'a>(
+    trips: &[&'a Trip],
+    service_day: Option<(&ServiceCalendars, NaiveDate)>,
+) -> Option<&'a Trip> {
+    service_day
+        .and_then(|(calendars, service_date)| {
+            trips
+                .iter()
+                .find(|trip| is_service_active(calendars, &trip.service_id, service_date))
+        })
+        .or_else(|| trips.first())
+        .copied()
+}
+
+// Geometries are built once per feed, before any service day applies, so each follows the
+// route's first trip; its stop chainages come from that same trip.
+fn load_route_geometries(
+    routes: &[Route],
+    trips_by_route: &HashMap<String, Vec<Trip>>,
+    stop_times_by_trip: &HashMap<String, Vec<StopTime>>,
+    stops_map: &HashMap<String, Stop>,
+    shapes_by_id: &HashMap<String, Vec<ShapePoint>>,
+) -> HashMap<String, RouteGeometry> {
+    trips_by_route
+        .keys()
+        .filter_map(|route_id| {
+            let shape = get_shape_by_route(route_id, None, trips_by_route, shapes_by_id).ok()?;
+            let points: Vec<(f64, f64)> = shape
+                .points
+                .iter()
+                .map(|point| (point.lat, point.lon))
+                .collect();
+            if points.len() < 2 {
+                return None;
+            }
+
+            let mut cumulative_m = Vec::with_capacity(points.len());
+            let mut total_m = 0.0;
+            cumulative_m.push(total_m);
+            for window in points.windows(2) {
+                total_m +=
+                    haversine_distance(window[0].0, window[0].1, window[1].0, window[1].1) * 1000.0;
+                cumulative_m.push(total_m);
+            }
+
+            let mut geometry = RouteGeometry {
+                route_id: route_id.clone(),
+                shape_id: shape.shape_id,
+                points,
+                cumulative_m,
+                stop_chainages: Vec::new(),
+            };
+            if let Ok(route_stops) = get_stops_by_route(
+                route_id,
+                routes,
+                trips_by_route,
+                stop_times_by_trip,
+                stops_map,
+            ) {
+                let mut stop_chainages: Vec<(String, f64)> = route_stops
+                    .stops
+                    .iter()
+                    .filter_map(|stop| {
+                        project_onto_shape(&geometry, stop.stop_lat, stop.stop_lon)
+                            .map(|projection| (stop.stop_id.clone(), projection.chainage_m))
+                    })
+                    .collect();
+                stop_chainages
+                    .sort_by(|a, b| a.1.partial_cmp(&b.1).unwrap_or(std::cmp::Ordering::Equal));
+                geometry.stop_chainages = stop_chainages;
+            }
+
+            Some((normalize_route_code(route_id), geometry))
+        })
+        .collect()
+}
+
+pub(crate) fn kl_hour_of_day(unix_ms: i64) -> u32 {
+    match (
+        chrono::DateTime::from_timestamp_millis(unix_ms),
+        FixedOffset::east_opt(KL_UTC_OFFSET_SECONDS),
+    ) {
+        (Some(utc_time), Some(kl_offset)) => utc_time.with_timezone(&kl_offset).hour(),
+        _ => 0,
+    }
+}
+
+// The shape of the same trip get_stops_by_route_direction picks for the service day, so the
+// polyline and the stop sequence describe one pattern.
+pub(crate) fn get_shape_by_route(
+    route_id: &str,
+    service_day: Option<(&ServiceCalendars, NaiveDate)>,
+    trips_by_route: &HashMap<String, Vec<Trip>>,
+    shapes_by_id: &HashMap<String, Vec<ShapePoint>>,
+) -> Result<RouteShapeResponse, AppError> {
+    let trips: Vec<&Trip> = trips_by_route.get(route_id).into_iter().flatten().collect();
+    let first_trip = service_day_trip(&trips, service_day)
+        .ok_or_else(|| AppError::NotFound(format!("No trips found for route '{}'", route_id)))?;
+    let shape_points = shapes_by_id.get(&first_trip.shape_id).ok_or_else(|| {
+        AppError::NotFound(format!(
+            "No shape found for shape_id '{}'",
+            first_trip.shape_id
+        ))
+    })?;
+
+    let points = shape_points
+        .iter()
+        .map(|point| RouteShapePoint {
+            lat: point.shape_pt_lat,
+            lon: point.shape_pt_lon,
+            sequence: point.shape_pt_sequence,
+        })
+        .collect();
+
+    Ok(RouteShapeResponse {
+        route_id: route_id.to_string(),
+        shape_id: first_trip.shape_id.clone(),
+        points,
+    })
+}
+
+fn build_stop_code_index(stops_map: &HashMap<String, Stop>) -> HashMap<String, Vec<String>> {
+    let mut stop_ids_by_code: HashMap<String, Vec<String>> = HashMap::new();
+    for stop in stops_map.values() {
+        if let Some(code) = &stop.stop_code {
+            stop_ids_by_code
+                .entry(code.to_uppercase())
+                .or_default()
+                .push(stop.stop_id.clone());
+        }
+    }
+    for stop_ids in stop_ids_by_code.values_mut() {
+        stop_ids.sort();
+    }
+    stop_ids_by_code
+}
+
+// Built from every trip of the route, so a stop is listed under a route when any of its patterns
+// (weekday, weekend, either direction) calls there, whichever one runs on a given day.
+fn build_stop_route_index(
+    routes: &[Route],
+    trips_by_route: &HashMap<String, Vec<Trip>>,
+    stop_times_by_trip: &HashMap<String, Vec<StopTime>>,
+) -> HashMap<String, Vec<String>> {
+    let mut route_ids_by_stop: HashMap<String, Vec<String>> = HashMap::new();
+    for route in routes {
+        let stop_times = trips_by_route
+            .get(&route.route_id)
+            .into_iter()
+            .flatten()
+            .filter_map(|trip| stop_times_by_trip.get(&trip.trip_id))
+            .flatten();
+        for stop_time in stop_times {
+            let route_ids = route_ids_by_stop
+                .entry(stop_time.stop_id.clone())
+                .or_default();
+            // Routes are indexed one at a time, so a repeat is always the last one pushed.
+            if route_ids.last() != Some(&route.route_id) {
+                route_ids.push(route.route_id.clone());
+            }
+        }
+    }
+    route_ids_by_stop
+}
+
+// Accepts a stop_id or a sign code. Unknown keys pass through so the caller's own not-found
+// handling applies; a code shared by several stops is a 409 listing the stop_ids to use instead.
+pub(crate) fn resolve_stop_key(
+    key: &str,
+    stops_map: &HashMap<String, Stop>,
+    stop_ids_by_code: &HashMap<String, Vec<String>>,
+) -> Result<String, AppError> {
+    let key = key.trim();
+    if stops_map.contains_key(key) {
+        return Ok(key.to_string());
+    }
+    match stop_ids_by_code.get(&key.to_uppercase()).map(Vec::as_slice) {
+        Some([stop_id]) => Ok(stop_id.clone()),
+        Some(stop_ids) if !stop_ids.is_empty() => Err(AppError::Conflict(format!(
+            "Stop code '{}' matches several stops, use a stop_id: {}",
+            key,
+            stop_ids.join(", ")
+        ))),
+        _ => Ok(key.to_string()),
+    }
+}
+
+// Rapid KL stop names start with the code printed on the stop sign, e.g. "KL1397 FLAT PKNS"
+// or "(M) PJ469 TERMINAL BAS"; the feed has no separate stop_code column.
+fn derive_stop_code(stop_name: &str) -> Option<String> {
+    let first_word = stop_name
+        .split_whitespace()
+        .find(|word| !(word.starts_with('(') && word.ends_with(')')))?;
+    let letters = first_word
+        .chars()
+        .take_while(|ch| ch.is_ascii_alphabetic())
+        .count();
+    let is_code = letters > 0
+        && letters < first_word.len()
+        && first_word[letters..].chars().all(|ch| ch.is_ascii_digit());
+    is_code.then(|| first_word.to_uppercase())
+}
+
+// Searches the loaded feed's stops, extra feeds included. Closed stops are passed over for the
+// nearest open one.
+pub(crate) fn find_nearest_stop(
+    stops_map: &HashMap<String, Stop>,
+    lat: f64,
+    lon: f64,
+    closed_stops: &HashMap<String, StopClosure>,
+) -> Result<NearestStopResponse, AppError> {
+    if !(-90.0..=90.0).contains(&lat) || !(-180.0..=180.0).contains(&lon) {
+        return Err(AppError::Validation(
+            "Invalid latitude/longitude values".to_string(),
+        ));
+    }
+
+    let nearest_stop = stops_map
+        .values()
+        .filter(|stop| !closed_stops.contains_key(&stop.stop_id))
+        .map(|stop| {
+            let distance_km = haversine_distance(lat, lon, stop.stop_lat, stop.stop_lon);
+            (stop, distance_km)
+        })
+        .min_by(|(_, left_distance), (_, right_distance)| {
+            left_distance
+                .partial_cmp(right_distance)
+                .unwrap_or(std::cmp::Ordering::Equal)
+        })
+        .ok_or_else(|| AppError::NotFound("No stops available".to_string()))?;
+
+    let (stop, distance_km) = nearest_stop;
+    Ok(NearestStopResponse {
+        stop_id: stop.stop_id.clone(),
+        stop_code: stop.stop_code.clone(),
+        stop_name: stop.stop_name.clone(),
+        stop_desc: stop.stop_desc.clone(),
+        stop_lat: stop.stop_lat,
+        stop_lon: stop.stop_lon,
+        distance_km: (distance_km * 1000.0).round() / 1000.0,
+        distance_meters: (distance_km * 1000.0 * 10.0).round() / 10.0,
+        place: None,
+    })
+}
+
+// Up to `count` open stops within radius_m of a stop, closest first.
+pub(crate) fn nearby_open_stops(
+    stops_map: &HashMap<String, Stop>,
+    stop: &Stop,
+    closed_stops: &HashMap<String, StopClosure>,
+    count: usize,
+    radius_m: f64,
+) -> Vec<NearbyStop> {
+    let mut nearby: Vec<NearbyStop> = stops_map
+        .values()
+        .filter(|other| other.stop_id != stop.stop_id)
+        .filter(|other| !closed_stops.contains_key(&other.stop_id))
+        .map(|other| NearbyStop {
+            stop_id: other.stop_id.clone(),
+            stop_code: other.stop_code.clone(),
+            stop_name: other.stop_name.clone(),
+            distance_meters: (haversine_distance(
+                stop.stop_lat,
+                stop.stop_lon,
+                other.stop_lat,
+                other.stop_lon,
+            ) * 1000.0
+                * 10.0)
+                .round()
+                / 10.0,
+        })
+        .filter(|other| other.distance_meters <= radius_m)
+        .collect();
+    nearby.sort_by(|left, right| {
+        left.distance_meters
+            .partial_cmp(&right.distance_meters)
+            .unwrap_or(std::cmp::Ordering::Equal)
+    });
+    nearby.truncate(count);
+    nearby
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::*;
+    use crate::models::RouteStopsCache;
+    use crate::test_support::{
+        t789_feed_files, FeedDir, CALENDAR, ROUTES_HEADER, STOPS_HEADER, TRIPS,
+    };
+
+    fn gtfs_context(feed: GtfsFeed) -> GtfsContext {
+        GtfsContext {
+            feed: Arc::new(feed),
+            route_stops_cache: Arc::new(RouteStopsCache::default()),
+            detours: HashMap::new(),
+            service_date: None,
+        }
+    }
+
+    // Kuala Lumpur wall-clock time on a date; 2026-03-02 is a Monday.
+    fn kl_time_ms(date: (i32, u32, u32), hours: i64, minutes: i64) -> i64 {
+        let date = NaiveDate::from_ymd_opt(date.0, date.1, date.2).unwrap();
+        kl_day_bounds_ms(date).unwrap().0 + (hours * 60 + minutes) * 60_000
+    }
+
+    fn issue_kinds(validation: &GtfsValidation, file_name: &str) -> Vec<GtfsIssueKind> {
+        validation.files[file_name]
+            .issues
+            .iter()
+            .map(|issue| issue.kind)
+            .collect()
+    }
+
+    #[test]
+    fn load_gtfs_tables_leaves_out_and_records_bad_rows() {
+        let feed = FeedDir::new(
+            "gtfs-bad-rows",
+            &[
+                (
+                    "routes.txt",
+                    format!(
+                        "{}T7890,rapidkl,T789,Kelana Jaya - Lembah Subang,3,,\n\
+                         T7890,rapidkl,T789,Listed twice,3,,\n\
+                         T8010,rapidkl,T801,Route type is not a number,bus,,\n",
+                        ROUTES_HEADER
+                    ),
+                ),
+                ("trips.txt", TRIPS.to_string()),
+                (
+                    "stop_times.txt",
+                    "trip_id,arrival_time,departure_time,stop_id,stop_sequence,stop_headsign\n\
+                     T7890-1,07:00:00,07:00:00,1000838,1,\n\
+                     T7890-1,07:05:00,07:05:00,1008485,2,\n\
+                     T7890-1,07:06:00,07:06:00,1000838,2,\n\
+                     T7890-1,07:10:00,07:10:00,9999999,3,\n"
+                        .to_string(),
+                ),
+                (
+                    "stops.txt",
+                    format!(
+                        "{}1000838,,KL1397 FLAT PKNS,,3.1219,101.6572\n\
+                         1008485,,(M) PJ469 TERMINAL BAS,,3.1101,101.6401\n\
+                         1010001,,NO POSITION,,0,0\n",
+                        STOPS_HEADER
+                    ),
+                ),
+                (
+                    "shapes.txt",
+                    "shape_id,shape_pt_lat,shape_pt_lon,shape_pt_sequence\n\
+                     S1,3.1101,101.6401,2\n\
+                     S1,3.1219,101.6572,1\n\
+                     S1,95.0,101.6500,3\n"
+                        .to_string(),
+                ),
+                ("calendar.txt", CALENDAR.to_string()),
+            ],
+        );
+
+        let tables = load_gtfs_tables(&feed.0).unwrap();
+        assert_eq!(tables.routes.len(), 1);
+        assert_eq!(
+            tables.routes[0].route_long_name,
+            "Kelana Jaya - Lembah Subang"
+        );
+        assert_eq!(tables.stops_map.len(), 2);
+        assert_eq!(
+            tables.stops_map["1008485"].stop_code.as_deref(),
+            Some("PJ469")
+        );
+        let sequences: Vec<u32> = tables.stop_times_by_trip["T7890-1"]
+            .iter()
+            .map(|stop_time| stop_time.stop_sequence)
+            .collect();
+        assert_eq!(sequences, vec![1, 2]);
+        let shape_lats: Vec<f64> = tables.shapes_by_id["S1"]
+            .iter()
+            .map(|point| point.shape_pt_lat)
+            .collect();
+        assert_eq!(shape_lats, vec![3.1219, 3.1101]);
+
+        let validation = &tables.validation;
+        assert_eq!(validation.issue_count(), 6);
+        assert_eq!(
+            issue_kinds(validation, "routes.txt"),
+            vec![GtfsIssueKind::MalformedRow, GtfsIssueKind::DuplicateId]
+        );
+        assert_eq!(validation.files["routes.txt"].issues[1].line, Some(3));
+        assert_eq!(
+            issue_kinds(validation, "stops.txt"),
+            vec![GtfsIssueKind::InvalidCoordinates]
+        );
+        assert_eq!(
+            issue_kinds(validation, "stop_times.txt"),
+            vec![GtfsIssueKind::DuplicateId, GtfsIssueKind::MissingReference]
+        );
+        assert_eq!(
+            issue_kinds(validation, "shapes.txt"),
+            vec![GtfsIssueKind::InvalidCoordinates]
+        );
+    }
+
+    #[test]
+    fn load_gtfs_tables_fails_without_a_usable_stop() {
+        let feed = FeedDir::new(
+            "gtfs-no-stops",
+            &[
+                (
+                    "routes.txt",
+                    format!("{}T7890,rapidkl,T789,Kelana Jaya,3,,\n", ROUTES_HEADER),
+                ),
+                ("trips.txt", TRIPS.to_string()),
+                (
+                    "stop_times.txt",
+                    "trip_id,arrival_time,departure_time,stop_id,stop_sequence,stop_headsign\n"
+                        .to_string(),
+                ),
+                (
+                    "stops.txt",
+                    format!("{}1010001,,NO POSITION,,0,0\n", STOPS_HEADER),
+                ),
+            ],
+        );
+        assert!(matches!(
+            load_gtfs_tables(&feed.0),
+            Err(LoadError::Invalid(_))
+        ));
+    }
+
+    fn zip_archive(files: &[(&str, &str)]) -> Vec<u8> {
+        let mut zip = zip::ZipWriter::new(std::io::Cursor::new(Vec::new()));
+        for (file_name, contents) in files {
+            zip.start_file(*file_name, zip::write::SimpleFileOptions::default())
+                .unwrap();
+            zip.write_all(contents.as_bytes()).unwrap();
+        }
+        zip.finish().unwrap().into_inner()
+    }
+
+    #[test]
+    fn install_gtfs_static_removes_the_staging_dir_when_the_feed_is_bad() {
+        let feed = FeedDir::new("gtfs-static-bad", &[]);
+        let data_dir = feed.0.join("rapid_kl_data");
+        let staging_dir = gtfs_sibling_path(&data_dir, ".staging");
+
+        // Unpacks fine but has no stops.txt.
+        let archive = zip_archive(&[("routes.txt", ROUTES_HEADER), ("trips.txt", TRIPS)]);
+        assert!(install_gtfs_static(&archive, &data_dir).is_err());
+        assert!(!staging_dir.exists());
+        assert!(!data_dir.exists());
+
+        assert!(install_gtfs_static(b"not a zip", &data_dir).is_err());
+        assert!(!staging_dir.exists());
+    }
+
+    #[test]
+    fn trip_start_secs_expands_a_frequency_window_into_trip_starts() {
+        let frequencies_by_trip = HashMap::from([(
+            "T7890-1".to_string(),
+            vec![Frequency {
+                trip_id: "T7890-1".to_string(),
+                start_time: "06:00:00".to_string(),
+                end_time: "07:00:00".to_string(),
+                headway_secs: 900,
+            }],
+        )]);
+        // The window end is exclusive.
+        assert_eq!(
+            trip_start_secs("T7890-1", 21_000, &frequencies_by_trip),
+            vec![21_600, 22_500, 23_400, 24_300]
+        );
+        assert_eq!(
+            trip_start_secs("T7890-2", 21_000, &frequencies_by_trip),
+            vec![21_000]
+        );
+    }
+
+    #[test]
+    fn route_headways_lists_the_windows_of_services_running_that_day() {
+        let feed = FeedDir::new("gtfs-headways", &t789_feed_files(Some(CALENDAR)));
+        let gtfs = gtfs_context(load_gtfs_feed(&feed.0).unwrap());
+        let monday = NaiveDate::from_ymd_opt(2026, 3, 2).unwrap();
+        let windows: Vec<(String, f64)> = route_headways("T7890", &gtfs, monday)
+            .into_iter()
+            .map(|headway| (headway.start_time, headway.headway_minutes))
+            .collect();
+        assert_eq!(
+            windows,
+            vec![
+                ("06:00:00".to_string(), 15.0),
+                ("08:00:00".to_string(), 10.0),
+                ("23:00:00".to_string(), 30.0),
+            ]
+        );
+        let saturday = NaiveDate::from_ymd_opt(2026, 3, 7).unwrap();
+        assert!(route_headways("T7890", &gtfs, saturday).is_empty());
+    }
+
+    #[test]
+    fn current_route_headway_prefers_the_smallest_overlapping_headway() {
+        let feed = FeedDir::new("gtfs-headway-overlap", &t789_feed_files(Some(CALENDAR)));
+        let gtfs = gtfs_context(load_gtfs_feed(&feed.0).unwrap());
+        let headway_at = |hours, minutes| {
+            current_route_headway("T7890", &gtfs, kl_time_ms((2026, 3, 2), hours, minutes))
+                .map(|headway| headway.headway_minutes)
+        };
+        assert_eq!(headway_at(7, 0), Some(15.0));
+        assert_eq!(headway_at(8, 30), Some(10.0));
+        assert_eq!(headway_at(9, 30), Some(10.0));
+        assert_eq!(headway_at(11, 0), None);
+    }
+
+    #[test]
+    fn current_route_headway_counts_windows_past_midnight_on_the_previous_day() {
+        let feed = FeedDir::new("gtfs-headway-midnight", &t789_feed_files(Some(CALENDAR)));
+        let gtfs = gtfs_context(load_gtfs_feed(&feed.0).unwrap());
+        // 00:30 on Saturday is 24:30:00 of Friday's service, which runs; Saturday's doesn't.
+        let headway = current_route_headway("T7890", &gtfs, kl_time_ms((2026, 3, 7), 0, 30));
+        assert_eq!(
+            headway.map(|headway| (headway.start_time, headway.headway_minutes)),
+            Some(("23:00:00".to_string(), 30.0))
+        );
+        assert!(current_route_headway("T7890", &gtfs, kl_time_ms((2026, 3, 8), 0, 30)).is_none());
+    }
+
+    #[test]
+    fn is_service_active_applies_exceptions_over_the_weekly_pattern() {
+        let calendars = ServiceCalendars {
+            calendars: vec![ServiceCalendar {
+                service_id: "weekday".to_string(),
+                monday: 1,
+                tuesday: 1,
+                wednesday: 1,
+                thursday: 1,
+                friday: 1,
+                saturday: 0,
+                sunday: 0,
+                start_date: "20260101".to_string(),
+                end_date: "20261231".to_string(),
+            }],
+            exceptions: HashMap::from([
+                (("weekday".to_string(), "20260303".to_string()), 2),
+                (("weekday".to_string(), "20260307".to_string()), 1),
+            ]),
+        };
+        let active = |year, month, day| {
+            is_service_active(
+                &calendars,
+                "weekday",
+                NaiveDate::from_ymd_opt(year, month, day).unwrap(),
+            )
+        };
+        assert!(active(2026, 3, 2));
+        // Removed for the day, then added on a Saturday it wouldn't otherwise run.
+        assert!(!active(2026, 3, 3));
+        assert!(active(2026, 3, 7));
+        assert!(!active(2026, 3, 8));
+        // Mondays outside the calendar's date range.
+        assert!(!active(2025, 12, 29));
+        assert!(!active(2027, 1, 4));
+        assert!(!is_service_active(
+            &calendars,
+            "weekend",
+            NaiveDate::from_ymd_opt(2026, 3, 7).unwrap()
+        ));
+    }
+
+    #[test]
+    fn a_feed_with_only_calendar_dates_runs_on_the_dates_it_adds() {
+        let mut files = t789_feed_files(None);
+        files.push((
+            "calendar_dates.txt",
+            "service_id,date,exception_type\n\
+             weekday,20260302,1\n"
+                .to_string(),
+        ));
+        let feed = FeedDir::new("gtfs-calendar-dates-only", &files);
+        let gtfs = gtfs_context(load_gtfs_feed(&feed.0).unwrap());
+        assert!(gtfs.calendars.calendars.is_empty());
+
+        let monday = NaiveDate::from_ymd_opt(2026, 3, 2).unwrap();
+        let tuesday = NaiveDate::from_ymd_opt(2026, 3, 3).unwrap();
+        assert!(is_service_active(&gtfs.calendars, "weekday", monday));
+        assert!(!is_service_active(&gtfs.calendars, "weekday", tuesday));
+        assert_eq!(route_headways("T7890", &gtfs, monday).len(), 3);
+        assert!(route_headways("T7890", &gtfs, tuesday).is_empty());
+    }
+
+    #[test]
+    fn route_stops_shape_and_stop_index_follow_the_service_day_pattern() {
+        let mut files = t789_feed_files(Some(
+            "service_id,monday,tuesday,wednesday,thursday,friday,saturday,sunday,start_date,end_date\n\
+             weekday,1,1,1,1,1,0,0,20260101,20261231\n\
+             weekend,0,0,0,0,0,1,1,20260101,20261231\n",
+        ));
+        for (file_name, contents) in &mut files {
+            match *file_name {
+                "trips.txt" => contents.push_str("T7890,weekend,T7890-2,S2,Lembah Subang,0\n"),
+                "stop_times.txt" => contents.push_str(
+                    "T7890-2,07:00:00,07:00:00,1000838,1,\n\
+                     T7890-2,07:04:00,07:04:00,1010002,2,\n",
+                ),
+                "stops.txt" => contents.push_str("1010002,,KL2094 PANTAI DALAM,,3.1150,101.6630\n"),
+                _ => {}
+            }
+        }
+        files.push((
+            "shapes.txt",
+            "shape_id,shape_pt_lat,shape_pt_lon,shape_pt_sequence\n\
+             S1,3.1219,101.6572,1\n\
+             S1,3.1101,101.6401,2\n\
+             S2,3.1219,101.6572,1\n\
+             S2,3.1150,101.6630,2\n"
+                .to_string(),
+        ));
+        let feed = FeedDir::new("gtfs-service-day-pattern", &files);
+        let gtfs = load_gtfs_feed(&feed.0).unwrap();
+
+        // Both patterns are indexed, and the route is listed once at the stop they share.
+        assert_eq!(gtfs.route_ids_by_stop["1000838"], vec!["T7890"]);
+        assert_eq!(gtfs.route_ids_by_stop["1008485"], vec!["T7890"]);
+        assert_eq!(gtfs.route_ids_by_stop["1010002"], vec!["T7890"]);
+
+        let monday = NaiveDate::from_ymd_opt(2026, 3, 2).unwrap();
+        let saturday = NaiveDate::from_ymd_opt(2026, 3, 7).unwrap();
+        let pattern_on = |service_day: Option<NaiveDate>| {
+            let service_day = service_day.map(|date| (&gtfs.calendars, date));
+            let shape = get_shape_by_route(
+                "T7890",
+                service_day,
+                &gtfs.trips_by_route,
+                &gtfs.shapes_by_id,
+            )
+            .unwrap();
+            let route_stops = get_stops_by_route_direction(
+                "T7890",
+                None,
+                service_day,
+                &gtfs.routes,
+                &gtfs.trips_by_route,
+                &gtfs.stop_times_by_trip,
+                &gtfs.stops_map,
+            )
+            .unwrap();
+            let stop_ids: Vec<String> = route_stops
+                .stops
+                .into_iter()
+                .map(|stop| stop.stop_id)
+                .collect();
+            (shape.shape_id, stop_ids)
+        };
+        assert_eq!(
+            pattern_on(Some(saturday)),
+            (
+                "S2".to_string(),
+                vec!["1000838".to_string(), "1010002".to_string()]
+            )
+        );
+        assert_eq!(
+            pattern_on(Some(monday)),
+            (
+                "S1".to_string(),
+                vec!["1000838".to_string(), "1008485".to_string()]
+            )
+        );
+        assert_eq!(pattern_on(None), pattern_on(Some(monday)));
+    }
+
+    #[test]
+    fn find_nearest_stop_passes_over_closed_stops() {
+        let feed = FeedDir::new("gtfs-nearest-stop", &t789_feed_files(Some(CALENDAR)));
+        let gtfs = load_gtfs_feed(&feed.0).unwrap();
+        let nearest =
+            find_nearest_stop(&gtfs.stops_map, 3.1218, 101.6570, &HashMap::new()).unwrap();
+        assert_eq!(nearest.stop_id, "1000838");
+        assert_eq!(nearest.stop_code.as_deref(), Some("KL1397"));
+
+        let closed_stops = HashMap::from([(
+            "1000838".to_string(),
+            StopClosure {
+                id: "closure-1".to_string(),
+                stop_id: "1000838".to_string(),
+                stop_name: "KL1397 FLAT PKNS".to_string(),
+                valid_from_unix_ms: 0,
+                valid_until_unix_ms: i64::MAX,
+                message: "Roadworks".to_string(),
+                created_at_unix_ms: 0,
+            },
+        )]);
+        let nearest = find_nearest_stop(&gtfs.stops_map, 3.1218, 101.6570, &closed_stops).unwrap();
+        assert_eq!(nearest.stop_id, "1008485");
+        assert!(find_nearest_stop(&gtfs.stops_map, 91.0, 101.6570, &HashMap::new()).is_err());
+    }
+
+    #[test]
+    fn valid_gtfs_coordinates_rejects_blank_and_impossible_positions() {
+        assert!(valid_gtfs_coordinates(3.1219, 101.6572));
+        assert!(!valid_gtfs_coordinates(0.0, 0.0));
+        assert!(!valid_gtfs_coordinates(90.5, 101.6572));
+        assert!(!valid_gtfs_coordinates(3.1219, -180.5));
+        assert!(!valid_gtfs_coordinates(f64::NAN, 101.6572));
+    }
+
+    #[test]
+    fn parse_gtfs_time_counts_past_midnight() {
+        assert_eq!(parse_gtfs_time("07:05:30"), Some(25_530));
+        assert_eq!(parse_gtfs_time(" 25:10:05"), Some(90_605));
+        assert_eq!(parse_gtfs_time("07:05"), None);
+        assert_eq!(parse_gtfs_time("seven"), None);
+    }
+
+    #[test]
+    fn derive_stop_code_reads_the_code_off_the_stop_name() {
+        assert_eq!(
+            derive_stop_code("KL1397 FLAT PKNS").as_deref(),
+            Some("KL1397")
+        );
+        assert_eq!(
+            derive_stop_code("(M) pj469 TERMINAL BAS").as_deref(),
+            Some("PJ469")
+        );
+        assert_eq!(derive_stop_code("HAB PASAR SENI"), None);
+        assert_eq!(derive_stop_code("1397 FLAT PKNS"), None);
+    }
+
+    #[test]
+    fn parse_extra_gtfs_feeds_skips_malformed_entries() {
+        let feeds =
+            parse_extra_gtfs_feeds(" mrtfeeder = data/gtfs_mrtfeeder ,nopath,=data/x,spare=, ");
+        assert_eq!(feeds.len(), 1);
+        assert_eq!(feeds[0].provider, "mrtfeeder");
+        assert_eq!(
+            feeds[0].gtfs_data_path,
+            PathBuf::from("data/gtfs_mrtfeeder")
+        );
+    }
+
+    #[test]
+    fn normalize_redis_key_prefix_drops_trailing_separators() {
+        assert_eq!(
+            normalize_redis_key_prefix(" rapidbro:kl: ").as_deref(),
+            Some("rapidbro:kl")
+        );
+        assert_eq!(normalize_redis_key_prefix(":"), None);
+        assert_eq!(normalize_redis_key_prefix(""), None);
+    }
+
+    #[test]
+    fn gtfs_display_color_falls_back_on_malformed_values() {
+        assert_eq!(gtfs_display_color("e4002b", "FFFFFF"), "#E4002B");
+        assert_eq!(gtfs_display_color("#00a651", "FFFFFF"), "#00A651");
+        assert_eq!(gtfs_display_color("red", "FFFFFF"), "#FFFFFF");
+        assert_eq!(gtfs_display_color("", "000000"), "#000000");
+    }
+}
@@ -0,0 +1,1656 @@
+// Live feed ingestion: socket.io bus positions, GTFS-RT vehicle positions, service alerts and
+// feed recording/replay.
+
+use axum::{
+    body::Bytes,
+    http::{
+        header::{ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED},
+        HeaderName,
+    },
+};
+use base64::Engine;
+use chrono::{FixedOffset, TimeZone};
+use flate2::read::GzDecoder;
+use futures_util::FutureExt;
+use hmac::{Hmac, Mac};
+use prost::Message;
+use rapidbro_eta::{normalize_route_code, RouteGeometry, KL_UTC_OFFSET_SECONDS};
+use rapidbro_types::{BusPosition, IngestorStatus, TripDirection, TripMetadata, VehicleInfo};
+use rust_socketio::{asynchronous::ClientBuilder, Payload, TransportType};
+use serde_json::json;
+use sha2::Sha256;
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::{sync::Notify, time::MissedTickBehavior};
+
+use crate::api::{parse_flag, shape_gtfs_rt_feed, POSITION_PRECISIONS, REDACTION_PROFILES};
+use crate::gtfs::{current_gtfs_feed, LoadError};
+use crate::models::{
+    BusNoRules, CaptainIdPrivacy, CityConfig, DeadLetterSample, GeoPolygon, NamedGeofence,
+    PrivacySettings, RecordedFeedFrame, ResponseShape, ServiceAlert, VehicleRosterRow, Warmup,
+};
+use crate::pipeline::{
+    default_ingest_stages, record_stage_runs, run_ingest_pipeline, IngestBatch, IngestContext,
+    IngestStage,
+};
+use crate::store::{REDIS_BUSES_LAST_SEEN_KEY, REDIS_SERVICE_ALERTS_KEY};
+use crate::{env_or, now_unix_ms, AppState};
+
+// Last good copy of the upstream vehicle-position feed, kept as received and, shaped for each
+// tier and precision, in both wire formats so /gtfs never has to decode or re-serialize on the
+// request path.
+#[derive(Debug, Default)]
+pub(crate) struct GtfsRtCache {
+    protobuf: Option<Bytes>,
+    // (protobuf, JSON) for every ResponseShape.
+    pub(crate) shaped: HashMap<ResponseShape, (Bytes, Bytes)>,
+    pub(crate) etag: Option<String>,
+    upstream_etag: Option<String>,
+    upstream_last_modified: Option<String>,
+    pub(crate) fetched_at_unix_ms: Option<i64>,
+    pub(crate) last_error: Option<String>,
+}
+
+#[derive(Debug)]
+enum GtfsRtFetchError {
+    Request(reqwest::Error),
+    Status(u16),
+    Decode(prost::DecodeError),
+    Encode(serde_json::Error),
+}
+
+impl std::fmt::Display for GtfsRtFetchError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            GtfsRtFetchError::Request(error) => write!(f, "upstream request failed: {}", error),
+            GtfsRtFetchError::Status(status) => write!(f, "upstream returned HTTP {}", status),
+            GtfsRtFetchError::Decode(error) => {
+                write!(f, "upstream feed is not valid GTFS-RT: {}", error)
+            }
+            GtfsRtFetchError::Encode(error) => {
+                write!(f, "failed to encode feed as JSON: {}", error)
+            }
+        }
+    }
+}
+
+pub(crate) struct ReplayArgs {
+    // A recorded file, or "-" to read frames streamed on stdin.
+    from: String,
+    // Playback speed multiplier; None replays as fast as Redis accepts writes.
+    speed: Option<f64>,
+    pub(crate) redis_url: Option<String>,
+    flush_db: bool,
+}
+
+pub(crate) const SOCKET_URL: &str = "https://rapidbus-socketio-avl.prasarana.com.my";
+const CAPTAIN_ID_HASH_PREFIX: &str = "anon:";
+const MAX_DEAD_LETTER_SAMPLES: usize = 20;
+// Two default stationary windows, so stationary filtering has seen every bus at least once.
+const DEFAULT_WARMUP_MIN_SECONDS: i64 = 120;
+const DEFAULT_WARMUP_MIN_BATCHES: u64 = 10;
+const DEAD_LETTER_PREVIEW_CHARS: usize = 200;
+// Long pauses in a recording (overnight, outages) are not reproduced during replay.
+const MAX_REPLAY_GAP_MS: i64 = 60_000;
+const SERVICE_ALERTS_POLL_INTERVAL_SECONDS: u64 = 120;
+pub(crate) const DEFAULT_GTFS_RT_VEHICLE_POSITIONS_URL: &str =
+    "https://api.data.gov.my/gtfs-realtime/vehicle-position/prasarana?category=rapid-bus-kl";
+const DEFAULT_GTFS_RT_REFRESH_SECONDS: u64 = 30;
+const GTFS_RT_REQUEST_TIMEOUT_SECONDS: u64 = 10;
+const DEFAULT_RELOAD_INTERVAL_SECONDS: u64 = 20;
+const DEFAULT_RELOAD_INTERVAL_MIN_SECONDS: u64 = 10;
+const DEFAULT_RELOAD_INTERVAL_MAX_SECONDS: u64 = 120;
+// Share of a reload window's messages that may fail to decode before the upstream counts as
+// unstable.
+const RELOAD_UNSTABLE_DECODE_FAILURE_RATIO: f64 = 0.1;
+pub(crate) fn initial_ingestor_status() -> IngestorStatus {
+    IngestorStatus {
+        connected: false,
+        reconnect_count: 0,
+        messages_processed: 0,
+        buses_written: 0,
+        decode_failures: 0,
+        redis_write_failures: 0,
+        duplicate_buses_merged: 0,
+        outside_service_area_positions: 0,
+        seeded_buses: 0,
+        last_seeded_unix_ms: None,
+        write_retries: 0,
+        write_chunks_failed: 0,
+        buses_write_failed: 0,
+        serialize_failures: 0,
+        last_message_unix_ms: None,
+        last_error: None,
+        reload_interval_seconds: None,
+        stages: Vec::new(),
+    }
+}
+
+// Bounds for the adaptive onFts-reload interval, from RELOAD_INTERVAL_{MIN,MAX}_SECONDS.
+#[derive(Debug, Clone, Copy)]
+struct ReloadIntervalBounds {
+    min_seconds: u64,
+    max_seconds: u64,
+}
+
+fn reload_interval_bounds_from_env() -> ReloadIntervalBounds {
+    let min_seconds = env_or(
+        "RELOAD_INTERVAL_MIN_SECONDS",
+        DEFAULT_RELOAD_INTERVAL_MIN_SECONDS,
+    )
+    .max(1);
+    let max_seconds = env_or(
+        "RELOAD_INTERVAL_MAX_SECONDS",
+        DEFAULT_RELOAD_INTERVAL_MAX_SECONDS,
+    )
+    .max(min_seconds);
+    ReloadIntervalBounds {
+        min_seconds,
+        max_seconds,
+    }
+}
+
+// The wait before the next reload, from what the feed did since the last one. Data arriving
+// cleanly shortens it by a quarter so positions stay fresh; silence or a burst of undecodable
+// payloads means the upstream is struggling, and doubling backs off instead of piling on.
+fn next_reload_interval_seconds(
+    current_seconds: u64,
+    messages: u64,
+    decode_failures: u64,
+    bounds: ReloadIntervalBounds,
+) -> u64 {
+    let unstable = messages == 0
+        || decode_failures as f64 > messages as f64 * RELOAD_UNSTABLE_DECODE_FAILURE_RATIO;
+    let next_seconds = if unstable {
+        current_seconds.saturating_mul(2)
+    } else {
+        current_seconds - current_seconds / 4
+    };
+    next_seconds.clamp(bounds.min_seconds, bounds.max_seconds)
+}
+
+pub(crate) async fn run_bus_ingestor(state: AppState) {
+    let mut backoff_seconds: u64 = 1;
+    // Raw socket frames are appended here when set, in the format `replay` reads back.
+    let feed_recorder = env::var("FEED_RECORD_PATH")
+        .ok()
+        .filter(|value| !value.trim().is_empty())
+        .and_then(|path| {
+            match std::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&path)
+            {
+                Ok(file) => {
+                    println!("Recording feed frames to {}", path);
+                    Some(Arc::new(std::sync::Mutex::new(file)))
+                }
+                Err(error) => {
+                    println!("Feed recording disabled, cannot open '{}': {}", path, error);
+                    None
+                }
+            }
+        });
+    let stages = Arc::new(default_ingest_stages());
+    let reload_bounds = reload_interval_bounds_from_env();
+
+    loop {
+        let redis_conn = match state.redis_client.get_multiplexed_async_connection().await {
+            Ok(connection) => connection,
+            Err(error) => {
+                record_ingestor_error(
+                    &state,
+                    format!("Redis connection failed before socket connect: {}", error),
+                    true,
+                )
+                .await;
+                tokio::time::sleep(Duration::from_secs(backoff_seconds)).await;
+                backoff_seconds = (backoff_seconds * 2).min(30);
+                continue;
+            }
+        };
+
+        let disconnect_notify = Arc::new(Notify::new());
+        let on_any_state = state.clone();
+        let on_any_conn = redis_conn.clone();
+        let on_any_recorder = feed_recorder.clone();
+        let on_any_stages = stages.clone();
+
+        let on_any = move |_event: rust_socketio::Event,
+                           payload: Payload,
+                           _socket: rust_socketio::asynchronous::Client| {
+            let state = on_any_state.clone();
+            let redis_conn = on_any_conn.clone();
+            let recorder = on_any_recorder.clone();
+            let stages = on_any_stages.clone();
+            async move {
+                let now_ms = state.clock.now_ms();
+                if let Some(recorder) = &recorder {
+                    record_feed_frame(recorder, &payload, now_ms);
+                }
+                // Read per payload so a reloaded feed's shapes apply without reconnecting.
+                let gtfs = current_gtfs_feed(&state);
+                ingest_payload(
+                    &state,
+                    &redis_conn,
+                    &gtfs.route_geometries,
+                    &stages,
+                    payload,
+                    now_ms,
+                )
+                .await;
+            }
+            .boxed()
+        };
+
+        let disconnect_state = state.clone();
+        let disconnect_signal = disconnect_notify.clone();
+        let disconnect_state_for_error = state.clone();
+        let disconnect_signal_for_error = disconnect_notify.clone();
+
+        let socket = ClientBuilder::new(state.city.socket_url.as_str())
+            .transport_type(TransportType::Websocket)
+            .on_any(on_any)
+            .on("disconnect", move |_, _| {
+                let state = disconnect_state.clone();
+                let notify = disconnect_signal.clone();
+                async move {
+                    {
+                        let mut status = state.ingestor_status.write().await;
+                        status.connected = false;
+                        status.reload_interval_seconds = None;
+                        status.last_error = Some("Socket disconnected".to_string());
+                        status.reconnect_count += 1;
+                    }
+                    notify.notify_one();
+                }
+                .boxed()
+            })
+            .on("error", move |_, _| {
+                let state = disconnect_state_for_error.clone();
+                let notify = disconnect_signal_for_error.clone();
+                async move {
+                    {
+                        let mut status = state.ingestor_status.write().await;
+                        status.connected = false;
+                        status.reload_interval_seconds = None;
+                        status.last_error = Some("Socket error event".to_string());
+                        status.reconnect_count += 1;
+                    }
+                    notify.notify_one();
+                }
+                .boxed()
+            })
+            .connect()
+            .await;
+
+        match socket {
+            Ok(socket) => {
+                if let Err(error) = emit_reload_for_providers(&socket, &state.city).await {
+                    record_ingestor_error(
+                        &state,
+                        format!("Socket subscribe emit failed: {}", error),
+                        true,
+                    )
+                    .await;
+                    tokio::time::sleep(Duration::from_secs(backoff_seconds)).await;
+                    backoff_seconds = (backoff_seconds * 2).min(30);
+                    continue;
+                }
+
+                let mut reload_interval_seconds = DEFAULT_RELOAD_INTERVAL_SECONDS
+                    .clamp(reload_bounds.min_seconds, reload_bounds.max_seconds);
+                let (mut messages_seen, mut decode_failures_seen) = {
+                    let mut status = state.ingestor_status.write().await;
+                    status.connected = true;
+                    status.last_error = None;
+                    status.reload_interval_seconds = Some(reload_interval_seconds);
+                    (status.messages_processed, status.decode_failures)
+                };
+
+                backoff_seconds = 1;
+
+                loop {
+                    tokio::select! {
+                        _ = disconnect_notify.notified() => {
+                            break;
+                        }
+                        _ = tokio::time::sleep(Duration::from_secs(reload_interval_seconds)) => {
+                            {
+                                let mut status = state.ingestor_status.write().await;
+                                reload_interval_seconds = next_reload_interval_seconds(
+                                    reload_interval_seconds,
+                                    status.messages_processed.saturating_sub(messages_seen),
+                                    status.decode_failures.saturating_sub(decode_failures_seen),
+                                    reload_bounds,
+                                );
+                                status.reload_interval_seconds = Some(reload_interval_seconds);
+                                messages_seen = status.messages_processed;
+                                decode_failures_seen = status.decode_failures;
+                            }
+                            if let Err(error) = emit_reload_for_providers(&socket, &state.city).await {
+                                record_ingestor_error(
+                                    &state,
+                                    format!("Periodic socket reload emit failed: {}", error),
+                                    true,
+                                )
+                                .await;
+                                break;
+                            }
+                        }
+                    }
+                }
+                drop(socket);
+            }
+            Err(error) => {
+                record_ingestor_error(&state, format!("Socket connection failed: {}", error), true)
+                    .await;
+                tokio::time::sleep(Duration::from_secs(backoff_seconds)).await;
+                backoff_seconds = (backoff_seconds * 2).min(30);
+            }
+        }
+    }
+}
+
+// Run one socket payload through the ingest pipeline. Shared by the live ingestor and `replay`,
+// so a recorded frame goes through exactly the same stages as it did when it was received.
+async fn ingest_payload(
+    state: &AppState,
+    redis_conn: &redis::aio::MultiplexedConnection,
+    route_geometries: &HashMap<String, RouteGeometry>,
+    stages: &[Box<dyn IngestStage>],
+    payload: Payload,
+    now_ms: i64,
+) {
+    let batch = IngestBatch {
+        received_at_unix_ms: now_ms,
+        payload: Some(payload),
+        ..IngestBatch::default()
+    };
+    ingest_batch(state, redis_conn, route_geometries, stages, batch).await;
+}
+
+// Runs a batch through the pipeline and folds the outcome into the ingestor status. Returns
+// how many buses were stored.
+async fn ingest_batch(
+    state: &AppState,
+    redis_conn: &redis::aio::MultiplexedConnection,
+    route_geometries: &HashMap<String, RouteGeometry>,
+    stages: &[Box<dyn IngestStage>],
+    mut batch: IngestBatch,
+) -> usize {
+    let now_ms = batch.received_at_unix_ms;
+    let context = IngestContext {
+        state,
+        redis_conn: redis_conn.clone(),
+        route_geometries,
+        thresholds: *state.thresholds.read().await,
+    };
+    let pipeline_started_at = Instant::now();
+    let result = run_ingest_pipeline(stages, &context, &mut batch).await;
+    // Measured from the batch's receive time rather than the clock, so a replay reports the
+    // latency the recorded feed had.
+    let published_at_ms = now_ms + pipeline_started_at.elapsed().as_millis() as i64;
+    let stored_bus_nos: HashSet<&str> = batch
+        .store
+        .stored
+        .iter()
+        .map(|(bus_no, _)| bus_no.as_str())
+        .collect();
+    for bus in &batch.buses {
+        if !stored_bus_nos.contains(bus.bus_no.as_str()) {
+            continue;
+        }
+        if let Some(fix_ms) = bus.dt_gps.as_deref().and_then(parse_dt_gps_ms) {
+            state
+                .gps_to_redis_seconds
+                .observe_ms(published_at_ms - fix_ms);
+        }
+    }
+    let outside_count = batch
+        .buses
+        .iter()
+        .filter(|bus| bus.outside_service_area)
+        .count();
+
+    {
+        let mut status = state.ingestor_status.write().await;
+        status.messages_processed += 1;
+        status.last_message_unix_ms = Some(now_ms);
+        status.decode_failures += batch.dead_letters.len() as u64;
+        status.duplicate_buses_merged += batch.merged_count as u64;
+        status.outside_service_area_positions += outside_count as u64;
+        record_stage_runs(&mut status.stages, &batch.stage_runs);
+        let store = &batch.store;
+        status.buses_written += store.stored.len() as u64;
+        status.write_retries += u64::from(store.retries);
+        status.write_chunks_failed += store.failed_chunks as u64;
+        status.buses_write_failed += store.failed_bus_count as u64;
+        status.serialize_failures += store.serialize_failures as u64;
+        match &result {
+            Ok(()) if store.failed_chunks > 0 => {
+                status.last_error = Some(format!(
+                    "Redis write failed for {} chunks ({} buses): {}",
+                    store.failed_chunks,
+                    store.failed_bus_count,
+                    store.last_error.as_deref().unwrap_or("unknown error")
+                ));
+            }
+            Ok(()) if !store.stored.is_empty() => status.last_error = None,
+            Ok(()) => {}
+            // Every built-in stage that can fail does so on a Redis call.
+            Err(error) => {
+                status.redis_write_failures += 1;
+                status.last_error = Some(format!(
+                    "Ingest {} stage failed: {}",
+                    error.stage, error.message
+                ));
+            }
+        }
+    }
+    if let Err(error) = &result {
+        sentry::capture_message(
+            &format!("Ingestor {} stage failed: {}", error.stage, error.message),
+            sentry::Level::Error,
+        );
+    }
+
+    if !batch.dead_letters.is_empty() {
+        let mut samples = state.dead_letters.write().await;
+        for sample in batch.dead_letters {
+            if samples.len() >= MAX_DEAD_LETTER_SAMPLES {
+                samples.pop_front();
+            }
+            samples.push_back(sample);
+        }
+    }
+    batch.store.stored.len()
+}
+
+fn record_feed_frame(recorder: &std::sync::Mutex<File>, payload: &Payload, now_ms: i64) {
+    let Payload::Text(values) = payload else {
+        return;
+    };
+    let frame = RecordedFeedFrame {
+        received_at_unix_ms: now_ms,
+        payload: values
+            .iter()
+            .filter_map(|value| value.as_str().map(str::to_string))
+            .collect(),
+    };
+    if frame.payload.is_empty() {
+        return;
+    }
+    let Ok(line) = serde_json::to_string(&frame) else {
+        return;
+    };
+    if let Ok(mut file) = recorder.lock() {
+        if let Err(error) = writeln!(file, "{}", line) {
+            println!("Failed to record feed frame: {}", error);
+        }
+    }
+}
+
+pub(crate) fn parse_replay_args(args: &[String]) -> Result<ReplayArgs, String> {
+    let mut from = None;
+    let mut speed = Some(1.0);
+    let mut redis_url = None;
+    let mut flush_db = false;
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--from" => from = iter.next().cloned(),
+            "--speed" => {
+                let value = iter.next().ok_or("--speed needs a value")?;
+                speed = match value.trim().to_lowercase().trim_end_matches('x') {
+                    "max" => None,
+                    multiplier => {
+                        let multiplier = multiplier
+                            .parse::<f64>()
+                            .map_err(|_| format!("Invalid --speed '{}'", value))?;
+                        (multiplier > 0.0).then_some(multiplier)
+                    }
+                };
+            }
+            "--redis" => redis_url = iter.next().cloned(),
+            "--flush-db" => flush_db = true,
+            other => return Err(format!("Unknown replay argument '{}'", other)),
+        }
+    }
+    Ok(ReplayArgs {
+        from: from.ok_or(
+            "usage: be replay --from <file|-> [--speed 10x|max] [--redis <url>] [--flush-db]",
+        )?,
+        speed,
+        redis_url,
+        flush_db,
+    })
+}
+
+// Each frame is stored with its recorded receive time, so replaying the same file into a
+// flushed database always produces the same keys and scores.
+pub(crate) async fn run_replay(state: AppState, args: ReplayArgs) -> Result<(), String> {
+    let reader: Box<dyn std::io::BufRead> = if args.from == "-" {
+        Box::new(std::io::BufReader::new(std::io::stdin()))
+    } else {
+        let file = File::open(&args.from)
+            .map_err(|error| format!("Cannot open '{}': {}", args.from, error))?;
+        Box::new(std::io::BufReader::new(file))
+    };
+    let mut redis_conn = state
+        .redis_client
+        .get_multiplexed_async_connection()
+        .await
+        .map_err(|error| error.to_string())?;
+    let stages = default_ingest_stages();
+    if args.flush_db {
+        redis::cmd("FLUSHDB")
+            .query_async::<()>(&mut redis_conn)
+            .await
+            .map_err(|error| error.to_string())?;
+        println!("Flushed target Redis database before replay");
+    }
+    let gtfs = current_gtfs_feed(&state);
+
+    let mut previous_received_ms: Option<i64> = None;
+    let mut frame_count = 0u64;
+    let mut skipped_lines = 0u64;
+    for line in std::io::BufRead::lines(reader) {
+        let line = line.map_err(|error| error.to_string())?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let Ok(frame) = serde_json::from_str::<RecordedFeedFrame>(&line) else {
+            skipped_lines += 1;
+            continue;
+        };
+
+        if let (Some(speed), Some(previous_ms)) = (args.speed, previous_received_ms) {
+            let gap_ms = (frame.received_at_unix_ms - previous_ms).clamp(0, MAX_REPLAY_GAP_MS);
+            tokio::time::sleep(Duration::from_millis((gap_ms as f64 / speed) as u64)).await;
+        }
+        previous_received_ms = Some(frame.received_at_unix_ms);
+
+        let payload = Payload::Text(
+            frame
+                .payload
+                .into_iter()
+                .map(serde_json::Value::String)
+                .collect(),
+        );
+        ingest_payload(
+            &state,
+            &redis_conn,
+            &gtfs.route_geometries,
+            &stages,
+            payload,
+            frame.received_at_unix_ms,
+        )
+        .await;
+        frame_count += 1;
+    }
+
+    let status = state.ingestor_status.read().await;
+    println!(
+        "Replayed {} frames ({} unreadable lines skipped): {} buses written, {} decode failures, {} Redis write failures",
+        frame_count,
+        skipped_lines,
+        status.buses_written,
+        status.decode_failures,
+        status.redis_write_failures
+    );
+    Ok(())
+}
+
+// Reads Polygon and MultiPolygon features from a GeoJSON FeatureCollection; the feature's
+// "name" property labels the area.
+pub(crate) fn load_geofences(path: &str) -> Result<Vec<NamedGeofence>, LoadError> {
+    let mut contents = String::new();
+    File::open(path)?.read_to_string(&mut contents)?;
+    let collection: serde_json::Value = serde_json::from_str(&contents)?;
+    let features = collection["features"]
+        .as_array()
+        .ok_or("expected a GeoJSON FeatureCollection")?;
+
+    let mut geofences = Vec::new();
+    for (index, feature) in features.iter().enumerate() {
+        let geometry = &feature["geometry"];
+        let polygons = match geometry["type"].as_str() {
+            Some("Polygon") => vec![parse_geojson_polygon(&geometry["coordinates"])?],
+            Some("MultiPolygon") => geometry["coordinates"]
+                .as_array()
+                .ok_or("MultiPolygon without coordinates")?
+                .iter()
+                .map(parse_geojson_polygon)
+                .collect::<Result<Vec<_>, _>>()?,
+            other => {
+                return Err(
+                    format!("feature {} has unsupported geometry {:?}", index, other).into(),
+                )
+            }
+        };
+        let name = feature["properties"]["name"]
+            .as_str()
+            .map(str::to_string)
+            .unwrap_or_else(|| format!("area-{}", index + 1));
+        geofences.push(NamedGeofence { name, polygons });
+    }
+    println!("Loaded {} geofences from {}", geofences.len(), path);
+    Ok(geofences)
+}
+
+fn parse_geojson_polygon(coordinates: &serde_json::Value) -> Result<GeoPolygon, String> {
+    let rings = coordinates
+        .as_array()
+        .ok_or("Polygon without coordinates")?
+        .iter()
+        .map(|ring| {
+            ring.as_array()
+                .ok_or("ring is not an array")?
+                .iter()
+                .map(
+                    |position| match (position[0].as_f64(), position[1].as_f64()) {
+                        (Some(lon), Some(lat)) => Ok((lon, lat)),
+                        _ => Err("position is not [lon, lat]"),
+                    },
+                )
+                .collect::<Result<Vec<_>, _>>()
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    if rings.first().is_none_or(|outer| outer.len() < 3) {
+        return Err("polygon needs an outer ring of at least 3 positions".to_string());
+    }
+    Ok(GeoPolygon { rings })
+}
+
+pub(crate) fn find_geofence(
+    geofences: &[NamedGeofence],
+    lat: f64,
+    lon: f64,
+) -> Option<&NamedGeofence> {
+    geofences.iter().find(|geofence| {
+        geofence
+            .polygons
+            .iter()
+            .any(|polygon| polygon_contains(polygon, lat, lon))
+    })
+}
+
+fn polygon_contains(polygon: &GeoPolygon, lat: f64, lon: f64) -> bool {
+    let mut rings = polygon.rings.iter();
+    let Some(outer) = rings.next() else {
+        return false;
+    };
+    ring_contains(outer, lat, lon) && !rings.any(|hole| ring_contains(hole, lat, lon))
+}
+
+// Even-odd ray casting; fine at depot/city scale where edges are effectively straight.
+fn ring_contains(ring: &[(f64, f64)], lat: f64, lon: f64) -> bool {
+    if ring.len() < 3 {
+        return false;
+    }
+    let mut inside = false;
+    let mut previous = ring.len() - 1;
+    for current in 0..ring.len() {
+        let (x1, y1) = ring[current];
+        let (x2, y2) = ring[previous];
+        if (y1 > lat) != (y2 > lat) && lon < (x2 - x1) * (lat - y1) / (y2 - y1) + x1 {
+            inside = !inside;
+        }
+        previous = current;
+    }
+    inside
+}
+
+// Roster CSV columns: bus_no,model,depot,capacity,wheelchair_lift. Keys go through the same
+// canonicalization as live bus numbers so the two always line up.
+pub(crate) fn load_vehicle_roster(
+    path: &str,
+    rules: &BusNoRules,
+) -> Result<HashMap<String, VehicleInfo>, LoadError> {
+    let file = File::open(path)?;
+    let mut rdr = csv::ReaderBuilder::new()
+        .has_headers(true)
+        .trim(csv::Trim::All)
+        .from_reader(file);
+    let mut roster = HashMap::new();
+    for result in rdr.deserialize() {
+        let row: VehicleRosterRow = result?;
+        let bus_no = canonical_bus_no(&row.bus_no, rules);
+        if bus_no.is_empty() {
+            continue;
+        }
+        let non_empty = |value: Option<String>| value.filter(|value| !value.is_empty());
+        roster.insert(
+            bus_no,
+            VehicleInfo {
+                model: non_empty(row.model),
+                depot: non_empty(row.depot),
+                capacity: row.capacity,
+                wheelchair_lift: row.wheelchair_lift.as_deref().and_then(parse_flag),
+            },
+        );
+    }
+    println!("Loaded vehicle roster with {} vehicles", roster.len());
+    Ok(roster)
+}
+
+pub(crate) fn attach_vehicle_info(
+    mut bus: BusPosition,
+    roster: &HashMap<String, VehicleInfo>,
+) -> BusPosition {
+    bus.vehicle = roster.get(&bus.bus_no).cloned();
+    bus
+}
+
+pub(crate) fn attach_bus_freshness(
+    mut bus: BusPosition,
+    last_seen_unix_ms: &HashMap<String, i64>,
+    now_ms: i64,
+    bus_ttl_ms: i64,
+) -> BusPosition {
+    bus.last_seen_unix_ms = last_seen_unix_ms.get(&bus.bus_no).copied();
+    bus.expires_in_seconds = bus
+        .last_seen_unix_ms
+        .map(|seen_ms| (seen_ms + bus_ttl_ms - now_ms).max(0) / 1_000);
+    bus
+}
+
+pub(crate) fn bus_no_rules_from_env() -> BusNoRules {
+    let canonical = |value: &str| -> String {
+        value
+            .chars()
+            .filter(|ch| !ch.is_whitespace())
+            .collect::<String>()
+            .to_uppercase()
+    };
+    let strip_prefixes = env::var("BUS_NO_STRIP_PREFIXES")
+        .unwrap_or_default()
+        .split(',')
+        .map(canonical)
+        .filter(|prefix| !prefix.is_empty())
+        .collect();
+    // BUS_NO_ALIASES=ALIAS=CANONICAL,... for ids that no generic rule can reconcile.
+    let aliases = env::var("BUS_NO_ALIASES")
+        .unwrap_or_default()
+        .split(',')
+        .filter_map(|pair| pair.split_once('='))
+        .map(|(alias, target)| (canonical(alias), canonical(target)))
+        .filter(|(alias, target)| !alias.is_empty() && !target.is_empty())
+        .collect();
+    BusNoRules {
+        strip_prefixes,
+        aliases,
+    }
+}
+
+pub(crate) fn canonical_bus_no(raw_bus_no: &str, rules: &BusNoRules) -> String {
+    let mut canonical: String = raw_bus_no
+        .chars()
+        .filter(|ch| !ch.is_whitespace())
+        .collect::<String>()
+        .to_uppercase();
+    if let Some(rest) = rules
+        .strip_prefixes
+        .iter()
+        .find_map(|prefix| canonical.strip_prefix(prefix.as_str()))
+        .filter(|rest| !rest.is_empty())
+    {
+        canonical = rest.to_string();
+    }
+    rules.aliases.get(&canonical).cloned().unwrap_or(canonical)
+}
+
+// Collapses ghost duplicates: ids that canonicalize to the same bus keep the freshest report,
+// and different ids on the same route at identical coordinates with the same GPS timestamp are
+// treated as one vehicle. Returns the survivors and how many reports were merged away.
+pub(crate) fn reconcile_duplicate_buses(
+    buses: Vec<BusPosition>,
+    rules: &BusNoRules,
+) -> (Vec<BusPosition>, usize) {
+    let input_count = buses.len();
+    let mut reconciled: Vec<BusPosition> = Vec::with_capacity(input_count);
+    let mut index_by_bus_no: HashMap<String, usize> = HashMap::new();
+
+    for mut bus in buses {
+        bus.bus_no = canonical_bus_no(&bus.bus_no, rules);
+        match index_by_bus_no.get(&bus.bus_no) {
+            Some(&index) => {
+                if bus.dt_gps >= reconciled[index].dt_gps {
+                    reconciled[index] = bus;
+                }
+            }
+            None => {
+                index_by_bus_no.insert(bus.bus_no.clone(), reconciled.len());
+                reconciled.push(bus);
+            }
+        }
+    }
+
+    let mut seen_fixes: HashSet<(String, i64, i64, String)> = HashSet::new();
+    reconciled.retain(|bus| {
+        // Without a GPS timestamp or a real fix there is nothing to call simultaneous.
+        let Some(dt_gps) = bus.dt_gps.as_ref().filter(|value| !value.is_empty()) else {
+            return true;
+        };
+        if bus.latitude == 0.0 && bus.longitude == 0.0 {
+            return true;
+        }
+        seen_fixes.insert((
+            normalize_route_code(&bus.route),
+            (bus.latitude * 100_000.0).round() as i64,
+            (bus.longitude * 100_000.0).round() as i64,
+            dt_gps.clone(),
+        ))
+    });
+
+    let merged_count = input_count - reconciled.len();
+    (reconciled, merged_count)
+}
+
+// dt_gps is Kuala Lumpur wall-clock time, "YYYY-MM-DD HH:MM:SS".
+fn parse_dt_gps_ms(dt_gps: &str) -> Option<i64> {
+    let kl_offset = FixedOffset::east_opt(KL_UTC_OFFSET_SECONDS)?;
+    let local = chrono::NaiveDateTime::parse_from_str(dt_gps.trim(), "%Y-%m-%d %H:%M:%S").ok()?;
+    kl_offset
+        .from_local_datetime(&local)
+        .single()
+        .map(|time| time.timestamp_millis())
+}
+
+pub(crate) fn dead_letter_sample(now_ms: i64, reason: &str, payload: &str) -> DeadLetterSample {
+    DeadLetterSample {
+        received_at_unix_ms: now_ms,
+        reason: reason.to_string(),
+        payload_preview: payload.chars().take(DEAD_LETTER_PREVIEW_CHARS).collect(),
+    }
+}
+
+// One reload request per provider the city serves: its own, then each extra feed's.
+async fn emit_reload_for_providers(
+    socket: &rust_socketio::asynchronous::Client,
+    city: &CityConfig,
+) -> Result<(), rust_socketio::Error> {
+    let providers = std::iter::once(city.provider.as_str()).chain(
+        city.extra_gtfs_feeds
+            .iter()
+            .map(|extra_feed| extra_feed.provider.as_str()),
+    );
+    for provider in providers {
+        let payload = json!({
+            "sid": "",
+            "uid": "",
+            "provider": provider,
+            "route": ""
+        });
+        socket.emit("onFts-reload", payload).await?;
+    }
+    Ok(())
+}
+
+// Buses reported under an extra feed's provider get that feed's "{provider}:" prefix on their
+// route and stop, so they match the merged GTFS ids (see prefix_gtfs_ids).
+pub(crate) fn prefix_extra_feed_ids(mut bus: BusPosition, city: &CityConfig) -> BusPosition {
+    let Some(extra_feed) = city.extra_gtfs_feeds.iter().find(|extra_feed| {
+        extra_feed
+            .provider
+            .eq_ignore_ascii_case(bus.provider.trim())
+    }) else {
+        return bus;
+    };
+    let prefixed = |id: &str| format!("{}:{}", extra_feed.provider, id.trim());
+    if !bus.route.trim().is_empty() {
+        bus.route = prefixed(&bus.route);
+    }
+    bus.busstop_id = bus
+        .busstop_id
+        .as_deref()
+        .filter(|stop_id| !stop_id.trim().is_empty())
+        .map(prefixed);
+    bus
+}
+
+pub(crate) fn normalize_trip_metadata(mut bus: BusPosition) -> BusPosition {
+    let trip_no = bus
+        .trip_no
+        .as_deref()
+        .map(str::trim)
+        .filter(|value| !value.is_empty())
+        .map(str::to_string);
+    let trip_rev_kind = bus
+        .trip_rev_kind
+        .as_deref()
+        .map(str::trim)
+        .filter(|value| !value.is_empty())
+        .map(str::to_string);
+    let direction = match trip_rev_kind.as_deref().map(str::to_uppercase).as_deref() {
+        Some("0" | "F" | "FW" | "FWD" | "FORWARD" | "O" | "OUTBOUND") => TripDirection::Outbound,
+        Some("1" | "R" | "RV" | "REV" | "REVERSE" | "I" | "INBOUND") => TripDirection::Inbound,
+        _ => TripDirection::Unknown,
+    };
+
+    bus.trip = Some(TripMetadata {
+        trip_no,
+        direction,
+        trip_rev_kind,
+    });
+    bus
+}
+
+pub(crate) fn warmup_from_env(started_at_unix_ms: i64) -> Warmup {
+    Warmup {
+        started_at_unix_ms,
+        min_duration_ms: env_or("WARMUP_MIN_SECONDS", DEFAULT_WARMUP_MIN_SECONDS).max(0) * 1_000,
+        min_batches: env_or("WARMUP_MIN_BATCHES", DEFAULT_WARMUP_MIN_BATCHES),
+        gtfs_only: env_or("WARMUP_GTFS_ONLY", false),
+    }
+}
+
+// Fixture mode serves a complete recorded frame and never warms up.
+pub(crate) async fn is_warming_up(state: &AppState) -> bool {
+    if state.fixture.is_some() {
+        return false;
+    }
+    let warmup = state.warmup;
+    state.clock.now_ms() - warmup.started_at_unix_ms < warmup.min_duration_ms
+        || state.ingestor_status.read().await.messages_processed < warmup.min_batches
+}
+
+pub(crate) fn privacy_settings_from_env() -> PrivacySettings {
+    let captain_id = match env::var("CAPTAIN_ID_PRIVACY")
+        .unwrap_or_default()
+        .trim()
+        .to_lowercase()
+        .as_str()
+    {
+        "hash" => CaptainIdPrivacy::Hash,
+        _ => CaptainIdPrivacy::Strip,
+    };
+    let hash_salt = env::var("PRIVACY_HASH_SALT")
+        .ok()
+        .filter(|value| !value.is_empty())
+        .unwrap_or_else(|| {
+            if captain_id == CaptainIdPrivacy::Hash {
+                println!("PRIVACY_HASH_SALT is not set; captain_id hashes will change on restart");
+            }
+            format!("rapidbro-{}", now_unix_ms())
+        });
+
+    PrivacySettings {
+        captain_id,
+        hash_salt,
+        retain_raw_captain_id: env_or("RETAIN_RAW_CAPTAIN_ID", false),
+    }
+}
+
+fn pseudonymize_captain_id(raw_captain_id: &str, salt: &str) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(salt.as_bytes()).expect("HMAC accepts keys of any length");
+    mac.update(raw_captain_id.as_bytes());
+    let digest = mac.finalize().into_bytes();
+    let hex: String = digest
+        .iter()
+        .take(8)
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+    format!("{}{}", CAPTAIN_ID_HASH_PREFIX, hex)
+}
+
+// captain_id identifies the driver: strip it, or replace it with a salted pseudonym that still
+// lets consumers tell shifts apart. Already-pseudonymized values pass through unchanged.
+pub(crate) fn apply_captain_id_privacy(
+    mut bus: BusPosition,
+    privacy: &PrivacySettings,
+) -> BusPosition {
+    bus.captain_id = match (privacy.captain_id, bus.captain_id.take()) {
+        (CaptainIdPrivacy::Hash, Some(captain_id)) if !captain_id.trim().is_empty() => {
+            if captain_id.starts_with(CAPTAIN_ID_HASH_PREFIX) {
+                Some(captain_id)
+            } else {
+                Some(pseudonymize_captain_id(
+                    captain_id.trim(),
+                    &privacy.hash_salt,
+                ))
+            }
+        }
+        _ => None,
+    };
+    bus
+}
+
+pub(crate) fn parse_bus_positions_from_json(decoded: &str) -> Option<Vec<BusPosition>> {
+    if let Ok(single_bus) = serde_json::from_str::<BusPosition>(decoded) {
+        return Some(vec![single_bus]);
+    }
+
+    if let Ok(bus_list) = serde_json::from_str::<Vec<BusPosition>>(decoded) {
+        return Some(bus_list);
+    }
+
+    let value = serde_json::from_str::<serde_json::Value>(decoded).ok()?;
+    if let serde_json::Value::Array(entries) = value {
+        let buses: Vec<BusPosition> = entries
+            .into_iter()
+            .filter_map(|entry| serde_json::from_value::<BusPosition>(entry).ok())
+            .collect();
+
+        if buses.is_empty() {
+            None
+        } else {
+            Some(buses)
+        }
+    } else {
+        None
+    }
+}
+
+async fn record_ingestor_error(state: &AppState, message: String, count_reconnect: bool) {
+    sentry::capture_message(&message, sentry::Level::Warning);
+    let mut status = state.ingestor_status.write().await;
+    status.connected = false;
+    status.last_error = Some(message);
+    if count_reconnect {
+        status.reconnect_count += 1;
+    }
+}
+
+pub(crate) async fn run_service_alert_poller(state: AppState, feed_url: String) {
+    let mut poll_interval =
+        tokio::time::interval(Duration::from_secs(SERVICE_ALERTS_POLL_INTERVAL_SECONDS));
+    poll_interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
+
+    println!("Polling service alerts from {}", feed_url);
+    loop {
+        poll_interval.tick().await;
+        if let Err(error) = poll_service_alerts(&state, &feed_url).await {
+            println!("Service alert poll failed: {}", error);
+        }
+    }
+}
+
+async fn poll_service_alerts(state: &AppState, feed_url: &str) -> Result<(), String> {
+    let body = reqwest::get(feed_url)
+        .await
+        .and_then(|response| response.error_for_status())
+        .map_err(|error| error.to_string())?
+        .bytes()
+        .await
+        .map_err(|error| error.to_string())?;
+    let feed = gtfs_realtime::FeedMessage::decode(body).map_err(|error| error.to_string())?;
+
+    let mut redis_conn = state
+        .redis_client
+        .get_multiplexed_async_connection()
+        .await
+        .map_err(|error| error.to_string())?;
+    let existing: HashMap<String, String> = redis::cmd("HGETALL")
+        .arg(state.redis_keys.key(REDIS_SERVICE_ALERTS_KEY))
+        .query_async(&mut redis_conn)
+        .await
+        .map_err(|error| error.to_string())?;
+
+    let now_ms = state.clock.now_ms();
+    let mut pipe = redis::pipe();
+    let mut current_ids = HashSet::new();
+    for entity in feed.entity {
+        if entity.is_deleted.unwrap_or(false) {
+            continue;
+        }
+        let Some(alert) = entity.alert else {
+            continue;
+        };
+        let previous = existing
+            .get(&entity.id)
+            .and_then(|value| serde_json::from_str::<ServiceAlert>(value).ok());
+        let header = translated_text(&alert.header_text).unwrap_or_else(|| entity.id.clone());
+        let description = translated_text(&alert.description_text);
+        let active_period = alert.active_period.first();
+
+        let mut service_alert = ServiceAlert {
+            id: entity.id.clone(),
+            header,
+            description,
+            url: translated_text(&alert.url),
+            route_ids: alert
+                .informed_entity
+                .iter()
+                .filter_map(|selector| selector.route_id.clone())
+                .collect(),
+            stop_ids: alert
+                .informed_entity
+                .iter()
+                .filter_map(|selector| selector.stop_id.clone())
+                .collect(),
+            active_from_unix_ms: active_period
+                .and_then(|period| period.start)
+                .map(|seconds| seconds as i64 * 1_000),
+            active_until_unix_ms: active_period
+                .and_then(|period| period.end)
+                .map(|seconds| seconds as i64 * 1_000),
+            first_seen_unix_ms: previous
+                .as_ref()
+                .map(|alert| alert.first_seen_unix_ms)
+                .unwrap_or(now_ms),
+            updated_at_unix_ms: now_ms,
+        };
+        // Only bump `updated` when the alert text actually changes, so feed readers don't
+        // resurface unchanged entries on every poll.
+        if let Some(previous) = previous.filter(|previous| {
+            previous.header == service_alert.header
+                && previous.description == service_alert.description
+        }) {
+            service_alert.updated_at_unix_ms = previous.updated_at_unix_ms;
+        }
+
+        let Ok(value) = serde_json::to_string(&service_alert) else {
+            continue;
+        };
+        current_ids.insert(entity.id.clone());
+        pipe.cmd("HSET")
+            .arg(state.redis_keys.key(REDIS_SERVICE_ALERTS_KEY))
+            .arg(&entity.id)
+            .arg(value)
+            .ignore();
+    }
+
+    // The hash mirrors the upstream feed; alerts that disappear upstream are over.
+    for id in existing.keys().filter(|id| !current_ids.contains(*id)) {
+        pipe.cmd("HDEL")
+            .arg(state.redis_keys.key(REDIS_SERVICE_ALERTS_KEY))
+            .arg(id)
+            .ignore();
+    }
+    pipe.query_async::<()>(&mut redis_conn)
+        .await
+        .map_err(|error| error.to_string())
+}
+
+// Prefer the English translation when the feed provides several.
+fn translated_text(text: &Option<gtfs_realtime::TranslatedString>) -> Option<String> {
+    let translations = &text.as_ref()?.translation;
+    translations
+        .iter()
+        .find(|translation| translation.language.as_deref() == Some("en"))
+        .or_else(|| translations.first())
+        .map(|translation| translation.text.trim().to_string())
+        .filter(|text| !text.is_empty())
+}
+
+// Decode base64 + gzip compressed data from the websocket
+pub(crate) fn decode_bus_data(encoded: &str) -> Option<String> {
+    let decoded = base64::engine::general_purpose::STANDARD
+        .decode(encoded)
+        .ok()?;
+
+    let mut decoder = GzDecoder::new(&decoded[..]);
+    let mut decompressed = String::new();
+    decoder.read_to_string(&mut decompressed).ok()?;
+
+    Some(decompressed)
+}
+
+pub(crate) fn gtfs_rt_refresh_seconds() -> u64 {
+    env::var("GTFS_RT_REFRESH_SECONDS")
+        .ok()
+        .and_then(|value| value.parse::<u64>().ok())
+        .filter(|seconds| *seconds > 0)
+        .unwrap_or(DEFAULT_GTFS_RT_REFRESH_SECONDS)
+}
+
+pub(crate) async fn run_gtfs_rt_refresher(state: AppState, feed_url: String) {
+    let client = match reqwest::Client::builder()
+        .user_agent(concat!("rapidbro/", env!("CARGO_PKG_VERSION")))
+        .timeout(Duration::from_secs(GTFS_RT_REQUEST_TIMEOUT_SECONDS))
+        .build()
+    {
+        Ok(client) => client,
+        Err(error) => {
+            println!("GTFS-RT proxy disabled, failed to build client: {}", error);
+            return;
+        }
+    };
+    let mut refresh_interval =
+        tokio::time::interval(Duration::from_secs(gtfs_rt_refresh_seconds()));
+    refresh_interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
+
+    let seed_enabled = env_or("GTFS_RT_SEED", true);
+    let stages = default_ingest_stages();
+
+    println!("Refreshing GTFS-RT vehicle positions from {}", feed_url);
+    loop {
+        refresh_interval.tick().await;
+        if let Err(error) = refresh_gtfs_rt_cache(&state, &client, &feed_url).await {
+            println!("GTFS-RT refresh failed: {}", error);
+            state.gtfs_rt_cache.write().await.last_error = Some(error.to_string());
+            continue;
+        }
+        if seed_enabled {
+            match seed_from_gtfs_rt_if_empty(&state, &stages).await {
+                Ok(0) => {}
+                Ok(seeded) => println!("Seeded {} buses from GTFS-RT vehicle positions", seeded),
+                Err(error) => println!("GTFS-RT seeding failed: {}", error),
+            }
+        }
+    }
+}
+
+// The first refresh runs at startup, so after a restart the API has positions within seconds
+// instead of waiting for the socket. Only an empty snapshot is seeded: once the socket feed is
+// live its positions win. A vehicle whose plate differs from its socket bus_no shows twice
+// until the seeded copy expires.
+async fn seed_from_gtfs_rt_if_empty(
+    state: &AppState,
+    stages: &[Box<dyn IngestStage>],
+) -> Result<usize, String> {
+    let now_ms = state.clock.now_ms();
+    let mut redis_conn = state
+        .redis_client
+        .get_multiplexed_async_connection()
+        .await
+        .map_err(|error| error.to_string())?;
+    let active_bus_count: usize = redis::cmd("ZCOUNT")
+        .arg(state.redis_keys.key(REDIS_BUSES_LAST_SEEN_KEY))
+        .arg(now_ms - state.bus_ttl_ms + 1)
+        .arg("+inf")
+        .query_async(&mut redis_conn)
+        .await
+        .map_err(|error| error.to_string())?;
+    if active_bus_count > 0 {
+        return Ok(0);
+    }
+    let Some(body) = state.gtfs_rt_cache.read().await.protobuf.clone() else {
+        return Ok(0);
+    };
+    let feed = gtfs_realtime::FeedMessage::decode(body).map_err(|error| error.to_string())?;
+
+    let buses: Vec<BusPosition> = feed
+        .entity
+        .iter()
+        .filter(|entity| !entity.is_deleted.unwrap_or(false))
+        .filter_map(|entity| entity.vehicle.as_ref())
+        .filter(|vehicle| {
+            // Positions the socket would already have expired are not worth showing.
+            vehicle
+                .timestamp
+                .is_none_or(|seconds| now_ms - (seconds as i64) * 1_000 < state.bus_ttl_ms)
+        })
+        .filter_map(|vehicle| bus_position_from_gtfs_rt(vehicle, &state.city.provider))
+        .collect();
+    if buses.is_empty() {
+        return Ok(0);
+    }
+
+    let batch = IngestBatch {
+        received_at_unix_ms: now_ms,
+        buses,
+        ..IngestBatch::default()
+    };
+    let gtfs = current_gtfs_feed(state);
+    let seeded = ingest_batch(state, &redis_conn, &gtfs.route_geometries, stages, batch).await;
+
+    let mut status = state.ingestor_status.write().await;
+    status.seeded_buses += seeded as u64;
+    status.last_seeded_unix_ms = Some(now_ms);
+    Ok(seeded)
+}
+
+// A GTFS-RT vehicle position in the socket feed's shape, so it can go through the same
+// pipeline. GTFS-RT speed is m/s where the socket reports km/h, and its direction_id maps onto
+// trip_rev_kind.
+fn bus_position_from_gtfs_rt(
+    vehicle: &gtfs_realtime::VehiclePosition,
+    provider: &str,
+) -> Option<BusPosition> {
+    let position = vehicle.position.as_ref()?;
+    let descriptor = vehicle.vehicle.as_ref()?;
+    let bus_no = [&descriptor.license_plate, &descriptor.id, &descriptor.label]
+        .into_iter()
+        .flatten()
+        .map(|value| value.trim())
+        .find(|value| !value.is_empty())?
+        .to_string();
+    let trip = vehicle.trip.as_ref();
+    let route = trip
+        .and_then(|trip| trip.route_id.clone())
+        .filter(|route_id| !route_id.trim().is_empty())?;
+    let kl_offset = FixedOffset::east_opt(KL_UTC_OFFSET_SECONDS)?;
+    let reported_at = vehicle
+        .timestamp
+        .and_then(|seconds| chrono::DateTime::from_timestamp(seconds as i64, 0))
+        .map(|time| {
+            time.with_timezone(&kl_offset)
+                .format("%Y-%m-%d %H:%M:%S")
+                .to_string()
+        });
+
+    Some(normalize_trip_metadata(BusPosition {
+        dt_received: reported_at.clone(),
+        dt_gps: reported_at,
+        latitude: f64::from(position.latitude),
+        longitude: f64::from(position.longitude),
+        dir: None,
+        speed: position
+            .speed
+            .map(|meters_per_second| f64::from(meters_per_second) * 3.6)
+            .unwrap_or(0.0),
+        angle: position.bearing.map(f64::from).unwrap_or(0.0),
+        route,
+        bus_no,
+        trip_no: trip.and_then(|trip| trip.trip_id.clone()),
+        captain_id: None,
+        trip_rev_kind: trip
+            .and_then(|trip| trip.direction_id)
+            .map(|direction_id| direction_id.to_string()),
+        engine_status: 1,
+        accessibility: 0,
+        busstop_id: vehicle.stop_id.clone(),
+        provider: provider.to_string(),
+        trip: None,
+        in_depot: false,
+        depot_name: None,
+        outside_service_area: false,
+        off_route: false,
+        vehicle: None,
+        last_seen_unix_ms: None,
+        expires_in_seconds: None,
+    }))
+}
+
+// Sends the upstream validators back so an unchanged feed costs a 304 instead of a download.
+// A body is only cached once it decodes, so a bad upstream response never replaces a good copy.
+async fn refresh_gtfs_rt_cache(
+    state: &AppState,
+    client: &reqwest::Client,
+    feed_url: &str,
+) -> Result<(), GtfsRtFetchError> {
+    let mut request = client.get(feed_url);
+    {
+        let cache = state.gtfs_rt_cache.read().await;
+        if cache.protobuf.is_some() {
+            if let Some(etag) = &cache.upstream_etag {
+                request = request.header(IF_NONE_MATCH, etag.as_str());
+            }
+            if let Some(last_modified) = &cache.upstream_last_modified {
+                request = request.header(IF_MODIFIED_SINCE, last_modified.as_str());
+            }
+        }
+    }
+
+    let response = request.send().await.map_err(GtfsRtFetchError::Request)?;
+    let status = response.status();
+    if status == reqwest::StatusCode::NOT_MODIFIED {
+        let mut cache = state.gtfs_rt_cache.write().await;
+        cache.fetched_at_unix_ms = Some(state.clock.now_ms());
+        cache.last_error = None;
+        return Ok(());
+    }
+    if !status.is_success() {
+        return Err(GtfsRtFetchError::Status(status.as_u16()));
+    }
+
+    let header_string = |name: HeaderName| {
+        response
+            .headers()
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string)
+    };
+    let upstream_etag = header_string(ETAG);
+    let upstream_last_modified = header_string(LAST_MODIFIED);
+    let body = response.bytes().await.map_err(GtfsRtFetchError::Request)?;
+    let feed =
+        gtfs_realtime::FeedMessage::decode(body.clone()).map_err(GtfsRtFetchError::Decode)?;
+    let mut shaped = HashMap::new();
+    for profile in REDACTION_PROFILES {
+        for precision in POSITION_PRECISIONS {
+            let shape = ResponseShape { profile, precision };
+            let mut shaped_feed = feed.clone();
+            shape_gtfs_rt_feed(&mut shaped_feed, shape);
+            let json_body = serde_json::to_vec(&shaped_feed).map_err(GtfsRtFetchError::Encode)?;
+            shaped.insert(
+                shape,
+                (
+                    Bytes::from(shaped_feed.encode_to_vec()),
+                    Bytes::from(json_body),
+                ),
+            );
+        }
+    }
+
+    let mut hasher = std::collections::hash_map::DefaultHasher::new();
+    std::hash::Hash::hash(&body[..], &mut hasher);
+    let etag = format!("{:016x}", std::hash::Hasher::finish(&hasher));
+
+    let mut cache = state.gtfs_rt_cache.write().await;
+    cache.protobuf = Some(body);
+    cache.shaped = shaped;
+    cache.etag = Some(etag);
+    cache.upstream_etag = upstream_etag;
+    cache.upstream_last_modified = upstream_last_modified;
+    cache.fetched_at_unix_ms = Some(state.clock.now_ms());
+    cache.last_error = None;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use flate2::{write::GzEncoder, Compression};
+
+    use super::*;
+    use crate::test_support::test_bus;
+
+    // What the socket sends: gzip, then base64.
+    fn encode_bus_data(decoded: &str) -> String {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(decoded.as_bytes()).unwrap();
+        base64::engine::general_purpose::STANDARD.encode(encoder.finish().unwrap())
+    }
+
+    fn rules(strip_prefixes: &[&str], aliases: &[(&str, &str)]) -> BusNoRules {
+        BusNoRules {
+            strip_prefixes: strip_prefixes
+                .iter()
+                .map(|prefix| prefix.to_string())
+                .collect(),
+            aliases: aliases
+                .iter()
+                .map(|(from, to)| (from.to_string(), to.to_string()))
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn socket_payload_decodes_to_bus_positions() {
+        let bus = serde_json::to_string(&test_bus("WXY1234", 3.1219, 101.6572, 24.0)).unwrap();
+        let decoded = decode_bus_data(&encode_bus_data(&bus)).unwrap();
+        assert_eq!(decoded, bus);
+
+        let single = parse_bus_positions_from_json(&decoded).unwrap();
+        assert_eq!(single.len(), 1);
+        assert_eq!(single[0].bus_no, "WXY1234");
+
+        // A list with one unreadable entry keeps the rest.
+        let mixed = format!("[{}, {{\"bus_no\": \"WXY9\"}}]", bus);
+        assert_eq!(parse_bus_positions_from_json(&mixed).unwrap().len(), 1);
+        assert!(parse_bus_positions_from_json("[{\"bus_no\": \"WXY9\"}]").is_none());
+
+        assert!(decode_bus_data("not base64!").is_none());
+        // Valid base64 that isn't gzip.
+        assert!(decode_bus_data("aGVsbG8=").is_none());
+    }
+
+    #[test]
+    fn normalize_trip_metadata_maps_direction_codes() {
+        let direction = |trip_rev_kind: Option<&str>| {
+            let mut bus = test_bus("WXY1234", 3.1, 101.7, 20.0);
+            bus.trip_rev_kind = trip_rev_kind.map(str::to_string);
+            normalize_trip_metadata(bus).trip.unwrap().direction
+        };
+        assert_eq!(direction(Some(" f ")), TripDirection::Outbound);
+        assert_eq!(direction(Some("0")), TripDirection::Outbound);
+        assert_eq!(direction(Some("Rev")), TripDirection::Inbound);
+        assert_eq!(direction(Some("1")), TripDirection::Inbound);
+        assert_eq!(direction(Some("X")), TripDirection::Unknown);
+        assert_eq!(direction(None), TripDirection::Unknown);
+
+        let mut bus = test_bus("WXY1234", 3.1, 101.7, 20.0);
+        bus.trip_no = Some("  ".to_string());
+        bus.trip_rev_kind = Some(" R ".to_string());
+        let trip = normalize_trip_metadata(bus).trip.unwrap();
+        assert!(trip.trip_no.is_none());
+        assert_eq!(trip.trip_rev_kind.as_deref(), Some("R"));
+    }
+
+    #[test]
+    fn canonical_bus_no_strips_prefixes_then_applies_aliases() {
+        let rules = rules(&["RKL"], &[("WXY1235", "WXY1234")]);
+        assert_eq!(canonical_bus_no(" wxy 1234 ", &rules), "WXY1234");
+        assert_eq!(canonical_bus_no("RKLWXY1235", &rules), "WXY1234");
+        // A prefix is never stripped down to nothing.
+        assert_eq!(canonical_bus_no("RKL", &rules), "RKL");
+    }
+
+    #[test]
+    fn reconcile_duplicate_buses_keeps_the_freshest_report() {
+        let report = |bus_no: &str, latitude: f64, dt_gps: &str| {
+            let mut bus = test_bus(bus_no, latitude, 101.7, 20.0);
+            bus.dt_gps = Some(dt_gps.to_string());
+            bus
+        };
+        let (buses, merged) = reconcile_duplicate_buses(
+            vec![
+                report("wxy1234", 3.10, "2026-03-02 08:00:05"),
+                report("WXY 1234", 3.11, "2026-03-02 08:00:10"),
+                // A ghost id at the same fix at the same instant.
+                report("GHOST1", 3.11, "2026-03-02 08:00:10"),
+                report("WXY5678", 3.20, "2026-03-02 08:00:10"),
+            ],
+            &rules(&[], &[]),
+        );
+        assert_eq!(merged, 2);
+        let kept: Vec<(&str, f64)> = buses
+            .iter()
+            .map(|bus| (bus.bus_no.as_str(), bus.latitude))
+            .collect();
+        assert_eq!(kept, vec![("WXY1234", 3.11), ("WXY5678", 3.20)]);
+    }
+
+    #[test]
+    fn reload_interval_shortens_on_clean_data_and_backs_off_otherwise() {
+        let bounds = ReloadIntervalBounds {
+            min_seconds: DEFAULT_RELOAD_INTERVAL_MIN_SECONDS,
+            max_seconds: DEFAULT_RELOAD_INTERVAL_MAX_SECONDS,
+        };
+        assert_eq!(next_reload_interval_seconds(40, 100, 0, bounds), 30);
+        assert_eq!(next_reload_interval_seconds(40, 100, 10, bounds), 30);
+        assert_eq!(next_reload_interval_seconds(40, 100, 11, bounds), 80);
+        assert_eq!(next_reload_interval_seconds(40, 0, 0, bounds), 80);
+        assert_eq!(next_reload_interval_seconds(12, 100, 0, bounds), 10);
+        assert_eq!(next_reload_interval_seconds(100, 0, 0, bounds), 120);
+    }
+
+    #[test]
+    fn prefix_extra_feed_ids_only_touches_extra_providers() {
+        let city: CityConfig = serde_json::from_value(json!({
+            "id": "kl",
+            "name": "Kuala Lumpur",
+            "gtfs_data_path": "rapid_kl_data",
+            "socket_url": "wss://example.invalid",
+            "provider": "RKL",
+            "extra_gtfs_feeds": [
+                { "provider": "mrtfeeder", "gtfs_data_path": "gtfs_mrtfeeder" },
+            ],
+        }))
+        .unwrap();
+
+        let mut feeder_bus = test_bus("WXY1234", 3.1, 101.7, 20.0);
+        feeder_bus.provider = "MRTFEEDER".to_string();
+        feeder_bus.route = "T460 ".to_string();
+        feeder_bus.busstop_id = Some(" ".to_string());
+        let feeder_bus = prefix_extra_feed_ids(feeder_bus, &city);
+        assert_eq!(feeder_bus.route, "mrtfeeder:T460");
+        assert!(feeder_bus.busstop_id.is_none());
+
+        let mut city_bus = test_bus("WXY5678", 3.1, 101.7, 20.0);
+        city_bus.busstop_id = Some("1000838".to_string());
+        let city_bus = prefix_extra_feed_ids(city_bus, &city);
+        assert_eq!(city_bus.route, "T789");
+        assert_eq!(city_bus.busstop_id.as_deref(), Some("1000838"));
+    }
+
+    #[test]
+    fn parse_dt_gps_ms_reads_kuala_lumpur_wall_clock() {
+        assert_eq!(
+            parse_dt_gps_ms("2026-03-02 08:00:00"),
+            Some(1_772_409_600_000)
+        );
+        assert_eq!(parse_dt_gps_ms("2026-03-02T08:00:00"), None);
+    }
+
+    #[test]
+    fn polygon_contains_respects_holes() {
+        let polygon = parse_geojson_polygon(&json!([
+            [
+                [101.0, 3.0],
+                [102.0, 3.0],
+                [102.0, 4.0],
+                [101.0, 4.0],
+                [101.0, 3.0]
+            ],
+            [
+                [101.4, 3.4],
+                [101.6, 3.4],
+                [101.6, 3.6],
+                [101.4, 3.6],
+                [101.4, 3.4]
+            ],
+        ]))
+        .unwrap();
+        assert!(polygon_contains(&polygon, 3.2, 101.2));
+        assert!(!polygon_contains(&polygon, 3.5, 101.5));
+        assert!(!polygon_contains(&polygon, 4.5, 101.5));
+        assert!(parse_geojson_polygon(&json!([[[101.0, 3.0], [102.0, 3.0]]])).is_err());
+    }
+
+    #[test]
+    fn gtfs_rt_vehicle_becomes_a_socket_style_position() {
+        let vehicle = gtfs_realtime::VehiclePosition {
+            position: Some(gtfs_realtime::Position {
+                latitude: 3.1219,
+                longitude: 101.6572,
+                speed: Some(10.0),
+                bearing: Some(90.0),
+                ..Default::default()
+            }),
+            vehicle: Some(gtfs_realtime::VehicleDescriptor {
+                id: Some("bus-17".to_string()),
+                license_plate: Some(" ".to_string()),
+                ..Default::default()
+            }),
+            trip: Some(gtfs_realtime::TripDescriptor {
+                route_id: Some("T7890".to_string()),
+                trip_id: Some("T7890-1".to_string()),
+                direction_id: Some(1),
+                ..Default::default()
+            }),
+            timestamp: Some(1_772_409_600),
+            ..Default::default()
+        };
+        let bus = bus_position_from_gtfs_rt(&vehicle, "RKL").unwrap();
+        // A blank plate falls through to the vehicle id.
+        assert_eq!(bus.bus_no, "bus-17");
+        assert!((bus.speed - 36.0).abs() < 1e-3);
+        assert_eq!(bus.dt_gps.as_deref(), Some("2026-03-02 08:00:00"));
+        assert_eq!(bus.trip.unwrap().direction, TripDirection::Inbound);
+
+        let unrouted = gtfs_realtime::VehiclePosition {
+            trip: None,
+            ..vehicle
+        };
+        assert!(bus_position_from_gtfs_rt(&unrouted, "RKL").is_none());
+    }
+}
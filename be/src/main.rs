@@ -1,298 +1,261 @@
-use axum::{
-    extract::{Path, Query, State},
-    http::StatusCode,
-    routing::get,
-    Json, Router,
+mod api;
+mod eta;
+mod gtfs;
+mod ingest;
+mod messages;
+mod models;
+mod pipeline;
+mod store;
+mod telegram;
+#[cfg(test)]
+mod test_support;
+
+use axum::{extract::DefaultBodyLimit, middleware, routing::get, Router};
+use axum_server::{
+    accept::{Accept, DefaultAcceptor},
+    tls_rustls::{RustlsAcceptor, RustlsConfig},
 };
-use base64::Engine;
-use flate2::read::GzDecoder;
-use futures_util::FutureExt;
-use prost::Message;
-use rust_socketio::{asynchronous::ClientBuilder, Payload, TransportType};
-use serde::{Deserialize, Serialize};
-use serde_json::json;
-use std::collections::{HashMap, HashSet};
+use futures_util::future::BoxFuture;
+use hyper_util::{
+    rt::{TokioExecutor, TokioTimer},
+    server::conn::auto::Builder as AutoBuilder,
+};
+use rapidbro_eta::Thresholds;
+use rapidbro_types::{
+    BusEta, IngestEvent, IngestorStatus, RouteDetour, RouteRuntimesResponse, StartupPhase,
+    StartupPhaseTiming, StopClosure, StopIncomingResponse, VehicleInfo,
+};
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::env;
-use std::fs::File;
-use std::io::Read;
-use std::path::Path as StdPath;
-use std::sync::Arc;
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
-use tokio::sync::{Notify, RwLock};
-use tokio::time::MissedTickBehavior;
+use std::net::SocketAddr;
+use std::os::unix::fs::FileTypeExt;
+use std::path::{Path as StdPath, PathBuf};
+use std::pin::Pin;
+use std::sync::{atomic::AtomicU64, Arc};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    sync::{watch, OwnedSemaphorePermit, RwLock, Semaphore},
+    time::MissedTickBehavior,
+};
 use tower_http::cors::{Any, CorsLayer};
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct BusPosition {
-    pub dt_received: Option<String>,
-    pub dt_gps: Option<String>,
-    pub latitude: f64,
-    pub longitude: f64,
-    pub dir: Option<String>,
-    pub speed: f64,
-    pub angle: f64,
-    pub route: String,
-    pub bus_no: String,
-    pub trip_no: Option<String>,
-    pub captain_id: Option<String>,
-    pub trip_rev_kind: Option<String>,
-    pub engine_status: i32,
-    pub accessibility: i32,
-    pub busstop_id: Option<String>,
-    pub provider: String,
-}
-
-// GTFS data structures
-#[derive(Debug, Clone, Serialize, Deserialize)]
-struct Route {
-    route_id: String,
-    agency_id: String,
-    route_short_name: String,
-    route_long_name: String,
-    route_type: u32,
-    route_color: String,
-    route_text_color: String,
-}
-
-#[derive(Debug, Clone, Serialize, Deserialize)]
-struct Trip {
-    route_id: String,
-    service_id: String,
-    trip_id: String,
-    shape_id: String,
-    trip_headsign: Option<String>,
-    direction_id: Option<u32>,
-}
-
-#[derive(Debug, Clone, Serialize, Deserialize)]
-struct StopTime {
-    trip_id: String,
-    arrival_time: String,
-    departure_time: String,
-    stop_id: String,
-    stop_sequence: u32,
-    stop_headsign: Option<String>,
-}
-
-#[derive(Debug, Clone, Serialize, Deserialize)]
-struct Stop {
-    stop_id: String,
-    stop_name: String,
-    stop_desc: String,
-    stop_lat: f64,
-    stop_lon: f64,
-}
-
-#[derive(Debug, Clone, Serialize, Deserialize)]
-struct StopWithDetails {
-    stop_id: String,
-    stop_name: String,
-    stop_desc: String,
-    stop_lat: f64,
-    stop_lon: f64,
-    sequence: u32,
-}
-
-#[derive(Debug, Clone, Serialize, Deserialize)]
-struct RouteStopsResponse {
-    route_id: String,
-    route_short_name: String,
-    route_long_name: String,
-    stops: Vec<StopWithDetails>,
-}
-
-#[derive(Debug, Clone, Serialize, Deserialize)]
-struct ShapePoint {
-    shape_id: String,
-    shape_pt_lat: f64,
-    shape_pt_lon: f64,
-    shape_pt_sequence: u32,
-}
-
-#[derive(Debug, Clone, Serialize)]
-struct RouteShapePoint {
-    lat: f64,
-    lon: f64,
-    sequence: u32,
-}
-
-#[derive(Debug, Clone, Serialize)]
-struct RouteShapeResponse {
-    route_id: String,
-    shape_id: String,
-    points: Vec<RouteShapePoint>,
-}
-
-#[derive(Debug, Deserialize)]
-struct NearestStopQuery {
-    lat: f64,
-    lon: f64,
-}
-
-#[derive(Debug, Serialize)]
-struct NearestStopResponse {
-    stop_id: String,
-    stop_name: String,
-    stop_desc: String,
-    stop_lat: f64,
-    stop_lon: f64,
-    distance_km: f64,
-    distance_meters: f64,
-}
-
-#[derive(Debug, Clone, Serialize)]
-struct StopRouteSummary {
-    route_id: String,
-    route_short_name: String,
-    route_long_name: String,
-}
-
-#[derive(Debug, Serialize)]
-struct StopRoutesResponse {
-    stop_id: String,
-    routes: Vec<StopRouteSummary>,
-}
-
-#[derive(Debug, Serialize)]
-struct ErrorResponse {
-    error: String,
-}
-
-#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
-#[serde(rename_all = "lowercase")]
-enum StopResolutionSource {
-    Live,
-    Derived,
-}
-
-#[derive(Debug, Clone)]
-struct ResolvedCurrentStop {
-    stop_id: String,
-    stop_name: String,
-    sequence: u32,
-    source: StopResolutionSource,
-}
-
-#[derive(Debug, Clone, Serialize)]
-struct BusEta {
-    route_id: String,
-    bus_no: String,
-    current_lat: f64,
-    current_lon: f64,
-    current_stop_id: String,
-    current_stop_name: String,
-    current_sequence: u32,
-    stop_resolution_source: StopResolutionSource,
-    stops_away: u32,
-    distance_km: f64,
-    speed_kmh: f64,
-    eta_minutes: f64,
-}
+use crate::api::{
+    admin_routes, api_key_tiers_from_env, api_routes, coarse_position_tiers_from_env,
+    feature_flags_from_env, get_admin_dashboard, get_bus_resolution_log, get_metrics,
+    get_readiness, get_schema, get_schema_index, latency_budgets_from_env, load_shedder_from_env,
+    mark_deprecated_alias, negotiate_api_version, new_feature_flags, reverse_geocoder_from_env,
+    run_hot_stop_precompute, sentry_request_context, track_latency, LoadShedder, ReverseGeocoder,
+    Singleflight, CURRENT_API_PREFIX, DEFAULT_HOT_STOP_COUNT, FRESHNESS_BUCKETS_SECONDS,
+    MAX_QUERY_LIMIT,
+};
+use crate::eta::{apply_thresholds_patch, thresholds_from_env};
+use crate::gtfs::{
+    city_registry_from_env, fetch_gtfs_static, gtfs_static_client, load_city_gtfs_feed,
+    load_route_groups, parse_bootstrap_args, run_bootstrap, run_gtfs_reloader,
+    run_gtfs_static_refresher, LoadError,
+};
+use crate::ingest::{
+    bus_no_rules_from_env, initial_ingestor_status, load_geofences, load_vehicle_roster,
+    parse_replay_args, privacy_settings_from_env, run_bus_ingestor, run_gtfs_rt_refresher,
+    run_replay, run_service_alert_poller, warmup_from_env, GtfsRtCache,
+};
+use crate::models::{
+    BusNoRules, CityConfig, DeadLetterSample, FeatureFlags, GtfsFeed, HistoryFrame,
+    HistoryFrameCache, HotStopBoards, LatencyHistogram, NamedGeofence, PrivacySettings,
+    RedactionProfile, ReplicaLag, ResolutionLog, RouteStopsCache, ShadowEvaluation,
+    TerminusSchedule, ThresholdsPatch, UsageCounters, Warmup,
+};
+use crate::store::{
+    load_fixture_snapshot, operator_alert_config_from_env, run_active_bus_count_sampler,
+    run_anomaly_detector, run_bus_event_webhook, run_daily_report_generator,
+    run_feature_flag_refresher, run_history_recorder, run_ingest_event_listener,
+    run_replica_lag_monitor, run_runtime_profile_refresher, run_service_change_refresher,
+    run_snapshot_exporter, run_terminus_schedule_refresher, run_usage_flusher,
+    snapshot_export_config_from_env, RedisKeys, DEFAULT_HISTORY_CACHE_BUDGET_MB,
+    DEFAULT_HISTORY_SAMPLE_SECONDS, REDIS_THRESHOLDS_KEY,
+};
+use crate::telegram::run_telegram_bot;
 
 #[derive(Debug, Clone)]
 struct AppState {
+    // Writes always go here; reads too unless a replica is configured.
     redis_client: redis::Client,
+    // Set from REDIS_READ_URL; API reads are served from this replica.
+    redis_read_client: Option<redis::Client>,
+    replica_lag: Arc<RwLock<Option<ReplicaLag>>>,
+    // The city this state serves; every city gets its own state with its own Redis namespace.
+    city: Arc<CityConfig>,
+    // The city's static feed; run_gtfs_reloader swaps in a new one when the files change.
+    gtfs: Arc<std::sync::RwLock<Arc<GtfsFeed>>>,
+    redis_keys: RedisKeys,
     ingestor_status: Arc<RwLock<IngestorStatus>>,
+    thresholds: Arc<RwLock<Thresholds>>,
+    admin_api_key: Option<String>,
+    // Signs pagination cursors; see PageCursor.
+    cursor_secret: Arc<String>,
+    // API key to the redaction profile its responses are shaped with.
+    api_key_tiers: Arc<HashMap<String, RedactionProfile>>,
+    // Tiers served coarse positions whatever precision they ask for.
+    coarse_position_tiers: Arc<HashSet<RedactionProfile>>,
+    privacy: PrivacySettings,
+    dead_letters: Arc<RwLock<VecDeque<DeadLetterSample>>>,
+    active_bus_count_history: Arc<RwLock<VecDeque<(i64, usize)>>>,
+    stop_eta_computed_total: Arc<AtomicU64>,
+    // GPS fix to the position being readable in Redis, and from there to the response that
+    // serves it. Clients don't acknowledge what they receive, so network and render time after
+    // the response is written are not covered.
+    gps_to_redis_seconds: Arc<LatencyHistogram>,
+    redis_to_response_seconds: Arc<LatencyHistogram>,
     bus_ttl_ms: i64,
     stale_after_ms: i64,
+    bus_no_rules: BusNoRules,
+    vehicle_roster: Arc<HashMap<String, VehicleInfo>>,
+    // Group name to canonical member route_ids.
+    route_groups: Arc<HashMap<String, Vec<String>>>,
+    // Recorded runtimes by route_id, refreshed periodically; the ETA fallback speed model.
+    runtime_profiles: Arc<RwLock<HashMap<String, RouteRuntimesResponse>>>,
+    // By route_id, refreshed periodically; when buses waiting at a first stop will leave.
+    terminus_schedules: Arc<RwLock<HashMap<String, TerminusSchedule>>>,
+    feature_flags: Arc<FeatureFlags>,
+    // Declared detours by route_id, including ones not yet in effect; refreshed from Redis.
+    detours: Arc<std::sync::Mutex<HashMap<String, Vec<RouteDetour>>>>,
+    // Declared stop closures by stop_id, likewise.
+    stop_closures: Arc<std::sync::Mutex<HashMap<String, Vec<StopClosure>>>>,
+    shadow_evaluation: Arc<ShadowEvaluation>,
+    usage: Arc<UsageCounters>,
+    hot_stops: Arc<HotStopBoards>,
+    resolution_log: Arc<ResolutionLog>,
+    route_stops_cache: Arc<RouteStopsCache>,
+    // Shared across cities, unlike the other caches, so the budget holds for the whole process.
+    history_frame_cache: Arc<HistoryFrameCache>,
+    depots: Arc<Vec<NamedGeofence>>,
+    // Empty means no service area is configured and nothing is filtered.
+    service_area: Arc<Vec<NamedGeofence>>,
+    reverse_geocoder: Option<Arc<ReverseGeocoder>>,
+    gtfs_rt_cache: Arc<RwLock<GtfsRtCache>>,
+    // Zero disables snapshot history and with it as_of queries.
+    history_retention_ms: i64,
+    // Set in fixture mode: every snapshot read returns this frame instead of touching Redis.
+    fixture: Option<Arc<HistoryFrame>>,
+    clock: Arc<dyn Clock>,
+    warmup: Warmup,
+    // Completed startup phases, in order; see get_readiness.
+    startup_phases: Arc<std::sync::Mutex<Vec<StartupPhaseTiming>>>,
+    load_shedder: LoadShedder,
+    stop_eta_flights: Arc<Singleflight<StopIncomingResponse>>,
+    route_eta_flights: Arc<Singleflight<Vec<BusEta>>>,
+    // Latest ingest event heard on the city's channel from any instance; None until one arrives.
+    ingest_events: Arc<watch::Sender<Option<IngestEvent>>>,
+}
+
+// Source of "now" for staleness, stationary windows, TTL cleanup and ETA math. Live runs use
+// the system clock; fixture mode pins it so every response is reproducible.
+trait Clock: Send + Sync + std::fmt::Debug {
+    fn now_ms(&self) -> i64;
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-struct IngestorStatus {
-    connected: bool,
-    reconnect_count: u64,
-    messages_processed: u64,
-    buses_written: u64,
-    decode_failures: u64,
-    redis_write_failures: u64,
-    last_message_unix_ms: Option<i64>,
-    last_error: Option<String>,
-}
-
-#[derive(Debug, Serialize)]
-struct GetAllMeta {
-    source: &'static str,
-    last_ingest_at_unix_ms: Option<i64>,
-    is_stale: bool,
-    active_bus_count: usize,
-}
+#[derive(Debug)]
+struct SystemClock;
 
-#[derive(Debug, Serialize)]
-struct GetAllResponse {
-    data: Vec<BusPosition>,
-    meta: GetAllMeta,
+impl Clock for SystemClock {
+    fn now_ms(&self) -> i64 {
+        now_unix_ms()
+    }
 }
 
-#[derive(Debug, Clone, Serialize)]
-struct RouteBusPositionResponse {
-    #[serde(flatten)]
-    bus: BusPosition,
-    resolved_stop_id: Option<String>,
-    resolved_stop_name: Option<String>,
-    resolved_stop_sequence: Option<u32>,
-    stop_resolution_source: Option<StopResolutionSource>,
-}
+#[derive(Debug)]
+struct FixedClock(i64);
 
-#[derive(Debug, Serialize)]
-struct StopIncomingMeta {
-    source: &'static str,
-    generated_at_unix_ms: i64,
-    last_ingest_at_unix_ms: Option<i64>,
-    is_stale: bool,
-    active_bus_count: usize,
-    incoming_bus_count: usize,
-    has_incoming_buses: bool,
+impl Clock for FixedClock {
+    fn now_ms(&self) -> i64 {
+        self.0
+    }
 }
 
-#[derive(Debug, Serialize)]
-struct StopIncomingResponse {
-    stop_id: String,
-    stop_name: String,
-    stop_desc: String,
-    data: Vec<BusEta>,
-    meta: StopIncomingMeta,
+enum ListenTarget {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-struct BusMotionState {
-    reference_lat: f64,
-    reference_lon: f64,
-    stationary_since_unix_ms: Option<i64>,
+struct TlsPaths {
+    cert_path: PathBuf,
+    key_path: PathBuf,
+    reload_interval: Duration,
 }
 
-#[derive(Debug)]
-struct RedisBusSnapshot {
-    buses: Vec<BusPosition>,
-    motion_states: HashMap<String, BusMotionState>,
-    active_bus_count: usize,
-    last_ingest_at_unix_ms: Option<i64>,
+struct ServerTuning {
+    max_connections: usize,
+    max_request_body_bytes: usize,
+    http1_header_read_timeout: Duration,
+    http2_keep_alive_interval: Duration,
+    http2_keep_alive_timeout: Duration,
+    http2_max_concurrent_streams: u32,
 }
 
-struct GtfsContext {
-    routes: Vec<Route>,
-    trips_by_route: HashMap<String, Vec<Trip>>,
-    stop_times_by_trip: HashMap<String, Vec<StopTime>>,
-    stops_map: HashMap<String, Stop>,
+struct ListenConfig {
+    target: ListenTarget,
+    tls: Option<TlsPaths>,
 }
 
-const SOCKET_URL: &str = "https://rapidbus-socketio-avl.prasarana.com.my";
-const GTFS_DATA_PATH: &str = "../rapid_kl_data";
-const REDIS_BUSES_LATEST_KEY: &str = "rapidbro:buses:latest";
-const REDIS_BUSES_LAST_SEEN_KEY: &str = "rapidbro:buses:last_seen";
-const REDIS_BUSES_MOTION_KEY: &str = "rapidbro:buses:motion";
-const REDIS_INGEST_LAST_KEY: &str = "rapidbro:ingestor:last_ingest_at";
+const DEFAULT_BIND_ADDR: &str = "0.0.0.0:3030";
+const DEFAULT_TLS_RELOAD_INTERVAL_SECONDS: u64 = 3_600;
+const DEFAULT_MAX_CONNECTIONS: usize = 1_024;
+const DEFAULT_MAX_REQUEST_BODY_BYTES: usize = 64 * 1024;
+const DEFAULT_HTTP1_HEADER_READ_TIMEOUT_SECONDS: u64 = 30;
+const DEFAULT_HTTP2_KEEP_ALIVE_INTERVAL_SECONDS: u64 = 30;
+const DEFAULT_HTTP2_KEEP_ALIVE_TIMEOUT_SECONDS: u64 = 20;
+const DEFAULT_HTTP2_MAX_CONCURRENT_STREAMS: u32 = 256;
 const DEFAULT_REDIS_URL: &str = "redis://127.0.0.1:6379/";
 const DEFAULT_BUS_TTL_SECONDS: i64 = 120;
 const DEFAULT_STALE_AFTER_SECONDS: i64 = 20;
-const MAX_DERIVED_STOP_DISTANCE_KM: f64 = 0.75;
-const STATIONARY_SPEED_THRESHOLD_KMH: f64 = 1.0;
-const STATIONARY_DISTANCE_THRESHOLD_KM: f64 = 0.03;
-const STATIONARY_WINDOW_MS: i64 = 60_000;
-const PANTAI_HILLPARK_PHASE_5_STOP_ID: &str = "1008485";
-
 #[tokio::main]
 async fn main() {
-    let redis_url = env::var("REDIS_URL").unwrap_or_else(|_| DEFAULT_REDIS_URL.to_string());
+    // Error reporting is opt-in; without SENTRY_DSN every sentry call below is a no-op.
+    let _sentry_guard = env::var("SENTRY_DSN")
+        .ok()
+        .filter(|dsn| !dsn.trim().is_empty())
+        .map(|dsn| {
+            sentry::init((
+                dsn,
+                sentry::ClientOptions {
+                    release: sentry::release_name!(),
+                    environment: env::var("SENTRY_ENVIRONMENT").ok().map(Into::into),
+                    ..Default::default()
+                },
+            ))
+        });
+
+    let mut startup_phases = Vec::new();
+    let mut phase_started = Instant::now();
+
+    // `be replay ...` feeds recorded frames through the ingest pipeline instead of serving.
+    let cli_args: Vec<String> = env::args().collect();
+    // `be bootstrap ...` prepares an empty deployment and exits; see run_bootstrap.
+    if cli_args.get(1).map(String::as_str) == Some("bootstrap") {
+        let bootstrap_args =
+            parse_bootstrap_args(&cli_args[2..]).unwrap_or_else(|error| panic!("{}", error));
+        if let Err(error) = run_bootstrap(bootstrap_args).await {
+            panic!("Bootstrap failed: {}", error);
+        }
+        return;
+    }
+    let replay_args = match cli_args.get(1).map(String::as_str) {
+        Some("replay") => {
+            Some(parse_replay_args(&cli_args[2..]).unwrap_or_else(|error| panic!("{}", error)))
+        }
+        _ => None,
+    };
+
+    let listen_config = listen_config_from_env()
+        .unwrap_or_else(|error| panic!("Invalid listen configuration: {}", error));
+    let server_tuning = server_tuning_from_env();
+    let redis_url = replay_args
+        .as_ref()
+        .and_then(|args| args.redis_url.clone())
+        .or_else(|| env::var("REDIS_URL").ok())
+        .unwrap_or_else(|| DEFAULT_REDIS_URL.to_string());
     let bus_ttl_seconds = env::var("BUS_TTL_SECONDS")
         .ok()
         .and_then(|value| value.parse::<i64>().ok())
@@ -301,6 +264,61 @@ async fn main() {
         .ok()
         .and_then(|value| value.parse::<i64>().ok())
         .unwrap_or(DEFAULT_STALE_AFTER_SECONDS);
+    let privacy = privacy_settings_from_env();
+    let bus_no_rules = bus_no_rules_from_env();
+    let depots = match env::var("DEPOT_GEOFENCE_PATH") {
+        Ok(path) if !path.trim().is_empty() => load_geofences(&path)
+            .unwrap_or_else(|error| panic!("Failed to load depot geofences '{}': {}", path, error)),
+        _ => Vec::new(),
+    };
+    let service_area = match env::var("SERVICE_AREA_PATH") {
+        Ok(path) if !path.trim().is_empty() => load_geofences(&path)
+            .unwrap_or_else(|error| panic!("Failed to load service area '{}': {}", path, error)),
+        _ => Vec::new(),
+    };
+    let vehicle_roster = match env::var("VEHICLE_ROSTER_PATH") {
+        Ok(path) if !path.trim().is_empty() => load_vehicle_roster(&path, &bus_no_rules)
+            .unwrap_or_else(|error| panic!("Failed to load vehicle roster '{}': {}", path, error)),
+        _ => HashMap::new(),
+    };
+    let mut cities = city_registry_from_env()
+        .unwrap_or_else(|error| panic!("Failed to load city registry: {}", error));
+    let default_city = cities.remove(0);
+    let route_groups = load_city_route_groups(&default_city);
+    let reverse_geocoder = reverse_geocoder_from_env();
+    let history_retention_hours = env::var("HISTORY_RETENTION_HOURS")
+        .ok()
+        .and_then(|value| value.parse::<i64>().ok())
+        .unwrap_or(0)
+        .max(0);
+    let history_sample_seconds = env::var("HISTORY_SAMPLE_SECONDS")
+        .ok()
+        .and_then(|value| value.parse::<u64>().ok())
+        .filter(|seconds| *seconds > 0)
+        .unwrap_or(DEFAULT_HISTORY_SAMPLE_SECONDS);
+    // Decoded frames kept in memory for as_of queries; 0 turns the cache off.
+    let history_cache_budget_mb =
+        env_or("HISTORY_CACHE_BUDGET_MB", DEFAULT_HISTORY_CACHE_BUDGET_MB);
+    // Top-N most requested stops kept precomputed; 0 turns the worker off.
+    let hot_stop_count = env::var("HOT_STOP_COUNT")
+        .ok()
+        .and_then(|value| value.parse::<usize>().ok())
+        .unwrap_or(DEFAULT_HOT_STOP_COUNT)
+        .min(MAX_QUERY_LIMIT);
+    let admin_api_key = env::var("ADMIN_API_KEY")
+        .ok()
+        .filter(|value| !value.trim().is_empty());
+    // Shared by every instance behind a load balancer, or cursors only work on the one that
+    // issued them.
+    let cursor_secret = env::var("PAGE_CURSOR_SECRET")
+        .ok()
+        .filter(|value| !value.is_empty())
+        .unwrap_or_else(|| {
+            println!(
+                "PAGE_CURSOR_SECRET is not set; pagination cursors will not survive a restart"
+            );
+            format!("rapidbro-cursor-{}", now_unix_ms())
+        });
 
     let cors = CorsLayer::new()
         .allow_origin(Any)
@@ -313,1458 +331,788 @@ async fn main() {
             redis_url, error
         );
     });
-
-    // Fail fast if Redis is unavailable at startup.
-    let mut redis_conn = redis_client
-        .get_multiplexed_async_connection()
-        .await
-        .unwrap_or_else(|error| panic!("Failed to connect to Redis '{}': {}", redis_url, error));
-    let _: String = redis::cmd("PING")
-        .query_async(&mut redis_conn)
-        .await
-        .unwrap_or_else(|error| panic!("Failed to ping Redis '{}': {}", redis_url, error));
-
-    let app_state = AppState {
-        redis_client: redis_client.clone(),
-        ingestor_status: Arc::new(RwLock::new(IngestorStatus {
-            connected: false,
-            reconnect_count: 0,
-            messages_processed: 0,
-            buses_written: 0,
-            decode_failures: 0,
-            redis_write_failures: 0,
-            last_message_unix_ms: None,
-            last_error: None,
-        })),
-        bus_ttl_ms: bus_ttl_seconds * 1_000,
-        stale_after_ms: stale_after_seconds * 1_000,
-    };
-
-    let ingestor_state = app_state.clone();
-    tokio::spawn(async move {
-        run_bus_ingestor(ingestor_state).await;
+    let redis_read_url = env::var("REDIS_READ_URL")
+        .ok()
+        .filter(|value| !value.trim().is_empty());
+    let redis_read_client = redis_read_url.as_ref().map(|url| {
+        redis::Client::open(url.as_str()).unwrap_or_else(|error| {
+            panic!(
+                "Failed to create Redis read client for '{}': {}",
+                url, error
+            )
+        })
     });
-
-    let app = Router::new()
-        .route("/gtfs", get(prasarana_gtfs_data))
-        .route("/get-all", get(fetch_all_buses))
-        .route("/ingestor/status", get(get_ingestor_status))
-        .route("/get-route-t789", get(get_route_t789))
-        .route("/get-t789-eta", get(get_t789_eta))
-        .route(
-            "/get-pantai-hillpark-phase-5-eta",
-            get(get_pantai_hillpark_phase_5_eta),
-        )
-        .route("/route/{route_id}/eta/{stop_id}", get(get_route_eta))
-        .route("/stops/{stop_id}/eta", get(get_stop_eta))
-        .route("/stops/{stop_id}/routes", get(get_stop_routes))
-        .route("/route/{route_id}/stops", get(get_route_stops))
-        .route("/route/{route_id}/shape", get(get_route_shape))
-        .route("/stops/nearest", get(get_nearest_stop))
-        .layer(cors)
-        .with_state(app_state);
-
-    let listener = tokio::net::TcpListener::bind("0.0.0.0:3030").await.unwrap();
-
-    println!("Server is running on http://localhost:3030");
-    axum::serve(listener, app).await.unwrap();
-}
-
-async fn fetch_all_buses(
-    State(state): State<AppState>,
-) -> Result<Json<GetAllResponse>, (StatusCode, Json<ErrorResponse>)> {
-    let snapshot = load_active_bus_snapshot(&state).await?;
-    let now_ms = now_unix_ms();
-    let is_stale = match snapshot.last_ingest_at_unix_ms {
-        Some(last_ingest_ms) => now_ms - last_ingest_ms > state.stale_after_ms,
-        None => true,
+    let redis_keys = RedisKeys {
+        prefix: default_city.redis_key_prefix.clone(),
     };
-
     println!(
-        "Calling fetch_all_buses via Redis: {} active buses",
-        snapshot.buses.len()
+        "Serving city '{}' with Redis key prefix {}:",
+        default_city.id, redis_keys.prefix
     );
-    Ok(Json(GetAllResponse {
-        data: snapshot.buses,
-        meta: GetAllMeta {
-            source: "redis",
-            last_ingest_at_unix_ms: snapshot.last_ingest_at_unix_ms,
-            is_stale,
-            active_bus_count: snapshot.active_bus_count,
-        },
-    }))
-}
-
-async fn load_active_bus_snapshot(
-    state: &AppState,
-) -> Result<RedisBusSnapshot, (StatusCode, Json<ErrorResponse>)> {
-    let now_ms = now_unix_ms();
-    let cutoff_ms = now_ms - state.bus_ttl_ms;
-    let mut redis_conn = state
-        .redis_client
-        .get_multiplexed_async_connection()
-        .await
-        .map_err(internal_error)?;
-
-    let stale_bus_ids: Vec<String> = redis::cmd("ZRANGEBYSCORE")
-        .arg(REDIS_BUSES_LAST_SEEN_KEY)
-        .arg("-inf")
-        .arg(cutoff_ms)
-        .query_async(&mut redis_conn)
-        .await
-        .map_err(internal_error)?;
-
-    if !stale_bus_ids.is_empty() {
-        let mut delete_pipe = redis::pipe();
-        delete_pipe
-            .cmd("HDEL")
-            .arg(REDIS_BUSES_LATEST_KEY)
-            .arg(&stale_bus_ids)
-            .ignore();
-        delete_pipe
-            .cmd("HDEL")
-            .arg(REDIS_BUSES_MOTION_KEY)
-            .arg(&stale_bus_ids)
-            .ignore();
-        delete_pipe
-            .cmd("ZREMRANGEBYSCORE")
-            .arg(REDIS_BUSES_LAST_SEEN_KEY)
-            .arg("-inf")
-            .arg(cutoff_ms)
-            .ignore();
-        delete_pipe
-            .query_async::<()>(&mut redis_conn)
-            .await
-            .map_err(internal_error)?;
-    }
 
-    let active_bus_ids: Vec<String> = redis::cmd("ZRANGEBYSCORE")
-        .arg(REDIS_BUSES_LAST_SEEN_KEY)
-        .arg(cutoff_ms + 1)
-        .arg("+inf")
-        .query_async(&mut redis_conn)
-        .await
-        .map_err(internal_error)?;
-
-    let buses: Vec<BusPosition> = if active_bus_ids.is_empty() {
-        Vec::new()
-    } else {
-        let raw_buses: Vec<Option<String>> = redis::cmd("HMGET")
-            .arg(REDIS_BUSES_LATEST_KEY)
-            .arg(&active_bus_ids)
-            .query_async(&mut redis_conn)
-            .await
-            .map_err(internal_error)?;
-
-        raw_buses
-            .into_iter()
-            .flatten()
-            .filter_map(|entry| serde_json::from_str::<BusPosition>(&entry).ok())
-            .collect()
-    };
-
-    let motion_states: HashMap<String, BusMotionState> = if active_bus_ids.is_empty() {
-        HashMap::new()
-    } else {
-        let raw_states: Vec<Option<String>> = redis::cmd("HMGET")
-            .arg(REDIS_BUSES_MOTION_KEY)
-            .arg(&active_bus_ids)
-            .query_async(&mut redis_conn)
-            .await
-            .map_err(internal_error)?;
-
-        active_bus_ids
-            .iter()
-            .cloned()
-            .zip(raw_states.into_iter())
-            .filter_map(|(bus_no, raw_state)| {
-                raw_state.and_then(|value| {
-                    serde_json::from_str::<BusMotionState>(&value)
-                        .ok()
-                        .map(|state| (bus_no, state))
-                })
-            })
-            .collect()
+    // Fixture mode serves a fixed snapshot at a fixed time so endpoint output is byte-for-byte
+    // reproducible; it needs no Redis and starts no background tasks.
+    let fixture = env::var("FIXTURE_SNAPSHOT_PATH")
+        .ok()
+        .filter(|value| !value.trim().is_empty())
+        .map(|path| {
+            let frame = load_fixture_snapshot(&path).unwrap_or_else(|error| {
+                panic!("Failed to load fixture snapshot '{}': {}", path, error)
+            });
+            println!(
+                "Fixture mode: serving {} buses from {}",
+                frame.buses.len(),
+                path
+            );
+            frame
+        });
+    let clock: Arc<dyn Clock> = match env::var("FIXTURE_NOW_MS")
+        .ok()
+        .and_then(|value| value.parse::<i64>().ok())
+        .or_else(|| fixture.as_ref().map(|frame| frame.captured_at_unix_ms))
+    {
+        Some(fixed_ms) => Arc::new(FixedClock(fixed_ms)),
+        None => Arc::new(SystemClock),
     };
 
-    let last_ingest_at_unix_ms: Option<i64> = redis::cmd("GET")
-        .arg(REDIS_INGEST_LAST_KEY)
-        .query_async(&mut redis_conn)
-        .await
-        .unwrap_or(None);
-
-    Ok(RedisBusSnapshot {
-        buses,
-        motion_states,
-        active_bus_count: active_bus_ids.len(),
-        last_ingest_at_unix_ms,
-    })
-}
-
-async fn get_ingestor_status(State(state): State<AppState>) -> Json<IngestorStatus> {
-    Json(state.ingestor_status.read().await.clone())
-}
-
-async fn run_bus_ingestor(state: AppState) {
-    let mut backoff_seconds: u64 = 1;
-
-    loop {
-        let redis_conn = match state.redis_client.get_multiplexed_async_connection().await {
-            Ok(connection) => connection,
-            Err(error) => {
-                record_ingestor_error(
-                    &state,
-                    format!("Redis connection failed before socket connect: {}", error),
-                    true,
-                )
-                .await;
-                tokio::time::sleep(Duration::from_secs(backoff_seconds)).await;
-                backoff_seconds = (backoff_seconds * 2).min(30);
-                continue;
-            }
-        };
-
-        let disconnect_notify = Arc::new(Notify::new());
-        let on_any_state = state.clone();
-        let on_any_conn = redis_conn.clone();
-
-        let on_any = move |_event: rust_socketio::Event,
-                           payload: Payload,
-                           _socket: rust_socketio::asynchronous::Client| {
-            let state = on_any_state.clone();
-            let mut redis_conn = on_any_conn.clone();
-            async move {
-                let now_ms = now_unix_ms();
-                let (buses, decode_failures) = parse_bus_positions_from_payload(payload);
-
-                {
-                    let mut status = state.ingestor_status.write().await;
-                    status.messages_processed += 1;
-                    status.last_message_unix_ms = Some(now_ms);
-                    status.decode_failures += decode_failures;
-                }
-
-                if buses.is_empty() {
-                    return;
-                }
-
-                match write_buses_to_redis(&mut redis_conn, &buses, now_ms).await {
-                    Ok(written_count) => {
-                        let mut status = state.ingestor_status.write().await;
-                        status.buses_written += written_count as u64;
-                        status.last_error = None;
-                    }
-                    Err(error) => {
-                        let mut status = state.ingestor_status.write().await;
-                        status.redis_write_failures += 1;
-                        status.last_error = Some(format!("Redis write failed: {}", error));
-                    }
-                }
-            }
-            .boxed()
-        };
-
-        let disconnect_state = state.clone();
-        let disconnect_signal = disconnect_notify.clone();
-        let disconnect_state_for_error = state.clone();
-        let disconnect_signal_for_error = disconnect_notify.clone();
-
-        let socket = ClientBuilder::new(SOCKET_URL)
-            .transport_type(TransportType::Websocket)
-            .on_any(on_any)
-            .on("disconnect", move |_, _| {
-                let state = disconnect_state.clone();
-                let notify = disconnect_signal.clone();
-                async move {
-                    {
-                        let mut status = state.ingestor_status.write().await;
-                        status.connected = false;
-                        status.last_error = Some("Socket disconnected".to_string());
-                        status.reconnect_count += 1;
-                    }
-                    notify.notify_one();
-                }
-                .boxed()
-            })
-            .on("error", move |_, _| {
-                let state = disconnect_state_for_error.clone();
-                let notify = disconnect_signal_for_error.clone();
-                async move {
-                    {
-                        let mut status = state.ingestor_status.write().await;
-                        status.connected = false;
-                        status.last_error = Some("Socket error event".to_string());
-                        status.reconnect_count += 1;
-                    }
-                    notify.notify_one();
-                }
-                .boxed()
-            })
-            .connect()
-            .await;
-
-        match socket {
-            Ok(socket) => {
-                let payload = json!({
-                    "sid": "",
-                    "uid": "",
-                    "provider": "RKL",
-                    "route": ""
-                });
-                if let Err(error) = socket.emit("onFts-reload", payload).await {
-                    record_ingestor_error(
-                        &state,
-                        format!("Socket subscribe emit failed: {}", error),
-                        true,
-                    )
-                    .await;
-                    tokio::time::sleep(Duration::from_secs(backoff_seconds)).await;
-                    backoff_seconds = (backoff_seconds * 2).min(30);
-                    continue;
-                }
-
-                {
-                    let mut status = state.ingestor_status.write().await;
-                    status.connected = true;
-                    status.last_error = None;
-                }
+    phase_started = finish_startup_phase(
+        &mut startup_phases,
+        StartupPhase::LoadingConfig,
+        phase_started,
+    );
 
-                backoff_seconds = 1;
-                let mut reload_interval = tokio::time::interval(Duration::from_secs(20));
-                reload_interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
-                // Consume immediate first tick so the first periodic reload happens after the interval.
-                reload_interval.tick().await;
-
-                loop {
-                    tokio::select! {
-                        _ = disconnect_notify.notified() => {
-                            break;
-                        }
-                        _ = reload_interval.tick() => {
-                            let payload = json!({
-                                "sid": "",
-                                "uid": "",
-                                "provider": "RKL",
-                                "route": ""
-                            });
-
-                            if let Err(error) = socket.emit("onFts-reload", payload).await {
-                                record_ingestor_error(
-                                    &state,
-                                    format!("Periodic socket reload emit failed: {}", error),
-                                    true,
-                                )
-                                .await;
-                                break;
-                            }
-                        }
-                    }
-                }
-                drop(socket);
-            }
-            Err(error) => {
-                record_ingestor_error(&state, format!("Socket connection failed: {}", error), true)
-                    .await;
-                tokio::time::sleep(Duration::from_secs(backoff_seconds)).await;
-                backoff_seconds = (backoff_seconds * 2).min(30);
+    let mut gtfs_feeds: HashMap<String, Arc<GtfsFeed>> = HashMap::new();
+    for city in std::iter::once(&default_city).chain(cities.iter()) {
+        // A failed download still starts on whatever feed is already on disk.
+        if let Some(feed_url) = &city.gtfs_static_url {
+            let fetched = match gtfs_static_client() {
+                Ok(client) => fetch_gtfs_static(&client, feed_url, &city.gtfs_data_path).await,
+                Err(error) => Err(LoadError::Invalid(error.to_string())),
+            };
+            match fetched {
+                Ok(true) => println!(
+                    "Downloaded GTFS static feed for city '{}' from {}",
+                    city.id, feed_url
+                ),
+                Ok(false) => println!(
+                    "GTFS static feed for city '{}' is unchanged at {}",
+                    city.id, feed_url
+                ),
+                Err(error) => println!(
+                    "Failed to download GTFS static feed for city '{}', using {}: {}",
+                    city.id,
+                    city.gtfs_data_path.display(),
+                    error
+                ),
             }
         }
+        let feed = load_city_gtfs_feed(city).unwrap_or_else(|error| {
+            panic!(
+                "Failed to load GTFS for city '{}' from {}: {}",
+                city.id,
+                city.gtfs_data_path.display(),
+                error
+            )
+        });
+        println!(
+            "Loaded GTFS for city '{}': {} routes, {} stops",
+            city.id,
+            feed.routes.len(),
+            feed.stops_map.len()
+        );
+        gtfs_feeds.insert(city.id.clone(), Arc::new(feed));
     }
-}
+    phase_started = finish_startup_phase(
+        &mut startup_phases,
+        StartupPhase::LoadingGtfs,
+        phase_started,
+    );
 
-async fn write_buses_to_redis(
-    redis_conn: &mut redis::aio::MultiplexedConnection,
-    buses: &[BusPosition],
-    now_ms: i64,
-) -> Result<usize, String> {
-    let mut serialized_entries: Vec<(String, String)> = Vec::new();
-    let valid_buses: HashMap<String, &BusPosition> = buses
-        .iter()
-        .filter(|bus| !bus.bus_no.is_empty())
-        .map(|bus| (bus.bus_no.clone(), bus))
-        .collect();
-    let bus_ids: Vec<String> = valid_buses.keys().cloned().collect();
-
-    let previous_motion_states: HashMap<String, BusMotionState> = if bus_ids.is_empty() {
-        HashMap::new()
-    } else {
-        let raw_states: Vec<Option<String>> = redis::cmd("HMGET")
-            .arg(REDIS_BUSES_MOTION_KEY)
-            .arg(&bus_ids)
-            .query_async(redis_conn)
+    let mut redis_conn = None;
+    if fixture.is_none() {
+        // Fail fast if Redis is unavailable at startup.
+        let mut conn = redis_client
+            .get_multiplexed_async_connection()
             .await
-            .map_err(|error| error.to_string())?;
-
-        bus_ids
-            .iter()
-            .cloned()
-            .zip(raw_states.into_iter())
-            .filter_map(|(bus_no, raw_state)| {
-                raw_state.and_then(|value| {
-                    serde_json::from_str::<BusMotionState>(&value)
-                        .ok()
-                        .map(|state| (bus_no, state))
-                })
-            })
-            .collect()
-    };
-
-    for bus in buses {
-        if bus.bus_no.is_empty() {
-            continue;
-        }
-
-        if let Ok(serialized_bus) = serde_json::to_string(bus) {
-            serialized_entries.push((bus.bus_no.clone(), serialized_bus));
-        }
-    }
-
-    if serialized_entries.is_empty() {
-        return Ok(0);
-    }
-
-    let mut pipe = redis::pipe();
-    for (bus_no, bus_json) in &serialized_entries {
-        let Some(bus) = valid_buses.get(bus_no) else {
-            continue;
-        };
-        let motion_state = update_bus_motion_state(previous_motion_states.get(bus_no), bus, now_ms);
-
-        pipe.cmd("HSET")
-            .arg(REDIS_BUSES_LATEST_KEY)
-            .arg(bus_no)
-            .arg(bus_json)
-            .ignore();
-        pipe.cmd("HSET")
-            .arg(REDIS_BUSES_MOTION_KEY)
-            .arg(bus_no)
-            .arg(serde_json::to_string(&motion_state).map_err(|error| error.to_string())?)
-            .ignore();
-        pipe.cmd("ZADD")
-            .arg(REDIS_BUSES_LAST_SEEN_KEY)
-            .arg(now_ms)
-            .arg(bus_no)
-            .ignore();
-    }
-
-    pipe.cmd("SET")
-        .arg(REDIS_INGEST_LAST_KEY)
-        .arg(now_ms)
-        .ignore();
-
-    pipe.query_async::<()>(redis_conn)
-        .await
-        .map_err(|error| error.to_string())?;
-
-    Ok(serialized_entries.len())
-}
-
-fn parse_bus_positions_from_payload(payload: Payload) -> (Vec<BusPosition>, u64) {
-    let mut buses = Vec::new();
-    let mut decode_failures = 0;
-
-    if let Payload::Text(values) = payload {
-        for value in values {
-            let Some(encoded_str) = value.as_str() else {
-                continue;
-            };
-
-            let Some(decoded) = decode_bus_data(encoded_str) else {
-                decode_failures += 1;
-                continue;
-            };
-
-            match parse_bus_positions_from_json(&decoded) {
-                Some(mut parsed_buses) => buses.append(&mut parsed_buses),
-                None => decode_failures += 1,
-            }
+            .unwrap_or_else(|error| {
+                panic!("Failed to connect to Redis '{}': {}", redis_url, error)
+            });
+        let _: String = redis::cmd("PING")
+            .query_async(&mut conn)
+            .await
+            .unwrap_or_else(|error| panic!("Failed to ping Redis '{}': {}", redis_url, error));
+        redis_conn = Some(conn);
+
+        if let (Some(read_client), Some(read_url)) = (&redis_read_client, &redis_read_url) {
+            let mut read_conn = read_client
+                .get_multiplexed_async_connection()
+                .await
+                .unwrap_or_else(|error| {
+                    panic!(
+                        "Failed to connect to Redis replica '{}': {}",
+                        read_url, error
+                    )
+                });
+            let _: String = redis::cmd("PING")
+                .query_async(&mut read_conn)
+                .await
+                .unwrap_or_else(|error| {
+                    panic!("Failed to ping Redis replica '{}': {}", read_url, error)
+                });
+            println!("Serving reads from Redis replica {}", read_url);
         }
     }
-
-    (buses, decode_failures)
-}
-
-fn parse_bus_positions_from_json(decoded: &str) -> Option<Vec<BusPosition>> {
-    if let Ok(single_bus) = serde_json::from_str::<BusPosition>(decoded) {
-        return Some(vec![single_bus]);
+    if fixture.is_none() {
+        phase_started = finish_startup_phase(
+            &mut startup_phases,
+            StartupPhase::VerifyingRedis,
+            phase_started,
+        );
     }
+    let thresholds = load_persisted_thresholds(redis_conn.as_mut(), &redis_keys).await;
 
-    if let Ok(bus_list) = serde_json::from_str::<Vec<BusPosition>>(decoded) {
-        return Some(bus_list);
-    }
+    let warmup = warmup_from_env(clock.now_ms());
+    let app_state = AppState {
+        redis_client: redis_client.clone(),
+        redis_read_client,
+        replica_lag: Arc::new(RwLock::new(None)),
+        gtfs: Arc::new(std::sync::RwLock::new(gtfs_feeds[&default_city.id].clone())),
+        city: Arc::new(default_city),
+        redis_keys,
+        ingestor_status: Arc::new(RwLock::new(initial_ingestor_status())),
+        thresholds: Arc::new(RwLock::new(thresholds)),
+        admin_api_key,
+        cursor_secret: Arc::new(cursor_secret),
+        api_key_tiers: Arc::new(api_key_tiers_from_env()),
+        coarse_position_tiers: Arc::new(coarse_position_tiers_from_env()),
+        privacy,
+        dead_letters: Arc::new(RwLock::new(VecDeque::new())),
+        active_bus_count_history: Arc::new(RwLock::new(VecDeque::new())),
+        stop_eta_computed_total: Arc::new(AtomicU64::new(0)),
+        gps_to_redis_seconds: Arc::new(LatencyHistogram::new(&FRESHNESS_BUCKETS_SECONDS)),
+        redis_to_response_seconds: Arc::new(LatencyHistogram::new(&FRESHNESS_BUCKETS_SECONDS)),
+        bus_ttl_ms: bus_ttl_seconds * 1_000,
+        stale_after_ms: stale_after_seconds * 1_000,
+        vehicle_roster: Arc::new(vehicle_roster),
+        route_groups: Arc::new(route_groups),
+        runtime_profiles: Arc::new(RwLock::new(HashMap::new())),
+        terminus_schedules: Arc::new(RwLock::new(HashMap::new())),
+        feature_flags: Arc::new(feature_flags_from_env()),
+        detours: Arc::new(std::sync::Mutex::new(HashMap::new())),
+        stop_closures: Arc::new(std::sync::Mutex::new(HashMap::new())),
+        shadow_evaluation: Arc::new(ShadowEvaluation::default()),
+        usage: Arc::new(UsageCounters::default()),
+        hot_stops: Arc::new(HotStopBoards::default()),
+        resolution_log: Arc::new(ResolutionLog::default()),
+        route_stops_cache: Arc::new(RouteStopsCache::default()),
+        history_frame_cache: Arc::new(HistoryFrameCache {
+            budget_bytes: history_cache_budget_mb.saturating_mul(1024 * 1024),
+            ..HistoryFrameCache::default()
+        }),
+        depots: Arc::new(depots),
+        service_area: Arc::new(service_area),
+        reverse_geocoder: reverse_geocoder.map(Arc::new),
+        gtfs_rt_cache: Arc::new(RwLock::new(GtfsRtCache::default())),
+        history_retention_ms: history_retention_hours * 3_600_000,
+        fixture: fixture.map(Arc::new),
+        clock,
+        warmup,
+        startup_phases: Arc::new(std::sync::Mutex::new(startup_phases)),
+        load_shedder: load_shedder_from_env(),
+        stop_eta_flights: Arc::new(Singleflight::new()),
+        route_eta_flights: Arc::new(Singleflight::new()),
+        ingest_events: Arc::new(watch::channel(None).0),
+        bus_no_rules,
+    };
 
-    let value = serde_json::from_str::<serde_json::Value>(decoded).ok()?;
-    if let serde_json::Value::Array(entries) = value {
-        let buses: Vec<BusPosition> = entries
-            .into_iter()
-            .filter_map(|entry| serde_json::from_value::<BusPosition>(entry).ok())
-            .collect();
-
-        if buses.is_empty() {
-            None
-        } else {
-            Some(buses)
+    if let Some(replay_args) = replay_args {
+        if let Err(error) = run_replay(app_state, replay_args).await {
+            panic!("Replay failed: {}", error);
         }
-    } else {
-        None
+        return;
     }
-}
 
-async fn record_ingestor_error(state: &AppState, message: String, count_reconnect: bool) {
-    let mut status = state.ingestor_status.write().await;
-    status.connected = false;
-    status.last_error = Some(message);
-    if count_reconnect {
-        status.reconnect_count += 1;
+    // The default city comes first; the rest of the registry shares its deployment settings.
+    let mut city_states = vec![app_state.clone()];
+    for city in cities {
+        let keys = RedisKeys {
+            prefix: city.redis_key_prefix.clone(),
+        };
+        let thresholds = load_persisted_thresholds(redis_conn.as_mut(), &keys).await;
+        println!(
+            "Serving city '{}' with Redis key prefix {}:",
+            city.id, keys.prefix
+        );
+        let gtfs = gtfs_feeds[&city.id].clone();
+        city_states.push(city_app_state(&app_state, city, gtfs, thresholds));
     }
-}
-
-fn internal_error(error: impl std::fmt::Display) -> (StatusCode, Json<ErrorResponse>) {
-    (
-        StatusCode::INTERNAL_SERVER_ERROR,
-        Json(ErrorResponse {
-            error: format!("Internal server error: {}", error),
-        }),
-    )
-}
-
-fn is_t789_route(route: &str) -> bool {
-    normalize_route_code(route) == "T789"
-}
 
-fn is_bus_on_route(bus_route: &str, route_id: &str) -> bool {
-    let bus_base = normalize_route_code(bus_route);
-    let route_base = normalize_route_code(route_id);
-    !bus_base.is_empty() && bus_base == route_base
-}
+    if app_state.fixture.is_none() {
+        for city_state in &city_states {
+            let ingestor_state = city_state.clone();
+            tokio::spawn(async move {
+                run_bus_ingestor(ingestor_state).await;
+            });
 
-fn normalize_route_code(route: &str) -> String {
-    route
-        .trim()
-        .to_uppercase()
-        .trim_end_matches('0')
-        .to_string()
-}
+            let ingest_event_state = city_state.clone();
+            tokio::spawn(async move {
+                run_ingest_event_listener(ingest_event_state).await;
+            });
 
-fn now_unix_ms() -> i64 {
-    SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .map(|duration| duration.as_millis() as i64)
-        .unwrap_or(0)
-}
+            let sampler_state = city_state.clone();
+            tokio::spawn(async move {
+                run_active_bus_count_sampler(sampler_state).await;
+            });
 
-// Get buses for route T789 specifically from Redis snapshot
-async fn get_route_t789(
-    State(state): State<AppState>,
-) -> Result<Json<serde_json::Value>, (StatusCode, Json<ErrorResponse>)> {
-    let snapshot = load_active_bus_snapshot(&state).await?;
-    let gtfs = load_gtfs_context()?;
-    let visible_buses = filter_non_stationary_buses(&snapshot);
-    let route_stops = get_stops_by_route(
-        "T7890",
-        &gtfs.routes,
-        &gtfs.trips_by_route,
-        &gtfs.stop_times_by_trip,
-        &gtfs.stops_map,
-    )
-    .map_err(|(status, msg)| (status, Json(ErrorResponse { error: msg })))?;
-    let t789_buses: Vec<RouteBusPositionResponse> = visible_buses
-        .into_iter()
-        .filter(|bus| is_t789_route(&bus.route))
-        .map(|bus| {
-            let resolved_stop = resolve_current_stop(&bus, &route_stops);
-            RouteBusPositionResponse {
-                resolved_stop_id: resolved_stop.as_ref().map(|stop| stop.stop_id.clone()),
-                resolved_stop_name: resolved_stop.as_ref().map(|stop| stop.stop_name.clone()),
-                resolved_stop_sequence: resolved_stop.as_ref().map(|stop| stop.sequence),
-                stop_resolution_source: resolved_stop.map(|stop| stop.source),
-                bus,
+            if city_state.history_retention_ms > 0 {
+                let history_state = city_state.clone();
+                tokio::spawn(async move {
+                    run_history_recorder(history_state, history_sample_seconds).await;
+                });
             }
-        })
-        .collect();
 
-    println!(
-        "Calling get_route_t789 via Redis: {} active buses",
-        t789_buses.len()
-    );
-
-    if t789_buses.len() == 1 {
-        let value = serde_json::to_value(&t789_buses[0]).unwrap_or_else(|_| json!({}));
-        Ok(Json(value))
-    } else {
-        let value = serde_json::to_value(&t789_buses).unwrap_or_else(|_| json!([]));
-        Ok(Json(value))
-    }
-}
+            // Reports walk a whole day of history frames, so they need a day of retention.
+            if city_state.history_retention_ms >= 24 * 3_600_000 {
+                let report_state = city_state.clone();
+                let report_export = if city_state.city.id == app_state.city.id {
+                    snapshot_export_config_from_env()
+                } else {
+                    None
+                };
+                tokio::spawn(async move {
+                    run_daily_report_generator(report_state, report_export).await;
+                });
+            } else {
+                println!(
+                    "Daily route reports for '{}' are off; they need HISTORY_RETENTION_HOURS >= 24",
+                    city_state.city.id
+                );
+            }
 
-// Calculate ETA for T789 buses from Redis snapshot to reach stop 1000838 (KL1397 FLAT PKNS KERINCHI/KL GATEWAY)
-async fn get_t789_eta(
-    State(state): State<AppState>,
-) -> Result<Json<Vec<BusEta>>, (StatusCode, Json<ErrorResponse>)> {
-    const TARGET_STOP_ID: &str = "1000838";
-    let eta_results = calculate_route_eta(&state, "T7890", TARGET_STOP_ID).await?;
-    println!(
-        "Calling get_t789_eta: found {} buses with ETA",
-        eta_results.len()
-    );
-    Ok(Json(eta_results))
-}
+            let runtime_profile_state = city_state.clone();
+            tokio::spawn(async move {
+                run_runtime_profile_refresher(runtime_profile_state).await;
+            });
 
-// Calculate ETA for all incoming buses to Pantai Hillpark Phase 5 (stop 1008485).
-async fn get_pantai_hillpark_phase_5_eta(
-    State(state): State<AppState>,
-) -> Result<Json<StopIncomingResponse>, (StatusCode, Json<ErrorResponse>)> {
-    let snapshot = load_active_bus_snapshot(&state).await?;
-    let gtfs = load_gtfs_context()?;
-    let stop = gtfs
-        .stops_map
-        .get(PANTAI_HILLPARK_PHASE_5_STOP_ID)
-        .ok_or_else(|| {
-            (
-                StatusCode::NOT_FOUND,
-                Json(ErrorResponse {
-                    error: format!(
-                        "Stop '{}' not found in GTFS data",
-                        PANTAI_HILLPARK_PHASE_5_STOP_ID
-                    ),
-                }),
-            )
-        })?;
-    let eta_results =
-        calculate_stop_eta_from_snapshot(&snapshot, &gtfs, PANTAI_HILLPARK_PHASE_5_STOP_ID);
-    let now_ms = now_unix_ms();
-    let is_stale = match snapshot.last_ingest_at_unix_ms {
-        Some(last_ingest_ms) => now_ms - last_ingest_ms > state.stale_after_ms,
-        None => true,
-    };
+            let gtfs_reload_state = city_state.clone();
+            tokio::spawn(async move {
+                run_gtfs_reloader(gtfs_reload_state).await;
+            });
 
-    println!(
-        "Calling get_pantai_hillpark_phase_5_eta: {} incoming buses",
-        eta_results.len()
-    );
+            if let Some(feed_url) = city_state.city.gtfs_static_url.clone() {
+                let gtfs_static_state = city_state.clone();
+                tokio::spawn(async move {
+                    run_gtfs_static_refresher(gtfs_static_state, feed_url).await;
+                });
+            }
 
-    Ok(Json(StopIncomingResponse {
-        stop_id: stop.stop_id.clone(),
-        stop_name: stop.stop_name.clone(),
-        stop_desc: stop.stop_desc.clone(),
-        meta: StopIncomingMeta {
-            source: "redis",
-            generated_at_unix_ms: now_ms,
-            last_ingest_at_unix_ms: snapshot.last_ingest_at_unix_ms,
-            is_stale,
-            active_bus_count: snapshot.active_bus_count,
-            incoming_bus_count: eta_results.len(),
-            has_incoming_buses: !eta_results.is_empty(),
-        },
-        data: eta_results,
-    }))
-}
+            let terminus_state = city_state.clone();
+            tokio::spawn(async move {
+                run_terminus_schedule_refresher(terminus_state).await;
+            });
 
-// Calculate ETA for buses in route/{route_id} to reach stop/{stop_id}, based on Redis snapshot.
-async fn get_route_eta(
-    Path((route_id, stop_id)): Path<(String, String)>,
-    State(state): State<AppState>,
-) -> Result<Json<Vec<BusEta>>, (StatusCode, Json<ErrorResponse>)> {
-    let eta_results = calculate_route_eta(&state, &route_id, &stop_id).await?;
-    println!(
-        "Calling get_route_eta for route_id={}, stop_id={}: {} buses",
-        route_id,
-        stop_id,
-        eta_results.len()
-    );
-    Ok(Json(eta_results))
-}
+            let feature_flag_state = city_state.clone();
+            tokio::spawn(async move {
+                run_feature_flag_refresher(feature_flag_state).await;
+            });
 
-// Calculate ETA for all routes incoming to /stops/{stop_id}
-async fn get_stop_eta(
-    Path(stop_id): Path<String>,
-    State(state): State<AppState>,
-) -> Result<Json<Vec<BusEta>>, (StatusCode, Json<ErrorResponse>)> {
-    let snapshot = load_active_bus_snapshot(&state).await?;
-    let gtfs = load_gtfs_context()?;
-    let all_eta_results = calculate_stop_eta_from_snapshot(&snapshot, &gtfs, &stop_id);
+            let service_change_state = city_state.clone();
+            tokio::spawn(async move {
+                run_service_change_refresher(service_change_state).await;
+            });
 
-    println!(
-        "Calling get_stop_eta for stop_id={}: {} incoming buses",
-        stop_id,
-        all_eta_results.len()
-    );
-    Ok(Json(all_eta_results))
-}
+            if let Some(webhook_url) = env::var("BUS_EVENTS_WEBHOOK_URL")
+                .ok()
+                .filter(|value| !value.trim().is_empty())
+            {
+                let event_state = city_state.clone();
+                let webhook_secret = env::var("BUS_EVENTS_WEBHOOK_SECRET")
+                    .ok()
+                    .filter(|value| !value.trim().is_empty());
+                tokio::spawn(async move {
+                    run_bus_event_webhook(event_state, webhook_url, webhook_secret).await;
+                });
+            }
 
-async fn get_stop_routes(
-    Path(stop_id): Path<String>,
-) -> Result<Json<StopRoutesResponse>, (StatusCode, Json<ErrorResponse>)> {
-    let gtfs = load_gtfs_context()?;
-    let routes = get_routes_for_stop(
-        &stop_id,
-        &gtfs.routes,
-        &gtfs.trips_by_route,
-        &gtfs.stop_times_by_trip,
-        &gtfs.stops_map,
-    )
-    .map_err(|(status, message)| (status, Json(ErrorResponse { error: message })))?;
+            let usage_state = city_state.clone();
+            tokio::spawn(async move {
+                run_usage_flusher(usage_state).await;
+            });
 
-    println!(
-        "Calling get_stop_routes for stop_id={}: {} routes",
-        stop_id,
-        routes.len()
-    );
+            if hot_stop_count > 0 {
+                let hot_stop_state = city_state.clone();
+                tokio::spawn(async move {
+                    run_hot_stop_precompute(hot_stop_state, hot_stop_count).await;
+                });
+            }
 
-    Ok(Json(StopRoutesResponse { stop_id, routes }))
-}
+            if let Some(feed_url) = city_state.city.gtfs_rt_vehicle_positions_url.clone() {
+                let gtfs_rt_state = city_state.clone();
+                tokio::spawn(async move {
+                    run_gtfs_rt_refresher(gtfs_rt_state, feed_url).await;
+                });
+            }
 
-fn calculate_stop_eta_from_snapshot(
-    snapshot: &RedisBusSnapshot,
-    gtfs: &GtfsContext,
-    stop_id: &str,
-) -> Vec<BusEta> {
-    let visible_buses = filter_non_stationary_buses(snapshot);
-    let mut all_eta_results: Vec<BusEta> = Vec::new();
-    let mut seen_bus_route: HashSet<String> = HashSet::new();
-
-    for route in &gtfs.routes {
-        let route_stops = match get_stops_by_route(
-            &route.route_id,
-            &gtfs.routes,
-            &gtfs.trips_by_route,
-            &gtfs.stop_times_by_trip,
-            &gtfs.stops_map,
-        ) {
-            Ok(stops) => stops,
-            Err(_) => continue,
-        };
+            if city_state.redis_read_client.is_some() {
+                let lag_state = city_state.clone();
+                tokio::spawn(async move {
+                    run_replica_lag_monitor(lag_state).await;
+                });
+            }
 
-        if !route_stops.stops.iter().any(|stop| stop.stop_id == stop_id) {
-            continue;
+            let detector_state = city_state.clone();
+            let operator_alerts = operator_alert_config_from_env();
+            tokio::spawn(async move {
+                run_anomaly_detector(detector_state, operator_alerts).await;
+            });
         }
 
-        let route_eta_results = match calculate_route_eta_from_stops(
-            &visible_buses,
-            &route.route_id,
-            stop_id,
-            &route_stops,
-        ) {
-            Ok(results) => results,
-            Err(_) => continue,
-        };
+        // Service alerts, snapshot export and the Telegram bot are wired to the default city.
 
-        for eta in route_eta_results {
-            let key = format!("{}::{}", eta.route_id, eta.bus_no);
-            if seen_bus_route.insert(key) {
-                all_eta_results.push(eta);
-            }
+        // Service alerts are only ingested when an upstream GTFS-RT alerts feed is configured.
+        if let Some(feed_url) = env::var("SERVICE_ALERTS_URL")
+            .ok()
+            .filter(|value| !value.trim().is_empty())
+        {
+            let alerts_state = app_state.clone();
+            tokio::spawn(async move {
+                run_service_alert_poller(alerts_state, feed_url).await;
+            });
         }
-    }
-
-    all_eta_results.sort_by(|a, b| {
-        a.eta_minutes
-            .partial_cmp(&b.eta_minutes)
-            .unwrap_or(std::cmp::Ordering::Equal)
-    });
-
-    all_eta_results
-}
-
-fn update_bus_motion_state(
-    previous_state: Option<&BusMotionState>,
-    bus: &BusPosition,
-    now_ms: i64,
-) -> BusMotionState {
-    let reference_lat = previous_state
-        .map(|state| state.reference_lat)
-        .unwrap_or(bus.latitude);
-    let reference_lon = previous_state
-        .map(|state| state.reference_lon)
-        .unwrap_or(bus.longitude);
-    let distance_from_reference =
-        haversine_distance(bus.latitude, bus.longitude, reference_lat, reference_lon);
-    let is_slow = bus.speed <= STATIONARY_SPEED_THRESHOLD_KMH;
-
-    if distance_from_reference >= STATIONARY_DISTANCE_THRESHOLD_KM {
-        return BusMotionState {
-            reference_lat: bus.latitude,
-            reference_lon: bus.longitude,
-            stationary_since_unix_ms: is_slow.then_some(now_ms),
-        };
-    }
-
-    if is_slow {
-        return BusMotionState {
-            reference_lat,
-            reference_lon,
-            stationary_since_unix_ms: previous_state
-                .and_then(|state| state.stationary_since_unix_ms)
-                .or(Some(now_ms)),
-        };
-    }
-
-    BusMotionState {
-        reference_lat: bus.latitude,
-        reference_lon: bus.longitude,
-        stationary_since_unix_ms: None,
-    }
-}
-
-fn is_bus_stationary(snapshot: &RedisBusSnapshot, bus_no: &str, now_ms: i64) -> bool {
-    snapshot
-        .motion_states
-        .get(bus_no)
-        .and_then(|state| state.stationary_since_unix_ms)
-        .map(|since_ms| now_ms - since_ms >= STATIONARY_WINDOW_MS)
-        .unwrap_or(false)
-}
-
-fn filter_non_stationary_buses(snapshot: &RedisBusSnapshot) -> Vec<BusPosition> {
-    let now_ms = now_unix_ms();
 
-    snapshot
-        .buses
-        .iter()
-        .filter(|bus| !is_bus_stationary(snapshot, &bus.bus_no, now_ms))
-        .cloned()
-        .collect()
-}
+        if let Some(export_config) = snapshot_export_config_from_env() {
+            let export_state = app_state.clone();
+            tokio::spawn(async move {
+                run_snapshot_exporter(export_state, export_config).await;
+            });
+        }
 
-fn resolve_current_stop(
-    bus: &BusPosition,
-    route_stops: &RouteStopsResponse,
-) -> Option<ResolvedCurrentStop> {
-    if let Some(bus_stop_id) = bus.busstop_id.as_ref().filter(|id| !id.is_empty()) {
-        if let Some(stop) = route_stops
-            .stops
-            .iter()
-            .find(|stop| stop.stop_id == *bus_stop_id)
+        // The Telegram bot is opt-in and shares the same Redis snapshot as the HTTP API.
+        if let Some(token) = env::var("TELEGRAM_BOT_TOKEN")
+            .ok()
+            .filter(|value| !value.trim().is_empty())
         {
-            return Some(ResolvedCurrentStop {
-                stop_id: stop.stop_id.clone(),
-                stop_name: stop.stop_name.clone(),
-                sequence: stop.sequence,
-                source: StopResolutionSource::Live,
+            let telegram_state = app_state.clone();
+            tokio::spawn(async move {
+                run_telegram_bot(telegram_state, token).await;
             });
         }
     }
 
-    let nearest_stop = route_stops.stops.iter().min_by(|a, b| {
-        let distance_a = haversine_distance(bus.latitude, bus.longitude, a.stop_lat, a.stop_lon);
-        let distance_b = haversine_distance(bus.latitude, bus.longitude, b.stop_lat, b.stop_lon);
-        distance_a
-            .partial_cmp(&distance_b)
-            .unwrap_or(std::cmp::Ordering::Equal)
-    })?;
-
-    let distance_km = haversine_distance(
-        bus.latitude,
-        bus.longitude,
-        nearest_stop.stop_lat,
-        nearest_stop.stop_lon,
-    );
-
-    if distance_km > MAX_DERIVED_STOP_DISTANCE_KM {
-        return None;
+    if app_state.fixture.is_none() {
+        if let Ok(mut phases) = app_state.startup_phases.lock() {
+            finish_startup_phase(&mut phases, StartupPhase::StartingIngestor, phase_started);
+        }
     }
 
-    Some(ResolvedCurrentStop {
-        stop_id: nearest_stop.stop_id.clone(),
-        stop_name: nearest_stop.stop_name.clone(),
-        sequence: nearest_stop.sequence,
-        source: StopResolutionSource::Derived,
-    })
-}
+    // Readiness covers every city, so /ready gets all of their states.
+    let readiness_states = Arc::new(city_states.clone());
 
-async fn calculate_route_eta(
-    state: &AppState,
-    route_id: &str,
-    target_stop_id: &str,
-) -> Result<Vec<BusEta>, (StatusCode, Json<ErrorResponse>)> {
-    let snapshot = load_active_bus_snapshot(state).await?;
-    let visible_buses = filter_non_stationary_buses(&snapshot);
-    let gtfs = load_gtfs_context()?;
-    let route_stops = get_stops_by_route(
-        route_id,
-        &gtfs.routes,
-        &gtfs.trips_by_route,
-        &gtfs.stop_times_by_trip,
-        &gtfs.stops_map,
-    )
-    .map_err(|(status, msg)| (status, Json(ErrorResponse { error: msg })))?;
-
-    calculate_route_eta_from_stops(&visible_buses, route_id, target_stop_id, &route_stops).map_err(
-        |message| {
-            (
-                StatusCode::NOT_FOUND,
-                Json(ErrorResponse { error: message }),
+    // Every city, the default one included, is also reachable under /v1/{city_id}/....
+    let mut app = Router::new().nest(CURRENT_API_PREFIX, api_routes(&app_state));
+    for city_state in &city_states {
+        app = app.nest(
+            &format!("{}/{}", CURRENT_API_PREFIX, city_state.city.id),
+            api_routes(city_state).with_state(city_state.clone()),
+        );
+    }
+    let mut app = app
+        .merge(api_routes(&app_state).route_layer(middleware::from_fn(mark_deprecated_alias)))
+        .route_layer(middleware::from_fn(negotiate_api_version))
+        .route("/metrics", get(get_metrics))
+        .route("/ready", get(get_readiness).with_state(readiness_states))
+        .route("/schema", get(get_schema_index))
+        .route("/schema/{type_name}", get(get_schema))
+        .route("/admin", get(get_admin_dashboard))
+        .route(
+            "/debug/buses/{bus_no}/resolution-log",
+            get(get_bus_resolution_log),
+        )
+        .nest("/admin", admin_routes());
+    // The operator routes above act on the default city; each city also has its own copy.
+    for city_state in city_states {
+        let city_id = city_state.city.id.clone();
+        app = app
+            .route(
+                &format!("/metrics/{}", city_id),
+                get(get_metrics).with_state(city_state.clone()),
             )
-        },
-    )
-}
-
-fn calculate_route_eta_from_stops(
-    buses: &[BusPosition],
-    route_id: &str,
-    target_stop_id: &str,
-    route_stops: &RouteStopsResponse,
-) -> Result<Vec<BusEta>, String> {
-    const DEFAULT_SPEED_KMH: f64 = 20.0;
-
-    let target_stop = route_stops
-        .stops
-        .iter()
-        .find(|s| s.stop_id == target_stop_id)
-        .ok_or_else(|| {
-            format!(
-                "Target stop '{}' not found in route '{}'",
-                target_stop_id, route_id
+            .route(
+                &format!("/debug/{}/buses/{{bus_no}}/resolution-log", city_id),
+                get(get_bus_resolution_log).with_state(city_state.clone()),
             )
-        })?;
-    let target_sequence = target_stop.sequence;
-
-    let mut eta_results: Vec<BusEta> = Vec::new();
+            .nest(
+                &format!("/admin/{}", city_id),
+                admin_routes().with_state(city_state),
+            );
+    }
+    let app = app
+        .layer(DefaultBodyLimit::max(server_tuning.max_request_body_bytes))
+        .layer(middleware::from_fn(sentry_request_context))
+        .layer(middleware::from_fn_with_state(
+            Arc::new(latency_budgets_from_env()),
+            track_latency,
+        ))
+        .layer(cors)
+        .with_state(app_state);
 
-    for bus in buses
-        .iter()
-        .filter(|bus| is_bus_on_route(&bus.route, route_id))
-    {
-        let resolved_stop = match resolve_current_stop(bus, route_stops) {
-            Some(stop) => stop,
-            None => continue,
-        };
+    match listen_config.target {
+        ListenTarget::Unix(socket_path) => {
+            remove_stale_unix_socket(&socket_path);
+            let listener = tokio::net::UnixListener::bind(&socket_path).unwrap_or_else(|error| {
+                panic!(
+                    "Failed to bind unix socket '{}': {}",
+                    socket_path.display(),
+                    error
+                )
+            });
 
-        let current_sequence = resolved_stop.sequence;
-        if current_sequence >= target_sequence {
-            continue;
+            // Behind a reverse proxy the proxy owns client keep-alive and connection limits;
+            // only the request body limit applies here.
+            println!("Server is running on unix:{}", socket_path.display());
+            axum::serve(listener, app).await.unwrap();
         }
-
-        let stops_away = target_sequence - current_sequence;
-
-        let intermediate_stops: Vec<&StopWithDetails> = route_stops
-            .stops
-            .iter()
-            .filter(|s| s.sequence > current_sequence && s.sequence <= target_sequence)
-            .collect();
-
-        let mut total_distance_km = 0.0;
-        let mut prev_lat = bus.latitude;
-        let mut prev_lon = bus.longitude;
-
-        for stop in &intermediate_stops {
-            total_distance_km +=
-                haversine_distance(prev_lat, prev_lon, stop.stop_lat, stop.stop_lon);
-            prev_lat = stop.stop_lat;
-            prev_lon = stop.stop_lon;
+        ListenTarget::Tcp(addr) => {
+            let connection_permits = Arc::new(Semaphore::new(server_tuning.max_connections));
+            match listen_config.tls {
+                Some(tls) => {
+                    let rustls_config = RustlsConfig::from_pem_file(&tls.cert_path, &tls.key_path)
+                        .await
+                        .unwrap_or_else(|error| {
+                            panic!("Failed to load TLS certificate/key: {}", error)
+                        });
+                    tokio::spawn(reload_tls_config(rustls_config.clone(), tls));
+
+                    let mut server = axum_server::bind(addr).acceptor(ConnectionLimitAcceptor {
+                        inner: RustlsAcceptor::new(rustls_config),
+                        permits: connection_permits,
+                    });
+                    apply_server_tuning(server.http_builder(), &server_tuning);
+
+                    println!("Server is running on https://{}", addr);
+                    server.serve(app.into_make_service()).await.unwrap();
+                }
+                None => {
+                    let mut server = axum_server::bind(addr).acceptor(ConnectionLimitAcceptor {
+                        inner: DefaultAcceptor::new(),
+                        permits: connection_permits,
+                    });
+                    apply_server_tuning(server.http_builder(), &server_tuning);
+
+                    println!("Server is running on http://{}", addr);
+                    server.serve(app.into_make_service()).await.unwrap();
+                }
+            }
         }
-
-        let speed = if bus.speed > 0.0 {
-            bus.speed
-        } else {
-            DEFAULT_SPEED_KMH
-        };
-        let eta_minutes = (total_distance_km / speed) * 60.0;
-
-        eta_results.push(BusEta {
-            route_id: route_id.to_string(),
-            bus_no: bus.bus_no.clone(),
-            current_lat: bus.latitude,
-            current_lon: bus.longitude,
-            current_stop_id: resolved_stop.stop_id,
-            current_stop_name: resolved_stop.stop_name,
-            current_sequence,
-            stop_resolution_source: resolved_stop.source,
-            stops_away,
-            distance_km: (total_distance_km * 100.0).round() / 100.0,
-            speed_kmh: bus.speed,
-            eta_minutes: (eta_minutes * 10.0).round() / 10.0,
-        });
     }
-
-    eta_results.sort_by(|a, b| {
-        a.eta_minutes
-            .partial_cmp(&b.eta_minutes)
-            .unwrap_or(std::cmp::Ordering::Equal)
-    });
-
-    Ok(eta_results)
 }
 
-fn load_gtfs_context() -> Result<GtfsContext, (StatusCode, Json<ErrorResponse>)> {
-    let routes = load_routes().map_err(|e| {
-        (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            Json(ErrorResponse {
-                error: format!("Failed to load routes: {}", e),
-            }),
-        )
-    })?;
-
-    let trips_by_route = load_trips().map_err(|e| {
-        (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            Json(ErrorResponse {
-                error: format!("Failed to load trips: {}", e),
-            }),
-        )
-    })?;
-
-    let stop_times_by_trip = load_stop_times().map_err(|e| {
-        (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            Json(ErrorResponse {
-                error: format!("Failed to load stop times: {}", e),
-            }),
-        )
-    })?;
-
-    let stops_map = load_stops().map_err(|e| {
-        (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            Json(ErrorResponse {
-                error: format!("Failed to load stops: {}", e),
-            }),
-        )
-    })?;
-
-    Ok(GtfsContext {
-        routes,
-        trips_by_route,
-        stop_times_by_trip,
-        stops_map,
-    })
-}
-
-fn get_routes_for_stop(
-    stop_id: &str,
-    routes: &[Route],
-    trips_by_route: &HashMap<String, Vec<Trip>>,
-    stop_times_by_trip: &HashMap<String, Vec<StopTime>>,
-    stops_map: &HashMap<String, Stop>,
-) -> Result<Vec<StopRouteSummary>, (StatusCode, String)> {
-    if !stops_map.contains_key(stop_id) {
-        return Err((
-            StatusCode::NOT_FOUND,
-            format!("Stop '{}' not found", stop_id),
-        ));
-    }
-
-    let mut stop_routes: Vec<StopRouteSummary> = routes
-        .iter()
-        .filter_map(|route| {
-            let route_stops = get_stops_by_route(
-                &route.route_id,
-                routes,
-                trips_by_route,
-                stop_times_by_trip,
-                stops_map,
-            )
-            .ok()?;
-
-            route_stops
-                .stops
-                .iter()
-                .any(|stop| stop.stop_id == stop_id)
-                .then(|| StopRouteSummary {
-                    route_id: route.route_id.clone(),
-                    route_short_name: route.route_short_name.clone(),
-                    route_long_name: route.route_long_name.clone(),
-                })
-        })
-        .collect();
-
-    stop_routes.sort_by(|a, b| {
-        a.route_short_name
-            .cmp(&b.route_short_name)
-            .then(a.route_id.cmp(&b.route_id))
+fn finish_startup_phase(
+    phases: &mut Vec<StartupPhaseTiming>,
+    phase: StartupPhase,
+    started: Instant,
+) -> Instant {
+    let duration_ms = started.elapsed().as_millis() as i64;
+    println!("Startup phase {:?} done in {}ms", phase, duration_ms);
+    phases.push(StartupPhaseTiming {
+        phase,
+        completed_at_unix_ms: now_unix_ms(),
+        duration_ms,
     });
-
-    if stop_routes.is_empty() {
-        return Err((
-            StatusCode::NOT_FOUND,
-            format!("No routes found for stop '{}'", stop_id),
-        ));
+    Instant::now()
+}
+
+fn server_tuning_from_env() -> ServerTuning {
+    ServerTuning {
+        max_connections: env_or("MAX_CONNECTIONS", DEFAULT_MAX_CONNECTIONS),
+        max_request_body_bytes: env_or("MAX_REQUEST_BODY_BYTES", DEFAULT_MAX_REQUEST_BODY_BYTES),
+        http1_header_read_timeout: Duration::from_secs(env_or(
+            "HTTP1_HEADER_READ_TIMEOUT_SECONDS",
+            DEFAULT_HTTP1_HEADER_READ_TIMEOUT_SECONDS,
+        )),
+        http2_keep_alive_interval: Duration::from_secs(env_or(
+            "HTTP2_KEEP_ALIVE_INTERVAL_SECONDS",
+            DEFAULT_HTTP2_KEEP_ALIVE_INTERVAL_SECONDS,
+        )),
+        http2_keep_alive_timeout: Duration::from_secs(env_or(
+            "HTTP2_KEEP_ALIVE_TIMEOUT_SECONDS",
+            DEFAULT_HTTP2_KEEP_ALIVE_TIMEOUT_SECONDS,
+        )),
+        http2_max_concurrent_streams: env_or(
+            "HTTP2_MAX_CONCURRENT_STREAMS",
+            DEFAULT_HTTP2_MAX_CONCURRENT_STREAMS,
+        ),
     }
-
-    Ok(stop_routes)
 }
 
-// Decode base64 + gzip compressed data from the websocket
-fn decode_bus_data(encoded: &str) -> Option<String> {
-    let decoded = base64::engine::general_purpose::STANDARD
-        .decode(encoded)
-        .ok()?;
-
-    let mut decoder = GzDecoder::new(&decoded[..]);
-    let mut decompressed = String::new();
-    decoder.read_to_string(&mut decompressed).ok()?;
-
-    Some(decompressed)
-}
-
-// Calculate haversine distance between two GPS coordinates (returns km)
-fn haversine_distance(lat1: f64, lon1: f64, lat2: f64, lon2: f64) -> f64 {
-    let r = 6371.0; // Earth radius in km
-    let dlat = (lat2 - lat1).to_radians();
-    let dlon = (lon2 - lon1).to_radians();
-    let a = (dlat / 2.0).sin().powi(2)
-        + lat1.to_radians().cos() * lat2.to_radians().cos() * (dlon / 2.0).sin().powi(2);
-    let c = 2.0 * a.sqrt().asin();
-    r * c
+// HTTP/1.1 keeps connections alive between requests; HTTP/2 (h2c, or ALPN over TLS) pings idle
+// long-lived clients such as kiosks so dead peers are noticed and their slots released.
+fn apply_server_tuning(builder: &mut AutoBuilder<TokioExecutor>, tuning: &ServerTuning) {
+    builder
+        .http1()
+        .keep_alive(true)
+        .timer(TokioTimer::new())
+        .header_read_timeout(tuning.http1_header_read_timeout);
+    builder
+        .http2()
+        .timer(TokioTimer::new())
+        .keep_alive_interval(Some(tuning.http2_keep_alive_interval))
+        .keep_alive_timeout(tuning.http2_keep_alive_timeout)
+        .max_concurrent_streams(tuning.http2_max_concurrent_streams);
+}
+
+// Caps open connections: each accepted stream holds a semaphore permit until it is dropped, and
+// connections beyond the limit are closed straight away (before any TLS handshake).
+#[derive(Clone)]
+struct ConnectionLimitAcceptor<A> {
+    inner: A,
+    permits: Arc<Semaphore>,
+}
+
+impl<A, I, S> Accept<I, S> for ConnectionLimitAcceptor<A>
+where
+    A: Accept<I, S> + Clone + Send + 'static,
+    A::Future: Send,
+    A::Stream: Send,
+    A::Service: Send,
+    I: Send + 'static,
+    S: Send + 'static,
+{
+    type Stream = LimitedStream<A::Stream>;
+    type Service = A::Service;
+    type Future = BoxFuture<'static, std::io::Result<(Self::Stream, Self::Service)>>;
+
+    fn accept(&self, stream: I, service: S) -> Self::Future {
+        let inner = self.inner.clone();
+        let permits = self.permits.clone();
+
+        Box::pin(async move {
+            let Ok(permit) = permits.try_acquire_owned() else {
+                return Err(std::io::Error::other("connection limit reached"));
+            };
+            let (stream, service) = inner.accept(stream, service).await?;
+            Ok((
+                LimitedStream {
+                    inner: stream,
+                    _permit: permit,
+                },
+                service,
+            ))
+        })
+    }
 }
 
-// Data OpenDOSM Prasarana - uses protobuf (alternative data source)
-#[allow(dead_code)]
-async fn prasarana_gtfs_data() -> Json<gtfs_realtime::FeedMessage> {
-    let endpoint =
-        "https://api.data.gov.my/gtfs-realtime/vehicle-position/prasarana?category=rapid-bus-kl";
-    let response = reqwest::get(endpoint).await.unwrap();
-    let body = response.bytes().await.unwrap();
-    let feed = gtfs_realtime::FeedMessage::decode(body).unwrap();
-
-    println!("Calling prasarana_gtfs_data");
-    Json(feed)
+struct LimitedStream<T> {
+    inner: T,
+    _permit: OwnedSemaphorePermit,
 }
 
-// GTFS data loading functions
-fn load_routes() -> Result<Vec<Route>, Box<dyn std::error::Error>> {
-    let path = StdPath::new(GTFS_DATA_PATH).join("routes.txt");
-    let file = File::open(path)?;
-    let mut rdr = csv::ReaderBuilder::new()
-        .has_headers(true)
-        .from_reader(file);
-    let mut routes = Vec::new();
-    for result in rdr.deserialize() {
-        let route: Route = result?;
-        routes.push(route);
+impl<T: AsyncRead + Unpin> AsyncRead for LimitedStream<T> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_read(cx, buf)
     }
-    Ok(routes)
 }
 
-fn load_trips() -> Result<HashMap<String, Vec<Trip>>, Box<dyn std::error::Error>> {
-    let path = StdPath::new(GTFS_DATA_PATH).join("trips.txt");
-    let file = File::open(path)?;
-    let mut rdr = csv::ReaderBuilder::new()
-        .has_headers(true)
-        .from_reader(file);
-    let mut trips_by_route: HashMap<String, Vec<Trip>> = HashMap::new();
-    for result in rdr.deserialize() {
-        let trip: Trip = result?;
-        trips_by_route
-            .entry(trip.route_id.clone())
-            .or_default()
-            .push(trip);
+impl<T: AsyncWrite + Unpin> AsyncWrite for LimitedStream<T> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
     }
-    Ok(trips_by_route)
-}
 
-fn load_stop_times() -> Result<HashMap<String, Vec<StopTime>>, Box<dyn std::error::Error>> {
-    let path = StdPath::new(GTFS_DATA_PATH).join("stop_times.txt");
-    let file = File::open(path)?;
-    let mut rdr = csv::ReaderBuilder::new()
-        .has_headers(true)
-        .from_reader(file);
-    let mut stop_times_by_trip: HashMap<String, Vec<StopTime>> = HashMap::new();
-    for result in rdr.deserialize() {
-        let stop_time: StopTime = result?;
-        stop_times_by_trip
-            .entry(stop_time.trip_id.clone())
-            .or_default()
-            .push(stop_time);
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[std::io::IoSlice<'_>],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write_vectored(cx, bufs)
     }
-    Ok(stop_times_by_trip)
-}
 
-fn load_stops() -> Result<HashMap<String, Stop>, Box<dyn std::error::Error>> {
-    let path = StdPath::new(GTFS_DATA_PATH).join("stops.txt");
-    let file = File::open(path)?;
-    let mut rdr = csv::ReaderBuilder::new()
-        .has_headers(true)
-        .from_reader(file);
-    let mut stops_map = HashMap::new();
-    for result in rdr.deserialize() {
-        let stop: Stop = result?;
-        stops_map.insert(stop.stop_id.clone(), stop);
+    fn is_write_vectored(&self) -> bool {
+        self.inner.is_write_vectored()
     }
-    Ok(stops_map)
-}
 
-fn load_shapes() -> Result<HashMap<String, Vec<ShapePoint>>, Box<dyn std::error::Error>> {
-    let path = StdPath::new(GTFS_DATA_PATH).join("shapes.txt");
-    let file = File::open(path)?;
-    let mut rdr = csv::ReaderBuilder::new()
-        .has_headers(true)
-        .from_reader(file);
-    let mut shapes_by_id: HashMap<String, Vec<ShapePoint>> = HashMap::new();
-    for result in rdr.deserialize() {
-        let shape_point: ShapePoint = result?;
-        shapes_by_id
-            .entry(shape_point.shape_id.clone())
-            .or_default()
-            .push(shape_point);
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
     }
-    Ok(shapes_by_id)
-}
-
-// Get stops by route_id
-fn get_stops_by_route(
-    route_id: &str,
-    routes: &[Route],
-    trips_by_route: &HashMap<String, Vec<Trip>>,
-    stop_times_by_trip: &HashMap<String, Vec<StopTime>>,
-    stops_map: &HashMap<String, Stop>,
-) -> Result<RouteStopsResponse, (StatusCode, String)> {
-    // Find the route
-    let route = routes
-        .iter()
-        .find(|r| r.route_id == route_id)
-        .ok_or_else(|| {
-            (
-                StatusCode::NOT_FOUND,
-                format!("Route '{}' not found", route_id),
-            )
-        })?;
-
-    // Get trips for this route
-    let trips = trips_by_route.get(route_id).ok_or_else(|| {
-        (
-            StatusCode::NOT_FOUND,
-            format!("No trips found for route '{}'", route_id),
-        )
-    })?;
-
-    // Get the first trip's stop times
-    let first_trip = &trips[0];
-    let stop_times = stop_times_by_trip.get(&first_trip.trip_id).ok_or_else(|| {
-        (
-            StatusCode::NOT_FOUND,
-            format!("No stop times found for trip '{}'", first_trip.trip_id),
-        )
-    })?;
-
-    // Sort by stop_sequence
-    let mut sorted_stop_times: Vec<&StopTime> = stop_times.iter().collect();
-    sorted_stop_times.sort_by_key(|st| st.stop_sequence);
-
-    // Build response with stop details
-    let stops: Vec<StopWithDetails> = sorted_stop_times
-        .into_iter()
-        .filter_map(|st| {
-            stops_map.get(&st.stop_id).map(|stop| StopWithDetails {
-                stop_id: stop.stop_id.clone(),
-                stop_name: stop.stop_name.clone(),
-                stop_desc: stop.stop_desc.clone(),
-                stop_lat: stop.stop_lat,
-                stop_lon: stop.stop_lon,
-                sequence: st.stop_sequence,
-            })
-        })
-        .collect();
-
-    Ok(RouteStopsResponse {
-        route_id: route.route_id.clone(),
-        route_short_name: route.route_short_name.clone(),
-        route_long_name: route.route_long_name.clone(),
-        stops,
-    })
-}
-
-fn get_shape_by_route(
-    route_id: &str,
-    trips_by_route: &HashMap<String, Vec<Trip>>,
-    shapes_by_id: &HashMap<String, Vec<ShapePoint>>,
-) -> Result<RouteShapeResponse, (StatusCode, String)> {
-    let trips = trips_by_route.get(route_id).ok_or_else(|| {
-        (
-            StatusCode::NOT_FOUND,
-            format!("No trips found for route '{}'", route_id),
-        )
-    })?;
-
-    let first_trip = &trips[0];
-    let shape_points = shapes_by_id.get(&first_trip.shape_id).ok_or_else(|| {
-        (
-            StatusCode::NOT_FOUND,
-            format!("No shape found for shape_id '{}'", first_trip.shape_id),
-        )
-    })?;
 
-    let mut sorted_points: Vec<&ShapePoint> = shape_points.iter().collect();
-    sorted_points.sort_by_key(|point| point.shape_pt_sequence);
-
-    let points = sorted_points
-        .into_iter()
-        .map(|point| RouteShapePoint {
-            lat: point.shape_pt_lat,
-            lon: point.shape_pt_lon,
-            sequence: point.shape_pt_sequence,
-        })
-        .collect();
-
-    Ok(RouteShapeResponse {
-        route_id: route_id.to_string(),
-        shape_id: first_trip.shape_id.clone(),
-        points,
-    })
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
 }
 
-// Axum handler for /route/:route_id/stops
-async fn get_route_stops(
-    Path(route_id): Path<String>,
-) -> Result<Json<RouteStopsResponse>, (StatusCode, Json<ErrorResponse>)> {
-    // Load GTFS data
-    let routes = match load_routes() {
-        Ok(r) => r,
-        Err(e) => {
-            return Err((
-                StatusCode::INTERNAL_SERVER_ERROR,
-                Json(ErrorResponse {
-                    error: format!("Failed to load routes: {}", e),
-                }),
-            ));
+fn listen_config_from_env() -> Result<ListenConfig, String> {
+    let bind_addr = env::var("BIND_ADDR").unwrap_or_else(|_| DEFAULT_BIND_ADDR.to_string());
+    let target = match bind_addr.strip_prefix("unix:") {
+        Some(socket_path) if !socket_path.is_empty() => {
+            ListenTarget::Unix(PathBuf::from(socket_path))
         }
+        Some(_) => return Err("BIND_ADDR 'unix:' requires a socket path".to_string()),
+        None => ListenTarget::Tcp(bind_addr.parse::<SocketAddr>().map_err(|error| {
+            format!(
+                "BIND_ADDR '{}' is not a valid socket address: {}",
+                bind_addr, error
+            )
+        })?),
     };
 
-    let trips_by_route = match load_trips() {
-        Ok(t) => t,
-        Err(e) => {
-            return Err((
-                StatusCode::INTERNAL_SERVER_ERROR,
-                Json(ErrorResponse {
-                    error: format!("Failed to load trips: {}", e),
-                }),
-            ));
+    let cert_path = env::var("TLS_CERT_PATH")
+        .ok()
+        .filter(|value| !value.is_empty());
+    let key_path = env::var("TLS_KEY_PATH")
+        .ok()
+        .filter(|value| !value.is_empty());
+    let tls = match (cert_path, key_path) {
+        (Some(cert_path), Some(key_path)) => {
+            for path in [&cert_path, &key_path] {
+                if !StdPath::new(path).is_file() {
+                    return Err(format!("TLS file '{}' does not exist", path));
+                }
+            }
+            Some(TlsPaths {
+                cert_path: PathBuf::from(cert_path),
+                key_path: PathBuf::from(key_path),
+                reload_interval: Duration::from_secs(env_or(
+                    "TLS_RELOAD_INTERVAL_SECONDS",
+                    DEFAULT_TLS_RELOAD_INTERVAL_SECONDS,
+                )),
+            })
         }
+        (None, None) => None,
+        _ => return Err("TLS_CERT_PATH and TLS_KEY_PATH must be set together".to_string()),
     };
 
-    let stop_times_by_trip = match load_stop_times() {
-        Ok(st) => st,
-        Err(e) => {
-            return Err((
-                StatusCode::INTERNAL_SERVER_ERROR,
-                Json(ErrorResponse {
-                    error: format!("Failed to load stop times: {}", e),
-                }),
-            ));
-        }
-    };
+    if tls.is_some() && matches!(target, ListenTarget::Unix(_)) {
+        return Err("TLS is not supported on unix sockets; terminate TLS at the proxy".to_string());
+    }
 
-    let stops_map = match load_stops() {
-        Ok(s) => s,
-        Err(e) => {
-            return Err((
-                StatusCode::INTERNAL_SERVER_ERROR,
-                Json(ErrorResponse {
-                    error: format!("Failed to load stops: {}", e),
-                }),
-            ));
-        }
-    };
+    Ok(ListenConfig { target, tls })
+}
 
-    match get_stops_by_route(
-        &route_id,
-        &routes,
-        &trips_by_route,
-        &stop_times_by_trip,
-        &stops_map,
-    ) {
-        Ok(response) => {
-            println!("Calling get_route_stops for route_id={}", route_id);
-            Ok(Json(response))
-        }
-        Err((status, message)) => Err((status, Json(ErrorResponse { error: message }))),
+// A socket file left behind by a previous process would make bind fail; only ever remove
+// actual sockets so a misconfigured path can't delete a regular file.
+fn remove_stale_unix_socket(socket_path: &StdPath) {
+    let is_socket = std::fs::symlink_metadata(socket_path)
+        .map(|metadata| metadata.file_type().is_socket())
+        .unwrap_or(false);
+    if is_socket {
+        let _ = std::fs::remove_file(socket_path);
     }
 }
 
-async fn get_route_shape(
-    Path(route_id): Path<String>,
-) -> Result<Json<RouteShapeResponse>, (StatusCode, Json<ErrorResponse>)> {
-    let trips_by_route = match load_trips() {
-        Ok(t) => t,
-        Err(e) => {
-            return Err((
-                StatusCode::INTERNAL_SERVER_ERROR,
-                Json(ErrorResponse {
-                    error: format!("Failed to load trips: {}", e),
-                }),
-            ));
-        }
-    };
-
-    let shapes_by_id = match load_shapes() {
-        Ok(s) => s,
-        Err(e) => {
-            return Err((
-                StatusCode::INTERNAL_SERVER_ERROR,
-                Json(ErrorResponse {
-                    error: format!("Failed to load shapes: {}", e),
-                }),
-            ));
-        }
-    };
+// Re-read the certificate pair periodically so renewed certificates are picked up without a
+// restart. A failed reload keeps serving the previous certificate.
+async fn reload_tls_config(rustls_config: RustlsConfig, tls: TlsPaths) {
+    let mut reload_interval = tokio::time::interval(tls.reload_interval);
+    reload_interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
+    reload_interval.tick().await;
 
-    match get_shape_by_route(&route_id, &trips_by_route, &shapes_by_id) {
-        Ok(response) => {
-            println!("Calling get_route_shape for route_id={}", route_id);
-            Ok(Json(response))
+    loop {
+        reload_interval.tick().await;
+        if let Err(error) = rustls_config
+            .reload_from_pem_file(&tls.cert_path, &tls.key_path)
+            .await
+        {
+            println!("Failed to reload TLS certificate/key: {}", error);
         }
-        Err((status, message)) => Err((status, Json(ErrorResponse { error: message }))),
     }
 }
 
-// Axum handler for /stops/nearest?lat={lat}&lon={lon}
-async fn get_nearest_stop(
-    Query(query): Query<NearestStopQuery>,
-) -> Result<Json<NearestStopResponse>, (StatusCode, Json<ErrorResponse>)> {
-    if !(-90.0..=90.0).contains(&query.lat) || !(-180.0..=180.0).contains(&query.lon) {
-        return Err((
-            StatusCode::BAD_REQUEST,
-            Json(ErrorResponse {
-                error: "Invalid latitude/longitude values".to_string(),
-            }),
-        ));
+// Runtime state is per city so ingestors, caches and in-flight ETA requests never mix; the
+// deployment settings (privacy, TTLs, load shedding, admin key) are shared.
+fn city_app_state(
+    base: &AppState,
+    city: CityConfig,
+    gtfs: Arc<GtfsFeed>,
+    thresholds: Thresholds,
+) -> AppState {
+    let route_groups = load_city_route_groups(&city);
+    AppState {
+        gtfs: Arc::new(std::sync::RwLock::new(gtfs)),
+        redis_keys: RedisKeys {
+            prefix: city.redis_key_prefix.clone(),
+        },
+        city: Arc::new(city),
+        replica_lag: Arc::new(RwLock::new(None)),
+        ingestor_status: Arc::new(RwLock::new(initial_ingestor_status())),
+        thresholds: Arc::new(RwLock::new(thresholds)),
+        dead_letters: Arc::new(RwLock::new(VecDeque::new())),
+        active_bus_count_history: Arc::new(RwLock::new(VecDeque::new())),
+        stop_eta_computed_total: Arc::new(AtomicU64::new(0)),
+        gps_to_redis_seconds: Arc::new(LatencyHistogram::new(&FRESHNESS_BUCKETS_SECONDS)),
+        redis_to_response_seconds: Arc::new(LatencyHistogram::new(&FRESHNESS_BUCKETS_SECONDS)),
+        route_groups: Arc::new(route_groups),
+        runtime_profiles: Arc::new(RwLock::new(HashMap::new())),
+        terminus_schedules: Arc::new(RwLock::new(HashMap::new())),
+        feature_flags: Arc::new(new_feature_flags(
+            base.feature_flags.defaults.clone(),
+            base.feature_flags.shadow.clone(),
+        )),
+        detours: Arc::new(std::sync::Mutex::new(HashMap::new())),
+        stop_closures: Arc::new(std::sync::Mutex::new(HashMap::new())),
+        shadow_evaluation: Arc::new(ShadowEvaluation::default()),
+        usage: Arc::new(UsageCounters::default()),
+        hot_stops: Arc::new(HotStopBoards::default()),
+        resolution_log: Arc::new(ResolutionLog::default()),
+        route_stops_cache: Arc::new(RouteStopsCache::default()),
+        gtfs_rt_cache: Arc::new(RwLock::new(GtfsRtCache::default())),
+        stop_eta_flights: Arc::new(Singleflight::new()),
+        route_eta_flights: Arc::new(Singleflight::new()),
+        ingest_events: Arc::new(watch::channel(None).0),
+        ..base.clone()
     }
+}
 
-    let stops_map = load_stops().map_err(|e| {
-        (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            Json(ErrorResponse {
-                error: format!("Failed to load stops: {}", e),
-            }),
-        )
-    })?;
-
-    let nearest_stop = stops_map
-        .values()
-        .map(|stop| {
-            let distance_km =
-                haversine_distance(query.lat, query.lon, stop.stop_lat, stop.stop_lon);
-            (stop, distance_km)
-        })
-        .min_by(|(_, left_distance), (_, right_distance)| {
-            left_distance
-                .partial_cmp(right_distance)
-                .unwrap_or(std::cmp::Ordering::Equal)
-        })
-        .ok_or_else(|| {
-            (
-                StatusCode::NOT_FOUND,
-                Json(ErrorResponse {
-                    error: "No stops available".to_string(),
-                }),
+fn load_city_route_groups(city: &CityConfig) -> HashMap<String, Vec<String>> {
+    match &city.route_groups_path {
+        Some(path) => load_route_groups(path, &city.gtfs_data_path).unwrap_or_else(|error| {
+            panic!(
+                "Failed to load route groups '{}' for city '{}': {}",
+                path, city.id, error
             )
-        })?;
-
-    let (stop, distance_km) = nearest_stop;
-    let response = NearestStopResponse {
-        stop_id: stop.stop_id.clone(),
-        stop_name: stop.stop_name.clone(),
-        stop_desc: stop.stop_desc.clone(),
-        stop_lat: stop.stop_lat,
-        stop_lon: stop.stop_lon,
-        distance_km: (distance_km * 1000.0).round() / 1000.0,
-        distance_meters: (distance_km * 1000.0 * 10.0).round() / 10.0,
+        }),
+        None => HashMap::new(),
+    }
+}
+
+// Thresholds patched through the admin API are persisted per city and win over the environment.
+async fn load_persisted_thresholds(
+    redis_conn: Option<&mut redis::aio::MultiplexedConnection>,
+    keys: &RedisKeys,
+) -> Thresholds {
+    let thresholds = thresholds_from_env();
+    let Some(redis_conn) = redis_conn else {
+        return thresholds;
     };
+    let persisted_thresholds: Option<String> = redis::cmd("GET")
+        .arg(keys.key(REDIS_THRESHOLDS_KEY))
+        .query_async(redis_conn)
+        .await
+        .unwrap_or(None);
+    match persisted_thresholds
+        .and_then(|value| serde_json::from_str::<ThresholdsPatch>(&value).ok())
+    {
+        Some(patch) => apply_thresholds_patch(thresholds, patch).unwrap_or(thresholds),
+        None => thresholds,
+    }
+}
 
-    println!(
-        "Calling get_nearest_stop for lat={}, lon={} -> stop_id={}",
-        query.lat, query.lon, response.stop_id
-    );
-    Ok(Json(response))
+fn env_or<T: std::str::FromStr>(name: &str, default: T) -> T {
+    env::var(name)
+        .ok()
+        .and_then(|value| value.parse::<T>().ok())
+        .unwrap_or(default)
+}
+
+fn now_unix_ms() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_millis() as i64)
+        .unwrap_or(0)
 }
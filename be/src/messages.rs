@@ -0,0 +1,168 @@
+// Rider-facing caveats for ETA responses. Internal state (staleness, warmup, a lost feed, thin
+// fixes, the timetable) is reduced to RiderSignals once, and a fixed table of rules turns the
+// signals into message codes, so every frontend shows the same caveats for the same state.
+
+use rapidbro_types::{BusEta, RiderMessage, RiderMessageCode, ScheduledHeadway};
+use std::collections::HashMap;
+
+use crate::gtfs::current_route_headway;
+use crate::ingest::is_warming_up;
+use crate::models::{GtfsContext, TerminusSchedule};
+use crate::store::{kl_date, replica_is_behind};
+use crate::AppState;
+
+// The soonest ETA counts as a rough guess once its fix has decayed below this weight.
+const LOW_CONFIDENCE_DATA_WEIGHT: f64 = 0.5;
+// A route's next trip start this far off and on a later day means today's service is over; the
+// gap keeps trips just past midnight, which still belong to today's service, from counting.
+const LAST_BUS_MIN_GAP_MS: i64 = 2 * 3_600_000;
+
+pub(crate) struct RiderSignals<'a> {
+    // The positions behind the ETAs are older than STALE_AFTER_SECONDS, or the replica serving
+    // them is behind.
+    pub(crate) is_stale: bool,
+    // The ingestor has lost its upstream feed, so no newer positions are on the way.
+    degraded: bool,
+    pub(crate) warming_up: bool,
+    // Sorted as served, soonest first.
+    etas: &'a [BusEta],
+    // Routes at the stop whose last trip of the day has left with no bus still due.
+    pub(crate) departed_route_ids: Vec<String>,
+    // Frequency-based routes at the stop with no bus due, as of their scheduled headway.
+    pub(crate) headway_only_route_ids: Vec<String>,
+}
+
+struct RiderMessageRule {
+    code: RiderMessageCode,
+    message: &'static str,
+    // The route_ids the message is about when it fires: empty for the whole response.
+    applies: fn(&RiderSignals) -> Option<Vec<String>>,
+}
+
+// In the order messages are listed in responses.
+const RIDER_MESSAGE_RULES: &[RiderMessageRule] = &[
+    RiderMessageRule {
+        code: RiderMessageCode::DataDelayed,
+        message: "Live bus positions are delayed, so arrival times may be out of date.",
+        applies: |signals| (signals.is_stale || signals.degraded).then(Vec::new),
+    },
+    RiderMessageRule {
+        code: RiderMessageCode::LowConfidence,
+        message: "Arrival times are rough estimates right now.",
+        applies: |signals| {
+            let thin_fix = signals.etas.first().is_some_and(|eta| {
+                eta.data_weight
+                    .is_some_and(|weight| weight < LOW_CONFIDENCE_DATA_WEIGHT)
+            });
+            (signals.warming_up || thin_fix).then(Vec::new)
+        },
+    },
+    RiderMessageRule {
+        code: RiderMessageCode::LastBusDeparted,
+        message: "The last bus of the day has already left for these routes.",
+        applies: |signals| {
+            (!signals.departed_route_ids.is_empty()).then(|| signals.departed_route_ids.clone())
+        },
+    },
+    RiderMessageRule {
+        code: RiderMessageCode::ScheduledHeadwayOnly,
+        message: "No bus is visible yet on these routes; they are scheduled to run at a regular frequency.",
+        applies: |signals| {
+            (!signals.headway_only_route_ids.is_empty())
+                .then(|| signals.headway_only_route_ids.clone())
+        },
+    },
+];
+
+pub(crate) fn rider_messages(signals: &RiderSignals) -> Vec<RiderMessage> {
+    RIDER_MESSAGE_RULES
+        .iter()
+        .filter_map(|rule| {
+            (rule.applies)(signals).map(|route_ids| RiderMessage {
+                code: rule.code,
+                message: rule.message.to_string(),
+                route_ids,
+            })
+        })
+        .collect()
+}
+
+// Staleness, feed loss and warmup as of captured_at. History replays describe a past instant,
+// so only staleness applies to them.
+pub(crate) async fn rider_signals<'a>(
+    state: &AppState,
+    etas: &'a [BusEta],
+    last_ingest_at_unix_ms: Option<i64>,
+    captured_at_unix_ms: i64,
+    as_of: Option<i64>,
+) -> RiderSignals<'a> {
+    let replica_lagging = match as_of {
+        Some(_) => false,
+        None => (*state.replica_lag.read().await)
+            .is_some_and(|lag| replica_is_behind(&lag, state.stale_after_ms)),
+    };
+    let is_stale = match last_ingest_at_unix_ms {
+        Some(last_ingest_ms) => captured_at_unix_ms - last_ingest_ms > state.stale_after_ms,
+        None => true,
+    } || replica_lagging;
+    let live = as_of.is_none() && state.fixture.is_none();
+    RiderSignals {
+        is_stale,
+        degraded: live && !state.ingestor_status.read().await.connected,
+        warming_up: as_of.is_none() && is_warming_up(state).await,
+        etas,
+        departed_route_ids: Vec::new(),
+        headway_only_route_ids: Vec::new(),
+    }
+}
+
+// Routes calling at stop_id with no bus on its way there and no trip left to start today.
+pub(crate) fn departed_route_ids(
+    gtfs: &GtfsContext,
+    stop_id: &str,
+    etas: &[BusEta],
+    terminus_schedules: &HashMap<String, TerminusSchedule>,
+    now_ms: i64,
+) -> Vec<String> {
+    let today = kl_date(now_ms);
+    gtfs.route_ids_by_stop
+        .get(stop_id)
+        .into_iter()
+        .flatten()
+        .filter(|route_id| !etas.iter().any(|eta| eta.route_id == **route_id))
+        .filter(|route_id| {
+            let Some(schedule) = terminus_schedules.get(*route_id) else {
+                return false;
+            };
+            match schedule
+                .departures_unix_ms
+                .iter()
+                .find(|departure_ms| **departure_ms >= now_ms)
+            {
+                Some(next_ms) => {
+                    next_ms - now_ms >= LAST_BUS_MIN_GAP_MS && kl_date(*next_ms) > today
+                }
+                // Schedules carry tomorrow's starts too, so an empty one is a route not running.
+                None => false,
+            }
+        })
+        .cloned()
+        .collect()
+}
+
+// The "roughly every N minutes" fallback: routes calling at stop_id with no bus on its way there
+// but a frequencies.txt window in effect now.
+pub(crate) fn scheduled_headways_without_buses(
+    gtfs: &GtfsContext,
+    stop_id: &str,
+    etas: &[BusEta],
+    now_ms: i64,
+) -> Vec<ScheduledHeadway> {
+    gtfs.route_ids_by_stop
+        .get(stop_id)
+        .into_iter()
+        .flatten()
+        .filter(|route_id| !etas.iter().any(|eta| eta.route_id == **route_id))
+        .filter_map(|route_id| current_route_headway(route_id, gtfs, now_ms))
+        .collect()
+}
@@ -0,0 +1,593 @@
+// Domain types shared by the GTFS loader, the ingest pipeline, the Redis store and the ETA engine.
+
+use chrono::NaiveDate;
+use rapidbro_eta::{BusMotionState, RouteGeometry};
+use rapidbro_types::{
+    BusPosition, GtfsFileValidation, GtfsValidationReport, RouteDetour, RouteStopsResponse,
+    StopIncomingResponse, StopResolutionRecord,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::path::PathBuf;
+use std::sync::{
+    atomic::{AtomicU64, AtomicUsize},
+    Arc,
+};
+use tokio::sync::RwLock;
+
+// GTFS data structures
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct Route {
+    pub(crate) route_id: String,
+    agency_id: String,
+    pub(crate) route_short_name: String,
+    pub(crate) route_long_name: String,
+    route_type: u32,
+    pub(crate) route_color: String,
+    pub(crate) route_text_color: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct Trip {
+    pub(crate) route_id: String,
+    pub(crate) service_id: String,
+    pub(crate) trip_id: String,
+    pub(crate) shape_id: String,
+    pub(crate) trip_headsign: Option<String>,
+    pub(crate) direction_id: Option<u32>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct ServiceCalendar {
+    pub(crate) service_id: String,
+    pub(crate) monday: u8,
+    pub(crate) tuesday: u8,
+    pub(crate) wednesday: u8,
+    pub(crate) thursday: u8,
+    pub(crate) friday: u8,
+    pub(crate) saturday: u8,
+    pub(crate) sunday: u8,
+    pub(crate) start_date: String,
+    pub(crate) end_date: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub(crate) struct ServiceCalendarDate {
+    pub(crate) service_id: String,
+    pub(crate) date: String,
+    pub(crate) exception_type: u8,
+}
+
+// calendar.txt plus the calendar_dates.txt exceptions (public holidays, festival services)
+// layered on top of it.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct ServiceCalendars {
+    pub(crate) calendars: Vec<ServiceCalendar>,
+    // (service_id, YYYYMMDD) -> exception_type: 1 adds the service that day, 2 removes it.
+    pub(crate) exceptions: HashMap<(String, String), u8>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct Frequency {
+    pub(crate) trip_id: String,
+    pub(crate) start_time: String,
+    pub(crate) end_time: String,
+    pub(crate) headway_secs: u32,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct StopTime {
+    pub(crate) trip_id: String,
+    arrival_time: String,
+    pub(crate) departure_time: String,
+    pub(crate) stop_id: String,
+    pub(crate) stop_sequence: u32,
+    stop_headsign: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct Stop {
+    pub(crate) stop_id: String,
+    // Optional GTFS column; derived from the stop name when the feed leaves it out.
+    #[serde(default)]
+    pub(crate) stop_code: Option<String>,
+    pub(crate) stop_name: String,
+    pub(crate) stop_desc: String,
+    pub(crate) stop_lat: f64,
+    pub(crate) stop_lon: f64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct ShapePoint {
+    pub(crate) shape_id: String,
+    pub(crate) shape_pt_lat: f64,
+    pub(crate) shape_pt_lon: f64,
+    pub(crate) shape_pt_sequence: u32,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub(crate) struct GeocodedPlace {
+    pub(crate) lat: f64,
+    pub(crate) lon: f64,
+    #[serde(default)]
+    pub(crate) street: Option<String>,
+    #[serde(default)]
+    pub(crate) locality: Option<String>,
+}
+
+// A polygon in (lon, lat) order as in GeoJSON; the first ring is the outer boundary and any
+// further rings are holes.
+#[derive(Debug, Clone)]
+pub(crate) struct GeoPolygon {
+    pub(crate) rings: Vec<Vec<(f64, f64)>>,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct NamedGeofence {
+    pub(crate) name: String,
+    pub(crate) polygons: Vec<GeoPolygon>,
+}
+
+// How far the read replica trails the primary, from the last lag check.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct ReplicaLag {
+    pub(crate) link_up: bool,
+    pub(crate) lag_ms: i64,
+}
+
+// One city or agency served by this process: its static GTFS feed, the AVL socket and provider
+// code the ingestor subscribes with, and the Redis namespace its live data is kept under.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct CityConfig {
+    pub(crate) id: String,
+    pub(crate) name: String,
+    pub(crate) gtfs_data_path: PathBuf,
+    pub(crate) socket_url: String,
+    pub(crate) provider: String,
+    // Empty means "{DEFAULT_REDIS_KEY_PREFIX}:{id}".
+    #[serde(default)]
+    pub(crate) redis_key_prefix: String,
+    // The GTFS-RT proxy is only run for cities that have a vehicle positions feed.
+    #[serde(default)]
+    pub(crate) gtfs_rt_vehicle_positions_url: Option<String>,
+    #[serde(default)]
+    pub(crate) route_groups_path: Option<String>,
+    // When set, the static feed is downloaded into gtfs_data_path at startup and refreshed on a
+    // schedule instead of being synced there by hand.
+    #[serde(default)]
+    pub(crate) gtfs_static_url: Option<String>,
+    // Further operators' feeds merged into this city's, e.g. MRT Feeder alongside Rapid Bus KL.
+    #[serde(default)]
+    pub(crate) extra_gtfs_feeds: Vec<ExtraGtfsFeed>,
+}
+
+// A feed merged into a city after its own. Every route, trip, stop, shape and service id in it
+// is prefixed "{provider}:", so it can't collide with the others; provider is the AVL provider
+// code its buses report, which get the same prefix on their route as they are ingested.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct ExtraGtfsFeed {
+    pub(crate) provider: String,
+    pub(crate) gtfs_data_path: PathBuf,
+}
+
+// One feed directory's tables, before the indexes over them are built.
+#[derive(Debug, Default)]
+pub(crate) struct GtfsTables {
+    pub(crate) routes: Vec<Route>,
+    pub(crate) trips_by_route: HashMap<String, Vec<Trip>>,
+    pub(crate) stop_times_by_trip: HashMap<String, Vec<StopTime>>,
+    pub(crate) stops_map: HashMap<String, Stop>,
+    pub(crate) shapes_by_id: HashMap<String, Vec<ShapePoint>>,
+    pub(crate) calendars: ServiceCalendars,
+    pub(crate) frequencies_by_trip: HashMap<String, Vec<Frequency>>,
+    pub(crate) validation: GtfsValidation,
+}
+
+// Rows left out while loading feed tables, by file name.
+#[derive(Debug, Default)]
+pub(crate) struct GtfsValidation {
+    pub(crate) files: BTreeMap<String, GtfsFileValidation>,
+}
+
+// Rules that map the feed's inconsistent bus_no spellings onto one canonical id. Whitespace
+// and case are always normalized; prefixes and aliases come from the environment.
+#[derive(Debug, Clone)]
+pub(crate) struct BusNoRules {
+    pub(crate) strip_prefixes: Vec<String>,
+    pub(crate) aliases: HashMap<String, String>,
+}
+
+// What a caller's API key tier may see. Requests without a key are public; keys are assigned a
+// tier by API_KEY_TIERS, and the admin key is always internal.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub(crate) enum RedactionProfile {
+    Public,
+    Partner,
+    Internal,
+}
+
+// How precisely live bus positions are served. Coarse rounds coordinates to about 11 m and
+// speeds to COARSE_SPEED_STEP_KMH; tiers listed in COARSE_POSITION_TIERS always get it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub(crate) enum PositionPrecision {
+    Exact,
+    Coarse,
+}
+
+// What apply_redaction_profile settled on for a request, handed to handlers whose body it can't
+// reshape itself (the /gtfs protobuf). A response that carries it back was shaped by its handler
+// and is passed through untouched.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub(crate) struct ResponseShape {
+    pub(crate) profile: RedactionProfile,
+    pub(crate) precision: PositionPrecision,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum CaptainIdPrivacy {
+    Strip,
+    Hash,
+}
+
+// How driver-identifying fields are handled. Raw values never leave the process through the
+// API; retain_raw_captain_id only keeps them in a private Redis hash for internal lookups.
+#[derive(Debug, Clone)]
+pub(crate) struct PrivacySettings {
+    pub(crate) captain_id: CaptainIdPrivacy,
+    pub(crate) hash_salt: String,
+    pub(crate) retain_raw_captain_id: bool,
+}
+
+// Right after start the motion states, speed averages and active-bus history are thin. Until both
+// the minimum time has passed and enough ingest batches have arrived, responses say so and
+// alerts that depend on that history are held back.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Warmup {
+    pub(crate) started_at_unix_ms: i64,
+    pub(crate) min_duration_ms: i64,
+    pub(crate) min_batches: u64,
+    // WARMUP_GTFS_ONLY: refuse live endpoints until warmup ends instead of serving thin data.
+    pub(crate) gtfs_only: bool,
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct ThresholdsPatch {
+    pub(crate) max_derived_stop_distance_km: Option<f64>,
+    pub(crate) stationary_speed_threshold_kmh: Option<f64>,
+    pub(crate) stationary_distance_threshold_km: Option<f64>,
+    pub(crate) stationary_window_ms: Option<i64>,
+    pub(crate) default_speed_kmh: Option<f64>,
+    pub(crate) speed_ema_alpha: Option<f64>,
+    pub(crate) min_eta_speed_kmh: Option<f64>,
+    pub(crate) max_eta_speed_kmh: Option<f64>,
+    pub(crate) route_deviation_corridor_m: Option<f64>,
+    pub(crate) route_deviation_min_updates: Option<u32>,
+    pub(crate) arriving_soon_max_minutes: Option<f64>,
+    pub(crate) incoming_max_minutes: Option<f64>,
+}
+
+// A payload the ingestor could not turn into bus positions, kept for the admin dashboard.
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct DeadLetterSample {
+    pub(crate) received_at_unix_ms: i64,
+    pub(crate) reason: String,
+    pub(crate) payload_preview: String,
+}
+
+#[derive(Debug)]
+pub(crate) struct RedisBusSnapshot {
+    pub(crate) buses: Vec<BusPosition>,
+    pub(crate) motion_states: HashMap<String, BusMotionState>,
+    pub(crate) active_bus_count: usize,
+    pub(crate) outside_service_area_count: usize,
+    pub(crate) last_ingest_at_unix_ms: Option<i64>,
+    // When each bus last reported, from the last-seen sorted set.
+    pub(crate) last_seen_unix_ms: HashMap<String, i64>,
+    // The instant the snapshot describes; "now" for live reads, as_of for history replays.
+    pub(crate) captured_at_unix_ms: i64,
+}
+
+// Recent derived (or rejected) stop resolutions per bus, oldest first, kept in memory for
+// GET /debug/buses/{bus_no}/resolution-log. Synchronous so the ETA paths can record inline.
+#[derive(Debug, Default)]
+pub(crate) struct ResolutionLog {
+    pub(crate) by_bus: std::sync::Mutex<HashMap<String, VecDeque<StopResolutionRecord>>>,
+}
+
+// When buses laying over at a route's first stop are due out: the scheduled trip starts for today
+// and tomorrow, and the typical layover recorded there for when the schedule has none.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct TerminusSchedule {
+    pub(crate) first_stop_id: String,
+    // Ascending.
+    pub(crate) departures_unix_ms: Vec<i64>,
+    pub(crate) mean_layover_ms: Option<i64>,
+}
+
+// A sampled copy of the live snapshot, stored so ETAs can be recomputed for a past instant.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct HistoryFrame {
+    pub(crate) captured_at_unix_ms: i64,
+    pub(crate) buses: Vec<BusPosition>,
+    pub(crate) motion_states: HashMap<String, BusMotionState>,
+    pub(crate) active_bus_count: usize,
+    pub(crate) outside_service_area_count: usize,
+    pub(crate) last_ingest_at_unix_ms: Option<i64>,
+    // Absent from frames recorded before per-bus ages were kept.
+    #[serde(default)]
+    pub(crate) last_seen_unix_ms: HashMap<String, i64>,
+}
+
+// One received socket message as stored by FEED_RECORD_PATH, one JSON object per line.
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct RecordedFeedFrame {
+    pub(crate) received_at_unix_ms: i64,
+    pub(crate) payload: Vec<String>,
+}
+
+// The parsed static feed of one city, shared by every request until a reload replaces it.
+#[derive(Debug)]
+pub(crate) struct GtfsFeed {
+    pub(crate) routes: Vec<Route>,
+    pub(crate) trips_by_route: HashMap<String, Vec<Trip>>,
+    pub(crate) stop_times_by_trip: HashMap<String, Vec<StopTime>>,
+    pub(crate) stops_map: HashMap<String, Stop>,
+    pub(crate) stop_ids_by_code: HashMap<String, Vec<String>>,
+    // stop_id to the route_ids whose stop sequence includes it, in feed route order.
+    pub(crate) route_ids_by_stop: HashMap<String, Vec<String>>,
+    pub(crate) feed_version: String,
+    // Route shapes with their stops projected on, by normalized route code; empty without
+    // shapes.txt.
+    pub(crate) route_geometries: HashMap<String, RouteGeometry>,
+    // shapes.txt by shape_id; empty when the feed has none.
+    pub(crate) shapes_by_id: HashMap<String, Vec<ShapePoint>>,
+    // Empty when the feed has neither calendar.txt nor calendar_dates.txt.
+    pub(crate) calendars: ServiceCalendars,
+    // frequencies.txt by trip_id; empty for a feed run entirely to timetables.
+    pub(crate) frequencies_by_trip: HashMap<String, Vec<Frequency>>,
+    pub(crate) validation: GtfsValidationReport,
+}
+
+// The feed as one request sees it: the shared tables plus the detours in effect right now.
+// Derefs to the feed so its tables read as fields of the context.
+pub(crate) struct GtfsContext {
+    pub(crate) feed: Arc<GtfsFeed>,
+    pub(crate) route_stops_cache: Arc<RouteStopsCache>,
+    // Detours in effect when the context was loaded, by route_id.
+    pub(crate) detours: HashMap<String, Vec<RouteDetour>>,
+    // Kuala Lumpur date the context was loaded on; stop sequences follow trips running that day.
+    pub(crate) service_date: Option<NaiveDate>,
+}
+
+impl std::ops::Deref for GtfsContext {
+    type Target = GtfsFeed;
+
+    fn deref(&self) -> &GtfsFeed {
+        &self.feed
+    }
+}
+
+// (route_id, direction_id, feed_version, service_date). A new feed changes the version and a new
+// day the date, so entries built for the old ones are never served again and age out.
+pub(crate) type RouteStopsKey = (String, Option<u32>, String, Option<NaiveDate>);
+
+// Route stop sequences already built, handed out by Arc so ETA requests borrow them instead of
+// rebuilding. Bounded; when full the least recently used entry is dropped.
+#[derive(Debug, Default)]
+pub(crate) struct RouteStopsCache {
+    // Value is the sequence and the use tick it was last served at.
+    pub(crate) entries: std::sync::Mutex<HashMap<RouteStopsKey, (Arc<RouteStopsResponse>, u64)>>,
+    pub(crate) use_tick: AtomicU64,
+    pub(crate) hits: AtomicU64,
+    pub(crate) misses: AtomicU64,
+}
+
+// (redis key prefix, frame timestamp). Frames never change once written, so an entry stays
+// valid until it is evicted.
+pub(crate) type HistoryFrameKey = (String, i64);
+
+// A cached frame, its size and the use tick it was last served at.
+type HistoryFrameEntry = (Arc<HistoryFrame>, usize, u64);
+
+// Cumulative latency distribution over fixed bucket bounds, rendered in the Prometheus text
+// format. counts has one slot per bound plus a last one for +Inf.
+#[derive(Debug)]
+pub(crate) struct LatencyHistogram {
+    pub(crate) bounds_seconds: &'static [f64],
+    pub(crate) counts: Vec<AtomicU64>,
+    pub(crate) sum_ms: AtomicU64,
+}
+
+// Decoded history frames served to as_of requests, shared by every city so one budget caps
+// them all. Sized by the frame's JSON length; when over budget the least recently used frames
+// are dropped.
+#[derive(Debug, Default)]
+pub(crate) struct HistoryFrameCache {
+    // Zero disables the cache.
+    pub(crate) budget_bytes: usize,
+    pub(crate) entries: std::sync::Mutex<HashMap<HistoryFrameKey, HistoryFrameEntry>>,
+    pub(crate) used_bytes: AtomicUsize,
+    pub(crate) use_tick: AtomicU64,
+    pub(crate) hits: AtomicU64,
+    pub(crate) misses: AtomicU64,
+    pub(crate) evictions: AtomicU64,
+}
+
+// Rollout percentage per flag: 0 is off, 100 is on everywhere, and anything between puts a
+// stable share of subjects (stops, routes) on the new behavior so both variants can be compared.
+#[derive(Debug, Default)]
+pub(crate) struct FeatureFlags {
+    // From FEATURE_FLAGS at startup; Redis overrides are layered on top on every refresh.
+    pub(crate) defaults: HashMap<String, u8>,
+    // Flags in shadow mode: requests are served with the flag off while the flagged variant is
+    // computed alongside and compared, whatever the rollout says.
+    pub(crate) shadow: HashSet<String>,
+    pub(crate) rollout_percent: std::sync::Mutex<HashMap<String, u8>>,
+    // (flag, enabled) to the number of times a request was evaluated into that variant.
+    pub(crate) evaluations: std::sync::Mutex<HashMap<(String, bool), u64>>,
+}
+
+// Both predictions for one bus at one stop, kept until the bus is seen departing the stop.
+#[derive(Debug, Clone)]
+pub(crate) struct ShadowPrediction {
+    pub(crate) flag: String,
+    pub(crate) predicted_at_unix_ms: i64,
+    pub(crate) served_arrival_unix_ms: i64,
+    pub(crate) shadow_arrival_unix_ms: i64,
+}
+
+// Running totals per flag; divide the sums by their counts for mean divergence and error.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct ShadowStats {
+    pub(crate) comparisons: u64,
+    pub(crate) divergence_minutes_sum: f64,
+    pub(crate) scored: u64,
+    pub(crate) served_error_minutes_sum: f64,
+    pub(crate) shadow_error_minutes_sum: f64,
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct ShadowEvaluation {
+    // (stop_id, "route_id|bus_no"), the same member format as the stop departure log.
+    pub(crate) pending: std::sync::Mutex<HashMap<(String, String), ShadowPrediction>>,
+    pub(crate) stats: std::sync::Mutex<HashMap<String, ShadowStats>>,
+}
+
+// Every Redis key under one city's prefix, as written by GET /admin/snapshot/export. Values are
+// Redis DUMP payloads, so any key type round-trips; names are relative to the prefix so a dump
+// can be restored under another one.
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct RedisStateSnapshot {
+    pub(crate) version: u32,
+    pub(crate) key_prefix: String,
+    pub(crate) exported_at_unix_ms: i64,
+    pub(crate) keys: Vec<RedisKeyDump>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct RedisKeyDump {
+    pub(crate) key: String,
+    // Remaining time to live; None for keys that never expire.
+    pub(crate) pttl_ms: Option<i64>,
+    // Base64 of the DUMP payload.
+    pub(crate) dump: String,
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct SnapshotImportResponse {
+    pub(crate) key_prefix: String,
+    pub(crate) restored_keys: usize,
+}
+
+// What is left of a bus once it expires from the live fleet, kept for BUS_TOMBSTONE_RETENTION_MS.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct BusTombstone {
+    pub(crate) bus: BusPosition,
+    pub(crate) last_seen_unix_ms: i64,
+    pub(crate) removed_at_unix_ms: i64,
+}
+
+// Stop boards precomputed for the most requested stops. A board is served only while its
+// ingest_seq matches the latest batch; anything older falls back to computing on demand.
+#[derive(Debug, Default)]
+pub(crate) struct HotStopBoards {
+    pub(crate) boards: RwLock<HashMap<String, HotStopBoard>>,
+    pub(crate) hits_total: AtomicU64,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct HotStopBoard {
+    pub(crate) ingest_seq: u64,
+    pub(crate) response: StopIncomingResponse,
+}
+
+// Request counts since the last flush to Redis, by route pattern and by the stop_id or route_id
+// in the path. Only successful requests are counted, so unknown ids never take up a field.
+#[derive(Debug, Default)]
+pub(crate) struct UsageCounters {
+    pub(crate) endpoints: std::sync::Mutex<HashMap<String, u64>>,
+    pub(crate) stops: std::sync::Mutex<HashMap<String, u64>>,
+    pub(crate) routes: std::sync::Mutex<HashMap<String, u64>>,
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct VehicleRosterRow {
+    pub(crate) bus_no: String,
+    pub(crate) model: Option<String>,
+    pub(crate) depot: Option<String>,
+    pub(crate) capacity: Option<u32>,
+    pub(crate) wheelchair_lift: Option<String>,
+}
+
+// A GTFS-RT alert as stored in Redis; first_seen_unix_ms survives re-polls of the same id.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct ServiceAlert {
+    pub(crate) id: String,
+    pub(crate) header: String,
+    pub(crate) description: Option<String>,
+    pub(crate) url: Option<String>,
+    pub(crate) route_ids: Vec<String>,
+    pub(crate) stop_ids: Vec<String>,
+    pub(crate) active_from_unix_ms: Option<i64>,
+    pub(crate) active_until_unix_ms: Option<i64>,
+    pub(crate) first_seen_unix_ms: i64,
+    pub(crate) updated_at_unix_ms: i64,
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn fixture_snapshot_parses_as_a_history_frame() {
+        let frame: HistoryFrame =
+            serde_json::from_str(include_str!("../fixtures/snapshot.json")).unwrap();
+        assert_eq!(frame.captured_at_unix_ms, 1_772_409_600_000);
+        assert_eq!(frame.buses.len(), frame.active_bus_count);
+        // Recorded before per-bus ages were kept.
+        assert!(frame.last_seen_unix_ms.is_empty());
+        let motion_state = &frame.motion_states["WB1234A"];
+        assert_eq!(motion_state.smoothed_speed_kmh, Some(22.5));
+        assert!(motion_state.chainage.is_none());
+        assert!(!motion_state.off_route);
+    }
+
+    #[test]
+    fn city_config_leaves_optional_fields_empty() {
+        let city: CityConfig = serde_json::from_value(json!({
+            "id": "kl",
+            "name": "Kuala Lumpur",
+            "gtfs_data_path": "rapid_kl_data",
+            "socket_url": "wss://example.invalid",
+            "provider": "RKL",
+        }))
+        .unwrap();
+        assert_eq!(city.redis_key_prefix, "");
+        assert!(city.gtfs_rt_vehicle_positions_url.is_none());
+        assert!(city.gtfs_static_url.is_none());
+        assert!(city.extra_gtfs_feeds.is_empty());
+    }
+
+    #[test]
+    fn gtfs_rows_read_with_optional_columns_missing() {
+        let mut stops = csv::Reader::from_reader(
+            "stop_id,stop_name,stop_desc,stop_lat,stop_lon\n\
+             1000838,KL1397 FLAT PKNS,,3.1219,101.6572\n"
+                .as_bytes(),
+        );
+        let stop: Stop = stops.deserialize().next().unwrap().unwrap();
+        assert!(stop.stop_code.is_none());
+        assert_eq!(stop.stop_desc, "");
+
+        let mut trips = csv::Reader::from_reader(
+            "route_id,service_id,trip_id,shape_id,trip_headsign,direction_id\n\
+             T7890,weekday,T7890-1,S1,,\n"
+                .as_bytes(),
+        );
+        let trip: Trip = trips.deserialize().next().unwrap().unwrap();
+        assert!(trip.trip_headsign.is_none());
+        assert!(trip.direction_id.is_none());
+    }
+}
@@ -0,0 +1,643 @@
+// Ingest pipeline: the ordered stages one socket payload goes through between the socket handler
+// and Redis. Each stage is metered on its own in IngestorStatus::stages.
+
+use futures_util::{future::BoxFuture, FutureExt};
+use rapidbro_eta::{
+    completed_dwell, departed_terminus, normalize_route_code, project_bus_chainage,
+    record_stop_passages, stops_passed_between, track_route_deviation, update_bus_motion_state,
+    BusMotionState, RouteGeometry, RuntimeSample, Thresholds,
+};
+use rapidbro_types::{BusPosition, IngestEvent, IngestStageStats, TripStartedEvent};
+use rust_socketio::Payload;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::time::Instant;
+
+use crate::eta::trip_started_event;
+use crate::gtfs::load_gtfs_context;
+use crate::ingest::{
+    dead_letter_sample, decode_bus_data, find_geofence, normalize_trip_metadata,
+    parse_bus_positions_from_json, prefix_extra_feed_ids, reconcile_duplicate_buses,
+};
+use crate::models::DeadLetterSample;
+use crate::store::{
+    load_motion_states, publish_bus_events, publish_ingest_event, publish_trip_started_events,
+    store_enriched_buses, StoreOutcome,
+};
+use crate::AppState;
+
+const DECODE_STAGE: &str = "decode";
+const NORMALIZE_STAGE: &str = "normalize";
+const VALIDATE_STAGE: &str = "validate";
+const DEDUPE_STAGE: &str = "dedupe";
+const GEOFENCE_STAGE: &str = "geofence";
+const MOTION_STAGE: &str = "motion";
+const STORE_STAGE: &str = "store";
+const PUBLISH_STAGE: &str = "publish";
+
+// What every stage of one payload may read. The thresholds are read once per payload so all
+// stages see the same values.
+pub(crate) struct IngestContext<'a> {
+    pub(crate) state: &'a AppState,
+    pub(crate) redis_conn: redis::aio::MultiplexedConnection,
+    pub(crate) route_geometries: &'a HashMap<String, RouteGeometry>,
+    pub(crate) thresholds: Thresholds,
+}
+
+// One payload on its way through the pipeline; each stage reads what earlier stages left and
+// fills in its own part.
+#[derive(Debug, Default)]
+pub(crate) struct IngestBatch {
+    pub(crate) received_at_unix_ms: i64,
+    pub(crate) payload: Option<Payload>,
+    // Decoded JSON text of each payload entry.
+    pub(crate) frames: Vec<String>,
+    pub(crate) buses: Vec<BusPosition>,
+    pub(crate) dead_letters: Vec<DeadLetterSample>,
+    pub(crate) merged_count: usize,
+    pub(crate) enrichments: HashMap<String, BusEnrichment>,
+    pub(crate) store: StoreOutcome,
+    pub(crate) stage_runs: Vec<IngestStageRun>,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct IngestStageRun {
+    name: &'static str,
+    elapsed_us: u64,
+    buses_dropped: usize,
+    failed: bool,
+}
+
+// What the motion stage worked out for one bus, written out by the store and publish stages.
+#[derive(Debug, Clone)]
+pub(crate) struct BusEnrichment {
+    pub(crate) motion_state: BusMotionState,
+    pub(crate) was_off_route: bool,
+    pub(crate) passed_stops: Vec<PassedStop>,
+    // Route the runtime samples were timed on; None when the bus has no previous state or shape.
+    pub(crate) runtime_route_id: Option<String>,
+    pub(crate) runtime_samples: Vec<RuntimeSample>,
+    pub(crate) completed_dwell: Option<(String, i64)>,
+    // The bus just pulled away from the first stop of runtime_route_id after laying over there.
+    trip_started: bool,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct PassedStop {
+    pub(crate) route_id: String,
+    // The stop before it on the shape, for segment activity; None at the first stop.
+    pub(crate) from_stop_id: Option<String>,
+    pub(crate) stop_id: String,
+}
+
+#[derive(Debug)]
+pub(crate) struct IngestStageError {
+    pub(crate) stage: &'static str,
+    pub(crate) message: String,
+}
+
+// One step of the pipeline. New enrichment is a new stage in default_ingest_stages; the
+// socket handler and replay never change.
+pub(crate) trait IngestStage: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    fn run<'a>(
+        &'a self,
+        context: &'a IngestContext<'a>,
+        batch: &'a mut IngestBatch,
+    ) -> BoxFuture<'a, Result<(), String>>;
+}
+
+pub(crate) fn default_ingest_stages() -> Vec<Box<dyn IngestStage>> {
+    vec![
+        Box::new(DecodeStage),
+        Box::new(NormalizeStage),
+        Box::new(ValidateStage),
+        Box::new(DedupeStage),
+        Box::new(GeofenceStage),
+        Box::new(MotionStage),
+        Box::new(StoreStage),
+        Box::new(PublishStage),
+    ]
+}
+
+// Runs the stages in order and stops at the first failure, so nothing downstream works from a
+// half-finished batch. Every stage that ran is recorded in batch.stage_runs.
+pub(crate) async fn run_ingest_pipeline(
+    stages: &[Box<dyn IngestStage>],
+    context: &IngestContext<'_>,
+    batch: &mut IngestBatch,
+) -> Result<(), IngestStageError> {
+    for stage in stages {
+        let started_at = Instant::now();
+        let buses_before = batch.buses.len();
+        let result = stage.run(context, batch).await;
+        batch.stage_runs.push(IngestStageRun {
+            name: stage.name(),
+            elapsed_us: started_at.elapsed().as_micros() as u64,
+            buses_dropped: buses_before.saturating_sub(batch.buses.len()),
+            failed: result.is_err(),
+        });
+        if let Err(message) = result {
+            return Err(IngestStageError {
+                stage: stage.name(),
+                message,
+            });
+        }
+    }
+    Ok(())
+}
+
+// Folds one payload's stage runs into the running per-stage meters, keeping pipeline order.
+pub(crate) fn record_stage_runs(stats: &mut Vec<IngestStageStats>, runs: &[IngestStageRun]) {
+    for run in runs {
+        let index = match stats.iter().position(|stage| stage.name == run.name) {
+            Some(index) => index,
+            None => {
+                stats.push(IngestStageStats {
+                    name: run.name.to_string(),
+                    runs: 0,
+                    failures: 0,
+                    buses_dropped: 0,
+                    total_us: 0,
+                    last_us: 0,
+                });
+                stats.len() - 1
+            }
+        };
+        let stage = &mut stats[index];
+        stage.runs += 1;
+        stage.failures += u64::from(run.failed);
+        stage.buses_dropped += run.buses_dropped as u64;
+        stage.total_us += run.elapsed_us;
+        stage.last_us = run.elapsed_us;
+    }
+}
+
+// base64 + gzip of each socket payload entry.
+struct DecodeStage;
+
+impl IngestStage for DecodeStage {
+    fn name(&self) -> &'static str {
+        DECODE_STAGE
+    }
+
+    fn run<'a>(
+        &'a self,
+        _context: &'a IngestContext<'a>,
+        batch: &'a mut IngestBatch,
+    ) -> BoxFuture<'a, Result<(), String>> {
+        async move {
+            let Some(Payload::Text(values)) = batch.payload.take() else {
+                return Ok(());
+            };
+            for value in values {
+                let Some(encoded_str) = value.as_str() else {
+                    continue;
+                };
+                match decode_bus_data(encoded_str) {
+                    Some(decoded) => batch.frames.push(decoded),
+                    None => batch.dead_letters.push(dead_letter_sample(
+                        batch.received_at_unix_ms,
+                        "base64/gzip decode failed",
+                        encoded_str,
+                    )),
+                }
+            }
+            Ok(())
+        }
+        .boxed()
+    }
+}
+
+// Decoded JSON into bus positions with trip metadata in one shape.
+struct NormalizeStage;
+
+impl IngestStage for NormalizeStage {
+    fn name(&self) -> &'static str {
+        NORMALIZE_STAGE
+    }
+
+    fn run<'a>(
+        &'a self,
+        context: &'a IngestContext<'a>,
+        batch: &'a mut IngestBatch,
+    ) -> BoxFuture<'a, Result<(), String>> {
+        async move {
+            for decoded in std::mem::take(&mut batch.frames) {
+                match parse_bus_positions_from_json(&decoded) {
+                    Some(parsed_buses) => batch.buses.extend(parsed_buses.into_iter().map(|bus| {
+                        normalize_trip_metadata(prefix_extra_feed_ids(bus, &context.state.city))
+                    })),
+                    None => batch.dead_letters.push(dead_letter_sample(
+                        batch.received_at_unix_ms,
+                        "JSON did not match the bus position schema",
+                        &decoded,
+                    )),
+                }
+            }
+            Ok(())
+        }
+        .boxed()
+    }
+}
+
+// A report without a bus_no cannot be keyed anywhere, so it goes no further.
+struct ValidateStage;
+
+impl IngestStage for ValidateStage {
+    fn name(&self) -> &'static str {
+        VALIDATE_STAGE
+    }
+
+    fn run<'a>(
+        &'a self,
+        _context: &'a IngestContext<'a>,
+        batch: &'a mut IngestBatch,
+    ) -> BoxFuture<'a, Result<(), String>> {
+        async move {
+            batch.buses.retain(|bus| !bus.bus_no.trim().is_empty());
+            Ok(())
+        }
+        .boxed()
+    }
+}
+
+struct DedupeStage;
+
+impl IngestStage for DedupeStage {
+    fn name(&self) -> &'static str {
+        DEDUPE_STAGE
+    }
+
+    fn run<'a>(
+        &'a self,
+        context: &'a IngestContext<'a>,
+        batch: &'a mut IngestBatch,
+    ) -> BoxFuture<'a, Result<(), String>> {
+        async move {
+            let (buses, merged_count) = reconcile_duplicate_buses(
+                std::mem::take(&mut batch.buses),
+                &context.state.bus_no_rules,
+            );
+            batch.buses = buses;
+            batch.merged_count += merged_count;
+            Ok(())
+        }
+        .boxed()
+    }
+}
+
+// Depot and service-area flags from the configured geofences.
+struct GeofenceStage;
+
+impl IngestStage for GeofenceStage {
+    fn name(&self) -> &'static str {
+        GEOFENCE_STAGE
+    }
+
+    fn run<'a>(
+        &'a self,
+        context: &'a IngestContext<'a>,
+        batch: &'a mut IngestBatch,
+    ) -> BoxFuture<'a, Result<(), String>> {
+        async move {
+            let state = context.state;
+            for bus in &mut batch.buses {
+                bus.depot_name = find_geofence(&state.depots, bus.latitude, bus.longitude)
+                    .map(|depot| depot.name.clone());
+                bus.in_depot = bus.depot_name.is_some();
+                bus.outside_service_area = !state.service_area.is_empty()
+                    && find_geofence(&state.service_area, bus.latitude, bus.longitude).is_none();
+            }
+            Ok(())
+        }
+        .boxed()
+    }
+}
+
+// Route match, chainage and stop resolution: each bus's motion state is carried forward from
+// the one stored at its previous report.
+struct MotionStage;
+
+impl IngestStage for MotionStage {
+    fn name(&self) -> &'static str {
+        MOTION_STAGE
+    }
+
+    fn run<'a>(
+        &'a self,
+        context: &'a IngestContext<'a>,
+        batch: &'a mut IngestBatch,
+    ) -> BoxFuture<'a, Result<(), String>> {
+        async move {
+            let bus_ids: Vec<String> = batch
+                .buses
+                .iter()
+                .map(|bus| bus.bus_no.clone())
+                .collect::<HashSet<_>>()
+                .into_iter()
+                .collect();
+            let mut redis_conn = context.redis_conn.clone();
+            let previous_motion_states =
+                load_motion_states(&mut redis_conn, &context.state.redis_keys, &bus_ids).await?;
+            for bus in &batch.buses {
+                let enrichment = enrich_bus_motion(
+                    previous_motion_states.get(&bus.bus_no),
+                    bus,
+                    batch.received_at_unix_ms,
+                    &context.thresholds,
+                    context.route_geometries,
+                );
+                batch.enrichments.insert(bus.bus_no.clone(), enrichment);
+            }
+            Ok(())
+        }
+        .boxed()
+    }
+}
+
+fn enrich_bus_motion(
+    previous: Option<&BusMotionState>,
+    bus: &BusPosition,
+    now_ms: i64,
+    thresholds: &Thresholds,
+    route_geometries: &HashMap<String, RouteGeometry>,
+) -> BusEnrichment {
+    let mut motion_state = update_bus_motion_state(previous, bus, now_ms, thresholds);
+    motion_state.chainage = project_bus_chainage(bus, route_geometries, now_ms, thresholds);
+    track_route_deviation(
+        previous,
+        &mut motion_state,
+        bus,
+        route_geometries,
+        thresholds,
+    );
+
+    let geometry = route_geometries.get(&normalize_route_code(&bus.route));
+    let mut passed_stops = Vec::new();
+    if let (Some(previous_chainage), Some(current), Some(geometry)) = (
+        previous.and_then(|state| state.chainage.as_ref()),
+        motion_state.chainage.as_ref(),
+        geometry,
+    ) {
+        for stop_id in stops_passed_between(geometry, previous_chainage, current) {
+            let from_stop_id = geometry
+                .stop_chainages
+                .iter()
+                .position(|(id, _)| id == stop_id)
+                .and_then(|index| index.checked_sub(1))
+                .map(|index| geometry.stop_chainages[index].0.clone());
+            passed_stops.push(PassedStop {
+                route_id: geometry.route_id.clone(),
+                from_stop_id,
+                stop_id: stop_id.to_string(),
+            });
+        }
+    }
+
+    let (runtime_route_id, runtime_samples) = match (previous, geometry) {
+        (Some(previous), Some(geometry)) => (
+            Some(geometry.route_id.clone()),
+            record_stop_passages(geometry, previous, &mut motion_state),
+        ),
+        _ => (None, Vec::new()),
+    };
+    let completed_dwell = previous
+        .and_then(|previous| completed_dwell(previous, &motion_state, bus, geometry, now_ms));
+    let trip_started = previous.is_some_and(|previous| {
+        departed_terminus(previous, &motion_state, geometry, now_ms, thresholds)
+    });
+
+    BusEnrichment {
+        was_off_route: previous.is_some_and(|state| state.off_route),
+        motion_state,
+        passed_stops,
+        runtime_route_id,
+        runtime_samples,
+        completed_dwell,
+        trip_started,
+    }
+}
+
+struct StoreStage;
+
+impl IngestStage for StoreStage {
+    fn name(&self) -> &'static str {
+        STORE_STAGE
+    }
+
+    fn run<'a>(
+        &'a self,
+        context: &'a IngestContext<'a>,
+        batch: &'a mut IngestBatch,
+    ) -> BoxFuture<'a, Result<(), String>> {
+        async move {
+            if batch.buses.is_empty() {
+                return Ok(());
+            }
+            let mut redis_conn = context.redis_conn.clone();
+            batch.store = store_enriched_buses(
+                &mut redis_conn,
+                &context.state.redis_keys,
+                &batch.buses,
+                &batch.enrichments,
+                batch.received_at_unix_ms,
+                &context.state.privacy,
+            )
+            .await;
+            // Partial failures are reported through the outcome; only a batch that lost every
+            // write fails the stage.
+            if batch.store.stored.is_empty() && batch.store.failed_chunks > 0 {
+                return Err(batch
+                    .store
+                    .last_error
+                    .clone()
+                    .unwrap_or_else(|| "no bus could be written".to_string()));
+            }
+            Ok(())
+        }
+        .boxed()
+    }
+}
+
+// Bus events for stream consumers, then the batch's ingest event for pub/sub subscribers, once
+// the positions they describe are stored.
+struct PublishStage;
+
+impl IngestStage for PublishStage {
+    fn name(&self) -> &'static str {
+        PUBLISH_STAGE
+    }
+
+    fn run<'a>(
+        &'a self,
+        context: &'a IngestContext<'a>,
+        batch: &'a mut IngestBatch,
+    ) -> BoxFuture<'a, Result<(), String>> {
+        async move {
+            let mut redis_conn = context.redis_conn.clone();
+            publish_bus_events(
+                &mut redis_conn,
+                &context.state.redis_keys,
+                &batch.store.stored,
+                &batch.enrichments,
+            )
+            .await?;
+
+            let started: Vec<&BusPosition> = batch
+                .buses
+                .iter()
+                .filter(|bus| {
+                    batch
+                        .enrichments
+                        .get(&bus.bus_no)
+                        .is_some_and(|enrichment| enrichment.trip_started)
+                })
+                .filter(|bus| {
+                    batch
+                        .store
+                        .stored
+                        .iter()
+                        .any(|(bus_no, _)| *bus_no == bus.bus_no)
+                })
+                .collect();
+            if !started.is_empty() {
+                let gtfs = load_gtfs_context(context.state);
+                let events: Vec<TripStartedEvent> = started
+                    .into_iter()
+                    .filter_map(|bus| {
+                        trip_started_event(
+                            &gtfs,
+                            bus,
+                            batch.enrichments.get(&bus.bus_no)?,
+                            &context.thresholds,
+                            batch.received_at_unix_ms,
+                        )
+                    })
+                    .collect();
+                publish_trip_started_events(&mut redis_conn, &context.state.redis_keys, &events)
+                    .await?;
+            }
+
+            let Some(snapshot_seq) = batch.store.snapshot_seq else {
+                return Ok(());
+            };
+            let stored_bus_nos: HashSet<&str> = batch
+                .store
+                .stored
+                .iter()
+                .map(|(bus_no, _)| bus_no.as_str())
+                .collect();
+            let mut route_counts = BTreeMap::new();
+            for bus in batch
+                .buses
+                .iter()
+                .filter(|bus| stored_bus_nos.contains(bus.bus_no.as_str()))
+            {
+                *route_counts
+                    .entry(normalize_route_code(&bus.route))
+                    .or_insert(0) += 1;
+            }
+            let event = IngestEvent {
+                snapshot_seq,
+                written_at_unix_ms: batch.received_at_unix_ms,
+                bus_nos: batch
+                    .store
+                    .stored
+                    .iter()
+                    .map(|(bus_no, _)| bus_no.clone())
+                    .collect(),
+                route_counts,
+            };
+            publish_ingest_event(&mut redis_conn, &context.state.redis_keys, &event).await
+        }
+        .boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rapidbro_eta::is_bus_stationary;
+
+    use super::*;
+    use crate::eta::thresholds_from_env;
+    use crate::test_support::{test_bus, TestClock};
+    use crate::Clock;
+
+    const NOW_MS: i64 = 1_772_409_600_000;
+
+    // Runs one motion stage update for bus at the clock's current time.
+    fn enrich(
+        previous: Option<&BusMotionState>,
+        bus: &BusPosition,
+        clock: &TestClock,
+    ) -> BusMotionState {
+        enrich_bus_motion(
+            previous,
+            bus,
+            clock.now_ms(),
+            &thresholds_from_env(),
+            &HashMap::new(),
+        )
+        .motion_state
+    }
+
+    fn stationary(state: &BusMotionState, clock: &TestClock) -> bool {
+        let motion_states = HashMap::from([("WXY1234".to_string(), state.clone())]);
+        is_bus_stationary(
+            &motion_states,
+            "WXY1234",
+            clock.now_ms(),
+            &thresholds_from_env(),
+        )
+    }
+
+    #[test]
+    fn idle_bus_turns_stationary_once_the_window_has_passed() {
+        let window_ms = thresholds_from_env().stationary_window_ms;
+        let clock = TestClock::new(NOW_MS);
+        let idle = test_bus("WXY1234", 3.1, 101.7, 0.0);
+
+        let state = enrich(None, &idle, &clock);
+        assert_eq!(state.stationary_since_unix_ms, Some(NOW_MS));
+        assert!(!stationary(&state, &clock));
+
+        clock.advance(window_ms - 1_000);
+        let state = enrich(Some(&state), &idle, &clock);
+        // Later idle updates keep the original start of the window.
+        assert_eq!(state.stationary_since_unix_ms, Some(NOW_MS));
+        assert!(!stationary(&state, &clock));
+
+        clock.advance(1_000);
+        assert!(stationary(&state, &clock));
+    }
+
+    #[test]
+    fn moving_off_resets_the_stationary_window() {
+        let window_ms = thresholds_from_env().stationary_window_ms;
+        let clock = TestClock::new(NOW_MS);
+        let state = enrich(None, &test_bus("WXY1234", 3.1, 101.7, 0.0), &clock);
+
+        clock.advance(window_ms);
+        assert!(stationary(&state, &clock));
+
+        // About 1 km further on at speed.
+        let state = enrich(
+            Some(&state),
+            &test_bus("WXY1234", 3.109, 101.7, 30.0),
+            &clock,
+        );
+        assert_eq!(state.stationary_since_unix_ms, None);
+        assert!(!stationary(&state, &clock));
+
+        // Pulling up again starts a fresh window from the new position.
+        clock.advance(10_000);
+        let state = enrich(
+            Some(&state),
+            &test_bus("WXY1234", 3.109, 101.7, 0.0),
+            &clock,
+        );
+        assert_eq!(state.stationary_since_unix_ms, Some(clock.now_ms()));
+        assert!(!stationary(&state, &clock));
+    }
+}
@@ -0,0 +1,3079 @@
+// Redis-backed bus snapshot, history frames, incidents and snapshot export.
+
+use axum::http::header::CONTENT_TYPE;
+use base64::Engine;
+use chrono::{FixedOffset, NaiveDate, TimeZone};
+use flate2::{write::GzEncoder, Compression};
+use futures_util::StreamExt;
+use hmac::{Hmac, Mac};
+use rapidbro_eta::{
+    haversine_distance, is_bus_on_route, is_bus_stationary, is_on_detour, normalize_route_code,
+    BusMotionState, RuntimeSample, KL_UTC_OFFSET_SECONDS,
+};
+use rapidbro_types::{
+    BusPosition, DailyRouteReport, Incident, IncidentKind, IngestEvent, RecentDeparture,
+    RouteDayStats, RouteDetour, RouteHourStats, RouteRuntimesResponse, RuntimeHourStats,
+    SegmentRuntimeProfile, StopClosure, TripStartedEvent, UsageCount, UsageResponse,
+};
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::json;
+use sha2::Sha256;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::env;
+use std::fs::File;
+use std::io::Write;
+use std::sync::{atomic::Ordering as AtomicOrdering, Arc};
+use std::time::Duration;
+use teloxide::{prelude::Requester, types::ChatId, Bot};
+use tokio::time::MissedTickBehavior;
+
+use crate::api::{internal_error, is_known_feature_flag, AppError, Stage, StageTimer};
+use crate::eta::{
+    eta_stops_by_route, DWELL_BUCKETS, MIN_RUNTIME_PROFILE_SAMPLES, RECENT_DEPARTURE_WINDOW_MS,
+};
+use crate::gtfs::{
+    current_gtfs_feed, kl_hour_of_day, load_gtfs_context, scheduled_trip_starts_for_route,
+    LoadError,
+};
+use crate::ingest::{apply_captain_id_privacy, is_warming_up, reconcile_duplicate_buses};
+use crate::models::{
+    BusTombstone, HistoryFrame, HistoryFrameCache, HistoryFrameKey, PrivacySettings,
+    RedisBusSnapshot, RedisKeyDump, RedisStateSnapshot, ReplicaLag, TerminusSchedule,
+};
+use crate::pipeline::BusEnrichment;
+use crate::{env_or, AppState, Clock};
+
+// Every key lives under "{prefix}:" so several environments or cities can share one Redis
+// instance. Set with REDIS_KEY_PREFIX, or per city in the city registry.
+#[derive(Debug, Clone)]
+pub(crate) struct RedisKeys {
+    pub(crate) prefix: String,
+}
+
+impl RedisKeys {
+    pub(crate) fn key(&self, name: &str) -> String {
+        format!("{}:{}", self.prefix, name)
+    }
+}
+
+pub(crate) const DEFAULT_REDIS_KEY_PREFIX: &str = "rapidbro";
+// Key names below are relative to the configured prefix; build full keys with RedisKeys::key.
+const REDIS_BUSES_LATEST_KEY: &str = "buses:latest";
+pub(crate) const REDIS_BUSES_LAST_SEEN_KEY: &str = "buses:last_seen";
+const REDIS_BUSES_MOTION_KEY: &str = "buses:motion";
+const REDIS_INGEST_LAST_KEY: &str = "ingestor:last_ingest_at";
+const REDIS_INGEST_EVENTS_CHANNEL: &str = "ingest:events";
+const REDIS_TRIP_EVENTS_CHANNEL: &str = "events:trips";
+const INGEST_EVENT_RESUBSCRIBE_MAX_SECONDS: u64 = 30;
+pub(crate) const REDIS_ROUTES_LAST_SEEN_KEY: &str = "routes:last_seen";
+// Bumped on every ingest write and every prune; /get-all deltas are expressed against it.
+const REDIS_SNAPSHOT_SEQ_KEY: &str = "snapshot:seq";
+const REDIS_BUSES_CHANGED_SEQ_KEY: &str = "buses:changed_seq";
+const REDIS_BUSES_REMOVED_SEQ_KEY: &str = "buses:removed_seq";
+// Tombstones of pruned buses: a hash of bus_no to BusTombstone plus a ZSET of removal times.
+const REDIS_BUS_TOMBSTONES_KEY: &str = "buses:tombstones";
+const REDIS_BUS_TOMBSTONES_REMOVED_AT_KEY: &str = "buses:tombstones:removed_at";
+pub(crate) const BUS_TOMBSTONE_RETENTION_MS: i64 = 60 * 60_000;
+// Redis stream of bus lifecycle events: event=removed with data=BusTombstone JSON, and
+// event=off_route/on_route with data=BusPosition JSON.
+const REDIS_BUS_EVENTS_KEY: &str = "events:buses";
+const BUS_EVENTS_MAX_LEN: usize = 10_000;
+const BUS_EVENT_WEBHOOK_POLL_SECONDS: u64 = 5;
+const BUS_EVENT_WEBHOOK_BATCH: usize = 100;
+const BUS_EVENT_WEBHOOK_ATTEMPTS: u32 = 3;
+// Doubled after every failed attempt.
+const BUS_EVENT_WEBHOOK_RETRY_BASE_MS: u64 = 500;
+const BUS_EVENT_SIGNATURE_HEADER: &str = "x-rapidbro-signature";
+const REDIS_STATE_SNAPSHOT_VERSION: u32 = 1;
+const REDIS_STATE_SNAPSHOT_BATCH: usize = 500;
+// Key name prefixes left out of state snapshots: raw captain ids never leave the instance, and
+// history frames are only included on request since they dwarf everything else.
+const REDIS_PRIVATE_KEY_PREFIX: &str = "private:";
+const REDIS_HISTORY_KEY_PREFIX: &str = "history:";
+// Removals older than this many sequence steps are forgotten; clients further behind get the
+// full fleet again.
+pub(crate) const DELTA_SYNC_MAX_LAG_SEQS: u64 = 2_000;
+// Buses per atomic write pipeline; a failed chunk loses only these.
+const INGEST_WRITE_CHUNK_SIZE: usize = 50;
+const INGEST_WRITE_MAX_ATTEMPTS: u32 = 3;
+// Doubles after every retry.
+const INGEST_WRITE_RETRY_BASE_MS: u64 = 50;
+pub(crate) const REDIS_THRESHOLDS_KEY: &str = "config:thresholds";
+const REDIS_STOP_DEPARTURES_KEY_PREFIX: &str = "stops:departures:";
+pub(crate) const REDIS_STOP_DWELL_KEY_PREFIX: &str = "stops:dwell:";
+const REDIS_ROUTE_RUNTIMES_KEY_PREFIX: &str = "routes:runtimes:";
+const RUNTIME_PROFILE_REFRESH_SECONDS: u64 = 600;
+const TERMINUS_SCHEDULE_REFRESH_SECONDS: u64 = 300;
+const REPLICA_LAG_CHECK_SECONDS: u64 = 5;
+// Hash of flag name to rollout percentage, written by PATCH /admin/feature-flags.
+pub(crate) const REDIS_FEATURE_FLAGS_KEY: &str = "config:feature_flags";
+const FEATURE_FLAG_REFRESH_SECONDS: u64 = 30;
+// detour id to RouteDetour JSON.
+pub(crate) const REDIS_DETOURS_KEY: &str = "config:detours";
+// closure id to StopClosure JSON.
+pub(crate) const REDIS_STOP_CLOSURES_KEY: &str = "config:stop_closures";
+const SERVICE_CHANGE_REFRESH_SECONDS: u64 = 30;
+const REDIS_PRIVATE_CAPTAIN_IDS_KEY: &str = "private:captain_ids";
+const ACTIVE_BUS_SAMPLE_INTERVAL_SECONDS: u64 = 60;
+const MAX_ACTIVE_BUS_SAMPLES: usize = 60;
+const REDIS_INCIDENTS_KEY: &str = "incidents";
+const ANOMALY_CHECK_INTERVAL_SECONDS: u64 = 60;
+const ANOMALY_BUS_DROP_WINDOW_MS: i64 = 5 * 60_000;
+const ANOMALY_BUS_DROP_MIN_BASELINE: usize = 10;
+const ANOMALY_MIN_DECODE_FAILURES: u64 = 10;
+const ANOMALY_DECODE_FAILURE_RATIO: f64 = 0.2;
+const ANOMALY_IDENTICAL_COORDINATES_MIN_BUSES: usize = 3;
+const ANOMALY_ROUTE_DEVIATION_MAX_LISTED: usize = 10;
+const RESOLVED_INCIDENT_RETENTION_MS: i64 = 7 * 24 * 3_600_000;
+pub(crate) const REDIS_SERVICE_ALERTS_KEY: &str = "alerts";
+// Sampled snapshots for as_of queries: a ZSET of frame timestamps plus one expiring key each.
+const REDIS_HISTORY_FRAMES_KEY: &str = "history:frames";
+const REDIS_HISTORY_FRAME_KEY_PREFIX: &str = "history:frame:";
+pub(crate) const DEFAULT_HISTORY_SAMPLE_SECONDS: u64 = 30;
+pub(crate) const DEFAULT_HISTORY_CACHE_BUDGET_MB: usize = 64;
+const DEFAULT_EXPORT_INTERVAL_SECONDS: u64 = 300;
+const DEFAULT_EXPORT_S3_REGION: &str = "us-east-1";
+const DEFAULT_EXPORT_S3_PREFIX: &str = "rapidbro";
+const REDIS_ROUTE_REPORT_KEY_PREFIX: &str = "reports:routes:";
+// One hash per KL day and kind: usage:{date}:endpoints, usage:{date}:stops, usage:{date}:routes.
+const REDIS_USAGE_KEY_PREFIX: &str = "usage:";
+pub(crate) const USAGE_FLUSH_SECONDS: u64 = 60;
+pub(crate) const USAGE_RETENTION_DAYS: u32 = 30;
+// One hash per hour: stop|{stop_id} and segment|{route_id}|{from_stop_id}|{to_stop_id} to the
+// number of buses that passed.
+const REDIS_ACTIVITY_KEY_PREFIX: &str = "activity:";
+pub(crate) const ACTIVITY_RETENTION_HOURS: u32 = 7 * 24;
+const ROUTE_REPORT_RETENTION_MS: i64 = 90 * 24 * 3_600_000;
+// Reports run this long after KL midnight so the day's last history frames are in.
+const ROUTE_REPORT_DELAY_MS: i64 = 15 * 60_000;
+const ROUTE_REPORT_FRAME_BATCH: usize = 100;
+// Consecutive fixes implying more than this are a gap in the trajectory, not distance driven.
+const ROUTE_REPORT_MAX_SEGMENT_SPEED_KMH: f64 = 120.0;
+// Reads go to the replica when REDIS_READ_URL is set; writes always go to redis_client.
+pub(crate) fn read_redis_client(state: &AppState) -> &redis::Client {
+    state
+        .redis_read_client
+        .as_ref()
+        .unwrap_or(&state.redis_client)
+}
+
+// A replica that has lost its primary, or trails it by more than the staleness window, serves
+// data that looks current but isn't; responses built from it are flagged stale.
+pub(crate) fn replica_is_behind(lag: &ReplicaLag, stale_after_ms: i64) -> bool {
+    !lag.link_up || lag.lag_ms > stale_after_ms
+}
+
+pub(crate) async fn load_active_bus_snapshot(
+    state: &AppState,
+) -> Result<RedisBusSnapshot, AppError> {
+    let _timer = StageTimer::start(Stage::Redis);
+    if let Some(frame) = &state.fixture {
+        return Ok(snapshot_from_frame(
+            frame.as_ref().clone(),
+            state.clock.now_ms(),
+        ));
+    }
+    let now_ms = state.clock.now_ms();
+    let mut redis_conn = state
+        .redis_client
+        .get_multiplexed_async_connection()
+        .await?;
+    let cutoff_ms = prune_stale_buses(
+        &mut redis_conn,
+        &state.redis_keys,
+        state.clock.as_ref(),
+        state.bus_ttl_ms,
+    )
+    .await?;
+    // Pruning is a write; the snapshot itself is read from the replica when there is one.
+    if let Some(read_client) = &state.redis_read_client {
+        redis_conn = read_client.get_multiplexed_async_connection().await?;
+    }
+
+    let last_seen: Vec<(String, f64)> = redis::cmd("ZRANGEBYSCORE")
+        .arg(state.redis_keys.key(REDIS_BUSES_LAST_SEEN_KEY))
+        .arg(cutoff_ms + 1)
+        .arg("+inf")
+        .arg("WITHSCORES")
+        .query_async(&mut redis_conn)
+        .await?;
+    let active_bus_ids: Vec<String> = last_seen.iter().map(|(bus_no, _)| bus_no.clone()).collect();
+    let last_seen_unix_ms: HashMap<String, i64> = last_seen
+        .into_iter()
+        .map(|(bus_no, seen_ms)| (bus_no, seen_ms as i64))
+        .collect();
+
+    let buses: Vec<BusPosition> = if active_bus_ids.is_empty() {
+        Vec::new()
+    } else {
+        let raw_buses: Vec<Option<String>> = redis::cmd("HMGET")
+            .arg(state.redis_keys.key(REDIS_BUSES_LATEST_KEY))
+            .arg(&active_bus_ids)
+            .query_async(&mut redis_conn)
+            .await?;
+
+        raw_buses
+            .into_iter()
+            .flatten()
+            .filter_map(|entry| serde_json::from_str::<BusPosition>(&entry).ok())
+            .collect()
+    };
+    // Ids are canonical at ingest, but ghosts reported in separate messages only meet here.
+    let (buses, merged_count) = reconcile_duplicate_buses(buses, &state.bus_no_rules);
+    // Positions outside the service area are GPS faults; keep them out of every public view
+    // but report how many there are.
+    let (outside_service_area, mut buses): (Vec<BusPosition>, Vec<BusPosition>) =
+        buses.into_iter().partition(|bus| bus.outside_service_area);
+
+    let motion_states: HashMap<String, BusMotionState> = if active_bus_ids.is_empty() {
+        HashMap::new()
+    } else {
+        let raw_states: Vec<Option<String>> = redis::cmd("HMGET")
+            .arg(state.redis_keys.key(REDIS_BUSES_MOTION_KEY))
+            .arg(&active_bus_ids)
+            .query_async(&mut redis_conn)
+            .await?;
+
+        active_bus_ids
+            .iter()
+            .cloned()
+            .zip(raw_states)
+            .filter_map(|(bus_no, raw_state)| {
+                raw_state.and_then(|value| {
+                    serde_json::from_str::<BusMotionState>(&value)
+                        .ok()
+                        .map(|state| (bus_no, state))
+                })
+            })
+            .collect()
+    };
+
+    let last_ingest_at_unix_ms: Option<i64> = redis::cmd("GET")
+        .arg(state.redis_keys.key(REDIS_INGEST_LAST_KEY))
+        .query_async(&mut redis_conn)
+        .await
+        .unwrap_or(None);
+
+    // A bus following a declared detour is where it should be.
+    let detours = active_detours(state, now_ms);
+    let thresholds = *state.thresholds.read().await;
+    for bus in &mut buses {
+        bus.off_route = motion_states
+            .get(&bus.bus_no)
+            .is_some_and(|state| state.off_route)
+            && !detours.iter().any(|(route_id, route_detours)| {
+                is_bus_on_route(&bus.route, route_id)
+                    && is_on_detour(bus, route_detours, &thresholds)
+            });
+    }
+
+    Ok(RedisBusSnapshot {
+        buses,
+        motion_states,
+        active_bus_count: active_bus_ids
+            .len()
+            .saturating_sub(merged_count + outside_service_area.len()),
+        outside_service_area_count: outside_service_area.len(),
+        last_ingest_at_unix_ms,
+        last_seen_unix_ms,
+        captured_at_unix_ms: now_ms,
+    })
+}
+
+// The tombstone of a bus that left the live fleet within BUS_TOMBSTONE_RETENTION_MS.
+pub(crate) async fn load_bus_tombstone(
+    state: &AppState,
+    bus_no: &str,
+) -> Result<Option<BusTombstone>, AppError> {
+    if state.fixture.is_some() {
+        return Ok(None);
+    }
+    let _timer = StageTimer::start(Stage::Redis);
+    let mut redis_conn = read_redis_client(state)
+        .get_multiplexed_async_connection()
+        .await?;
+    let raw: Option<String> = redis::cmd("HGET")
+        .arg(state.redis_keys.key(REDIS_BUS_TOMBSTONES_KEY))
+        .arg(bus_no)
+        .query_async(&mut redis_conn)
+        .await?;
+    let cutoff_ms = state.clock.now_ms() - BUS_TOMBSTONE_RETENTION_MS;
+    Ok(raw
+        .and_then(|raw| serde_json::from_str::<BusTombstone>(&raw).ok())
+        .filter(|tombstone| tombstone.removed_at_unix_ms > cutoff_ms))
+}
+
+pub(crate) async fn export_redis_state(
+    state: &AppState,
+    include_history: bool,
+) -> Result<RedisStateSnapshot, AppError> {
+    let mut redis_conn = state
+        .redis_client
+        .get_multiplexed_async_connection()
+        .await?;
+    let full_prefix = state.redis_keys.key("");
+    let mut key_names = Vec::new();
+    let mut cursor: u64 = 0;
+    loop {
+        let (next_cursor, batch): (u64, Vec<String>) = redis::cmd("SCAN")
+            .arg(cursor)
+            .arg("MATCH")
+            .arg(format!("{}*", full_prefix))
+            .arg("COUNT")
+            .arg(REDIS_STATE_SNAPSHOT_BATCH)
+            .query_async(&mut redis_conn)
+            .await?;
+        key_names.extend(batch.into_iter().filter_map(|key| {
+            let name = key.strip_prefix(&full_prefix)?.to_string();
+            let skipped = name.starts_with(REDIS_PRIVATE_KEY_PREFIX)
+                || (!include_history && name.starts_with(REDIS_HISTORY_KEY_PREFIX));
+            (!skipped).then_some(name)
+        }));
+        if next_cursor == 0 {
+            break;
+        }
+        cursor = next_cursor;
+    }
+    key_names.sort();
+    key_names.dedup();
+
+    let mut keys = Vec::with_capacity(key_names.len());
+    for names in key_names.chunks(REDIS_STATE_SNAPSHOT_BATCH) {
+        let mut pipe = redis::pipe();
+        for name in names {
+            let key = state.redis_keys.key(name);
+            pipe.cmd("DUMP").arg(&key).cmd("PTTL").arg(&key);
+        }
+        let replies: Vec<redis::Value> = pipe.query_async(&mut redis_conn).await?;
+        for (name, reply) in names.iter().zip(replies.chunks(2)) {
+            let [dump, pttl] = reply else {
+                continue;
+            };
+            // A key that expired between SCAN and DUMP comes back nil.
+            let Ok(Some(dump)) = redis::from_redis_value::<Option<Vec<u8>>>(dump) else {
+                continue;
+            };
+            let pttl_ms = redis::from_redis_value::<i64>(pttl).unwrap_or(-1);
+            keys.push(RedisKeyDump {
+                key: name.clone(),
+                pttl_ms: (pttl_ms > 0).then_some(pttl_ms),
+                dump: base64::engine::general_purpose::STANDARD.encode(dump),
+            });
+        }
+    }
+
+    Ok(RedisStateSnapshot {
+        version: REDIS_STATE_SNAPSHOT_VERSION,
+        key_prefix: state.redis_keys.prefix.clone(),
+        exported_at_unix_ms: state.clock.now_ms(),
+        keys,
+    })
+}
+
+// Restores every key in the snapshot under this city's prefix, overwriting keys that already
+// exist. Keys the snapshot does not mention are left alone.
+pub(crate) async fn import_redis_state(
+    state: &AppState,
+    snapshot: &RedisStateSnapshot,
+) -> Result<usize, AppError> {
+    if snapshot.version != REDIS_STATE_SNAPSHOT_VERSION {
+        return Err(AppError::Validation(format!(
+            "Unsupported snapshot version {}, expected {}",
+            snapshot.version, REDIS_STATE_SNAPSHOT_VERSION
+        )));
+    }
+    let mut payloads = Vec::with_capacity(snapshot.keys.len());
+    for entry in &snapshot.keys {
+        if entry.key.starts_with(REDIS_PRIVATE_KEY_PREFIX) {
+            continue;
+        }
+        let dump = base64::engine::general_purpose::STANDARD
+            .decode(&entry.dump)
+            .map_err(|error| {
+                AppError::Validation(format!(
+                    "Key '{}' has an invalid dump: {}",
+                    entry.key, error
+                ))
+            })?;
+        payloads.push((
+            state.redis_keys.key(&entry.key),
+            entry.pttl_ms.unwrap_or(0),
+            dump,
+        ));
+    }
+
+    let mut redis_conn = state
+        .redis_client
+        .get_multiplexed_async_connection()
+        .await?;
+    for batch in payloads.chunks(REDIS_STATE_SNAPSHOT_BATCH) {
+        let mut pipe = redis::pipe();
+        for (key, pttl_ms, dump) in batch {
+            pipe.cmd("RESTORE")
+                .arg(key)
+                .arg(*pttl_ms)
+                .arg(dump.as_slice())
+                .arg("REPLACE")
+                .ignore();
+        }
+        pipe.query_async::<()>(&mut redis_conn).await?;
+    }
+    Ok(payloads.len())
+}
+
+// Relays bus events to BUS_EVENTS_WEBHOOK_URL as they land on the stream, starting from the
+// moment the relay starts. Each ingest event triggers a read; the poll only covers missed ones.
+// Events raised during warmup are passed over, like every other alert, since the motion state
+// behind them isn't settled yet. A POST is retried BUS_EVENT_WEBHOOK_ATTEMPTS times before the
+// event is logged and skipped.
+pub(crate) async fn run_bus_event_webhook(
+    state: AppState,
+    webhook_url: String,
+    webhook_secret: Option<String>,
+) {
+    let mut poll_interval =
+        tokio::time::interval(Duration::from_secs(BUS_EVENT_WEBHOOK_POLL_SECONDS));
+    poll_interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
+    let http = reqwest::Client::new();
+    let mut last_event_id = format!("{}-0", state.clock.now_ms());
+    let mut ingest_events = state.ingest_events.subscribe();
+
+    loop {
+        // Bus events are appended before the batch's ingest event is published, so waking on
+        // it finds them already on the stream.
+        tokio::select! {
+            _ = poll_interval.tick() => {}
+            Ok(()) = ingest_events.changed() => {}
+        }
+        let events: redis::RedisResult<Vec<(String, HashMap<String, String>)>> =
+            match state.redis_client.get_multiplexed_async_connection().await {
+                Ok(mut redis_conn) => {
+                    redis::cmd("XRANGE")
+                        .arg(state.redis_keys.key(REDIS_BUS_EVENTS_KEY))
+                        .arg(format!("({}", last_event_id))
+                        .arg("+")
+                        .arg("COUNT")
+                        .arg(BUS_EVENT_WEBHOOK_BATCH)
+                        .query_async(&mut redis_conn)
+                        .await
+                }
+                Err(error) => Err(error),
+            };
+        let events = match events {
+            Ok(events) => events,
+            Err(error) => {
+                println!("Failed to read bus events: {}", error);
+                continue;
+            }
+        };
+
+        let warming_up = !events.is_empty() && is_warming_up(&state).await;
+        for (event_id, fields) in events {
+            last_event_id = event_id;
+            if warming_up {
+                continue;
+            }
+            let data = fields
+                .get("data")
+                .and_then(|raw| serde_json::from_str::<serde_json::Value>(raw).ok());
+            let body = json!({
+                "event": fields.get("event"),
+                "bus_no": fields.get("bus_no"),
+                "city": state.city.id,
+                "data": data,
+            });
+            if let Err(error) =
+                deliver_bus_event(&http, &webhook_url, webhook_secret.as_deref(), &body).await
+            {
+                println!(
+                    "Failed to deliver bus event webhook after {} attempts, skipping it: {}",
+                    BUS_EVENT_WEBHOOK_ATTEMPTS, error
+                );
+            }
+        }
+    }
+}
+
+// With a secret, the body is signed as "sha256=<hex HMAC-SHA256 of the body>" so the receiver
+// can tell the relay from anyone else who learns the URL.
+async fn deliver_bus_event(
+    http: &reqwest::Client,
+    webhook_url: &str,
+    webhook_secret: Option<&str>,
+    body: &serde_json::Value,
+) -> Result<(), reqwest::Error> {
+    let payload = body.to_string();
+    let signature = webhook_secret.map(|secret| {
+        let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes())
+            .expect("HMAC accepts keys of any length");
+        mac.update(payload.as_bytes());
+        let hex: String = mac
+            .finalize()
+            .into_bytes()
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect();
+        format!("sha256={}", hex)
+    });
+    let mut attempt = 1;
+    loop {
+        let mut request = http
+            .post(webhook_url)
+            .timeout(Duration::from_secs(10))
+            .header(CONTENT_TYPE, "application/json")
+            .body(payload.clone());
+        if let Some(signature) = &signature {
+            request = request.header(BUS_EVENT_SIGNATURE_HEADER, signature);
+        }
+        let result = request
+            .send()
+            .await
+            .and_then(|response| response.error_for_status());
+        match result {
+            Ok(_) => return Ok(()),
+            Err(error) if attempt >= BUS_EVENT_WEBHOOK_ATTEMPTS => return Err(error),
+            Err(_) => {
+                tokio::time::sleep(Duration::from_millis(
+                    BUS_EVENT_WEBHOOK_RETRY_BASE_MS << (attempt - 1),
+                ))
+                .await;
+                attempt += 1;
+            }
+        }
+    }
+}
+
+// Just the ingest timestamp, for responses that report freshness without reading the fleet.
+pub(crate) async fn load_last_ingest_at(state: &AppState) -> Result<Option<i64>, AppError> {
+    if let Some(frame) = &state.fixture {
+        return Ok(frame.last_ingest_at_unix_ms);
+    }
+    let _timer = StageTimer::start(Stage::Redis);
+    let mut redis_conn = read_redis_client(state)
+        .get_multiplexed_async_connection()
+        .await?;
+    let last_ingest_at_unix_ms: Option<i64> = redis::cmd("GET")
+        .arg(state.redis_keys.key(REDIS_INGEST_LAST_KEY))
+        .query_async(&mut redis_conn)
+        .await?;
+    Ok(last_ingest_at_unix_ms)
+}
+
+// The current snapshot sequence; None in fixture mode, where there is nothing to diff against.
+pub(crate) async fn load_snapshot_seq(state: &AppState) -> Result<Option<u64>, AppError> {
+    if state.fixture.is_some() {
+        return Ok(None);
+    }
+    let _timer = StageTimer::start(Stage::Redis);
+    let mut redis_conn = read_redis_client(state)
+        .get_multiplexed_async_connection()
+        .await?;
+    let snapshot_seq: Option<u64> = redis::cmd("GET")
+        .arg(state.redis_keys.key(REDIS_SNAPSHOT_SEQ_KEY))
+        .query_async(&mut redis_conn)
+        .await?;
+    Ok(Some(snapshot_seq.unwrap_or(0)))
+}
+
+// Bus numbers written, and bus numbers pruned, after since_seq.
+pub(crate) async fn load_bus_changes_since(
+    state: &AppState,
+    since_seq: u64,
+) -> Result<(HashSet<String>, HashSet<String>), AppError> {
+    let _timer = StageTimer::start(Stage::Redis);
+    let mut redis_conn = read_redis_client(state)
+        .get_multiplexed_async_connection()
+        .await?;
+    let (changed, removed): (Vec<String>, Vec<String>) = redis::pipe()
+        .cmd("ZRANGEBYSCORE")
+        .arg(state.redis_keys.key(REDIS_BUSES_CHANGED_SEQ_KEY))
+        .arg(format!("({}", since_seq))
+        .arg("+inf")
+        .cmd("ZRANGEBYSCORE")
+        .arg(state.redis_keys.key(REDIS_BUSES_REMOVED_SEQ_KEY))
+        .arg(format!("({}", since_seq))
+        .arg("+inf")
+        .query_async(&mut redis_conn)
+        .await?;
+    Ok((changed.into_iter().collect(), removed.into_iter().collect()))
+}
+
+// A prune at now_ms drops buses last seen at or before the first cutoff, and tombstones removed
+// at or before the second.
+fn prune_cutoffs(now_ms: i64, bus_ttl_ms: i64) -> (i64, i64) {
+    (now_ms - bus_ttl_ms, now_ms - BUS_TOMBSTONE_RETENTION_MS)
+}
+
+// Drops buses not seen within the TTL from every per-bus key and returns the cutoff used. Each
+// dropped bus leaves a tombstone with its last position and a removed event on the bus stream.
+async fn prune_stale_buses(
+    redis_conn: &mut redis::aio::MultiplexedConnection,
+    keys: &RedisKeys,
+    clock: &dyn Clock,
+    bus_ttl_ms: i64,
+) -> Result<i64, redis::RedisError> {
+    let now_ms = clock.now_ms();
+    let (cutoff_ms, tombstone_cutoff_ms) = prune_cutoffs(now_ms, bus_ttl_ms);
+    let stale_buses: Vec<(String, f64)> = redis::cmd("ZRANGEBYSCORE")
+        .arg(keys.key(REDIS_BUSES_LAST_SEEN_KEY))
+        .arg("-inf")
+        .arg(cutoff_ms)
+        .arg("WITHSCORES")
+        .query_async(redis_conn)
+        .await?;
+
+    if !stale_buses.is_empty() {
+        let stale_bus_ids: Vec<String> = stale_buses
+            .iter()
+            .map(|(bus_no, _)| bus_no.clone())
+            .collect();
+        let last_positions: Vec<Option<String>> = redis::cmd("HMGET")
+            .arg(keys.key(REDIS_BUSES_LATEST_KEY))
+            .arg(&stale_bus_ids)
+            .query_async(redis_conn)
+            .await?;
+        let expired_tombstones: Vec<String> = redis::cmd("ZRANGEBYSCORE")
+            .arg(keys.key(REDIS_BUS_TOMBSTONES_REMOVED_AT_KEY))
+            .arg("-inf")
+            .arg(tombstone_cutoff_ms)
+            .query_async(redis_conn)
+            .await?;
+        let snapshot_seq: u64 = redis::cmd("INCR")
+            .arg(keys.key(REDIS_SNAPSHOT_SEQ_KEY))
+            .query_async(redis_conn)
+            .await?;
+        let mut delete_pipe = redis::pipe();
+        if !expired_tombstones.is_empty() {
+            delete_pipe
+                .cmd("HDEL")
+                .arg(keys.key(REDIS_BUS_TOMBSTONES_KEY))
+                .arg(&expired_tombstones)
+                .ignore();
+            delete_pipe
+                .cmd("ZREM")
+                .arg(keys.key(REDIS_BUS_TOMBSTONES_REMOVED_AT_KEY))
+                .arg(&expired_tombstones)
+                .ignore();
+        }
+        for ((bus_no, last_seen_ms), last_position) in stale_buses.iter().zip(last_positions) {
+            delete_pipe
+                .cmd("ZADD")
+                .arg(keys.key(REDIS_BUSES_REMOVED_SEQ_KEY))
+                .arg(snapshot_seq)
+                .arg(bus_no)
+                .ignore();
+            let Some(bus) =
+                last_position.and_then(|raw| serde_json::from_str::<BusPosition>(&raw).ok())
+            else {
+                continue;
+            };
+            let tombstone = BusTombstone {
+                bus,
+                last_seen_unix_ms: *last_seen_ms as i64,
+                removed_at_unix_ms: now_ms,
+            };
+            let Ok(tombstone_json) = serde_json::to_string(&tombstone) else {
+                continue;
+            };
+            delete_pipe
+                .cmd("HSET")
+                .arg(keys.key(REDIS_BUS_TOMBSTONES_KEY))
+                .arg(bus_no)
+                .arg(&tombstone_json)
+                .ignore();
+            delete_pipe
+                .cmd("ZADD")
+                .arg(keys.key(REDIS_BUS_TOMBSTONES_REMOVED_AT_KEY))
+                .arg(now_ms)
+                .arg(bus_no)
+                .ignore();
+            delete_pipe
+                .cmd("XADD")
+                .arg(keys.key(REDIS_BUS_EVENTS_KEY))
+                .arg("MAXLEN")
+                .arg("~")
+                .arg(BUS_EVENTS_MAX_LEN)
+                .arg("*")
+                .arg("event")
+                .arg("removed")
+                .arg("bus_no")
+                .arg(bus_no)
+                .arg("data")
+                .arg(&tombstone_json)
+                .ignore();
+        }
+        delete_pipe
+            .cmd("ZREM")
+            .arg(keys.key(REDIS_BUSES_CHANGED_SEQ_KEY))
+            .arg(&stale_bus_ids)
+            .ignore();
+        delete_pipe
+            .cmd("HDEL")
+            .arg(keys.key(REDIS_BUSES_LATEST_KEY))
+            .arg(&stale_bus_ids)
+            .ignore();
+        delete_pipe
+            .cmd("HDEL")
+            .arg(keys.key(REDIS_BUSES_MOTION_KEY))
+            .arg(&stale_bus_ids)
+            .ignore();
+        delete_pipe
+            .cmd("HDEL")
+            .arg(keys.key(REDIS_PRIVATE_CAPTAIN_IDS_KEY))
+            .arg(&stale_bus_ids)
+            .ignore();
+        delete_pipe
+            .cmd("ZREMRANGEBYSCORE")
+            .arg(keys.key(REDIS_BUSES_LAST_SEEN_KEY))
+            .arg("-inf")
+            .arg(cutoff_ms)
+            .ignore();
+        delete_pipe.query_async::<()>(redis_conn).await?;
+    }
+
+    Ok(cutoff_ms)
+}
+
+// Live snapshot when as_of is absent, otherwise the newest history frame at or before as_of.
+// Frames older than the bus TTL are not used, since every bus in them would have expired.
+pub(crate) async fn load_bus_snapshot_as_of(
+    state: &AppState,
+    as_of: Option<i64>,
+) -> Result<RedisBusSnapshot, AppError> {
+    let Some(as_of) = as_of else {
+        return load_active_bus_snapshot(state).await;
+    };
+    let _timer = StageTimer::start(Stage::Redis);
+    if let Some(frame) = &state.fixture {
+        return Ok(snapshot_from_frame(frame.as_ref().clone(), as_of));
+    }
+    if state.history_retention_ms <= 0 {
+        return Err(AppError::Validation(
+            "as_of requires snapshot history; set HISTORY_RETENTION_HOURS".to_string(),
+        ));
+    }
+    if as_of > state.clock.now_ms() {
+        return Err(AppError::Validation(
+            "as_of must not be in the future".to_string(),
+        ));
+    }
+
+    let mut redis_conn = read_redis_client(state)
+        .get_multiplexed_async_connection()
+        .await?;
+    let frame_ids: Vec<i64> = redis::cmd("ZREVRANGEBYSCORE")
+        .arg(state.redis_keys.key(REDIS_HISTORY_FRAMES_KEY))
+        .arg(as_of)
+        .arg(as_of - state.bus_ttl_ms)
+        .arg("LIMIT")
+        .arg(0)
+        .arg(1)
+        .query_async(&mut redis_conn)
+        .await?;
+    let not_found = || {
+        AppError::NotFound(format!(
+            "No snapshot history recorded shortly before as_of={}",
+            as_of
+        ))
+    };
+    let frame_id = *frame_ids.first().ok_or_else(not_found)?;
+    let cache_key: HistoryFrameKey = (state.redis_keys.prefix.clone(), frame_id);
+    if let Some(frame) = cached_history_frame(&state.history_frame_cache, &cache_key) {
+        return Ok(snapshot_from_frame(frame.as_ref().clone(), as_of));
+    }
+
+    let raw_frame: Option<String> = redis::cmd("GET")
+        .arg(format!(
+            "{}{}",
+            state.redis_keys.key(REDIS_HISTORY_FRAME_KEY_PREFIX),
+            frame_id
+        ))
+        .query_async(&mut redis_conn)
+        .await?;
+    let raw_frame = raw_frame.ok_or_else(not_found)?;
+    let frame = serde_json::from_str::<HistoryFrame>(&raw_frame).map_err(|_| not_found())?;
+    let frame = Arc::new(frame);
+    cache_history_frame(
+        &state.history_frame_cache,
+        cache_key,
+        frame.clone(),
+        raw_frame.len(),
+    );
+
+    Ok(snapshot_from_frame(frame.as_ref().clone(), as_of))
+}
+
+// Always a miss while the budget is zero, without counting it.
+fn cached_history_frame(
+    cache: &HistoryFrameCache,
+    key: &HistoryFrameKey,
+) -> Option<Arc<HistoryFrame>> {
+    if cache.budget_bytes == 0 {
+        return None;
+    }
+    let tick = cache.use_tick.fetch_add(1, AtomicOrdering::Relaxed);
+    let hit = cache.entries.lock().ok().and_then(|mut entries| {
+        entries.get_mut(key).map(|(frame, _, last_used)| {
+            *last_used = tick;
+            frame.clone()
+        })
+    });
+    let counter = if hit.is_some() {
+        &cache.hits
+    } else {
+        &cache.misses
+    };
+    counter.fetch_add(1, AtomicOrdering::Relaxed);
+    hit
+}
+
+// A frame bigger than the whole budget is served but never kept.
+fn cache_history_frame(
+    cache: &HistoryFrameCache,
+    key: HistoryFrameKey,
+    frame: Arc<HistoryFrame>,
+    size_bytes: usize,
+) {
+    if size_bytes > cache.budget_bytes {
+        return;
+    }
+    let tick = cache.use_tick.fetch_add(1, AtomicOrdering::Relaxed);
+    // A poisoned lock only costs the caching, never the response.
+    let Ok(mut entries) = cache.entries.lock() else {
+        return;
+    };
+    if let Some((_, replaced_bytes, _)) = entries.insert(key, (frame, size_bytes, tick)) {
+        cache
+            .used_bytes
+            .fetch_sub(replaced_bytes, AtomicOrdering::Relaxed);
+    }
+    let mut used_bytes = cache
+        .used_bytes
+        .fetch_add(size_bytes, AtomicOrdering::Relaxed)
+        + size_bytes;
+    while used_bytes > cache.budget_bytes {
+        let least_recent = entries
+            .iter()
+            .min_by_key(|(_, (_, _, last_used))| *last_used)
+            .map(|(key, _)| key.clone());
+        let Some((_, evicted_bytes, _)) = least_recent.and_then(|key| entries.remove(&key)) else {
+            break;
+        };
+        used_bytes = cache
+            .used_bytes
+            .fetch_sub(evicted_bytes, AtomicOrdering::Relaxed)
+            - evicted_bytes;
+        cache.evictions.fetch_add(1, AtomicOrdering::Relaxed);
+    }
+}
+
+fn snapshot_from_frame(frame: HistoryFrame, captured_at_unix_ms: i64) -> RedisBusSnapshot {
+    RedisBusSnapshot {
+        buses: frame.buses,
+        motion_states: frame.motion_states,
+        active_bus_count: frame.active_bus_count,
+        outside_service_area_count: frame.outside_service_area_count,
+        last_ingest_at_unix_ms: frame.last_ingest_at_unix_ms,
+        last_seen_unix_ms: frame.last_seen_unix_ms,
+        captured_at_unix_ms,
+    }
+}
+
+// Fixture files use the history frame layout, so a frame pulled from Redis can be checked in
+// as a fixture unchanged.
+pub(crate) fn load_fixture_snapshot(path: &str) -> Result<HistoryFrame, LoadError> {
+    let file = File::open(path)?;
+    Ok(serde_json::from_reader(file)?)
+}
+
+pub(crate) async fn run_history_recorder(state: AppState, sample_seconds: u64) {
+    let mut sample_interval = tokio::time::interval(Duration::from_secs(sample_seconds));
+    sample_interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
+
+    println!(
+        "Recording snapshot history every {}s, keeping {}h",
+        sample_seconds,
+        state.history_retention_ms / 3_600_000
+    );
+    loop {
+        sample_interval.tick().await;
+        if let Err(error) = record_history_frame(&state).await {
+            println!("Failed to record snapshot history: {}", error);
+        }
+    }
+}
+
+async fn record_history_frame(state: &AppState) -> Result<(), String> {
+    let snapshot = load_active_bus_snapshot(state)
+        .await
+        .map_err(|error| error.to_string())?;
+    let captured_at_unix_ms = snapshot.captured_at_unix_ms;
+    let frame = HistoryFrame {
+        captured_at_unix_ms,
+        buses: snapshot.buses,
+        motion_states: snapshot.motion_states,
+        active_bus_count: snapshot.active_bus_count,
+        outside_service_area_count: snapshot.outside_service_area_count,
+        last_ingest_at_unix_ms: snapshot.last_ingest_at_unix_ms,
+        last_seen_unix_ms: snapshot.last_seen_unix_ms,
+    };
+    let frame_json = serde_json::to_string(&frame).map_err(|error| error.to_string())?;
+
+    let mut redis_conn = state
+        .redis_client
+        .get_multiplexed_async_connection()
+        .await
+        .map_err(|error| error.to_string())?;
+    redis::pipe()
+        .cmd("SET")
+        .arg(format!(
+            "{}{}",
+            state.redis_keys.key(REDIS_HISTORY_FRAME_KEY_PREFIX),
+            captured_at_unix_ms
+        ))
+        .arg(frame_json)
+        .arg("PX")
+        .arg(state.history_retention_ms)
+        .ignore()
+        .cmd("ZADD")
+        .arg(state.redis_keys.key(REDIS_HISTORY_FRAMES_KEY))
+        .arg(captured_at_unix_ms)
+        .arg(captured_at_unix_ms)
+        .ignore()
+        .cmd("ZREMRANGEBYSCORE")
+        .arg(state.redis_keys.key(REDIS_HISTORY_FRAMES_KEY))
+        .arg("-inf")
+        .arg(captured_at_unix_ms - state.history_retention_ms)
+        .ignore()
+        .query_async::<()>(&mut redis_conn)
+        .await
+        .map_err(|error| error.to_string())
+}
+
+pub(crate) async fn load_motion_states(
+    redis_conn: &mut redis::aio::MultiplexedConnection,
+    keys: &RedisKeys,
+    bus_ids: &[String],
+) -> Result<HashMap<String, BusMotionState>, String> {
+    if bus_ids.is_empty() {
+        return Ok(HashMap::new());
+    }
+    let raw_states: Vec<Option<String>> = redis::cmd("HMGET")
+        .arg(keys.key(REDIS_BUSES_MOTION_KEY))
+        .arg(bus_ids)
+        .query_async(redis_conn)
+        .await
+        .map_err(|error| error.to_string())?;
+
+    Ok(bus_ids
+        .iter()
+        .cloned()
+        .zip(raw_states)
+        .filter_map(|(bus_no, raw_state)| {
+            raw_state.and_then(|value| {
+                serde_json::from_str::<BusMotionState>(&value)
+                    .ok()
+                    .map(|state| (bus_no, state))
+            })
+        })
+        .collect())
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct StoreOutcome {
+    // None when nothing was written, or the sequence could not be advanced.
+    pub(crate) snapshot_seq: Option<u64>,
+    // (bus_no, JSON) as written, captain_id already redacted.
+    pub(crate) stored: Vec<(String, String)>,
+    pub(crate) serialize_failures: usize,
+    pub(crate) retries: u32,
+    pub(crate) failed_chunks: usize,
+    pub(crate) failed_bus_count: usize,
+    pub(crate) last_error: Option<String>,
+}
+
+// Writes the batch's buses and what the motion stage worked out for them. Buses go out in
+// atomic chunks so one failed write loses only its chunk, and transient failures are retried.
+// A bus that fails to serialize is skipped on its own; last_ingest_at is written whenever the
+// batch had any bus, since the feed was alive either way.
+pub(crate) async fn store_enriched_buses(
+    redis_conn: &mut redis::aio::MultiplexedConnection,
+    keys: &RedisKeys,
+    buses: &[BusPosition],
+    enrichments: &HashMap<String, BusEnrichment>,
+    now_ms: i64,
+    privacy: &PrivacySettings,
+) -> StoreOutcome {
+    let mut outcome = StoreOutcome::default();
+    if buses.is_empty() {
+        return outcome;
+    }
+    // (bus, enrichment, bus JSON, motion state JSON)
+    let mut serialized_entries: Vec<(&BusPosition, &BusEnrichment, String, String)> = Vec::new();
+    for bus in buses {
+        let Some(enrichment) = enrichments.get(&bus.bus_no) else {
+            continue;
+        };
+        let stored_bus = apply_captain_id_privacy(bus.clone(), privacy);
+        match (
+            serde_json::to_string(&stored_bus),
+            serde_json::to_string(&enrichment.motion_state),
+        ) {
+            (Ok(bus_json), Ok(motion_json)) => {
+                serialized_entries.push((bus, enrichment, bus_json, motion_json))
+            }
+            _ => outcome.serialize_failures += 1,
+        }
+    }
+
+    if !serialized_entries.is_empty() {
+        let mut pipe = redis::pipe();
+        pipe.cmd("INCR").arg(keys.key(REDIS_SNAPSHOT_SEQ_KEY));
+        let (retries, result) = query_pipeline_with_retry::<(u64,)>(redis_conn, &pipe).await;
+        outcome.retries += retries;
+        match result {
+            Ok((snapshot_seq,)) => outcome.snapshot_seq = Some(snapshot_seq),
+            Err(error) => {
+                outcome.failed_chunks += 1;
+                outcome.failed_bus_count += serialized_entries.len();
+                outcome.last_error = Some(error.to_string());
+            }
+        }
+    }
+
+    if let Some(snapshot_seq) = outcome.snapshot_seq {
+        for chunk in serialized_entries.chunks(INGEST_WRITE_CHUNK_SIZE) {
+            let mut pipe = redis::pipe();
+            pipe.atomic();
+            for (bus, enrichment, bus_json, motion_json) in chunk {
+                let bus_no = &bus.bus_no;
+                if !enrichment.passed_stops.is_empty() {
+                    let activity_key = activity_key(keys, now_ms / 3_600_000);
+                    for passed in &enrichment.passed_stops {
+                        pipe.cmd("HINCRBY")
+                            .arg(&activity_key)
+                            .arg(format!("stop|{}", passed.stop_id))
+                            .arg(1)
+                            .ignore();
+                        if let Some(from_stop_id) = &passed.from_stop_id {
+                            pipe.cmd("HINCRBY")
+                                .arg(&activity_key)
+                                .arg(format!(
+                                    "segment|{}|{}|{}",
+                                    passed.route_id, from_stop_id, passed.stop_id
+                                ))
+                                .arg(1)
+                                .ignore();
+                        }
+                        pipe.cmd("EXPIRE")
+                            .arg(&activity_key)
+                            .arg(i64::from(ACTIVITY_RETENTION_HOURS) * 3_600)
+                            .ignore();
+                        let departures_key = format!(
+                            "{}{}",
+                            keys.key(REDIS_STOP_DEPARTURES_KEY_PREFIX),
+                            passed.stop_id
+                        );
+                        pipe.cmd("ZADD")
+                            .arg(&departures_key)
+                            .arg(now_ms)
+                            .arg(format!("{}|{}", passed.route_id, bus_no))
+                            .ignore();
+                        pipe.cmd("ZREMRANGEBYSCORE")
+                            .arg(&departures_key)
+                            .arg("-inf")
+                            .arg(now_ms - RECENT_DEPARTURE_WINDOW_MS)
+                            .ignore();
+                        pipe.cmd("PEXPIRE")
+                            .arg(&departures_key)
+                            .arg(RECENT_DEPARTURE_WINDOW_MS)
+                            .ignore();
+                    }
+                }
+
+                if let Some(route_id) = &enrichment.runtime_route_id {
+                    let runtimes_key =
+                        format!("{}{}", keys.key(REDIS_ROUTE_RUNTIMES_KEY_PREFIX), route_id);
+                    for sample in &enrichment.runtime_samples {
+                        let (field, started_at_unix_ms, runtime_ms) = match sample {
+                            RuntimeSample::Segment {
+                                from_stop_id,
+                                to_stop_id,
+                                started_at_unix_ms,
+                                runtime_ms,
+                            } => (
+                                format!("{}>{}", from_stop_id, to_stop_id),
+                                *started_at_unix_ms,
+                                *runtime_ms,
+                            ),
+                            RuntimeSample::Trip {
+                                started_at_unix_ms,
+                                runtime_ms,
+                            } => ("trip".to_string(), *started_at_unix_ms, *runtime_ms),
+                        };
+                        let hour = kl_hour_of_day(started_at_unix_ms);
+                        pipe.cmd("HINCRBY")
+                            .arg(&runtimes_key)
+                            .arg(format!("{}:{}:count", hour, field))
+                            .arg(1)
+                            .ignore();
+                        pipe.cmd("HINCRBY")
+                            .arg(&runtimes_key)
+                            .arg(format!("{}:{}:sum_ms", hour, field))
+                            .arg(runtime_ms)
+                            .ignore();
+                    }
+                }
+
+                if let Some((stop_id, dwell_ms)) = &enrichment.completed_dwell {
+                    let dwell_key = format!("{}{}", keys.key(REDIS_STOP_DWELL_KEY_PREFIX), stop_id);
+                    let hour = kl_hour_of_day(now_ms);
+                    let bucket = DWELL_BUCKETS
+                        .iter()
+                        .find(|(_, upper_s)| *dwell_ms < upper_s.saturating_mul(1_000))
+                        .map(|(label, _)| *label)
+                        .unwrap_or("120s+");
+                    pipe.cmd("HINCRBY")
+                        .arg(&dwell_key)
+                        .arg(format!("{}:count", hour))
+                        .arg(1)
+                        .ignore();
+                    pipe.cmd("HINCRBY")
+                        .arg(&dwell_key)
+                        .arg(format!("{}:sum_ms", hour))
+                        .arg(*dwell_ms)
+                        .ignore();
+                    pipe.cmd("HINCRBY")
+                        .arg(&dwell_key)
+                        .arg(format!("{}:{}", hour, bucket))
+                        .arg(1)
+                        .ignore();
+                }
+
+                pipe.cmd("HSET")
+                    .arg(keys.key(REDIS_BUSES_LATEST_KEY))
+                    .arg(bus_no)
+                    .arg(bus_json)
+                    .ignore();
+                if privacy.retain_raw_captain_id {
+                    if let Some(captain_id) = bus.captain_id.as_deref().filter(|id| !id.is_empty())
+                    {
+                        pipe.cmd("HSET")
+                            .arg(keys.key(REDIS_PRIVATE_CAPTAIN_IDS_KEY))
+                            .arg(bus_no)
+                            .arg(captain_id)
+                            .ignore();
+                    }
+                }
+                pipe.cmd("HSET")
+                    .arg(keys.key(REDIS_BUSES_MOTION_KEY))
+                    .arg(bus_no)
+                    .arg(motion_json)
+                    .ignore();
+                pipe.cmd("ZADD")
+                    .arg(keys.key(REDIS_BUSES_LAST_SEEN_KEY))
+                    .arg(now_ms)
+                    .arg(bus_no)
+                    .ignore();
+                pipe.cmd("ZADD")
+                    .arg(keys.key(REDIS_BUSES_CHANGED_SEQ_KEY))
+                    .arg(snapshot_seq)
+                    .arg(bus_no)
+                    .ignore();
+                let route = normalize_route_code(&bus.route);
+                if !route.is_empty() {
+                    pipe.cmd("HSET")
+                        .arg(keys.key(REDIS_ROUTES_LAST_SEEN_KEY))
+                        .arg(route)
+                        .arg(now_ms)
+                        .ignore();
+                }
+            }
+
+            let (retries, result) = query_pipeline_with_retry::<()>(redis_conn, &pipe).await;
+            outcome.retries += retries;
+            match result {
+                Ok(()) => outcome.stored.extend(
+                    chunk
+                        .iter()
+                        .map(|(bus, _, bus_json, _)| (bus.bus_no.clone(), bus_json.clone())),
+                ),
+                Err(error) => {
+                    outcome.failed_chunks += 1;
+                    outcome.failed_bus_count += chunk.len();
+                    outcome.last_error = Some(error.to_string());
+                }
+            }
+        }
+    }
+
+    let mut pipe = redis::pipe();
+    if let Some(snapshot_seq) = outcome.snapshot_seq {
+        pipe.cmd("ZREMRANGEBYSCORE")
+            .arg(keys.key(REDIS_BUSES_REMOVED_SEQ_KEY))
+            .arg("-inf")
+            .arg(snapshot_seq.saturating_sub(DELTA_SYNC_MAX_LAG_SEQS))
+            .ignore();
+    }
+    // A bus back in the feed is live again; its tombstone no longer applies.
+    if !outcome.stored.is_empty() {
+        let written_bus_nos: Vec<&String> =
+            outcome.stored.iter().map(|(bus_no, _)| bus_no).collect();
+        pipe.cmd("HDEL")
+            .arg(keys.key(REDIS_BUS_TOMBSTONES_KEY))
+            .arg(&written_bus_nos)
+            .ignore();
+        pipe.cmd("ZREM")
+            .arg(keys.key(REDIS_BUS_TOMBSTONES_REMOVED_AT_KEY))
+            .arg(&written_bus_nos)
+            .ignore();
+    }
+    pipe.cmd("SET")
+        .arg(keys.key(REDIS_INGEST_LAST_KEY))
+        .arg(now_ms)
+        .ignore();
+    let (retries, result) = query_pipeline_with_retry::<()>(redis_conn, &pipe).await;
+    outcome.retries += retries;
+    if let Err(error) = result {
+        outcome.failed_chunks += 1;
+        outcome.last_error = Some(error.to_string());
+    }
+
+    outcome
+}
+
+// Connection-level failures, where Redis may never have seen the commands; anything Redis
+// itself rejected would fail again.
+fn is_transient_redis_error(error: &redis::RedisError) -> bool {
+    error.is_io_error()
+        || error.is_timeout()
+        || error.is_connection_dropped()
+        || error.is_connection_refusal()
+        || matches!(
+            error.kind(),
+            redis::ErrorKind::TryAgain | redis::ErrorKind::BusyLoadingError
+        )
+}
+
+// Returns how many retries were spent alongside the final result. A timeout after an atomic
+// chunk did apply can count its counters twice; positions and motion states are overwritten
+// and come out the same.
+async fn query_pipeline_with_retry<T: redis::FromRedisValue>(
+    redis_conn: &mut redis::aio::MultiplexedConnection,
+    pipe: &redis::Pipeline,
+) -> (u32, Result<T, redis::RedisError>) {
+    let mut retries = 0;
+    loop {
+        match pipe.query_async::<T>(redis_conn).await {
+            Err(error)
+                if retries + 1 < INGEST_WRITE_MAX_ATTEMPTS && is_transient_redis_error(&error) =>
+            {
+                tokio::time::sleep(Duration::from_millis(INGEST_WRITE_RETRY_BASE_MS << retries))
+                    .await;
+                retries += 1;
+            }
+            result => return (retries, result),
+        }
+    }
+}
+
+// Appends off_route/on_route transitions of stored buses to the bus event stream.
+pub(crate) async fn publish_bus_events(
+    redis_conn: &mut redis::aio::MultiplexedConnection,
+    keys: &RedisKeys,
+    stored: &[(String, String)],
+    enrichments: &HashMap<String, BusEnrichment>,
+) -> Result<usize, String> {
+    let mut pipe = redis::pipe();
+    let mut event_count = 0;
+    for (bus_no, bus_json) in stored {
+        let Some(enrichment) = enrichments.get(bus_no) else {
+            continue;
+        };
+        if enrichment.motion_state.off_route == enrichment.was_off_route {
+            continue;
+        }
+        pipe.cmd("XADD")
+            .arg(keys.key(REDIS_BUS_EVENTS_KEY))
+            .arg("MAXLEN")
+            .arg("~")
+            .arg(BUS_EVENTS_MAX_LEN)
+            .arg("*")
+            .arg("event")
+            .arg(if enrichment.motion_state.off_route {
+                "off_route"
+            } else {
+                "on_route"
+            })
+            .arg("bus_no")
+            .arg(bus_no)
+            .arg("data")
+            .arg(bus_json)
+            .ignore();
+        event_count += 1;
+    }
+    if event_count > 0 {
+        pipe.query_async::<()>(redis_conn)
+            .await
+            .map_err(|error| error.to_string())?;
+    }
+    Ok(event_count)
+}
+
+// Appended to the bus event stream, which the webhook relay forwards, and published for live
+// subscribers in the same round trip.
+pub(crate) async fn publish_trip_started_events(
+    redis_conn: &mut redis::aio::MultiplexedConnection,
+    keys: &RedisKeys,
+    events: &[TripStartedEvent],
+) -> Result<(), String> {
+    if events.is_empty() {
+        return Ok(());
+    }
+    let mut pipe = redis::pipe();
+    for event in events {
+        let event_json = serde_json::to_string(event).map_err(|error| error.to_string())?;
+        pipe.cmd("XADD")
+            .arg(keys.key(REDIS_BUS_EVENTS_KEY))
+            .arg("MAXLEN")
+            .arg("~")
+            .arg(BUS_EVENTS_MAX_LEN)
+            .arg("*")
+            .arg("event")
+            .arg("trip_started")
+            .arg("bus_no")
+            .arg(&event.bus_no)
+            .arg("data")
+            .arg(&event_json)
+            .ignore();
+        pipe.cmd("PUBLISH")
+            .arg(keys.key(REDIS_TRIP_EVENTS_CHANNEL))
+            .arg(&event_json)
+            .ignore();
+    }
+    pipe.query_async::<()>(redis_conn)
+        .await
+        .map_err(|error| error.to_string())
+}
+
+pub(crate) async fn publish_ingest_event(
+    redis_conn: &mut redis::aio::MultiplexedConnection,
+    keys: &RedisKeys,
+    event: &IngestEvent,
+) -> Result<(), String> {
+    redis::cmd("PUBLISH")
+        .arg(keys.key(REDIS_INGEST_EVENTS_CHANNEL))
+        .arg(serde_json::to_string(event).map_err(|error| error.to_string())?)
+        .query_async::<()>(redis_conn)
+        .await
+        .map_err(|error| error.to_string())
+}
+
+// Relays the city's ingest events into state.ingest_events for in-process consumers. Pub/sub
+// is fire-and-forget: events published while resubscribing are lost, so consumers keep their
+// own polling as a fallback.
+pub(crate) async fn run_ingest_event_listener(state: AppState) {
+    let channel = state.redis_keys.key(REDIS_INGEST_EVENTS_CHANNEL);
+    let mut backoff_seconds: u64 = 1;
+
+    loop {
+        let subscribed = match read_redis_client(&state).get_async_pubsub().await {
+            Ok(mut pubsub) => match pubsub.subscribe(&channel).await {
+                Ok(()) => Ok(pubsub),
+                Err(error) => Err(error),
+            },
+            Err(error) => Err(error),
+        };
+        let pubsub = match subscribed {
+            Ok(pubsub) => pubsub,
+            Err(error) => {
+                println!("Failed to subscribe to {}: {}", channel, error);
+                tokio::time::sleep(Duration::from_secs(backoff_seconds)).await;
+                backoff_seconds = (backoff_seconds * 2).min(INGEST_EVENT_RESUBSCRIBE_MAX_SECONDS);
+                continue;
+            }
+        };
+        backoff_seconds = 1;
+
+        let mut messages = pubsub.into_on_message();
+        while let Some(message) = messages.next().await {
+            let Ok(payload) = message.get_payload::<String>() else {
+                continue;
+            };
+            match serde_json::from_str::<IngestEvent>(&payload) {
+                Ok(event) => {
+                    state.ingest_events.send_replace(Some(event));
+                }
+                Err(error) => println!("Ignoring unreadable ingest event: {}", error),
+            }
+        }
+        println!(
+            "Ingest event subscription to {} closed; resubscribing",
+            channel
+        );
+    }
+}
+
+pub(crate) async fn run_active_bus_count_sampler(state: AppState) {
+    let mut sample_interval =
+        tokio::time::interval(Duration::from_secs(ACTIVE_BUS_SAMPLE_INTERVAL_SECONDS));
+    sample_interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
+
+    loop {
+        sample_interval.tick().await;
+        let now_ms = state.clock.now_ms();
+        let Ok(mut redis_conn) = read_redis_client(&state)
+            .get_multiplexed_async_connection()
+            .await
+        else {
+            continue;
+        };
+        let Ok(active_bus_count) = redis::cmd("ZCOUNT")
+            .arg(state.redis_keys.key(REDIS_BUSES_LAST_SEEN_KEY))
+            .arg(now_ms - state.bus_ttl_ms + 1)
+            .arg("+inf")
+            .query_async::<usize>(&mut redis_conn)
+            .await
+        else {
+            continue;
+        };
+
+        let mut history = state.active_bus_count_history.write().await;
+        if history.len() >= MAX_ACTIVE_BUS_SAMPLES {
+            history.pop_front();
+        }
+        history.push_back((now_ms, active_bus_count));
+    }
+}
+
+// The replica's copy of the last-ingest marker trails the primary's by the replication lag;
+// INFO replication on the replica says whether it is still attached at all.
+pub(crate) async fn run_replica_lag_monitor(state: AppState) {
+    let Some(read_client) = state.redis_read_client.clone() else {
+        return;
+    };
+    let mut check_interval = tokio::time::interval(Duration::from_secs(REPLICA_LAG_CHECK_SECONDS));
+    check_interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
+
+    loop {
+        check_interval.tick().await;
+        let lag = match check_replica_lag(&state, &read_client).await {
+            Ok((link_up, lag_ms)) => ReplicaLag { link_up, lag_ms },
+            Err(error) => {
+                println!("Replica lag check failed: {}", error);
+                ReplicaLag {
+                    link_up: false,
+                    lag_ms: 0,
+                }
+            }
+        };
+        *state.replica_lag.write().await = Some(lag);
+    }
+}
+
+async fn check_replica_lag(
+    state: &AppState,
+    read_client: &redis::Client,
+) -> Result<(bool, i64), redis::RedisError> {
+    let ingest_key = state.redis_keys.key(REDIS_INGEST_LAST_KEY);
+    let mut primary_conn = state
+        .redis_client
+        .get_multiplexed_async_connection()
+        .await?;
+    let mut replica_conn = read_client.get_multiplexed_async_connection().await?;
+    let primary_ingest_ms: Option<i64> = redis::cmd("GET")
+        .arg(&ingest_key)
+        .query_async(&mut primary_conn)
+        .await?;
+    let replica_ingest_ms: Option<i64> = redis::cmd("GET")
+        .arg(&ingest_key)
+        .query_async(&mut replica_conn)
+        .await?;
+    let info: String = redis::cmd("INFO")
+        .arg("replication")
+        .query_async(&mut replica_conn)
+        .await?;
+
+    // A read URL that points at a primary (e.g. in development) is trivially up to date.
+    let link_up = info
+        .lines()
+        .map(str::trim)
+        .any(|line| line == "master_link_status:up" || line == "role:master");
+    let lag_ms = match (primary_ingest_ms, replica_ingest_ms) {
+        (Some(primary_ms), Some(replica_ms)) => (primary_ms - replica_ms).max(0),
+        _ => 0,
+    };
+    Ok((link_up, lag_ms))
+}
+
+// Typical runtimes recorded for a route, aggregated per local hour of day. Fields of the hash
+// are "{hour}:{from}>{to}:{count|sum_ms}" for segments and "{hour}:trip:{count|sum_ms}" for
+// first-stop-to-last-stop runs.
+pub(crate) async fn load_route_runtimes(
+    redis_conn: &mut redis::aio::MultiplexedConnection,
+    keys: &RedisKeys,
+    route_id: &str,
+) -> Result<RouteRuntimesResponse, redis::RedisError> {
+    let counters: HashMap<String, u64> = redis::cmd("HGETALL")
+        .arg(format!(
+            "{}{}",
+            keys.key(REDIS_ROUTE_RUNTIMES_KEY_PREFIX),
+            route_id
+        ))
+        .query_async(redis_conn)
+        .await?;
+
+    // (hour, field) -> (count, sum_ms)
+    let mut totals: HashMap<(u32, String), (u64, u64)> = HashMap::new();
+    for (key, value) in &counters {
+        let Some((hour, rest)) = key.split_once(':') else {
+            continue;
+        };
+        let Some((field, counter)) = rest.rsplit_once(':') else {
+            continue;
+        };
+        let Ok(hour) = hour.parse::<u32>() else {
+            continue;
+        };
+        let entry = totals.entry((hour, field.to_string())).or_default();
+        match counter {
+            "count" => entry.0 = *value,
+            "sum_ms" => entry.1 = *value,
+            _ => {}
+        }
+    }
+
+    let mut trip: Vec<RuntimeHourStats> = Vec::new();
+    let mut segments: HashMap<(String, String), Vec<RuntimeHourStats>> = HashMap::new();
+    for ((hour, field), (sample_count, sum_ms)) in totals {
+        if sample_count == 0 {
+            continue;
+        }
+        let stats = RuntimeHourStats {
+            hour,
+            sample_count,
+            mean_runtime_seconds: (sum_ms as f64 / sample_count as f64 / 100.0).round() / 10.0,
+        };
+        if field == "trip" {
+            trip.push(stats);
+        } else if let Some((from_stop_id, to_stop_id)) = field.split_once('>') {
+            segments
+                .entry((from_stop_id.to_string(), to_stop_id.to_string()))
+                .or_default()
+                .push(stats);
+        }
+    }
+    trip.sort_by_key(|stats| stats.hour);
+    let mut segments: Vec<SegmentRuntimeProfile> = segments
+        .into_iter()
+        .map(|((from_stop_id, to_stop_id), mut hours)| {
+            hours.sort_by_key(|stats| stats.hour);
+            SegmentRuntimeProfile {
+                from_stop_id,
+                to_stop_id,
+                hours,
+            }
+        })
+        .collect();
+    segments
+        .sort_by(|a, b| (&a.from_stop_id, &a.to_stop_id).cmp(&(&b.from_stop_id, &b.to_stop_id)));
+
+    Ok(RouteRuntimesResponse {
+        route_id: route_id.to_string(),
+        trip,
+        segments,
+    })
+}
+
+// Keeps state.runtime_profiles, the fallback speed model for ETAs, close to what Redis has
+// recorded. Routes with no samples are left out.
+pub(crate) async fn run_runtime_profile_refresher(state: AppState) {
+    let mut refresh_interval =
+        tokio::time::interval(Duration::from_secs(RUNTIME_PROFILE_REFRESH_SECONDS));
+    refresh_interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
+
+    loop {
+        refresh_interval.tick().await;
+        let Ok(mut redis_conn) = read_redis_client(&state)
+            .get_multiplexed_async_connection()
+            .await
+        else {
+            continue;
+        };
+
+        let mut profiles: HashMap<String, RouteRuntimesResponse> = HashMap::new();
+        for route in &current_gtfs_feed(&state).routes {
+            match load_route_runtimes(&mut redis_conn, &state.redis_keys, &route.route_id).await {
+                Ok(profile) if !profile.segments.is_empty() || !profile.trip.is_empty() => {
+                    profiles.insert(route.route_id.clone(), profile);
+                }
+                Ok(_) => {}
+                Err(error) => {
+                    println!(
+                        "Failed to load runtimes for route {}: {}",
+                        route.route_id, error
+                    );
+                }
+            }
+        }
+        *state.runtime_profiles.write().await = profiles;
+    }
+}
+
+// Mean recorded dwell at a stop for one local hour, or over the whole day when that hour has too
+// few samples to go on.
+async fn load_mean_dwell_ms(
+    redis_conn: &mut redis::aio::MultiplexedConnection,
+    redis_keys: &RedisKeys,
+    stop_id: &str,
+    hour: u32,
+) -> Result<Option<i64>, redis::RedisError> {
+    let counters: HashMap<String, u64> = redis::cmd("HGETALL")
+        .arg(format!(
+            "{}{}",
+            redis_keys.key(REDIS_STOP_DWELL_KEY_PREFIX),
+            stop_id
+        ))
+        .query_async(redis_conn)
+        .await?;
+    let counter = |hour: u32, name: &str| {
+        counters
+            .get(&format!("{}:{}", hour, name))
+            .copied()
+            .unwrap_or(0)
+    };
+    let (count, sum_ms) = if counter(hour, "count") >= MIN_RUNTIME_PROFILE_SAMPLES {
+        (counter(hour, "count"), counter(hour, "sum_ms"))
+    } else {
+        (0..24).fold((0, 0), |(count, sum_ms), hour| {
+            (
+                count + counter(hour, "count"),
+                sum_ms + counter(hour, "sum_ms"),
+            )
+        })
+    };
+    Ok((count >= MIN_RUNTIME_PROFILE_SAMPLES).then(|| (sum_ms / count) as i64))
+}
+
+// Keeps state.terminus_schedules in step with the timetable and the dwell recorded at each
+// route's first stop. Tomorrow's starts are included so late-night layovers still find a trip.
+pub(crate) async fn run_terminus_schedule_refresher(state: AppState) {
+    let mut refresh_interval =
+        tokio::time::interval(Duration::from_secs(TERMINUS_SCHEDULE_REFRESH_SECONDS));
+    refresh_interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
+
+    loop {
+        refresh_interval.tick().await;
+        let gtfs = load_gtfs_context(&state);
+        let Ok(mut redis_conn) = read_redis_client(&state)
+            .get_multiplexed_async_connection()
+            .await
+        else {
+            continue;
+        };
+        let now_ms = state.clock.now_ms();
+        let Some(today) = kl_date(now_ms) else {
+            continue;
+        };
+        let service_days: Vec<(NaiveDate, i64)> = [Some(today), today.succ_opt()]
+            .into_iter()
+            .flatten()
+            .filter_map(|date| kl_day_bounds_ms(date).map(|(start_ms, _)| (date, start_ms)))
+            .collect();
+        let hour = kl_hour_of_day(now_ms);
+
+        let mut schedules: HashMap<String, TerminusSchedule> = HashMap::new();
+        for route in &gtfs.routes {
+            let Some(first_stop_id) = eta_stops_by_route(&gtfs, &route.route_id)
+                .ok()
+                .and_then(|route_stops| route_stops.stops.first().map(|stop| stop.stop_id.clone()))
+            else {
+                continue;
+            };
+            let mut departures_unix_ms: Vec<i64> = service_days
+                .iter()
+                .flat_map(|(date, day_start_ms)| {
+                    scheduled_trip_starts_for_route(
+                        &route.route_id,
+                        &gtfs,
+                        &gtfs.calendars,
+                        &gtfs.frequencies_by_trip,
+                        *date,
+                    )
+                    .into_iter()
+                    .map(move |trip| day_start_ms + trip.start_secs * 1000)
+                })
+                .filter(|departure_ms| *departure_ms >= now_ms)
+                .collect();
+            departures_unix_ms.sort_unstable();
+            departures_unix_ms.dedup();
+            let mean_layover_ms =
+                match load_mean_dwell_ms(&mut redis_conn, &state.redis_keys, &first_stop_id, hour)
+                    .await
+                {
+                    Ok(mean_ms) => mean_ms,
+                    Err(error) => {
+                        println!(
+                            "Failed to load layovers for route {}: {}",
+                            route.route_id, error
+                        );
+                        None
+                    }
+                };
+            schedules.insert(
+                route.route_id.clone(),
+                TerminusSchedule {
+                    first_stop_id,
+                    departures_unix_ms,
+                    mean_layover_ms,
+                },
+            );
+        }
+        *state.terminus_schedules.write().await = schedules;
+    }
+}
+
+// Picks up rollouts changed through another instance's admin API. Overrides for flags this
+// build doesn't know are ignored.
+pub(crate) async fn run_feature_flag_refresher(state: AppState) {
+    let mut refresh_interval =
+        tokio::time::interval(Duration::from_secs(FEATURE_FLAG_REFRESH_SECONDS));
+    refresh_interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
+
+    loop {
+        refresh_interval.tick().await;
+        let Ok(mut redis_conn) = read_redis_client(&state)
+            .get_multiplexed_async_connection()
+            .await
+        else {
+            continue;
+        };
+        let overrides: HashMap<String, String> = match redis::cmd("HGETALL")
+            .arg(state.redis_keys.key(REDIS_FEATURE_FLAGS_KEY))
+            .query_async(&mut redis_conn)
+            .await
+        {
+            Ok(overrides) => overrides,
+            Err(error) => {
+                println!("Failed to load feature flags: {}", error);
+                continue;
+            }
+        };
+
+        let mut rollout_percent = state.feature_flags.defaults.clone();
+        for (flag, value) in overrides {
+            if let (true, Ok(percent)) = (is_known_feature_flag(&flag), value.trim().parse::<u8>())
+            {
+                rollout_percent.insert(flag, percent.min(100));
+            }
+        }
+        if let Ok(mut current) = state.feature_flags.rollout_percent.lock() {
+            *current = rollout_percent;
+        }
+    }
+}
+
+// Picks up detours and stop closures declared or ended through another instance's admin API.
+pub(crate) async fn run_service_change_refresher(state: AppState) {
+    let mut refresh_interval =
+        tokio::time::interval(Duration::from_secs(SERVICE_CHANGE_REFRESH_SECONDS));
+    refresh_interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
+
+    loop {
+        refresh_interval.tick().await;
+        let Ok(mut redis_conn) = state.redis_client.get_multiplexed_async_connection().await else {
+            continue;
+        };
+
+        match load_unexpired_entries(
+            &state,
+            &mut redis_conn,
+            REDIS_DETOURS_KEY,
+            |detour: &RouteDetour| detour.valid_until_unix_ms,
+        )
+        .await
+        {
+            Ok(entries) => {
+                let mut detours: HashMap<String, Vec<RouteDetour>> = HashMap::new();
+                for detour in entries {
+                    detours
+                        .entry(detour.route_id.clone())
+                        .or_default()
+                        .push(detour);
+                }
+                if let Ok(mut current) = state.detours.lock() {
+                    *current = detours;
+                }
+            }
+            Err(error) => println!("Failed to load detours: {}", error),
+        }
+
+        match load_unexpired_entries(
+            &state,
+            &mut redis_conn,
+            REDIS_STOP_CLOSURES_KEY,
+            |closure: &StopClosure| closure.valid_until_unix_ms,
+        )
+        .await
+        {
+            Ok(entries) => {
+                let mut closures: HashMap<String, Vec<StopClosure>> = HashMap::new();
+                for closure in entries {
+                    closures
+                        .entry(closure.stop_id.clone())
+                        .or_default()
+                        .push(closure);
+                }
+                if let Ok(mut current) = state.stop_closures.lock() {
+                    *current = closures;
+                }
+            }
+            Err(error) => println!("Failed to load stop closures: {}", error),
+        }
+    }
+}
+
+// Every entry of a service change hash that has not expired yet, ordered by id, which for one
+// route or stop is declaration order. Expired or unreadable entries are deleted so the hash
+// only holds current and upcoming changes.
+async fn load_unexpired_entries<T: DeserializeOwned>(
+    state: &AppState,
+    redis_conn: &mut redis::aio::MultiplexedConnection,
+    key: &str,
+    valid_until_unix_ms: impl Fn(&T) -> i64,
+) -> Result<Vec<T>, redis::RedisError> {
+    let raw_entries: BTreeMap<String, String> = redis::cmd("HGETALL")
+        .arg(state.redis_keys.key(key))
+        .query_async(redis_conn)
+        .await?;
+
+    let now_ms = state.clock.now_ms();
+    let mut expired_ids: Vec<String> = Vec::new();
+    let mut entries: Vec<T> = Vec::new();
+    for (id, value) in raw_entries {
+        match serde_json::from_str::<T>(&value) {
+            Ok(entry) if valid_until_unix_ms(&entry) > now_ms => entries.push(entry),
+            _ => expired_ids.push(id),
+        }
+    }
+    if !expired_ids.is_empty() {
+        redis::cmd("HDEL")
+            .arg(state.redis_keys.key(key))
+            .arg(&expired_ids)
+            .query_async::<()>(redis_conn)
+            .await?;
+    }
+    Ok(entries)
+}
+
+// Detours in effect at now_ms, by route_id.
+pub(crate) fn active_detours(state: &AppState, now_ms: i64) -> HashMap<String, Vec<RouteDetour>> {
+    let Ok(detours) = state.detours.lock() else {
+        return HashMap::new();
+    };
+    detours
+        .iter()
+        .filter_map(|(route_id, route_detours)| {
+            let active: Vec<RouteDetour> = route_detours
+                .iter()
+                .filter(|detour| {
+                    detour.valid_from_unix_ms <= now_ms && now_ms < detour.valid_until_unix_ms
+                })
+                .cloned()
+                .collect();
+            (!active.is_empty()).then(|| (route_id.clone(), active))
+        })
+        .collect()
+}
+
+// Stop ids closed at now_ms; the latest declared closure wins when several overlap.
+pub(crate) fn active_stop_closures(state: &AppState, now_ms: i64) -> HashMap<String, StopClosure> {
+    let Ok(closures) = state.stop_closures.lock() else {
+        return HashMap::new();
+    };
+    closures
+        .iter()
+        .filter_map(|(stop_id, stop_closures)| {
+            stop_closures
+                .iter()
+                .rev()
+                .find(|closure| {
+                    closure.valid_from_unix_ms <= now_ms && now_ms < closure.valid_until_unix_ms
+                })
+                .map(|closure| (stop_id.clone(), closure.clone()))
+        })
+        .collect()
+}
+
+// Where incident open/resolve notifications go; both targets are optional.
+#[derive(Debug, Clone)]
+pub(crate) struct OperatorAlertConfig {
+    webhook_url: Option<String>,
+    telegram: Option<(String, ChatId)>,
+}
+
+pub(crate) fn operator_alert_config_from_env() -> OperatorAlertConfig {
+    let non_empty = |name: &str| env::var(name).ok().filter(|value| !value.trim().is_empty());
+    let telegram_chat = non_empty("TELEGRAM_OPERATOR_CHAT_ID")
+        .and_then(|value| value.trim().parse::<i64>().ok())
+        .map(ChatId);
+    OperatorAlertConfig {
+        webhook_url: non_empty("INCIDENT_WEBHOOK_URL"),
+        telegram: non_empty("TELEGRAM_BOT_TOKEN").zip(telegram_chat),
+    }
+}
+
+pub(crate) async fn run_anomaly_detector(state: AppState, alerts: OperatorAlertConfig) {
+    let mut check_interval =
+        tokio::time::interval(Duration::from_secs(ANOMALY_CHECK_INTERVAL_SECONDS));
+    check_interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
+    // (messages_processed, decode_failures) at the previous check, for per-interval deltas.
+    let mut previous_counters: Option<(u64, u64)> = None;
+
+    loop {
+        check_interval.tick().await;
+        let now_ms = state.clock.now_ms();
+        let mut detected: HashMap<IncidentKind, String> = HashMap::new();
+
+        {
+            let history = state.active_bus_count_history.read().await;
+            if let Some(&(latest_ms, latest_count)) = history.back() {
+                let baseline = history
+                    .iter()
+                    .filter(|(sampled_ms, _)| latest_ms - sampled_ms <= ANOMALY_BUS_DROP_WINDOW_MS)
+                    .map(|(_, count)| *count)
+                    .max()
+                    .unwrap_or(latest_count);
+                if baseline >= ANOMALY_BUS_DROP_MIN_BASELINE && latest_count * 2 < baseline {
+                    detected.insert(
+                        IncidentKind::ActiveBusDrop,
+                        format!(
+                            "Active buses dropped from {} to {} within 5 minutes",
+                            baseline, latest_count
+                        ),
+                    );
+                }
+            }
+        }
+
+        let status = state.ingestor_status.read().await.clone();
+        if let Some((previous_messages, previous_failures)) = previous_counters {
+            let messages = status.messages_processed.saturating_sub(previous_messages);
+            let failures = status.decode_failures.saturating_sub(previous_failures);
+            if failures >= ANOMALY_MIN_DECODE_FAILURES
+                && failures as f64 >= messages.max(1) as f64 * ANOMALY_DECODE_FAILURE_RATIO
+            {
+                detected.insert(
+                    IncidentKind::DecodeFailureSpike,
+                    format!(
+                        "{} of {} AVL messages failed to decode in the last minute",
+                        failures, messages
+                    ),
+                );
+            }
+        }
+        previous_counters = Some((status.messages_processed, status.decode_failures));
+
+        // The bus-drop baseline and decode ratios are meaningless on a few minutes of data.
+        if is_warming_up(&state).await {
+            continue;
+        }
+
+        if let Ok(snapshot) = load_active_bus_snapshot(&state).await {
+            let mut off_route: Vec<String> = snapshot
+                .buses
+                .iter()
+                .filter(|bus| bus.off_route)
+                .map(|bus| format!("{} ({})", bus.bus_no, bus.route))
+                .collect();
+            if !off_route.is_empty() {
+                off_route.sort();
+                let count = off_route.len();
+                off_route.truncate(ANOMALY_ROUTE_DEVIATION_MAX_LISTED);
+                detected.insert(
+                    IncidentKind::RouteDeviation,
+                    format!(
+                        "Buses off their route shape ({}): {}{}",
+                        count,
+                        off_route.join(", "),
+                        if count > off_route.len() { ", ..." } else { "" }
+                    ),
+                );
+            }
+            if let Some(first) = snapshot.buses.first() {
+                let all_identical = snapshot
+                    .buses
+                    .iter()
+                    .all(|bus| bus.latitude == first.latitude && bus.longitude == first.longitude);
+                if snapshot.buses.len() >= ANOMALY_IDENTICAL_COORDINATES_MIN_BUSES && all_identical
+                {
+                    detected.insert(
+                        IncidentKind::IdenticalCoordinates,
+                        format!(
+                            "All {} active buses report identical coordinates ({}, {})",
+                            snapshot.buses.len(),
+                            first.latitude,
+                            first.longitude
+                        ),
+                    );
+                }
+            }
+        }
+
+        if let Err(error) = reconcile_incidents(&state, &alerts, now_ms, detected).await {
+            println!("Failed to record incidents: {}", error);
+        }
+    }
+}
+
+// Opens one incident per kind while its condition holds and resolves it once it clears.
+async fn reconcile_incidents(
+    state: &AppState,
+    alerts: &OperatorAlertConfig,
+    now_ms: i64,
+    mut detected: HashMap<IncidentKind, String>,
+) -> Result<(), String> {
+    let mut redis_conn = state
+        .redis_client
+        .get_multiplexed_async_connection()
+        .await
+        .map_err(|error| error.to_string())?;
+    let incidents = load_incidents(&mut redis_conn, &state.redis_keys).await?;
+
+    let mut pipe = redis::pipe();
+    let mut notifications = Vec::new();
+    for mut incident in incidents {
+        if incident.resolved_at_unix_ms.is_some() {
+            if incident.resolved_at_unix_ms < Some(now_ms - RESOLVED_INCIDENT_RETENTION_MS) {
+                pipe.cmd("HDEL")
+                    .arg(state.redis_keys.key(REDIS_INCIDENTS_KEY))
+                    .arg(&incident.id)
+                    .ignore();
+            }
+            continue;
+        }
+        match detected.remove(&incident.kind) {
+            Some(message) => {
+                incident.message = message;
+                incident.last_detected_at_unix_ms = now_ms;
+            }
+            None => {
+                incident.resolved_at_unix_ms = Some(now_ms);
+                notifications.push(format!("Resolved: {}", incident.message));
+            }
+        }
+        pipe.cmd("HSET")
+            .arg(state.redis_keys.key(REDIS_INCIDENTS_KEY))
+            .arg(&incident.id)
+            .arg(serde_json::to_string(&incident).map_err(|error| error.to_string())?)
+            .ignore();
+    }
+
+    for (kind, message) in detected {
+        let incident = Incident {
+            id: format!("{}-{}", kind.as_str(), now_ms),
+            kind,
+            message,
+            opened_at_unix_ms: now_ms,
+            last_detected_at_unix_ms: now_ms,
+            resolved_at_unix_ms: None,
+        };
+        println!("Incident opened: {}", incident.message);
+        sentry::capture_message(&incident.message, sentry::Level::Warning);
+        notifications.push(format!("Incident: {}", incident.message));
+        pipe.cmd("HSET")
+            .arg(state.redis_keys.key(REDIS_INCIDENTS_KEY))
+            .arg(&incident.id)
+            .arg(serde_json::to_string(&incident).map_err(|error| error.to_string())?)
+            .ignore();
+    }
+
+    pipe.query_async::<()>(&mut redis_conn)
+        .await
+        .map_err(|error| error.to_string())?;
+
+    for text in notifications {
+        notify_operator(alerts, &text).await;
+    }
+    Ok(())
+}
+
+pub(crate) async fn load_incidents(
+    redis_conn: &mut redis::aio::MultiplexedConnection,
+    keys: &RedisKeys,
+) -> Result<Vec<Incident>, String> {
+    let stored: HashMap<String, String> = redis::cmd("HGETALL")
+        .arg(keys.key(REDIS_INCIDENTS_KEY))
+        .query_async(redis_conn)
+        .await
+        .map_err(|error| error.to_string())?;
+    let mut incidents: Vec<Incident> = stored
+        .values()
+        .filter_map(|value| serde_json::from_str(value).ok())
+        .collect();
+    incidents.sort_by_key(|incident| std::cmp::Reverse(incident.opened_at_unix_ms));
+    Ok(incidents)
+}
+
+async fn notify_operator(alerts: &OperatorAlertConfig, text: &str) {
+    if let Some(webhook_url) = &alerts.webhook_url {
+        let result = reqwest::Client::new()
+            .post(webhook_url)
+            .timeout(Duration::from_secs(10))
+            .header(CONTENT_TYPE, "application/json")
+            .body(json!({ "text": text }).to_string())
+            .send()
+            .await
+            .and_then(|response| response.error_for_status());
+        if let Err(error) = result {
+            println!("Failed to deliver incident webhook: {}", error);
+        }
+    }
+    if let Some((token, chat_id)) = &alerts.telegram {
+        if let Err(error) = Bot::new(token).send_message(*chat_id, text).await {
+            println!("Failed to send incident to Telegram: {}", error);
+        }
+    }
+}
+
+// Open data export target. Setting EXPORT_S3_BUCKET enables the exporter; EXPORT_S3_ENDPOINT
+// switches to path-style requests against an S3-compatible server such as MinIO.
+#[derive(Debug, Clone)]
+pub(crate) struct SnapshotExportConfig {
+    bucket: String,
+    endpoint: Option<String>,
+    region: String,
+    access_key_id: Option<String>,
+    secret_access_key: Option<String>,
+    prefix: String,
+    interval: Duration,
+}
+
+pub(crate) fn snapshot_export_config_from_env() -> Option<SnapshotExportConfig> {
+    let non_empty = |name: &str| env::var(name).ok().filter(|value| !value.trim().is_empty());
+    Some(SnapshotExportConfig {
+        bucket: non_empty("EXPORT_S3_BUCKET")?,
+        endpoint: non_empty("EXPORT_S3_ENDPOINT"),
+        region: non_empty("EXPORT_S3_REGION")
+            .unwrap_or_else(|| DEFAULT_EXPORT_S3_REGION.to_string()),
+        access_key_id: non_empty("EXPORT_S3_ACCESS_KEY_ID"),
+        secret_access_key: non_empty("EXPORT_S3_SECRET_ACCESS_KEY"),
+        prefix: non_empty("EXPORT_S3_PREFIX")
+            .map(|prefix| prefix.trim_matches('/').to_string())
+            .unwrap_or_else(|| DEFAULT_EXPORT_S3_PREFIX.to_string()),
+        interval: Duration::from_secs(
+            env_or("EXPORT_INTERVAL_SECONDS", DEFAULT_EXPORT_INTERVAL_SECONDS).max(30),
+        ),
+    })
+}
+
+#[derive(Debug, Serialize)]
+struct ExportRouteStats {
+    route: String,
+    bus_count: usize,
+    stationary_count: usize,
+    mean_speed_kmh: f64,
+}
+
+#[derive(Debug, Serialize)]
+struct ExportSnapshot {
+    exported_at_unix_ms: i64,
+    last_ingest_at_unix_ms: Option<i64>,
+    active_bus_count: usize,
+    routes: Vec<ExportRouteStats>,
+    positions: Vec<BusPosition>,
+}
+
+pub(crate) async fn run_snapshot_exporter(state: AppState, config: SnapshotExportConfig) {
+    let bucket = match open_export_bucket(&config) {
+        Ok(bucket) => bucket,
+        Err(error) => {
+            println!("Snapshot export disabled: {}", error);
+            return;
+        }
+    };
+    let mut export_interval = tokio::time::interval(config.interval);
+    export_interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
+
+    println!(
+        "Exporting snapshots to s3://{}/{} every {}s",
+        config.bucket,
+        config.prefix,
+        config.interval.as_secs()
+    );
+    loop {
+        export_interval.tick().await;
+        match export_snapshot(&state, &bucket, &config.prefix).await {
+            Ok(key) => println!("Exported snapshot to {}", key),
+            Err(error) => {
+                println!("Snapshot export failed: {}", error);
+                sentry::capture_message(
+                    &format!("Snapshot export failed: {}", error),
+                    sentry::Level::Warning,
+                );
+            }
+        }
+    }
+}
+
+fn open_export_bucket(config: &SnapshotExportConfig) -> Result<Box<s3::Bucket>, String> {
+    let region = match &config.endpoint {
+        Some(endpoint) => s3::Region::Custom {
+            region: config.region.clone(),
+            endpoint: endpoint.clone(),
+        },
+        None => config
+            .region
+            .parse::<s3::Region>()
+            .map_err(|error| error.to_string())?,
+    };
+    let credentials = s3::creds::Credentials::new(
+        config.access_key_id.as_deref(),
+        config.secret_access_key.as_deref(),
+        None,
+        None,
+        None,
+    )
+    .map_err(|error| error.to_string())?;
+    let bucket =
+        s3::Bucket::new(&config.bucket, region, credentials).map_err(|error| error.to_string())?;
+    Ok(if config.endpoint.is_some() {
+        bucket.with_path_style()
+    } else {
+        bucket
+    })
+}
+
+async fn export_snapshot(
+    state: &AppState,
+    bucket: &s3::Bucket,
+    prefix: &str,
+) -> Result<String, String> {
+    let snapshot = load_active_bus_snapshot(state)
+        .await
+        .map_err(|error| error.to_string())?;
+    let thresholds = *state.thresholds.read().await;
+    let now_ms = state.clock.now_ms();
+
+    let mut routes: HashMap<String, ExportRouteStats> = HashMap::new();
+    for bus in &snapshot.buses {
+        let route = normalize_route_code(&bus.route);
+        let stats = routes
+            .entry(route.clone())
+            .or_insert_with(|| ExportRouteStats {
+                route,
+                bus_count: 0,
+                stationary_count: 0,
+                mean_speed_kmh: 0.0,
+            });
+        stats.bus_count += 1;
+        stats.mean_speed_kmh += bus.speed;
+        if is_bus_stationary(&snapshot.motion_states, &bus.bus_no, now_ms, &thresholds) {
+            stats.stationary_count += 1;
+        }
+    }
+    let mut routes: Vec<ExportRouteStats> = routes
+        .into_values()
+        .map(|mut stats| {
+            stats.mean_speed_kmh =
+                (stats.mean_speed_kmh / stats.bus_count as f64 * 10.0).round() / 10.0;
+            stats
+        })
+        .collect();
+    routes.sort_by(|left, right| left.route.cmp(&right.route));
+
+    let export = ExportSnapshot {
+        exported_at_unix_ms: now_ms,
+        last_ingest_at_unix_ms: snapshot.last_ingest_at_unix_ms,
+        active_bus_count: snapshot.active_bus_count,
+        routes,
+        // Published data never carries driver identifiers, hashed or not.
+        positions: snapshot
+            .buses
+            .into_iter()
+            .map(|mut bus| {
+                bus.captain_id = None;
+                bus
+            })
+            .collect(),
+    };
+
+    let json = serde_json::to_vec(&export).map_err(|error| error.to_string())?;
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder
+        .write_all(&json)
+        .map_err(|error| error.to_string())?;
+    let compressed = encoder.finish().map_err(|error| error.to_string())?;
+
+    let kl_offset = FixedOffset::east_opt(KL_UTC_OFFSET_SECONDS).expect("valid KL offset");
+    let exported_at = chrono::DateTime::from_timestamp_millis(now_ms)
+        .ok_or_else(|| "clock out of range".to_string())?
+        .with_timezone(&kl_offset);
+    let key = format!(
+        "{}/snapshots/date={}/hour={}/{}.json.gz",
+        prefix,
+        exported_at.format("%Y-%m-%d"),
+        exported_at.format("%H"),
+        now_ms
+    );
+    bucket
+        .put_object_with_content_type(&key, &compressed, "application/gzip")
+        .await
+        .map_err(|error| error.to_string())?;
+    Ok(key)
+}
+
+fn usage_key(keys: &RedisKeys, date: NaiveDate, kind: &str) -> String {
+    format!(
+        "{}{}:{}",
+        keys.key(REDIS_USAGE_KEY_PREFIX),
+        date.format("%Y-%m-%d"),
+        kind
+    )
+}
+
+fn activity_key(keys: &RedisKeys, hour: i64) -> String {
+    format!("{}{}", keys.key(REDIS_ACTIVITY_KEY_PREFIX), hour)
+}
+
+// Pass-by counts summed over the last `hours` hours, the current one included: by stop_id and
+// by (route_id, from_stop_id, to_stop_id).
+pub(crate) async fn load_activity(
+    state: &AppState,
+    hours: u32,
+) -> Result<(HashMap<String, u64>, HashMap<(String, String, String), u64>), AppError> {
+    if state.fixture.is_some() {
+        return Ok((HashMap::new(), HashMap::new()));
+    }
+    let _timer = StageTimer::start(Stage::Redis);
+    let mut redis_conn = read_redis_client(state)
+        .get_multiplexed_async_connection()
+        .await?;
+    let current_hour = state.clock.now_ms() / 3_600_000;
+    let mut pipe = redis::pipe();
+    for hours_ago in 0..i64::from(hours) {
+        pipe.cmd("HGETALL")
+            .arg(activity_key(&state.redis_keys, current_hour - hours_ago));
+    }
+    let hourly: Vec<HashMap<String, u64>> = pipe.query_async(&mut redis_conn).await?;
+
+    let mut stops: HashMap<String, u64> = HashMap::new();
+    let mut segments: HashMap<(String, String, String), u64> = HashMap::new();
+    for (field, count) in hourly.into_iter().flatten() {
+        let mut parts = field.split('|');
+        match (parts.next(), parts.next(), parts.next(), parts.next()) {
+            (Some("stop"), Some(stop_id), None, None) => {
+                *stops.entry(stop_id.to_string()).or_default() += count;
+            }
+            (Some("segment"), Some(route_id), Some(from_stop_id), Some(to_stop_id)) => {
+                *segments
+                    .entry((
+                        route_id.to_string(),
+                        from_stop_id.to_string(),
+                        to_stop_id.to_string(),
+                    ))
+                    .or_default() += count;
+            }
+            _ => {}
+        }
+    }
+    Ok((stops, segments))
+}
+
+pub(crate) fn kl_date(unix_ms: i64) -> Option<NaiveDate> {
+    let kl_offset = FixedOffset::east_opt(KL_UTC_OFFSET_SECONDS)?;
+    Some(
+        chrono::DateTime::from_timestamp_millis(unix_ms)?
+            .with_timezone(&kl_offset)
+            .date_naive(),
+    )
+}
+
+// Counts are held in memory between flushes; a failed flush drops that minute rather than
+// retrying, which is acceptable for a popularity ranking.
+pub(crate) async fn run_usage_flusher(state: AppState) {
+    let mut flush_interval = tokio::time::interval(Duration::from_secs(USAGE_FLUSH_SECONDS));
+    flush_interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
+
+    loop {
+        flush_interval.tick().await;
+        let Some(date) = kl_date(state.clock.now_ms()) else {
+            continue;
+        };
+        let mut pipe = redis::pipe();
+        let mut has_counts = false;
+        for (kind, counters) in [
+            ("endpoints", &state.usage.endpoints),
+            ("stops", &state.usage.stops),
+            ("routes", &state.usage.routes),
+        ] {
+            let counts = match counters.lock() {
+                Ok(mut counts) => std::mem::take(&mut *counts),
+                Err(_) => continue,
+            };
+            if counts.is_empty() {
+                continue;
+            }
+            has_counts = true;
+            let key = usage_key(&state.redis_keys, date, kind);
+            for (field, count) in counts {
+                pipe.cmd("HINCRBY").arg(&key).arg(field).arg(count).ignore();
+            }
+            pipe.cmd("EXPIRE")
+                .arg(&key)
+                .arg(i64::from(USAGE_RETENTION_DAYS + 1) * 86_400)
+                .ignore();
+        }
+        if !has_counts {
+            continue;
+        }
+
+        let result = match state.redis_client.get_multiplexed_async_connection().await {
+            Ok(mut redis_conn) => pipe.query_async::<()>(&mut redis_conn).await,
+            Err(error) => Err(error),
+        };
+        if let Err(error) = result {
+            println!("Failed to flush usage counts: {}", error);
+        }
+    }
+}
+
+// Sums each kind over the last `days` KL days, today included.
+pub(crate) async fn load_usage(
+    state: &AppState,
+    days: u32,
+    limit: usize,
+) -> Result<UsageResponse, AppError> {
+    let today =
+        kl_date(state.clock.now_ms()).ok_or_else(|| internal_error("clock is out of range"))?;
+    let mut redis_conn = read_redis_client(state)
+        .get_multiplexed_async_connection()
+        .await?;
+
+    let mut totals: Vec<Vec<UsageCount>> = Vec::new();
+    for kind in ["endpoints", "stops", "routes"] {
+        let mut pipe = redis::pipe();
+        for days_ago in 0..days {
+            if let Some(date) = today.checked_sub_days(chrono::Days::new(u64::from(days_ago))) {
+                pipe.cmd("HGETALL")
+                    .arg(usage_key(&state.redis_keys, date, kind));
+            }
+        }
+        let daily: Vec<HashMap<String, u64>> = pipe.query_async(&mut redis_conn).await?;
+        let mut summed: HashMap<String, u64> = HashMap::new();
+        for (key, count) in daily.into_iter().flatten() {
+            *summed.entry(key).or_default() += count;
+        }
+        let mut counts: Vec<UsageCount> = summed
+            .into_iter()
+            .map(|(key, count)| UsageCount { key, count })
+            .collect();
+        counts.sort_by(|a, b| b.count.cmp(&a.count).then_with(|| a.key.cmp(&b.key)));
+        counts.truncate(limit);
+        totals.push(counts);
+    }
+
+    let routes = totals.pop().unwrap_or_default();
+    let stops = totals.pop().unwrap_or_default();
+    let endpoints = totals.pop().unwrap_or_default();
+    Ok(UsageResponse {
+        days,
+        endpoints,
+        stops,
+        routes,
+    })
+}
+
+// Bounds of a KL service day as [start, end) in unix ms.
+pub(crate) fn kl_day_bounds_ms(date: NaiveDate) -> Option<(i64, i64)> {
+    let kl_offset = FixedOffset::east_opt(KL_UTC_OFFSET_SECONDS)?;
+    let start = kl_offset
+        .from_local_datetime(&date.and_hms_opt(0, 0, 0)?)
+        .single()?
+        .timestamp_millis();
+    Some((start, start + 24 * 3_600_000))
+}
+
+#[derive(Debug, Default)]
+struct RouteDayAccumulator {
+    hours: HashSet<u32>,
+    buses: HashSet<String>,
+    km_operated: f64,
+    // (bus_no, trip start) so a trip seen in many frames counts once.
+    trip_starts: HashSet<(String, i64)>,
+}
+
+// Walks every history frame of the day in time order. Buses parked in a depot count towards
+// nothing; distance is summed between consecutive fixes of the same bus on the same route.
+async fn build_daily_route_report(
+    state: &AppState,
+    date: NaiveDate,
+) -> Result<DailyRouteReport, AppError> {
+    let (day_start_ms, day_end_ms) = kl_day_bounds_ms(date)
+        .ok_or_else(|| AppError::Validation(format!("Invalid report date {}", date)))?;
+    let mut redis_conn = read_redis_client(state)
+        .get_multiplexed_async_connection()
+        .await?;
+    let frame_ids: Vec<i64> = redis::cmd("ZRANGEBYSCORE")
+        .arg(state.redis_keys.key(REDIS_HISTORY_FRAMES_KEY))
+        .arg(day_start_ms)
+        .arg(format!("({}", day_end_ms))
+        .query_async(&mut redis_conn)
+        .await?;
+
+    let mut routes: HashMap<String, RouteDayAccumulator> = HashMap::new();
+    let mut last_fix: HashMap<String, (String, f64, f64, i64)> = HashMap::new();
+    let mut frames_sampled = 0;
+    for batch in frame_ids.chunks(ROUTE_REPORT_FRAME_BATCH) {
+        let frame_keys: Vec<String> = batch
+            .iter()
+            .map(|frame_id| {
+                format!(
+                    "{}{}",
+                    state.redis_keys.key(REDIS_HISTORY_FRAME_KEY_PREFIX),
+                    frame_id
+                )
+            })
+            .collect();
+        let raw_frames: Vec<Option<String>> = redis::cmd("MGET")
+            .arg(&frame_keys)
+            .query_async(&mut redis_conn)
+            .await?;
+        for frame in raw_frames
+            .into_iter()
+            .flatten()
+            .filter_map(|value| serde_json::from_str::<HistoryFrame>(&value).ok())
+        {
+            frames_sampled += 1;
+            let hour = kl_hour_of_day(frame.captured_at_unix_ms);
+            for bus in frame.buses.iter().filter(|bus| !bus.in_depot) {
+                let route = normalize_route_code(&bus.route);
+                if route.is_empty() || bus.bus_no.is_empty() {
+                    continue;
+                }
+                let stats = routes.entry(route.clone()).or_default();
+                stats.hours.insert(hour);
+                stats.buses.insert(bus.bus_no.clone());
+                if let Some(started_at) = frame
+                    .motion_states
+                    .get(&bus.bus_no)
+                    .and_then(|motion| motion.last_stop_passage.as_ref())
+                    .and_then(|passage| passage.trip_started_at_unix_ms)
+                    .filter(|started_at| (day_start_ms..day_end_ms).contains(started_at))
+                {
+                    stats.trip_starts.insert((bus.bus_no.clone(), started_at));
+                }
+
+                let fix = (
+                    route.clone(),
+                    bus.latitude,
+                    bus.longitude,
+                    frame.captured_at_unix_ms,
+                );
+                if let Some((last_route, last_lat, last_lon, last_ms)) =
+                    last_fix.insert(bus.bus_no.clone(), fix)
+                {
+                    let elapsed_hours = (frame.captured_at_unix_ms - last_ms) as f64 / 3_600_000.0;
+                    let distance_km =
+                        haversine_distance(last_lat, last_lon, bus.latitude, bus.longitude);
+                    if last_route == route
+                        && elapsed_hours > 0.0
+                        && distance_km / elapsed_hours <= ROUTE_REPORT_MAX_SEGMENT_SPEED_KMH
+                    {
+                        stats.km_operated += distance_km;
+                    }
+                }
+            }
+        }
+    }
+
+    let mut routes: Vec<RouteDayStats> = routes
+        .into_iter()
+        .map(|(route, stats)| {
+            let mut starts: Vec<i64> = stats
+                .trip_starts
+                .into_iter()
+                .map(|(_, started_at)| started_at)
+                .collect();
+            starts.sort_unstable();
+            let mut hourly: BTreeMap<u32, (usize, Vec<i64>)> = BTreeMap::new();
+            for (index, started_at) in starts.iter().enumerate() {
+                let hour_stats = hourly.entry(kl_hour_of_day(*started_at)).or_default();
+                hour_stats.0 += 1;
+                if index > 0 {
+                    hour_stats.1.push(started_at - starts[index - 1]);
+                }
+            }
+            RouteDayStats {
+                route,
+                service_hours_observed: stats.hours.len() as u32,
+                buses_deployed: stats.buses.len(),
+                km_operated: (stats.km_operated * 10.0).round() / 10.0,
+                hourly: hourly
+                    .into_iter()
+                    .map(|(hour, (trips_started, gaps_ms))| RouteHourStats {
+                        hour,
+                        trips_started,
+                        average_headway_minutes: (!gaps_ms.is_empty()).then(|| {
+                            let mean_ms = gaps_ms.iter().sum::<i64>() as f64 / gaps_ms.len() as f64;
+                            (mean_ms / 6_000.0).round() / 10.0
+                        }),
+                    })
+                    .collect(),
+            }
+        })
+        .collect();
+    routes.sort_by(|left, right| left.route.cmp(&right.route));
+
+    Ok(DailyRouteReport {
+        date: date.format("%Y-%m-%d").to_string(),
+        generated_at_unix_ms: state.clock.now_ms(),
+        frames_sampled,
+        routes,
+    })
+}
+
+// One row per route; headways are spread over hour_00..hour_23 columns so the sheet opens
+// ready to chart.
+#[derive(Debug, Clone)]
+pub(crate) struct ObservedTripStart {
+    pub(crate) bus_no: String,
+    pub(crate) shape_id: String,
+    pub(crate) started_at_unix_ms: i64,
+}
+
+// Trips buses on the route were seen starting from the first stop in [from_ms, to_ms), read
+// back from history frames. A trip seen in many frames counts once.
+pub(crate) async fn load_observed_trip_starts(
+    state: &AppState,
+    route_id: &str,
+    from_ms: i64,
+    to_ms: i64,
+) -> Result<Vec<ObservedTripStart>, AppError> {
+    let mut redis_conn = read_redis_client(state)
+        .get_multiplexed_async_connection()
+        .await?;
+    let frame_ids: Vec<i64> = redis::cmd("ZRANGEBYSCORE")
+        .arg(state.redis_keys.key(REDIS_HISTORY_FRAMES_KEY))
+        .arg(from_ms)
+        .arg(format!("({}", to_ms))
+        .query_async(&mut redis_conn)
+        .await?;
+
+    let mut seen: HashSet<(String, i64)> = HashSet::new();
+    let mut starts = Vec::new();
+    for batch in frame_ids.chunks(ROUTE_REPORT_FRAME_BATCH) {
+        let frame_keys: Vec<String> = batch
+            .iter()
+            .map(|frame_id| {
+                format!(
+                    "{}{}",
+                    state.redis_keys.key(REDIS_HISTORY_FRAME_KEY_PREFIX),
+                    frame_id
+                )
+            })
+            .collect();
+        let raw_frames: Vec<Option<String>> = redis::cmd("MGET")
+            .arg(&frame_keys)
+            .query_async(&mut redis_conn)
+            .await?;
+        for frame in raw_frames
+            .into_iter()
+            .flatten()
+            .filter_map(|value| serde_json::from_str::<HistoryFrame>(&value).ok())
+        {
+            for bus in frame
+                .buses
+                .iter()
+                .filter(|bus| !bus.in_depot && is_bus_on_route(&bus.route, route_id))
+            {
+                let Some(passage) = frame
+                    .motion_states
+                    .get(&bus.bus_no)
+                    .and_then(|motion| motion.last_stop_passage.as_ref())
+                else {
+                    continue;
+                };
+                let Some(started_at) = passage
+                    .trip_started_at_unix_ms
+                    .filter(|started_at| (from_ms..to_ms).contains(started_at))
+                else {
+                    continue;
+                };
+                if seen.insert((bus.bus_no.clone(), started_at)) {
+                    starts.push(ObservedTripStart {
+                        bus_no: bus.bus_no.clone(),
+                        shape_id: passage.shape_id.clone(),
+                        started_at_unix_ms: started_at,
+                    });
+                }
+            }
+        }
+    }
+    starts.sort_by_key(|start| start.started_at_unix_ms);
+    Ok(starts)
+}
+
+pub(crate) fn daily_route_report_csv(report: &DailyRouteReport) -> Result<Vec<u8>, String> {
+    let mut writer = csv::Writer::from_writer(Vec::new());
+    let mut header: Vec<String> = [
+        "date",
+        "route",
+        "service_hours_observed",
+        "buses_deployed",
+        "km_operated",
+        "trips_started",
+    ]
+    .iter()
+    .map(|column| column.to_string())
+    .collect();
+    header.extend((0..24).map(|hour| format!("headway_minutes_hour_{:02}", hour)));
+    writer
+        .write_record(&header)
+        .map_err(|error| error.to_string())?;
+
+    for route in &report.routes {
+        let mut row = vec![
+            report.date.clone(),
+            route.route.clone(),
+            route.service_hours_observed.to_string(),
+            route.buses_deployed.to_string(),
+            format!("{:.1}", route.km_operated),
+            route
+                .hourly
+                .iter()
+                .map(|hour| hour.trips_started)
+                .sum::<usize>()
+                .to_string(),
+        ];
+        row.extend((0..24).map(|hour| {
+            route
+                .hourly
+                .iter()
+                .find(|stats| stats.hour == hour)
+                .and_then(|stats| stats.average_headway_minutes)
+                .map(|minutes| format!("{:.1}", minutes))
+                .unwrap_or_default()
+        }));
+        writer
+            .write_record(&row)
+            .map_err(|error| error.to_string())?;
+    }
+    writer.into_inner().map_err(|error| error.to_string())
+}
+
+pub(crate) async fn load_daily_route_report(
+    state: &AppState,
+    date: NaiveDate,
+) -> Result<Option<DailyRouteReport>, AppError> {
+    let mut redis_conn = read_redis_client(state)
+        .get_multiplexed_async_connection()
+        .await?;
+    let raw_report: Option<String> = redis::cmd("GET")
+        .arg(format!(
+            "{}{}",
+            state.redis_keys.key(REDIS_ROUTE_REPORT_KEY_PREFIX),
+            date.format("%Y-%m-%d")
+        ))
+        .query_async(&mut redis_conn)
+        .await?;
+    Ok(raw_report.and_then(|value| serde_json::from_str(&value).ok()))
+}
+
+// Reports are kept in Redis for the API and, when a bucket is configured, written next to the
+// exported snapshots as JSON and CSV.
+async fn store_daily_route_report(
+    state: &AppState,
+    report: &DailyRouteReport,
+    bucket: Option<(&s3::Bucket, &str)>,
+) -> Result<(), String> {
+    let json = serde_json::to_vec(report).map_err(|error| error.to_string())?;
+    let mut redis_conn = state
+        .redis_client
+        .get_multiplexed_async_connection()
+        .await
+        .map_err(|error| error.to_string())?;
+    redis::cmd("SET")
+        .arg(format!(
+            "{}{}",
+            state.redis_keys.key(REDIS_ROUTE_REPORT_KEY_PREFIX),
+            report.date
+        ))
+        .arg(&json)
+        .arg("PX")
+        .arg(ROUTE_REPORT_RETENTION_MS)
+        .query_async::<()>(&mut redis_conn)
+        .await
+        .map_err(|error| error.to_string())?;
+
+    if let Some((bucket, prefix)) = bucket {
+        let csv = daily_route_report_csv(report)?;
+        let key_prefix = format!("{}/reports/date={}", prefix, report.date);
+        bucket
+            .put_object_with_content_type(
+                format!("{}/routes.json", key_prefix),
+                &json,
+                "application/json",
+            )
+            .await
+            .map_err(|error| error.to_string())?;
+        bucket
+            .put_object_with_content_type(format!("{}/routes.csv", key_prefix), &csv, "text/csv")
+            .await
+            .map_err(|error| error.to_string())?;
+    }
+    Ok(())
+}
+
+// Once a day, shortly after KL midnight, reports on the day that just ended.
+pub(crate) async fn run_daily_report_generator(
+    state: AppState,
+    export_config: Option<SnapshotExportConfig>,
+) {
+    let bucket = match export_config.as_ref().map(open_export_bucket) {
+        Some(Ok(bucket)) => Some(bucket),
+        Some(Err(error)) => {
+            println!("Report upload disabled: {}", error);
+            None
+        }
+        None => None,
+    };
+    let kl_offset = FixedOffset::east_opt(KL_UTC_OFFSET_SECONDS).expect("valid KL offset");
+
+    loop {
+        let now_ms = state.clock.now_ms();
+        let Some(today) = chrono::DateTime::from_timestamp_millis(now_ms)
+            .map(|now| now.with_timezone(&kl_offset).date_naive())
+        else {
+            return;
+        };
+        let Some((today_start_ms, _)) = kl_day_bounds_ms(today) else {
+            return;
+        };
+        let (run_at_ms, report_date) = if now_ms < today_start_ms + ROUTE_REPORT_DELAY_MS {
+            (today_start_ms + ROUTE_REPORT_DELAY_MS, today.pred_opt())
+        } else {
+            (
+                today_start_ms + 24 * 3_600_000 + ROUTE_REPORT_DELAY_MS,
+                Some(today),
+            )
+        };
+        tokio::time::sleep(Duration::from_millis((run_at_ms - now_ms).max(0) as u64)).await;
+        let Some(report_date) = report_date else {
+            continue;
+        };
+
+        let result = match build_daily_route_report(&state, report_date).await {
+            Ok(report) => {
+                let target = bucket
+                    .as_deref()
+                    .zip(export_config.as_ref().map(|config| config.prefix.as_str()));
+                store_daily_route_report(&state, &report, target)
+                    .await
+                    .map(|_| report.routes.len())
+            }
+            Err(error) => Err(error.to_string()),
+        };
+        match result {
+            Ok(route_count) => println!(
+                "Generated route report for {} ({}): {} routes",
+                report_date, state.city.id, route_count
+            ),
+            Err(error) => {
+                println!("Route report for {} failed: {}", report_date, error);
+                sentry::capture_message(
+                    &format!("Route report for {} failed: {}", report_date, error),
+                    sentry::Level::Warning,
+                );
+            }
+        }
+    }
+}
+
+// Buses whose chainage crossed this stop within the recent departure window, newest first.
+pub(crate) async fn load_recent_departures(
+    state: &AppState,
+    stop_id: &str,
+    now_ms: i64,
+) -> Result<Vec<RecentDeparture>, AppError> {
+    if state.fixture.is_some() {
+        return Ok(Vec::new());
+    }
+    let _timer = StageTimer::start(Stage::Redis);
+    let mut redis_conn = read_redis_client(state)
+        .get_multiplexed_async_connection()
+        .await?;
+    let entries: Vec<(String, f64)> = redis::cmd("ZRANGEBYSCORE")
+        .arg(format!(
+            "{}{}",
+            state.redis_keys.key(REDIS_STOP_DEPARTURES_KEY_PREFIX),
+            stop_id
+        ))
+        .arg(now_ms - RECENT_DEPARTURE_WINDOW_MS)
+        .arg(now_ms)
+        .arg("WITHSCORES")
+        .query_async(&mut redis_conn)
+        .await?;
+
+    let mut departures: Vec<RecentDeparture> = entries
+        .into_iter()
+        .filter_map(|(member, departed_at)| {
+            let (route_id, bus_no) = member.split_once('|')?;
+            let departed_at_unix_ms = departed_at as i64;
+            Some(RecentDeparture {
+                route_id: route_id.to_string(),
+                bus_no: bus_no.to_string(),
+                departed_at_unix_ms,
+                minutes_ago: ((now_ms - departed_at_unix_ms) as f64 / 6_000.0).round() / 10.0,
+            })
+        })
+        .collect();
+    departures.sort_by_key(|departure| std::cmp::Reverse(departure.departed_at_unix_ms));
+
+    Ok(departures)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ingest::attach_bus_freshness;
+    use crate::test_support::{test_bus, TestClock};
+    use crate::DEFAULT_BUS_TTL_SECONDS;
+
+    const NOW_MS: i64 = 1_772_409_600_000;
+    const BUS_TTL_MS: i64 = DEFAULT_BUS_TTL_SECONDS * 1_000;
+
+    #[test]
+    fn prune_keeps_a_bus_for_its_full_ttl() {
+        let clock = TestClock::new(NOW_MS);
+        let last_seen_ms = clock.now_ms();
+
+        clock.advance(BUS_TTL_MS - 1);
+        let (cutoff_ms, _) = prune_cutoffs(clock.now_ms(), BUS_TTL_MS);
+        assert!(last_seen_ms > cutoff_ms);
+
+        // The prune range runs up to and including the cutoff.
+        clock.advance(1);
+        let (cutoff_ms, _) = prune_cutoffs(clock.now_ms(), BUS_TTL_MS);
+        assert_eq!(cutoff_ms, last_seen_ms);
+    }
+
+    #[test]
+    fn expires_in_seconds_reaches_zero_as_the_bus_is_pruned() {
+        let clock = TestClock::new(NOW_MS);
+        let last_seen = HashMap::from([("WXY1234".to_string(), clock.now_ms())]);
+        let expires_in_seconds = |now_ms| {
+            attach_bus_freshness(
+                test_bus("WXY1234", 3.1, 101.7, 20.0),
+                &last_seen,
+                now_ms,
+                BUS_TTL_MS,
+            )
+            .expires_in_seconds
+        };
+
+        assert_eq!(expires_in_seconds(clock.now_ms()), Some(120));
+        clock.advance(30_000);
+        assert_eq!(expires_in_seconds(clock.now_ms()), Some(90));
+
+        clock.set(NOW_MS + BUS_TTL_MS);
+        assert_eq!(expires_in_seconds(clock.now_ms()), Some(0));
+        assert_eq!(prune_cutoffs(clock.now_ms(), BUS_TTL_MS).0, NOW_MS);
+
+        // A read racing the prune never reports a negative countdown.
+        clock.advance(5_000);
+        assert_eq!(expires_in_seconds(clock.now_ms()), Some(0));
+    }
+
+    #[test]
+    fn tombstones_outlive_the_bus_by_the_retention_window() {
+        let clock = TestClock::new(NOW_MS);
+        let removed_at_ms = clock.now_ms();
+
+        clock.advance(BUS_TOMBSTONE_RETENTION_MS - 1);
+        let (_, tombstone_cutoff_ms) = prune_cutoffs(clock.now_ms(), BUS_TTL_MS);
+        assert!(removed_at_ms > tombstone_cutoff_ms);
+
+        clock.advance(1);
+        let (_, tombstone_cutoff_ms) = prune_cutoffs(clock.now_ms(), BUS_TTL_MS);
+        assert_eq!(tombstone_cutoff_ms, removed_at_ms);
+    }
+
+    fn history_frame(captured_at_unix_ms: i64) -> Arc<HistoryFrame> {
+        Arc::new(HistoryFrame {
+            captured_at_unix_ms,
+            buses: Vec::new(),
+            motion_states: HashMap::new(),
+            active_bus_count: 0,
+            outside_service_area_count: 0,
+            last_ingest_at_unix_ms: None,
+            last_seen_unix_ms: HashMap::new(),
+        })
+    }
+
+    fn frame_key(captured_at_unix_ms: i64) -> HistoryFrameKey {
+        ("rapidbro:kl".to_string(), captured_at_unix_ms)
+    }
+
+    #[test]
+    fn history_cache_evicts_the_least_recently_served_frame() {
+        let cache = HistoryFrameCache {
+            budget_bytes: 250,
+            ..HistoryFrameCache::default()
+        };
+        cache_history_frame(&cache, frame_key(1), history_frame(1), 100);
+        cache_history_frame(&cache, frame_key(2), history_frame(2), 100);
+        // Serving frame 1 makes frame 2 the older of the two.
+        assert!(cached_history_frame(&cache, &frame_key(1)).is_some());
+
+        cache_history_frame(&cache, frame_key(3), history_frame(3), 100);
+        assert!(cached_history_frame(&cache, &frame_key(2)).is_none());
+        assert!(cached_history_frame(&cache, &frame_key(1)).is_some());
+        assert!(cached_history_frame(&cache, &frame_key(3)).is_some());
+        assert_eq!(cache.used_bytes.load(AtomicOrdering::Relaxed), 200);
+        assert_eq!(cache.evictions.load(AtomicOrdering::Relaxed), 1);
+        assert_eq!(cache.hits.load(AtomicOrdering::Relaxed), 3);
+        assert_eq!(cache.misses.load(AtomicOrdering::Relaxed), 1);
+    }
+
+    #[test]
+    fn history_cache_skips_frames_over_budget_and_a_zero_budget() {
+        let cache = HistoryFrameCache {
+            budget_bytes: 100,
+            ..HistoryFrameCache::default()
+        };
+        cache_history_frame(&cache, frame_key(1), history_frame(1), 101);
+        assert!(cached_history_frame(&cache, &frame_key(1)).is_none());
+        assert_eq!(cache.used_bytes.load(AtomicOrdering::Relaxed), 0);
+
+        let disabled = HistoryFrameCache::default();
+        cache_history_frame(&disabled, frame_key(1), history_frame(1), 0);
+        assert!(cached_history_frame(&disabled, &frame_key(1)).is_none());
+        assert_eq!(disabled.misses.load(AtomicOrdering::Relaxed), 0);
+    }
+
+    #[test]
+    fn kl_service_day_starts_at_local_midnight() {
+        // NOW_MS is 08:00 on 2 March in Kuala Lumpur, UTC+8.
+        let midnight_ms = NOW_MS - 8 * 3_600_000;
+        let date = kl_date(NOW_MS).unwrap();
+        assert_eq!(date, NaiveDate::from_ymd_opt(2026, 3, 2).unwrap());
+        assert_eq!(kl_date(midnight_ms), Some(date));
+        assert_eq!(
+            kl_date(midnight_ms - 1),
+            NaiveDate::from_ymd_opt(2026, 3, 1)
+        );
+        assert_eq!(
+            kl_day_bounds_ms(date),
+            Some((midnight_ms, midnight_ms + 24 * 3_600_000))
+        );
+    }
+
+    #[test]
+    fn replica_is_behind_when_its_link_is_down_or_it_lags() {
+        let lag = |link_up, lag_ms| ReplicaLag { link_up, lag_ms };
+        assert!(!replica_is_behind(&lag(true, 20_000), 20_000));
+        assert!(replica_is_behind(&lag(true, 20_001), 20_000));
+        assert!(replica_is_behind(&lag(false, 0), 20_000));
+    }
+}
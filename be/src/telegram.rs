@@ -0,0 +1,221 @@
+// The Telegram bot: departure boards for a stop id or a shared location, and "/watch" alerts
+// when a bus gets close. Replies come from the same stop ETA path as the HTTP API.
+
+use rapidbro_eta::is_bus_on_route;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::time::Duration;
+use teloxide::{
+    prelude::Requester,
+    types::{ChatId, Message as TelegramMessage},
+    Bot,
+};
+use tokio::{sync::RwLock, time::MissedTickBehavior};
+
+use crate::api::build_stop_incoming_response;
+use crate::gtfs::{current_gtfs_feed, find_nearest_stop};
+use crate::ingest::is_warming_up;
+use crate::store::active_stop_closures;
+use crate::AppState;
+
+const TELEGRAM_WATCH_POLL_INTERVAL_SECONDS: u64 = 30;
+const TELEGRAM_DEFAULT_WATCH_MINUTES: f64 = 5.0;
+const TELEGRAM_WATCH_EXPIRY_MS: i64 = 2 * 3_600_000;
+const TELEGRAM_MAX_WATCHES_PER_CHAT: usize = 5;
+const TELEGRAM_BOARD_MAX_ROWS: usize = 10;
+const TELEGRAM_HELP: &str = "Send a stop id or share your location to get the next buses.\n\
+/watch <stop> <route> [minutes] - message me when that bus is close (default 5 min)\n\
+/unwatch - cancel all your watches";
+
+// A pending "/watch" request: notify chat_id once route_id is within `minutes` of stop_id.
+#[derive(Debug, Clone)]
+struct TelegramWatch {
+    chat_id: ChatId,
+    stop_id: String,
+    route_id: String,
+    minutes: f64,
+    created_at_unix_ms: i64,
+}
+
+pub(crate) async fn run_telegram_bot(state: AppState, token: String) {
+    let bot = Bot::new(token);
+    let watches: Arc<RwLock<Vec<TelegramWatch>>> = Arc::new(RwLock::new(Vec::new()));
+
+    let watch_bot = bot.clone();
+    let watch_state = state.clone();
+    let watch_list = watches.clone();
+    tokio::spawn(async move {
+        run_telegram_watch_loop(watch_bot, watch_state, watch_list).await;
+    });
+
+    println!("Telegram bot started");
+    teloxide::repl(bot, move |bot: Bot, msg: TelegramMessage| {
+        let state = state.clone();
+        let watches = watches.clone();
+        async move {
+            let reply = handle_telegram_message(&state, &watches, &msg).await;
+            bot.send_message(msg.chat.id, reply).await?;
+            Ok(())
+        }
+    })
+    .await;
+}
+
+async fn handle_telegram_message(
+    state: &AppState,
+    watches: &RwLock<Vec<TelegramWatch>>,
+    msg: &TelegramMessage,
+) -> String {
+    if let Some(location) = msg.location() {
+        return match find_nearest_stop(
+            &current_gtfs_feed(state).stops_map,
+            location.latitude,
+            location.longitude,
+            &active_stop_closures(state, state.clock.now_ms()),
+        ) {
+            Ok(stop) => telegram_departure_board(state, &stop.stop_id).await,
+            Err(error) => error.to_string(),
+        };
+    }
+
+    let text = msg.text().unwrap_or_default().trim();
+    let mut parts = text.split_whitespace();
+    // Commands may arrive as "/watch@botname" in group chats.
+    let command = parts
+        .next()
+        .map(|part| part.split('@').next().unwrap_or(part));
+
+    match command {
+        None | Some("/start") | Some("/help") => TELEGRAM_HELP.to_string(),
+        Some("/watch") => {
+            let (Some(stop_id), Some(route_id)) = (parts.next(), parts.next()) else {
+                return "Usage: /watch <stop> <route> [minutes]".to_string();
+            };
+            let minutes = parts
+                .next()
+                .and_then(|value| value.parse::<f64>().ok())
+                .filter(|value| *value > 0.0)
+                .unwrap_or(TELEGRAM_DEFAULT_WATCH_MINUTES);
+
+            let mut watches = watches.write().await;
+            let chat_watch_count = watches
+                .iter()
+                .filter(|watch| watch.chat_id == msg.chat.id)
+                .count();
+            if chat_watch_count >= TELEGRAM_MAX_WATCHES_PER_CHAT {
+                return format!(
+                    "You already have {} watches. Send /unwatch to clear them.",
+                    chat_watch_count
+                );
+            }
+            watches.push(TelegramWatch {
+                chat_id: msg.chat.id,
+                stop_id: stop_id.to_string(),
+                route_id: route_id.to_string(),
+                minutes,
+                created_at_unix_ms: state.clock.now_ms(),
+            });
+            format!(
+                "Watching route {} at stop {}. I'll message you when a bus is {} minutes away.",
+                route_id, stop_id, minutes
+            )
+        }
+        Some("/unwatch") => {
+            let mut watches = watches.write().await;
+            let before = watches.len();
+            watches.retain(|watch| watch.chat_id != msg.chat.id);
+            format!("Cancelled {} watches.", before - watches.len())
+        }
+        Some(command) if command.starts_with('/') => TELEGRAM_HELP.to_string(),
+        Some(stop_id) => telegram_departure_board(state, stop_id).await,
+    }
+}
+
+async fn telegram_departure_board(state: &AppState, stop_id: &str) -> String {
+    let response = match build_stop_incoming_response(state, stop_id, None).await {
+        Ok(response) => response,
+        Err(error) => return error.to_string(),
+    };
+
+    let mut lines = vec![format!("{} ({})", response.stop_name, response.stop_id)];
+    if response.data.is_empty() {
+        lines.push("No buses on the way right now.".to_string());
+    }
+    for eta in response.data.iter().take(TELEGRAM_BOARD_MAX_ROWS) {
+        lines.push(format!(
+            "{} {} - {:.0} min ({} stops away)",
+            eta.route_id, eta.bus_no, eta.eta_minutes, eta.stops_away
+        ));
+    }
+    if response.meta.is_stale {
+        lines.push("Live data is delayed, times may be off.".to_string());
+    }
+    lines.join("\n")
+}
+
+async fn run_telegram_watch_loop(
+    bot: Bot,
+    state: AppState,
+    watches: Arc<RwLock<Vec<TelegramWatch>>>,
+) {
+    let mut poll_interval =
+        tokio::time::interval(Duration::from_secs(TELEGRAM_WATCH_POLL_INTERVAL_SECONDS));
+    poll_interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
+
+    loop {
+        poll_interval.tick().await;
+        let now_ms = state.clock.now_ms();
+        let pending = watches.read().await.clone();
+        // Watches wait out warmup rather than firing on ETAs from thin motion state.
+        if pending.is_empty() || is_warming_up(&state).await {
+            continue;
+        }
+
+        let stop_ids: HashSet<&str> = pending.iter().map(|watch| watch.stop_id.as_str()).collect();
+        let mut responses = HashMap::new();
+        for stop_id in stop_ids {
+            if let Ok(response) = build_stop_incoming_response(&state, stop_id, None).await {
+                responses.insert(stop_id.to_string(), response);
+            }
+        }
+
+        let mut finished = Vec::new();
+        for (index, watch) in pending.iter().enumerate() {
+            if now_ms - watch.created_at_unix_ms > TELEGRAM_WATCH_EXPIRY_MS {
+                finished.push(index);
+                continue;
+            }
+            let Some(response) = responses.get(&watch.stop_id) else {
+                continue;
+            };
+            let Some(eta) = response
+                .data
+                .iter()
+                .filter(|eta| is_bus_on_route(&eta.route_id, &watch.route_id))
+                .find(|eta| eta.eta_minutes <= watch.minutes)
+            else {
+                continue;
+            };
+
+            let text = format!(
+                "Bus {} on route {} is about {:.0} min from {}.",
+                eta.bus_no, eta.route_id, eta.eta_minutes, response.stop_name
+            );
+            if let Err(error) = bot.send_message(watch.chat_id, text).await {
+                println!("Failed to send Telegram watch alert: {}", error);
+            }
+            finished.push(index);
+        }
+
+        if !finished.is_empty() {
+            // Watches added while this tick ran sit past the snapshot and are left alone.
+            let mut watches = watches.write().await;
+            let mut index = 0;
+            watches.retain(|_| {
+                let keep = !finished.contains(&index);
+                index += 1;
+                keep
+            });
+        }
+    }
+}
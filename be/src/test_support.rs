@@ -0,0 +1,221 @@
+// Fixtures shared by the module tests: small GTFS feeds on disk, an AppState that needs no
+// Redis, a clock tests can move and a bus as the socket reports it.
+
+use rapidbro_types::BusPosition;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::env;
+use std::path::PathBuf;
+use std::sync::{
+    atomic::{AtomicU64, Ordering as AtomicOrdering},
+    Arc,
+};
+use tokio::sync::{watch, RwLock};
+
+use crate::api::{
+    load_shedder_from_env, new_feature_flags, Singleflight, FRESHNESS_BUCKETS_SECONDS,
+};
+use crate::eta::thresholds_from_env;
+use crate::gtfs::{default_city_from_env, DEFAULT_PROVIDER};
+use crate::ingest::{initial_ingestor_status, GtfsRtCache};
+use crate::models::{
+    BusNoRules, CaptainIdPrivacy, GtfsFeed, HistoryFrame, HistoryFrameCache, HotStopBoards,
+    LatencyHistogram, PrivacySettings, ResolutionLog, RouteStopsCache, ShadowEvaluation,
+    UsageCounters, Warmup,
+};
+use crate::store::{RedisKeys, DEFAULT_REDIS_KEY_PREFIX};
+use crate::{AppState, Clock, FixedClock};
+
+pub(crate) const ROUTES_HEADER: &str =
+    "route_id,agency_id,route_short_name,route_long_name,route_type,route_color,route_text_color\n";
+pub(crate) const TRIPS: &str = "route_id,service_id,trip_id,shape_id,trip_headsign,direction_id\n\
+                                T7890,weekday,T7890-1,S1,Lembah Subang,0\n";
+pub(crate) const STOPS_HEADER: &str = "stop_id,stop_code,stop_name,stop_desc,stop_lat,stop_lon\n";
+pub(crate) const CALENDAR: &str =
+    "service_id,monday,tuesday,wednesday,thursday,friday,saturday,sunday,start_date,end_date\n\
+     weekday,1,1,1,1,1,0,0,20260101,20261231\n";
+const FREQUENCIES: &str = "trip_id,start_time,end_time,headway_secs\n\
+                                      T7890-1,06:00:00,09:00:00,900\n\
+                                      T7890-1,08:00:00,10:00:00,600\n\
+                                      T7890-1,23:00:00,25:30:00,1800\n";
+
+// A feed directory of its own under the temp dir, removed again when dropped.
+pub(crate) struct FeedDir(pub(crate) PathBuf);
+
+impl FeedDir {
+    pub(crate) fn new(name: &str, files: &[(&str, String)]) -> Self {
+        let dir = env::temp_dir().join(format!("rapidbro-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        for (file_name, contents) in files {
+            std::fs::write(dir.join(file_name), contents).unwrap();
+        }
+        Self(dir)
+    }
+}
+
+impl Drop for FeedDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+// One weekday route, T7890, stopping at 1000838 and then 1008485.
+pub(crate) fn t789_feed_files(calendar: Option<&str>) -> Vec<(&'static str, String)> {
+    let mut files = vec![
+        (
+            "routes.txt",
+            format!("{}T7890,rapidkl,T789,Kelana Jaya,3,,\n", ROUTES_HEADER),
+        ),
+        ("trips.txt", TRIPS.to_string()),
+        (
+            "stop_times.txt",
+            "trip_id,arrival_time,departure_time,stop_id,stop_sequence,stop_headsign\n\
+             T7890-1,06:00:00,06:00:00,1000838,1,\n\
+             T7890-1,06:05:00,06:05:00,1008485,2,\n"
+                .to_string(),
+        ),
+        (
+            "stops.txt",
+            format!(
+                "{}1000838,,KL1397 FLAT PKNS,,3.1219,101.6572\n\
+                 1008485,,(M) PJ469 TERMINAL BAS,,3.1101,101.6401\n",
+                STOPS_HEADER
+            ),
+        ),
+        ("frequencies.txt", FREQUENCIES.to_string()),
+    ];
+    if let Some(calendar) = calendar {
+        files.push(("calendar.txt", calendar.to_string()));
+    }
+    files
+}
+
+// The default city over `gtfs`, serving an empty fixture frame at a fixed `now_ms` so handlers
+// and extractors run without Redis; shared by the module tests.
+pub(crate) fn test_app_state(gtfs: GtfsFeed, now_ms: i64) -> AppState {
+    AppState {
+        redis_client: redis::Client::open("redis://127.0.0.1/").unwrap(),
+        redis_read_client: None,
+        replica_lag: Arc::new(RwLock::new(None)),
+        city: Arc::new(default_city_from_env()),
+        gtfs: Arc::new(std::sync::RwLock::new(Arc::new(gtfs))),
+        redis_keys: RedisKeys {
+            prefix: DEFAULT_REDIS_KEY_PREFIX.to_string(),
+        },
+        ingestor_status: Arc::new(RwLock::new(initial_ingestor_status())),
+        thresholds: Arc::new(RwLock::new(thresholds_from_env())),
+        admin_api_key: None,
+        cursor_secret: Arc::new("test-cursor-secret".to_string()),
+        api_key_tiers: Arc::new(HashMap::new()),
+        coarse_position_tiers: Arc::new(HashSet::new()),
+        privacy: PrivacySettings {
+            captain_id: CaptainIdPrivacy::Strip,
+            hash_salt: String::new(),
+            retain_raw_captain_id: false,
+        },
+        dead_letters: Arc::new(RwLock::new(VecDeque::new())),
+        active_bus_count_history: Arc::new(RwLock::new(VecDeque::new())),
+        stop_eta_computed_total: Arc::new(AtomicU64::new(0)),
+        gps_to_redis_seconds: Arc::new(LatencyHistogram::new(&FRESHNESS_BUCKETS_SECONDS)),
+        redis_to_response_seconds: Arc::new(LatencyHistogram::new(&FRESHNESS_BUCKETS_SECONDS)),
+        bus_ttl_ms: 120_000,
+        stale_after_ms: 60_000,
+        bus_no_rules: BusNoRules {
+            strip_prefixes: Vec::new(),
+            aliases: HashMap::new(),
+        },
+        vehicle_roster: Arc::new(HashMap::new()),
+        route_groups: Arc::new(HashMap::new()),
+        runtime_profiles: Arc::new(RwLock::new(HashMap::new())),
+        terminus_schedules: Arc::new(RwLock::new(HashMap::new())),
+        feature_flags: Arc::new(new_feature_flags(HashMap::new(), HashSet::new())),
+        detours: Arc::new(std::sync::Mutex::new(HashMap::new())),
+        stop_closures: Arc::new(std::sync::Mutex::new(HashMap::new())),
+        shadow_evaluation: Arc::new(ShadowEvaluation::default()),
+        usage: Arc::new(UsageCounters::default()),
+        hot_stops: Arc::new(HotStopBoards::default()),
+        resolution_log: Arc::new(ResolutionLog::default()),
+        route_stops_cache: Arc::new(RouteStopsCache::default()),
+        history_frame_cache: Arc::new(HistoryFrameCache::default()),
+        depots: Arc::new(Vec::new()),
+        service_area: Arc::new(Vec::new()),
+        reverse_geocoder: None,
+        gtfs_rt_cache: Arc::new(RwLock::new(GtfsRtCache::default())),
+        history_retention_ms: 0,
+        fixture: Some(Arc::new(HistoryFrame {
+            captured_at_unix_ms: now_ms,
+            buses: Vec::new(),
+            motion_states: HashMap::new(),
+            active_bus_count: 0,
+            outside_service_area_count: 0,
+            last_ingest_at_unix_ms: None,
+            last_seen_unix_ms: HashMap::new(),
+        })),
+        clock: Arc::new(FixedClock(now_ms)),
+        warmup: Warmup {
+            started_at_unix_ms: now_ms,
+            min_duration_ms: 0,
+            min_batches: 0,
+            gtfs_only: false,
+        },
+        startup_phases: Arc::new(std::sync::Mutex::new(Vec::new())),
+        load_shedder: load_shedder_from_env(),
+        stop_eta_flights: Arc::new(Singleflight::new()),
+        route_eta_flights: Arc::new(Singleflight::new()),
+        ingest_events: Arc::new(watch::channel(None).0),
+    }
+}
+
+// Like FixedClock, but a test can move it between calls to step through windows and TTLs.
+#[derive(Debug)]
+pub(crate) struct TestClock(std::sync::atomic::AtomicI64);
+
+impl TestClock {
+    pub(crate) fn new(now_ms: i64) -> Self {
+        Self(std::sync::atomic::AtomicI64::new(now_ms))
+    }
+
+    pub(crate) fn set(&self, now_ms: i64) {
+        self.0.store(now_ms, AtomicOrdering::SeqCst);
+    }
+
+    pub(crate) fn advance(&self, delta_ms: i64) {
+        self.0.fetch_add(delta_ms, AtomicOrdering::SeqCst);
+    }
+}
+
+impl Clock for TestClock {
+    fn now_ms(&self) -> i64 {
+        self.0.load(AtomicOrdering::SeqCst)
+    }
+}
+
+// A bus as the socket reports it, before any ingest enrichment.
+pub(crate) fn test_bus(bus_no: &str, latitude: f64, longitude: f64, speed: f64) -> BusPosition {
+    BusPosition {
+        dt_received: None,
+        dt_gps: None,
+        latitude,
+        longitude,
+        dir: None,
+        speed,
+        angle: 0.0,
+        route: "T789".to_string(),
+        bus_no: bus_no.to_string(),
+        trip_no: None,
+        captain_id: None,
+        trip_rev_kind: None,
+        engine_status: 1,
+        accessibility: 0,
+        busstop_id: None,
+        provider: DEFAULT_PROVIDER.to_string(),
+        trip: None,
+        in_depot: false,
+        depot_name: None,
+        outside_service_area: false,
+        off_route: false,
+        vehicle: None,
+        last_seen_unix_ms: None,
+        expires_in_seconds: None,
+    }
+}
@@ -39,6 +39,21 @@ type BusEta = {
   eta_minutes: number
 }
 
+type RecentDeparture = {
+  route_id: string
+  bus_no: string
+  departed_at_unix_ms: number
+  minutes_ago: number
+}
+
+type StopEtaResponse = {
+  stop_id: string
+  stop_name: string
+  stop_desc: string
+  data: BusEta[]
+  recent_departures: RecentDeparture[]
+}
+
 type StopRouteSummary = {
   route_id: string
   route_short_name: string
@@ -118,7 +133,7 @@ function App() {
       lon: lon.toString(),
     })
     const response = await fetch(
-      `${apiBaseUrl}/stops/nearest?${params.toString()}`,
+      `${apiBaseUrl}/v1/stops/nearest?${params.toString()}`,
     )
     if (!response.ok) {
       const fallbackMessage = 'Unable to fetch nearest bus stop'
@@ -142,7 +157,7 @@ function App() {
 
     try {
       const response = await fetch(
-        `${apiBaseUrl}/stops/${encodeURIComponent(stopId)}/eta`,
+        `${apiBaseUrl}/v1/stops/${encodeURIComponent(stopId)}/eta`,
       )
       if (!response.ok) {
         const fallbackMessage = 'Unable to fetch ETA for nearest stop'
@@ -152,8 +167,8 @@ function App() {
         throw new Error(body?.error ?? fallbackMessage)
       }
 
-      const data = (await response.json()) as BusEta[]
-      setNearestStopEta(data)
+      const body = (await response.json()) as StopEtaResponse
+      setNearestStopEta(body.data)
       setSelectedBusKey(null)
     } catch (error) {
       setEtaErrorMessage(
@@ -173,7 +188,7 @@ function App() {
 
     try {
       const response = await fetch(
-        `${apiBaseUrl}/stops/${encodeURIComponent(stopId)}/routes`,
+        `${apiBaseUrl}/v1/stops/${encodeURIComponent(stopId)}/routes`,
       )
       if (!response.ok) {
         const fallbackMessage = 'Unable to fetch routes for nearest stop'
@@ -206,7 +221,7 @@ function App() {
 
     try {
       const response = await fetch(
-        `${apiBaseUrl}/route/${encodeURIComponent(routeId)}/stops`,
+        `${apiBaseUrl}/v1/route/${encodeURIComponent(routeId)}/stops`,
       )
       if (!response.ok) {
         const fallbackMessage = 'Unable to fetch route stops'
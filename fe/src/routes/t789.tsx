@@ -371,10 +371,10 @@ function T789Page() {
     try {
       const [busesResponse, etaResponse, stopsResponse, shapeResponse] =
         await Promise.all([
-          fetch(`${apiBaseUrl}/get-route-t789`),
-          fetch(`${apiBaseUrl}/get-t789-eta`),
-          fetch(`${apiBaseUrl}/route/T7890/stops`),
-          fetch(`${apiBaseUrl}/route/T7890/shape`),
+          fetch(`${apiBaseUrl}/v1/get-route-t789`),
+          fetch(`${apiBaseUrl}/v1/get-t789-eta`),
+          fetch(`${apiBaseUrl}/v1/route/T7890/stops`),
+          fetch(`${apiBaseUrl}/v1/route/T7890/shape`),
         ])
 
       if (!busesResponse.ok) {